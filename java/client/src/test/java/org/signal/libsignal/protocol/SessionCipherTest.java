@@ -10,6 +10,7 @@ import org.signal.libsignal.protocol.message.CiphertextMessage;
 import org.signal.libsignal.protocol.message.SignalMessage;
 import org.signal.libsignal.protocol.state.SignalProtocolStore;
 import org.signal.libsignal.protocol.state.SessionRecord;
+import org.signal.libsignal.protocol.util.AsyncOperations;
 import org.signal.libsignal.protocol.util.Pair;
 
 import java.security.NoSuchAlgorithmException;
@@ -19,6 +20,10 @@ import java.util.Collections;
 import java.util.LinkedList;
 import java.util.List;
 import java.util.Random;
+import java.util.concurrent.ExecutionException;
+import java.util.concurrent.ExecutorService;
+import java.util.concurrent.Executors;
+import java.util.concurrent.TimeUnit;
 
 
 public class SessionCipherTest extends TestCase {
@@ -70,6 +75,49 @@ public class SessionCipherTest extends TestCase {
     }
   }
 
+  public void testAsyncEncryptDecrypt() throws Exception {
+    PairOfSessions sessions = initializeSessionsV3();
+
+    SignalProtocolStore aliceStore = new TestInMemorySignalProtocolStore();
+    SignalProtocolStore bobStore   = new TestInMemorySignalProtocolStore();
+
+    aliceStore.storeSession(new SignalProtocolAddress("+14159999999", 1), sessions.aliceSession);
+    bobStore.storeSession(new SignalProtocolAddress("+14158888888", 1), sessions.bobSession);
+
+    SessionCipher aliceCipher = new SessionCipher(aliceStore, new SignalProtocolAddress("+14159999999", 1));
+    SessionCipher bobCipher   = new SessionCipher(bobStore, new SignalProtocolAddress("+14158888888", 1));
+
+    ExecutorService executor = Executors.newSingleThreadExecutor();
+    try {
+      CiphertextMessage message =
+          aliceCipher.encrypt("hello".getBytes(), executor).get(10, TimeUnit.SECONDS);
+
+      byte[] plaintext =
+          bobCipher.decrypt(new SignalMessage(message.serialize()), executor).get(10, TimeUnit.SECONDS);
+      assertTrue(Arrays.equals("hello".getBytes(), plaintext));
+
+      // Failures complete the future exceptionally rather than being thrown.
+      try {
+        bobCipher.decrypt(new SignalMessage(message.serialize()), executor).get(10, TimeUnit.SECONDS);
+        throw new AssertionError("Should have failed!");
+      } catch (ExecutionException e) {
+        assertTrue(e.getCause() instanceof DuplicateMessageException);
+      }
+
+      // So do Errors, which are not Exceptions.
+      try {
+        AsyncOperations.run(executor, () -> {
+          throw new StackOverflowError();
+        }).get(10, TimeUnit.SECONDS);
+        throw new AssertionError("Should have failed!");
+      } catch (ExecutionException e) {
+        assertTrue(e.getCause() instanceof StackOverflowError);
+      }
+    } finally {
+      executor.shutdown();
+    }
+  }
+
   public void testDecryptAfterReset() throws Exception {
     PairOfSessions sessions = initializeSessionsV3();
 
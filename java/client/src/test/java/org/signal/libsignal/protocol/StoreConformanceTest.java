@@ -0,0 +1,16 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.protocol;
+
+import junit.framework.TestCase;
+import org.signal.libsignal.protocol.state.StoreConformance;
+
+public class StoreConformanceTest extends TestCase {
+
+  public void testInMemoryStoreConforms() {
+    StoreConformance.check(new TestInMemorySignalProtocolStore());
+  }
+}
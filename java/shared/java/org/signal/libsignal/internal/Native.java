@@ -185,6 +185,7 @@ public final class Native {
   public static native long ECPrivateKey_GetPublicKey(long k);
   public static native byte[] ECPrivateKey_Serialize(long obj);
   public static native byte[] ECPrivateKey_Sign(long key, byte[] message);
+  public static native byte[] ECPrivateKey_SignWithLabel(long key, String label, byte[] message);
 
   public static native int ECPublicKey_Compare(long key1, long key2);
   public static native long ECPublicKey_Deserialize(byte[] data, int offset);
@@ -193,6 +194,7 @@ public final class Native {
   public static native byte[] ECPublicKey_GetPublicKeyBytes(long obj);
   public static native byte[] ECPublicKey_Serialize(long obj);
   public static native boolean ECPublicKey_Verify(long key, byte[] message, byte[] signature);
+  public static native boolean ECPublicKey_VerifyWithLabel(long key, String label, byte[] message, byte[] signature);
 
   public static native void ExpiringProfileKeyCredentialResponse_CheckValidContents(byte[] buffer);
 
@@ -333,6 +335,8 @@ public final class Native {
   public static native int PreKeySignalMessage_GetSignedPreKeyId(long obj);
   public static native int PreKeySignalMessage_GetVersion(long obj);
   public static native long PreKeySignalMessage_New(int messageVersion, int registrationId, int preKeyId, int signedPreKeyId, long baseKey, long identityKey, long signalMessage);
+  public static native boolean PreKeySignalMessage_VerifyAlternateIdentity(long m, long alternateIdentity);
+  public static native long PreKeySignalMessage_WithAlternateIdentitySignature(long m, byte[] signature);
 
   public static native void ProfileKeyCiphertext_CheckValidContents(byte[] buffer);
 
@@ -471,6 +475,7 @@ public final class Native {
   public static native byte[] SessionCipher_DecryptPreKeySignalMessage(long message, long protocolAddress, SessionStore sessionStore, IdentityKeyStore identityKeyStore, PreKeyStore prekeyStore, SignedPreKeyStore signedPrekeyStore, KyberPreKeyStore kyberPrekeyStore, Object ctx);
   public static native byte[] SessionCipher_DecryptSignalMessage(long message, long protocolAddress, SessionStore sessionStore, IdentityKeyStore identityKeyStore, Object ctx);
   public static native CiphertextMessage SessionCipher_EncryptMessage(byte[] ptext, long protocolAddress, SessionStore sessionStore, IdentityKeyStore identityKeyStore, Object ctx);
+  public static native CiphertextMessage SessionCipher_EncryptMessageWithAlternateIdentitySignature(byte[] ptext, long protocolAddress, SessionStore sessionStore, IdentityKeyStore identityKeyStore, byte[] alternateIdentitySignature, Object ctx);
 
   public static native void SessionRecord_ArchiveCurrentState(long sessionRecord);
   public static native boolean SessionRecord_CurrentRatchetKeyMatches(long s, long key);
@@ -519,6 +524,8 @@ public final class Native {
   public static native long SignedPreKeyRecord_GetTimestamp(long obj);
   public static native long SignedPreKeyRecord_New(int id, long timestamp, long pubKey, long privKey, byte[] signature);
 
+  public static native void StoreConformance_Check(SessionStore sessionStore, IdentityKeyStore identityStore, PreKeyStore preKeyStore, SignedPreKeyStore signedPreKeyStore, KyberPreKeyStore kyberPreKeyStore, SenderKeyStore senderKeyStore, Object ctx);
+
   public static native long Svr2Client_New(byte[] mrenclave, byte[] attestationMsg, long currentTimestamp);
 
   public static native long UnidentifiedSenderMessageContent_Deserialize(byte[] data);
@@ -0,0 +1,23 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+package org.signal.libsignal.protocol;
+
+/**
+ * Thrown when the identity key for an address has changed and the caller's identity change policy
+ * says to reject the message rather than save the new key.
+ */
+public class IdentityKeyChangedException extends Exception {
+
+  private final SignalProtocolAddress address;
+
+  public IdentityKeyChangedException(SignalProtocolAddress address, String message) {
+    super(message);
+    this.address = address;
+  }
+
+  public SignalProtocolAddress getAddress() {
+    return address;
+  }
+}
@@ -0,0 +1,16 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+package org.signal.libsignal.protocol;
+
+/**
+ * Thrown when a stored record's integrity tag is missing or does not match its contents.
+ *
+ * <p>The record has been corrupted or tampered with while in storage and should not be used.
+ */
+public class RecordIntegrityCheckFailedException extends IllegalStateException {
+  public RecordIntegrityCheckFailedException(String message) {
+    super(message);
+  }
+}
@@ -0,0 +1,17 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+package org.signal.libsignal.protocol;
+
+/**
+ * Thrown when the system random number generator produces output that fails a basic health check.
+ *
+ * <p>Nothing generated with the failing generator should be trusted; the operation should not be
+ * retried until the underlying problem has been fixed.
+ */
+public class RngHealthCheckFailedException extends IllegalStateException {
+  public RngHealthCheckFailedException(String message) {
+    super(message);
+  }
+}
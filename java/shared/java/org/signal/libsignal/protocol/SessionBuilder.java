@@ -16,6 +16,10 @@ import org.signal.libsignal.protocol.state.SessionRecord;
 import org.signal.libsignal.protocol.state.SessionStore;
 import org.signal.libsignal.protocol.state.SignalProtocolStore;
 import org.signal.libsignal.protocol.state.SignedPreKeyStore;
+import org.signal.libsignal.protocol.util.AsyncOperations;
+
+import java.util.concurrent.CompletableFuture;
+import java.util.concurrent.Executor;
 
 /**
  * SessionBuilder is responsible for setting up encrypted sessions.
@@ -97,4 +101,20 @@ public class SessionBuilder {
                                                 null);
     }
   }
+
+  /**
+   * Run {@link #process(PreKeyBundle)} on {@code executor}.
+   *
+   * The work is not done asynchronously; it blocks the executor's thread, including any store
+   * callbacks. The returned future completes exceptionally with whatever the blocking method
+   * throws.
+   *
+   * @see AsyncOperations
+   */
+  public CompletableFuture<Void> process(PreKeyBundle preKey, Executor executor) {
+    return AsyncOperations.run(executor, () -> {
+      process(preKey);
+      return null;
+    });
+  }
 }
@@ -19,9 +19,12 @@ import org.signal.libsignal.protocol.state.SessionRecord;
 import org.signal.libsignal.protocol.state.SessionStore;
 import org.signal.libsignal.protocol.state.SignedPreKeyStore;
 import org.signal.libsignal.protocol.state.KyberPreKeyStore;
+import org.signal.libsignal.protocol.util.AsyncOperations;
 
 import java.security.InvalidAlgorithmParameterException;
 import java.security.NoSuchAlgorithmException;
+import java.util.concurrent.CompletableFuture;
+import java.util.concurrent.Executor;
 
 /**
  * The main entry point for Signal Protocol encrypt/decrypt operations.
@@ -86,6 +89,28 @@ public class SessionCipher {
     }
   }
 
+  /**
+   * Encrypt a message, attaching a signature over an alternate identity if the message sets up a
+   * new session.
+   *
+   * @param  paddedMessage The plaintext message bytes, optionally padded to a constant multiple.
+   * @param  alternateIdentitySignature The result of
+   *         {@link org.signal.libsignal.protocol.IdentityKeyPair#signAlternateIdentity}.
+   * @return A ciphertext message encrypted to the recipient+device tuple.
+   */
+  public CiphertextMessage encrypt(byte[] paddedMessage, byte[] alternateIdentitySignature)
+      throws UntrustedIdentityException
+  {
+    try (NativeHandleGuard remoteAddress = new NativeHandleGuard(this.remoteAddress)) {
+      return Native.SessionCipher_EncryptMessageWithAlternateIdentitySignature(paddedMessage,
+                                                                               remoteAddress.nativeHandle(),
+                                                                               sessionStore,
+                                                                               identityKeyStore,
+                                                                               alternateIdentitySignature,
+                                                                               null);
+    }
+  }
+
   /**
    * Decrypt a message.
    *
@@ -143,6 +168,37 @@ public class SessionCipher {
     }
   }
 
+  /**
+   * Run {@link #encrypt(byte[])} on {@code executor}.
+   *
+   * The work is not done asynchronously; it blocks the executor's thread, including any store
+   * callbacks. The returned future completes exceptionally with whatever the blocking method
+   * throws.
+   *
+   * @see AsyncOperations
+   */
+  public CompletableFuture<CiphertextMessage> encrypt(byte[] paddedMessage, Executor executor) {
+    return AsyncOperations.run(executor, () -> encrypt(paddedMessage));
+  }
+
+  /**
+   * Run {@link #decrypt(PreKeySignalMessage)} on {@code executor}.
+   *
+   * @see #encrypt(byte[], Executor)
+   */
+  public CompletableFuture<byte[]> decrypt(PreKeySignalMessage ciphertext, Executor executor) {
+    return AsyncOperations.run(executor, () -> decrypt(ciphertext));
+  }
+
+  /**
+   * Run {@link #decrypt(SignalMessage)} on {@code executor}.
+   *
+   * @see #encrypt(byte[], Executor)
+   */
+  public CompletableFuture<byte[]> decrypt(SignalMessage ciphertext, Executor executor) {
+    return AsyncOperations.run(executor, () -> decrypt(ciphertext));
+  }
+
   public int getRemoteRegistrationId() {
     if (!sessionStore.containsSession(remoteAddress)) {
       throw new IllegalStateException(String.format("No session for (%s)!", remoteAddress));
@@ -45,6 +45,19 @@ public class ECPrivateKey implements NativeHandleGuard.Owner {
     }
   }
 
+  /**
+   * Signs an application payload, binding the signature to {@code label}.
+   *
+   * <p>Use a distinct label for each kind of payload, and verify with {@link
+   * ECPublicKey#verifySignatureWithLabel}. Labels must be 1 to 255 bytes long and must not start
+   * with {@code Signal_}.
+   */
+  public byte[] calculateSignatureWithLabel(String label, byte[] message) {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      return Native.ECPrivateKey_SignWithLabel(guard.nativeHandle(), label, message);
+    }
+  }
+
   public byte[] calculateAgreement(ECPublicKey other) {
     try (
       NativeHandleGuard privateKey = new NativeHandleGuard(this);
@@ -50,6 +50,16 @@ public class ECPublicKey implements Comparable<ECPublicKey>, NativeHandleGuard.O
     }
   }
 
+  /**
+   * Verifies a signature made by {@link ECPrivateKey#calculateSignatureWithLabel} with the same
+   * label.
+   */
+  public boolean verifySignatureWithLabel(String label, byte[] message, byte[] signature) {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      return Native.ECPublicKey_VerifyWithLabel(guard.nativeHandle(), label, message, signature);
+    }
+  }
+
   public byte[] serialize() {
     try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
       return Native.ECPublicKey_Serialize(guard.nativeHandle());
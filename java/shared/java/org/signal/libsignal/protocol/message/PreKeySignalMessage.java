@@ -82,6 +82,22 @@ public class PreKeySignalMessage implements CiphertextMessage, NativeHandleGuard
     }
   }
 
+  /**
+   * Checks that the sender's identity key has vouched for {@code alternate} as another identity
+   * of the same user.
+   *
+   * @return false if the message carries no alternate identity signature, or one over a different
+   *         identity.
+   */
+  public boolean verifyAlternateIdentity(IdentityKey alternate) {
+    try (
+      NativeHandleGuard guard = new NativeHandleGuard(this);
+      NativeHandleGuard alternateGuard = new NativeHandleGuard(alternate.getPublicKey());
+    ) {
+      return Native.PreKeySignalMessage_VerifyAlternateIdentity(guard.nativeHandle(), alternateGuard.nativeHandle());
+    }
+  }
+
   @Override
   public byte[] serialize() {
     try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
@@ -0,0 +1,26 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+package org.signal.libsignal.protocol.state;
+
+import org.signal.libsignal.internal.Native;
+
+/**
+ * Checks that a store implementation behaves like libsignal's in-memory reference stores.
+ * <p>
+ * The checks write entries under random addresses and IDs and don't remove them afterwards, so
+ * run them against a scratch instance of the store rather than one holding real data.
+ */
+public final class StoreConformance {
+  private StoreConformance() {}
+
+  /**
+   * Runs every conformance check against {@code store}.
+   *
+   * @throws IllegalStateException listing each check the store failed.
+   */
+  public static void check(SignalProtocolStore store) {
+    Native.StoreConformance_Check(store, store, store, store, store, store, null);
+  }
+}
@@ -0,0 +1,49 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.protocol.util;
+
+import java.util.concurrent.CompletableFuture;
+import java.util.concurrent.Executor;
+
+/**
+ * Runs blocking libsignal operations on a caller-provided {@link Executor}.
+ *
+ * This does not make the operations themselves asynchronous: the native call and every store
+ * callback it makes still block the executor's thread until they finish. It only moves that work
+ * off the caller's thread, so a UI thread or coroutine dispatcher isn't held up while stores do
+ * I/O. Operations on the same session should go through a serial executor, since the stores are
+ * not expected to be called concurrently.
+ */
+public final class AsyncOperations {
+  public interface Operation<T> {
+    T run() throws Exception;
+  }
+
+  private AsyncOperations() {}
+
+  /**
+   * Runs {@code operation} on {@code executor}.
+   *
+   * The returned future completes with the operation's result, or exceptionally with whatever it
+   * threw, including {@link Error}s. If {@code executor} rejects the task, the future completes
+   * exceptionally with the rejection.
+   */
+  public static <T> CompletableFuture<T> run(Executor executor, Operation<T> operation) {
+    CompletableFuture<T> future = new CompletableFuture<>();
+    try {
+      executor.execute(() -> {
+        try {
+          future.complete(operation.run());
+        } catch (Throwable t) {
+          future.completeExceptionally(t);
+        }
+      });
+    } catch (Throwable t) {
+      future.completeExceptionally(t);
+    }
+    return future;
+  }
+}
@@ -11,6 +11,15 @@ type Uuid = Buffer;
 /// what's important is that it's an integer less than Number.MAX_SAFE_INTEGER.
 type Timestamp = number;
 
+interface Wrapper<T> {
+  readonly _nativeHandle: T
+}
+
+// eslint-disable-next-line @typescript-eslint/no-unused-vars
+type Serialized<T> = Buffer;
+
+export function registerErrors(errorsModule: Record<string, unknown>): void;
+
 export abstract class IdentityKeyStore {
   _getIdentityKey(): Promise<PrivateKey>;
   _getLocalRegistrationId(): Promise<number>;
@@ -18,48 +27,32 @@ export abstract class IdentityKeyStore {
   _isTrustedIdentity(name: ProtocolAddress, key: PublicKey, sending: boolean): Promise<boolean>;
   _getIdentity(name: ProtocolAddress): Promise<PublicKey | null>;
 }
-
-export abstract class SessionStore {
-  _saveSession(addr: ProtocolAddress, record: SessionRecord): Promise<void>;
-  _getSession(addr: ProtocolAddress): Promise<SessionRecord | null>;
-}
-
-export abstract class PreKeyStore {
-  _savePreKey(preKeyId: number, record: PreKeyRecord): Promise<void>;
-  _getPreKey(preKeyId: number): Promise<PreKeyRecord>;
-  _removePreKey(preKeyId: number): Promise<void>;
-}
-
-export abstract class SignedPreKeyStore {
-  _saveSignedPreKey(signedPreKeyId: number, record: SignedPreKeyRecord): Promise<void>;
-  _getSignedPreKey(signedPreKeyId: number): Promise<SignedPreKeyRecord>;
+export abstract class InputStream {
+  _read(amount: number): Promise<Buffer>;
+  _skip(amount: number): Promise<void>;
 }
-
 export abstract class KyberPreKeyStore {
   _saveKyberPreKey(kyberPreKeyId: number, record: KyberPreKeyRecord): Promise<void>;
   _getKyberPreKey(kyberPreKeyId: number): Promise<KyberPreKeyRecord>;
   _markKyberPreKeyUsed(kyberPreKeyId: number): Promise<void>;
 }
-
+export abstract class PreKeyStore {
+  _savePreKey(preKeyId: number, record: PreKeyRecord): Promise<void>;
+  _getPreKey(preKeyId: number): Promise<PreKeyRecord>;
+  _removePreKey(preKeyId: number): Promise<void>;
+}
 export abstract class SenderKeyStore {
   _saveSenderKey(sender: ProtocolAddress, distributionId: Uuid, record: SenderKeyRecord): Promise<void>;
   _getSenderKey(sender: ProtocolAddress, distributionId: Uuid): Promise<SenderKeyRecord | null>;
 }
-
-export abstract class InputStream {
-  _read(amount: number): Promise<Buffer>;
-  _skip(amount: number): Promise<void>;
+export abstract class SessionStore {
+  _saveSession(addr: ProtocolAddress, record: SessionRecord): Promise<void>;
+  _getSession(addr: ProtocolAddress): Promise<SessionRecord | null>;
 }
-
-interface Wrapper<T> {
-  readonly _nativeHandle: T
+export abstract class SignedPreKeyStore {
+  _saveSignedPreKey(signedPreKeyId: number, record: SignedPreKeyRecord): Promise<void>;
+  _getSignedPreKey(signedPreKeyId: number): Promise<SignedPreKeyRecord>;
 }
-
-// eslint-disable-next-line @typescript-eslint/no-unused-vars
-type Serialized<T> = Buffer;
-
-export function registerErrors(errorsModule: Record<string, unknown>): void;
-
 export const enum LogLevel { Error = 1, Warn, Info, Debug, Trace }
 export function Aes256GcmSiv_Decrypt(aesGcmSiv: Wrapper<Aes256GcmSiv>, ctext: Buffer, nonce: Buffer, associatedData: Buffer): Buffer;
 export function Aes256GcmSiv_Encrypt(aesGcmSivObj: Wrapper<Aes256GcmSiv>, ptext: Buffer, nonce: Buffer, associatedData: Buffer): Buffer;
@@ -194,12 +187,15 @@ export function PreKeySignalMessage_GetSignedPreKeyId(obj: Wrapper<PreKeySignalM
 export function PreKeySignalMessage_GetVersion(obj: Wrapper<PreKeySignalMessage>): number;
 export function PreKeySignalMessage_New(messageVersion: number, registrationId: number, preKeyId: number | null, signedPreKeyId: number, baseKey: Wrapper<PublicKey>, identityKey: Wrapper<PublicKey>, signalMessage: Wrapper<SignalMessage>): PreKeySignalMessage;
 export function PreKeySignalMessage_Serialize(obj: Wrapper<PreKeySignalMessage>): Buffer;
+export function PreKeySignalMessage_VerifyAlternateIdentity(m: Wrapper<PreKeySignalMessage>, alternateIdentity: Wrapper<PublicKey>): boolean;
+export function PreKeySignalMessage_WithAlternateIdentitySignature(m: Wrapper<PreKeySignalMessage>, signature: Buffer): PreKeySignalMessage;
 export function PrivateKey_Agree(privateKey: Wrapper<PrivateKey>, publicKey: Wrapper<PublicKey>): Buffer;
 export function PrivateKey_Deserialize(data: Buffer): PrivateKey;
 export function PrivateKey_Generate(): PrivateKey;
 export function PrivateKey_GetPublicKey(k: Wrapper<PrivateKey>): PublicKey;
 export function PrivateKey_Serialize(obj: Wrapper<PrivateKey>): Buffer;
 export function PrivateKey_Sign(key: Wrapper<PrivateKey>, message: Buffer): Buffer;
+export function PrivateKey_SignWithLabel(key: Wrapper<PrivateKey>, label: string, message: Buffer): Buffer;
 export function ProfileKeyCiphertext_CheckValidContents(buffer: Buffer): void;
 export function ProfileKeyCommitment_CheckValidContents(buffer: Buffer): void;
 export function ProfileKeyCredentialPresentation_CheckValidContents(presentationBytes: Buffer): void;
@@ -221,6 +217,7 @@ export function PublicKey_Equals(lhs: Wrapper<PublicKey>, rhs: Wrapper<PublicKey
 export function PublicKey_GetPublicKeyBytes(obj: Wrapper<PublicKey>): Buffer;
 export function PublicKey_Serialize(obj: Wrapper<PublicKey>): Buffer;
 export function PublicKey_Verify(key: Wrapper<PublicKey>, message: Buffer, signature: Buffer): boolean;
+export function PublicKey_VerifyWithLabel(key: Wrapper<PublicKey>, label: string, message: Buffer, signature: Buffer): boolean;
 export function ReceiptCredentialPresentation_CheckValidContents(buffer: Buffer): void;
 export function ReceiptCredentialPresentation_GetReceiptExpirationTime(presentation: Serialized<ReceiptCredentialPresentation>): Timestamp;
 export function ReceiptCredentialPresentation_GetReceiptLevel(presentation: Serialized<ReceiptCredentialPresentation>): Buffer;
@@ -317,6 +314,7 @@ export function SessionBuilder_ProcessPreKeyBundle(bundle: Wrapper<PreKeyBundle>
 export function SessionCipher_DecryptPreKeySignalMessage(message: Wrapper<PreKeySignalMessage>, protocolAddress: Wrapper<ProtocolAddress>, sessionStore: SessionStore, identityKeyStore: IdentityKeyStore, prekeyStore: PreKeyStore, signedPrekeyStore: SignedPreKeyStore, kyberPrekeyStore: KyberPreKeyStore, ctx: null): Promise<Buffer>;
 export function SessionCipher_DecryptSignalMessage(message: Wrapper<SignalMessage>, protocolAddress: Wrapper<ProtocolAddress>, sessionStore: SessionStore, identityKeyStore: IdentityKeyStore, ctx: null): Promise<Buffer>;
 export function SessionCipher_EncryptMessage(ptext: Buffer, protocolAddress: Wrapper<ProtocolAddress>, sessionStore: SessionStore, identityKeyStore: IdentityKeyStore, ctx: null): Promise<CiphertextMessage>;
+export function SessionCipher_EncryptMessageWithAlternateIdentitySignature(ptext: Buffer, protocolAddress: Wrapper<ProtocolAddress>, sessionStore: SessionStore, identityKeyStore: IdentityKeyStore, alternateIdentitySignature: Buffer, ctx: null): Promise<CiphertextMessage>;
 export function SessionRecord_ArchiveCurrentState(sessionRecord: Wrapper<SessionRecord>): void;
 export function SessionRecord_CurrentRatchetKeyMatches(s: Wrapper<SessionRecord>, key: Wrapper<PublicKey>): boolean;
 export function SessionRecord_Deserialize(data: Buffer): SessionRecord;
@@ -344,6 +342,7 @@ export function SignedPreKeyRecord_GetSignature(obj: Wrapper<SignedPreKeyRecord>
 export function SignedPreKeyRecord_GetTimestamp(obj: Wrapper<SignedPreKeyRecord>): Timestamp;
 export function SignedPreKeyRecord_New(id: number, timestamp: Timestamp, pubKey: Wrapper<PublicKey>, privKey: Wrapper<PrivateKey>, signature: Buffer): SignedPreKeyRecord;
 export function SignedPreKeyRecord_Serialize(obj: Wrapper<SignedPreKeyRecord>): Buffer;
+export function StoreConformance_Check(sessionStore: SessionStore, identityStore: IdentityKeyStore, preKeyStore: PreKeyStore, signedPreKeyStore: SignedPreKeyStore, kyberPreKeyStore: KyberPreKeyStore, senderKeyStore: SenderKeyStore, ctx: null): Promise<void>;
 export function UnidentifiedSenderMessageContent_Deserialize(data: Buffer): UnidentifiedSenderMessageContent;
 export function UnidentifiedSenderMessageContent_GetContentHint(m: Wrapper<UnidentifiedSenderMessageContent>): number;
 export function UnidentifiedSenderMessageContent_GetContents(obj: Wrapper<UnidentifiedSenderMessageContent>): Buffer;
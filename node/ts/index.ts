@@ -190,6 +190,11 @@ export class PublicKey {
     return Native.PublicKey_Compare(this, other);
   }
 
+  /// Compares the two keys in constant time.
+  equals(other: PublicKey): boolean {
+    return Native.PublicKey_Equals(this, other);
+  }
+
   serialize(): Buffer {
     return Native.PublicKey_Serialize(this);
   }
@@ -202,6 +207,15 @@ export class PublicKey {
     return Native.PublicKey_Verify(this, msg, sig);
   }
 
+  /**
+   * Verifies a signature made by {@link PrivateKey#signWithLabel} with the same label.
+   *
+   * Throws if the label is not a valid label.
+   */
+  verifyWithLabel(label: string, msg: Buffer, sig: Buffer): boolean {
+    return Native.PublicKey_VerifyWithLabel(this, label, msg, sig);
+  }
+
   verifyAlternateIdentity(other: PublicKey, signature: Buffer): boolean {
     return Native.IdentityKey_VerifyAlternateIdentity(this, other, signature);
   }
@@ -234,6 +248,16 @@ export class PrivateKey {
     return Native.PrivateKey_Sign(this, msg);
   }
 
+  /**
+   * Signs an application payload, binding the signature to `label`.
+   *
+   * Use a distinct label for each kind of payload. Labels must be 1 to 255 bytes long and must
+   * not start with `Signal_`.
+   */
+  signWithLabel(label: string, msg: Buffer): Buffer {
+    return Native.PrivateKey_SignWithLabel(this, label, msg);
+  }
+
   agree(other_key: PublicKey): Buffer {
     return Native.PrivateKey_Agree(this, other_key);
   }
@@ -715,6 +739,16 @@ export class PreKeySignalMessage {
   serialize(): Buffer {
     return Native.PreKeySignalMessage_Serialize(this);
   }
+
+  /**
+   * Checks that the sender's identity key has vouched for `alternate` as another identity of the
+   * same user.
+   *
+   * Returns false if the message carries no alternate identity signature.
+   */
+  verifyAlternateIdentity(alternate: PublicKey): boolean {
+    return Native.PreKeySignalMessage_VerifyAlternateIdentity(this, alternate);
+  }
 }
 
 export class SessionRecord {
@@ -1507,6 +1541,55 @@ export async function signalEncrypt(
   );
 }
 
+/**
+ * Like {@link signalEncrypt}, but attaches `alternateIdentitySignature` (from
+ * {@link IdentityKeyPair.signAlternateIdentity}) if the message sets up a new session.
+ */
+export async function signalEncryptWithAlternateIdentitySignature(
+  message: Buffer,
+  address: ProtocolAddress,
+  sessionStore: SessionStore,
+  identityStore: IdentityKeyStore,
+  alternateIdentitySignature: Buffer
+): Promise<CiphertextMessage> {
+  return CiphertextMessage._fromNativeHandle(
+    await Native.SessionCipher_EncryptMessageWithAlternateIdentitySignature(
+      message,
+      address,
+      sessionStore,
+      identityStore,
+      alternateIdentitySignature,
+      null
+    )
+  );
+}
+
+/**
+ * Checks that the given stores behave like libsignal's in-memory reference stores, throwing an
+ * error that lists every check they failed.
+ *
+ * The checks write entries under random addresses and IDs and don't remove them afterwards, so
+ * pass scratch instances of the stores rather than ones holding real data.
+ */
+export function checkStoreConformance(
+  sessionStore: SessionStore,
+  identityStore: IdentityKeyStore,
+  prekeyStore: PreKeyStore,
+  signedPrekeyStore: SignedPreKeyStore,
+  kyberPrekeyStore: KyberPreKeyStore,
+  senderKeyStore: SenderKeyStore
+): Promise<void> {
+  return Native.StoreConformance_Check(
+    sessionStore,
+    identityStore,
+    prekeyStore,
+    signedPrekeyStore,
+    kyberPrekeyStore,
+    senderKeyStore,
+    null
+  );
+}
+
 export function signalDecrypt(
   message: SignalMessage,
   address: ProtocolAddress,
@@ -78,7 +78,7 @@ class InMemoryIdentityKeyStore extends SignalClient.IdentityKeyStore {
     const idx = `${name.name()}::${name.deviceId()}`;
     const currentKey = this.idKeys.get(idx);
     if (currentKey) {
-      return currentKey.compare(key) == 0;
+      return currentKey.equals(key);
     } else {
       return true;
     }
@@ -850,6 +850,18 @@ describe('SignalClient', () => {
     assert.deepEqual(recordFromBytes, record);
   });
 
+  it('in-memory stores pass the store conformance checks', async () => {
+    const stores = new TestStores();
+    await SignalClient.checkStoreConformance(
+      stores.session,
+      stores.identity,
+      stores.prekey,
+      stores.signed,
+      stores.kyber,
+      stores.sender
+    );
+  });
+
   it('SignalMessage and PreKeySignalMessage', () => {
     const messageVersion = 3;
     const macKey = Buffer.alloc(32, 0xab);
@@ -1887,6 +1899,12 @@ describe('SignalClient', () => {
 
     assert(pub_b.verify(msg, sig_b));
     assert(!pub_a.verify(msg, sig_b));
+
+    const labeled = priv_a.signWithLabel('Test_Label', msg);
+    assert(pub_a.verifyWithLabel('Test_Label', msg, labeled));
+    assert(!pub_a.verifyWithLabel('Test_Other', msg, labeled));
+    assert(!pub_a.verify(msg, labeled));
+    assert.throws(() => priv_a.signWithLabel('Signal_Label', msg));
   });
 
   it('ECC key agreement work', () => {
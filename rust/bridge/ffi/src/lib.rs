@@ -71,7 +71,10 @@ pub unsafe extern "C" fn signal_error_get_address(
     run_ffi_safe(|| {
         let err = err.as_ref().ok_or(SignalFfiError::NullPointer)?;
         match err {
-            SignalFfiError::Signal(SignalProtocolError::InvalidRegistrationId(addr, _value)) => {
+            SignalFfiError::Signal(SignalProtocolError::InvalidRegistrationId(addr, _))
+            | SignalFfiError::Signal(SignalProtocolError::UntrustedIdentity(addr))
+            | SignalFfiError::Signal(SignalProtocolError::IdentityKeyChanged(addr))
+            | SignalFfiError::Signal(SignalProtocolError::SessionNotFound(addr)) => {
                 write_result_to(out, addr.clone())?;
             }
             _ => {
@@ -114,6 +117,63 @@ pub unsafe extern "C" fn signal_error_get_uuid(
     })
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn signal_error_get_registration_id(
+    err: *const SignalFfiError,
+    out: *mut u32,
+) -> *mut SignalFfiError {
+    let err = AssertUnwindSafe(err);
+    run_ffi_safe(|| {
+        let err = err.as_ref().ok_or(SignalFfiError::NullPointer)?;
+        match err {
+            SignalFfiError::Signal(SignalProtocolError::InvalidRegistrationId(_, value)) => {
+                write_result_to(out, *value)?;
+            }
+            _ => {
+                return Err(SignalFfiError::Signal(
+                    SignalProtocolError::InvalidArgument(format!(
+                        "cannot get registration ID from error ({})",
+                        err
+                    )),
+                ));
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Gets the receiving chain's current index and the rejected message's counter from a
+/// `DuplicatedMessage` error.
+#[no_mangle]
+pub unsafe extern "C" fn signal_error_get_duplicated_message_counters(
+    err: *const SignalFfiError,
+    out_chain_index: *mut u32,
+    out_counter: *mut u32,
+) -> *mut SignalFfiError {
+    let err = AssertUnwindSafe(err);
+    run_ffi_safe(|| {
+        let err = err.as_ref().ok_or(SignalFfiError::NullPointer)?;
+        match err {
+            SignalFfiError::Signal(SignalProtocolError::DuplicatedMessage(
+                chain_index,
+                counter,
+            )) => {
+                write_result_to(out_chain_index, *chain_index)?;
+                write_result_to(out_counter, *counter)?;
+            }
+            _ => {
+                return Err(SignalFfiError::Signal(
+                    SignalProtocolError::InvalidArgument(format!(
+                        "cannot get message counters from error ({})",
+                        err
+                    )),
+                ));
+            }
+        }
+        Ok(())
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn signal_error_get_type(err: *const SignalFfiError) -> u32 {
     match err.as_ref() {
@@ -182,7 +242,7 @@ pub unsafe extern "C" fn signal_sealed_session_cipher_decrypt(
         let decrypted = sealed_sender_decrypt(
             ctext,
             trust_root,
-            timestamp,
+            Timestamp::from_epoch_millis(timestamp),
             local_e164,
             local_uuid,
             local_device_id.into(),
@@ -191,6 +251,7 @@ pub unsafe extern "C" fn signal_sealed_session_cipher_decrypt(
             &mut prekey_store,
             &mut signed_prekey_store,
             &mut kyber_pre_key_store,
+            &mut rand::rngs::OsRng,
             Some(ctx),
         )
         .now_or_never()
@@ -33,15 +33,20 @@ pub enum SignalErrorCode {
 
     InvalidMessage = 30,
     SealedSenderSelfSend = 31,
+    InvalidPadding = 32,
+    InvalidProfileCiphertext = 33,
 
     InvalidKey = 40,
     InvalidSignature = 41,
     InvalidAttestationData = 42,
+    InvalidKeyEncoding = 43,
+    MismatchedKeyTypes = 44,
 
     FingerprintVersionMismatch = 51,
     FingerprintParsingError = 52,
 
     UntrustedIdentity = 60,
+    IdentityKeyChanged = 61,
 
     InvalidKeyIdentifier = 70,
 
@@ -49,8 +54,11 @@ pub enum SignalErrorCode {
     InvalidRegistrationId = 81,
     InvalidSession = 82,
     InvalidSenderKeySession = 83,
+    RecordIntegrityCheckFailed = 84,
+    UnrecognizedRecordVersion = 85,
 
     DuplicatedMessage = 90,
+    WorkLimitExceeded = 91,
 
     CallbackError = 100,
 
@@ -72,6 +80,10 @@ pub enum SignalErrorCode {
     InvalidMediaInput = 131,
     #[allow(dead_code)]
     UnsupportedMediaInput = 132,
+
+    Cancelled = 140,
+
+    RngHealthCheckFailed = 150,
 }
 
 impl From<&SignalFfiError> for SignalErrorCode {
@@ -79,6 +91,8 @@ impl From<&SignalFfiError> for SignalErrorCode {
         match err {
             SignalFfiError::NullPointer => SignalErrorCode::NullParameter,
 
+            SignalFfiError::Cancelled => SignalErrorCode::Cancelled,
+
             SignalFfiError::UnexpectedPanic(_)
             | SignalFfiError::DeviceTransfer(DeviceTransferError::InternalError(_))
             | SignalFfiError::Signal(SignalProtocolError::FfiBindingError(_)) => {
@@ -161,6 +175,8 @@ impl From<&SignalFfiError> for SignalErrorCode {
             SignalFfiError::Signal(SignalProtocolError::InvalidMessage(..))
             | SignalFfiError::Signal(SignalProtocolError::CiphertextMessageTooShort(_))
             | SignalFfiError::Signal(SignalProtocolError::InvalidSealedSenderMessage(_))
+            | SignalFfiError::Signal(SignalProtocolError::EnvelopeTooOld { .. })
+            | SignalFfiError::Signal(SignalProtocolError::EnvelopeFromFuture { .. })
             | SignalFfiError::Signal(SignalProtocolError::BadKEMCiphertextLength(_, _))
             | SignalFfiError::SignalCrypto(SignalCryptoError::InvalidTag)
             | SignalFfiError::Sgx(SgxError::DcapError(_))
@@ -180,6 +196,10 @@ impl From<&SignalFfiError> for SignalErrorCode {
                 SignalErrorCode::UntrustedIdentity
             }
 
+            SignalFfiError::Signal(SignalProtocolError::IdentityKeyChanged(_)) => {
+                SignalErrorCode::IdentityKeyChanged
+            }
+
             SignalFfiError::Signal(SignalProtocolError::InvalidState(_, _))
             | SignalFfiError::Sgx(SgxError::InvalidBridgeStateError)
             | SignalFfiError::HsmEnclave(HsmEnclaveError::InvalidBridgeStateError) => {
@@ -202,6 +222,46 @@ impl From<&SignalFfiError> for SignalErrorCode {
                 SignalErrorCode::CallbackError
             }
 
+            SignalFfiError::Signal(SignalProtocolError::RngHealthCheckFailed(_)) => {
+                SignalErrorCode::RngHealthCheckFailed
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::RecordIntegrityCheckFailed(_)) => {
+                SignalErrorCode::RecordIntegrityCheckFailed
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::WorkLimitExceeded(_)) => {
+                SignalErrorCode::WorkLimitExceeded
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::InvalidKeyEncoding(_)) => {
+                SignalErrorCode::InvalidKeyEncoding
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::MismatchedKeyTypes(_, _)) => {
+                SignalErrorCode::MismatchedKeyTypes
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::InvalidPadding(_)) => {
+                SignalErrorCode::InvalidPadding
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::InvalidProfileCiphertext(_)) => {
+                SignalErrorCode::InvalidProfileCiphertext
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::UnrecognizedRecordVersion(_, _)) => {
+                SignalErrorCode::UnrecognizedRecordVersion
+            }
+
+            SignalFfiError::Signal(SignalProtocolError::Extension(_)) => {
+                SignalErrorCode::UnknownError
+            }
+
+            // SignalProtocolError is non_exhaustive. Variants added to it should get an arm of
+            // their own above rather than falling through to this one.
+            SignalFfiError::Signal(_) => SignalErrorCode::UnknownError,
+
             SignalFfiError::ZkGroupVerificationFailure(ZkGroupVerificationFailure) => {
                 SignalErrorCode::VerificationFailure
             }
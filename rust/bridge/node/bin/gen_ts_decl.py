@@ -28,7 +28,9 @@ def translate_to_ts(typ):
         "i32": "number",
         "u8": "number",
         "u32": "number",
+        "DeviceId": "number",
         "u64": "Buffer",  # FIXME: eventually this should be a bigint
+        "f64": "number",
         "bool": "boolean",
         "String": "string",
         "&str": "string",
@@ -92,6 +94,32 @@ def camelcase(arg):
     return parts[0] + ''.join(x.title() for x in parts[1:])
 
 
+# Make sure /not/ to match arguments with nested parentheses,
+# which won't survive textual splitting below.
+function_sig = re.compile(r'(.+)\(([^()]*)\): (.+);?')
+
+
+def translate_decl(decl):
+    function_match = function_sig.match(decl)
+    if function_match is None:
+        return decl
+
+    (prefix, args, ret_type) = function_match.groups()
+
+    ts_ret_type = translate_to_ts(ret_type)
+    ts_args = []
+    if args:
+        if '::' in args:
+            raise Exception(f'Paths are not supported. Use alias for the type of \'{args}\'')
+
+        for arg in args.split(', '):
+            (arg_name, arg_type) = arg.split(': ')
+            ts_arg_type = translate_to_ts(arg_type)
+            ts_args.append('%s: %s' % (camelcase(arg_name.strip()), ts_arg_type))
+
+    return '%s(%s): %s;' % (prefix, ', '.join(ts_args), ts_ret_type)
+
+
 def collect_decls(crate_dir, features=()):
     args = [
         'cargo',
@@ -128,9 +156,9 @@ def collect_decls(crate_dir, features=()):
     # Note that the doc attribute is sometimes wrapped onto two lines.
     attr_decl = re.compile(r'\s*(?:#\[doc\s*=\s*)?"ts: (.+)"\]')
 
-    # Make sure /not/ to match arguments with nested parentheses,
-    # which won't survive textual splitting below.
-    function_sig = re.compile(r'(.+)\(([^()]*)\): (.+);?')
+    # A declaration ending in '{' starts a block (such as a store callback interface) whose members
+    # are declared one per line, up to a closing '}'. The block is emitted as a single declaration.
+    block = None
 
     for line in stdout.split('\n'):
         match = comment_decl.match(line) or attr_decl.match(line)
@@ -138,26 +166,25 @@ def collect_decls(crate_dir, features=()):
             continue
 
         (decl,) = match.groups()
-
-        function_match = function_sig.match(decl)
-        if function_match is None:
-            yield decl
+        decl = decl.strip()
+
+        if block is not None:
+            if decl == '}':
+                block.append(decl)
+                yield '\n'.join(block)
+                block = None
+            else:
+                block.append('  ' + translate_decl(decl))
             continue
 
-        (prefix, args, ret_type) = function_match.groups()
-
-        ts_ret_type = translate_to_ts(ret_type)
-        ts_args = []
-        if args:
-            if '::' in args:
-                raise Exception(f'Paths are not supported. Use alias for the type of \'{args}\'')
+        if decl.endswith('{'):
+            block = [decl]
+            continue
 
-            for arg in args.split(', '):
-                (arg_name, arg_type) = arg.split(': ')
-                ts_arg_type = translate_to_ts(arg_type)
-                ts_args.append('%s: %s' % (camelcase(arg_name.strip()), ts_arg_type))
+        yield translate_decl(decl)
 
-        yield '%s(%s): %s;' % (prefix, ', '.join(ts_args), ts_ret_type)
+    if block is not None:
+        raise Exception(f'unterminated declaration block \'{block[0]}\'')
 
 
 mode = None
@@ -37,4 +37,4 @@ mod result;
 pub use result::JsPromiseResult;
 
 mod util;
-pub use util::call_method;
+pub use util::{call_method, resolve_promise};
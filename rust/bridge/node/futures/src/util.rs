@@ -29,3 +29,18 @@ pub fn call_method<'a>(
     let method: Handle<JsFunction> = this.get(cx, method_name)?;
     method.call(cx, this, args)
 }
+
+/// Wraps `value` in a promise, as if by JavaScript's [`Promise.resolve`][resolve].
+///
+/// Promises (and other thenables) are passed through, so a callback may return either a promise
+/// or a plain value and still be awaited with [JsFuture::get_promise](crate::JsFuture::get_promise).
+///
+/// [resolve]: https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Global_Objects/Promise/resolve
+pub fn resolve_promise<'a>(
+    cx: &mut impl Context<'a>,
+    value: Handle<'a, JsValue>,
+) -> JsResult<'a, JsObject> {
+    let global = cx.global();
+    let promise_class: Handle<JsObject> = global.get(cx, "Promise")?;
+    call_method(cx, promise_class, "resolve", [value])?.downcast_or_throw(cx)
+}
@@ -57,6 +57,13 @@ describe('native', () => {
     assert.equal(result, 'Moxie Moxie');
   });
 
+  it('can handle store-like callbacks that return plain values', async () => {
+    const result = await native.doubleNameFromStore({
+      getName: () => 'Moxie',
+    });
+    assert.equal(result, 'Moxie Moxie');
+  });
+
   it('can handle store-like callbacks that fail', async () => {
     const promise = native.doubleNameFromStore({
       getName: () => Promise.reject('uh oh'),
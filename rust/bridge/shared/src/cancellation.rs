@@ -0,0 +1,82 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Cancellable variants of operations that can take long enough to be worth abandoning.
+
+use libsignal_bridge_macros::*;
+use libsignal_protocol::error::Result;
+use libsignal_protocol::*;
+
+use crate::ffi::{CancellableIdentityKeyStore, CancellationToken};
+use crate::support::*;
+use crate::*;
+
+bridge_handle!(CancellationToken, jni = false, node = false);
+
+/// How many fingerprint iterations to do between checks of the token.
+const FINGERPRINT_ITERATIONS_PER_CHECK: u32 = 256;
+
+#[bridge_fn(jni = false, node = false)]
+fn CancellationToken_New() -> CancellationToken {
+    CancellationToken::new()
+}
+
+#[bridge_fn_void(jni = false, node = false)]
+fn CancellationToken_Cancel(token: &CancellationToken) {
+    token.cancel()
+}
+
+#[bridge_fn(jni = false, node = false)]
+fn CancellationToken_IsCancelled(token: &CancellationToken) -> bool {
+    token.is_cancelled()
+}
+
+#[bridge_fn(jni = false, node = false)]
+fn Fingerprint_NewCancellable(
+    iterations: u32,
+    version: u32,
+    local_identifier: &[u8],
+    local_key: &PublicKey,
+    remote_identifier: &[u8],
+    remote_key: &PublicKey,
+    token: &CancellationToken,
+) -> Result<Fingerprint> {
+    let mut generator = FingerprintGenerator::new(
+        version,
+        iterations,
+        local_identifier,
+        &IdentityKey::new(*local_key),
+        remote_identifier,
+        &IdentityKey::new(*remote_key),
+    )?;
+    while !generator.is_complete() {
+        token.check()?;
+        generator.advance(FINGERPRINT_ITERATIONS_PER_CHECK);
+    }
+    generator.finish()
+}
+
+#[bridge_fn(jni = false, node = false)]
+async fn SealedSender_MultiRecipientEncryptCancellable(
+    recipients: &[&ProtocolAddress],
+    recipient_sessions: &[&SessionRecord],
+    content: &UnidentifiedSenderMessageContent,
+    identity_key_store: &mut dyn IdentityKeyStore,
+    token: &CancellationToken,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    token.check()?;
+    let mut rng = rand::rngs::OsRng;
+    let mut identity_key_store = CancellableIdentityKeyStore::new(identity_key_store, token);
+    sealed_sender_multi_recipient_encrypt(
+        recipients,
+        recipient_sessions,
+        content,
+        &mut identity_key_store,
+        ctx,
+        &mut rng,
+    )
+    .await
+}
@@ -35,12 +35,7 @@ fn Cds2ClientState_New(
     attestation_msg: &[u8],
     current_timestamp: Timestamp,
 ) -> Result<SgxClientState> {
-    new_client(
-        mrenclave,
-        attestation_msg,
-        std::time::SystemTime::UNIX_EPOCH
-            + std::time::Duration::from_millis(current_timestamp.as_millis()),
-    )
+    new_client(mrenclave, attestation_msg, current_timestamp.into())
 }
 
 #[cfg(all(not(target_os = "android"), feature = "jni"))]
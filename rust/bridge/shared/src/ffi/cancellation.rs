@@ -0,0 +1,137 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use libsignal_protocol::*;
+
+/// A flag that lets a C client stop a long-running operation from another thread.
+///
+/// Cancellation is cooperative: operations that accept a token check it between steps (and before
+/// each store callback), and fail with [`OperationCancelled`] once it has been set. Clones share the
+/// same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails with [`OperationCancelled`] (as a [`SignalProtocolError::Extension`]) if the token
+    /// has been cancelled.
+    pub fn check(&self) -> Result<(), SignalProtocolError> {
+        if self.is_cancelled() {
+            return Err(SignalProtocolError::extension(OperationCancelled));
+        }
+        Ok(())
+    }
+}
+
+/// The error produced when an operation stops because its [`CancellationToken`] was cancelled.
+#[derive(Debug)]
+pub struct OperationCancelled;
+
+impl fmt::Display for OperationCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation was cancelled")
+    }
+}
+
+impl std::error::Error for OperationCancelled {}
+
+/// Checks a [`CancellationToken`] before every call into the wrapped store.
+///
+/// Every trait method is forwarded, including those with default implementations, so that an
+/// override in the wrapped store is still used.
+pub struct CancellableIdentityKeyStore<'a> {
+    inner: &'a mut dyn IdentityKeyStore,
+    token: &'a CancellationToken,
+}
+
+impl<'a> CancellableIdentityKeyStore<'a> {
+    pub fn new(inner: &'a mut dyn IdentityKeyStore, token: &'a CancellationToken) -> Self {
+        Self { inner, token }
+    }
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for CancellableIdentityKeyStore<'_> {
+    async fn get_identity_key_pair(
+        &self,
+        ctx: Context,
+    ) -> Result<IdentityKeyPair, SignalProtocolError> {
+        self.token.check()?;
+        self.inner.get_identity_key_pair(ctx).await
+    }
+
+    async fn get_identity_private_key(
+        &self,
+        ctx: Context,
+    ) -> Result<Box<dyn PrivateKeyOps>, SignalProtocolError> {
+        self.token.check()?;
+        self.inner.get_identity_private_key(ctx).await
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32, SignalProtocolError> {
+        self.token.check()?;
+        self.inner.get_local_registration_id(ctx).await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        self.token.check()?;
+        self.inner.save_identity(address, identity, ctx).await
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+        ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        self.token.check()?;
+        self.inner
+            .is_trusted_identity(address, identity, direction, ctx)
+            .await
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.token.check()?;
+        self.inner.get_identity(address, ctx).await
+    }
+
+    async fn apply_identity_rotation(
+        &mut self,
+        address: &ProtocolAddress,
+        rotation: &IdentityRotation,
+        ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        self.token.check()?;
+        self.inner
+            .apply_identity_rotation(address, rotation, ctx)
+            .await
+    }
+}
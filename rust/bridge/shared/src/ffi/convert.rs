@@ -450,17 +450,32 @@ impl ResultTypeInfo for Option<u32> {
     }
 }
 
+/// Passes the ID through unchecked, like `DeviceId::from(u32)`.
+impl SimpleArgTypeInfo for libsignal_protocol::DeviceId {
+    type ArgType = u32;
+    fn convert_from(foreign: Self::ArgType) -> SignalFfiResult<Self> {
+        Ok(foreign.into())
+    }
+}
+
+impl ResultTypeInfo for libsignal_protocol::DeviceId {
+    type ResultType = u32;
+    fn convert_into(self) -> SignalFfiResult<Self::ResultType> {
+        Ok(self.into())
+    }
+}
+
 impl SimpleArgTypeInfo for crate::protocol::Timestamp {
     type ArgType = u64;
     fn convert_from(foreign: Self::ArgType) -> SignalFfiResult<Self> {
-        Ok(Self::from_millis(foreign))
+        Ok(Self::from_epoch_millis(foreign))
     }
 }
 
 impl ResultTypeInfo for crate::protocol::Timestamp {
     type ResultType = u64;
     fn convert_into(self) -> SignalFfiResult<Self::ResultType> {
-        Ok(self.as_millis())
+        Ok(self.epoch_millis())
     }
 }
 
@@ -661,6 +676,7 @@ macro_rules! ffi_arg_type {
     (Option<String>) => (*const libc::c_char);
     (Option<&str>) => (*const libc::c_char);
     (Context) => (*mut libc::c_void);
+    (DeviceId) => (u32);
     (Timestamp) => (u64);
     (Uuid) => (*const [u8; 16]);
     (ServiceId) => (*const libsignal_protocol::ServiceIdFixedWidthBinaryBytes);
@@ -704,6 +720,7 @@ macro_rules! ffi_result_type {
     (Option<String>) => (*const libc::c_char);
     (Option<&str>) => (*const libc::c_char);
     (Option<$typ:ty>) => (*mut $typ);
+    (DeviceId) => (u32);
     (Timestamp) => (u64);
     (Uuid) => ([u8; 16]);
     (ServiceId) => (libsignal_protocol::ServiceIdFixedWidthBinaryBytes);
@@ -18,7 +18,7 @@ use zkgroup::{ZkGroupDeserializationFailure, ZkGroupVerificationFailure};
 
 use crate::support::describe_panic;
 
-use super::NullPointerError;
+use super::{NullPointerError, OperationCancelled};
 
 /// The top-level error type (opaquely) returned to C clients when something goes wrong.
 #[derive(Debug)]
@@ -36,6 +36,7 @@ pub enum SignalFfiError {
     Io(IoError),
     #[cfg(feature = "signal-media")]
     MediaSanitizeParse(signal_media::sanitize::ParseErrorReport),
+    Cancelled,
     NullPointer,
     InvalidUtf8String,
     UnexpectedPanic(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
@@ -67,6 +68,7 @@ impl fmt::Display for SignalFfiError {
             SignalFfiError::MediaSanitizeParse(e) => {
                 write!(f, "Media sanitizer failed to parse media file: {}", e)
             }
+            SignalFfiError::Cancelled => write!(f, "{}", OperationCancelled),
             SignalFfiError::NullPointer => write!(f, "null pointer"),
             SignalFfiError::InvalidUtf8String => write!(f, "invalid UTF8 string"),
             SignalFfiError::UnexpectedPanic(e) => {
@@ -78,7 +80,10 @@ impl fmt::Display for SignalFfiError {
 
 impl From<SignalProtocolError> for SignalFfiError {
     fn from(e: SignalProtocolError) -> SignalFfiError {
-        SignalFfiError::Signal(e)
+        match e.downcast_extension::<OperationCancelled>() {
+            Ok(OperationCancelled) => SignalFfiError::Cancelled,
+            Err(e) => SignalFfiError::Signal(e),
+        }
     }
 }
 
@@ -6,6 +6,9 @@
 use libsignal_protocol::*;
 use std::ffi::CString;
 
+mod cancellation;
+pub use cancellation::*;
+
 #[macro_use]
 mod convert;
 pub use convert::*;
@@ -6,7 +6,6 @@
 use super::*;
 use async_trait::async_trait;
 use libc::{c_int, c_uint, c_void};
-use uuid::Uuid;
 
 type GetIdentityKeyPair =
     extern "C" fn(store_ctx: *mut c_void, keyp: *mut *mut PrivateKey, ctx: *mut c_void) -> c_int;
@@ -509,14 +508,18 @@ pub struct FfiSenderKeyStoreStruct {
 impl SenderKeyStore for &FfiSenderKeyStoreStruct {
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         record: &SenderKeyRecord,
         ctx: Context,
     ) -> Result<(), SignalProtocolError> {
         let ctx = ctx.unwrap_or(std::ptr::null_mut());
-        let result =
-            (self.store_sender_key)(self.ctx, sender, distribution_id.as_bytes(), record, ctx);
+        let result = (self.store_sender_key)(
+            self.ctx,
+            sender_key_name.sender(),
+            sender_key_name.distribution_id().as_bytes(),
+            record,
+            ctx,
+        );
 
         if let Some(error) = CallbackError::check(result) {
             return Err(SignalProtocolError::ApplicationCallbackError(
@@ -530,8 +533,7 @@ impl SenderKeyStore for &FfiSenderKeyStoreStruct {
 
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         ctx: Context,
     ) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
         let ctx = ctx.unwrap_or(std::ptr::null_mut());
@@ -539,8 +541,8 @@ impl SenderKeyStore for &FfiSenderKeyStoreStruct {
         let result = (self.load_sender_key)(
             self.ctx,
             &mut record,
-            sender,
-            distribution_id.as_bytes(),
+            sender_key_name.sender(),
+            sender_key_name.distribution_id().as_bytes(),
             ctx,
         );
 
@@ -173,6 +173,14 @@ impl<'a> SimpleArgTypeInfo<'a> for u64 {
     }
 }
 
+/// Supports values `0..=Integer.MAX_VALUE`, like `u32`; the ID is otherwise unchecked.
+impl<'a> SimpleArgTypeInfo<'a> for libsignal_protocol::DeviceId {
+    type ArgType = jint;
+    fn convert_from(env: &JNIEnv, foreign: jint) -> SignalJniResult<Self> {
+        u32::convert_from(env, foreign).map(Self::from)
+    }
+}
+
 /// Supports values `0..=Long.MAX_VALUE`.
 ///
 /// Negative `long` values are *not* reinterpreted as large `u64` values.
@@ -186,7 +194,7 @@ impl<'a> SimpleArgTypeInfo<'a> for crate::protocol::Timestamp {
                 foreign
             )));
         }
-        Ok(Self::from_millis(foreign as u64))
+        Ok(Self::from_epoch_millis(foreign as u64))
     }
 }
 
@@ -510,6 +518,16 @@ impl ResultTypeInfo for u64 {
     }
 }
 
+impl ResultTypeInfo for libsignal_protocol::DeviceId {
+    type ResultType = jint;
+    fn convert_into(self, env: &JNIEnv) -> SignalJniResult<Self::ResultType> {
+        u32::from(self).convert_into(env)
+    }
+    fn convert_into_jobject(_signal_jni_result: &SignalJniResult<Self::ResultType>) -> JObject {
+        JObject::null()
+    }
+}
+
 /// Reinterprets the bits of the timestamp's `u64` as a Java `long`.
 ///
 /// Note that this is different from the implementation of [`ArgTypeInfo`] for `Timestamp`.
@@ -517,7 +535,7 @@ impl ResultTypeInfo for crate::protocol::Timestamp {
     type ResultType = jlong;
     fn convert_into(self, _env: &JNIEnv) -> SignalJniResult<Self::ResultType> {
         // Note that we don't check bounds here.
-        Ok(self.as_millis() as jlong)
+        Ok(self.epoch_millis() as jlong)
     }
     fn convert_into_jobject(_signal_jni_result: &SignalJniResult<Self::ResultType>) -> JObject {
         JObject::null()
@@ -1177,6 +1195,9 @@ macro_rules! jni_arg_type {
     (Context) => {
         jni::JObject
     };
+    (DeviceId) => {
+        jni::jint
+    };
     (Timestamp) => {
         jni::jlong
     };
@@ -1260,6 +1281,9 @@ macro_rules! jni_result_type {
     (Uuid) => {
         jni::JavaReturnUUID
     };
+    (DeviceId) => {
+        jni::jint
+    };
     (Timestamp) => {
         jni::jlong
     };
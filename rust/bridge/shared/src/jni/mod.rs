@@ -143,6 +143,25 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
             return;
         }
 
+        SignalJniError::Signal(SignalProtocolError::IdentityKeyChanged(ref addr)) => {
+            let throwable = protocol_address_to_jobject(env, addr)
+                .and_then(|addr_object| Ok((addr_object, env.new_string(error.to_string())?)))
+                .and_then(|(addr_object, message)| {
+                    let args = jni_args!((
+                        addr_object => org.signal.libsignal.protocol.SignalProtocolAddress,
+                        message => java.lang.String,
+                    ) -> void);
+                    Ok(env.new_object(
+                        jni_class_name!(org.signal.libsignal.protocol.IdentityKeyChangedException),
+                        args.sig,
+                        &args.args,
+                    )?)
+                });
+
+            try_throw(env, throwable, error);
+            return;
+        }
+
         SignalJniError::Signal(SignalProtocolError::InvalidSenderKeySession {
             distribution_id,
         }) => {
@@ -242,6 +261,7 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         | SignalJniError::Jni(_)
         | SignalJniError::Signal(SignalProtocolError::ApplicationCallbackError(_, _))
         | SignalJniError::Signal(SignalProtocolError::FfiBindingError(_))
+        | SignalJniError::Signal(SignalProtocolError::Extension(_))
         | SignalJniError::DeviceTransfer(DeviceTransferError::InternalError(_))
         | SignalJniError::DeviceTransfer(DeviceTransferError::KeyDecodingFailed) => {
             jni_class_name!(java.lang.RuntimeException)
@@ -261,6 +281,8 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         | SignalJniError::Signal(SignalProtocolError::SignatureValidationFailed)
         | SignalJniError::Signal(SignalProtocolError::BadKeyType(_))
         | SignalJniError::Signal(SignalProtocolError::BadKeyLength(_, _))
+        | SignalJniError::Signal(SignalProtocolError::InvalidKeyEncoding(_))
+        | SignalJniError::Signal(SignalProtocolError::MismatchedKeyTypes(_, _))
         | SignalJniError::Signal(SignalProtocolError::InvalidMacKeyLength(_))
         | SignalJniError::Signal(SignalProtocolError::BadKEMKeyType(_))
         | SignalJniError::Signal(SignalProtocolError::WrongKEMKeyType(_, _))
@@ -281,14 +303,20 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
         | SignalJniError::Signal(SignalProtocolError::CiphertextMessageTooShort(_))
         | SignalJniError::Signal(SignalProtocolError::InvalidProtobufEncoding)
         | SignalJniError::Signal(SignalProtocolError::InvalidSealedSenderMessage(_))
+        | SignalJniError::Signal(SignalProtocolError::EnvelopeTooOld { .. })
+        | SignalJniError::Signal(SignalProtocolError::EnvelopeFromFuture { .. })
         | SignalJniError::Signal(SignalProtocolError::BadKEMCiphertextLength(_, _))
+        | SignalJniError::Signal(SignalProtocolError::WorkLimitExceeded(_))
+        | SignalJniError::Signal(SignalProtocolError::InvalidPadding(_))
+        | SignalJniError::Signal(SignalProtocolError::InvalidProfileCiphertext(_))
         | SignalJniError::SignalCrypto(SignalCryptoError::InvalidTag) => {
             jni_class_name!(org.signal.libsignal.protocol.InvalidMessageException)
         }
 
         SignalJniError::Signal(SignalProtocolError::UnrecognizedCiphertextVersion(_))
         | SignalJniError::Signal(SignalProtocolError::UnrecognizedMessageVersion(_))
-        | SignalJniError::Signal(SignalProtocolError::UnknownSealedSenderVersion(_)) => {
+        | SignalJniError::Signal(SignalProtocolError::UnknownSealedSenderVersion(_))
+        | SignalJniError::Signal(SignalProtocolError::UnrecognizedRecordVersion(_, _)) => {
             jni_class_name!(org.signal.libsignal.protocol.InvalidVersionException)
         }
 
@@ -306,8 +334,22 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
             )
         }
 
+        SignalJniError::Signal(SignalProtocolError::RecordIntegrityCheckFailed(_)) => {
+            jni_class_name!(
+                org.signal
+                    .libsignal
+                    .protocol
+                    .RecordIntegrityCheckFailedException
+            )
+        }
+
+        SignalJniError::Signal(SignalProtocolError::RngHealthCheckFailed(_)) => {
+            jni_class_name!(org.signal.libsignal.protocol.RngHealthCheckFailedException)
+        }
+
         SignalJniError::Signal(SignalProtocolError::SealedSenderSelfSend)
         | SignalJniError::Signal(SignalProtocolError::UntrustedIdentity(_))
+        | SignalJniError::Signal(SignalProtocolError::IdentityKeyChanged(_))
         | SignalJniError::Signal(SignalProtocolError::FingerprintVersionMismatch(_, _))
         | SignalJniError::Signal(SignalProtocolError::SessionNotFound(..))
         | SignalJniError::Signal(SignalProtocolError::InvalidRegistrationId(..))
@@ -318,6 +360,10 @@ fn throw_error(env: &JNIEnv, error: SignalJniError) {
             unreachable!("already handled in prior match")
         }
 
+        // SignalProtocolError is non_exhaustive. Variants added to it should get an arm of their
+        // own above rather than falling through to this one.
+        SignalJniError::Signal(_) => jni_class_name!(java.lang.RuntimeException),
+
         SignalJniError::HsmEnclave(HsmEnclaveError::HSMHandshakeError(_))
         | SignalJniError::HsmEnclave(HsmEnclaveError::HSMCommunicationError(_)) => {
             jni_class_name!(
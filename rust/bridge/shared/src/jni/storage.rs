@@ -566,20 +566,22 @@ impl<'a> JniSenderKeyStore<'a> {
 impl<'a> SenderKeyStore for JniSenderKeyStore<'a> {
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         record: &SenderKeyRecord,
         _ctx: Context,
     ) -> Result<(), SignalProtocolError> {
-        Ok(self.do_store_sender_key(sender, distribution_id, record)?)
+        Ok(self.do_store_sender_key(
+            sender_key_name.sender(),
+            sender_key_name.distribution_id(),
+            record,
+        )?)
     }
 
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         _ctx: Context,
     ) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
-        Ok(self.do_load_sender_key(sender, distribution_id)?)
+        Ok(self.do_load_sender_key(sender_key_name.sender(), sender_key_name.distribution_id())?)
     }
 }
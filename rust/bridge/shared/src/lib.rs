@@ -43,6 +43,9 @@ pub mod zkgroup;
 #[cfg(feature = "ffi")]
 pub mod ias;
 
+#[cfg(feature = "ffi")]
+mod cancellation;
+
 // Desktop does not use SVR
 #[cfg(any(feature = "jni", feature = "ffi"))]
 mod pin;
@@ -258,7 +258,7 @@ impl SimpleArgTypeInfo for crate::protocol::Timestamp {
         if !can_convert_js_number_to_int(value, 0.0..=MAX_SAFE_JS_INTEGER) {
             return cx.throw_range_error(format!("cannot convert {} to Timestamp (u64)", value));
         }
-        Ok(Self::from_millis(value as u64))
+        Ok(Self::from_epoch_millis(value as u64))
     }
 }
 
@@ -588,11 +588,11 @@ impl<'a> ResultTypeInfo<'a> for bool {
 impl<'a> ResultTypeInfo<'a> for crate::protocol::Timestamp {
     type ResultType = JsNumber;
     fn convert_into(self, cx: &mut impl Context<'a>) -> NeonResult<Handle<'a, Self::ResultType>> {
-        let result = self.as_millis() as f64;
+        let result = self.epoch_millis() as f64;
         if result > MAX_SAFE_JS_INTEGER {
             cx.throw_range_error(format!(
                 "precision loss during conversion of {} to f64",
-                self.as_millis()
+                self.epoch_millis()
             ))?;
         }
         Ok(cx.number(result))
@@ -787,6 +787,21 @@ full_range_integer!(u8);
 full_range_integer!(u32);
 full_range_integer!(i32);
 
+/// Converts the same values as `u32`; the ID is otherwise unchecked.
+impl SimpleArgTypeInfo for libsignal_protocol::DeviceId {
+    type ArgType = JsNumber;
+    fn convert_from(cx: &mut FunctionContext, foreign: Handle<Self::ArgType>) -> NeonResult<Self> {
+        u32::convert_from(cx, foreign).map(Self::from)
+    }
+}
+
+impl<'a> ResultTypeInfo<'a> for libsignal_protocol::DeviceId {
+    type ResultType = JsNumber;
+    fn convert_into(self, cx: &mut impl Context<'a>) -> NeonResult<Handle<'a, Self::ResultType>> {
+        u32::from(self).convert_into(cx)
+    }
+}
+
 impl<T> SimpleArgTypeInfo for Serialized<T>
 where
     T: FixedLengthBincodeSerializable + for<'a> serde::Deserialize<'a>,
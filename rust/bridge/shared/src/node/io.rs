@@ -14,6 +14,12 @@ use std::cell::Cell;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::sync::Arc;
 
+/// Wraps a JavaScript `InputStream` so Rust code can call into it.
+///
+/// ts: export abstract class InputStream {
+/// ts:   _read(amount: u32): Promise<Vec<u8>>
+/// ts:   _skip(amount: f64): Promise<()>
+/// ts: }
 pub struct NodeInputStream {
     js_channel: Channel,
     stream_object: Arc<Root<JsObject>>,
@@ -35,7 +41,7 @@ impl NodeInputStream {
             let stream_object = stream_object_shared.to_inner(cx);
             let amount: Handle<JsNumber> = amount.convert_into(cx)?;
             let result = call_method(cx, stream_object, "_read", [amount.upcast()])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             stream_object_shared.finalize(cx);
             Ok(result)
         })
@@ -67,7 +73,7 @@ impl NodeInputStream {
             let stream_object = stream_object_shared.to_inner(cx);
             let amount = cx.number(amount);
             let result = call_method(cx, stream_object, "_skip", [amount.upcast()])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             stream_object_shared.finalize(cx);
             Ok(result)
         })
@@ -11,6 +11,13 @@ use std::cell::RefCell;
 use std::sync::Arc;
 use uuid::Uuid;
 
+/// Wraps a JavaScript `PreKeyStore` so Rust code can call into it.
+///
+/// ts: export abstract class PreKeyStore {
+/// ts:   _savePreKey(pre_key_id: u32, record: PreKeyRecord): Promise<()>
+/// ts:   _getPreKey(pre_key_id: u32): Promise<PreKeyRecord>
+/// ts:   _removePreKey(pre_key_id: u32): Promise<()>
+/// ts: }
 pub struct NodePreKeyStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -30,7 +37,7 @@ impl NodePreKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let id = id.convert_into(cx)?;
             let result = call_method(cx, store_object, "_getPreKey", [id.upcast()])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -53,8 +60,8 @@ impl NodePreKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let id: Handle<JsNumber> = id.convert_into(cx)?;
             let record: Handle<JsValue> = record.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_savePreKey", [id.upcast(), record])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_savePreKey", [id.upcast(), record])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -76,8 +83,8 @@ impl NodePreKeyStore {
         JsFuture::get_promise(&self.js_channel, move |cx| {
             let store_object = store_object_shared.to_inner(cx);
             let id: Handle<JsNumber> = id.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_removePreKey", [id.upcast()])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_removePreKey", [id.upcast()])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -135,6 +142,12 @@ impl PreKeyStore for NodePreKeyStore {
     }
 }
 
+/// Wraps a JavaScript `SignedPreKeyStore` so Rust code can call into it.
+///
+/// ts: export abstract class SignedPreKeyStore {
+/// ts:   _saveSignedPreKey(signed_pre_key_id: u32, record: SignedPreKeyRecord): Promise<()>
+/// ts:   _getSignedPreKey(signed_pre_key_id: u32): Promise<SignedPreKeyRecord>
+/// ts: }
 pub struct NodeSignedPreKeyStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -154,7 +167,7 @@ impl NodeSignedPreKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let id = id.convert_into(cx)?;
             let result = call_method(cx, store_object, "_getSignedPreKey", [id.upcast()])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -181,8 +194,8 @@ impl NodeSignedPreKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let id: Handle<JsNumber> = id.convert_into(cx)?;
             let record: Handle<JsValue> = record.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_saveSignedPreKey", [id.upcast(), record])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_saveSignedPreKey", [id.upcast(), record])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -230,6 +243,13 @@ impl SignedPreKeyStore for NodeSignedPreKeyStore {
     }
 }
 
+/// Wraps a JavaScript `KyberPreKeyStore` so Rust code can call into it.
+///
+/// ts: export abstract class KyberPreKeyStore {
+/// ts:   _saveKyberPreKey(kyber_pre_key_id: u32, record: KyberPreKeyRecord): Promise<()>
+/// ts:   _getKyberPreKey(kyber_pre_key_id: u32): Promise<KyberPreKeyRecord>
+/// ts:   _markKyberPreKeyUsed(kyber_pre_key_id: u32): Promise<()>
+/// ts: }
 pub struct NodeKyberPreKeyStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -249,7 +269,7 @@ impl NodeKyberPreKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let id = id.convert_into(cx)?;
             let result = call_method(cx, store_object, "_getKyberPreKey", [id.upcast()])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -276,8 +296,8 @@ impl NodeKyberPreKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let id: Handle<JsNumber> = id.convert_into(cx)?;
             let record: Handle<JsValue> = record.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_saveKyberPreKey", [id.upcast(), record])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_saveKyberPreKey", [id.upcast(), record])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -299,8 +319,8 @@ impl NodeKyberPreKeyStore {
         JsFuture::get_promise(&self.js_channel, move |cx| {
             let store_object = store_object_shared.to_inner(cx);
             let id: Handle<JsNumber> = id.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_markKyberPreKeyUsed", [id.upcast()])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_markKyberPreKeyUsed", [id.upcast()])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -358,6 +378,12 @@ impl KyberPreKeyStore for NodeKyberPreKeyStore {
     }
 }
 
+/// Wraps a JavaScript `SessionStore` so Rust code can call into it.
+///
+/// ts: export abstract class SessionStore {
+/// ts:   _saveSession(addr: ProtocolAddress, record: SessionRecord): Promise<()>
+/// ts:   _getSession(addr: ProtocolAddress): Promise<Option<SessionRecord>>
+/// ts: }
 pub struct NodeSessionStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -377,7 +403,7 @@ impl NodeSessionStore {
             let store_object = store_object_shared.to_inner(cx);
             let name: Handle<JsValue> = name.convert_into(cx)?;
             let result = call_method(cx, store_object, "_getSession", [name])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -410,8 +436,8 @@ impl NodeSessionStore {
             let store_object = store_object_shared.to_inner(cx);
             let name = name.convert_into(cx)?;
             let record = record.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_saveSession", [name, record.upcast()])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_saveSession", [name, record.upcast()])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -459,6 +485,15 @@ impl SessionStore for NodeSessionStore {
     }
 }
 
+/// Wraps a JavaScript `IdentityKeyStore` so Rust code can call into it.
+///
+/// ts: export abstract class IdentityKeyStore {
+/// ts:   _getIdentityKey(): Promise<PrivateKey>
+/// ts:   _getLocalRegistrationId(): Promise<u32>
+/// ts:   _saveIdentity(name: ProtocolAddress, key: PublicKey): Promise<bool>
+/// ts:   _isTrustedIdentity(name: ProtocolAddress, key: PublicKey, sending: bool): Promise<bool>
+/// ts:   _getIdentity(name: ProtocolAddress): Promise<Option<PublicKey>>
+/// ts: }
 pub struct NodeIdentityKeyStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -477,7 +512,7 @@ impl NodeIdentityKeyStore {
         JsFuture::get_promise(&self.js_channel, move |cx| {
             let store_object = store_object_shared.to_inner(cx);
             let result = call_method(cx, store_object, "_getIdentityKey", [])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -498,8 +533,8 @@ impl NodeIdentityKeyStore {
         let store_object_shared = self.store_object.clone();
         JsFuture::get_promise(&self.js_channel, move |cx| {
             let store_object = store_object_shared.to_inner(cx);
-            let result = call_method(cx, store_object, "_getLocalRegistrationId", [])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_getLocalRegistrationId", [])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -522,7 +557,7 @@ impl NodeIdentityKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let name: Handle<JsValue> = name.convert_into(cx)?;
             let result = call_method(cx, store_object, "_getIdentity", [name])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -555,8 +590,8 @@ impl NodeIdentityKeyStore {
             let store_object = store_object_shared.to_inner(cx);
             let name: Handle<JsValue> = name.convert_into(cx)?;
             let key: Handle<JsValue> = key.convert_into(cx)?;
-            let result = call_method(cx, store_object, "_saveIdentity", [name, key])?
-                .downcast_or_throw(cx)?;
+            let result = call_method(cx, store_object, "_saveIdentity", [name, key])?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -592,8 +627,8 @@ impl NodeIdentityKeyStore {
                 store_object,
                 "_isTrustedIdentity",
                 [name, key, sending.upcast()],
-            )?
-            .downcast_or_throw(cx)?;
+            )?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -676,6 +711,12 @@ impl IdentityKeyStore for NodeIdentityKeyStore {
     }
 }
 
+/// Wraps a JavaScript `SenderKeyStore` so Rust code can call into it.
+///
+/// ts: export abstract class SenderKeyStore {
+/// ts:   _saveSenderKey(sender: ProtocolAddress, distribution_id: Uuid, record: SenderKeyRecord): Promise<()>
+/// ts:   _getSenderKey(sender: ProtocolAddress, distribution_id: Uuid): Promise<Option<SenderKeyRecord>>
+/// ts: }
 pub struct NodeSenderKeyStore {
     js_channel: Channel,
     store_object: Arc<Root<JsObject>>,
@@ -700,7 +741,7 @@ impl NodeSenderKeyStore {
             let sender: Handle<JsValue> = sender.convert_into(cx)?;
             let distribution_id: Handle<JsValue> = distribution_id.convert_into(cx)?.upcast();
             let result = call_method(cx, store_object, "_getSenderKey", [sender, distribution_id])?;
-            let result = result.downcast_or_throw(cx)?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -740,8 +781,8 @@ impl NodeSenderKeyStore {
                 store_object,
                 "_saveSenderKey",
                 [sender, distribution_id, record],
-            )?
-            .downcast_or_throw(cx)?;
+            )?;
+            let result = resolve_promise(cx, result)?;
             store_object_shared.finalize(cx);
             Ok(result)
         })
@@ -769,24 +810,29 @@ impl Finalize for NodeSenderKeyStore {
 impl SenderKeyStore for NodeSenderKeyStore {
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         _ctx: libsignal_protocol::Context,
     ) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
-        self.do_get_sender_key(sender.clone(), distribution_id)
-            .await
-            .map_err(|s| js_error_to_rust("getSenderKey", s))
+        self.do_get_sender_key(
+            sender_key_name.sender().clone(),
+            sender_key_name.distribution_id(),
+        )
+        .await
+        .map_err(|s| js_error_to_rust("getSenderKey", s))
     }
 
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         record: &SenderKeyRecord,
         _ctx: libsignal_protocol::Context,
     ) -> Result<(), SignalProtocolError> {
-        self.do_save_sender_key(sender.clone(), distribution_id, record.clone())
-            .await
-            .map_err(|s| js_error_to_rust("saveSenderKey", s))
+        self.do_save_sender_key(
+            sender_key_name.sender().clone(),
+            sender_key_name.distribution_id(),
+            record.clone(),
+        )
+        .await
+        .map_err(|s| js_error_to_rust("saveSenderKey", s))
     }
 }
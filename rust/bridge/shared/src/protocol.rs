@@ -49,24 +49,7 @@ bridge_handle!(KyberKeyPair);
 bridge_handle!(KyberPublicKey);
 bridge_handle!(KyberSecretKey);
 
-#[derive(Clone, Copy, Debug)]
-pub(crate) struct Timestamp(u64);
-
-impl Timestamp {
-    pub(crate) fn from_millis(millis: u64) -> Self {
-        Self(millis)
-    }
-
-    pub(crate) fn as_millis(self) -> u64 {
-        self.0
-    }
-}
-
-impl From<u64> for Timestamp {
-    fn from(value: u64) -> Self {
-        Self::from_millis(value)
-    }
-}
+pub(crate) use libsignal_protocol::Timestamp;
 
 #[bridge_fn(ffi = false)]
 fn HKDF_DeriveSecrets(
@@ -129,8 +112,8 @@ fn ServiceId_ParseFromServiceIdString(input: String) -> Result<ServiceId> {
 }
 
 #[bridge_fn(ffi = "address_new")]
-fn ProtocolAddress_New(name: String, device_id: u32) -> ProtocolAddress {
-    ProtocolAddress::new(name, device_id.into())
+fn ProtocolAddress_New(name: String, device_id: DeviceId) -> ProtocolAddress {
+    ProtocolAddress::new(name, device_id)
 }
 
 #[bridge_fn(ffi = "publickey_deserialize", jni = false)]
@@ -155,7 +138,7 @@ bridge_get!(
     ffi = "publickey_get_public_key_bytes",
     jni = "ECPublicKey_1GetPublicKeyBytes"
 );
-bridge_get!(ProtocolAddress::device_id as DeviceId -> u32, ffi = "address_get_device_id");
+bridge_get!(ProtocolAddress::device_id as DeviceId -> DeviceId, ffi = "address_get_device_id");
 bridge_get!(ProtocolAddress::name as Name -> &str, ffi = "address_get_name");
 
 #[bridge_fn(ffi = "publickey_equals", node = "PublicKey_Equals")]
@@ -177,6 +160,19 @@ fn ECPublicKey_Verify(key: &PublicKey, message: &[u8], signature: &[u8]) -> Resu
     key.verify_signature(message, signature)
 }
 
+#[bridge_fn(
+    ffi = "publickey_verify_with_label",
+    node = "PublicKey_VerifyWithLabel"
+)]
+fn ECPublicKey_VerifyWithLabel(
+    key: &PublicKey,
+    label: String,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool> {
+    key.verify_signature_with_label(&label, message, signature)
+}
+
 #[bridge_fn(ffi = "privatekey_deserialize", jni = "ECPrivateKey_1Deserialize")]
 fn PrivateKey_Deserialize(data: &[u8]) -> Result<PrivateKey> {
     PrivateKey::deserialize(data)
@@ -206,6 +202,14 @@ fn ECPrivateKey_Sign(key: &PrivateKey, message: &[u8]) -> Result<Vec<u8>> {
     Ok(key.calculate_signature(message, &mut rng)?.into_vec())
 }
 
+#[bridge_fn(ffi = "privatekey_sign_with_label", node = "PrivateKey_SignWithLabel")]
+fn ECPrivateKey_SignWithLabel(key: &PrivateKey, label: String, message: &[u8]) -> Result<Vec<u8>> {
+    let mut rng = rand::rngs::OsRng;
+    Ok(key
+        .calculate_signature_with_label(&label, message, &mut rng)?
+        .into_vec())
+}
+
 #[bridge_fn(ffi = "privatekey_agree", node = "PrivateKey_Agree")]
 fn ECPrivateKey_Agree(private_key: &PrivateKey, public_key: &PublicKey) -> Result<Vec<u8>> {
     Ok(private_key.calculate_agreement(public_key)?.into_vec())
@@ -393,8 +397,8 @@ fn SignalMessage_VerifyMac(
 }
 
 #[bridge_fn(ffi = "message_get_sender_ratchet_key", node = false)]
-fn SignalMessage_GetSenderRatchetKey(m: &SignalMessage) -> PublicKey {
-    *m.sender_ratchet_key()
+fn SignalMessage_GetSenderRatchetKey(m: &SignalMessage) -> Result<PublicKey> {
+    Ok(*m.sender_ratchet_key()?)
 }
 
 #[bridge_fn]
@@ -419,6 +423,23 @@ fn PreKeySignalMessage_New(
     )
 }
 
+#[bridge_fn]
+fn PreKeySignalMessage_WithAlternateIdentitySignature(
+    m: &PreKeySignalMessage,
+    signature: &[u8],
+) -> PreKeySignalMessage {
+    m.clone()
+        .with_alternate_identity_signature(signature.into())
+}
+
+#[bridge_fn]
+fn PreKeySignalMessage_VerifyAlternateIdentity(
+    m: &PreKeySignalMessage,
+    alternate_identity: &PublicKey,
+) -> Result<bool> {
+    m.verify_alternate_identity(&IdentityKey::new(*alternate_identity))
+}
+
 #[bridge_fn(node = false)]
 fn PreKeySignalMessage_GetBaseKey(m: &PreKeySignalMessage) -> PublicKey {
     *m.base_key()
@@ -546,7 +567,7 @@ fn DecryptionErrorMessage_ForOriginalMessage(
     DecryptionErrorMessage::for_original(
         original_bytes,
         original_type,
-        original_timestamp.as_millis(),
+        original_timestamp,
         original_sender_device_id,
     )
 }
@@ -582,7 +603,7 @@ fn PlaintextContent_DeserializeAndGetContent(bytes: &[u8]) -> Result<Vec<u8>> {
 #[bridge_fn(jni = "PreKeyBundle_1New")]
 fn PreKeyBundle_New(
     registration_id: u32,
-    device_id: u32,
+    device_id: DeviceId,
     prekey_id: Option<u32>,
     prekey: Option<&PublicKey>,
     signed_prekey_id: u32,
@@ -607,7 +628,7 @@ fn PreKeyBundle_New(
 
     let bundle = PreKeyBundle::new(
         registration_id,
-        device_id.into(),
+        device_id,
         prekey,
         signed_prekey_id.into(),
         *signed_prekey,
@@ -632,7 +653,7 @@ fn PreKeyBundle_GetIdentityKey(p: &PreKeyBundle) -> Result<PublicKey> {
 
 bridge_get!(PreKeyBundle::signed_pre_key_signature -> &[u8]);
 bridge_get!(PreKeyBundle::registration_id -> u32);
-bridge_get!(PreKeyBundle::device_id -> u32);
+bridge_get!(PreKeyBundle::device_id -> DeviceId);
 bridge_get!(PreKeyBundle::signed_pre_key_id -> u32);
 bridge_get!(PreKeyBundle::pre_key_id -> Option<u32>);
 bridge_get!(PreKeyBundle::pre_key_public -> Option<PublicKey>);
@@ -684,7 +705,7 @@ fn SignedPreKeyRecord_New(
     signature: &[u8],
 ) -> SignedPreKeyRecord {
     let keypair = KeyPair::new(*pub_key, *priv_key);
-    SignedPreKeyRecord::new(id.into(), timestamp.as_millis(), &keypair, signature)
+    SignedPreKeyRecord::new(id.into(), timestamp, &keypair, signature)
 }
 
 #[bridge_fn]
@@ -694,7 +715,7 @@ fn KyberPreKeyRecord_New(
     key_pair: &KyberKeyPair,
     signature: &[u8],
 ) -> KyberPreKeyRecord {
-    KyberPreKeyRecord::new(id.into(), timestamp.as_millis(), key_pair, signature)
+    KyberPreKeyRecord::new(id.into(), timestamp, key_pair, signature)
 }
 
 bridge_deserialize!(PreKeyRecord::deserialize);
@@ -742,7 +763,7 @@ bridge_get!(SenderCertificate::signature -> &[u8]);
 bridge_get!(SenderCertificate::sender_uuid -> &str);
 bridge_get!(SenderCertificate::sender_e164 -> Option<&str>);
 bridge_get!(SenderCertificate::expiration -> Timestamp);
-bridge_get!(SenderCertificate::sender_device_id as GetDeviceId -> u32);
+bridge_get!(SenderCertificate::sender_device_id as GetDeviceId -> DeviceId);
 bridge_get!(SenderCertificate::key -> PublicKey);
 
 #[bridge_fn]
@@ -751,7 +772,7 @@ fn SenderCertificate_Validate(
     key: &PublicKey,
     time: Timestamp,
 ) -> Result<bool> {
-    cert.validate(key, time.as_millis())
+    cert.validate(key, time)
 }
 
 #[bridge_fn]
@@ -763,7 +784,7 @@ fn SenderCertificate_GetServerCertificate(cert: &SenderCertificate) -> Result<Se
 fn SenderCertificate_New(
     sender_uuid: String,
     sender_e164: Option<String>,
-    sender_device_id: u32,
+    sender_device_id: DeviceId,
     sender_key: &PublicKey,
     expiration: Timestamp,
     signer_cert: &ServerCertificate,
@@ -775,8 +796,8 @@ fn SenderCertificate_New(
         sender_uuid,
         sender_e164,
         *sender_key,
-        sender_device_id.into(),
-        expiration.as_millis(),
+        sender_device_id,
+        expiration,
         signer_cert.clone(),
         signer_key,
         &mut rng,
@@ -985,7 +1006,7 @@ bridge_get!(SessionRecord::has_sender_chain as HasSenderChain -> bool, ffi = fal
 
 bridge_get!(SealedSenderDecryptionResult::sender_uuid -> String, ffi = false, jni = false);
 bridge_get!(SealedSenderDecryptionResult::sender_e164 -> Option<String>, ffi = false, jni = false);
-bridge_get!(SealedSenderDecryptionResult::device_id -> u32, ffi = false, jni = false);
+bridge_get!(SealedSenderDecryptionResult::device_id -> DeviceId, ffi = false, jni = false);
 bridge_get!(
     SealedSenderDecryptionResult::message as Message -> &[u8],
     ffi = false,
@@ -1117,6 +1138,26 @@ async fn SessionCipher_EncryptMessage(
     .await
 }
 
+#[bridge_fn(ffi = "encrypt_message_with_alternate_identity_signature")]
+async fn SessionCipher_EncryptMessageWithAlternateIdentitySignature(
+    ptext: &[u8],
+    protocol_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_key_store: &mut dyn IdentityKeyStore,
+    alternate_identity_signature: &[u8],
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt_with_alternate_identity_signature(
+        ptext,
+        protocol_address,
+        session_store,
+        identity_key_store,
+        alternate_identity_signature,
+        ctx,
+    )
+    .await
+}
+
 #[bridge_fn(ffi = "decrypt_message")]
 async fn SessionCipher_DecryptSignalMessage(
     message: &SignalMessage,
@@ -1243,25 +1284,27 @@ async fn SealedSender_DecryptMessage(
     timestamp: Timestamp,
     local_e164: Option<String>,
     local_uuid: String,
-    local_device_id: u32,
+    local_device_id: DeviceId,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     prekey_store: &mut dyn PreKeyStore,
     signed_prekey_store: &mut dyn SignedPreKeyStore,
     kyber_prekey_store: &mut dyn KyberPreKeyStore,
 ) -> Result<SealedSenderDecryptionResult> {
+    let mut csprng = rand::rngs::OsRng;
     sealed_sender_decrypt(
         message,
         trust_root,
-        timestamp.as_millis(),
+        timestamp,
         local_e164,
         local_uuid,
-        local_device_id.into(),
+        local_device_id,
         identity_store,
         session_store,
         prekey_store,
         signed_prekey_store,
         kyber_prekey_store,
+        &mut csprng,
         None,
     )
     .await
@@ -1314,3 +1357,29 @@ async fn GroupCipher_DecryptMessage(
 ) -> Result<Vec<u8>> {
     group_decrypt(message, store, sender, ctx).await
 }
+
+#[allow(clippy::too_many_arguments)]
+#[bridge_fn_void]
+async fn StoreConformance_Check(
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    let mut csprng = rand::rngs::OsRng;
+    conformance::check_store_conformance(
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        sender_key_store,
+        &mut csprng,
+        ctx,
+    )
+    .await
+    .into_result()
+}
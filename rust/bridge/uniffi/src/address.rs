@@ -0,0 +1,28 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use libsignal_protocol::ProtocolAddress;
+
+/// A particular device of a particular user, as in [`ProtocolAddress`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, uniffi::Record)]
+pub struct Address {
+    pub name: String,
+    pub device_id: u32,
+}
+
+impl From<&ProtocolAddress> for Address {
+    fn from(address: &ProtocolAddress) -> Self {
+        Self {
+            name: address.name().to_owned(),
+            device_id: address.device_id().into(),
+        }
+    }
+}
+
+impl From<Address> for ProtocolAddress {
+    fn from(address: Address) -> Self {
+        ProtocolAddress::new(address.name, address.device_id.into())
+    }
+}
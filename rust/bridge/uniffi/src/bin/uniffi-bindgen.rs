@@ -0,0 +1,8 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
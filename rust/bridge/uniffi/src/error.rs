@@ -0,0 +1,149 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::fmt;
+
+use libsignal_protocol::SignalProtocolError;
+
+use crate::Address;
+
+/// The error thrown by every fallible function in these bindings.
+///
+/// Store implementations may throw it as well; it is passed back to the caller unchanged.
+#[derive(Debug, uniffi::Error)]
+pub enum SignalError {
+    InvalidArgument {
+        message: String,
+    },
+    InvalidKey {
+        message: String,
+    },
+    InvalidMessage {
+        message: String,
+    },
+    UntrustedIdentity {
+        address: Address,
+    },
+    SessionNotFound {
+        address: Address,
+    },
+    DuplicatedMessage {
+        chain_index: u32,
+        counter: u32,
+    },
+    NoSenderKeyState {
+        distribution_id: String,
+    },
+    /// A store callback failed without throwing a [`SignalError`] of its own.
+    Store {
+        message: String,
+    },
+    Other {
+        message: String,
+    },
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidArgument { message }
+            | Self::InvalidKey { message }
+            | Self::InvalidMessage { message }
+            | Self::Store { message }
+            | Self::Other { message } => write!(f, "{}", message),
+            Self::UntrustedIdentity { address } => {
+                write!(
+                    f,
+                    "untrusted identity for {}.{}",
+                    address.name, address.device_id
+                )
+            }
+            Self::SessionNotFound { address } => {
+                write!(
+                    f,
+                    "session not found for {}.{}",
+                    address.name, address.device_id
+                )
+            }
+            Self::DuplicatedMessage {
+                chain_index,
+                counter,
+            } => write!(f, "message with old counter {} / {}", chain_index, counter),
+            Self::NoSenderKeyState { distribution_id } => {
+                write!(
+                    f,
+                    "missing sender key state for distribution ID {}",
+                    distribution_id
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignalError {}
+
+impl From<SignalProtocolError> for SignalError {
+    fn from(error: SignalProtocolError) -> Self {
+        use SignalProtocolError as E;
+        let message = error.to_string();
+        match error {
+            E::ApplicationCallbackError(_, inner) => {
+                let inner: Box<dyn std::error::Error + Send + Sync> = inner;
+                match inner.downcast::<SignalError>() {
+                    Ok(original) => *original,
+                    Err(_) => Self::Store { message },
+                }
+            }
+            E::InvalidArgument(_) => Self::InvalidArgument { message },
+            E::NoKeyTypeIdentifier
+            | E::BadKeyType(_)
+            | E::BadKeyLength(..)
+            | E::InvalidKeyEncoding(_)
+            | E::MismatchedKeyTypes(..)
+            | E::BadKEMKeyType(_)
+            | E::WrongKEMKeyType(..)
+            | E::BadKEMKeyLength(..) => Self::InvalidKey { message },
+            E::InvalidProtobufEncoding
+            | E::CiphertextMessageTooShort(_)
+            | E::LegacyCiphertextVersion(_)
+            | E::UnrecognizedCiphertextVersion(_)
+            | E::UnrecognizedMessageVersion(_)
+            | E::SignatureValidationFailed
+            | E::InvalidMessage(..)
+            | E::InvalidSealedSenderMessage(_)
+            | E::UnknownSealedSenderVersion(_)
+            | E::BadKEMCiphertextLength(..) => Self::InvalidMessage { message },
+            E::UntrustedIdentity(address) | E::IdentityKeyChanged(address) => {
+                Self::UntrustedIdentity {
+                    address: (&address).into(),
+                }
+            }
+            E::SessionNotFound(address) => Self::SessionNotFound {
+                address: (&address).into(),
+            },
+            E::DuplicatedMessage(chain_index, counter) => Self::DuplicatedMessage {
+                chain_index,
+                counter,
+            },
+            E::NoSenderKeyState { distribution_id } => Self::NoSenderKeyState {
+                distribution_id: distribution_id.to_string(),
+            },
+            _ => Self::Other { message },
+        }
+    }
+}
+
+impl From<uniffi::UnexpectedUniFFICallbackError> for SignalError {
+    fn from(error: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        Self::Store {
+            message: error.reason,
+        }
+    }
+}
+
+/// Wraps an error thrown by a store so that it survives the trip through the protocol code.
+pub(crate) fn callback_error(method: &'static str, error: SignalError) -> SignalProtocolError {
+    SignalProtocolError::ApplicationCallbackError(method, Box::new(error))
+}
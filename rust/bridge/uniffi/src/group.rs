@@ -0,0 +1,85 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use libsignal_protocol::{self as protocol, SenderKeyDistributionMessage};
+use uuid::Uuid;
+
+use crate::storage::Adapter;
+use crate::{run, Address, SenderKeyStore, SignalError};
+
+fn parse_distribution_id(distribution_id: &str) -> Result<Uuid, SignalError> {
+    Uuid::parse_str(distribution_id).map_err(|e| SignalError::InvalidArgument {
+        message: format!("invalid distribution ID: {}", e),
+    })
+}
+
+/// Creates (or reuses) the local sender key for `distribution_id`, returning the serialized
+/// distribution message to send to the other members.
+#[uniffi::export]
+pub fn create_sender_key_distribution_message(
+    sender: Address,
+    distribution_id: String,
+    store: Arc<dyn SenderKeyStore>,
+) -> Result<Vec<u8>, SignalError> {
+    let message = run(protocol::create_sender_key_distribution_message(
+        &sender.into(),
+        parse_distribution_id(&distribution_id)?,
+        &mut Adapter(&*store),
+        &mut rand::rngs::OsRng,
+        None,
+    ))?;
+    Ok(message.serialized().to_vec())
+}
+
+#[uniffi::export]
+pub fn process_sender_key_distribution_message(
+    sender: Address,
+    message: Vec<u8>,
+    store: Arc<dyn SenderKeyStore>,
+) -> Result<(), SignalError> {
+    let message = SenderKeyDistributionMessage::try_from(&message[..])?;
+    run(protocol::process_sender_key_distribution_message(
+        &sender.into(),
+        &message,
+        &mut Adapter(&*store),
+        None,
+    ))?;
+    Ok(())
+}
+
+#[uniffi::export]
+pub fn group_encrypt(
+    sender: Address,
+    distribution_id: String,
+    plaintext: Vec<u8>,
+    store: Arc<dyn SenderKeyStore>,
+) -> Result<Vec<u8>, SignalError> {
+    let message = run(protocol::group_encrypt(
+        &mut Adapter(&*store),
+        &sender.into(),
+        parse_distribution_id(&distribution_id)?,
+        &plaintext,
+        &mut rand::rngs::OsRng,
+        None,
+    ))?;
+    Ok(message.serialized().to_vec())
+}
+
+#[uniffi::export]
+pub fn group_decrypt(
+    sender: Address,
+    message: Vec<u8>,
+    store: Arc<dyn SenderKeyStore>,
+) -> Result<Vec<u8>, SignalError> {
+    Ok(run(protocol::group_decrypt(
+        &message,
+        &mut Adapter(&*store),
+        &sender.into(),
+        None,
+    ))?)
+}
@@ -0,0 +1,166 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::convert::TryFrom;
+
+use libsignal_protocol::{
+    self as protocol, kem, GenericSignedPreKey, IdentityKey, IdentityKeyPair, KeyPair,
+    KyberPreKeyRecord, PreKeyRecord, PublicKey, SignedPreKeyRecord, Timestamp,
+};
+
+use crate::SignalError;
+
+/// A newly generated pre-key, ready to be stored locally and uploaded.
+#[derive(uniffi::Record)]
+pub struct GeneratedPreKey {
+    pub id: u32,
+    /// The serialized record, for the local [`PreKeyStore`](crate::PreKeyStore) (or
+    /// [`SignedPreKeyStore`](crate::SignedPreKeyStore), or
+    /// [`KyberPreKeyStore`](crate::KyberPreKeyStore)).
+    pub record: Vec<u8>,
+    pub public_key: Vec<u8>,
+    /// The signature over `public_key` by the identity key, if this is a signed pre-key.
+    pub signature: Option<Vec<u8>>,
+}
+
+/// Another user's pre-key bundle, as fetched from the server.
+#[derive(uniffi::Record)]
+pub struct PreKeyBundle {
+    pub registration_id: u32,
+    pub device_id: u32,
+    pub pre_key_id: Option<u32>,
+    pub pre_key_public: Option<Vec<u8>>,
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key_public: Vec<u8>,
+    pub signed_pre_key_signature: Vec<u8>,
+    pub identity_key: Vec<u8>,
+    pub kyber_pre_key_id: Option<u32>,
+    pub kyber_pre_key_public: Option<Vec<u8>>,
+    pub kyber_pre_key_signature: Option<Vec<u8>>,
+}
+
+impl TryFrom<PreKeyBundle> for protocol::PreKeyBundle {
+    type Error = SignalError;
+
+    fn try_from(bundle: PreKeyBundle) -> Result<Self, SignalError> {
+        let pre_key = match (bundle.pre_key_id, bundle.pre_key_public) {
+            (Some(id), Some(public)) => Some((id.into(), PublicKey::deserialize(&public)?)),
+            (None, None) => None,
+            _ => {
+                return Err(SignalError::InvalidArgument {
+                    message: "pre-key ID and public key must be given together".to_owned(),
+                })
+            }
+        };
+        let mut result = protocol::PreKeyBundle::new(
+            bundle.registration_id,
+            bundle.device_id.into(),
+            pre_key,
+            bundle.signed_pre_key_id.into(),
+            PublicKey::deserialize(&bundle.signed_pre_key_public)?,
+            bundle.signed_pre_key_signature,
+            IdentityKey::decode(&bundle.identity_key)?,
+        )?;
+        match (
+            bundle.kyber_pre_key_id,
+            bundle.kyber_pre_key_public,
+            bundle.kyber_pre_key_signature,
+        ) {
+            (Some(id), Some(public), Some(signature)) => {
+                result = result.with_kyber_pre_key(
+                    id.into(),
+                    kem::PublicKey::deserialize(&public)?,
+                    signature,
+                );
+            }
+            (None, None, None) => {}
+            _ => {
+                return Err(SignalError::InvalidArgument {
+                    message: "Kyber pre-key ID, public key, and signature must be given together"
+                        .to_owned(),
+                })
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Generates a new identity key pair, returning it serialized.
+#[uniffi::export]
+pub fn generate_identity_key_pair() -> Vec<u8> {
+    IdentityKeyPair::generate(&mut rand::rngs::OsRng)
+        .serialize()
+        .into_vec()
+}
+
+/// Returns the serialized public identity key of a serialized identity key pair.
+#[uniffi::export]
+pub fn identity_key_pair_public_key(identity_key_pair: Vec<u8>) -> Result<Vec<u8>, SignalError> {
+    let identity_key_pair = IdentityKeyPair::try_from(&identity_key_pair[..])?;
+    Ok(identity_key_pair.identity_key().serialize().into_vec())
+}
+
+#[uniffi::export]
+pub fn generate_pre_key(id: u32) -> Result<GeneratedPreKey, SignalError> {
+    let key_pair = KeyPair::generate(&mut rand::rngs::OsRng);
+    Ok(GeneratedPreKey {
+        id,
+        record: PreKeyRecord::new(id.into(), &key_pair).serialize()?,
+        public_key: key_pair.public_key.serialize().into_vec(),
+        signature: None,
+    })
+}
+
+/// Generates a signed pre-key, signed by the serialized `identity_key_pair`.
+///
+/// `timestamp` is in milliseconds since the Unix epoch.
+#[uniffi::export]
+pub fn generate_signed_pre_key(
+    id: u32,
+    timestamp: u64,
+    identity_key_pair: Vec<u8>,
+) -> Result<GeneratedPreKey, SignalError> {
+    let mut csprng = rand::rngs::OsRng;
+    let identity_key_pair = IdentityKeyPair::try_from(&identity_key_pair[..])?;
+    let key_pair = KeyPair::generate(&mut csprng);
+    let public_key = key_pair.public_key.serialize().into_vec();
+    let signature = identity_key_pair
+        .private_key()
+        .calculate_signature(&public_key, &mut csprng)?
+        .into_vec();
+    let record = SignedPreKeyRecord::new(
+        id.into(),
+        Timestamp::from_epoch_millis(timestamp),
+        &key_pair,
+        &signature,
+    );
+    Ok(GeneratedPreKey {
+        id,
+        record: record.serialize()?,
+        public_key,
+        signature: Some(signature),
+    })
+}
+
+/// Generates a Kyber1024 pre-key, signed by the serialized `identity_key_pair`.
+#[uniffi::export]
+pub fn generate_kyber_pre_key(
+    id: u32,
+    identity_key_pair: Vec<u8>,
+) -> Result<GeneratedPreKey, SignalError> {
+    let identity_key_pair = IdentityKeyPair::try_from(&identity_key_pair[..])?;
+    let record = KyberPreKeyRecord::generate(
+        kem::KeyType::Kyber1024,
+        id.into(),
+        identity_key_pair.private_key(),
+        &mut rand::rngs::OsRng,
+    )?;
+    Ok(GeneratedPreKey {
+        id,
+        public_key: record.public_key()?.serialize().into_vec(),
+        signature: Some(record.signature()?),
+        record: record.serialize()?,
+    })
+}
@@ -0,0 +1,43 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Bindings for libsignal-protocol generated with [uniffi](https://mozilla.github.io/uniffi-rs/).
+//!
+//! Unlike the hand-written bridges, this exposes a deliberately small, byte-oriented interface:
+//! keys and records cross the boundary in their serialized forms, and stores are implemented by
+//! the foreign language as plain synchronous interfaces. Kotlin, Swift, and Python bindings are
+//! all generated from the same library; see the README for how to run `uniffi-bindgen`.
+
+#![deny(clippy::unwrap_used)]
+
+use futures_util::FutureExt;
+
+mod address;
+mod error;
+mod group;
+mod keys;
+mod sealed_sender;
+mod session;
+mod storage;
+
+pub use address::*;
+pub use error::*;
+pub use group::*;
+pub use keys::*;
+pub use sealed_sender::*;
+pub use session::*;
+pub use storage::*;
+
+uniffi::setup_scaffolding!();
+
+/// Runs a protocol operation to completion.
+///
+/// The store interfaces exposed here are synchronous, so the futures produced by the protocol
+/// functions never actually wait on anything.
+fn run<F: std::future::Future>(future: F) -> F::Output {
+    future
+        .now_or_never()
+        .expect("uniffi stores are synchronous")
+}
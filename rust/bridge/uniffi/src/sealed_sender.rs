@@ -0,0 +1,88 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::sync::Arc;
+
+use libsignal_protocol::{self as protocol, PublicKey, SenderCertificate, Timestamp};
+
+use crate::storage::Adapter;
+use crate::{
+    run, Address, IdentityKeyStore, KyberPreKeyStore, PreKeyStore, SessionStore, SignalError,
+    SignedPreKeyStore,
+};
+
+/// A decrypted sealed sender message, along with who sent it.
+#[derive(uniffi::Record)]
+pub struct SealedSenderMessage {
+    pub sender_uuid: String,
+    pub sender_e164: Option<String>,
+    pub sender_device_id: u32,
+    pub message: Vec<u8>,
+}
+
+/// Encrypts `plaintext` for `destination`'s session, hiding the sender behind the serialized
+/// `sender_certificate`.
+#[uniffi::export]
+pub fn sealed_sender_encrypt(
+    destination: Address,
+    sender_certificate: Vec<u8>,
+    plaintext: Vec<u8>,
+    session_store: Arc<dyn SessionStore>,
+    identity_store: Arc<dyn IdentityKeyStore>,
+) -> Result<Vec<u8>, SignalError> {
+    let sender_certificate = SenderCertificate::deserialize(&sender_certificate)?;
+    Ok(run(protocol::sealed_sender_encrypt(
+        &destination.into(),
+        &sender_certificate,
+        &plaintext,
+        &mut Adapter(&*session_store),
+        &mut Adapter(&*identity_store),
+        None,
+        &mut rand::rngs::OsRng,
+    ))?)
+}
+
+/// Decrypts a sealed sender message addressed to the local user.
+///
+/// `trust_root` is the serialized public key that server certificates must be signed by, and
+/// `timestamp` (in milliseconds since the Unix epoch) is checked against the sender certificate's
+/// expiration.
+#[uniffi::export]
+#[allow(clippy::too_many_arguments)]
+pub fn sealed_sender_decrypt(
+    message: Vec<u8>,
+    trust_root: Vec<u8>,
+    timestamp: u64,
+    local_e164: Option<String>,
+    local_uuid: String,
+    local_device_id: u32,
+    session_store: Arc<dyn SessionStore>,
+    identity_store: Arc<dyn IdentityKeyStore>,
+    pre_key_store: Arc<dyn PreKeyStore>,
+    signed_pre_key_store: Arc<dyn SignedPreKeyStore>,
+    kyber_pre_key_store: Arc<dyn KyberPreKeyStore>,
+) -> Result<SealedSenderMessage, SignalError> {
+    let result = run(protocol::sealed_sender_decrypt(
+        &message,
+        &PublicKey::deserialize(&trust_root)?,
+        Timestamp::from_epoch_millis(timestamp),
+        local_e164,
+        local_uuid,
+        local_device_id.into(),
+        &mut Adapter(&*identity_store),
+        &mut Adapter(&*session_store),
+        &mut Adapter(&*pre_key_store),
+        &mut Adapter(&*signed_pre_key_store),
+        &mut Adapter(&*kyber_pre_key_store),
+        &mut rand::rngs::OsRng,
+        None,
+    ))?;
+    Ok(SealedSenderMessage {
+        sender_uuid: result.sender_uuid,
+        sender_e164: result.sender_e164,
+        sender_device_id: result.device_id.into(),
+        message: result.message,
+    })
+}
@@ -0,0 +1,113 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use libsignal_protocol::{
+    self as protocol, CiphertextMessage, CiphertextMessageType, PreKeySignalMessage,
+    ProtocolAddress, SignalMessage,
+};
+
+use crate::storage::Adapter;
+use crate::{
+    run, Address, IdentityKeyStore, KyberPreKeyStore, PreKeyBundle, PreKeyStore, SessionStore,
+    SignalError, SignedPreKeyStore,
+};
+
+/// The kinds of message produced by [`encrypt_message`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum MessageType {
+    /// A message in an established session.
+    Whisper,
+    /// The first messages sent in a session, before the recipient has replied.
+    PreKey,
+}
+
+/// An encrypted one-to-one message.
+#[derive(uniffi::Record)]
+pub struct EncryptedMessage {
+    pub message_type: MessageType,
+    pub body: Vec<u8>,
+}
+
+/// Starts a session with `address` using their pre-key bundle.
+#[uniffi::export]
+pub fn process_pre_key_bundle(
+    bundle: PreKeyBundle,
+    address: Address,
+    session_store: Arc<dyn SessionStore>,
+    identity_store: Arc<dyn IdentityKeyStore>,
+) -> Result<(), SignalError> {
+    let bundle = protocol::PreKeyBundle::try_from(bundle)?;
+    run(protocol::process_prekey_bundle(
+        &address.into(),
+        &mut Adapter(&*session_store),
+        &mut Adapter(&*identity_store),
+        &bundle,
+        &mut rand::rngs::OsRng,
+        None,
+    ))?;
+    Ok(())
+}
+
+#[uniffi::export]
+pub fn encrypt_message(
+    plaintext: Vec<u8>,
+    address: Address,
+    session_store: Arc<dyn SessionStore>,
+    identity_store: Arc<dyn IdentityKeyStore>,
+) -> Result<EncryptedMessage, SignalError> {
+    let message = run(protocol::message_encrypt(
+        &plaintext,
+        &address.into(),
+        &mut Adapter(&*session_store),
+        &mut Adapter(&*identity_store),
+        None,
+    ))?;
+    let message_type = match message.message_type() {
+        CiphertextMessageType::Whisper => MessageType::Whisper,
+        CiphertextMessageType::PreKey => MessageType::PreKey,
+        CiphertextMessageType::SenderKey | CiphertextMessageType::Plaintext => {
+            unreachable!("message_encrypt only produces session messages")
+        }
+    };
+    Ok(EncryptedMessage {
+        message_type,
+        body: message.serialize().to_vec(),
+    })
+}
+
+#[uniffi::export]
+pub fn decrypt_message(
+    message: EncryptedMessage,
+    address: Address,
+    session_store: Arc<dyn SessionStore>,
+    identity_store: Arc<dyn IdentityKeyStore>,
+    pre_key_store: Arc<dyn PreKeyStore>,
+    signed_pre_key_store: Arc<dyn SignedPreKeyStore>,
+    kyber_pre_key_store: Arc<dyn KyberPreKeyStore>,
+) -> Result<Vec<u8>, SignalError> {
+    let message = match message.message_type {
+        MessageType::Whisper => {
+            CiphertextMessage::SignalMessage(SignalMessage::try_from(&message.body[..])?)
+        }
+        MessageType::PreKey => CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(&message.body[..])?,
+        ),
+    };
+    let address = ProtocolAddress::from(address);
+    Ok(run(protocol::message_decrypt(
+        &message,
+        &address,
+        &mut Adapter(&*session_store),
+        &mut Adapter(&*identity_store),
+        &mut Adapter(&*pre_key_store),
+        &mut Adapter(&*signed_pre_key_store),
+        &mut Adapter(&*kyber_pre_key_store),
+        &mut rand::rngs::OsRng,
+        None,
+    ))?)
+}
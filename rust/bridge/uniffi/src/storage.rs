@@ -0,0 +1,307 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Store interfaces implemented by the foreign language.
+//!
+//! Records and keys are passed in their serialized forms. A store that has no entry for a key
+//! returns `null`/`nil`/`None` rather than throwing.
+
+use std::convert::TryFrom;
+
+use async_trait::async_trait;
+use libsignal_protocol::{
+    self as protocol, Context, GenericSignedPreKey, IdentityKey, IdentityKeyPair, KyberPreKeyId,
+    KyberPreKeyRecord, PreKeyId, PreKeyRecord, ProtocolAddress, SenderKeyName, SenderKeyRecord,
+    SessionRecord, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
+};
+
+use crate::error::callback_error;
+use crate::{Address, SignalError};
+
+type Result<T, E = SignalProtocolError> = std::result::Result<T, E>;
+
+/// Whether an identity key is being checked for sending or receiving a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+impl From<protocol::Direction> for Direction {
+    fn from(direction: protocol::Direction) -> Self {
+        match direction {
+            protocol::Direction::Sending => Self::Sending,
+            protocol::Direction::Receiving => Self::Receiving,
+        }
+    }
+}
+
+#[uniffi::export(with_foreign)]
+pub trait IdentityKeyStore: Send + Sync {
+    /// The local user's serialized identity key pair.
+    fn get_identity_key_pair(&self) -> Result<Vec<u8>, SignalError>;
+    fn get_local_registration_id(&self) -> Result<u32, SignalError>;
+    /// Records `identity_key` for `address`, returning `true` if it replaced a different key.
+    fn save_identity(&self, address: Address, identity_key: Vec<u8>) -> Result<bool, SignalError>;
+    fn is_trusted_identity(
+        &self,
+        address: Address,
+        identity_key: Vec<u8>,
+        direction: Direction,
+    ) -> Result<bool, SignalError>;
+    fn get_identity(&self, address: Address) -> Result<Option<Vec<u8>>, SignalError>;
+}
+
+#[uniffi::export(with_foreign)]
+pub trait PreKeyStore: Send + Sync {
+    fn get_pre_key(&self, id: u32) -> Result<Option<Vec<u8>>, SignalError>;
+    fn save_pre_key(&self, id: u32, record: Vec<u8>) -> Result<(), SignalError>;
+    fn remove_pre_key(&self, id: u32) -> Result<(), SignalError>;
+}
+
+#[uniffi::export(with_foreign)]
+pub trait SignedPreKeyStore: Send + Sync {
+    fn get_signed_pre_key(&self, id: u32) -> Result<Option<Vec<u8>>, SignalError>;
+    fn save_signed_pre_key(&self, id: u32, record: Vec<u8>) -> Result<(), SignalError>;
+}
+
+#[uniffi::export(with_foreign)]
+pub trait KyberPreKeyStore: Send + Sync {
+    fn get_kyber_pre_key(&self, id: u32) -> Result<Option<Vec<u8>>, SignalError>;
+    fn save_kyber_pre_key(&self, id: u32, record: Vec<u8>) -> Result<(), SignalError>;
+    fn mark_kyber_pre_key_used(&self, id: u32) -> Result<(), SignalError>;
+}
+
+#[uniffi::export(with_foreign)]
+pub trait SessionStore: Send + Sync {
+    fn load_session(&self, address: Address) -> Result<Option<Vec<u8>>, SignalError>;
+    fn store_session(&self, address: Address, record: Vec<u8>) -> Result<(), SignalError>;
+}
+
+#[uniffi::export(with_foreign)]
+pub trait SenderKeyStore: Send + Sync {
+    fn load_sender_key(
+        &self,
+        sender: Address,
+        distribution_id: String,
+    ) -> Result<Option<Vec<u8>>, SignalError>;
+    fn store_sender_key(
+        &self,
+        sender: Address,
+        distribution_id: String,
+        record: Vec<u8>,
+    ) -> Result<(), SignalError>;
+}
+
+/// Presents a foreign store as the corresponding libsignal-protocol store.
+pub(crate) struct Adapter<'a, S: ?Sized>(pub &'a S);
+
+#[async_trait(?Send)]
+impl protocol::IdentityKeyStore for Adapter<'_, dyn IdentityKeyStore> {
+    async fn get_identity_key_pair(&self, _ctx: Context) -> Result<IdentityKeyPair> {
+        let bytes = self
+            .0
+            .get_identity_key_pair()
+            .map_err(|e| callback_error("get_identity_key_pair", e))?;
+        IdentityKeyPair::try_from(&bytes[..])
+    }
+
+    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32> {
+        self.0
+            .get_local_registration_id()
+            .map_err(|e| callback_error("get_local_registration_id", e))
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _ctx: Context,
+    ) -> Result<bool> {
+        self.0
+            .save_identity(address.into(), identity.serialize().into_vec())
+            .map_err(|e| callback_error("save_identity", e))
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: protocol::Direction,
+        _ctx: Context,
+    ) -> Result<bool> {
+        self.0
+            .is_trusted_identity(
+                address.into(),
+                identity.serialize().into_vec(),
+                direction.into(),
+            )
+            .map_err(|e| callback_error("is_trusted_identity", e))
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.0
+            .get_identity(address.into())
+            .map_err(|e| callback_error("get_identity", e))?
+            .map(|bytes| IdentityKey::decode(&bytes))
+            .transpose()
+    }
+}
+
+#[async_trait(?Send)]
+impl protocol::PreKeyStore for Adapter<'_, dyn PreKeyStore> {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, _ctx: Context) -> Result<PreKeyRecord> {
+        let bytes = self
+            .0
+            .get_pre_key(prekey_id.into())
+            .map_err(|e| callback_error("get_pre_key", e))?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        PreKeyRecord::deserialize(&bytes)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0
+            .save_pre_key(prekey_id.into(), record.serialize()?)
+            .map_err(|e| callback_error("save_pre_key", e))
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, _ctx: Context) -> Result<()> {
+        self.0
+            .remove_pre_key(prekey_id.into())
+            .map_err(|e| callback_error("remove_pre_key", e))
+    }
+}
+
+#[async_trait(?Send)]
+impl protocol::SignedPreKeyStore for Adapter<'_, dyn SignedPreKeyStore> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        let bytes = self
+            .0
+            .get_signed_pre_key(signed_prekey_id.into())
+            .map_err(|e| callback_error("get_signed_pre_key", e))?
+            .ok_or(SignalProtocolError::InvalidSignedPreKeyId)?;
+        SignedPreKeyRecord::deserialize(&bytes)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0
+            .save_signed_pre_key(signed_prekey_id.into(), record.serialize()?)
+            .map_err(|e| callback_error("save_signed_pre_key", e))
+    }
+}
+
+#[async_trait(?Send)]
+impl protocol::KyberPreKeyStore for Adapter<'_, dyn KyberPreKeyStore> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<KyberPreKeyRecord> {
+        let bytes = self
+            .0
+            .get_kyber_pre_key(kyber_prekey_id.into())
+            .map_err(|e| callback_error("get_kyber_pre_key", e))?
+            .ok_or(SignalProtocolError::InvalidKyberPreKeyId)?;
+        KyberPreKeyRecord::deserialize(&bytes)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0
+            .save_kyber_pre_key(kyber_prekey_id.into(), record.serialize()?)
+            .map_err(|e| callback_error("save_kyber_pre_key", e))
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0
+            .mark_kyber_pre_key_used(kyber_prekey_id.into())
+            .map_err(|e| callback_error("mark_kyber_pre_key_used", e))
+    }
+}
+
+#[async_trait(?Send)]
+impl protocol::SessionStore for Adapter<'_, dyn SessionStore> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        self.0
+            .load_session(address.into())
+            .map_err(|e| callback_error("load_session", e))?
+            .map(|bytes| SessionRecord::deserialize(&bytes))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0
+            .store_session(address.into(), record.serialize()?)
+            .map_err(|e| callback_error("store_session", e))
+    }
+}
+
+#[async_trait(?Send)]
+impl protocol::SenderKeyStore for Adapter<'_, dyn SenderKeyStore> {
+    async fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0
+            .store_sender_key(
+                sender_key_name.sender().into(),
+                sender_key_name.distribution_id().to_string(),
+                record.serialize()?,
+            )
+            .map_err(|e| callback_error("store_sender_key", e))
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        _ctx: Context,
+    ) -> Result<Option<SenderKeyRecord>> {
+        self.0
+            .load_sender_key(
+                sender_key_name.sender().into(),
+                sender_key_name.distribution_id().to_string(),
+            )
+            .map_err(|e| callback_error("load_sender_key", e))?
+            .map(|bytes| SenderKeyRecord::deserialize(&bytes))
+            .transpose()
+    }
+}
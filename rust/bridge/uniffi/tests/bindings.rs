@@ -0,0 +1,379 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Exercises the exported functions with stores implemented on the Rust side, the same way the
+//! generated bindings call them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use libsignal_protocol::{KeyPair, SenderCertificate, ServerCertificate};
+use rand::rngs::OsRng;
+use signal_uniffi::*;
+
+#[derive(Default)]
+struct Client {
+    identity_key_pair: Vec<u8>,
+    registration_id: u32,
+    identities: Mutex<HashMap<Address, Vec<u8>>>,
+    untrusted: Mutex<Option<Address>>,
+    failing_session_writes: Mutex<bool>,
+    pre_keys: Mutex<HashMap<u32, Vec<u8>>>,
+    signed_pre_keys: Mutex<HashMap<u32, Vec<u8>>>,
+    kyber_pre_keys: Mutex<HashMap<u32, Vec<u8>>>,
+    sessions: Mutex<HashMap<Address, Vec<u8>>>,
+    sender_keys: Mutex<HashMap<(Address, String), Vec<u8>>>,
+}
+
+impl Client {
+    fn new(registration_id: u32) -> Arc<Self> {
+        Arc::new(Self {
+            identity_key_pair: generate_identity_key_pair(),
+            registration_id,
+            ..Default::default()
+        })
+    }
+
+    fn bundle(&self, device_id: u32) -> Result<PreKeyBundle, SignalError> {
+        let pre_key = generate_pre_key(1)?;
+        let signed_pre_key = generate_signed_pre_key(2, 1000, self.identity_key_pair.clone())?;
+        self.save_pre_key(pre_key.id, pre_key.record)?;
+        self.save_signed_pre_key(signed_pre_key.id, signed_pre_key.record)?;
+        Ok(PreKeyBundle {
+            registration_id: self.registration_id,
+            device_id,
+            pre_key_id: Some(pre_key.id),
+            pre_key_public: Some(pre_key.public_key),
+            signed_pre_key_id: signed_pre_key.id,
+            signed_pre_key_public: signed_pre_key.public_key,
+            signed_pre_key_signature: signed_pre_key.signature.expect("signed"),
+            identity_key: identity_key_pair_public_key(self.identity_key_pair.clone())?,
+            kyber_pre_key_id: None,
+            kyber_pre_key_public: None,
+            kyber_pre_key_signature: None,
+        })
+    }
+
+    fn decrypt(
+        self: &Arc<Self>,
+        message: EncryptedMessage,
+        from: &Address,
+    ) -> Result<Vec<u8>, SignalError> {
+        decrypt_message(
+            message,
+            from.clone(),
+            self.clone(),
+            self.clone(),
+            self.clone(),
+            self.clone(),
+            self.clone(),
+        )
+    }
+}
+
+impl IdentityKeyStore for Client {
+    fn get_identity_key_pair(&self) -> Result<Vec<u8>, SignalError> {
+        Ok(self.identity_key_pair.clone())
+    }
+
+    fn get_local_registration_id(&self) -> Result<u32, SignalError> {
+        Ok(self.registration_id)
+    }
+
+    fn save_identity(&self, address: Address, identity_key: Vec<u8>) -> Result<bool, SignalError> {
+        let previous = self
+            .identities
+            .lock()
+            .expect("not poisoned")
+            .insert(address, identity_key.clone());
+        Ok(matches!(previous, Some(previous) if previous != identity_key))
+    }
+
+    fn is_trusted_identity(
+        &self,
+        address: Address,
+        _identity_key: Vec<u8>,
+        _direction: Direction,
+    ) -> Result<bool, SignalError> {
+        Ok(self.untrusted.lock().expect("not poisoned").as_ref() != Some(&address))
+    }
+
+    fn get_identity(&self, address: Address) -> Result<Option<Vec<u8>>, SignalError> {
+        Ok(self
+            .identities
+            .lock()
+            .expect("not poisoned")
+            .get(&address)
+            .cloned())
+    }
+}
+
+impl PreKeyStore for Client {
+    fn get_pre_key(&self, id: u32) -> Result<Option<Vec<u8>>, SignalError> {
+        Ok(self
+            .pre_keys
+            .lock()
+            .expect("not poisoned")
+            .get(&id)
+            .cloned())
+    }
+
+    fn save_pre_key(&self, id: u32, record: Vec<u8>) -> Result<(), SignalError> {
+        self.pre_keys
+            .lock()
+            .expect("not poisoned")
+            .insert(id, record);
+        Ok(())
+    }
+
+    fn remove_pre_key(&self, id: u32) -> Result<(), SignalError> {
+        self.pre_keys.lock().expect("not poisoned").remove(&id);
+        Ok(())
+    }
+}
+
+impl SignedPreKeyStore for Client {
+    fn get_signed_pre_key(&self, id: u32) -> Result<Option<Vec<u8>>, SignalError> {
+        Ok(self
+            .signed_pre_keys
+            .lock()
+            .expect("not poisoned")
+            .get(&id)
+            .cloned())
+    }
+
+    fn save_signed_pre_key(&self, id: u32, record: Vec<u8>) -> Result<(), SignalError> {
+        self.signed_pre_keys
+            .lock()
+            .expect("not poisoned")
+            .insert(id, record);
+        Ok(())
+    }
+}
+
+impl KyberPreKeyStore for Client {
+    fn get_kyber_pre_key(&self, id: u32) -> Result<Option<Vec<u8>>, SignalError> {
+        Ok(self
+            .kyber_pre_keys
+            .lock()
+            .expect("not poisoned")
+            .get(&id)
+            .cloned())
+    }
+
+    fn save_kyber_pre_key(&self, id: u32, record: Vec<u8>) -> Result<(), SignalError> {
+        self.kyber_pre_keys
+            .lock()
+            .expect("not poisoned")
+            .insert(id, record);
+        Ok(())
+    }
+
+    fn mark_kyber_pre_key_used(&self, _id: u32) -> Result<(), SignalError> {
+        Ok(())
+    }
+}
+
+impl SessionStore for Client {
+    fn load_session(&self, address: Address) -> Result<Option<Vec<u8>>, SignalError> {
+        Ok(self
+            .sessions
+            .lock()
+            .expect("not poisoned")
+            .get(&address)
+            .cloned())
+    }
+
+    fn store_session(&self, address: Address, record: Vec<u8>) -> Result<(), SignalError> {
+        if *self.failing_session_writes.lock().expect("not poisoned") {
+            return Err(SignalError::Store {
+                message: "disk full".to_owned(),
+            });
+        }
+        self.sessions
+            .lock()
+            .expect("not poisoned")
+            .insert(address, record);
+        Ok(())
+    }
+}
+
+impl SenderKeyStore for Client {
+    fn load_sender_key(
+        &self,
+        sender: Address,
+        distribution_id: String,
+    ) -> Result<Option<Vec<u8>>, SignalError> {
+        Ok(self
+            .sender_keys
+            .lock()
+            .expect("not poisoned")
+            .get(&(sender, distribution_id))
+            .cloned())
+    }
+
+    fn store_sender_key(
+        &self,
+        sender: Address,
+        distribution_id: String,
+        record: Vec<u8>,
+    ) -> Result<(), SignalError> {
+        self.sender_keys
+            .lock()
+            .expect("not poisoned")
+            .insert((sender, distribution_id), record);
+        Ok(())
+    }
+}
+
+fn address(name: &str) -> Address {
+    Address {
+        name: name.to_owned(),
+        device_id: 1,
+    }
+}
+
+fn start_session(alice: &Arc<Client>, bob: &Arc<Client>) -> Result<(), SignalError> {
+    process_pre_key_bundle(bob.bundle(1)?, address("bob"), alice.clone(), alice.clone())
+}
+
+#[test]
+fn session_round_trip() -> Result<(), SignalError> {
+    let alice = Client::new(1);
+    let bob = Client::new(2);
+    start_session(&alice, &bob)?;
+
+    let message = encrypt_message(
+        b"hello bob".to_vec(),
+        address("bob"),
+        alice.clone(),
+        alice.clone(),
+    )?;
+    assert_eq!(message.message_type, MessageType::PreKey);
+    assert_eq!(bob.decrypt(message, &address("alice"))?, b"hello bob");
+
+    let reply = encrypt_message(
+        b"hello alice".to_vec(),
+        address("alice"),
+        bob.clone(),
+        bob.clone(),
+    )?;
+    assert_eq!(reply.message_type, MessageType::Whisper);
+    assert_eq!(alice.decrypt(reply, &address("bob"))?, b"hello alice");
+    Ok(())
+}
+
+#[test]
+fn errors_keep_their_details() -> Result<(), SignalError> {
+    let alice = Client::new(1);
+    let bob = Client::new(2);
+
+    match encrypt_message(b"hi".to_vec(), address("bob"), alice.clone(), alice.clone()) {
+        Err(SignalError::SessionNotFound { address: missing }) => {
+            assert_eq!(missing, address("bob"))
+        }
+        other => panic!("unexpected result: {:?}", other.map(|m| m.body)),
+    }
+
+    *alice.untrusted.lock().expect("not poisoned") = Some(address("bob"));
+    match start_session(&alice, &bob) {
+        Err(SignalError::UntrustedIdentity { address: untrusted }) => {
+            assert_eq!(untrusted, address("bob"))
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // Errors thrown by a store come back out unchanged.
+    *alice.untrusted.lock().expect("not poisoned") = None;
+    *alice.failing_session_writes.lock().expect("not poisoned") = true;
+    match start_session(&alice, &bob) {
+        Err(SignalError::Store { message }) => assert_eq!(message, "disk full"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn group_round_trip() -> Result<(), SignalError> {
+    let alice = Client::new(1);
+    let bob = Client::new(2);
+    let distribution_id = "d1d1d1d1-7000-11eb-b32a-33b8a8a487a6".to_owned();
+
+    let distribution_message = create_sender_key_distribution_message(
+        address("alice"),
+        distribution_id.clone(),
+        alice.clone(),
+    )?;
+    process_sender_key_distribution_message(address("alice"), distribution_message, bob.clone())?;
+
+    let message = group_encrypt(
+        address("alice"),
+        distribution_id,
+        b"hello group".to_vec(),
+        alice.clone(),
+    )?;
+    assert_eq!(
+        group_decrypt(address("alice"), message, bob.clone())?,
+        b"hello group"
+    );
+
+    assert!(matches!(
+        group_encrypt(address("alice"), "not a uuid".to_owned(), vec![], alice),
+        Err(SignalError::InvalidArgument { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn sealed_sender_round_trip() -> Result<(), SignalError> {
+    let alice = Client::new(1);
+    let bob = Client::new(2);
+    start_session(&alice, &bob)?;
+
+    let mut rng = OsRng;
+    let trust_root = KeyPair::generate(&mut rng);
+    let server_key = KeyPair::generate(&mut rng);
+    let server_certificate =
+        ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+    let alice_uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+    let sender_certificate = SenderCertificate::new(
+        alice_uuid.to_owned(),
+        None,
+        *libsignal_protocol::IdentityKey::decode(&identity_key_pair_public_key(
+            alice.identity_key_pair.clone(),
+        )?)?
+        .public_key(),
+        1.into(),
+        2000,
+        server_certificate,
+        &server_key.private_key,
+        &mut rng,
+    )?;
+
+    let message = sealed_sender_encrypt(
+        address("bob"),
+        sender_certificate.serialized()?.to_vec(),
+        b"hello from nobody".to_vec(),
+        alice.clone(),
+        alice.clone(),
+    )?;
+    let decrypted = sealed_sender_decrypt(
+        message,
+        trust_root.public_key.serialize().into_vec(),
+        1000,
+        None,
+        "bob".to_owned(),
+        1,
+        bob.clone(),
+        bob.clone(),
+        bob.clone(),
+        bob.clone(),
+        bob,
+    )?;
+    assert_eq!(decrypted.sender_uuid, alice_uuid);
+    assert_eq!(decrypted.sender_device_id, 1);
+    assert_eq!(decrypted.message, b"hello from nobody");
+    Ok(())
+}
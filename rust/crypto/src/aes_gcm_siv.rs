@@ -0,0 +1,260 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! AES-256-GCM-SIV ([RFC 8452]), the nonce-misuse-resistant AEAD used by sealed sender.
+//!
+//! [Aes256GcmSiv] encrypts a whole message at once. GCM-SIV has to see all of the plaintext before
+//! it can produce any ciphertext, so large inputs (such as a local database file) should instead
+//! be split into fixed-size chunks with [Aes256GcmSivChunkedEncryption], which authenticates each
+//! chunk's position and which chunk is last, so chunks cannot be reordered, dropped, or truncated
+//! without decryption failing.
+//!
+//! [RFC 8452]: https://www.rfc-editor.org/rfc/rfc8452
+
+use aes_gcm_siv::aead::{Aead, NewAead, Payload};
+use generic_array::GenericArray;
+
+use crate::{Error, Result};
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+/// The largest plaintext (and associated data) RFC 8452 allows in a single message: 2^36 bytes.
+pub const MAX_MESSAGE_SIZE: u64 = 1 << 36;
+
+/// One-shot AES-256-GCM-SIV encryption and decryption under a single key.
+///
+/// Ciphertexts are the encrypted message followed by the 16-byte tag. Reusing a nonce only reveals
+/// whether two messages (with the same associated data) were identical, but nonces should still
+/// be unique, for instance random.
+#[derive(Clone)]
+pub struct Aes256GcmSiv {
+    cipher: aes_gcm_siv::Aes256GcmSiv,
+}
+
+impl Aes256GcmSiv {
+    pub const KEY_SIZE: usize = KEY_SIZE;
+    pub const NONCE_SIZE: usize = NONCE_SIZE;
+    pub const TAG_SIZE: usize = TAG_SIZE;
+
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != KEY_SIZE {
+            return Err(Error::InvalidKeySize);
+        }
+        let cipher =
+            aes_gcm_siv::Aes256GcmSiv::new_from_slice(key).map_err(|_| Error::InvalidKeySize)?;
+        Ok(Self { cipher })
+    }
+
+    pub fn encrypt(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>> {
+        check_nonce(nonce)?;
+        check_message_size(associated_data)?;
+        check_message_size(plaintext)?;
+        self.cipher
+            .encrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| Error::InvalidInputSize)
+    }
+
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        check_nonce(nonce)?;
+        check_message_size(associated_data)?;
+        if ciphertext.len() < TAG_SIZE {
+            return Err(Error::InvalidInputSize);
+        }
+        check_message_size(&ciphertext[..ciphertext.len() - TAG_SIZE])?;
+        self.cipher
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| Error::InvalidTag)
+    }
+}
+
+fn check_nonce(nonce: &[u8]) -> Result<()> {
+    if nonce.len() != NONCE_SIZE {
+        return Err(Error::InvalidNonceSize);
+    }
+    Ok(())
+}
+
+fn check_message_size(message: &[u8]) -> Result<()> {
+    if message.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(Error::InvalidInputSize);
+    }
+    Ok(())
+}
+
+/// The largest chunk size accepted by [Aes256GcmSivChunkedEncryption], 16 MiB.
+///
+/// Decrypting a chunk needs the whole chunk in memory, so this bounds what a reader has to buffer.
+pub const MAX_CHUNK_SIZE: usize = 16 << 20;
+
+/// The size of the caller-chosen part of each chunk's nonce. The rest is the chunk's index and
+/// whether it is the last chunk.
+pub const CHUNK_NONCE_PREFIX_SIZE: usize = 7;
+
+/// The state shared by chunked encryption and decryption: the key, the nonce prefix, and how far
+/// through the stream we are.
+struct ChunkSequence {
+    cipher: Aes256GcmSiv,
+    nonce_prefix: [u8; CHUNK_NONCE_PREFIX_SIZE],
+    associated_data: Vec<u8>,
+    chunk_size: usize,
+    next_index: Option<u32>,
+}
+
+impl ChunkSequence {
+    fn new(
+        key: &[u8],
+        nonce_prefix: &[u8],
+        associated_data: &[u8],
+        chunk_size: usize,
+    ) -> Result<Self> {
+        let cipher = Aes256GcmSiv::new(key)?;
+        let mut prefix = [0; CHUNK_NONCE_PREFIX_SIZE];
+        if nonce_prefix.len() != prefix.len() {
+            return Err(Error::InvalidNonceSize);
+        }
+        prefix.copy_from_slice(nonce_prefix);
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(Error::InvalidInputSize);
+        }
+        check_message_size(associated_data)?;
+        Ok(Self {
+            cipher,
+            nonce_prefix: prefix,
+            associated_data: associated_data.to_vec(),
+            chunk_size,
+            next_index: Some(0),
+        })
+    }
+
+    /// Returns the nonce for the next chunk and advances past it, failing once the 2^32 chunk
+    /// indexes have all been used.
+    fn next_nonce(&mut self, is_last: bool) -> Result<[u8; NONCE_SIZE]> {
+        let index = self.next_index.ok_or(Error::InvalidState)?;
+        self.next_index = index.checked_add(1);
+
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[..CHUNK_NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[CHUNK_NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&index.to_be_bytes());
+        nonce[NONCE_SIZE - 1] = is_last.into();
+        Ok(nonce)
+    }
+}
+
+/// Encrypts a stream as a sequence of AES-256-GCM-SIV chunks.
+///
+/// Every chunk but the last holds exactly `chunk_size` bytes of plaintext; the last holds at most
+/// that many, possibly none. Each encrypted chunk is `TAG_SIZE` bytes longer than its plaintext
+/// and must be passed to [Aes256GcmSivChunkedDecryption] separately, in order.
+///
+/// `nonce_prefix` must never be reused with the same key for a different stream; a random prefix
+/// is fine for up to about 2^24 streams per key.
+pub struct Aes256GcmSivChunkedEncryption {
+    chunks: ChunkSequence,
+}
+
+impl Aes256GcmSivChunkedEncryption {
+    pub fn new(
+        key: &[u8],
+        nonce_prefix: &[u8],
+        associated_data: &[u8],
+        chunk_size: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            chunks: ChunkSequence::new(key, nonce_prefix, associated_data, chunk_size)?,
+        })
+    }
+
+    /// Encrypts a chunk that is not the last one; `plaintext` must be exactly `chunk_size` bytes.
+    pub fn encrypt_chunk(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        if plaintext.len() != self.chunks.chunk_size {
+            return Err(Error::InvalidInputSize);
+        }
+        let nonce = self.chunks.next_nonce(false)?;
+        self.chunks
+            .cipher
+            .encrypt(&nonce, &self.chunks.associated_data, plaintext)
+    }
+
+    /// Encrypts the final chunk, of at most `chunk_size` bytes, and ends the stream.
+    pub fn encrypt_last_chunk(mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        if plaintext.len() > self.chunks.chunk_size {
+            return Err(Error::InvalidInputSize);
+        }
+        let nonce = self.chunks.next_nonce(true)?;
+        self.chunks
+            .cipher
+            .encrypt(&nonce, &self.chunks.associated_data, plaintext)
+    }
+}
+
+/// Decrypts a stream produced by [Aes256GcmSivChunkedEncryption].
+///
+/// The caller must know which chunk is last (for instance, from the length of the stored data);
+/// passing it to [decrypt_chunk](Self::decrypt_chunk), or passing an earlier chunk to
+/// [decrypt_last_chunk](Self::decrypt_last_chunk), fails with [Error::InvalidTag], so a truncated
+/// stream is never mistaken for a complete one.
+pub struct Aes256GcmSivChunkedDecryption {
+    chunks: ChunkSequence,
+}
+
+impl Aes256GcmSivChunkedDecryption {
+    pub fn new(
+        key: &[u8],
+        nonce_prefix: &[u8],
+        associated_data: &[u8],
+        chunk_size: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            chunks: ChunkSequence::new(key, nonce_prefix, associated_data, chunk_size)?,
+        })
+    }
+
+    /// Decrypts a chunk that is not the last one; `ciphertext` must be exactly
+    /// `chunk_size + TAG_SIZE` bytes.
+    pub fn decrypt_chunk(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() != self.chunks.chunk_size + TAG_SIZE {
+            return Err(Error::InvalidInputSize);
+        }
+        let nonce = self.chunks.next_nonce(false)?;
+        self.chunks
+            .cipher
+            .decrypt(&nonce, &self.chunks.associated_data, ciphertext)
+    }
+
+    /// Decrypts the final chunk and ends the stream.
+    pub fn decrypt_last_chunk(mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() > self.chunks.chunk_size + TAG_SIZE {
+            return Err(Error::InvalidInputSize);
+        }
+        let nonce = self.chunks.next_nonce(true)?;
+        self.chunks
+            .cipher
+            .decrypt(&nonce, &self.chunks.associated_data, ciphertext)
+    }
+}
@@ -0,0 +1,372 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! The cipher Signal clients use for attachments.
+//!
+//! An attachment key is 64 bytes: a 32-byte AES-256 key followed by a 32-byte HMAC-SHA256 key.
+//! The encrypted attachment is
+//!
+//! ```text
+//! iv (16 bytes) || AES-256-CBC(plaintext, PKCS#7 padded) || HMAC-SHA256(iv || ciphertext)
+//! ```
+//!
+//! and its digest is the SHA-256 of all of that, which the sender includes in the message pointing
+//! to the attachment so the recipient can check the download before decrypting it.
+//!
+//! The `_gcm` variants use a 32-byte key and produce `nonce (12 bytes) || ciphertext || tag`,
+//! with a digest computed the same way.
+//!
+//! Streaming playback of attachments uses the chunked MAC in `libsignal_protocol`'s
+//! `incremental_mac` module; the ciphertext produced by [AttachmentEncryptor] can be fed to it a
+//! chunk at a time.
+
+use std::convert::TryInto;
+
+use aes::cipher::consts::U16;
+use aes::Aes256;
+use block_modes::block_padding::NoPadding;
+use block_modes::{BlockMode, Cbc};
+use generic_array::GenericArray;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::aes_gcm::{NONCE_SIZE, TAG_SIZE};
+use crate::{Aes256GcmDecryption, Aes256GcmEncryption, Error, Result};
+
+/// The size of an attachment key: an AES-256 key followed by an HMAC-SHA256 key.
+pub const ATTACHMENT_KEY_SIZE: usize = 64;
+/// The size of the IV at the start of an encrypted attachment.
+pub const ATTACHMENT_IV_SIZE: usize = 16;
+/// The size of the MAC at the end of an encrypted attachment.
+pub const ATTACHMENT_MAC_SIZE: usize = 32;
+/// The size of an attachment digest.
+pub const ATTACHMENT_DIGEST_SIZE: usize = 32;
+
+const AES_KEY_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 16;
+
+type Aes256Cbc = Cbc<Aes256, NoPadding>;
+
+/// An encrypted attachment, along with the digest the recipient should check it against.
+#[derive(Clone, Debug)]
+pub struct EncryptedAttachment {
+    pub ciphertext: Vec<u8>,
+    pub digest: [u8; ATTACHMENT_DIGEST_SIZE],
+}
+
+/// Splits an attachment key into its AES and HMAC halves.
+fn split_key(key: &[u8]) -> Result<(&[u8], &[u8])> {
+    if key.len() != ATTACHMENT_KEY_SIZE {
+        return Err(Error::InvalidKeySize);
+    }
+    Ok(key.split_at(AES_KEY_SIZE))
+}
+
+fn new_mac(mac_key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC accepts any key length")
+}
+
+/// Computes the digest of an encrypted attachment.
+pub fn attachment_digest(ciphertext: &[u8]) -> [u8; ATTACHMENT_DIGEST_SIZE] {
+    Sha256::digest(ciphertext).into()
+}
+
+fn verify_digest(ciphertext: &[u8], expected_digest: &[u8]) -> Result<()> {
+    if !bool::from(attachment_digest(ciphertext).ct_eq(expected_digest)) {
+        return Err(Error::InvalidTag);
+    }
+    Ok(())
+}
+
+/// Encrypts an attachment incrementally.
+///
+/// The output of each call is the next piece of the encrypted attachment, starting with the IV;
+/// concatenated, they match what [encrypt_attachment] produces for the same input.
+pub struct AttachmentEncryptor {
+    cipher: Aes256Cbc,
+    mac: Hmac<Sha256>,
+    digest: Sha256,
+    buffered: Vec<u8>,
+    iv: Option<[u8; ATTACHMENT_IV_SIZE]>,
+}
+
+impl AttachmentEncryptor {
+    /// Fails if `key` is not [ATTACHMENT_KEY_SIZE] bytes or `iv` is not [ATTACHMENT_IV_SIZE]
+    /// bytes.
+    ///
+    /// The IV must be freshly generated from a secure random source for every attachment.
+    pub fn new(key: &[u8], iv: &[u8]) -> Result<Self> {
+        let (aes_key, mac_key) = split_key(key)?;
+        let iv: [u8; ATTACHMENT_IV_SIZE] = iv.try_into().map_err(|_| Error::InvalidNonceSize)?;
+        let cipher = Aes256Cbc::new_from_slices(aes_key, &iv).map_err(|_| Error::InvalidKeySize)?;
+        Ok(Self {
+            cipher,
+            mac: new_mac(mac_key),
+            digest: Sha256::new(),
+            buffered: Vec::with_capacity(BLOCK_SIZE),
+            iv: Some(iv),
+        })
+    }
+
+    fn emit(&mut self, mut output: Vec<u8>) -> Vec<u8> {
+        if let Some(iv) = self.iv.take() {
+            output.splice(0..0, iv);
+        }
+        self.mac.update(&output);
+        self.digest.update(&output);
+        output
+    }
+
+    /// Encrypts the next part of the plaintext, returning whatever ciphertext is ready.
+    ///
+    /// Plaintext that doesn't fill a whole AES block is held until the next call.
+    pub fn update(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        self.buffered.extend_from_slice(plaintext);
+        let ready = self.buffered.len() - self.buffered.len() % BLOCK_SIZE;
+        let mut output: Vec<u8> = self.buffered.drain(..ready).collect();
+        for_each_block(&mut output, |blocks| self.cipher.encrypt_blocks(blocks));
+        self.emit(output)
+    }
+
+    /// Pads and encrypts any remaining plaintext and appends the MAC.
+    ///
+    /// Returns the end of the ciphertext and the digest of the whole encrypted attachment.
+    pub fn finalize(mut self) -> (Vec<u8>, [u8; ATTACHMENT_DIGEST_SIZE]) {
+        let padding = BLOCK_SIZE - self.buffered.len();
+        let mut output = std::mem::take(&mut self.buffered);
+        output.resize(BLOCK_SIZE, padding as u8);
+        for_each_block(&mut output, |blocks| self.cipher.encrypt_blocks(blocks));
+        let mut output = self.emit(output);
+
+        let mac = self.mac.finalize().into_bytes();
+        self.digest.update(mac);
+        output.extend_from_slice(&mac);
+        (output, self.digest.finalize().into())
+    }
+}
+
+fn for_each_block(data: &mut [u8], mut f: impl FnMut(&mut [GenericArray<u8, U16>])) {
+    for block in data.chunks_exact_mut(BLOCK_SIZE) {
+        f(std::slice::from_mut(GenericArray::from_mut_slice(block)));
+    }
+}
+
+/// Encrypts `plaintext` with `key`, using `iv` as the CBC IV.
+///
+/// The IV must be freshly generated from a secure random source for every attachment.
+pub fn encrypt_attachment(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<EncryptedAttachment> {
+    let mut encryptor = AttachmentEncryptor::new(key, iv)?;
+    let mut ciphertext = encryptor.update(plaintext);
+    let (tail, digest) = encryptor.finalize();
+    ciphertext.extend_from_slice(&tail);
+    Ok(EncryptedAttachment { ciphertext, digest })
+}
+
+/// Checks `ciphertext` against `expected_digest` and its MAC, then decrypts it.
+///
+/// Any mismatch, and any padding error after the MAC has been checked, is reported as
+/// [Error::InvalidTag].
+pub fn decrypt_attachment(
+    key: &[u8],
+    ciphertext: &[u8],
+    expected_digest: &[u8],
+) -> Result<Vec<u8>> {
+    let (aes_key, mac_key) = split_key(key)?;
+    let body_len = ciphertext
+        .len()
+        .checked_sub(ATTACHMENT_IV_SIZE + ATTACHMENT_MAC_SIZE)
+        .ok_or(Error::InvalidInputSize)?;
+    let partial_block = body_len % BLOCK_SIZE;
+    if body_len == 0 || partial_block != 0 {
+        return Err(Error::InvalidInputSize);
+    }
+    verify_digest(ciphertext, expected_digest)?;
+
+    let (authenticated, their_mac) = ciphertext.split_at(ciphertext.len() - ATTACHMENT_MAC_SIZE);
+    let mut mac = new_mac(mac_key);
+    mac.update(authenticated);
+    mac.verify(their_mac).map_err(|_| Error::InvalidTag)?;
+
+    let (iv, body) = authenticated.split_at(ATTACHMENT_IV_SIZE);
+    let mut plaintext = body.to_vec();
+    let mut cipher = Aes256Cbc::new_from_slices(aes_key, iv).map_err(|_| Error::InvalidKeySize)?;
+    for_each_block(&mut plaintext, |blocks| cipher.decrypt_blocks(blocks));
+
+    let padding = usize::from(*plaintext.last().expect("at least one block"));
+    if padding == 0
+        || padding > BLOCK_SIZE
+        || plaintext[plaintext.len() - padding..]
+            .iter()
+            .any(|&b| usize::from(b) != padding)
+    {
+        return Err(Error::InvalidTag);
+    }
+    plaintext.truncate(plaintext.len() - padding);
+    Ok(plaintext)
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a 32-byte `key`.
+///
+/// The nonce must never be reused with the same key.
+pub fn encrypt_attachment_gcm(
+    key: &[u8],
+    nonce: &[u8],
+    plaintext: &[u8],
+) -> Result<EncryptedAttachment> {
+    let mut gcm = Aes256GcmEncryption::new(key, nonce, &[])?;
+    let mut ciphertext = Vec::with_capacity(NONCE_SIZE + plaintext.len() + TAG_SIZE);
+    ciphertext.extend_from_slice(nonce);
+    ciphertext.extend_from_slice(plaintext);
+    gcm.encrypt(&mut ciphertext[NONCE_SIZE..])?;
+    ciphertext.extend_from_slice(&gcm.compute_tag()?);
+    let digest = attachment_digest(&ciphertext);
+    Ok(EncryptedAttachment { ciphertext, digest })
+}
+
+/// Checks `ciphertext` against `expected_digest`, then decrypts it with AES-256-GCM.
+pub fn decrypt_attachment_gcm(
+    key: &[u8],
+    ciphertext: &[u8],
+    expected_digest: &[u8],
+) -> Result<Vec<u8>> {
+    if ciphertext.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(Error::InvalidInputSize);
+    }
+    verify_digest(ciphertext, expected_digest)?;
+
+    let (nonce, rest) = ciphertext.split_at(NONCE_SIZE);
+    let (body, tag) = rest.split_at(rest.len() - TAG_SIZE);
+    let mut gcm = Aes256GcmDecryption::new(key, nonce, &[])?;
+    let mut plaintext = body.to_vec();
+    gcm.decrypt(&mut plaintext)?;
+    gcm.verify_tag(tag)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: [u8; ATTACHMENT_KEY_SIZE] = [0x42; ATTACHMENT_KEY_SIZE];
+    const IV: [u8; ATTACHMENT_IV_SIZE] = [0x07; ATTACHMENT_IV_SIZE];
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        for len in [0, 1, 15, 16, 17, 1000] {
+            let plaintext = vec![0xAB; len];
+            let encrypted = encrypt_attachment(&KEY, &IV, &plaintext)?;
+            assert_eq!(
+                encrypted.ciphertext.len(),
+                ATTACHMENT_IV_SIZE + (len / BLOCK_SIZE + 1) * BLOCK_SIZE + ATTACHMENT_MAC_SIZE
+            );
+            assert_eq!(&encrypted.ciphertext[..ATTACHMENT_IV_SIZE], &IV);
+            assert_eq!(encrypted.digest, attachment_digest(&encrypted.ciphertext));
+
+            // The body is plain AES-256-CBC with PKCS#7 padding.
+            let body = &encrypted.ciphertext
+                [ATTACHMENT_IV_SIZE..encrypted.ciphertext.len() - ATTACHMENT_MAC_SIZE];
+            assert_eq!(
+                crate::aes_256_cbc_encrypt(&plaintext, &KEY[..AES_KEY_SIZE], &IV)
+                    .expect("valid key and IV"),
+                body
+            );
+
+            let decrypted = decrypt_attachment(&KEY, &encrypted.ciphertext, &encrypted.digest)?;
+            assert_eq!(decrypted, plaintext);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() -> Result<()> {
+        let plaintext: Vec<u8> = (0..=255).cycle().take(1001).collect();
+        let expected = encrypt_attachment(&KEY, &IV, &plaintext)?;
+
+        let mut encryptor = AttachmentEncryptor::new(&KEY, &IV)?;
+        let mut ciphertext = Vec::new();
+        for chunk in plaintext.chunks(7) {
+            ciphertext.extend(encryptor.update(chunk));
+        }
+        let (tail, digest) = encryptor.finalize();
+        ciphertext.extend(tail);
+
+        assert_eq!(ciphertext, expected.ciphertext);
+        assert_eq!(digest, expected.digest);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_tampering() -> Result<()> {
+        let encrypted = encrypt_attachment(&KEY, &IV, b"attachment")?;
+
+        let mut wrong_digest = encrypted.digest;
+        wrong_digest[0] ^= 1;
+        assert!(matches!(
+            decrypt_attachment(&KEY, &encrypted.ciphertext, &wrong_digest),
+            Err(Error::InvalidTag)
+        ));
+
+        // A flipped bit with a matching digest still fails the MAC.
+        for i in [0, ATTACHMENT_IV_SIZE, encrypted.ciphertext.len() - 1] {
+            let mut ciphertext = encrypted.ciphertext.clone();
+            ciphertext[i] ^= 1;
+            let digest = attachment_digest(&ciphertext);
+            assert!(matches!(
+                decrypt_attachment(&KEY, &ciphertext, &digest),
+                Err(Error::InvalidTag)
+            ));
+        }
+
+        let mut wrong_key = KEY;
+        wrong_key[ATTACHMENT_KEY_SIZE - 1] ^= 1;
+        assert!(matches!(
+            decrypt_attachment(&wrong_key, &encrypted.ciphertext, &encrypted.digest),
+            Err(Error::InvalidTag)
+        ));
+
+        let truncated = &encrypted.ciphertext[1..];
+        assert!(matches!(
+            decrypt_attachment(&KEY, truncated, &attachment_digest(truncated)),
+            Err(Error::InvalidInputSize)
+        ));
+        assert!(matches!(
+            decrypt_attachment(&KEY[1..], &encrypted.ciphertext, &encrypted.digest),
+            Err(Error::InvalidKeySize)
+        ));
+        assert!(matches!(
+            encrypt_attachment(&KEY, &IV[1..], b""),
+            Err(Error::InvalidNonceSize)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn gcm_round_trip() -> Result<()> {
+        let key = [0x42; 32];
+        let nonce = [0x07; NONCE_SIZE];
+        let encrypted = encrypt_attachment_gcm(&key, &nonce, b"attachment")?;
+        assert_eq!(
+            encrypted.ciphertext.len(),
+            NONCE_SIZE + b"attachment".len() + TAG_SIZE
+        );
+        assert_eq!(
+            decrypt_attachment_gcm(&key, &encrypted.ciphertext, &encrypted.digest)?,
+            b"attachment"
+        );
+
+        let mut ciphertext = encrypted.ciphertext.clone();
+        ciphertext[NONCE_SIZE] ^= 1;
+        assert!(matches!(
+            decrypt_attachment_gcm(&key, &ciphertext, &attachment_digest(&ciphertext)),
+            Err(Error::InvalidTag)
+        ));
+        assert!(matches!(
+            decrypt_attachment_gcm(&key, &encrypted.ciphertext, &[0; ATTACHMENT_DIGEST_SIZE]),
+            Err(Error::InvalidTag)
+        ));
+        Ok(())
+    }
+}
@@ -11,9 +11,21 @@ mod hash;
 mod aes_cbc;
 mod aes_ctr;
 mod aes_gcm;
+mod aes_gcm_siv;
+
+mod attachment;
 
 pub use aes_cbc::{aes_256_cbc_decrypt, aes_256_cbc_encrypt, DecryptionError, EncryptionError};
 pub use aes_ctr::Aes256Ctr32;
 pub use aes_gcm::{Aes256GcmDecryption, Aes256GcmEncryption};
+pub use aes_gcm_siv::{
+    Aes256GcmSiv, Aes256GcmSivChunkedDecryption, Aes256GcmSivChunkedEncryption,
+    CHUNK_NONCE_PREFIX_SIZE, MAX_CHUNK_SIZE, MAX_MESSAGE_SIZE,
+};
+pub use attachment::{
+    attachment_digest, decrypt_attachment, decrypt_attachment_gcm, encrypt_attachment,
+    encrypt_attachment_gcm, AttachmentEncryptor, EncryptedAttachment, ATTACHMENT_DIGEST_SIZE,
+    ATTACHMENT_IV_SIZE, ATTACHMENT_KEY_SIZE, ATTACHMENT_MAC_SIZE,
+};
 pub use error::{Error, Result};
 pub use hash::{CryptographicHash, CryptographicMac};
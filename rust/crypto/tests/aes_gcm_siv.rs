@@ -0,0 +1,184 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use rand::Rng;
+use signal_crypto::{
+    Aes256GcmSiv, Aes256GcmSivChunkedDecryption, Aes256GcmSivChunkedEncryption, Error,
+};
+
+#[test]
+fn aes_gcm_siv_rfc8452_kats() -> Result<(), Error> {
+    // From RFC 8452, Appendix C.2.
+    let key = hex::decode("0100000000000000000000000000000000000000000000000000000000000000")
+        .expect("valid hex");
+    let nonce = hex::decode("030000000000000000000000").expect("valid hex");
+    let cipher = Aes256GcmSiv::new(&key)?;
+
+    for (pt, ct) in [
+        ("", "07f5f4169bbf55a8400cd47ea6fd400f"),
+        (
+            "0100000000000000",
+            "c2ef328e5c71c83b843122130f7364b761e0b97427e3df28",
+        ),
+    ] {
+        let pt = hex::decode(pt).expect("valid hex");
+        assert_eq!(hex::encode(cipher.encrypt(&nonce, &[], &pt)?), ct);
+        let ct = hex::decode(ct).expect("valid hex");
+        assert_eq!(cipher.decrypt(&nonce, &[], &ct)?, pt);
+    }
+    Ok(())
+}
+
+#[test]
+fn aes_gcm_siv_rejects_tampering() -> Result<(), Error> {
+    let mut rng = rand::rngs::OsRng;
+    let key: [u8; 32] = rng.gen();
+    let nonce: [u8; 12] = rng.gen();
+    let cipher = Aes256GcmSiv::new(&key)?;
+
+    let ct = cipher.encrypt(&nonce, b"header", b"database page")?;
+    assert_eq!(ct.len(), b"database page".len() + Aes256GcmSiv::TAG_SIZE);
+    assert_eq!(cipher.decrypt(&nonce, b"header", &ct)?, b"database page");
+
+    assert!(matches!(
+        cipher.decrypt(&nonce, b"other header", &ct),
+        Err(Error::InvalidTag)
+    ));
+    let mut flipped = ct.clone();
+    flipped[0] ^= 1;
+    assert!(matches!(
+        cipher.decrypt(&nonce, b"header", &flipped),
+        Err(Error::InvalidTag)
+    ));
+    assert!(matches!(
+        cipher.decrypt(&nonce, b"header", &ct[..Aes256GcmSiv::TAG_SIZE - 1]),
+        Err(Error::InvalidInputSize)
+    ));
+    assert!(matches!(
+        cipher.encrypt(&nonce[..8], b"header", b"page"),
+        Err(Error::InvalidNonceSize)
+    ));
+    assert!(matches!(
+        Aes256GcmSiv::new(&key[..16]),
+        Err(Error::InvalidKeySize)
+    ));
+    Ok(())
+}
+
+#[test]
+fn aes_gcm_siv_chunked_round_trip() -> Result<(), Error> {
+    let mut rng = rand::rngs::OsRng;
+    let key: [u8; 32] = rng.gen();
+    let nonce_prefix: [u8; 7] = rng.gen();
+    let chunk_size = 64;
+
+    for len in [
+        0,
+        1,
+        chunk_size - 1,
+        chunk_size,
+        chunk_size + 1,
+        5 * chunk_size + 3,
+    ] {
+        let plaintext: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+        let mut encryption =
+            Aes256GcmSivChunkedEncryption::new(&key, &nonce_prefix, b"file", chunk_size)?;
+        let mut chunks = plaintext.chunks(chunk_size).collect::<Vec<_>>();
+        // An exact multiple of the chunk size still ends with a (possibly empty) last chunk.
+        let needs_empty_chunk = match chunks.last() {
+            Some(last) => last.len() == chunk_size,
+            None => true,
+        };
+        if needs_empty_chunk {
+            chunks.push(&[]);
+        }
+        let (last, rest) = chunks.split_last().expect("at least one chunk");
+        let mut encrypted = rest
+            .iter()
+            .map(|chunk| encryption.encrypt_chunk(chunk))
+            .collect::<Result<Vec<_>, _>>()?;
+        encrypted.push(encryption.encrypt_last_chunk(last)?);
+
+        let mut decryption =
+            Aes256GcmSivChunkedDecryption::new(&key, &nonce_prefix, b"file", chunk_size)?;
+        let (last, rest) = encrypted.split_last().expect("at least one chunk");
+        let mut decrypted = Vec::new();
+        for chunk in rest {
+            decrypted.extend(decryption.decrypt_chunk(chunk)?);
+        }
+        decrypted.extend(decryption.decrypt_last_chunk(last)?);
+        assert_eq!(decrypted, plaintext);
+    }
+    Ok(())
+}
+
+#[test]
+fn aes_gcm_siv_chunked_detects_reordering_and_truncation() -> Result<(), Error> {
+    let mut rng = rand::rngs::OsRng;
+    let key: [u8; 32] = rng.gen();
+    let nonce_prefix: [u8; 7] = rng.gen();
+
+    let mut encryption = Aes256GcmSivChunkedEncryption::new(&key, &nonce_prefix, &[], 4)?;
+    let first = encryption.encrypt_chunk(b"abcd")?;
+    let second = encryption.encrypt_chunk(b"efgh")?;
+    let last = encryption.encrypt_last_chunk(b"ij")?;
+
+    let decryption = || Aes256GcmSivChunkedDecryption::new(&key, &nonce_prefix, &[], 4);
+
+    // Swapped chunks.
+    let mut swapped = decryption()?;
+    assert!(matches!(
+        swapped.decrypt_chunk(&second),
+        Err(Error::InvalidTag)
+    ));
+
+    // A stream cut off after a whole chunk.
+    let mut truncated = decryption()?;
+    truncated.decrypt_chunk(&first)?;
+    assert!(matches!(
+        truncated.decrypt_last_chunk(&second),
+        Err(Error::InvalidTag)
+    ));
+
+    // The last chunk passed off as an earlier one.
+    let mut extended = decryption()?;
+    extended.decrypt_chunk(&first)?;
+    extended.decrypt_chunk(&second)?;
+    assert!(matches!(
+        extended.decrypt_chunk(&last),
+        Err(Error::InvalidInputSize)
+    ));
+    Ok(())
+}
+
+#[test]
+fn aes_gcm_siv_chunked_limits() {
+    let key = [0; 32];
+    let prefix = [0; 7];
+    assert!(matches!(
+        Aes256GcmSivChunkedEncryption::new(&key, &prefix, &[], 0),
+        Err(Error::InvalidInputSize)
+    ));
+    assert!(matches!(
+        Aes256GcmSivChunkedEncryption::new(&key, &prefix, &[], signal_crypto::MAX_CHUNK_SIZE + 1),
+        Err(Error::InvalidInputSize)
+    ));
+    assert!(matches!(
+        Aes256GcmSivChunkedEncryption::new(&key, &prefix[..6], &[], 16),
+        Err(Error::InvalidNonceSize)
+    ));
+
+    let mut encryption =
+        Aes256GcmSivChunkedEncryption::new(&key, &prefix, &[], 16).expect("valid parameters");
+    assert!(matches!(
+        encryption.encrypt_chunk(&[0; 15]),
+        Err(Error::InvalidInputSize)
+    ));
+    assert!(matches!(
+        encryption.encrypt_last_chunk(&[0; 17]),
+        Err(Error::InvalidInputSize)
+    ));
+}
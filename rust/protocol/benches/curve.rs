@@ -4,7 +4,7 @@
 //
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use libsignal_protocol::KeyPair;
+use libsignal_protocol::{KeyPair, PublicKey};
 use rand::{thread_rng, Rng};
 
 pub fn generation(c: &mut Criterion) {
@@ -44,6 +44,42 @@ pub fn signatures(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, generation, key_agreement, signatures);
+pub fn batch_signatures(c: &mut Criterion) {
+    let rng = &mut thread_rng();
+    let mut some_data = [0; 1024];
+    rng.fill(&mut some_data);
+
+    let keys_and_sigs: Vec<_> = (0..64)
+        .map(|_| {
+            let key = KeyPair::generate(rng);
+            let sig = key.calculate_signature(&some_data, rng).unwrap();
+            (key.public_key, sig)
+        })
+        .collect();
+    let batch: Vec<(&PublicKey, &[u8], &[u8])> = keys_and_sigs
+        .iter()
+        .map(|(key, sig)| (key, &some_data[..], &sig[..]))
+        .collect();
+
+    c.bench_function("verify 64 signatures individually", |b| {
+        b.iter(|| {
+            batch
+                .iter()
+                .all(|(key, data, sig)| key.verify_signature(data, sig).unwrap())
+        })
+    });
+
+    c.bench_function("verify 64 signatures in a batch", |b| {
+        b.iter(|| PublicKey::verify_signatures_batch(&batch, rng).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    generation,
+    key_agreement,
+    signatures,
+    batch_signatures
+);
 
 criterion_main!(benches);
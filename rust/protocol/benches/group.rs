@@ -0,0 +1,114 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_util::FutureExt;
+use libsignal_protocol::*;
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+pub fn group_encrypt_decrypt_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut csprng = rand::rngs::OsRng;
+
+    let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+    let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+    let mut alice_store = support::test_in_memory_protocol_store()?;
+    let mut bob_store = support::test_in_memory_protocol_store()?;
+
+    let sent_distribution_message = create_sender_key_distribution_message(
+        &sender_address,
+        distribution_id,
+        &mut alice_store,
+        &mut csprng,
+        None,
+    )
+    .now_or_never()
+    .expect("sync")?;
+
+    c.bench_function("group process distribution message", |b| {
+        b.iter(|| {
+            let mut bob_store = bob_store.clone();
+            let recv_distribution_message =
+                SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())
+                    .expect("valid");
+            process_sender_key_distribution_message(
+                &sender_address,
+                &recv_distribution_message,
+                &mut bob_store,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+            .expect("success");
+        })
+    });
+
+    let recv_distribution_message =
+        SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?;
+    process_sender_key_distribution_message(
+        &sender_address,
+        &recv_distribution_message,
+        &mut bob_store,
+        None,
+    )
+    .now_or_never()
+    .expect("sync")?;
+
+    c.bench_function("group encrypt", |b| {
+        b.iter(|| {
+            group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                "a short message".as_bytes(),
+                &mut csprng,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+            .expect("success");
+        })
+    });
+
+    let message_to_decrypt = group_encrypt(
+        &mut alice_store,
+        &sender_address,
+        distribution_id,
+        "a short message".as_bytes(),
+        &mut csprng,
+        None,
+    )
+    .now_or_never()
+    .expect("sync")?;
+
+    c.bench_function("group decrypt", |b| {
+        b.iter(|| {
+            let mut bob_store = bob_store.clone();
+            group_decrypt(
+                message_to_decrypt.serialized(),
+                &mut bob_store,
+                &sender_address,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+            .expect("success");
+        })
+    });
+
+    Ok(())
+}
+
+pub fn group_encrypt_decrypt(c: &mut Criterion) {
+    group_encrypt_decrypt_result(c).expect("success");
+}
+
+criterion_group!(benches, group_encrypt_decrypt);
+
+criterion_main!(benches);
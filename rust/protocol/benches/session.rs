@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion, SamplingMode};
 use futures_util::FutureExt;
 use libsignal_protocol::*;
 use rand::rngs::OsRng;
@@ -115,7 +115,7 @@ pub fn session_encrypt_result(c: &mut Criterion) -> Result<(), SignalProtocolErr
             signed_pre_key_id.into(),
             &SignedPreKeyRecord::new(
                 signed_pre_key_id.into(),
-                /*timestamp*/ 42,
+                Timestamp::from_epoch_millis(42),
                 &bob_signed_pre_key_pair,
                 &bob_signed_pre_key_signature,
             ),
@@ -236,6 +236,210 @@ pub fn session_encrypt_decrypt_result(c: &mut Criterion) -> Result<(), SignalPro
     Ok(())
 }
 
+pub fn session_setup_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+    for (name, with_kyber) in [("x3dh", false), ("pqxdh", true)] {
+        let mut bob_store_builder = support::TestStoreBuilder::new()
+            .with_pre_key(support::IdChoice::Next)
+            .with_signed_pre_key(support::IdChoice::Next);
+        if with_kyber {
+            bob_store_builder.add_kyber_pre_key(support::IdChoice::Next);
+        }
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let bob_store = bob_store_builder.store;
+        let alice_store = support::TestStoreBuilder::new().store;
+
+        c.bench_function(&format!("session setup ({}) process bundle", name), |b| {
+            b.iter(|| {
+                let mut alice_store = alice_store.clone();
+                process_prekey_bundle(
+                    &bob_address,
+                    &mut alice_store.session_store,
+                    &mut alice_store.identity_store,
+                    &bob_pre_key_bundle,
+                    &mut OsRng,
+                    None,
+                )
+                .now_or_never()
+                .expect("sync")
+                .expect("success");
+            })
+        });
+
+        let mut alice_store = alice_store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")?;
+        let first_message = support::encrypt(&mut alice_store, &bob_address, "a short message")
+            .now_or_never()
+            .expect("sync")?;
+
+        c.bench_function(
+            &format!("session setup ({}) decrypt first message", name),
+            |b| {
+                b.iter(|| {
+                    let mut bob_store = bob_store.clone();
+                    support::decrypt(&mut bob_store, &alice_address, &first_message)
+                        .now_or_never()
+                        .expect("sync")
+                        .expect("success");
+                })
+            },
+        );
+    }
+
+    Ok(())
+}
+
+pub fn session_cold_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let (alice_session_record, bob_session_record) = support::initialize_sessions_v4()?;
+
+    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+    let mut alice_store = support::test_in_memory_protocol_store()?;
+    let mut bob_store = support::test_in_memory_protocol_store()?;
+
+    alice_store
+        .store_session(&bob_address, &alice_session_record, None)
+        .now_or_never()
+        .expect("sync")?;
+    bob_store
+        .store_session(&alice_address, &bob_session_record, None)
+        .now_or_never()
+        .expect("sync")?;
+
+    // Get both sides past the first message, so that the records look like long-lived sessions.
+    for _ in 0..3 {
+        let ctext = support::encrypt(&mut alice_store, &bob_address, "a short message")
+            .now_or_never()
+            .expect("sync")?;
+        support::decrypt(&mut bob_store, &alice_address, &ctext)
+            .now_or_never()
+            .expect("sync")?;
+        let ctext = support::encrypt(&mut bob_store, &alice_address, "a short message")
+            .now_or_never()
+            .expect("sync")?;
+        support::decrypt(&mut alice_store, &bob_address, &ctext)
+            .now_or_never()
+            .expect("sync")?;
+    }
+
+    let load = |store: &InMemSignalProtocolStore, address: &ProtocolAddress| {
+        store
+            .load_session(address, None)
+            .now_or_never()
+            .expect("sync")
+            .map(|record| record.expect("present").serialize())
+    };
+    let alice_serialized = load(&alice_store, &bob_address)??;
+    let bob_serialized = load(&bob_store, &alice_address)??;
+    let message_to_decrypt = support::encrypt(&mut alice_store, &bob_address, "a short message")
+        .now_or_never()
+        .expect("sync")?;
+
+    // "Cold" operations start from a serialized record, as if it had just been read from disk,
+    // and serialize the updated record afterwards.
+    c.bench_function("session encrypt (cold)", |b| {
+        b.iter(|| {
+            let mut alice_store = alice_store.clone();
+            let record = SessionRecord::deserialize(&alice_serialized).expect("valid");
+            alice_store
+                .store_session(&bob_address, &record, None)
+                .now_or_never()
+                .expect("sync")
+                .expect("success");
+            support::encrypt(&mut alice_store, &bob_address, "a short message")
+                .now_or_never()
+                .expect("sync")
+                .expect("success");
+            load(&alice_store, &bob_address)
+                .expect("success")
+                .expect("success");
+        })
+    });
+
+    c.bench_function("session decrypt (cold)", |b| {
+        b.iter(|| {
+            let mut bob_store = bob_store.clone();
+            let record = SessionRecord::deserialize(&bob_serialized).expect("valid");
+            bob_store
+                .store_session(&alice_address, &record, None)
+                .now_or_never()
+                .expect("sync")
+                .expect("success");
+            support::decrypt(&mut bob_store, &alice_address, &message_to_decrypt)
+                .now_or_never()
+                .expect("sync")
+                .expect("success");
+            load(&bob_store, &alice_address)
+                .expect("success")
+                .expect("success");
+        })
+    });
+
+    Ok(())
+}
+
+pub fn session_out_of_order_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut group = c.benchmark_group("session decrypt after skipping");
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(10);
+
+    let (alice_session_record, bob_session_record) = support::initialize_sessions_v4()?;
+
+    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+    let mut alice_store = support::test_in_memory_protocol_store()?;
+    let mut bob_store = support::test_in_memory_protocol_store()?;
+
+    alice_store
+        .store_session(&bob_address, &alice_session_record, None)
+        .now_or_never()
+        .expect("sync")?;
+    bob_store
+        .store_session(&alice_address, &bob_session_record, None)
+        .now_or_never()
+        .expect("sync")?;
+
+    let mut skipped = 0;
+    for target in [10, 100, 1000, 1999] {
+        while skipped < target {
+            support::encrypt(&mut alice_store, &bob_address, "lost in transit")
+                .now_or_never()
+                .expect("sync")?;
+            skipped += 1;
+        }
+        let message_to_decrypt = support::encrypt(&mut alice_store, &bob_address, "made it")
+            .now_or_never()
+            .expect("sync")?;
+        skipped += 1;
+
+        group.bench_function(format!("{}", target), |b| {
+            b.iter(|| {
+                let mut bob_store = bob_store.clone();
+                support::decrypt(&mut bob_store, &alice_address, &message_to_decrypt)
+                    .now_or_never()
+                    .expect("sync")
+                    .expect("success");
+            })
+        });
+    }
+
+    group.finish();
+    Ok(())
+}
+
 pub fn session_encrypt(c: &mut Criterion) {
     session_encrypt_result(c).expect("success");
 }
@@ -244,6 +448,25 @@ pub fn session_encrypt_decrypt(c: &mut Criterion) {
     session_encrypt_decrypt_result(c).expect("success");
 }
 
-criterion_group!(benches, session_encrypt, session_encrypt_decrypt);
+pub fn session_setup(c: &mut Criterion) {
+    session_setup_result(c).expect("success");
+}
+
+pub fn session_cold(c: &mut Criterion) {
+    session_cold_result(c).expect("success");
+}
+
+pub fn session_out_of_order(c: &mut Criterion) {
+    session_out_of_order_result(c).expect("success");
+}
+
+criterion_group!(
+    benches,
+    session_encrypt,
+    session_encrypt_decrypt,
+    session_setup,
+    session_cold,
+    session_out_of_order
+);
 
 criterion_main!(benches);
@@ -47,7 +47,7 @@ impl Participant {
                 signed_pre_key_id.into(),
                 &SignedPreKeyRecord::new(
                     signed_pre_key_id,
-                    /*timestamp*/ 42,
+                    Timestamp::from_epoch_millis(42),
                     &their_signed_pre_key_pair,
                     &their_signed_pre_key_signature,
                 ),
@@ -0,0 +1,34 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = PreKeySignalMessage::try_from(data) {
+        let _ = message.message_version();
+        let _ = message.registration_id();
+        let _ = message.pre_key_id();
+        let _ = message.signed_pre_key_id();
+        let _ = message.kyber_pre_key_id();
+        let _ = message.kyber_ciphertext();
+        let _ = message.base_key().serialize();
+        let _ = message.identity_key().serialize();
+        let _ = message.message().body();
+        let _ = message.alternate_identity_signature();
+        let _ = message.verify_alternate_identity(message.identity_key());
+        assert_eq!(message.serialized(), data);
+    }
+    let _ = DecryptionErrorMessage::for_original(
+        data,
+        CiphertextMessageType::PreKey,
+        Timestamp::UNIX_EPOCH,
+        1,
+    );
+});
@@ -0,0 +1,41 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use std::convert::TryFrom;
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    let identity = PrivateKey::deserialize(&[1; 32]).expect("valid key");
+    let identity = IdentityKeyPair::try_from(identity).expect("valid key");
+    let mut identity_store = InMemIdentityKeyStore::new(identity, 1);
+
+    // Parses either version of the envelope, and then the contents if they happen to decrypt.
+    let _ = sealed_sender_decrypt_to_usmc(data, &mut identity_store, None)
+        .now_or_never()
+        .expect("sync");
+
+    // The server-side splitting of multi-recipient messages.
+    if let Ok(messages) = sealed_sender_multi_recipient_fan_out(data) {
+        for message in messages {
+            let _ = sealed_sender_decrypt_to_usmc(&message, &mut identity_store, None)
+                .now_or_never()
+                .expect("sync");
+        }
+    }
+
+    // The decrypted contents.
+    if let Ok(usmc) = UnidentifiedSenderMessageContent::deserialize(data) {
+        let _ = usmc.msg_type();
+        let _ = usmc.sender();
+        let _ = usmc.contents();
+        let _ = usmc.content_hint();
+        let _ = usmc.group_id();
+    }
+});
@@ -0,0 +1,32 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    let trust_root = PrivateKey::deserialize(&[1; 32])
+        .and_then(|key| key.public_key())
+        .expect("valid key");
+
+    if let Ok(certificate) = SenderCertificate::deserialize(data) {
+        let _ = certificate.validate(&trust_root, Timestamp::UNIX_EPOCH);
+        let _ = certificate.sender_uuid();
+        let _ = certificate.sender_e164();
+        let _ = certificate.sender_device_id();
+        let _ = certificate.expiration();
+        let _ = certificate.key().map(|key| key.serialize());
+        let _ = certificate.signer().map(|signer| signer.key_id());
+        assert_eq!(certificate.serialized().expect("present"), data);
+    }
+
+    if let Ok(certificate) = ServerCertificate::deserialize(data) {
+        let _ = certificate.validate(&trust_root);
+        let _ = certificate.key_id();
+        let _ = certificate.public_key().map(|key| key.serialize());
+    }
+});
@@ -0,0 +1,23 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = SenderKeyDistributionMessage::try_from(data) {
+        let _ = message.message_version();
+        let _ = message.distribution_id();
+        let _ = message.chain_id();
+        let _ = message.iteration();
+        let _ = message.chain_key();
+        let _ = message.signing_key().map(PublicKey::serialize);
+        assert_eq!(message.serialized(), data);
+    }
+});
@@ -0,0 +1,33 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = SenderKeyMessage::try_from(data) {
+        let _ = message.message_version();
+        let _ = message.distribution_id();
+        let _ = message.chain_id();
+        let _ = message.iteration();
+        let _ = message.ciphertext();
+        assert_eq!(message.serialized(), data);
+
+        let signing_key = PrivateKey::deserialize(&[1; 32])
+            .and_then(|key| key.public_key())
+            .expect("valid key");
+        let _ = message.verify_signature(&signing_key);
+    }
+    let _ = DecryptionErrorMessage::for_original(
+        data,
+        CiphertextMessageType::SenderKey,
+        Timestamp::UNIX_EPOCH,
+        1,
+    );
+});
@@ -0,0 +1,39 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(record) = SessionRecord::deserialize(data) {
+        let _ = record.session_version();
+        let _ = record.remote_registration_id();
+        let _ = record.local_registration_id();
+        let _ = record.local_identity_key_bytes();
+        let _ = record.remote_identity_key_bytes();
+        let _ = record.alice_base_key();
+        let _ = record.get_receiver_chain_key_bytes(
+            &PrivateKey::deserialize(&[1; 32])
+                .and_then(|key| key.public_key())
+                .expect("valid key"),
+        );
+        let _ = record.has_sender_chain();
+        let _ = record.current_ratchet_key_matches(
+            &PrivateKey::deserialize(&[1; 32])
+                .and_then(|key| key.public_key())
+                .expect("valid key"),
+        );
+
+        // Whatever was accepted must survive a round trip.
+        let reserialized = record.serialize().expect("can serialize a parsed record");
+        let reparsed = SessionRecord::deserialize(&reserialized).expect("can reparse");
+        assert_eq!(
+            reparsed.serialize().expect("can serialize a parsed record"),
+            reserialized
+        );
+    }
+});
@@ -0,0 +1,38 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = SignalMessage::try_from(data) {
+        let _ = message.message_version();
+        let _ = message.body();
+        assert_eq!(message.serialized(), data);
+        // The header fields are only available once the header has been decrypted.
+        assert_eq!(
+            message.sender_ratchet_key().is_ok(),
+            !message.has_encrypted_header()
+        );
+        let _ = message.counter();
+
+        let identity = IdentityKey::new(
+            PrivateKey::deserialize(&[1; 32])
+                .and_then(|key| key.public_key())
+                .expect("valid key"),
+        );
+        let _ = message.verify_mac(&identity, &identity, &[0; 32]);
+    }
+    let _ = DecryptionErrorMessage::for_original(
+        data,
+        CiphertextMessageType::Whisper,
+        Timestamp::UNIX_EPOCH,
+        1,
+    );
+});
@@ -581,9 +581,46 @@ mod service_id_tests {
 /// represents some user.
 ///
 /// Used in [ProtocolAddress].
+///
+/// Converting from a `u32` does not check the value, so that IDs already in storage can always be
+/// loaded; use [DeviceId::new] for IDs coming from outside, or [DeviceId::is_valid] to check one.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct DeviceId(u32);
 
+impl DeviceId {
+    /// The device an account was registered with, which never changes.
+    pub const PRIMARY: Self = Self(1);
+    /// The largest device ID the service will assign.
+    pub const MAX: Self = Self(127);
+
+    /// Create a device ID, failing if `value` is outside `1..=127`.
+    pub fn new(value: u32) -> crate::Result<Self> {
+        let device_id = Self(value);
+        if !device_id.is_valid() {
+            return Err(crate::SignalProtocolError::InvalidArgument(format!(
+                "device id {} is out of range",
+                value
+            )));
+        }
+        Ok(device_id)
+    }
+
+    /// Whether this ID is one the service could have assigned; in particular, not 0.
+    pub fn is_valid(self) -> bool {
+        (Self::PRIMARY..=Self::MAX).contains(&self)
+    }
+
+    /// Whether this is [the primary device](Self::PRIMARY).
+    pub fn is_primary(self) -> bool {
+        self == Self::PRIMARY
+    }
+
+    /// Every valid device ID, in increasing order, starting with [PRIMARY](Self::PRIMARY).
+    pub fn all() -> impl DoubleEndedIterator<Item = Self> + Clone {
+        (Self::PRIMARY.0..=Self::MAX.0).map(Self)
+    }
+}
+
 impl From<u32> for DeviceId {
     fn from(value: u32) -> Self {
         Self(value)
@@ -648,6 +685,27 @@ impl ProtocolAddress {
     pub fn device_id(&self) -> DeviceId {
         self.device_id
     }
+
+    /// One address for each of `device_ids` of the user `name`, sorted by device ID with
+    /// duplicates removed.
+    ///
+    /// Fails if any of the IDs is not [valid](DeviceId::is_valid), so that a stray 0 in a device
+    /// list is caught before anything is encrypted to it.
+    pub fn for_devices(
+        name: &str,
+        device_ids: impl IntoIterator<Item = DeviceId>,
+    ) -> crate::Result<Vec<Self>> {
+        let mut device_ids = device_ids
+            .into_iter()
+            .map(|device_id| DeviceId::new(device_id.into()))
+            .collect::<crate::Result<Vec<_>>>()?;
+        device_ids.sort_unstable();
+        device_ids.dedup();
+        Ok(device_ids
+            .into_iter()
+            .map(|device_id| Self::new(name.to_owned(), device_id))
+            .collect())
+    }
 }
 
 impl fmt::Display for ProtocolAddress {
@@ -655,3 +713,179 @@ impl fmt::Display for ProtocolAddress {
         write!(f, "{}.{}", self.name, self.device_id)
     }
 }
+
+#[cfg(test)]
+mod device_id_tests {
+    use super::*;
+
+    #[test]
+    fn range() {
+        assert!(DeviceId::new(0).is_err());
+        assert!(DeviceId::new(128).is_err());
+        assert_eq!(DeviceId::new(1).expect("valid"), DeviceId::PRIMARY);
+        assert!(DeviceId::PRIMARY.is_primary());
+        assert!(!DeviceId::from(2).is_primary());
+        // Out-of-range IDs can still be constructed, just not with `new`.
+        assert!(!DeviceId::from(0).is_valid());
+    }
+
+    #[test]
+    fn all_devices() {
+        let all: Vec<DeviceId> = DeviceId::all().collect();
+        assert_eq!(all.len(), 127);
+        assert_eq!(all.first(), Some(&DeviceId::PRIMARY));
+        assert_eq!(all.last(), Some(&DeviceId::MAX));
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn addresses_for_devices() {
+        let addresses = ProtocolAddress::for_devices(
+            "alice",
+            [3, 1, 3, 2].iter().map(|&id| DeviceId::from(id)),
+        )
+        .expect("valid");
+        assert_eq!(
+            addresses
+                .iter()
+                .map(|address| u32::from(address.device_id()))
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(addresses.iter().all(|address| address.name() == "alice"));
+
+        assert!(ProtocolAddress::for_devices("alice", vec![DeviceId::from(0)]).is_err());
+    }
+}
+
+/// Identifies a sender key: the sender's address together with the distribution it belongs to.
+///
+/// This is the key used by [SenderKeyStore](crate::SenderKeyStore), so that the two halves can't be
+/// passed in the wrong order or mixed up with those of a different sender key.
+///
+/// The [Display](fmt::Display) form is `<name>.<device ID>::<distribution ID>`, and can be turned
+/// back into a `SenderKeyName` with [SenderKeyName::parse_from_string].
+#[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct SenderKeyName {
+    sender: ProtocolAddress,
+    distribution_id: Uuid,
+}
+
+impl SenderKeyName {
+    /// Create a new name for the sender key `sender` uses for `distribution_id`.
+    ///
+    ///```
+    /// use libsignal_protocol::{ProtocolAddress, SenderKeyName};
+    /// use uuid::Uuid;
+    ///
+    /// let sender = ProtocolAddress::new("+14151111111".to_string(), 1.into());
+    /// let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+    /// let name = SenderKeyName::new(sender.clone(), distribution_id);
+    ///
+    /// assert_eq!(name.sender(), &sender);
+    /// assert_eq!(name.distribution_id(), distribution_id);
+    /// assert_eq!(
+    ///     name.to_string(),
+    ///     "+14151111111.1::d1d1d1d1-7000-11eb-b32a-33b8a8a487a6"
+    /// );
+    ///```
+    pub fn new(sender: ProtocolAddress, distribution_id: Uuid) -> Self {
+        Self {
+            sender,
+            distribution_id,
+        }
+    }
+
+    /// The address of the client that sends messages with this sender key.
+    #[inline]
+    pub fn sender(&self) -> &ProtocolAddress {
+        &self.sender
+    }
+
+    /// The distribution (usually a group) this sender key is used for.
+    #[inline]
+    pub fn distribution_id(&self) -> Uuid {
+        self.distribution_id
+    }
+
+    /// Parse the [Display](fmt::Display) form of a `SenderKeyName`.
+    ///
+    /// Returns `None` if `input` is not of the form `<name>.<device ID>::<distribution ID>`.
+    pub fn parse_from_string(input: &str) -> Option<Self> {
+        let (address, distribution_id) = input.rsplit_once("::")?;
+        let (name, device_id) = address.rsplit_once('.')?;
+        let device_id: u32 = device_id.parse().ok()?;
+        let distribution_id = Uuid::try_parse(distribution_id).ok()?;
+        Some(Self::new(
+            ProtocolAddress::new(name.to_owned(), device_id.into()),
+            distribution_id,
+        ))
+    }
+}
+
+impl fmt::Display for SenderKeyName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}::{}",
+            self.sender,
+            self.distribution_id.as_hyphenated()
+        )
+    }
+}
+
+#[cfg(test)]
+mod sender_key_name_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_string() {
+        let distribution_id = uuid::uuid!("d1d1d1d1-7000-11eb-b32a-33b8a8a487a6");
+        for name in ["+14151111111", "a.b", "with::colons", ""] {
+            let sender_key_name = SenderKeyName::new(
+                ProtocolAddress::new(name.to_owned(), 7.into()),
+                distribution_id,
+            );
+            let serialized = sender_key_name.to_string();
+            assert_eq!(
+                SenderKeyName::parse_from_string(&serialized),
+                Some(sender_key_name),
+                "{serialized}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_strings() {
+        for input in [
+            "",
+            "+14151111111.1",
+            "+14151111111::d1d1d1d1-7000-11eb-b32a-33b8a8a487a6",
+            "+14151111111.x::d1d1d1d1-7000-11eb-b32a-33b8a8a487a6",
+            "+14151111111.1::not-a-uuid",
+            "d1d1d1d1-7000-11eb-b32a-33b8a8a487a6::+14151111111.1",
+        ] {
+            assert!(
+                SenderKeyName::parse_from_string(input).is_none(),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn distinguishes_components() {
+        let alice = ProtocolAddress::new("alice".to_owned(), 1.into());
+        let first = Uuid::from_u128(1);
+        let second = Uuid::from_u128(2);
+        let names: std::collections::HashSet<SenderKeyName> = vec![
+            SenderKeyName::new(alice.clone(), first),
+            SenderKeyName::new(alice.clone(), second),
+            SenderKeyName::new(ProtocolAddress::new("alice".to_owned(), 2.into()), first),
+            SenderKeyName::new(alice, first),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(names.len(), 3);
+    }
+}
@@ -0,0 +1,397 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! [`Arbitrary`] implementations for property-testing code built on this crate.
+//!
+//! Only available with the `proptest` feature. The generated values are realistic rather than
+//! merely well-typed: keys are valid, bundles carry valid signatures, and messages carry valid
+//! MACs and signatures (for keys that are generated along with them and then thrown away). Keys
+//! and signatures are derived from proptest's own randomness, so failures replay, with the
+//! exception of Kyber keys and ciphertexts, which always come from the system's randomness (see
+//! [`kem::KeyPair::generate`]).
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use uuid::Uuid;
+
+use crate::protocol::{
+    CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION,
+    SENDERKEY_MESSAGE_CURRENT_VERSION,
+};
+use crate::{
+    kem, Aci, CiphertextMessage, CiphertextMessageType, DecryptionErrorMessage, DeviceId,
+    IdentityKey, IdentityKeyPair, KeyPair, KyberPayload, PlaintextContent, PreKeyBundle,
+    PreKeySignalMessage, PrivateKey, ProtocolAddress, PublicKey, SenderKeyDistributionMessage,
+    SenderKeyMessage, SignalMessage, Timestamp,
+};
+
+/// Ciphertexts are AES-CBC output: a whole number of blocks.
+fn cbc_ciphertext() -> impl Strategy<Value = Vec<u8>> {
+    (1usize..16).prop_flat_map(|blocks| vec(any::<u8>(), blocks * 16))
+}
+
+fn message_version() -> impl Strategy<Value = u8> {
+    prop_oneof![
+        Just(CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION),
+        Just(CIPHERTEXT_MESSAGE_CURRENT_VERSION),
+    ]
+}
+
+fn kyber_ciphertext() -> kem::SerializedCiphertext {
+    kem::KeyPair::generate(kem::KeyType::Kyber1024)
+        .public_key
+        .encapsulate()
+        .1
+}
+
+impl Arbitrary for PrivateKey {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<[u8; 32]>()
+            .prop_map(|bytes| PrivateKey::deserialize(&bytes).expect("correct length"))
+            .boxed()
+    }
+}
+
+impl Arbitrary for KeyPair {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<PrivateKey>()
+            .prop_map(|private_key| {
+                KeyPair::new(private_key.public_key().expect("valid key"), private_key)
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for PublicKey {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<KeyPair>().prop_map(|pair| pair.public_key).boxed()
+    }
+}
+
+impl Arbitrary for IdentityKeyPair {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<KeyPair>().prop_map(IdentityKeyPair::from).boxed()
+    }
+}
+
+impl Arbitrary for IdentityKey {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<PublicKey>().prop_map(IdentityKey::new).boxed()
+    }
+}
+
+impl Arbitrary for DeviceId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        // Device 1 is the primary device, and by far the most common.
+        prop_oneof![Just(1), 2u32..128]
+            .prop_map(DeviceId::from)
+            .boxed()
+    }
+}
+
+impl Arbitrary for ProtocolAddress {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<[u8; 16]>(), any::<DeviceId>())
+            .prop_map(|(uuid, device_id)| {
+                ProtocolAddress::new(Aci::from_uuid_bytes(uuid).service_id_string(), device_id)
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for PreKeyBundle {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    /// Bundles with and without a one-time pre-key, and with and without a Kyber pre-key.
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<IdentityKeyPair>(),
+            1u32..16380,
+            any::<DeviceId>(),
+            proptest::option::of((0u32..0xFFFFFF, any::<PublicKey>())),
+            (0u32..0xFFFFFF, any::<PublicKey>()),
+            proptest::option::of(0u32..0xFFFFFF),
+            any::<bool>(),
+            any::<[u8; 32]>(),
+        )
+            .prop_map(
+                |(
+                    identity,
+                    registration_id,
+                    device_id,
+                    pre_key,
+                    (signed_pre_key_id, signed_pre_key),
+                    kyber_pre_key_id,
+                    supports_header_encryption,
+                    seed,
+                )| {
+                    let mut csprng = StdRng::from_seed(seed);
+                    let signature = identity
+                        .private_key()
+                        .calculate_signature(&signed_pre_key.serialize(), &mut csprng)
+                        .expect("can sign");
+                    let mut bundle = PreKeyBundle::new(
+                        registration_id,
+                        device_id,
+                        pre_key.map(|(id, key)| (id.into(), key)),
+                        signed_pre_key_id.into(),
+                        signed_pre_key,
+                        signature.into_vec(),
+                        *identity.identity_key(),
+                    )
+                    .expect("valid bundle");
+                    if let Some(id) = kyber_pre_key_id {
+                        let kyber_key = kem::KeyPair::generate(kem::KeyType::Kyber1024).public_key;
+                        let signature = identity
+                            .private_key()
+                            .calculate_signature(&kyber_key.serialize(), &mut csprng)
+                            .expect("can sign");
+                        bundle =
+                            bundle.with_kyber_pre_key(id.into(), kyber_key, signature.into_vec());
+                        if supports_header_encryption {
+                            bundle = bundle.with_header_encryption_support();
+                        }
+                    }
+                    bundle
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for SignalMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            message_version(),
+            any::<[u8; 32]>(),
+            any::<PublicKey>(),
+            any::<u32>(),
+            any::<u32>(),
+            cbc_ciphertext(),
+            any::<IdentityKey>(),
+            any::<IdentityKey>(),
+        )
+            .prop_map(
+                |(
+                    message_version,
+                    mac_key,
+                    sender_ratchet_key,
+                    counter,
+                    previous_counter,
+                    ciphertext,
+                    sender_identity_key,
+                    receiver_identity_key,
+                )| {
+                    SignalMessage::new(
+                        message_version,
+                        &mac_key,
+                        sender_ratchet_key,
+                        counter,
+                        previous_counter,
+                        &ciphertext,
+                        &sender_identity_key,
+                        &receiver_identity_key,
+                    )
+                    .expect("valid message")
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for PreKeySignalMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<SignalMessage>(),
+            1u32..16380,
+            proptest::option::of(0u32..0xFFFFFF),
+            0u32..0xFFFFFF,
+            0u32..0xFFFFFF,
+            any::<PublicKey>(),
+            any::<IdentityKey>(),
+        )
+            .prop_map(
+                |(
+                    message,
+                    registration_id,
+                    pre_key_id,
+                    signed_pre_key_id,
+                    kyber_pre_key_id,
+                    base_key,
+                    identity_key,
+                )| {
+                    let message_version = message.message_version();
+                    let kyber_payload = (message_version > CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION)
+                        .then(|| KyberPayload::new(kyber_pre_key_id.into(), kyber_ciphertext()));
+                    PreKeySignalMessage::new(
+                        message_version,
+                        registration_id,
+                        pre_key_id.map(Into::into),
+                        signed_pre_key_id.into(),
+                        kyber_payload,
+                        base_key,
+                        identity_key,
+                        message,
+                    )
+                    .expect("valid message")
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for SenderKeyMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<[u8; 16]>(),
+            any::<u32>(),
+            any::<u32>(),
+            cbc_ciphertext(),
+            any::<PrivateKey>(),
+            any::<[u8; 32]>(),
+        )
+            .prop_map(
+                |(distribution_id, chain_id, iteration, ciphertext, signing_key, seed)| {
+                    SenderKeyMessage::new(
+                        SENDERKEY_MESSAGE_CURRENT_VERSION,
+                        Uuid::from_bytes(distribution_id),
+                        chain_id,
+                        iteration,
+                        ciphertext.into_boxed_slice(),
+                        &mut StdRng::from_seed(seed),
+                        &signing_key,
+                    )
+                    .expect("valid message")
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for SenderKeyDistributionMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<[u8; 16]>(),
+            any::<u32>(),
+            any::<u32>(),
+            any::<[u8; 32]>(),
+            any::<PublicKey>(),
+        )
+            .prop_map(
+                |(distribution_id, chain_id, iteration, chain_key, signing_key)| {
+                    SenderKeyDistributionMessage::new(
+                        SENDERKEY_MESSAGE_CURRENT_VERSION,
+                        Uuid::from_bytes(distribution_id),
+                        chain_id,
+                        iteration,
+                        chain_key.to_vec(),
+                        signing_key,
+                    )
+                    .expect("valid message")
+                },
+            )
+            .boxed()
+    }
+}
+
+impl Arbitrary for CiphertextMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    /// Any of the four kinds of message; the plaintext ones are decryption error messages, the
+    /// only kind of plaintext content there is.
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            any::<SignalMessage>().prop_map(CiphertextMessage::SignalMessage),
+            any::<PreKeySignalMessage>().prop_map(CiphertextMessage::PreKeySignalMessage),
+            any::<SenderKeyMessage>().prop_map(CiphertextMessage::SenderKeyMessage),
+            (any::<SignalMessage>(), any::<u64>(), any::<DeviceId>()).prop_map(
+                |(original, timestamp, device_id)| {
+                    let error = DecryptionErrorMessage::for_original(
+                        original.serialized(),
+                        CiphertextMessageType::Whisper,
+                        Timestamp::from_epoch_millis(timestamp),
+                        device_id.into(),
+                    )
+                    .expect("valid message");
+                    CiphertextMessage::PlaintextContent(PlaintextContent::from(error))
+                }
+            ),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn bundles_are_valid(bundle: PreKeyBundle) {
+            let identity_key = *bundle.identity_key().expect("present");
+            prop_assert!(bundle.validate(&identity_key).expect("can validate").is_valid());
+        }
+
+        #[test]
+        fn messages_parse(message: CiphertextMessage) {
+            let serialized = message.serialize();
+            match message {
+                CiphertextMessage::SignalMessage(_) => {
+                    SignalMessage::try_from(serialized).expect("parses");
+                }
+                CiphertextMessage::PreKeySignalMessage(_) => {
+                    PreKeySignalMessage::try_from(serialized).expect("parses");
+                }
+                CiphertextMessage::SenderKeyMessage(_) => {
+                    SenderKeyMessage::try_from(serialized).expect("parses");
+                }
+                CiphertextMessage::PlaintextContent(_) => {
+                    PlaintextContent::try_from(serialized).expect("parses");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Authenticating data that travels outside the ratchet, under keys derived from a session.
+//!
+//! Some payloads (typing indicators, receipts, and the like) don't need the forward secrecy of a
+//! full [message_encrypt](crate::message_encrypt), but still shouldn't be forgeable by anyone
+//! other than the other party to the session. Each session records a secret, derived along with
+//! its initial root key, from which [derive_mac_key] produces a separate key for each `label` and
+//! [Direction]. The key one party derives for [Direction::Sending] is the key the other derives
+//! for [Direction::Receiving], and the two directions never share a key, so a MAC can't be
+//! reflected back to the party that produced it. These keys stay the same for as long as the
+//! session lasts, no matter how far the ratchet has advanced; once a new session replaces it, the
+//! keys change too.
+//!
+//! A MAC only says who produced the data, not when. Nothing here stops a MAC'd payload from being
+//! recorded and delivered again later in the same session; if that matters, include a timestamp
+//! or sequence number in the authenticated data and have the receiver reject ones it has already
+//! seen.
+//!
+//! Sessions created by older versions of this library don't have the secret, and
+//! [derive_mac_key] fails for them.
+
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::crypto::hmac_sha256;
+use crate::{Direction, Result, SessionRecord, SignalProtocolError};
+
+/// The length of a MAC produced by [authenticate].
+pub const MAC_LENGTH: usize = 32;
+
+/// Derive the key for authenticating data labelled `label` in the current session of `session`.
+///
+/// Use [Direction::Sending] to produce MACs for the other party and [Direction::Receiving] to
+/// check MACs from them. Use a different label for each kind of data, so a MAC over one can't be
+/// replayed as another.
+pub fn derive_mac_key(
+    session: &SessionRecord,
+    direction: Direction,
+    label: &[u8],
+) -> Result<[u8; 32]> {
+    let state = session.session_state().ok_or_else(|| {
+        SignalProtocolError::InvalidState("derive_mac_key", "No current session".into())
+    })?;
+    let secret = state.auxiliary_auth_key().ok_or_else(|| {
+        SignalProtocolError::InvalidState(
+            "derive_mac_key",
+            "session predates auxiliary authentication".into(),
+        )
+    })?;
+
+    let local_identity = state.local_identity_key()?;
+    let remote_identity = state.remote_identity_key()?.ok_or_else(|| {
+        SignalProtocolError::InvalidState("derive_mac_key", "No remote identity key".into())
+    })?;
+    let (sender, receiver) = match direction {
+        Direction::Sending => (local_identity, remote_identity),
+        Direction::Receiving => (remote_identity, local_identity),
+    };
+
+    let mut key = [0; 32];
+    hkdf::Hkdf::<Sha256>::new(None, secret)
+        .expand_multi_info(
+            &[
+                b"Signal_AuxiliaryMac_",
+                &sender.serialize(),
+                &receiver.serialize(),
+                label,
+            ],
+            &mut key,
+        )
+        .expect("valid length");
+    Ok(key)
+}
+
+/// Compute a MAC over `data` with a key from [derive_mac_key].
+pub fn authenticate(key: &[u8; 32], data: &[u8]) -> [u8; MAC_LENGTH] {
+    hmac_sha256(key, data)
+}
+
+/// Check, in constant time, that `mac` was produced by [authenticate] with the same key and data.
+pub fn verify(key: &[u8; 32], data: &[u8], mac: &[u8]) -> bool {
+    authenticate(key, data).ct_eq(mac).into()
+}
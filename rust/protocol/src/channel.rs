@@ -0,0 +1,379 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A Double Ratchet channel with a single peer, for protocols that just need to move bytes.
+//!
+//! [`SecureChannel`] owns all of its protocol state, so callers don't deal with stores, addresses,
+//! or message types: [`send`](SecureChannel::send) turns plaintext into an opaque blob, and
+//! [`recv`](SecureChannel::recv) turns the peer's blobs back into plaintext. Delivery is up to the
+//! caller; messages may arrive out of order, but each one can only be received once.
+//!
+//! A channel is set up in one of two ways:
+//!
+//! - The responder creates a [`SecureChannelListener`] and publishes its
+//!   [`bundle`](SecureChannelListener::bundle). The initiator calls
+//!   [`SecureChannel::with_bundle`], and the responder calls
+//!   [`accept`](SecureChannelListener::accept) with the first message it receives.
+//! - Both sides already share a secret, and call [`SecureChannel::with_shared_secret`] with
+//!   opposite [`ChannelRole`]s. Anyone who knows the secret can read messages until the first
+//!   reply from the responder ratchets in fresh keys, so the secret should be high-entropy and
+//!   used for a single channel.
+//!
+//! The channel is built on the in-memory stores; to persist a channel, use the regular store-based
+//! APIs instead.
+
+#![warn(missing_docs)]
+
+use std::convert::TryFrom;
+
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng};
+use sha2::Sha256;
+
+use crate::storage::block_on;
+use crate::{
+    generate_registration_id, initialize_alice_session_record, initialize_bob_session_record,
+    message_decrypt_prekey, message_decrypt_signal, message_encrypt, process_prekey_bundle,
+    AliceSignalProtocolParameters, BobSignalProtocolParameters, CiphertextMessageType, DeviceId,
+    GenericSignedPreKey, IdentityKey, IdentityKeyPair, IdentityKeyStore, InMemSignalProtocolStore,
+    KeyPair, PreKeyBundle, PreKeyRecord, PreKeySignalMessage, PreKeyStore, PrivateKey,
+    ProtocolAddress, Result, SessionStore, SignalMessage, SignalProtocolError, SignedPreKeyRecord,
+    SignedPreKeyStore,
+};
+
+const PRE_KEY_ID: u32 = 1;
+const SIGNED_PRE_KEY_ID: u32 = 1;
+
+/// Which end of a [`SecureChannel::with_shared_secret`] channel this is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelRole {
+    /// The side that sends first.
+    Initiator,
+    /// The other side.
+    Responder,
+}
+
+/// An established Double Ratchet channel with one peer.
+///
+/// The channel keeps `csprng` for the fresh ratchet keys it generates as messages go back and
+/// forth; pass a seeded generator to make a channel's traffic reproducible.
+pub struct SecureChannel<R = OsRng> {
+    store: InMemSignalProtocolStore,
+    csprng: R,
+}
+
+impl<R: Rng + CryptoRng> SecureChannel<R> {
+    /// Start a channel as the initiator, using a bundle published by a [`SecureChannelListener`]
+    /// (or any other pre-key bundle for the peer).
+    pub fn with_bundle(
+        identity_key_pair: IdentityKeyPair,
+        peer_bundle: &PreKeyBundle,
+        mut csprng: R,
+    ) -> Result<Self> {
+        let mut store = new_store(identity_key_pair, &mut csprng)?;
+        block_on(process_prekey_bundle(
+            &peer_address(),
+            &mut store.session_store,
+            &mut store.identity_store,
+            peer_bundle,
+            &mut csprng,
+            None,
+        ))?;
+        Ok(Self { store, csprng })
+    }
+
+    /// Set up a channel from a secret both sides already know, without exchanging any messages.
+    ///
+    /// Both sides must pass the same `secret` and opposite `role`s.
+    pub fn with_shared_secret(secret: &[u8], role: ChannelRole, mut csprng: R) -> Result<Self> {
+        let mut key_material = [0u8; 32 * 4];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(b"Signal_SecureChannel_SharedSecret", &mut key_material)
+            .expect("valid output length");
+        let mut keys = key_material.chunks(32).map(|bytes| -> Result<KeyPair> {
+            let private_key = PrivateKey::deserialize(bytes)?;
+            Ok(KeyPair::new(private_key.public_key()?, private_key))
+        });
+        let mut next_key = || keys.next().expect("four keys derived");
+        let initiator_identity = IdentityKeyPair::from(next_key()?);
+        let responder_identity = IdentityKeyPair::from(next_key()?);
+        let initiator_base_key = next_key()?;
+        let responder_signed_pre_key = next_key()?;
+
+        let (identity_key_pair, record) = match role {
+            ChannelRole::Initiator => {
+                let parameters = AliceSignalProtocolParameters::new(
+                    initiator_identity,
+                    initiator_base_key,
+                    *responder_identity.identity_key(),
+                    responder_signed_pre_key.public_key,
+                    responder_signed_pre_key.public_key,
+                );
+                (
+                    initiator_identity,
+                    initialize_alice_session_record(&parameters, &mut csprng)?,
+                )
+            }
+            ChannelRole::Responder => {
+                let parameters = BobSignalProtocolParameters::new(
+                    responder_identity,
+                    responder_signed_pre_key,
+                    None,
+                    responder_signed_pre_key,
+                    None,
+                    *initiator_identity.identity_key(),
+                    initiator_base_key.public_key,
+                    None,
+                );
+                (
+                    responder_identity,
+                    initialize_bob_session_record(&parameters)?,
+                )
+            }
+        };
+
+        let mut store = new_store(identity_key_pair, &mut csprng)?;
+        block_on(
+            store
+                .session_store
+                .store_session(&peer_address(), &record, None),
+        )?;
+        Ok(Self { store, csprng })
+    }
+
+    /// Encrypt `plaintext` for the peer.
+    pub fn send(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let message = block_on(message_encrypt(
+            plaintext,
+            &peer_address(),
+            &mut self.store.session_store,
+            &mut self.store.identity_store,
+            None,
+        ))?;
+        let mut result = vec![message.message_type() as u8];
+        result.extend_from_slice(message.serialize());
+        Ok(result)
+    }
+
+    /// Decrypt a message produced by the peer's [`send`](Self::send).
+    pub fn recv(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        let store = &mut self.store;
+        match parse(message)? {
+            (CiphertextMessageType::Whisper, body) => block_on(message_decrypt_signal(
+                &SignalMessage::try_from(body)?,
+                &peer_address(),
+                &mut store.session_store,
+                &mut store.identity_store,
+                &mut self.csprng,
+                None,
+            )),
+            // The initiator keeps sending PreKey messages until it hears back.
+            (CiphertextMessageType::PreKey, body) => block_on(message_decrypt_prekey(
+                &PreKeySignalMessage::try_from(body)?,
+                &peer_address(),
+                &mut store.session_store,
+                &mut store.identity_store,
+                &mut store.pre_key_store,
+                &mut store.signed_pre_key_store,
+                &mut store.kyber_pre_key_store,
+                &mut self.csprng,
+                None,
+            )),
+            (message_type, _) => Err(SignalProtocolError::InvalidMessage(
+                message_type,
+                "unexpected message type for a secure channel",
+            )),
+        }
+    }
+
+    /// The peer's identity key, which callers may want to verify out of band.
+    pub fn peer_identity(&self) -> Result<Option<IdentityKey>> {
+        block_on(self.store.get_identity(&peer_address(), None))
+    }
+
+    /// Our own identity key.
+    pub fn local_identity(&self) -> Result<IdentityKey> {
+        Ok(*block_on(self.store.get_identity_key_pair(None))?.identity_key())
+    }
+}
+
+/// The responder's half of a [`SecureChannel`] that hasn't received its first message yet.
+pub struct SecureChannelListener<R = OsRng> {
+    store: InMemSignalProtocolStore,
+    bundle: PreKeyBundle,
+    csprng: R,
+}
+
+impl<R: Rng + CryptoRng> SecureChannelListener<R> {
+    /// Generate the pre-keys for a new channel, signed with `identity_key_pair`.
+    ///
+    /// `csprng` is kept for the channel returned by [`accept`](Self::accept).
+    pub fn new(identity_key_pair: IdentityKeyPair, mut csprng: R) -> Result<Self> {
+        let mut store = new_store(identity_key_pair, &mut csprng)?;
+
+        let pre_key = KeyPair::generate(&mut csprng);
+        let signed_pre_key = SignedPreKeyRecord::generate(
+            SIGNED_PRE_KEY_ID.into(),
+            identity_key_pair.private_key(),
+            &mut csprng,
+        )?;
+        block_on(store.save_pre_key(
+            PRE_KEY_ID.into(),
+            &PreKeyRecord::new(PRE_KEY_ID.into(), &pre_key),
+            None,
+        ))?;
+        block_on(store.save_signed_pre_key(SIGNED_PRE_KEY_ID.into(), &signed_pre_key, None))?;
+
+        let bundle = PreKeyBundle::new(
+            block_on(store.get_local_registration_id(None))?,
+            local_device_id(),
+            Some((PRE_KEY_ID.into(), pre_key.public_key)),
+            SIGNED_PRE_KEY_ID.into(),
+            signed_pre_key.public_key()?,
+            signed_pre_key.signature()?,
+            *identity_key_pair.identity_key(),
+        )?;
+        Ok(Self {
+            store,
+            bundle,
+            csprng,
+        })
+    }
+
+    /// The bundle to give to the initiator.
+    pub fn bundle(&self) -> &PreKeyBundle {
+        &self.bundle
+    }
+
+    /// Complete the channel using the initiator's first message, returning the channel along with
+    /// the decrypted contents of that message.
+    pub fn accept(self, first_message: &[u8]) -> Result<(SecureChannel<R>, Vec<u8>)> {
+        let mut channel = SecureChannel {
+            store: self.store,
+            csprng: self.csprng,
+        };
+        let plaintext = channel.recv(first_message)?;
+        Ok((channel, plaintext))
+    }
+}
+
+fn new_store<R: Rng + CryptoRng>(
+    identity_key_pair: IdentityKeyPair,
+    csprng: &mut R,
+) -> Result<InMemSignalProtocolStore> {
+    let registration_id = generate_registration_id(csprng);
+    InMemSignalProtocolStore::new(identity_key_pair, registration_id)
+}
+
+/// Each channel only has one peer, so the address is just a placeholder.
+fn peer_address() -> ProtocolAddress {
+    ProtocolAddress::new("peer".to_owned(), local_device_id())
+}
+
+fn local_device_id() -> DeviceId {
+    1.into()
+}
+
+fn parse(message: &[u8]) -> Result<(CiphertextMessageType, &[u8])> {
+    let (&message_type, body) =
+        message
+            .split_first()
+            .ok_or(SignalProtocolError::CiphertextMessageTooShort(
+                message.len(),
+            ))?;
+    let message_type = CiphertextMessageType::try_from(message_type).map_err(|_| {
+        SignalProtocolError::InvalidArgument(format!("unknown message type {}", message_type))
+    })?;
+    Ok((message_type, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_from_bundle() -> Result<()> {
+        let listener = SecureChannelListener::new(IdentityKeyPair::generate(&mut OsRng), OsRng)?;
+        let mut initiator = SecureChannel::with_bundle(
+            IdentityKeyPair::generate(&mut OsRng),
+            listener.bundle(),
+            OsRng,
+        )?;
+
+        let first = initiator.send(b"hello")?;
+        let second = initiator.send(b"still there?")?;
+        let (mut responder, plaintext) = listener.accept(&first)?;
+        assert_eq!(plaintext, b"hello");
+        assert_eq!(responder.recv(&second)?, b"still there?");
+        assert_eq!(
+            responder.peer_identity()?,
+            Some(initiator.local_identity()?)
+        );
+
+        let reply = responder.send(b"yes")?;
+        assert_eq!(initiator.recv(&reply)?, b"yes");
+        assert!(initiator.recv(&reply).is_err(), "replays are rejected");
+
+        for i in 0..5 {
+            let message = format!("message {}", i);
+            let ctext = initiator.send(message.as_bytes())?;
+            assert_eq!(responder.recv(&ctext)?, message.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_from_shared_secret() -> Result<()> {
+        let secret = [0x5A; 32];
+        let mut initiator =
+            SecureChannel::with_shared_secret(&secret, ChannelRole::Initiator, OsRng)?;
+        let mut responder =
+            SecureChannel::with_shared_secret(&secret, ChannelRole::Responder, OsRng)?;
+
+        let ctext = initiator.send(b"ping")?;
+        assert_eq!(responder.recv(&ctext)?, b"ping");
+        let ctext = responder.send(b"pong")?;
+        assert_eq!(initiator.recv(&ctext)?, b"pong");
+
+        let mut eavesdropper =
+            SecureChannel::with_shared_secret(b"some other secret", ChannelRole::Responder, OsRng)?;
+        assert!(eavesdropper.recv(&initiator.send(b"ping")?).is_err());
+
+        assert!(matches!(
+            responder.recv(&[]),
+            Err(SignalProtocolError::CiphertextMessageTooShort(0))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_with_seeded_rng() -> Result<()> {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let secret = [0x5A; 32];
+        let run = || -> Result<Vec<Vec<u8>>> {
+            let mut initiator = SecureChannel::with_shared_secret(
+                &secret,
+                ChannelRole::Initiator,
+                StdRng::seed_from_u64(1),
+            )?;
+            let mut responder = SecureChannel::with_shared_secret(
+                &secret,
+                ChannelRole::Responder,
+                StdRng::seed_from_u64(2),
+            )?;
+            let ping = initiator.send(b"ping")?;
+            responder.recv(&ping)?;
+            let pong = responder.send(b"pong")?;
+            initiator.recv(&pong)?;
+            let ping_again = initiator.send(b"ping")?;
+            Ok(vec![ping, pong, ping_again])
+        };
+        assert_eq!(run()?, run()?);
+        Ok(())
+    }
+}
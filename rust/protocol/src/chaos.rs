@@ -0,0 +1,356 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Deterministic fault injection for testing how clients cope with a lossy transport.
+//!
+//! [`ChaosCipher`] wraps [`message_encrypt`] and, driven by a seeded RNG, corrupts, duplicates, and
+//! reorders the resulting ciphertexts before handing them back. The same seed and
+//! [`ChaosConfig`] always produce the same sequence of faults, so a failing test can be replayed.
+//!
+//! Only available with the `chaos` feature; this is not meant to be shipped in production builds.
+
+#![warn(missing_docs)]
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::{
+    message_encrypt, CiphertextMessageType, Context, IdentityKeyStore, ProtocolAddress, Result,
+    SessionStore,
+};
+
+/// How often [`ChaosCipher`] should inject each kind of fault.
+///
+/// Each probability is in the range `0.0..=1.0` and is rolled independently for every message.
+/// The [`Default`] configuration injects no faults at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// Probability that one bit of a message is flipped.
+    pub corrupt_probability: f64,
+    /// Probability that a message is delivered twice.
+    pub duplicate_probability: f64,
+    /// Probability that a message is held back and delivered after the next one.
+    pub reorder_probability: f64,
+}
+
+/// The fault (if any) that was applied to a [`ChaosDelivery`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChaosFault {
+    /// The bit `bit` of the byte at `byte_index` was flipped.
+    Corrupted {
+        /// Offset into the serialized message.
+        byte_index: usize,
+        /// Bit within that byte, `0..8`.
+        bit: u8,
+    },
+    /// This is the second copy of a message that was just delivered.
+    Duplicated,
+    /// This message was held back and is now arriving after later messages.
+    Reordered,
+}
+
+/// A ciphertext as it would arrive at the recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChaosDelivery {
+    /// The index of the [`ChaosCipher::encrypt`] call that produced this message, starting at 0.
+    pub sequence_number: u64,
+    /// The type of the message before any corruption.
+    pub message_type: CiphertextMessageType,
+    /// The (possibly corrupted) serialized message.
+    pub serialized: Vec<u8>,
+    /// Faults applied to this particular delivery, in order.
+    pub faults: Vec<ChaosFault>,
+}
+
+/// Wraps [`message_encrypt`] to deterministically corrupt, duplicate, and reorder its output.
+pub struct ChaosCipher {
+    rng: ChaCha20Rng,
+    config: ChaosConfig,
+    next_sequence_number: u64,
+    held_back: Vec<ChaosDelivery>,
+}
+
+impl ChaosCipher {
+    /// Create a new wrapper whose faults are fully determined by `seed` and `config`.
+    pub fn new(seed: u64, config: ChaosConfig) -> Self {
+        Self {
+            rng: ChaCha20Rng::seed_from_u64(seed),
+            config,
+            next_sequence_number: 0,
+            held_back: Vec::new(),
+        }
+    }
+
+    /// Encrypt `ptext` with [`message_encrypt`], returning the messages the recipient should
+    /// receive now.
+    ///
+    /// This may be empty (if the new message was held back for reordering), or contain several
+    /// messages (duplicates, or earlier held-back messages arriving late).
+    pub async fn encrypt(
+        &mut self,
+        ptext: &[u8],
+        remote_address: &ProtocolAddress,
+        session_store: &mut dyn SessionStore,
+        identity_store: &mut dyn IdentityKeyStore,
+        ctx: Context,
+    ) -> Result<Vec<ChaosDelivery>> {
+        let message =
+            message_encrypt(ptext, remote_address, session_store, identity_store, ctx).await?;
+
+        let mut delivery = ChaosDelivery {
+            sequence_number: self.next_sequence_number,
+            message_type: message.message_type(),
+            serialized: message.serialize().to_vec(),
+            faults: Vec::new(),
+        };
+        self.next_sequence_number += 1;
+
+        if self.roll(self.config.corrupt_probability) && !delivery.serialized.is_empty() {
+            let byte_index = self.rng.gen_range(0, delivery.serialized.len());
+            let bit = self.rng.gen_range(0, 8);
+            delivery.serialized[byte_index] ^= 1 << bit;
+            delivery
+                .faults
+                .push(ChaosFault::Corrupted { byte_index, bit });
+        }
+
+        let mut deliveries = Vec::new();
+        if self.roll(self.config.reorder_probability) {
+            delivery.faults.push(ChaosFault::Reordered);
+            self.held_back.push(delivery);
+            return Ok(deliveries);
+        }
+
+        if self.roll(self.config.duplicate_probability) {
+            let mut duplicate = delivery.clone();
+            duplicate.faults.push(ChaosFault::Duplicated);
+            deliveries.push(delivery);
+            deliveries.push(duplicate);
+        } else {
+            deliveries.push(delivery);
+        }
+        deliveries.append(&mut self.held_back);
+        Ok(deliveries)
+    }
+
+    /// Release any messages still held back for reordering.
+    pub fn flush(&mut self) -> Vec<ChaosDelivery> {
+        std::mem::take(&mut self.held_back)
+    }
+
+    fn roll(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability.min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_decrypt_signal, process_prekey_bundle, CiphertextMessage, GenericSignedPreKey,
+        IdentityKeyPair, InMemSignalProtocolStore, KeyPair, PreKeyBundle, PreKeyRecord,
+        PreKeySignalMessage, PreKeyStore, SignalMessage, SignedPreKeyRecord, SignedPreKeyStore,
+    };
+
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+    use std::convert::TryFrom;
+
+    fn sessions() -> Result<(
+        InMemSignalProtocolStore,
+        InMemSignalProtocolStore,
+        ProtocolAddress,
+        ProtocolAddress,
+    )> {
+        let mut csprng = OsRng;
+        let alice_address = ProtocolAddress::new("alice".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("bob".to_owned(), 1.into());
+        let mut alice_store =
+            InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 1)?;
+        let mut bob_store =
+            InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 2)?;
+
+        async {
+            let bob_identity = bob_store.get_identity_key_pair(None).await?;
+            let pre_key = KeyPair::generate(&mut csprng);
+            let signed_pre_key =
+                SignedPreKeyRecord::generate(2.into(), bob_identity.private_key(), &mut csprng)?;
+            bob_store
+                .save_pre_key(1.into(), &PreKeyRecord::new(1.into(), &pre_key), None)
+                .await?;
+            bob_store
+                .save_signed_pre_key(2.into(), &signed_pre_key, None)
+                .await?;
+            let bundle = PreKeyBundle::new(
+                2,
+                1.into(),
+                Some((1.into(), pre_key.public_key)),
+                2.into(),
+                signed_pre_key.public_key()?,
+                signed_pre_key.signature()?,
+                *bob_identity.identity_key(),
+            )?;
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+
+            // Have Bob receive one message so that Alice's later messages are plain
+            // SignalMessages.
+            let first = message_encrypt(
+                b"hi",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                None,
+            )
+            .await?;
+            let first = PreKeySignalMessage::try_from(first.serialize())?;
+            crate::message_decrypt(
+                &CiphertextMessage::PreKeySignalMessage(first),
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut bob_store.kyber_pre_key_store,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            let reply = message_encrypt(
+                b"hi back",
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                None,
+            )
+            .await?;
+            let reply = SignalMessage::try_from(reply.serialize())?;
+            message_decrypt_signal(
+                &reply,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+        .map(|()| (alice_store, bob_store, alice_address, bob_address))
+    }
+
+    fn run(seed: u64, config: ChaosConfig, count: usize) -> Result<Vec<ChaosDelivery>> {
+        let (mut alice_store, _, _, bob_address) = sessions()?;
+        let mut chaos = ChaosCipher::new(seed, config);
+        let mut deliveries = Vec::new();
+        for i in 0..count {
+            deliveries.extend(
+                chaos
+                    .encrypt(
+                        format!("message {}", i).as_bytes(),
+                        &bob_address,
+                        &mut alice_store.session_store,
+                        &mut alice_store.identity_store,
+                        None,
+                    )
+                    .now_or_never()
+                    .expect("sync")?,
+            );
+        }
+        deliveries.extend(chaos.flush());
+        Ok(deliveries)
+    }
+
+    #[test]
+    fn test_no_faults_by_default() -> Result<()> {
+        let deliveries = run(1, ChaosConfig::default(), 10)?;
+        assert_eq!(deliveries.len(), 10);
+        for (i, delivery) in deliveries.iter().enumerate() {
+            assert_eq!(delivery.sequence_number, i as u64);
+            assert!(delivery.faults.is_empty());
+            assert_eq!(delivery.message_type, CiphertextMessageType::Whisper);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_faults_are_deterministic() -> Result<()> {
+        let config = ChaosConfig {
+            corrupt_probability: 0.3,
+            duplicate_probability: 0.3,
+            reorder_probability: 0.3,
+        };
+        let faults = |deliveries: Vec<ChaosDelivery>| -> Vec<(u64, Vec<ChaosFault>)> {
+            deliveries
+                .into_iter()
+                .map(|delivery| (delivery.sequence_number, delivery.faults))
+                .collect()
+        };
+        let first = faults(run(42, config, 50)?);
+        let second = faults(run(42, config, 50)?);
+        assert_eq!(first, second);
+
+        // Every message shows up at least once, and nothing is lost after a flush.
+        for i in 0..50 {
+            assert!(first.iter().any(|(n, _)| *n == i));
+        }
+        let all_faults: Vec<&ChaosFault> = first.iter().flat_map(|(_, f)| f).collect();
+        assert!(all_faults.contains(&&ChaosFault::Duplicated));
+        assert!(all_faults.contains(&&ChaosFault::Reordered));
+        assert!(all_faults
+            .iter()
+            .any(|fault| matches!(fault, ChaosFault::Corrupted { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_corruption_is_detected() -> Result<()> {
+        let config = ChaosConfig {
+            corrupt_probability: 1.0,
+            ..ChaosConfig::default()
+        };
+        let (mut alice_store, mut bob_store, alice_address, bob_address) = sessions()?;
+        let mut chaos = ChaosCipher::new(7, config);
+        let deliveries = chaos
+            .encrypt(
+                b"corrupt me",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")?;
+        assert_eq!(deliveries.len(), 1);
+        assert!(matches!(
+            deliveries[0].faults[..],
+            [ChaosFault::Corrupted { .. }]
+        ));
+
+        let result = SignalMessage::try_from(deliveries[0].serialized.as_slice()).and_then(|m| {
+            message_decrypt_signal(
+                &m,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                &mut OsRng,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+        });
+        assert!(result.is_err());
+        Ok(())
+    }
+}
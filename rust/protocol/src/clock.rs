@@ -0,0 +1,44 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Where expiry checks get the current time from.
+//!
+//! Anything that compares a stored [Timestamp] against "now" (envelope ages, signed pre-key
+//! rotation) takes a [Clock] rather than reading the system clock itself. Production code passes
+//! [SystemClock]; tests and tools replaying captured traffic can pass a [FixedClock] set to when
+//! the traffic was recorded, or a closure for a clock they advance themselves.
+
+use crate::Timestamp;
+
+/// A source of the current time.
+pub trait Clock {
+    fn now(&self) -> Timestamp;
+}
+
+/// The operating system's clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::now()
+    }
+}
+
+/// A clock stopped at a single point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(pub Timestamp);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Timestamp {
+        self.0
+    }
+}
+
+impl<F: Fn() -> Timestamp> Clock for F {
+    fn now(&self) -> Timestamp {
+        self()
+    }
+}
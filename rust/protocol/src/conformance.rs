@@ -0,0 +1,667 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Checks that a store implementation behaves like the in-memory reference stores.
+//!
+//! [check_store_conformance] runs a fixed sequence of operations against each store and compares
+//! what it observes with what [InMemSignalProtocolStore](crate::InMemSignalProtocolStore) does.
+//! Because it only goes through the store traits, it works just as well on stores implemented in
+//! another language and passed in over the bridge, which is how the Java, Swift, and TypeScript
+//! wrappers run it.
+//!
+//! The checks write entries under randomly-generated addresses and IDs, and don't clean up after
+//! themselves. Run them against a scratch instance of the store, not one holding real data.
+
+#![warn(missing_docs)]
+
+use std::fmt;
+
+use rand::{CryptoRng, Rng};
+use uuid::Uuid;
+
+use crate::ratchet::RootKey;
+use crate::sender_keys::SenderKeyRecord;
+use crate::state::SessionState;
+use crate::{
+    kem, Context, Direction, GenericSignedPreKey, IdentityKeyPair, IdentityKeyStore, KeyPair,
+    KyberPreKeyRecord, KyberPreKeyStore, PreKeyRecord, PreKeyStore, ProtocolAddress, Result,
+    SenderKeyName, SenderKeyStore, SessionRecord, SessionStore, SignalProtocolError,
+    SignedPreKeyRecord, SignedPreKeyStore, Timestamp,
+};
+
+/// A check from [check_store_conformance] that the store did not pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConformanceFailure {
+    /// The name of the check.
+    pub check: &'static str,
+    /// What the store did differently from the reference.
+    pub problem: String,
+}
+
+impl fmt::Display for ConformanceFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.check, self.problem)
+    }
+}
+
+/// The outcome of [check_store_conformance].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConformanceReport {
+    /// The names of every check that was run, in order.
+    pub checks_run: Vec<&'static str>,
+    /// The checks that failed, in the order they were run.
+    pub failures: Vec<ConformanceFailure>,
+}
+
+impl ConformanceReport {
+    /// True if every check passed.
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Convert a failing report into a single [SignalProtocolError::InvalidState] that lists
+    /// every failure, for callers that can only pass errors along.
+    pub fn into_result(self) -> Result<()> {
+        if self.passed() {
+            return Ok(());
+        }
+        let failures: Vec<String> = self.failures.iter().map(ToString::to_string).collect();
+        Err(SignalProtocolError::InvalidState(
+            "check_store_conformance",
+            failures.join("; "),
+        ))
+    }
+
+    fn record(&mut self, check: &'static str, outcome: CheckOutcome) {
+        self.checks_run.push(check);
+        if let Err(problem) = outcome {
+            self.failures.push(ConformanceFailure { check, problem });
+        }
+    }
+}
+
+type CheckOutcome = std::result::Result<(), String>;
+
+/// Turn an unexpected store error into a check failure.
+fn call<T>(operation: &str, result: Result<T>) -> std::result::Result<T, String> {
+    result.map_err(|e| format!("{} failed: {}", operation, e))
+}
+
+fn ensure(condition: bool, problem: &str) -> CheckOutcome {
+    if condition {
+        Ok(())
+    } else {
+        Err(problem.to_string())
+    }
+}
+
+/// Run every conformance check against the given stores.
+///
+/// Store errors don't stop the run; each is reported as a failure of the check it occurred in.
+#[allow(clippy::too_many_arguments)]
+pub async fn check_store_conformance<R: Rng + CryptoRng>(
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    sender_key_store: &mut dyn SenderKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> ConformanceReport {
+    let mut report = ConformanceReport::default();
+    let name = format!("conformance-{}", Uuid::from_bytes(csprng.gen()));
+    let first_device = ProtocolAddress::new(name.clone(), 1.into());
+    let second_device = ProtocolAddress::new(name, 2.into());
+
+    check_identity_store(identity_store, &first_device, csprng, ctx, &mut report).await;
+    check_session_store(
+        session_store,
+        &first_device,
+        &second_device,
+        csprng,
+        ctx,
+        &mut report,
+    )
+    .await;
+    check_pre_key_stores(
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        csprng,
+        ctx,
+        &mut report,
+    )
+    .await;
+    check_sender_key_store(sender_key_store, &first_device, csprng, ctx, &mut report).await;
+
+    report
+}
+
+async fn check_identity_store<R: Rng + CryptoRng>(
+    store: &mut dyn IdentityKeyStore,
+    address: &ProtocolAddress,
+    csprng: &mut R,
+    ctx: Context,
+    report: &mut ConformanceReport,
+) {
+    report.record(
+        "identity_key_pair_is_stable",
+        async {
+            let first = call(
+                "get_identity_key_pair",
+                store.get_identity_key_pair(ctx).await,
+            )?;
+            let second = call(
+                "get_identity_key_pair",
+                store.get_identity_key_pair(ctx).await,
+            )?;
+            ensure(
+                first.identity_key() == second.identity_key()
+                    && first.private_key() == second.private_key(),
+                "returned a different key pair on the second call",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "local_registration_id_is_stable",
+        async {
+            let first = call(
+                "get_local_registration_id",
+                store.get_local_registration_id(ctx).await,
+            )?;
+            let second = call(
+                "get_local_registration_id",
+                store.get_local_registration_id(ctx).await,
+            )?;
+            ensure(
+                first == second,
+                "returned a different ID on the second call",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "unknown_identity_is_none",
+        async {
+            let identity = call("get_identity", store.get_identity(address, ctx).await)?;
+            ensure(
+                identity.is_none(),
+                "returned an identity for an unknown address",
+            )
+        }
+        .await,
+    );
+
+    let first_identity = *IdentityKeyPair::generate(csprng).identity_key();
+    let second_identity = *IdentityKeyPair::generate(csprng).identity_key();
+    report.record(
+        "save_identity_reports_replacement",
+        async {
+            let replaced = call(
+                "save_identity",
+                store.save_identity(address, &first_identity, ctx).await,
+            )?;
+            ensure(
+                !replaced,
+                "reported replacing an identity for a new address",
+            )?;
+            let replaced = call(
+                "save_identity",
+                store.save_identity(address, &first_identity, ctx).await,
+            )?;
+            ensure(!replaced, "reported replacing an identity with itself")?;
+            let replaced = call(
+                "save_identity",
+                store.save_identity(address, &second_identity, ctx).await,
+            )?;
+            ensure(replaced, "did not report replacing a different identity")?;
+            let identity = call("get_identity", store.get_identity(address, ctx).await)?;
+            ensure(
+                identity == Some(second_identity),
+                "did not return the most recently saved identity",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "saved_identity_is_trusted",
+        async {
+            for direction in [Direction::Sending, Direction::Receiving] {
+                let trusted = call(
+                    "is_trusted_identity",
+                    store
+                        .is_trusted_identity(address, &second_identity, direction.clone(), ctx)
+                        .await,
+                )?;
+                ensure(
+                    trusted,
+                    &format!("does not trust the saved identity when {:?}", direction),
+                )?;
+            }
+            Ok(())
+        }
+        .await,
+    );
+}
+
+fn sample_session<R: Rng + CryptoRng>(csprng: &mut R) -> SessionRecord {
+    SessionRecord::new(SessionState::new(
+        4,
+        IdentityKeyPair::generate(csprng).identity_key(),
+        IdentityKeyPair::generate(csprng).identity_key(),
+        &RootKey::new(csprng.gen()),
+    ))
+}
+
+async fn check_session_store<R: Rng + CryptoRng>(
+    store: &mut dyn SessionStore,
+    first_device: &ProtocolAddress,
+    second_device: &ProtocolAddress,
+    csprng: &mut R,
+    ctx: Context,
+    report: &mut ConformanceReport,
+) {
+    report.record(
+        "missing_session_is_none",
+        async {
+            let session = call("load_session", store.load_session(first_device, ctx).await)?;
+            ensure(
+                session.is_none(),
+                "returned a session for an unknown address",
+            )
+        }
+        .await,
+    );
+
+    let first = sample_session(csprng);
+    let second = sample_session(csprng);
+    let third = sample_session(csprng);
+    report.record(
+        "session_round_trip",
+        async {
+            call(
+                "store_session",
+                store.store_session(first_device, &first, ctx).await,
+            )?;
+            let loaded = call("load_session", store.load_session(first_device, ctx).await)?;
+            ensure(
+                same_session(loaded.as_ref(), &first),
+                "did not return the stored session",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "session_overwrite",
+        async {
+            call(
+                "store_session",
+                store.store_session(first_device, &second, ctx).await,
+            )?;
+            let loaded = call("load_session", store.load_session(first_device, ctx).await)?;
+            ensure(
+                same_session(loaded.as_ref(), &second),
+                "did not return the most recently stored session",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "sessions_keyed_by_device",
+        async {
+            call(
+                "store_session",
+                store.store_session(second_device, &third, ctx).await,
+            )?;
+            let loaded_first = call("load_session", store.load_session(first_device, ctx).await)?;
+            let loaded_second = call("load_session", store.load_session(second_device, ctx).await)?;
+            ensure(
+                same_session(loaded_first.as_ref(), &second)
+                    && same_session(loaded_second.as_ref(), &third),
+                "mixed up sessions for different devices of the same user",
+            )
+        }
+        .await,
+    );
+}
+
+fn same_session(loaded: Option<&SessionRecord>, expected: &SessionRecord) -> bool {
+    match loaded {
+        Some(loaded) => loaded.serialize().ok() == expected.serialize().ok(),
+        None => false,
+    }
+}
+
+async fn check_pre_key_stores<R: Rng + CryptoRng>(
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+    report: &mut ConformanceReport,
+) {
+    // Keep IDs within 24 bits, which some client stores assume.
+    let id: u32 = csprng.gen_range(1, 0xFF_FFFF);
+    let signing_key = KeyPair::generate(csprng);
+
+    report.record(
+        "missing_pre_key_fails",
+        async {
+            ensure(
+                pre_key_store.get_pre_key(id.into(), ctx).await.is_err(),
+                "returned a pre-key that was never saved",
+            )
+        }
+        .await,
+    );
+
+    let pre_key = PreKeyRecord::new(id.into(), &KeyPair::generate(csprng));
+    report.record(
+        "pre_key_round_trip",
+        async {
+            call(
+                "save_pre_key",
+                pre_key_store.save_pre_key(id.into(), &pre_key, ctx).await,
+            )?;
+            let loaded = call(
+                "get_pre_key",
+                pre_key_store.get_pre_key(id.into(), ctx).await,
+            )?;
+            ensure(
+                loaded.serialize().ok() == pre_key.serialize().ok(),
+                "did not return the saved pre-key",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "removed_pre_key_fails",
+        async {
+            call(
+                "remove_pre_key",
+                pre_key_store.remove_pre_key(id.into(), ctx).await,
+            )?;
+            ensure(
+                pre_key_store.get_pre_key(id.into(), ctx).await.is_err(),
+                "still returned a pre-key after it was removed",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "missing_signed_pre_key_fails",
+        async {
+            ensure(
+                signed_pre_key_store
+                    .get_signed_pre_key(id.into(), ctx)
+                    .await
+                    .is_err(),
+                "returned a signed pre-key that was never saved",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "signed_pre_key_round_trip",
+        async {
+            let key_pair = KeyPair::generate(csprng);
+            let signature = call(
+                "calculate_signature",
+                signing_key
+                    .private_key
+                    .calculate_signature(&key_pair.public_key.serialize(), csprng),
+            )?;
+            let record = SignedPreKeyRecord::new(
+                id.into(),
+                Timestamp::from_epoch_millis(1_000),
+                &key_pair,
+                &signature,
+            );
+            call(
+                "save_signed_pre_key",
+                signed_pre_key_store
+                    .save_signed_pre_key(id.into(), &record, ctx)
+                    .await,
+            )?;
+            let loaded = call(
+                "get_signed_pre_key",
+                signed_pre_key_store
+                    .get_signed_pre_key(id.into(), ctx)
+                    .await,
+            )?;
+            ensure(
+                loaded.serialize().ok() == record.serialize().ok(),
+                "did not return the saved signed pre-key",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "missing_kyber_pre_key_fails",
+        async {
+            ensure(
+                kyber_pre_key_store
+                    .get_kyber_pre_key(id.into(), ctx)
+                    .await
+                    .is_err(),
+                "returned a Kyber pre-key that was never saved",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "kyber_pre_key_round_trip",
+        async {
+            let record = call(
+                "KyberPreKeyRecord::generate",
+                KyberPreKeyRecord::generate(
+                    kem::KeyType::Kyber1024,
+                    id.into(),
+                    &signing_key.private_key,
+                    csprng,
+                ),
+            )?;
+            call(
+                "save_kyber_pre_key",
+                kyber_pre_key_store
+                    .save_kyber_pre_key(id.into(), &record, ctx)
+                    .await,
+            )?;
+            let loaded = call(
+                "get_kyber_pre_key",
+                kyber_pre_key_store.get_kyber_pre_key(id.into(), ctx).await,
+            )?;
+            ensure(
+                loaded.serialize().ok() == record.serialize().ok(),
+                "did not return the saved Kyber pre-key",
+            )?;
+            call(
+                "mark_kyber_pre_key_used",
+                kyber_pre_key_store
+                    .mark_kyber_pre_key_used(id.into(), ctx)
+                    .await,
+            )
+        }
+        .await,
+    );
+}
+
+async fn check_sender_key_store<R: Rng + CryptoRng>(
+    store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    csprng: &mut R,
+    ctx: Context,
+    report: &mut ConformanceReport,
+) {
+    let first_name = SenderKeyName::new(sender.clone(), Uuid::from_bytes(csprng.gen()));
+    let second_name = SenderKeyName::new(sender.clone(), Uuid::from_bytes(csprng.gen()));
+
+    report.record(
+        "missing_sender_key_is_none",
+        async {
+            let record = call(
+                "load_sender_key",
+                store.load_sender_key(&first_name, ctx).await,
+            )?;
+            ensure(
+                record.is_none(),
+                "returned a sender key that was never stored",
+            )
+        }
+        .await,
+    );
+
+    let first = sample_sender_key(csprng);
+    let second = sample_sender_key(csprng);
+    report.record(
+        "sender_key_round_trip",
+        async {
+            call(
+                "store_sender_key",
+                store.store_sender_key(&first_name, &first, ctx).await,
+            )?;
+            let loaded = call(
+                "load_sender_key",
+                store.load_sender_key(&first_name, ctx).await,
+            )?;
+            ensure(
+                same_sender_key(loaded.as_ref(), &first),
+                "did not return the stored sender key",
+            )
+        }
+        .await,
+    );
+
+    report.record(
+        "sender_keys_keyed_by_distribution_id",
+        async {
+            call(
+                "store_sender_key",
+                store.store_sender_key(&second_name, &second, ctx).await,
+            )?;
+            let loaded_first = call(
+                "load_sender_key",
+                store.load_sender_key(&first_name, ctx).await,
+            )?;
+            let loaded_second = call(
+                "load_sender_key",
+                store.load_sender_key(&second_name, ctx).await,
+            )?;
+            ensure(
+                same_sender_key(loaded_first.as_ref(), &first)
+                    && same_sender_key(loaded_second.as_ref(), &second),
+                "mixed up sender keys for different distribution IDs",
+            )
+        }
+        .await,
+    );
+}
+
+fn sample_sender_key<R: Rng + CryptoRng>(csprng: &mut R) -> SenderKeyRecord {
+    let signing_key = KeyPair::generate(csprng);
+    let chain_key: [u8; 32] = csprng.gen();
+    let mut record = SenderKeyRecord::new_empty();
+    record.add_sender_key_state(
+        3,
+        csprng.gen(),
+        0,
+        &chain_key,
+        signing_key.public_key,
+        Some(signing_key.private_key),
+    );
+    record
+}
+
+fn same_sender_key(loaded: Option<&SenderKeyRecord>, expected: &SenderKeyRecord) -> bool {
+    match loaded {
+        Some(loaded) => loaded.serialize().ok() == expected.serialize().ok(),
+        None => false,
+    }
+}
+
+#[cfg(all(test, feature = "inmem-stores"))]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+
+    use crate::{InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore};
+
+    fn run(session_store: &mut dyn SessionStore) -> ConformanceReport {
+        let mut store = InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut OsRng), 5)
+            .expect("can create store");
+        check_store_conformance(
+            session_store,
+            &mut store.identity_store,
+            &mut store.pre_key_store,
+            &mut store.signed_pre_key_store,
+            &mut store.kyber_pre_key_store,
+            &mut InMemSenderKeyStore::new(),
+            &mut OsRng,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn in_memory_stores_conform() {
+        let report = run(&mut InMemSessionStore::new());
+        assert_eq!(report.failures, vec![]);
+        assert_eq!(report.checks_run.len(), 19);
+        assert!(report.into_result().is_ok());
+    }
+
+    /// Forgets which device a session belongs to.
+    struct DeviceBlindSessionStore(InMemSessionStore);
+
+    #[async_trait(?Send)]
+    impl SessionStore for DeviceBlindSessionStore {
+        async fn load_session(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<SessionRecord>> {
+            let address = ProtocolAddress::new(address.name().to_owned(), 1.into());
+            self.0.load_session(&address, ctx).await
+        }
+
+        async fn store_session(
+            &mut self,
+            address: &ProtocolAddress,
+            record: &SessionRecord,
+            ctx: Context,
+        ) -> Result<()> {
+            let address = ProtocolAddress::new(address.name().to_owned(), 1.into());
+            self.0.store_session(&address, record, ctx).await
+        }
+    }
+
+    #[test]
+    fn broken_store_is_reported() {
+        let report = run(&mut DeviceBlindSessionStore(InMemSessionStore::new()));
+        let failed: Vec<&str> = report.failures.iter().map(|f| f.check).collect();
+        assert_eq!(failed, vec!["sessions_keyed_by_device"]);
+        assert!(matches!(
+            report.into_result(),
+            Err(SignalProtocolError::InvalidState("check_store_conformance", message))
+                if message.starts_with("sessions_keyed_by_device: ")
+        ));
+    }
+}
@@ -8,3 +8,30 @@ pub const MAX_MESSAGE_KEYS: usize = 2000;
 pub const MAX_RECEIVER_CHAINS: usize = 5;
 pub const ARCHIVED_STATES_MAX_LENGTH: usize = 40;
 pub const MAX_SENDER_KEY_STATES: usize = 5;
+
+/// Valid registration IDs fit in 14 bits.
+pub const MAX_REGISTRATION_ID: u32 = 0x3FFF;
+
+/// A session that still hasn't been acknowledged by the other party after this long was probably
+/// built from a signed pre-key they have since pruned.
+pub const MAX_UNACKNOWLEDGED_SESSION_AGE: std::time::Duration =
+    std::time::Duration::from_secs(30 * 24 * 60 * 60);
+
+/// After an upgrade to a newer protocol version, sessions using the older version stay archived for
+/// at least this long, so that messages the other party sent on them before the upgrade can still
+/// be decrypted.
+pub const OLDER_VERSION_GRACE_PERIOD: std::time::Duration =
+    std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Sender keys that haven't been updated for this long are removed by
+/// [InMemSenderKeyStore](crate::InMemSenderKeyStore)'s `prune_expired`.
+#[cfg(feature = "inmem-stores")]
+pub const MAX_SENDER_KEY_AGE: std::time::Duration =
+    std::time::Duration::from_secs(90 * 24 * 60 * 60);
+
+/// How many messages [InMemReplayCache](crate::InMemReplayCache) remembers by default.
+#[cfg(feature = "inmem-stores")]
+pub const REPLAY_CACHE_CAPACITY: usize = 10_000;
+
+/// Session flow statistics count messages over windows of this length.
+pub const FLOW_STATISTICS_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
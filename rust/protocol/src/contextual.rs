@@ -0,0 +1,192 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! The main entry points, with errors that say what was being done.
+//!
+//! Each function here takes the same arguments as the function of the same name at the crate root,
+//! and fails with a [ContextualError] recording the [ProtocolOperation], the remote address, and
+//! (where it is known up front) the type of message involved. That makes a failure logged far from
+//! the call, such as in a message-processing queue, diagnosable on its own:
+//!
+//! ```text
+//! message_decrypt of Whisper message for +14151111111.1 failed: invalid Whisper message: ...
+//! ```
+//!
+//! The underlying [SignalProtocolError](crate::SignalProtocolError) is still available through
+//! [ContextualError::error], and `?` converts back to it, so code that only matches on the error
+//! can switch between these and the plain versions freely.
+
+use rand::{CryptoRng, Rng};
+use uuid::Uuid;
+
+use crate::error::{ContextualError, ErrorContext, ProtocolOperation, ResultExt};
+use crate::{
+    CiphertextMessage, CiphertextMessageType, Context, IdentityKeyStore, KyberPreKeyStore,
+    PreKeyBundle, PreKeySignalMessage, PreKeyStore, PreKeysUsed, ProtocolAddress,
+    SenderKeyDistributionMessage, SenderKeyMessage, SenderKeyStore, SessionRecord, SessionStore,
+    SignedPreKeyStore,
+};
+
+type Result<T> = std::result::Result<T, ContextualError>;
+
+/// See [crate::message_encrypt].
+pub async fn message_encrypt(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    crate::message_encrypt(ptext, remote_address, session_store, identity_store, ctx)
+        .await
+        .with_context(
+            ErrorContext::new(ProtocolOperation::MessageEncrypt)
+                .with_remote_address(remote_address),
+        )
+}
+
+/// See [crate::message_decrypt].
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    crate::message_decrypt(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        csprng,
+        ctx,
+    )
+    .await
+    .with_context(
+        ErrorContext::new(ProtocolOperation::MessageDecrypt)
+            .with_remote_address(remote_address)
+            .with_message_type(ciphertext.message_type()),
+    )
+}
+
+/// See [crate::process_prekey].
+#[allow(clippy::too_many_arguments)]
+pub async fn process_prekey(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_prekey_store: &mut dyn SignedPreKeyStore,
+    kyber_prekey_store: &mut dyn KyberPreKeyStore,
+    ctx: Context,
+) -> Result<PreKeysUsed> {
+    crate::process_prekey(
+        message,
+        remote_address,
+        session_record,
+        identity_store,
+        pre_key_store,
+        signed_prekey_store,
+        kyber_prekey_store,
+        ctx,
+    )
+    .await
+    .with_context(
+        ErrorContext::new(ProtocolOperation::ProcessPreKey)
+            .with_remote_address(remote_address)
+            .with_message_type(CiphertextMessageType::PreKey),
+    )
+}
+
+/// See [crate::process_prekey_bundle].
+pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<()> {
+    crate::process_prekey_bundle(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        csprng,
+        ctx,
+    )
+    .await
+    .with_context(
+        ErrorContext::new(ProtocolOperation::ProcessPreKeyBundle)
+            .with_remote_address(remote_address),
+    )
+}
+
+/// See [crate::group_encrypt].
+///
+/// The address recorded is `sender`, which is the local device.
+pub async fn group_encrypt<R: Rng + CryptoRng>(
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintext: &[u8],
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SenderKeyMessage> {
+    crate::group_encrypt(
+        sender_key_store,
+        sender,
+        distribution_id,
+        plaintext,
+        csprng,
+        ctx,
+    )
+    .await
+    .with_context(
+        ErrorContext::new(ProtocolOperation::GroupEncrypt)
+            .with_remote_address(sender)
+            .with_message_type(CiphertextMessageType::SenderKey),
+    )
+}
+
+/// See [crate::group_decrypt].
+pub async fn group_decrypt(
+    skm_bytes: &[u8],
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    crate::group_decrypt(skm_bytes, sender_key_store, sender, ctx)
+        .await
+        .with_context(
+            ErrorContext::new(ProtocolOperation::GroupDecrypt)
+                .with_remote_address(sender)
+                .with_message_type(CiphertextMessageType::SenderKey),
+        )
+}
+
+/// See [crate::process_sender_key_distribution_message].
+pub async fn process_sender_key_distribution_message(
+    sender: &ProtocolAddress,
+    skdm: &SenderKeyDistributionMessage,
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    crate::process_sender_key_distribution_message(sender, skdm, sender_key_store, ctx)
+        .await
+        .with_context(
+            ErrorContext::new(ProtocolOperation::ProcessSenderKeyDistributionMessage)
+                .with_remote_address(sender),
+        )
+}
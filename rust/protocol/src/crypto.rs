@@ -28,7 +28,7 @@ pub(crate) enum DecryptionError {
     BadCiphertext(&'static str),
 }
 
-fn aes_256_ctr_encrypt(ptext: &[u8], key: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+pub(crate) fn aes_256_ctr_encrypt(ptext: &[u8], key: &[u8]) -> Result<Vec<u8>, EncryptionError> {
     let key: [u8; 32] = key.try_into().map_err(|_| EncryptionError::BadKeyOrIv)?;
 
     let zero_nonce = [0u8; 16];
@@ -39,7 +39,7 @@ fn aes_256_ctr_encrypt(ptext: &[u8], key: &[u8]) -> Result<Vec<u8>, EncryptionEr
     Ok(ctext)
 }
 
-fn aes_256_ctr_decrypt(ctext: &[u8], key: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+pub(crate) fn aes_256_ctr_decrypt(ctext: &[u8], key: &[u8]) -> Result<Vec<u8>, DecryptionError> {
     aes_256_ctr_encrypt(ctext, key).map_err(|e| match e {
         EncryptionError::BadKeyOrIv => DecryptionError::BadKeyOrIv,
     })
@@ -52,6 +52,14 @@ pub(crate) fn hmac_sha256(key: &[u8], input: &[u8]) -> [u8; 32] {
     hmac.finalize().into_bytes().into()
 }
 
+/// PBKDF2 (RFC 8018) with HMAC-SHA256 as the PRF.
+///
+/// `iterations` must be at least 1; callers are responsible for rejecting 0.
+pub(crate) fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) {
+    debug_assert!(iterations > 0, "PBKDF2 needs at least one iteration");
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations, output);
+}
+
 pub(crate) fn aes256_ctr_hmacsha256_encrypt(
     msg: &[u8],
     cipher_key: &[u8],
@@ -84,6 +92,18 @@ pub(crate) fn aes256_ctr_hmacsha256_decrypt(
 mod test {
     use super::*;
 
+    #[test]
+    fn pbkdf2_test() {
+        // From RFC 7914 section 11.
+        let mut output = [0u8; 64];
+        pbkdf2_hmac_sha256(b"passwd", b"salt", 1, &mut output);
+        assert_eq!(
+            hex::encode(output),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+             49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+        );
+    }
+
     #[test]
     fn aes_ctr_test() {
         let key = hex::decode("603DEB1015CA71BE2B73AEF0857D77811F352C073B6108D72D9810A30914DFF4")
@@ -4,8 +4,10 @@
 //
 
 pub(crate) mod curve25519;
+mod der;
+mod nistp256;
 
-use crate::{Result, SignalProtocolError};
+use crate::{CryptoRngCore, Result, SignalProtocolError};
 
 use std::cmp::Ordering;
 use std::convert::TryFrom;
@@ -15,9 +17,33 @@ use arrayref::array_ref;
 use rand::{CryptoRng, Rng};
 use subtle::ConstantTimeEq;
 
+// Used for domain separation between application signatures and the signatures the protocol
+// itself makes.
+const LABELED_SIGNATURE_PREFIX_1: &[u8] = &[0xFF; 32];
+const LABELED_SIGNATURE_PREFIX_2: &[u8] = b"Signal_ApplicationSignature";
+
+/// Checks a label for [PrivateKey::calculate_signature_with_label], returning its length prefix.
+fn signature_label_length(label: &str) -> Result<[u8; 1]> {
+    if label.is_empty() || label.starts_with("Signal_") {
+        return Err(SignalProtocolError::InvalidArgument(format!(
+            "invalid signature label {:?}",
+            label
+        )));
+    }
+    let length = u8::try_from(label.len()).map_err(|_| {
+        SignalProtocolError::InvalidArgument("signature label is too long".to_string())
+    })?;
+    Ok([length])
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum KeyType {
     Djb,
+    /// NIST P-256, for deployments with FIPS requirements.
+    ///
+    /// Every key in a session must have the same type; see [KeyPair::generate_with_key_type].
+    /// Sealed sender still requires [KeyType::Djb] identity keys.
+    P256,
 }
 
 impl fmt::Display for KeyType {
@@ -30,6 +56,7 @@ impl KeyType {
     fn value(&self) -> u8 {
         match &self {
             KeyType::Djb => 0x05u8,
+            KeyType::P256 => 0x06u8,
         }
     }
 }
@@ -40,6 +67,7 @@ impl TryFrom<u8> for KeyType {
     fn try_from(x: u8) -> Result<Self> {
         match x {
             0x05u8 => Ok(KeyType::Djb),
+            0x06u8 => Ok(KeyType::P256),
             t => Err(SignalProtocolError::BadKeyType(t)),
         }
     }
@@ -48,6 +76,7 @@ impl TryFrom<u8> for KeyType {
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum PublicKeyData {
     DjbPublicKey([u8; curve25519::PUBLIC_KEY_LENGTH]),
+    P256PublicKey([u8; nistp256::PUBLIC_KEY_LENGTH]),
 }
 
 #[derive(Clone, Copy, Eq)]
@@ -77,12 +106,23 @@ impl PublicKey {
                     key: PublicKeyData::DjbPublicKey(key),
                 })
             }
+            KeyType::P256 => {
+                if value.len() < nistp256::PUBLIC_KEY_LENGTH + 1 {
+                    return Err(SignalProtocolError::BadKeyLength(
+                        KeyType::P256,
+                        value.len(),
+                    ));
+                }
+                let key = *array_ref![value, 1, nistp256::PUBLIC_KEY_LENGTH];
+                Self::from_p256_public_key_bytes(&key)
+            }
         }
     }
 
     pub fn public_key_bytes(&self) -> Result<&[u8]> {
         match &self.key {
             PublicKeyData::DjbPublicKey(v) => Ok(v),
+            PublicKeyData::P256PublicKey(v) => Ok(v),
         }
     }
 
@@ -95,15 +135,52 @@ impl PublicKey {
         }
     }
 
-    pub fn serialize(&self) -> Box<[u8]> {
-        let value_len = match &self.key {
-            PublicKeyData::DjbPublicKey(v) => v.len(),
-        };
-        let mut result = Vec::with_capacity(1 + value_len);
-        result.push(self.key_type().value());
+    /// Create a P-256 public key from a compressed SEC1 point.
+    fn from_p256_public_key_bytes(bytes: &[u8; nistp256::PUBLIC_KEY_LENGTH]) -> Result<Self> {
+        if !nistp256::is_valid_public_key(bytes) {
+            return Err(SignalProtocolError::InvalidKeyEncoding(
+                "not a point on the P-256 curve",
+            ));
+        }
+        Ok(Self::new(PublicKeyData::P256PublicKey(*bytes)))
+    }
+
+    /// Encode as a DER SubjectPublicKeyInfo, as used by X.509 and OpenSSL.
+    ///
+    /// Unlike [serialize](Self::serialize), this doesn't include Signal's key type prefix.
+    pub fn to_der(&self) -> Vec<u8> {
         match &self.key {
-            PublicKeyData::DjbPublicKey(v) => result.extend_from_slice(v),
+            PublicKeyData::DjbPublicKey(v) => der::encode_public_key(v),
+            PublicKeyData::P256PublicKey(v) => der::encode_p256_public_key(v),
+        }
+    }
+
+    /// Decode a DER SubjectPublicKeyInfo for an X25519 key or a compressed P-256 key.
+    pub fn from_der(value: &[u8]) -> Result<Self> {
+        match der::decode_public_key(value) {
+            Ok(key) => Ok(Self::new(PublicKeyData::DjbPublicKey(key))),
+            Err(e) => match der::decode_p256_public_key(value) {
+                Ok(key) => Self::from_p256_public_key_bytes(&key),
+                Err(_) => Err(e),
+            },
         }
+    }
+
+    /// Encode as a PEM "PUBLIC KEY" block.
+    pub fn to_pem(&self) -> String {
+        der::encode_pem(der::PUBLIC_KEY_PEM_LABEL, &self.to_der())
+    }
+
+    /// Decode a PEM "PUBLIC KEY" block holding an X25519 or P-256 key.
+    pub fn from_pem(value: &str) -> Result<Self> {
+        Self::from_der(&der::decode_pem(der::PUBLIC_KEY_PEM_LABEL, value)?)
+    }
+
+    pub fn serialize(&self) -> Box<[u8]> {
+        let key_data = self.key_data();
+        let mut result = Vec::with_capacity(1 + key_data.len());
+        result.push(self.key_type().value());
+        result.extend_from_slice(key_data);
         result.into_boxed_slice()
     }
 
@@ -127,18 +204,124 @@ impl PublicKey {
                     array_ref![signature, 0, curve25519::SIGNATURE_LENGTH],
                 ))
             }
+            PublicKeyData::P256PublicKey(pub_key) => {
+                if signature.len() != nistp256::SIGNATURE_LENGTH {
+                    return Ok(false);
+                }
+                Ok(nistp256::PrivateKey::verify_signature(
+                    pub_key,
+                    message,
+                    array_ref![signature, 0, nistp256::SIGNATURE_LENGTH],
+                ))
+            }
         }
     }
 
+    /// Verify a signature made by [PrivateKey::calculate_signature_with_label] with the same
+    /// `label`.
+    ///
+    /// Fails if `label` is not a valid label; returns `false` if it is valid but the signature
+    /// isn't.
+    pub fn verify_signature_with_label(
+        &self,
+        label: &str,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool> {
+        let label_length = signature_label_length(label)?;
+        self.verify_signature_for_multipart_message(
+            &[
+                LABELED_SIGNATURE_PREFIX_1,
+                LABELED_SIGNATURE_PREFIX_2,
+                &label_length,
+                label.as_bytes(),
+                message,
+            ],
+            signature,
+        )
+    }
+
+    /// Verify a VXEdDSA signature made by [PrivateKey::calculate_vrf_signature], returning the
+    /// VRF output it proves, or `None` if the signature is invalid.
+    ///
+    /// Fails for key types other than [KeyType::Djb].
+    pub fn verify_vrf_signature(
+        &self,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<Option<[u8; curve25519::VRF_OUTPUT_LENGTH]>> {
+        match &self.key {
+            PublicKeyData::DjbPublicKey(pub_key) => {
+                if signature.len() != curve25519::VRF_SIGNATURE_LENGTH {
+                    return Ok(None);
+                }
+                Ok(curve25519::PrivateKey::verify_vrf_signature(
+                    pub_key,
+                    message,
+                    array_ref![signature, 0, curve25519::VRF_SIGNATURE_LENGTH],
+                ))
+            }
+            PublicKeyData::P256PublicKey(_) => Err(vrf_unsupported(self.key_type())),
+        }
+    }
+
+    /// Verify a batch of `(key, message, signature)` triples at once.
+    ///
+    /// This is considerably faster than calling [`Self::verify_signature`] in a loop (e.g. when
+    /// checking every signed pre-key in a device list), but only reports whether *all* signatures
+    /// are valid. Callers that need to know which signature failed should fall back to verifying
+    /// them individually.
+    ///
+    /// The batch also fails if any key or signature involves a Curve25519 point with a small-order
+    /// component. Honest keys and signatures never do, but checking individually is the only way
+    /// to tell such a batch apart from one with a forged signature.
+    pub fn verify_signatures_batch<R: CryptoRng + Rng>(
+        signatures: &[(&PublicKey, &[u8], &[u8])],
+        csprng: &mut R,
+    ) -> Result<bool> {
+        let mut messages = Vec::with_capacity(signatures.len());
+        let mut keys_and_signatures = Vec::with_capacity(signatures.len());
+        for (key, message, signature) in signatures {
+            match &key.key {
+                PublicKeyData::DjbPublicKey(pub_key) => {
+                    if signature.len() != curve25519::SIGNATURE_LENGTH {
+                        return Ok(false);
+                    }
+                    messages.push([*message]);
+                    keys_and_signatures.push((
+                        pub_key,
+                        array_ref![signature, 0, curve25519::SIGNATURE_LENGTH],
+                    ));
+                }
+                // There's no batch verification for ECDSA, so check these as they come.
+                PublicKeyData::P256PublicKey(_) => {
+                    if !key.verify_signature(message, signature)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        let batch: Vec<_> = keys_and_signatures
+            .into_iter()
+            .zip(&messages)
+            .map(|((pub_key, signature), message)| (pub_key, &message[..], signature))
+            .collect();
+        Ok(curve25519::PrivateKey::verify_signatures_batch(
+            csprng, &batch,
+        ))
+    }
+
     fn key_data(&self) -> &[u8] {
         match &self.key {
             PublicKeyData::DjbPublicKey(ref k) => k.as_ref(),
+            PublicKeyData::P256PublicKey(ref k) => k.as_ref(),
         }
     }
 
     pub fn key_type(&self) -> KeyType {
         match &self.key {
             PublicKeyData::DjbPublicKey(_) => KeyType::Djb,
+            PublicKeyData::P256PublicKey(_) => KeyType::P256,
         }
     }
 }
@@ -203,9 +386,94 @@ impl fmt::Debug for PublicKey {
     }
 }
 
+/// A [PublicKey] with the work of decoding it into a curve point done ahead of time.
+///
+/// Verifying a signature or computing an agreement with a [PublicKey] starts by decompressing it;
+/// when the same key is used over and over, as when a server checks many messages from the same
+/// senders, convert it to a `PrecomputedPublicKey` once and use that instead.
+#[derive(Clone)]
+pub struct PrecomputedPublicKey {
+    key: PublicKey,
+    data: PrecomputedPublicKeyData,
+}
+
+#[derive(Clone)]
+enum PrecomputedPublicKeyData {
+    Djb(Box<curve25519::PrecomputedPublicKey>),
+    P256(nistp256::PrecomputedPublicKey),
+}
+
+impl PrecomputedPublicKey {
+    pub fn new(key: &PublicKey) -> Self {
+        let data = match &key.key {
+            PublicKeyData::DjbPublicKey(k) => {
+                PrecomputedPublicKeyData::Djb(Box::new(curve25519::PrecomputedPublicKey::new(k)))
+            }
+            PublicKeyData::P256PublicKey(k) => PrecomputedPublicKeyData::P256(
+                nistp256::PrecomputedPublicKey::new(k)
+                    .expect("validated when the public key was created"),
+            ),
+        };
+        Self { key: *key, data }
+    }
+
+    /// The key this was computed from.
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    /// As [PublicKey::verify_signature].
+    pub fn verify_signature(&self, message: &[u8], signature: &[u8]) -> Result<bool> {
+        self.verify_signature_for_multipart_message(&[message], signature)
+    }
+
+    /// As [PublicKey::verify_signature_for_multipart_message].
+    pub fn verify_signature_for_multipart_message(
+        &self,
+        message: &[&[u8]],
+        signature: &[u8],
+    ) -> Result<bool> {
+        match &self.data {
+            PrecomputedPublicKeyData::Djb(key) => {
+                if signature.len() != curve25519::SIGNATURE_LENGTH {
+                    return Ok(false);
+                }
+                Ok(key.verify_signature(
+                    message,
+                    array_ref![signature, 0, curve25519::SIGNATURE_LENGTH],
+                ))
+            }
+            PrecomputedPublicKeyData::P256(key) => {
+                if signature.len() != nistp256::SIGNATURE_LENGTH {
+                    return Ok(false);
+                }
+                Ok(key.verify_signature(
+                    message,
+                    array_ref![signature, 0, nistp256::SIGNATURE_LENGTH],
+                ))
+            }
+        }
+    }
+}
+
+impl From<PublicKey> for PrecomputedPublicKey {
+    fn from(key: PublicKey) -> Self {
+        Self::new(&key)
+    }
+}
+
+impl fmt::Debug for PrecomputedPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PrecomputedPublicKey")
+            .field(&self.key)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum PrivateKeyData {
     DjbPrivateKey([u8; curve25519::PRIVATE_KEY_LENGTH]),
+    P256PrivateKey([u8; nistp256::PRIVATE_KEY_LENGTH]),
 }
 
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -213,8 +481,23 @@ pub struct PrivateKey {
     key: PrivateKeyData,
 }
 
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key itself.
+        write!(f, "PrivateKey {{ key_type={} }}", self.key_type())
+    }
+}
+
 impl PrivateKey {
+    /// Deserialize the output of [serialize](Self::serialize).
+    ///
+    /// Curve25519 keys are 32 bytes with no type prefix, for compatibility with existing stored
+    /// keys; keys of any other type start with their type byte.
     pub fn deserialize(value: &[u8]) -> Result<Self> {
+        if value.len() == nistp256::PRIVATE_KEY_LENGTH + 1 && value[0] == KeyType::P256.value() {
+            let key = *array_ref![value, 1, nistp256::PRIVATE_KEY_LENGTH];
+            return Self::from_p256_private_key_bytes(&key);
+        }
         if value.len() != curve25519::PRIVATE_KEY_LENGTH {
             Err(SignalProtocolError::BadKeyLength(KeyType::Djb, value.len()))
         } else {
@@ -230,12 +513,53 @@ impl PrivateKey {
         }
     }
 
+    fn from_p256_private_key_bytes(bytes: &[u8; nistp256::PRIVATE_KEY_LENGTH]) -> Result<Self> {
+        if nistp256::PrivateKey::from_bytes(bytes).is_none() {
+            return Err(SignalProtocolError::InvalidKeyEncoding(
+                "not a valid P-256 private key",
+            ));
+        }
+        Ok(Self {
+            key: PrivateKeyData::P256PrivateKey(*bytes),
+        })
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         match &self.key {
             PrivateKeyData::DjbPrivateKey(v) => v.to_vec(),
+            PrivateKeyData::P256PrivateKey(v) => [&[KeyType::P256.value()][..], v].concat(),
+        }
+    }
+
+    /// Encode as an unencrypted DER PKCS#8 PrivateKeyInfo.
+    pub fn to_pkcs8_der(&self) -> Vec<u8> {
+        match &self.key {
+            PrivateKeyData::DjbPrivateKey(v) => der::encode_private_key(v),
+            PrivateKeyData::P256PrivateKey(v) => der::encode_p256_private_key(v),
         }
     }
 
+    /// Decode an unencrypted DER PKCS#8 PrivateKeyInfo for an X25519 or P-256 key.
+    pub fn from_pkcs8_der(value: &[u8]) -> Result<Self> {
+        match der::decode_private_key(value) {
+            Ok(key) => Self::deserialize(&key),
+            Err(e) => match der::decode_p256_private_key(value) {
+                Ok(key) => Self::from_p256_private_key_bytes(&key),
+                Err(_) => Err(e),
+            },
+        }
+    }
+
+    /// Encode as a PEM "PRIVATE KEY" block.
+    pub fn to_pkcs8_pem(&self) -> String {
+        der::encode_pem(der::PRIVATE_KEY_PEM_LABEL, &self.to_pkcs8_der())
+    }
+
+    /// Decode a PEM "PRIVATE KEY" block holding an X25519 or P-256 key.
+    pub fn from_pkcs8_pem(value: &str) -> Result<Self> {
+        Self::from_pkcs8_der(&der::decode_pem(der::PRIVATE_KEY_PEM_LABEL, value)?)
+    }
+
     pub fn public_key(&self) -> Result<PublicKey> {
         match &self.key {
             PrivateKeyData::DjbPrivateKey(private_key) => {
@@ -243,12 +567,17 @@ impl PrivateKey {
                     curve25519::PrivateKey::from(*private_key).derive_public_key_bytes();
                 Ok(PublicKey::new(PublicKeyData::DjbPublicKey(public_key)))
             }
+            PrivateKeyData::P256PrivateKey(private_key) => {
+                let public_key = p256_private_key(private_key).derive_public_key_bytes();
+                Ok(PublicKey::new(PublicKeyData::P256PublicKey(public_key)))
+            }
         }
     }
 
     pub fn key_type(&self) -> KeyType {
         match &self.key {
             PrivateKeyData::DjbPrivateKey(_) => KeyType::Djb,
+            PrivateKeyData::P256PrivateKey(_) => KeyType::P256,
         }
     }
 
@@ -270,6 +599,59 @@ impl PrivateKey {
                 let private_key = curve25519::PrivateKey::from(k);
                 Ok(Box::new(private_key.calculate_signature(csprng, message)))
             }
+            PrivateKeyData::P256PrivateKey(k) => {
+                Ok(Box::new(p256_private_key(&k).calculate_signature(message)))
+            }
+        }
+    }
+
+    /// Sign `message` for use by an application, such as a device provisioning or registration
+    /// payload, rather than by the protocol.
+    ///
+    /// The signature covers `label` as well as `message`, so it can't be passed off as a signature
+    /// on some other kind of payload, nor as any signature the protocol itself makes with this key.
+    /// Pick a distinct label for each kind of payload, such as `"MyApp_DeviceProvisioning"`, and
+    /// verify with [PublicKey::verify_signature_with_label]. Labels must be 1 to 255 bytes long
+    /// and must not start with `Signal_`, which is reserved for this library.
+    pub fn calculate_signature_with_label<R: CryptoRng + Rng>(
+        &self,
+        label: &str,
+        message: &[u8],
+        csprng: &mut R,
+    ) -> Result<Box<[u8]>> {
+        let label_length = signature_label_length(label)?;
+        self.calculate_signature_for_multipart_message(
+            &[
+                LABELED_SIGNATURE_PREFIX_1,
+                LABELED_SIGNATURE_PREFIX_2,
+                &label_length,
+                label.as_bytes(),
+                message,
+            ],
+            csprng,
+        )
+    }
+
+    /// Compute a verifiable random function of `message` with this key, using VXEdDSA.
+    ///
+    /// Returns a signature and a 32-byte output. The output is deterministic (the same key and
+    /// message always give the same output) but unpredictable without the private key, and the
+    /// signature proves to anyone holding the public key that the output was computed correctly;
+    /// see [PublicKey::verify_vrf_signature]. The signature itself is randomized.
+    ///
+    /// Fails for key types other than [KeyType::Djb].
+    pub fn calculate_vrf_signature<R: CryptoRng + Rng>(
+        &self,
+        message: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Box<[u8]>, [u8; curve25519::VRF_OUTPUT_LENGTH])> {
+        match self.key {
+            PrivateKeyData::DjbPrivateKey(k) => {
+                let (signature, output) =
+                    curve25519::PrivateKey::from(k).calculate_vrf_signature(csprng, message);
+                Ok((Box::new(signature), output))
+            }
+            PrivateKeyData::P256PrivateKey(_) => Err(vrf_unsupported(self.key_type())),
         }
     }
 
@@ -279,10 +661,85 @@ impl PrivateKey {
                 let private_key = curve25519::PrivateKey::from(priv_key);
                 Ok(Box::new(private_key.calculate_agreement(&pub_key)))
             }
+            (PrivateKeyData::P256PrivateKey(priv_key), PublicKeyData::P256PublicKey(pub_key)) => {
+                let agreement = p256_private_key(&priv_key)
+                    .calculate_agreement(&pub_key)
+                    .expect("validated when the public key was created");
+                Ok(Box::new(agreement))
+            }
+            _ => Err(SignalProtocolError::MismatchedKeyTypes(
+                self.key_type(),
+                their_key.key_type(),
+            )),
+        }
+    }
+
+    /// As [PrivateKey::calculate_agreement], with a key prepared for repeated use.
+    pub fn calculate_agreement_with_precomputed(
+        &self,
+        their_key: &PrecomputedPublicKey,
+    ) -> Result<Box<[u8]>> {
+        match (self.key, &their_key.data) {
+            (PrivateKeyData::P256PrivateKey(priv_key), PrecomputedPublicKeyData::P256(pub_key)) => {
+                Ok(Box::new(
+                    pub_key.calculate_agreement(&p256_private_key(&priv_key)),
+                ))
+            }
+            // X25519 works on the encoded key directly.
+            _ => self.calculate_agreement(&their_key.key),
         }
     }
 }
 
+fn vrf_unsupported(key_type: KeyType) -> SignalProtocolError {
+    SignalProtocolError::InvalidArgument(format!("VXEdDSA is not supported for {} keys", key_type))
+}
+
+/// P-256 private keys are validated when a [PrivateKey] is created, so this can't fail.
+fn p256_private_key(bytes: &[u8; nistp256::PRIVATE_KEY_LENGTH]) -> nistp256::PrivateKey {
+    nistp256::PrivateKey::from_bytes(bytes).expect("validated when the private key was created")
+}
+
+/// The operations session setup needs from a private key.
+///
+/// [PrivateKey] implements this directly. Implement it yourself to keep a long-term key, such as
+/// an identity key, somewhere its bytes can't be read out: a secure enclave, Android Keystore, or
+/// an HSM. See [IdentityKeyStore::get_identity_private_key].
+///
+/// [IdentityKeyStore::get_identity_private_key]: crate::IdentityKeyStore::get_identity_private_key
+pub trait PrivateKeyOps {
+    /// The public key corresponding to this private key.
+    fn public_key(&self) -> Result<PublicKey>;
+
+    /// As [PrivateKey::calculate_signature_for_multipart_message].
+    fn calculate_signature_for_multipart_message(
+        &self,
+        message: &[&[u8]],
+        csprng: &mut dyn CryptoRngCore,
+    ) -> Result<Box<[u8]>>;
+
+    /// As [PrivateKey::calculate_agreement].
+    fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>>;
+}
+
+impl PrivateKeyOps for PrivateKey {
+    fn public_key(&self) -> Result<PublicKey> {
+        PrivateKey::public_key(self)
+    }
+
+    fn calculate_signature_for_multipart_message(
+        &self,
+        message: &[&[u8]],
+        mut csprng: &mut dyn CryptoRngCore,
+    ) -> Result<Box<[u8]>> {
+        PrivateKey::calculate_signature_for_multipart_message(self, message, &mut csprng)
+    }
+
+    fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>> {
+        PrivateKey::calculate_agreement(self, their_key)
+    }
+}
+
 impl From<PrivateKeyData> for PrivateKey {
     fn from(key: PrivateKeyData) -> PrivateKey {
         Self { key }
@@ -297,14 +754,40 @@ impl TryFrom<&[u8]> for PrivateKey {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct KeyPair {
     pub public_key: PublicKey,
     pub private_key: PrivateKey,
 }
 
 impl KeyPair {
+    /// The shortest seed accepted by [KeyPair::derive_from_seed].
+    pub const MIN_SEED_LENGTH: usize = 16;
+
+    /// Generate a new Curve25519 key pair.
     pub fn generate<R: Rng + CryptoRng>(csprng: &mut R) -> Self {
+        Self::generate_with_key_type(KeyType::Djb, csprng)
+    }
+
+    /// Generate a new key pair of the given type.
+    pub fn generate_with_key_type<R: Rng + CryptoRng>(key_type: KeyType, csprng: &mut R) -> Self {
+        match key_type {
+            KeyType::Djb => Self::generate_djb(csprng),
+            KeyType::P256 => {
+                let private_key = nistp256::PrivateKey::new(csprng);
+                Self {
+                    public_key: PublicKey::new(PublicKeyData::P256PublicKey(
+                        private_key.derive_public_key_bytes(),
+                    )),
+                    private_key: PrivateKey::from(PrivateKeyData::P256PrivateKey(
+                        private_key.private_key_bytes(),
+                    )),
+                }
+            }
+        }
+    }
+
+    fn generate_djb<R: Rng + CryptoRng>(csprng: &mut R) -> Self {
         let private_key = curve25519::PrivateKey::new(csprng);
 
         let public_key = PublicKey::from(PublicKeyData::DjbPublicKey(
@@ -327,6 +810,30 @@ impl KeyPair {
         }
     }
 
+    /// Deterministically derive a key pair from `seed`, using HKDF-SHA256.
+    ///
+    /// The same `seed` and `info` always produce the same key pair, and different `info` strings
+    /// produce unrelated ones, so one seed can back several keys. `seed` must have at least
+    /// [KeyPair::MIN_SEED_LENGTH] bytes, and should be high-entropy: anyone who can guess it can
+    /// recover the private key.
+    pub fn derive_from_seed(seed: &[u8], info: &str) -> Result<Self> {
+        if seed.len() < Self::MIN_SEED_LENGTH {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "seed must be at least {} bytes, not {}",
+                Self::MIN_SEED_LENGTH,
+                seed.len()
+            )));
+        }
+        let mut private_key = [0u8; curve25519::PRIVATE_KEY_LENGTH];
+        hkdf::Hkdf::<sha2::Sha256>::new(None, seed)
+            .expand_multi_info(
+                &[b"Signal_KeyPairFromSeed_", info.as_bytes()],
+                &mut private_key,
+            )
+            .expect("valid length");
+        Self::try_from(PrivateKey::deserialize(&private_key)?)
+    }
+
     pub fn from_public_and_private(public_key: &[u8], private_key: &[u8]) -> Result<Self> {
         let public_key = PublicKey::try_from(public_key)?;
         let private_key = PrivateKey::try_from(private_key)?;
@@ -364,6 +871,34 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_derive_from_seed() -> Result<()> {
+        let seed = b"correct horse battery staple";
+        let key_pair = KeyPair::derive_from_seed(seed, "identity")?;
+        assert_eq!(
+            hex::encode(key_pair.private_key.serialize()),
+            "c882e1c7dd810b5a2cb124de97f2559d2827e508080ce9a5d8a4543e863fa643"
+        );
+
+        let again = KeyPair::derive_from_seed(seed, "identity")?;
+        assert_eq!(again.public_key, key_pair.public_key);
+        assert_eq!(again.private_key, key_pair.private_key);
+        assert_eq!(
+            key_pair.private_key.public_key()?,
+            key_pair.public_key,
+            "public key matches private key"
+        );
+
+        let other = KeyPair::derive_from_seed(seed, "signed pre-key")?;
+        assert_ne!(other.public_key, key_pair.public_key);
+
+        assert!(matches!(
+            KeyPair::derive_from_seed(&seed[..15], "identity"),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        Ok(())
+    }
+
     #[test]
     fn test_large_signatures() -> Result<()> {
         let mut csprng = OsRng;
@@ -394,6 +929,173 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_labeled_signatures() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate(&mut csprng);
+        let message = b"provisioning payload";
+        let signature = key_pair.private_key.calculate_signature_with_label(
+            "Test_Provisioning",
+            message,
+            &mut csprng,
+        )?;
+
+        assert!(key_pair.public_key.verify_signature_with_label(
+            "Test_Provisioning",
+            message,
+            &signature
+        )?);
+        assert!(!key_pair.public_key.verify_signature_with_label(
+            "Test_Registration",
+            message,
+            &signature
+        )?);
+        assert!(!key_pair.public_key.verify_signature(message, &signature)?);
+        // The label is length-prefixed, so it can't be shifted into the message.
+        assert!(!key_pair.public_key.verify_signature_with_label(
+            "Test_Provisioning provisioning",
+            b" payload",
+            &signature
+        )?);
+
+        for label in ["", "Signal_Anything", &"x".repeat(256)] {
+            assert!(matches!(
+                key_pair
+                    .private_key
+                    .calculate_signature_with_label(label, message, &mut csprng),
+                Err(SignalProtocolError::InvalidArgument(_))
+            ));
+            assert!(key_pair
+                .public_key
+                .verify_signature_with_label(label, message, &signature)
+                .is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_vrf_signatures() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate(&mut csprng);
+        let (signature, output) = key_pair
+            .private_key
+            .calculate_vrf_signature(b"group", &mut csprng)?;
+        assert_eq!(
+            key_pair
+                .public_key
+                .verify_vrf_signature(b"group", &signature)?,
+            Some(output)
+        );
+        assert_eq!(
+            key_pair
+                .public_key
+                .verify_vrf_signature(b"group", &signature[1..])?,
+            None
+        );
+
+        let p256 = KeyPair::generate_with_key_type(KeyType::P256, &mut csprng);
+        assert!(p256
+            .private_key
+            .calculate_vrf_signature(b"group", &mut csprng)
+            .is_err());
+        assert!(p256
+            .public_key
+            .verify_vrf_signature(b"group", &signature)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_precomputed_public_keys() -> Result<()> {
+        let mut csprng = OsRng;
+        for key_type in [KeyType::Djb, KeyType::P256] {
+            let signer = KeyPair::generate_with_key_type(key_type, &mut csprng);
+            let other = KeyPair::generate_with_key_type(key_type, &mut csprng);
+            let precomputed = PrecomputedPublicKey::from(signer.public_key);
+            assert_eq!(precomputed.public_key(), &signer.public_key);
+
+            let mut signature = signer.calculate_signature(b"message", &mut csprng)?;
+            assert!(precomputed.verify_signature(b"message", &signature)?);
+            assert!(!precomputed.verify_signature(b"messagf", &signature)?);
+            assert!(!precomputed.verify_signature(b"message", &signature[1..])?);
+            signature[3] ^= 1;
+            assert!(!precomputed.verify_signature(b"message", &signature)?);
+
+            assert_eq!(
+                other
+                    .private_key
+                    .calculate_agreement_with_precomputed(&precomputed)?,
+                other.private_key.calculate_agreement(&signer.public_key)?
+            );
+        }
+
+        let djb = KeyPair::generate(&mut csprng);
+        let p256 = PrecomputedPublicKey::new(
+            &KeyPair::generate_with_key_type(KeyType::P256, &mut csprng).public_key,
+        );
+        assert!(matches!(
+            djb.private_key.calculate_agreement_with_precomputed(&p256),
+            Err(SignalProtocolError::MismatchedKeyTypes(..))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_signatures() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pairs: Vec<KeyPair> = (0..8).map(|_| KeyPair::generate(&mut csprng)).collect();
+        let messages: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 100 * i as usize]).collect();
+        let mut signatures = key_pairs
+            .iter()
+            .zip(&messages)
+            .map(|(key_pair, message)| key_pair.calculate_signature(message, &mut csprng))
+            .collect::<Result<Vec<_>>>()?;
+
+        fn batch<'a>(
+            key_pairs: &'a [KeyPair],
+            messages: &'a [Vec<u8>],
+            signatures: &'a [Box<[u8]>],
+        ) -> Vec<(&'a PublicKey, &'a [u8], &'a [u8])> {
+            key_pairs
+                .iter()
+                .zip(messages)
+                .zip(signatures)
+                .map(|((key_pair, message), signature)| {
+                    (&key_pair.public_key, &message[..], &signature[..])
+                })
+                .collect()
+        }
+
+        assert!(PublicKey::verify_signatures_batch(&[], &mut csprng)?);
+        assert!(PublicKey::verify_signatures_batch(
+            &batch(&key_pairs, &messages, &signatures),
+            &mut csprng
+        )?);
+
+        signatures[3][5] ^= 0x01;
+        assert!(!PublicKey::verify_signatures_batch(
+            &batch(&key_pairs, &messages, &signatures),
+            &mut csprng
+        )?);
+        signatures[3][5] ^= 0x01;
+
+        // Signatures attributed to the wrong key.
+        signatures.swap(1, 2);
+        assert!(!PublicKey::verify_signatures_batch(
+            &batch(&key_pairs, &messages, &signatures),
+            &mut csprng
+        )?);
+        signatures.swap(1, 2);
+
+        signatures[7] = signatures[7][..63].into();
+        assert!(!PublicKey::verify_signatures_batch(
+            &batch(&key_pairs, &messages, &signatures),
+            &mut csprng
+        )?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_decode_size() -> Result<()> {
         let mut csprng = OsRng;
@@ -426,4 +1128,41 @@ mod tests {
         assert_eq!(&serialized_public[..], &extra_space_decode?.serialize()[..]);
         Ok(())
     }
+
+    #[test]
+    fn test_p256_keys() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate_with_key_type(KeyType::P256, &mut csprng);
+        assert_eq!(key_pair.public_key.key_type(), KeyType::P256);
+        assert_eq!(key_pair.private_key.key_type(), KeyType::P256);
+
+        let serialized_public = key_pair.public_key.serialize();
+        assert_eq!(serialized_public.len(), 34);
+        assert_eq!(serialized_public[0], 0x06);
+        assert_eq!(
+            PublicKey::deserialize(&serialized_public)?,
+            key_pair.public_key
+        );
+
+        let serialized_private = key_pair.private_key.serialize();
+        assert_eq!(serialized_private.len(), 33);
+        assert_eq!(
+            PrivateKey::deserialize(&serialized_private)?,
+            key_pair.private_key
+        );
+
+        let message = b"sign me";
+        let signature = key_pair.calculate_signature(message, &mut csprng)?;
+        assert!(key_pair.public_key.verify_signature(message, &signature)?);
+
+        let djb_key_pair = KeyPair::generate(&mut csprng);
+        assert!(matches!(
+            key_pair.calculate_agreement(&djb_key_pair.public_key),
+            Err(SignalProtocolError::MismatchedKeyTypes(
+                KeyType::P256,
+                KeyType::Djb
+            ))
+        ));
+        Ok(())
+    }
 }
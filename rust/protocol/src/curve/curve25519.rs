@@ -3,10 +3,12 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
-use curve25519_dalek::edwards::EdwardsPoint;
+use arrayref::array_ref;
+use curve25519_dalek::constants::{ED25519_BASEPOINT_POINT, ED25519_BASEPOINT_TABLE};
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
 use rand::{CryptoRng, Rng};
 use sha2::{Digest, Sha512};
 use subtle::ConstantTimeEq;
@@ -16,6 +18,16 @@ const AGREEMENT_LENGTH: usize = 32;
 pub const PRIVATE_KEY_LENGTH: usize = 32;
 pub const PUBLIC_KEY_LENGTH: usize = 32;
 pub const SIGNATURE_LENGTH: usize = 64;
+pub const VRF_SIGNATURE_LENGTH: usize = 96;
+pub const VRF_OUTPUT_LENGTH: usize = 32;
+
+/// A public key, message (in parts), and signature to be checked by
+/// [`PrivateKey::verify_signatures_batch`].
+pub type SignatureBatchEntry<'a> = (
+    &'a [u8; PUBLIC_KEY_LENGTH],
+    &'a [&'a [u8]],
+    &'a [u8; SIGNATURE_LENGTH],
+);
 
 #[derive(Clone)]
 pub struct PrivateKey {
@@ -112,34 +124,183 @@ impl PrivateKey {
                 Some(x) => x,
                 None => return false,
             };
-        let cap_a = ed_pub_key_point.compress();
-        let mut cap_r = [0u8; 32];
-        cap_r.copy_from_slice(&signature[..32]);
-        let mut s = [0u8; 32];
-        s.copy_from_slice(&signature[32..]);
-        s[31] &= 0b0111_1111_u8;
-        if (s[31] & 0b1110_0000_u8) != 0 {
-            return false;
-        }
-        let minus_cap_a = -ed_pub_key_point;
+        verify_signature_with_point(
+            &ed_pub_key_point,
+            &ed_pub_key_point.compress(),
+            message,
+            signature,
+        )
+    }
 
-        let mut hash = Sha512::new();
-        // Explicitly pass a slice to avoid generating multiple versions of update().
-        hash.update(&cap_r[..]);
-        hash.update(cap_a.as_bytes());
-        for message_piece in message {
-            hash.update(message_piece);
+    /// Verifies many XEdDSA signatures at once.
+    ///
+    /// Each signature `(A_i, R_i, s_i)` over `h_i` is checked by picking a random 128-bit scalar
+    /// `z_i` and testing the single combined equation
+    /// `sum(z_i * R_i) + sum(z_i * h_i * A_i) - sum(z_i * s_i) * B == 0`
+    /// with one multiscalar multiplication, rather than one double-scalar multiplication per
+    /// signature.
+    ///
+    /// That equation doesn't multiply by the cofactor, so it only matches
+    /// [`Self::verify_signature`] for points in the prime-order subgroup. Any key or `R` with a
+    /// small-order component makes the whole batch fail, even if that signature would pass on its
+    /// own.
+    ///
+    /// A result of `true` means every signature is valid (with overwhelming probability); `false`
+    /// means at least one is not, or uses such a point, without saying which.
+    pub fn verify_signatures_batch<R>(csprng: &mut R, signatures: &[SignatureBatchEntry]) -> bool
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut scalars = Vec::with_capacity(2 * signatures.len() + 1);
+        let mut points = Vec::with_capacity(2 * signatures.len() + 1);
+        let mut basepoint_scalar = Scalar::zero();
+
+        for (their_public_key, message, signature) in signatures {
+            let mont_point = MontgomeryPoint(**their_public_key);
+            let ed_pub_key_point = match mont_point
+                .to_edwards((signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8) >> 7)
+            {
+                Some(x) => x,
+                None => return false,
+            };
+            if !ed_pub_key_point.is_torsion_free() {
+                return false;
+            }
+            let cap_a = ed_pub_key_point.compress();
+
+            let mut cap_r = [0u8; 32];
+            cap_r.copy_from_slice(&signature[..32]);
+            let cap_r_point = match CompressedEdwardsY(cap_r).decompress() {
+                Some(x) => x,
+                None => return false,
+            };
+            // Single verification compares encodings, so reject anything non-canonical here too.
+            if !bool::from(cap_r_point.compress().as_bytes().ct_eq(&cap_r)) {
+                return false;
+            }
+            if !cap_r_point.is_torsion_free() {
+                return false;
+            }
+
+            let mut s = [0u8; 32];
+            s.copy_from_slice(&signature[32..]);
+            s[31] &= 0b0111_1111_u8;
+            if (s[31] & 0b1110_0000_u8) != 0 {
+                return false;
+            }
+
+            let mut hash = Sha512::new();
+            // Explicitly pass a slice to avoid generating multiple versions of update().
+            hash.update(&cap_r[..]);
+            hash.update(cap_a.as_bytes());
+            for message_piece in message.iter() {
+                hash.update(message_piece);
+            }
+            let h = Scalar::from_hash(hash);
+
+            let mut z_bytes = [0u8; 32];
+            csprng.fill_bytes(&mut z_bytes[..16]);
+            let z = Scalar::from_bits(z_bytes);
+
+            basepoint_scalar -= z * Scalar::from_bits(s);
+            scalars.push(z);
+            points.push(cap_r_point);
+            scalars.push(z * h);
+            points.push(ed_pub_key_point);
         }
-        let h = Scalar::from_hash(hash);
 
-        let cap_r_check_point = EdwardsPoint::vartime_double_scalar_mul_basepoint(
-            &h,
-            &minus_cap_a,
-            &Scalar::from_bits(s),
+        scalars.push(basepoint_scalar);
+        points.push(ED25519_BASEPOINT_POINT);
+
+        EdwardsPoint::vartime_multiscalar_mul(scalars, points).is_identity()
+    }
+
+    /// Calculates a VXEdDSA signature and the VRF output it proves.
+    ///
+    /// Refer to https://signal.org/docs/specifications/xeddsa/#vxeddsa for more details. Unlike
+    /// [`Self::calculate_signature`], this follows the specification exactly: the Edwards form of
+    /// the public key always has a sign bit of 0, so there's nothing to smuggle in the signature.
+    ///
+    /// The output depends only on the key and the message; the signature lets anyone with the
+    /// public key check that, via [`Self::verify_vrf_signature`].
+    pub fn calculate_vrf_signature<R>(
+        &self,
+        csprng: &mut R,
+        message: &[u8],
+    ) -> ([u8; VRF_SIGNATURE_LENGTH], [u8; VRF_OUTPUT_LENGTH])
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut random_bytes = [0u8; 64];
+        csprng.fill_bytes(&mut random_bytes);
+
+        // calculate_key_pair: negate the key if needed so that A has a sign bit of 0.
+        let k = Scalar::from_bytes_mod_order(self.secret.to_bytes());
+        let e = (&k * &ED25519_BASEPOINT_TABLE).compress();
+        let a = if e.as_bytes()[31] & 0b1000_0000_u8 != 0 {
+            -k
+        } else {
+            k
+        };
+        let cap_a = (&a * &ED25519_BASEPOINT_TABLE).compress();
+
+        let cap_b_v = vrf_hash_to_point(&cap_a, message);
+        let cap_v = (a * cap_b_v).compress();
+
+        let r = Scalar::from_hash(
+            vrf_hash(3)
+                .chain(a.as_bytes())
+                .chain(cap_v.as_bytes())
+                .chain(&random_bytes[..]),
         );
-        let cap_r_check = cap_r_check_point.compress();
+        let cap_r = (&r * &ED25519_BASEPOINT_TABLE).compress();
+        let cap_r_v = (r * cap_b_v).compress();
 
-        bool::from(cap_r_check.as_bytes().ct_eq(&cap_r))
+        let h = vrf_challenge(&cap_a, &cap_v, &cap_r, &cap_r_v, message);
+        let s = r + h * a;
+
+        let mut signature = [0u8; VRF_SIGNATURE_LENGTH];
+        signature[..32].copy_from_slice(cap_v.as_bytes());
+        signature[32..64].copy_from_slice(h.as_bytes());
+        signature[64..].copy_from_slice(s.as_bytes());
+        let output = vrf_output(&cap_v.decompress().expect("computed above"));
+        (signature, output)
+    }
+
+    /// Verifies a VXEdDSA signature, returning the VRF output if it is valid.
+    pub fn verify_vrf_signature(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[u8],
+        signature: &[u8; VRF_SIGNATURE_LENGTH],
+    ) -> Option<[u8; VRF_OUTPUT_LENGTH]> {
+        let mut v_y = *array_ref![signature, 0, 32];
+        v_y[31] &= 0b0111_1111_u8;
+        if !is_canonical_field_element(their_public_key) || !is_canonical_field_element(&v_y) {
+            return None;
+        }
+        let h_bytes = *array_ref![signature, 32, 32];
+        let h = Scalar::from_canonical_bytes(h_bytes)?;
+        let s = Scalar::from_canonical_bytes(*array_ref![signature, 64, 32])?;
+
+        let a_point = MontgomeryPoint(*their_public_key).to_edwards(0)?;
+        let cap_a = a_point.compress();
+        let cap_b_v = vrf_hash_to_point(&cap_a, message);
+        let cap_v = CompressedEdwardsY(*array_ref![signature, 0, 32]);
+        let v_point = cap_v.decompress()?;
+        if a_point.is_small_order() || v_point.is_small_order() || cap_b_v.is_identity() {
+            return None;
+        }
+
+        let cap_r = EdwardsPoint::vartime_double_scalar_mul_basepoint(&h, &-a_point, &s).compress();
+        let cap_r_v =
+            EdwardsPoint::vartime_multiscalar_mul(&[s, h], &[cap_b_v, -v_point]).compress();
+        let h_check = vrf_challenge(&cap_a, &cap_v, &cap_r, &cap_r_v, message);
+
+        if bool::from(h_check.as_bytes().ct_eq(&h_bytes)) {
+            Some(vrf_output(&v_point))
+        } else {
+            None
+        }
     }
 
     pub fn derive_public_key_bytes(&self) -> [u8; PUBLIC_KEY_LENGTH] {
@@ -151,6 +312,131 @@ impl PrivateKey {
     }
 }
 
+/// Checks an XEdDSA signature against the Edwards form of the signer's public key, whose sign bit
+/// has already been taken from the signature.
+fn verify_signature_with_point(
+    ed_pub_key_point: &EdwardsPoint,
+    cap_a: &CompressedEdwardsY,
+    message: &[&[u8]],
+    signature: &[u8; SIGNATURE_LENGTH],
+) -> bool {
+    let mut cap_r = [0u8; 32];
+    cap_r.copy_from_slice(&signature[..32]);
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&signature[32..]);
+    s[31] &= 0b0111_1111_u8;
+    if (s[31] & 0b1110_0000_u8) != 0 {
+        return false;
+    }
+    let minus_cap_a = -ed_pub_key_point;
+
+    let mut hash = Sha512::new();
+    // Explicitly pass a slice to avoid generating multiple versions of update().
+    hash.update(&cap_r[..]);
+    hash.update(cap_a.as_bytes());
+    for message_piece in message {
+        hash.update(message_piece);
+    }
+    let h = Scalar::from_hash(hash);
+
+    let cap_r_check_point =
+        EdwardsPoint::vartime_double_scalar_mul_basepoint(&h, &minus_cap_a, &Scalar::from_bits(s));
+    let cap_r_check = cap_r_check_point.compress();
+
+    bool::from(cap_r_check.as_bytes().ct_eq(&cap_r))
+}
+
+/// A public key with both of its possible Edwards forms decompressed ahead of time.
+///
+/// XEdDSA signatures carry the sign bit of the signer's Edwards public key, so verifying one
+/// normally starts by converting the Montgomery public key to that form. Keeping both forms around
+/// saves that work when checking many signatures from the same key. (X25519 agreement works on
+/// the Montgomery form directly, so there is nothing to precompute for it.)
+#[derive(Clone)]
+pub struct PrecomputedPublicKey {
+    /// Indexed by sign bit; `None` for the few byte strings that have no Edwards form.
+    points: Option<[(EdwardsPoint, CompressedEdwardsY); 2]>,
+}
+
+impl PrecomputedPublicKey {
+    pub fn new(their_public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Self {
+        let points = MontgomeryPoint(*their_public_key)
+            .to_edwards(0)
+            .map(|positive| {
+                let negative = -positive;
+                [
+                    (positive, positive.compress()),
+                    (negative, negative.compress()),
+                ]
+            });
+        Self { points }
+    }
+
+    /// As [`PrivateKey::verify_signature`].
+    pub fn verify_signature(&self, message: &[&[u8]], signature: &[u8; SIGNATURE_LENGTH]) -> bool {
+        let sign_bit = (signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8) >> 7;
+        match &self.points {
+            Some(points) => {
+                let (point, compressed) = &points[usize::from(sign_bit)];
+                verify_signature_with_point(point, compressed, message, signature)
+            }
+            None => false,
+        }
+    }
+}
+
+/// The hash function `hash_i` from the XEdDSA specification: SHA-512, prefixed with
+/// `2^256 - 1 - i` in little-endian.
+fn vrf_hash(i: u8) -> Sha512 {
+    let mut prefix = [0xFFu8; 32];
+    prefix[0] -= i;
+    Sha512::new().chain(&prefix[..])
+}
+
+/// `hash_to_point(A || M)`, using Elligator 2 on `hash_2`.
+fn vrf_hash_to_point(cap_a: &CompressedEdwardsY, message: &[u8]) -> EdwardsPoint {
+    let mut input = Vec::with_capacity(32 + 32 + message.len());
+    input.extend_from_slice(&[0xFFu8; 32]);
+    input[0] -= 2;
+    input.extend_from_slice(cap_a.as_bytes());
+    input.extend_from_slice(message);
+    EdwardsPoint::hash_from_bytes::<Sha512>(&input)
+}
+
+fn vrf_challenge(
+    cap_a: &CompressedEdwardsY,
+    cap_v: &CompressedEdwardsY,
+    cap_r: &CompressedEdwardsY,
+    cap_r_v: &CompressedEdwardsY,
+    message: &[u8],
+) -> Scalar {
+    Scalar::from_hash(
+        vrf_hash(4)
+            .chain(cap_a.as_bytes())
+            .chain(cap_v.as_bytes())
+            .chain(cap_r.as_bytes())
+            .chain(cap_r_v.as_bytes())
+            .chain(message),
+    )
+}
+
+fn vrf_output(v: &EdwardsPoint) -> [u8; VRF_OUTPUT_LENGTH] {
+    let hash = vrf_hash(5)
+        .chain(v.mul_by_cofactor().compress().as_bytes())
+        .finalize();
+    *array_ref![hash, 0, VRF_OUTPUT_LENGTH]
+}
+
+/// Whether `bytes` is the little-endian encoding of an integer less than `2^255 - 19`.
+fn is_canonical_field_element(bytes: &[u8; 32]) -> bool {
+    if bytes[31] & 0b1000_0000_u8 != 0 {
+        return false;
+    }
+    let is_at_least_p =
+        bytes[31] == 0x7F && bytes[1..31].iter().all(|&b| b == 0xFF) && bytes[0] >= 0xED;
+    !is_at_least_p
+}
+
 impl From<[u8; PRIVATE_KEY_LENGTH]> for PrivateKey {
     fn from(private_key: [u8; 32]) -> Self {
         let secret = StaticSecret::from(private_key);
@@ -279,6 +565,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_vrf_signatures() {
+        let mut csprng = OsRng;
+        for _ in 0..20 {
+            let key = PrivateKey::new(&mut csprng);
+            let public_key = key.derive_public_key_bytes();
+            let (signature, output) = key.calculate_vrf_signature(&mut csprng, b"message");
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(&public_key, b"message", &signature),
+                Some(output)
+            );
+
+            // The output is a function of the key and message alone.
+            let (other_signature, other_output) =
+                key.calculate_vrf_signature(&mut csprng, b"message");
+            assert_ne!(signature, other_signature);
+            assert_eq!(output, other_output);
+            let (_, different_output) = key.calculate_vrf_signature(&mut csprng, b"messagf");
+            assert_ne!(output, different_output);
+
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(&public_key, b"messagf", &signature),
+                None
+            );
+            let other_key = PrivateKey::new(&mut csprng).derive_public_key_bytes();
+            assert_eq!(
+                PrivateKey::verify_vrf_signature(&other_key, b"message", &signature),
+                None
+            );
+            for i in 0..VRF_SIGNATURE_LENGTH {
+                let mut tampered = signature;
+                tampered[i] ^= 0x01;
+                assert_eq!(
+                    PrivateKey::verify_vrf_signature(&public_key, b"message", &tampered),
+                    None
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_canonical_field_elements() {
+        let mut p = [0xFFu8; 32];
+        p[0] = 0xED;
+        p[31] = 0x7F;
+        assert!(!is_canonical_field_element(&p));
+        p[0] = 0xEC;
+        assert!(is_canonical_field_element(&p));
+        assert!(!is_canonical_field_element(&[0xFF; 32]));
+        assert!(is_canonical_field_element(&[0; 32]));
+    }
+
+    #[test]
+    fn test_precomputed_signatures() {
+        let mut csprng = OsRng;
+        for _ in 0..50 {
+            let mut message = [0u8; 64];
+            csprng.fill_bytes(&mut message);
+            let key = PrivateKey::new(&mut csprng);
+            let precomputed = PrecomputedPublicKey::new(&key.derive_public_key_bytes());
+            let mut signature = key.calculate_signature(&mut csprng, &[&message]);
+            assert!(precomputed.verify_signature(&[&message], &signature));
+            signature[0] ^= 1;
+            assert!(!precomputed.verify_signature(&[&message], &signature));
+        }
+    }
+
     #[test]
     fn test_random_signatures() {
         let mut csprng = OsRng;
@@ -297,4 +650,55 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_batch_rejects_torsion() {
+        /// Fills every byte with 8, so each random `z` is a multiple of the cofactor and the
+        /// small-order components all cancel out of the batch equation.
+        struct CofactorRng;
+
+        impl RngCore for CofactorRng {
+            fn next_u32(&mut self) -> u32 {
+                0x0808_0808
+            }
+            fn next_u64(&mut self) -> u64 {
+                0x0808_0808_0808_0808
+            }
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                dest.fill(8);
+            }
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+        impl CryptoRng for CofactorRng {}
+
+        let mut csprng = OsRng;
+        let message = b"message";
+        let key = PrivateKey::new(&mut csprng);
+        let public_key = key.derive_public_key_bytes();
+        let signature = key.calculate_signature(&mut csprng, &[message]);
+        assert!(PrivateKey::verify_signatures_batch(
+            &mut CofactorRng,
+            &[(&public_key, &[message], &signature)]
+        ));
+
+        // Adding a point of order 8 to R breaks the signature, but not the batch equation.
+        let mut cap_r = [0u8; 32];
+        cap_r.copy_from_slice(&signature[..32]);
+        let cap_r = CompressedEdwardsY(cap_r).decompress().expect("valid point")
+            + curve25519_dalek::constants::EIGHT_TORSION[1];
+        let mut tweaked = signature;
+        tweaked[..32].copy_from_slice(cap_r.compress().as_bytes());
+        assert!(!PrivateKey::verify_signature(
+            &public_key,
+            &[message],
+            &tweaked
+        ));
+        assert!(!PrivateKey::verify_signatures_batch(
+            &mut CofactorRng,
+            &[(&public_key, &[message], &tweaked)]
+        ));
+    }
 }
@@ -0,0 +1,190 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! NIST P-256, for deployments that can only use FIPS-approved curves.
+//!
+//! Public keys are stored as compressed SEC1 points, private keys as big-endian scalars, and
+//! signatures as fixed-size ECDSA `r || s` pairs over SHA-256. Unlike XEdDSA, signing is
+//! deterministic (RFC 6979), so no randomness is needed.
+
+use p256::ecdsa::signature::{Signature as _, Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rand::{CryptoRng, Rng};
+
+const AGREEMENT_LENGTH: usize = 32;
+pub const PRIVATE_KEY_LENGTH: usize = 32;
+pub const PUBLIC_KEY_LENGTH: usize = 33;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+#[derive(Clone)]
+pub struct PrivateKey {
+    secret: p256::SecretKey,
+}
+
+impl PrivateKey {
+    pub fn new<R>(csprng: &mut R) -> Self
+    where
+        R: CryptoRng + Rng,
+    {
+        // Almost every 32-byte string is a valid scalar; the loop only repeats for values outside
+        // the group order, which is vanishingly unlikely.
+        loop {
+            let mut bytes = [0u8; PRIVATE_KEY_LENGTH];
+            csprng.fill_bytes(&mut bytes);
+            if let Some(key) = Self::from_bytes(&bytes) {
+                return key;
+            }
+        }
+    }
+
+    /// Returns `None` if `bytes` is zero or not less than the group order.
+    pub fn from_bytes(bytes: &[u8; PRIVATE_KEY_LENGTH]) -> Option<Self> {
+        p256::SecretKey::from_be_bytes(bytes)
+            .ok()
+            .map(|secret| Self { secret })
+    }
+
+    pub fn private_key_bytes(&self) -> [u8; PRIVATE_KEY_LENGTH] {
+        self.secret.to_be_bytes().into()
+    }
+
+    pub fn derive_public_key_bytes(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        let point = self.secret.public_key().to_encoded_point(true);
+        let mut result = [0u8; PUBLIC_KEY_LENGTH];
+        result.copy_from_slice(point.as_bytes());
+        result
+    }
+
+    /// Returns `None` if `their_public_key` is not a point on the curve.
+    pub fn calculate_agreement(
+        &self,
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+    ) -> Option<[u8; AGREEMENT_LENGTH]> {
+        let their_public_key = p256::PublicKey::from_sec1_bytes(their_public_key).ok()?;
+        let shared = p256::elliptic_curve::ecdh::diffie_hellman(
+            self.secret.to_nonzero_scalar(),
+            their_public_key.as_affine(),
+        );
+        Some((*shared.as_bytes()).into())
+    }
+
+    pub fn calculate_signature(&self, message: &[&[u8]]) -> [u8; SIGNATURE_LENGTH] {
+        let signature: Signature = SigningKey::from(&self.secret).sign(&message.concat());
+        let mut result = [0u8; SIGNATURE_LENGTH];
+        result.copy_from_slice(signature.as_ref());
+        result
+    }
+
+    pub fn verify_signature(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[&[u8]],
+        signature: &[u8; SIGNATURE_LENGTH],
+    ) -> bool {
+        let key = match VerifyingKey::from_sec1_bytes(their_public_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(&signature[..]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        key.verify(&message.concat(), &signature).is_ok()
+    }
+}
+
+/// A public key decompressed ahead of time, for repeated verification and agreement.
+#[derive(Clone, Copy)]
+pub struct PrecomputedPublicKey {
+    key: p256::PublicKey,
+    verifying_key: VerifyingKey,
+}
+
+impl PrecomputedPublicKey {
+    /// Returns `None` if `their_public_key` is not a point on the curve.
+    pub fn new(their_public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Option<Self> {
+        let key = p256::PublicKey::from_sec1_bytes(their_public_key).ok()?;
+        Some(Self {
+            key,
+            verifying_key: VerifyingKey::from(&key),
+        })
+    }
+
+    /// As [`PrivateKey::verify_signature`].
+    pub fn verify_signature(&self, message: &[&[u8]], signature: &[u8; SIGNATURE_LENGTH]) -> bool {
+        match Signature::from_bytes(&signature[..]) {
+            Ok(signature) => self
+                .verifying_key
+                .verify(&message.concat(), &signature)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// As [`PrivateKey::calculate_agreement`], from the other side.
+    pub fn calculate_agreement(&self, private_key: &PrivateKey) -> [u8; AGREEMENT_LENGTH] {
+        let shared = p256::elliptic_curve::ecdh::diffie_hellman(
+            private_key.secret.to_nonzero_scalar(),
+            self.key.as_affine(),
+        );
+        (*shared.as_bytes()).into()
+    }
+}
+
+/// Whether `bytes` is a compressed encoding of a point on the curve.
+pub fn is_valid_public_key(bytes: &[u8; PUBLIC_KEY_LENGTH]) -> bool {
+    p256::PublicKey::from_sec1_bytes(bytes).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn test_agreement() {
+        let alice = PrivateKey::new(&mut OsRng);
+        let bob = PrivateKey::new(&mut OsRng);
+
+        let shared_alice = alice.calculate_agreement(&bob.derive_public_key_bytes());
+        let shared_bob = bob.calculate_agreement(&alice.derive_public_key_bytes());
+        assert!(shared_alice.is_some());
+        assert_eq!(shared_alice, shared_bob);
+
+        let mut not_a_point = [0xFFu8; PUBLIC_KEY_LENGTH];
+        not_a_point[0] = 0x02;
+        assert!(!is_valid_public_key(&not_a_point));
+        assert_eq!(alice.calculate_agreement(&not_a_point), None);
+    }
+
+    #[test]
+    fn test_signature() {
+        let key = PrivateKey::new(&mut OsRng);
+        let public_key = key.derive_public_key_bytes();
+        let message: [&[u8]; 2] = [b"hello ", b"world"];
+
+        let signature = key.calculate_signature(&message);
+        assert!(PrivateKey::verify_signature(
+            &public_key,
+            &message,
+            &signature
+        ));
+        assert!(PrivateKey::verify_signature(
+            &public_key,
+            &[b"hello world"],
+            &signature
+        ));
+        assert!(!PrivateKey::verify_signature(
+            &public_key,
+            &[b"goodbye world"],
+            &signature
+        ));
+
+        let round_tripped = PrivateKey::from_bytes(&key.private_key_bytes()).expect("valid scalar");
+        assert_eq!(round_tripped.derive_public_key_bytes(), public_key);
+        assert!(PrivateKey::from_bytes(&[0; PRIVATE_KEY_LENGTH]).is_none());
+    }
+}
@@ -5,6 +5,7 @@
 
 use crate::curve::KeyType;
 use crate::kem;
+use crate::Timestamp;
 
 use displaydoc::Display;
 use thiserror::Error;
@@ -14,7 +15,13 @@ use std::panic::UnwindSafe;
 
 pub type Result<T> = std::result::Result<T, SignalProtocolError>;
 
+/// Errors produced by this crate.
+///
+/// New variants may be added in any release, so matches must include a wildcard arm. Errors from
+/// subsystems built on top of this crate that don't warrant a variant of their own can be carried
+/// in [`Extension`](Self::Extension); see [`SignalProtocolError::extension`].
 #[derive(Debug, Display, Error)]
+#[non_exhaustive]
 pub enum SignalProtocolError {
     /// invalid argument: {0}
     InvalidArgument(String),
@@ -44,12 +51,18 @@ pub enum SignalProtocolError {
     BadKeyType(u8),
     /// bad key length <{1}> for key with type <{0}>
     BadKeyLength(KeyType, usize),
+    /// invalid key encoding: {0}
+    InvalidKeyEncoding(&'static str),
+    /// mismatched key types <{0}> and <{1}>
+    MismatchedKeyTypes(KeyType, KeyType),
 
     /// invalid signature detected
     SignatureValidationFailed,
 
     /// untrusted identity for address {0}
     UntrustedIdentity(crate::ProtocolAddress),
+    /// identity key changed for address {0}
+    IdentityKeyChanged(crate::ProtocolAddress),
 
     /// invalid prekey identifier
     InvalidPreKeyId,
@@ -92,6 +105,16 @@ pub enum SignalProtocolError {
     UnknownSealedSenderVersion(u8),
     /// self send of a sealed sender message
     SealedSenderSelfSend,
+    /// sealed sender envelope timestamp {timestamp:?} is too old (local time {now:?})
+    EnvelopeTooOld {
+        timestamp: Timestamp,
+        now: Timestamp,
+    },
+    /// sealed sender envelope timestamp {timestamp:?} is in the future (local time {now:?})
+    EnvelopeFromFuture {
+        timestamp: Timestamp,
+        now: Timestamp,
+    },
 
     /// bad KEM key type <{0:#04x}>
     BadKEMKeyType(u8),
@@ -101,4 +124,252 @@ pub enum SignalProtocolError {
     BadKEMKeyLength(kem::KeyType, usize),
     /// bad KEM ciphertext length <{1}> for key with type <{0}>
     BadKEMCiphertextLength(kem::KeyType, usize),
+
+    /// decryption exceeded its limit on {0}
+    WorkLimitExceeded(&'static str),
+
+    /// record failed integrity check: {0}
+    RecordIntegrityCheckFailed(&'static str),
+    /// {0} has format version {1}, which is newer than this library supports
+    UnrecognizedRecordVersion(&'static str, u32),
+
+    /// random number generator failed health check: {0}
+    RngHealthCheckFailed(&'static str),
+
+    /// invalid plaintext padding: {0}
+    InvalidPadding(&'static str),
+
+    /// invalid profile ciphertext: {0}
+    InvalidProfileCiphertext(&'static str),
+
+    /// {0}
+    Extension(#[source] ExtensionError),
+}
+
+/// The payload of [`SignalProtocolError::Extension`].
+pub type ExtensionError = Box<dyn std::error::Error + Send + Sync + UnwindSafe + 'static>;
+
+impl SignalProtocolError {
+    /// Wraps an error from outside this crate's own subsystems, so it can be returned through
+    /// APIs (such as store callbacks) that produce [`SignalProtocolError`].
+    pub fn extension(error: impl std::error::Error + Send + Sync + UnwindSafe + 'static) -> Self {
+        Self::Extension(Box::new(error))
+    }
+
+    /// Returns the wrapped error if this is an [`Extension`](Self::Extension) of type `E`.
+    pub fn downcast_extension_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        match self {
+            Self::Extension(error) => {
+                let error: &(dyn std::error::Error + 'static) = &**error;
+                error.downcast_ref()
+            }
+            _ => None,
+        }
+    }
+
+    /// Unwraps an [`Extension`](Self::Extension) of type `E`, or gives back `self` unchanged.
+    pub fn downcast_extension<E: std::error::Error + 'static>(
+        self,
+    ) -> std::result::Result<E, Self> {
+        match self {
+            Self::Extension(error) if (&*error as &(dyn std::error::Error + 'static)).is::<E>() => {
+                let error: Box<dyn std::error::Error + Send + Sync> = error;
+                Ok(*error.downcast().expect("checked type above"))
+            }
+            other => Err(other),
+        }
+    }
+}
+
+impl From<ExtensionError> for SignalProtocolError {
+    fn from(error: ExtensionError) -> Self {
+        Self::Extension(error)
+    }
+}
+
+/// Converts errors from other subsystems into [`SignalProtocolError::Extension`].
+///
+/// (A blanket `From` impl isn't possible, since it would overlap with the conversions for this
+/// crate's own error types.)
+pub trait ExtensionResultExt<T> {
+    /// Wraps any error in [`SignalProtocolError::Extension`].
+    fn map_extension_err(self) -> Result<T>;
+}
+
+impl<T, E> ExtensionResultExt<T> for std::result::Result<T, E>
+where
+    E: std::error::Error + Send + Sync + UnwindSafe + 'static,
+{
+    fn map_extension_err(self) -> Result<T> {
+        self.map_err(SignalProtocolError::extension)
+    }
+}
+
+/// The library operation that produced a [ContextualError].
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolOperation {
+    /// message_encrypt
+    MessageEncrypt,
+    /// message_decrypt
+    MessageDecrypt,
+    /// process_prekey
+    ProcessPreKey,
+    /// process_prekey_bundle
+    ProcessPreKeyBundle,
+    /// group_encrypt
+    GroupEncrypt,
+    /// group_decrypt
+    GroupDecrypt,
+    /// process_sender_key_distribution_message
+    ProcessSenderKeyDistributionMessage,
+    /// sealed_sender_encrypt
+    SealedSenderEncrypt,
+    /// sealed_sender_decrypt
+    SealedSenderDecrypt,
+}
+
+/// What was being done when an error occurred, beyond what the error itself records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The operation that failed.
+    pub operation: ProtocolOperation,
+    /// The remote device involved, if the operation was specific to one.
+    pub remote_address: Option<crate::ProtocolAddress>,
+    /// The type of the message being processed, if any.
+    pub message_type: Option<crate::CiphertextMessageType>,
+}
+
+impl ErrorContext {
+    /// A context recording only `operation`.
+    pub fn new(operation: ProtocolOperation) -> Self {
+        Self {
+            operation,
+            remote_address: None,
+            message_type: None,
+        }
+    }
+
+    /// Records the remote device involved.
+    pub fn with_remote_address(mut self, remote_address: &crate::ProtocolAddress) -> Self {
+        self.remote_address = Some(remote_address.clone());
+        self
+    }
+
+    /// Records the type of the message being processed.
+    pub fn with_message_type(mut self, message_type: crate::CiphertextMessageType) -> Self {
+        self.message_type = Some(message_type);
+        self
+    }
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(message_type) = self.message_type {
+            write!(f, " of {:?} message", message_type)?;
+        }
+        if let Some(remote_address) = &self.remote_address {
+            write!(f, " for {}", remote_address)?;
+        }
+        Ok(())
+    }
+}
+
+/// A [SignalProtocolError] along with the [ErrorContext] it occurred in.
+///
+/// Produced by the functions in [crate::contextual], or by [ResultExt::with_context] for other
+/// calls. The underlying error is available as
+/// [`source`](std::error::Error::source), and can be recovered with [into_inner][Self::into_inner]
+/// to be handled as usual.
+#[derive(Debug, Error)]
+#[error("{context} failed: {error}")]
+pub struct ContextualError {
+    context: ErrorContext,
+    #[source]
+    error: SignalProtocolError,
+}
+
+impl ContextualError {
+    /// Records that `error` happened during `context`.
+    pub fn new(context: ErrorContext, error: SignalProtocolError) -> Self {
+        Self { context, error }
+    }
+
+    /// What was being done when the error occurred.
+    pub fn context(&self) -> &ErrorContext {
+        &self.context
+    }
+
+    /// The error itself.
+    pub fn error(&self) -> &SignalProtocolError {
+        &self.error
+    }
+
+    /// Discards the context, for handling the error as usual.
+    pub fn into_inner(self) -> SignalProtocolError {
+        self.error
+    }
+}
+
+impl From<ContextualError> for SignalProtocolError {
+    fn from(error: ContextualError) -> Self {
+        error.error
+    }
+}
+
+/// Attaches an [ErrorContext] to the error of a library call.
+pub trait ResultExt<T> {
+    /// Wraps any error in a [ContextualError] recording `context`.
+    fn with_context(self, context: ErrorContext) -> std::result::Result<T, ContextualError>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn with_context(self, context: ErrorContext) -> std::result::Result<T, ContextualError> {
+        self.map_err(|error| ContextualError::new(context, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Display, Error, PartialEq)]
+    /// enclave said no: {0}
+    struct EnclaveError(u32);
+
+    fn attest(code: u32) -> std::result::Result<(), EnclaveError> {
+        Err(EnclaveError(code))
+    }
+
+    #[test]
+    fn test_extension_round_trip() {
+        let error = attest(7).map_extension_err().expect_err("failed");
+        assert_eq!(error.to_string(), "enclave said no: 7");
+        assert_eq!(error.downcast_extension_ref(), Some(&EnclaveError(7)));
+        assert!(std::error::Error::source(&error).is_some());
+
+        assert!(error.downcast_extension_ref::<std::fmt::Error>().is_none());
+        let error = error
+            .downcast_extension::<std::fmt::Error>()
+            .expect_err("wrong type");
+        assert_eq!(
+            error.downcast_extension::<EnclaveError>().ok(),
+            Some(EnclaveError(7))
+        );
+
+        let not_extension = SignalProtocolError::InvalidPreKeyId;
+        assert!(not_extension
+            .downcast_extension_ref::<EnclaveError>()
+            .is_none());
+        assert!(matches!(
+            not_extension.downcast_extension::<EnclaveError>(),
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+
+        let boxed: ExtensionError = Box::new(EnclaveError(8));
+        assert!(matches!(
+            SignalProtocolError::from(boxed),
+            SignalProtocolError::Extension(_)
+        ));
+    }
 }
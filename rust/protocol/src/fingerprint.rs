@@ -7,14 +7,30 @@ use crate::{proto, IdentityKey, Result, SignalProtocolError};
 use prost::Message;
 use sha2::digest::Digest;
 use sha2::Sha512;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Write;
 use subtle::ConstantTimeEq;
 
+mod encodings;
+
+/// An alphabet for rendering a [DisplayableFingerprint].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintEncoding {
+    /// The standard 60 digits, as produced by [Display](fmt::Display).
+    Numeric,
+    /// 32 emoji, 16 for each party.
+    Emoji,
+    /// 24 words from the PGP word list, 12 for each party, separated by spaces.
+    Words,
+}
+
 #[derive(Debug, Clone)]
 pub struct DisplayableFingerprint {
     local: String,
     remote: String,
+    local_fingerprint: Vec<u8>,
+    remote_fingerprint: Vec<u8>,
 }
 
 impl fmt::Display for DisplayableFingerprint {
@@ -56,8 +72,32 @@ impl DisplayableFingerprint {
         Ok(Self {
             local: get_encoded_string(local)?,
             remote: get_encoded_string(remote)?,
+            local_fingerprint: local[..30].to_vec(),
+            remote_fingerprint: remote[..30].to_vec(),
         })
     }
+
+    /// Renders the fingerprint using `encoding`.
+    ///
+    /// As with the numeric form, both parties get the same result, with the two halves in the same
+    /// order as in the numeric form.
+    pub fn encode(&self, encoding: FingerprintEncoding) -> String {
+        let (first, second) = if self.local < self.remote {
+            (&self.local_fingerprint, &self.remote_fingerprint)
+        } else {
+            (&self.remote_fingerprint, &self.local_fingerprint)
+        };
+        match encoding {
+            FingerprintEncoding::Numeric => self.to_string(),
+            FingerprintEncoding::Emoji => encodings::encode_emoji(first)
+                .chain(encodings::encode_emoji(second))
+                .collect(),
+            FingerprintEncoding::Words => encodings::encode_words(first, 0)
+                .chain(encodings::encode_words(second, encodings::WORD_COUNT))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -112,58 +152,143 @@ impl ScannableFingerprint {
     }
 
     pub fn compare(&self, combined: &[u8]) -> Result<bool> {
+        match self.compare_detailed(combined)? {
+            FingerprintComparison::Match => Ok(true),
+            FingerprintComparison::VersionMismatch { theirs, ours } => Err(
+                SignalProtocolError::FingerprintVersionMismatch(theirs, ours),
+            ),
+            FingerprintComparison::IdentifierMismatch
+            | FingerprintComparison::ContentMismatch(_) => Ok(false),
+        }
+    }
+
+    /// Like [compare](Self::compare), but says what didn't match.
+    ///
+    /// `combined` is the serialized fingerprint scanned from the other party's device.
+    pub fn compare_detailed(&self, combined: &[u8]) -> Result<FingerprintComparison> {
         let combined = proto::fingerprint::CombinedFingerprints::decode(combined)
             .map_err(|_| SignalProtocolError::FingerprintParsingError)?;
 
         let their_version = combined.version.unwrap_or(0);
 
         if their_version != self.version {
-            return Err(SignalProtocolError::FingerprintVersionMismatch(
-                their_version,
-                self.version,
-            ));
+            return Ok(FingerprintComparison::VersionMismatch {
+                theirs: their_version,
+                ours: self.version,
+            });
         }
 
-        let same1 = combined
+        let remote_matches: bool = combined
             .local_fingerprint
             .as_ref()
             .ok_or(SignalProtocolError::FingerprintParsingError)?
             .content
             .as_ref()
             .ok_or(SignalProtocolError::FingerprintParsingError)?
-            .ct_eq(&self.remote_fingerprint);
-        let same2 = combined
+            .ct_eq(&self.remote_fingerprint)
+            .into();
+        let local_matches: bool = combined
             .remote_fingerprint
             .as_ref()
             .ok_or(SignalProtocolError::FingerprintParsingError)?
             .content
             .as_ref()
             .ok_or(SignalProtocolError::FingerprintParsingError)?
-            .ct_eq(&self.local_fingerprint);
-
-        Ok(same1.into() && same2.into())
+            .ct_eq(&self.local_fingerprint)
+            .into();
+
+        Ok(match (local_matches, remote_matches) {
+            (true, true) => FingerprintComparison::Match,
+            (false, false) => FingerprintComparison::IdentifierMismatch,
+            (false, true) => FingerprintComparison::ContentMismatch(FingerprintParty::Local),
+            (true, false) => FingerprintComparison::ContentMismatch(FingerprintParty::Remote),
+        })
     }
 }
 
+/// One side of a fingerprint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintParty {
+    /// The user doing the comparison.
+    Local,
+    /// The user whose fingerprint was scanned.
+    Remote,
+}
+
+/// The outcome of [ScannableFingerprint::compare_detailed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintComparison {
+    /// Both halves match.
+    Match,
+    /// The two fingerprints were generated with different versions, so can't be compared; one
+    /// of the two apps needs to be updated.
+    VersionMismatch { theirs: u32, ours: u32 },
+    /// Neither half matches, so the scanned fingerprint isn't for this pair of users. Most likely
+    /// the code for a different conversation was scanned.
+    IdentifierMismatch,
+    /// One half doesn't match: the two devices disagree about the given party's identity key
+    /// (or, less likely, their identifier). For [FingerprintParty::Remote], the other user's key
+    /// has changed since it was last verified, or someone is intercepting messages; for
+    /// [FingerprintParty::Local], the other user has an out-of-date key for us.
+    ContentMismatch(FingerprintParty),
+}
+
 #[derive(Debug, Clone)]
 pub struct Fingerprint {
     pub display: DisplayableFingerprint,
     pub scannable: ScannableFingerprint,
 }
 
-impl Fingerprint {
-    fn get_fingerprint(
-        iterations: u32,
-        local_id: &[u8],
-        local_key: &IdentityKey,
-    ) -> Result<Vec<u8>> {
-        if iterations <= 1 || iterations > 1000000 {
-            return Err(SignalProtocolError::InvalidArgument(format!(
-                "Invalid fingerprint iterations {}",
-                iterations
-            )));
+/// Vetted iteration counts for [Fingerprint::new] and [FingerprintGenerator::new].
+///
+/// More iterations make it harder to find a different identity key with a matching fingerprint,
+/// at the cost of more time to compute one. Both parties must use the same count, or their
+/// fingerprints won't match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintIterations {
+    /// 1024 iterations, for tests and other places where fingerprints aren't shown to users.
+    Fast,
+    /// 5200 iterations, as used by the Signal apps.
+    Standard,
+    /// 20,000 iterations.
+    Paranoid,
+}
+
+impl FingerprintIterations {
+    pub const fn iterations(self) -> u32 {
+        match self {
+            Self::Fast => 1024,
+            Self::Standard => 5200,
+            Self::Paranoid => 20_000,
         }
+    }
+}
 
+impl From<FingerprintIterations> for u32 {
+    fn from(preset: FingerprintIterations) -> Self {
+        preset.iterations()
+    }
+}
+
+fn check_iterations(iterations: u32) -> Result<u32> {
+    if iterations <= 1 || iterations > 1000000 {
+        return Err(SignalProtocolError::InvalidArgument(format!(
+            "Invalid fingerprint iterations {}",
+            iterations
+        )));
+    }
+    Ok(iterations)
+}
+
+/// One party's half of a fingerprint, part way through its iterated hash.
+#[derive(Clone)]
+struct IteratedHash {
+    key_bytes: Box<[u8]>,
+    buf: Vec<u8>,
+}
+
+impl IteratedHash {
+    fn new(local_id: &[u8], local_key: &IdentityKey) -> Self {
         let fingerprint_version = [0u8, 0u8]; // 0x0000
         let key_bytes = local_key.serialize();
 
@@ -175,19 +300,38 @@ impl Fingerprint {
         sha512.update(&key_bytes);
         sha512.update(local_id);
         sha512.update(&key_bytes);
-        let mut buf = sha512.finalize();
+        let buf = sha512.finalize().to_vec();
 
-        for _i in 1..iterations {
+        Self { key_bytes, buf }
+    }
+
+    fn iterate(&mut self, count: u32) {
+        for _i in 0..count {
             let mut sha512 = Sha512::new();
             // Explicitly pass a slice to avoid generating multiple versions of update().
-            sha512.update(&buf[..]);
-            sha512.update(&key_bytes);
-            buf = sha512.finalize();
+            sha512.update(&self.buf[..]);
+            sha512.update(&self.key_bytes);
+            self.buf = sha512.finalize().to_vec();
         }
-
-        Ok(buf.to_vec())
     }
+}
+
+/// Computes a [Fingerprint] a few iterations at a time.
+///
+/// Computing a fingerprint with [FingerprintIterations::Standard] can take long enough on a slow
+/// device to be noticeable on a UI thread. This splits up the work: call
+/// [advance](Self::advance) as time allows (or [generate_async](Self::generate_async) to have it
+/// done between other tasks), then [finish](Self::finish).
+#[derive(Clone)]
+pub struct FingerprintGenerator {
+    version: u32,
+    remaining_iterations: u32,
+    local: IteratedHash,
+    remote: IteratedHash,
+}
 
+impl FingerprintGenerator {
+    /// Takes the same arguments as [Fingerprint::new].
     pub fn new(
         version: u32,
         iterations: u32,
@@ -195,21 +339,223 @@ impl Fingerprint {
         local_key: &IdentityKey,
         remote_id: &[u8],
         remote_key: &IdentityKey,
-    ) -> Result<Fingerprint> {
-        let local_fingerprint = Fingerprint::get_fingerprint(iterations, local_id, local_key)?;
-        let remote_fingerprint = Fingerprint::get_fingerprint(iterations, remote_id, remote_key)?;
+    ) -> Result<Self> {
+        let iterations = check_iterations(iterations)?;
+        Ok(Self {
+            version,
+            remaining_iterations: iterations - 1,
+            local: IteratedHash::new(local_id, local_key),
+            remote: IteratedHash::new(remote_id, remote_key),
+        })
+    }
+
+    /// The number of iterations left for each party.
+    pub fn remaining_iterations(&self) -> u32 {
+        self.remaining_iterations
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining_iterations == 0
+    }
 
+    /// Does up to `iterations` more iterations for each party.
+    pub fn advance(&mut self, iterations: u32) {
+        let count = iterations.min(self.remaining_iterations);
+        self.local.iterate(count);
+        self.remote.iterate(count);
+        self.remaining_iterations -= count;
+    }
+
+    /// Does any remaining iterations and produces the fingerprint.
+    pub fn finish(mut self) -> Result<Fingerprint> {
+        self.advance(self.remaining_iterations);
         Ok(Fingerprint {
-            display: DisplayableFingerprint::new(&local_fingerprint, &remote_fingerprint)?,
-            scannable: ScannableFingerprint::new(version, &local_fingerprint, &remote_fingerprint),
+            display: DisplayableFingerprint::new(&self.local.buf, &self.remote.buf)?,
+            scannable: ScannableFingerprint::new(self.version, &self.local.buf, &self.remote.buf),
         })
     }
 
+    /// Computes the fingerprint `iterations_per_step` iterations at a time, yielding to the
+    /// executor in between.
+    pub async fn generate_async(mut self, iterations_per_step: u32) -> Result<Fingerprint> {
+        let iterations_per_step = iterations_per_step.max(1);
+        while !self.is_complete() {
+            self.advance(iterations_per_step);
+            YieldNow(false).await;
+        }
+        self.finish()
+    }
+}
+
+/// Returns `Pending` once, so that other tasks get a chance to run.
+struct YieldNow(bool);
+
+impl std::future::Future for YieldNow {
+    type Output = ();
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        if self.0 {
+            return std::task::Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    }
+}
+
+impl Fingerprint {
+    fn get_fingerprint(
+        iterations: u32,
+        local_id: &[u8],
+        local_key: &IdentityKey,
+    ) -> Result<Vec<u8>> {
+        let iterations = check_iterations(iterations)?;
+        let mut hash = IteratedHash::new(local_id, local_key);
+        hash.iterate(iterations - 1);
+        Ok(hash.buf)
+    }
+
+    /// `iterations` is usually one of the [FingerprintIterations] presets.
+    pub fn new(
+        version: u32,
+        iterations: u32,
+        local_id: &[u8],
+        local_key: &IdentityKey,
+        remote_id: &[u8],
+        remote_key: &IdentityKey,
+    ) -> Result<Fingerprint> {
+        FingerprintGenerator::new(
+            version, iterations, local_id, local_key, remote_id, remote_key,
+        )?
+        .finish()
+    }
+
     pub fn display_string(&self) -> Result<String> {
         Ok(format!("{}", self.display))
     }
 }
 
+/// A safety number covering every member of a group at once.
+///
+/// Each member's identity key is run through the same iterated hash as a one-to-one
+/// [`Fingerprint`], using the group ID as the stable identifier. Those per-member values are then
+/// hashed together in the order of the members' serialized identity keys, so every member computes
+/// the same result regardless of the order they learned about each other.
+///
+/// The per-member hashes are kept around, so [`add_member`](Self::add_member) and
+/// [`remove_member`](Self::remove_member) only pay for the iterated hash of the member that
+/// changed.
+#[derive(Debug, Clone)]
+pub struct GroupFingerprint {
+    version: u32,
+    iterations: u32,
+    group_id: Vec<u8>,
+    members: BTreeMap<Vec<u8>, Vec<u8>>,
+    fingerprint: Vec<u8>,
+}
+
+impl GroupFingerprint {
+    /// Duplicate members are only counted once.
+    pub fn new<'a>(
+        version: u32,
+        iterations: u32,
+        group_id: &[u8],
+        members: impl IntoIterator<Item = &'a IdentityKey>,
+    ) -> Result<Self> {
+        let mut result = Self {
+            version,
+            iterations,
+            group_id: group_id.to_vec(),
+            members: BTreeMap::new(),
+            fingerprint: Vec::new(),
+        };
+        for member in members {
+            result.insert_member(member)?;
+        }
+        result.recompute();
+        Ok(result)
+    }
+
+    /// Adds `member` to the group, returning `false` if it was already present.
+    pub fn add_member(&mut self, member: &IdentityKey) -> Result<bool> {
+        let added = self.insert_member(member)?;
+        if added {
+            self.recompute();
+        }
+        Ok(added)
+    }
+
+    /// Removes `member` from the group, returning `false` if it was not present.
+    pub fn remove_member(&mut self, member: &IdentityKey) -> bool {
+        let removed = self.members.remove(&member.serialize()[..]).is_some();
+        if removed {
+            self.recompute();
+        }
+        removed
+    }
+
+    pub fn contains_member(&self, member: &IdentityKey) -> bool {
+        self.members.contains_key(&member.serialize()[..])
+    }
+
+    pub fn member_count(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+
+    /// Formats the fingerprint as 60 decimal digits, like [`Fingerprint::display_string`].
+    pub fn display_string(&self) -> Result<String> {
+        Ok(get_encoded_string(&self.fingerprint[..30])?
+            + &get_encoded_string(&self.fingerprint[30..60])?)
+    }
+
+    /// Compares against the [`fingerprint`](Self::fingerprint) computed by another member, in
+    /// constant time.
+    pub fn compare(&self, their_fingerprint: &[u8]) -> bool {
+        self.fingerprint.ct_eq(their_fingerprint).into()
+    }
+
+    fn insert_member(&mut self, member: &IdentityKey) -> Result<bool> {
+        let key_bytes = member.serialize().into_vec();
+        if self.members.contains_key(&key_bytes) {
+            return Ok(false);
+        }
+        let member_fingerprint =
+            Fingerprint::get_fingerprint(self.iterations, &self.group_id, member)?;
+        self.members.insert(key_bytes, member_fingerprint);
+        Ok(true)
+    }
+
+    fn recompute(&mut self) {
+        let mut sha512 = Sha512::new();
+        sha512.update(GROUP_FINGERPRINT_LABEL);
+        sha512.update(self.version.to_be_bytes());
+        sha512.update((self.group_id.len() as u64).to_be_bytes());
+        sha512.update(&self.group_id);
+        sha512.update((self.members.len() as u64).to_be_bytes());
+        for member_fingerprint in self.members.values() {
+            sha512.update(member_fingerprint);
+        }
+        self.fingerprint = sha512.finalize().to_vec();
+    }
+}
+
+const GROUP_FINGERPRINT_LABEL: &[u8] = b"Signal_GroupFingerprint";
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -244,6 +590,95 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn fingerprint_alternate_encodings() -> Result<()> {
+        let l = vec![0x00; 30];
+        let r = vec![0xFF; 30];
+        let ours = DisplayableFingerprint::new(&l, &r)?;
+        let theirs = DisplayableFingerprint::new(&r, &l)?;
+
+        for encoding in [
+            FingerprintEncoding::Numeric,
+            FingerprintEncoding::Emoji,
+            FingerprintEncoding::Words,
+        ] {
+            assert_eq!(ours.encode(encoding), theirs.encode(encoding));
+        }
+        assert_eq!(
+            ours.encode(FingerprintEncoding::Numeric),
+            format!("{}", ours)
+        );
+        assert_eq!(
+            ours.encode(FingerprintEncoding::Emoji),
+            "🐶".repeat(16) + &"⭐".repeat(16)
+        );
+        assert_eq!(
+            ours.encode(FingerprintEncoding::Words),
+            ["aardvark adroitness"; 6]
+                .iter()
+                .chain(&["Zulu Yucatan"; 6])
+                .copied()
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        // Here the remote half comes first.
+        let mixed = DisplayableFingerprint::new(&[0x12, 0x34, 0x56].repeat(10), &r)?;
+        assert_eq!(
+            mixed.encode(FingerprintEncoding::Emoji),
+            "⭐".repeat(16) + &"🐰🥕🐦🐢".repeat(4)
+        );
+        assert!(mixed.encode(FingerprintEncoding::Words).ends_with(
+            "atlas confidence egghead backwater choking escapade \
+             atlas confidence egghead backwater choking escapade"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprint_generated_in_steps() -> Result<()> {
+        use futures_util::task::noop_waker_ref;
+        use std::future::Future;
+        use std::task::{Context, Poll};
+
+        let a_key = IdentityKey::decode(&hex::decode(ALICE_IDENTITY).expect("valid hex"))?;
+        let b_key = IdentityKey::decode(&hex::decode(BOB_IDENTITY).expect("valid hex"))?;
+        let generator = FingerprintGenerator::new(
+            2,
+            FingerprintIterations::Standard.into(),
+            ALICE_STABLE_ID.as_bytes(),
+            &a_key,
+            BOB_STABLE_ID.as_bytes(),
+            &b_key,
+        )?;
+        assert_eq!(generator.remaining_iterations(), 5199);
+
+        let mut stepped = generator.clone();
+        stepped.advance(5000);
+        assert!(!stepped.is_complete());
+        stepped.advance(5000);
+        assert!(stepped.is_complete());
+        assert_eq!(
+            hex::encode(stepped.finish()?.scannable.serialize()?),
+            ALICE_SCANNABLE_FINGERPRINT_V2
+        );
+
+        let mut future = Box::pin(generator.generate_async(1000));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut pending_count = 0;
+        let fprint = loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(result) => break result?,
+                Poll::Pending => pending_count += 1,
+            }
+        };
+        assert_eq!(pending_count, 6);
+        assert_eq!(format!("{}", fprint.display), DISPLAYABLE_FINGERPRINT_V1);
+
+        Ok(())
+    }
+
     #[test]
     fn fingerprint_test_v1() -> Result<()> {
         // testVectorsVersion1 in Java
@@ -395,6 +830,12 @@ mod test {
         assert!(b_fprint
             .scannable
             .compare(&a_fprint.scannable.serialize()?)?);
+        assert_eq!(
+            a_fprint
+                .scannable
+                .compare_detailed(&b_fprint.scannable.serialize()?)?,
+            FingerprintComparison::Match
+        );
 
         // Java is missing this test
         assert!(!a_fprint
@@ -453,6 +894,20 @@ mod test {
             .scannable
             .compare(&a_fprint.scannable.serialize()?)?);
 
+        // Alice has the wrong key for Bob.
+        assert_eq!(
+            a_fprint
+                .scannable
+                .compare_detailed(&b_fprint.scannable.serialize()?)?,
+            FingerprintComparison::ContentMismatch(FingerprintParty::Remote)
+        );
+        assert_eq!(
+            b_fprint
+                .scannable
+                .compare_detailed(&a_fprint.scannable.serialize()?)?,
+            FingerprintComparison::ContentMismatch(FingerprintParty::Local)
+        );
+
         Ok(())
     }
 
@@ -500,6 +955,30 @@ mod test {
             .scannable
             .compare(&a_fprint.scannable.serialize()?)?);
 
+        assert_eq!(
+            a_fprint
+                .scannable
+                .compare_detailed(&b_fprint.scannable.serialize()?)?,
+            FingerprintComparison::ContentMismatch(FingerprintParty::Local)
+        );
+
+        // A fingerprint for a different conversation entirely.
+        let c_key = IdentityKeyPair::generate(&mut OsRng);
+        let c_fprint = Fingerprint::new(
+            version,
+            iterations,
+            "+14154444444".as_bytes(),
+            c_key.identity_key(),
+            ALICE_STABLE_ID.as_bytes(),
+            a_key,
+        )?;
+        assert_eq!(
+            b_fprint
+                .scannable
+                .compare_detailed(&c_fprint.scannable.serialize()?)?,
+            FingerprintComparison::IdentifierMismatch
+        );
+
         Ok(())
     }
 
@@ -540,6 +1019,78 @@ mod test {
             hex::encode(a_fprint_v2.scannable.serialize()?)
         );
 
+        assert_eq!(
+            a_fprint_v1
+                .scannable
+                .compare_detailed(&a_fprint_v2.scannable.serialize()?)?,
+            FingerprintComparison::VersionMismatch { theirs: 2, ours: 1 }
+        );
+        assert!(matches!(
+            a_fprint_v1
+                .scannable
+                .compare(&a_fprint_v2.scannable.serialize()?),
+            Err(SignalProtocolError::FingerprintVersionMismatch(2, 1))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_fingerprint_matches_across_members() -> Result<()> {
+        use crate::IdentityKeyPair;
+        use rand::rngs::OsRng;
+
+        let keys: Vec<IdentityKey> = (0..4)
+            .map(|_| *IdentityKeyPair::generate(&mut OsRng).identity_key())
+            .collect();
+        let group_id = b"group id";
+        let iterations = 1024;
+
+        let forward = GroupFingerprint::new(2, iterations, group_id, &keys)?;
+        let backward = GroupFingerprint::new(2, iterations, group_id, keys.iter().rev())?;
+        assert_eq!(forward.fingerprint(), backward.fingerprint());
+        assert!(forward.compare(backward.fingerprint()));
+        assert_eq!(forward.display_string()?, backward.display_string()?);
+        assert_eq!(forward.display_string()?.len(), 60);
+        assert_eq!(forward.member_count(), 4);
+
+        let other_group = GroupFingerprint::new(2, iterations, b"other group", &keys)?;
+        assert!(!forward.compare(other_group.fingerprint()));
+
+        let other_version = GroupFingerprint::new(1, iterations, group_id, &keys)?;
+        assert!(!forward.compare(other_version.fingerprint()));
+
+        let fewer_members = GroupFingerprint::new(2, iterations, group_id, &keys[1..])?;
+        assert!(!forward.compare(fewer_members.fingerprint()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_fingerprint_incremental_update() -> Result<()> {
+        use crate::IdentityKeyPair;
+        use rand::rngs::OsRng;
+
+        let keys: Vec<IdentityKey> = (0..3)
+            .map(|_| *IdentityKeyPair::generate(&mut OsRng).identity_key())
+            .collect();
+        let group_id = b"group id";
+        let iterations = 1024;
+
+        let mut incremental = GroupFingerprint::new(2, iterations, group_id, &keys[..2])?;
+        assert!(incremental.add_member(&keys[2])?);
+        assert!(!incremental.add_member(&keys[2])?);
+        let full = GroupFingerprint::new(2, iterations, group_id, &keys)?;
+        assert!(incremental.compare(full.fingerprint()));
+
+        assert!(incremental.remove_member(&keys[0]));
+        assert!(!incremental.remove_member(&keys[0]));
+        assert!(!incremental.contains_member(&keys[0]));
+        let remaining = GroupFingerprint::new(2, iterations, group_id, &keys[1..])?;
+        assert!(incremental.compare(remaining.fingerprint()));
+
+        assert!(GroupFingerprint::new(2, 1, group_id, &keys).is_err());
+
         Ok(())
     }
 }
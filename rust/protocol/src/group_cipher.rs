@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
 use rand::{CryptoRng, Rng};
@@ -11,9 +12,11 @@ use uuid::Uuid;
 use crate::protocol::SENDERKEY_MESSAGE_CURRENT_VERSION;
 use crate::sender_keys::{SenderKeyState, SenderMessageKey};
 use crate::{
-    consts, CiphertextMessageType, Context, KeyPair, ProtocolAddress, Result,
-    SenderKeyDistributionMessage, SenderKeyMessage, SenderKeyRecord, SenderKeyStore,
-    SignalProtocolError,
+    consts, observer, sealed_sender_encrypt_from_usmc, sealed_sender_multi_recipient_encrypt,
+    CiphertextMessageType, ContentHint, Context, IdentityKeyStore, KeyPair, ProtocolAddress,
+    ReplayCache, ReplayKey, Result, SenderCertificate, SenderKeyDistributionMessage,
+    SenderKeyMessage, SenderKeyName, SenderKeyRecord, SenderKeyStore, ServiceId, SessionRecord,
+    SessionStore, SignalProtocolError, UnidentifiedSenderMessageContent,
 };
 
 pub async fn group_encrypt<R: Rng + CryptoRng>(
@@ -24,8 +27,82 @@ pub async fn group_encrypt<R: Rng + CryptoRng>(
     csprng: &mut R,
     ctx: Context,
 ) -> Result<SenderKeyMessage> {
+    let mut messages = group_encrypt_batch(
+        sender_key_store,
+        sender,
+        distribution_id,
+        &[plaintext],
+        csprng,
+        ctx,
+    )
+    .await?;
+    Ok(messages.pop().expect("one message per plaintext"))
+}
+
+/// Like [group_encrypt], but binds `associated_data` into the message's signature.
+///
+/// The data is not sent. Recipients must pass the same bytes to
+/// [group_decrypt_with_associated_data], or the signature check fails. This lets a sender tie the
+/// message to envelope details such as its timestamp, so a server can't replay it under another
+/// envelope.
+pub async fn group_encrypt_with_associated_data<R: Rng + CryptoRng>(
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintext: &[u8],
+    associated_data: &[u8],
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SenderKeyMessage> {
+    let mut messages = group_encrypt_batch_impl(
+        sender_key_store,
+        sender,
+        distribution_id,
+        &[plaintext],
+        associated_data,
+        csprng,
+        ctx,
+    )
+    .await?;
+    Ok(messages.pop().expect("one message per plaintext"))
+}
+
+/// Encrypt each of `plaintexts` in turn, as if by calling [group_encrypt] once for each.
+///
+/// The sender key record is loaded and stored only once, which matters when sending a burst of
+/// messages to a group. If any message fails to encrypt, the record is left untouched.
+pub async fn group_encrypt_batch<R: Rng + CryptoRng>(
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintexts: &[&[u8]],
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<SenderKeyMessage>> {
+    group_encrypt_batch_impl(
+        sender_key_store,
+        sender,
+        distribution_id,
+        plaintexts,
+        &[],
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+async fn group_encrypt_batch_impl<R: Rng + CryptoRng>(
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    plaintexts: &[&[u8]],
+    associated_data: &[u8],
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<SenderKeyMessage>> {
+    let sender_key_name = SenderKeyName::new(sender.clone(), distribution_id);
     let mut record = sender_key_store
-        .load_sender_key(sender, distribution_id, ctx)
+        .load_sender_key(&sender_key_name, ctx)
         .await?
         .ok_or(SignalProtocolError::NoSenderKeyState { distribution_id })?;
 
@@ -33,48 +110,151 @@ pub async fn group_encrypt<R: Rng + CryptoRng>(
         .sender_key_state_mut()
         .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
 
-    let sender_chain_key = sender_key_state
+    let signing_key = sender_key_state
+        .signing_key_private()
+        .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
+
+    let mut sender_chain_key = sender_key_state
         .sender_chain_key()
         .ok_or(SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
 
-    let message_keys = sender_chain_key.sender_message_key();
+    let mut messages = Vec::with_capacity(plaintexts.len());
+    for plaintext in plaintexts {
+        let message_keys = sender_chain_key.sender_message_key();
 
-    let ciphertext =
-        signal_crypto::aes_256_cbc_encrypt(plaintext, message_keys.cipher_key(), message_keys.iv())
-            .map_err(|_| {
-                log::error!(
-                    "outgoing sender key state corrupt for distribution ID {}",
-                    distribution_id,
-                );
-                SignalProtocolError::InvalidSenderKeySession { distribution_id }
-            })?;
+        let ciphertext = signal_crypto::aes_256_cbc_encrypt(
+            plaintext,
+            message_keys.cipher_key(),
+            message_keys.iv(),
+        )
+        .map_err(|_| {
+            log::error!(
+                "outgoing sender key state corrupt for distribution ID {}",
+                distribution_id,
+            );
+            SignalProtocolError::InvalidSenderKeySession { distribution_id }
+        })?;
 
-    let signing_key = sender_key_state
-        .signing_key_private()
-        .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
+        messages.push(SenderKeyMessage::new_with_associated_data(
+            sender_key_state.message_version() as u8,
+            distribution_id,
+            sender_key_state.chain_id(),
+            message_keys.iteration(),
+            ciphertext.into_boxed_slice(),
+            associated_data,
+            csprng,
+            &signing_key,
+        )?);
+
+        sender_chain_key = sender_chain_key.next();
+    }
+
+    sender_key_state.set_sender_chain_key(sender_chain_key);
+
+    sender_key_store
+        .store_sender_key(&sender_key_name, &record, ctx)
+        .await?;
+    observer::notify(|o| o.sender_key_updated(&sender_key_name));
 
-    let skm = SenderKeyMessage::new(
-        sender_key_state.message_version() as u8,
+    Ok(messages)
+}
+
+/// The ready-to-send output of [group_encrypt_sealed].
+#[derive(Debug, Clone)]
+pub enum SealedGroupMessage {
+    /// A single Sealed Sender v2 message, to be submitted once for all recipients.
+    MultiRecipient(Vec<u8>),
+    /// A Sealed Sender v1 envelope for each recipient device.
+    PerRecipient(HashMap<ProtocolAddress, Vec<u8>>),
+}
+
+/// Encrypt `plaintext` with the sender key for `distribution_id`, then seal it for each of
+/// `recipients`.
+///
+/// Sealed Sender v2 is used when every recipient is addressed by [ServiceId] and has a current
+/// session in `session_store`, since v2 needs each recipient's registration ID. Otherwise each
+/// recipient gets its own Sealed Sender v1 envelope. Either way, recipients must already have
+/// processed a [SenderKeyDistributionMessage] for this distribution, and their identity keys must
+/// be in `identity_store`.
+#[allow(clippy::too_many_arguments)]
+pub async fn group_encrypt_sealed<R: Rng + CryptoRng>(
+    recipients: &[&ProtocolAddress],
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_cert: &SenderCertificate,
+    plaintext: &[u8],
+    content_hint: ContentHint,
+    group_id: Option<Vec<u8>>,
+    sender_key_store: &mut dyn SenderKeyStore,
+    session_store: &dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SealedGroupMessage> {
+    let skm = group_encrypt(
+        sender_key_store,
+        sender,
         distribution_id,
-        sender_key_state.chain_id(),
-        message_keys.iteration(),
-        ciphertext.into_boxed_slice(),
+        plaintext,
         csprng,
-        &signing_key,
+        ctx,
+    )
+    .await?;
+    let usmc = UnidentifiedSenderMessageContent::new(
+        CiphertextMessageType::SenderKey,
+        sender_cert.clone(),
+        skm.serialized().to_vec(),
+        content_hint,
+        group_id,
     )?;
 
-    sender_key_state.set_sender_chain_key(sender_chain_key.next());
-
-    sender_key_store
-        .store_sender_key(sender, distribution_id, &record, ctx)
+    if let Some(sessions) = sessions_for_multi_recipient(recipients, session_store, ctx).await? {
+        let sessions: Vec<&SessionRecord> = sessions.iter().collect();
+        let message = sealed_sender_multi_recipient_encrypt(
+            recipients,
+            &sessions,
+            &usmc,
+            identity_store,
+            ctx,
+            csprng,
+        )
         .await?;
+        return Ok(SealedGroupMessage::MultiRecipient(message));
+    }
 
-    Ok(skm)
+    let mut envelopes = HashMap::with_capacity(recipients.len());
+    for &recipient in recipients {
+        let envelope =
+            sealed_sender_encrypt_from_usmc(recipient, &usmc, identity_store, ctx, csprng).await?;
+        envelopes.insert(recipient.clone(), envelope);
+    }
+    Ok(SealedGroupMessage::PerRecipient(envelopes))
+}
+
+/// Loads the sessions needed to use Sealed Sender v2 for `recipients`, or returns `None` if any
+/// recipient can't be sent to that way.
+async fn sessions_for_multi_recipient(
+    recipients: &[&ProtocolAddress],
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<Option<Vec<SessionRecord>>> {
+    let mut sessions = Vec::with_capacity(recipients.len());
+    for &recipient in recipients {
+        if ServiceId::parse_from_service_id_string(recipient.name()).is_none() {
+            return Ok(None);
+        }
+        match session_store.load_session(recipient, ctx).await? {
+            Some(session) if session.remote_registration_id().is_ok() => sessions.push(session),
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(sessions))
 }
 
 fn get_sender_key(
     state: &mut SenderKeyState,
     iteration: u32,
+    sender: &ProtocolAddress,
     distribution_id: Uuid,
 ) -> Result<SenderMessageKey> {
     let sender_chain_key = state
@@ -91,6 +271,7 @@ fn get_sender_key(
                 distribution_id,
                 iteration
             );
+            observer::notify(|o| o.duplicate_message(sender, current_iteration, iteration));
             return Err(SignalProtocolError::DuplicatedMessage(
                 current_iteration,
                 iteration,
@@ -128,14 +309,70 @@ pub async fn group_decrypt(
     sender_key_store: &mut dyn SenderKeyStore,
     sender: &ProtocolAddress,
     ctx: Context,
+) -> Result<Vec<u8>> {
+    group_decrypt_with_options(skm_bytes, &[], sender_key_store, None, sender, ctx).await
+}
+
+/// Like [`group_decrypt`], for a message encrypted with [group_encrypt_with_associated_data].
+///
+/// `associated_data` must match what the sender passed, or this fails with
+/// [`SignalProtocolError::SignatureValidationFailed`].
+pub async fn group_decrypt_with_associated_data(
+    skm_bytes: &[u8],
+    associated_data: &[u8],
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender: &ProtocolAddress,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    group_decrypt_with_options(
+        skm_bytes,
+        associated_data,
+        sender_key_store,
+        None,
+        sender,
+        ctx,
+    )
+    .await
+}
+
+/// Like [`group_decrypt`], but rejects messages found in `replay_cache` with
+/// [`SignalProtocolError::DuplicatedMessage`], and adds the message to it once decrypted.
+///
+/// The sender key is left unchanged when a replay is rejected.
+pub async fn group_decrypt_with_replay_cache(
+    skm_bytes: &[u8],
+    sender_key_store: &mut dyn SenderKeyStore,
+    replay_cache: &mut dyn ReplayCache,
+    sender: &ProtocolAddress,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    group_decrypt_with_options(
+        skm_bytes,
+        &[],
+        sender_key_store,
+        Some(replay_cache),
+        sender,
+        ctx,
+    )
+    .await
+}
+
+async fn group_decrypt_with_options(
+    skm_bytes: &[u8],
+    associated_data: &[u8],
+    sender_key_store: &mut dyn SenderKeyStore,
+    replay_cache: Option<&mut dyn ReplayCache>,
+    sender: &ProtocolAddress,
+    ctx: Context,
 ) -> Result<Vec<u8>> {
     let skm = SenderKeyMessage::try_from(skm_bytes)?;
 
     let distribution_id = skm.distribution_id();
     let chain_id = skm.chain_id();
 
+    let sender_key_name = SenderKeyName::new(sender.clone(), distribution_id);
     let mut record = sender_key_store
-        .load_sender_key(sender, skm.distribution_id(), ctx)
+        .load_sender_key(&sender_key_name, ctx)
         .await?
         .ok_or(SignalProtocolError::NoSenderKeyState { distribution_id })?;
 
@@ -162,11 +399,33 @@ pub async fn group_decrypt(
     let signing_key = sender_key_state
         .signing_key_public()
         .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
-    if !skm.verify_signature(&signing_key)? {
+    if !skm.verify_signature_with_associated_data(&signing_key, associated_data)? {
         return Err(SignalProtocolError::SignatureValidationFailed);
     }
 
-    let sender_key = get_sender_key(sender_key_state, skm.iteration(), distribution_id)?;
+    let replay_key =
+        ReplayKey::for_sender_key_message(sender, distribution_id, chain_id, skm.iteration());
+    if let Some(replay_cache) = &replay_cache {
+        if replay_cache.contains(&replay_key, ctx).await? {
+            log::warn!(
+                "rejecting replayed message from {} for distribution ID {}, chain ID {}, iteration {}",
+                sender,
+                distribution_id,
+                chain_id,
+                skm.iteration(),
+            );
+            let current_iteration = sender_key_state
+                .sender_chain_key()
+                .map_or(skm.iteration(), |chain_key| chain_key.iteration());
+            observer::notify(|o| o.duplicate_message(sender, current_iteration, skm.iteration()));
+            return Err(SignalProtocolError::DuplicatedMessage(
+                current_iteration,
+                skm.iteration(),
+            ));
+        }
+    }
+
+    let sender_key = get_sender_key(sender_key_state, skm.iteration(), sender, distribution_id)?;
 
     let plaintext = match signal_crypto::aes_256_cbc_decrypt(
         skm.ciphertext(),
@@ -193,8 +452,12 @@ pub async fn group_decrypt(
     };
 
     sender_key_store
-        .store_sender_key(sender, distribution_id, &record, ctx)
+        .store_sender_key(&sender_key_name, &record, ctx)
         .await?;
+    observer::notify(|o| o.sender_key_updated(&sender_key_name));
+    if let Some(replay_cache) = replay_cache {
+        replay_cache.insert(&replay_key, ctx).await?;
+    }
 
     Ok(plaintext)
 }
@@ -213,10 +476,14 @@ pub async fn process_sender_key_distribution_message(
         skdm.chain_id()?
     );
 
+    let sender_key_name = SenderKeyName::new(sender.clone(), distribution_id);
     let mut sender_key_record = sender_key_store
-        .load_sender_key(sender, distribution_id, ctx)
+        .load_sender_key(&sender_key_name, ctx)
         .await?
         .unwrap_or_else(SenderKeyRecord::new_empty);
+    let is_new_chain = sender_key_record
+        .sender_key_state_for_chain_id(skdm.chain_id()?)
+        .is_none();
 
     sender_key_record.add_sender_key_state(
         skdm.message_version(),
@@ -227,11 +494,36 @@ pub async fn process_sender_key_distribution_message(
         None,
     );
     sender_key_store
-        .store_sender_key(sender, distribution_id, &sender_key_record, ctx)
+        .store_sender_key(&sender_key_name, &sender_key_record, ctx)
         .await?;
+    observer::notify(|o| o.sender_key_updated(&sender_key_name));
+    if is_new_chain {
+        observer::notify(|o| o.sender_key_rotated(sender, distribution_id));
+    }
     Ok(())
 }
 
+/// Like [process_sender_key_distribution_message], but only if `skdm` carries an identity
+/// signature from the account `certificate` names.
+///
+/// The sender key is recorded under the address in `certificate`. `certificate` should already
+/// have been validated, as [sealed_sender_decrypt](crate::sealed_sender_decrypt) does.
+pub async fn process_attributed_sender_key_distribution_message(
+    certificate: &SenderCertificate,
+    skdm: &SenderKeyDistributionMessage,
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    if !skdm.verify_identity_signature(&certificate.key()?.into())? {
+        return Err(SignalProtocolError::SignatureValidationFailed);
+    }
+    let sender = ProtocolAddress::new(
+        certificate.sender_uuid()?.to_owned(),
+        certificate.sender_device_id()?,
+    );
+    process_sender_key_distribution_message(&sender, skdm, sender_key_store, ctx).await
+}
+
 pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng>(
     sender: &ProtocolAddress,
     distribution_id: Uuid,
@@ -239,40 +531,60 @@ pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng>(
     csprng: &mut R,
     ctx: Context,
 ) -> Result<SenderKeyDistributionMessage> {
+    let sender_key_name = SenderKeyName::new(sender.clone(), distribution_id);
     let sender_key_record = sender_key_store
-        .load_sender_key(sender, distribution_id, ctx)
+        .load_sender_key(&sender_key_name, ctx)
         .await?;
 
     let sender_key_record = match sender_key_record {
         Some(record) => record,
-        None => {
-            // libsignal-protocol-java uses 31-bit integers for sender key chain IDs
-            let chain_id = (csprng.gen::<u32>()) >> 1;
-            log::info!(
-                "Creating SenderKey for distribution {} with chain ID {}",
-                distribution_id,
-                chain_id
-            );
-
-            let iteration = 0;
-            let sender_key: [u8; 32] = csprng.gen();
-            let signing_key = KeyPair::generate(csprng);
-            let mut record = SenderKeyRecord::new_empty();
-            record.add_sender_key_state(
-                SENDERKEY_MESSAGE_CURRENT_VERSION,
-                chain_id,
-                iteration,
-                &sender_key,
-                signing_key.public_key,
-                Some(signing_key.private_key),
-            );
-            sender_key_store
-                .store_sender_key(sender, distribution_id, &record, ctx)
-                .await?;
-            record
-        }
+        None => create_sender_key(sender, distribution_id, sender_key_store, csprng, ctx).await?,
     };
 
+    distribution_message_for_record(&sender_key_record, distribution_id)
+}
+
+/// Generates a new sender key for `distribution_id` and stores it, replacing any existing one.
+async fn create_sender_key<R: Rng + CryptoRng>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    sender_key_store: &mut dyn SenderKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SenderKeyRecord> {
+    // libsignal-protocol-java uses 31-bit integers for sender key chain IDs
+    let chain_id = (csprng.gen::<u32>()) >> 1;
+    log::info!(
+        "Creating SenderKey for distribution {} with chain ID {}",
+        distribution_id,
+        chain_id
+    );
+
+    let iteration = 0;
+    let sender_key: [u8; 32] = csprng.gen();
+    let signing_key = KeyPair::generate(csprng);
+    let mut record = SenderKeyRecord::new_empty();
+    record.add_sender_key_state(
+        SENDERKEY_MESSAGE_CURRENT_VERSION,
+        chain_id,
+        iteration,
+        &sender_key,
+        signing_key.public_key,
+        Some(signing_key.private_key),
+    );
+    let sender_key_name = SenderKeyName::new(sender.clone(), distribution_id);
+    sender_key_store
+        .store_sender_key(&sender_key_name, &record, ctx)
+        .await?;
+    observer::notify(|o| o.sender_key_updated(&sender_key_name));
+    observer::notify(|o| o.sender_key_rotated(sender, distribution_id));
+    Ok(record)
+}
+
+fn distribution_message_for_record(
+    sender_key_record: &SenderKeyRecord,
+    distribution_id: Uuid,
+) -> Result<SenderKeyDistributionMessage> {
     let state = sender_key_record
         .sender_key_state()
         .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?;
@@ -291,3 +603,50 @@ pub async fn create_sender_key_distribution_message<R: Rng + CryptoRng>(
             .map_err(|_| SignalProtocolError::InvalidSenderKeySession { distribution_id })?,
     )
 }
+
+/// The result of [reset_sender_key].
+#[derive(Debug, Clone)]
+pub struct SenderKeyReset {
+    /// The distribution message for the new sender key.
+    pub distribution_message: SenderKeyDistributionMessage,
+    /// The members that need to receive `distribution_message` before they can decrypt anything
+    /// sent with the new key.
+    pub recipients: Vec<ProtocolAddress>,
+}
+
+/// Replaces the local sender key for `distribution_id` with a new one, for when a member leaves
+/// the group and must not be able to read anything sent afterwards.
+///
+/// `members` is the group's membership after the change, as a list of device addresses. Every
+/// one of them except `sender` itself is returned in [SenderKeyReset::recipients], in order and
+/// without duplicates, and should be sent the new distribution message (over 1:1 sessions) before
+/// the next group message. Members that miss it won't be able to decrypt group messages until
+/// they get it.
+pub async fn reset_sender_key<R: Rng + CryptoRng>(
+    sender: &ProtocolAddress,
+    distribution_id: Uuid,
+    members: &[&ProtocolAddress],
+    sender_key_store: &mut dyn SenderKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SenderKeyReset> {
+    log::info!(
+        "{} Resetting SenderKey for distribution {}",
+        sender,
+        distribution_id
+    );
+    let record = create_sender_key(sender, distribution_id, sender_key_store, csprng, ctx).await?;
+    let distribution_message = distribution_message_for_record(&record, distribution_id)?;
+
+    let mut recipients: Vec<ProtocolAddress> = Vec::with_capacity(members.len());
+    for &member in members {
+        if member != sender && !recipients.contains(member) {
+            recipients.push(member.clone());
+        }
+    }
+
+    Ok(SenderKeyReset {
+        distribution_message,
+        recipients,
+    })
+}
@@ -7,10 +7,16 @@
 
 #![warn(missing_docs)]
 
-use crate::{proto, KeyPair, PrivateKey, PublicKey, Result, SignalProtocolError};
+mod import;
+
+use crate::{
+    proto, CryptoRngCore, KeyPair, KeyType, PrivateKey, PrivateKeyOps, PublicKey, Result,
+    SignalProtocolError,
+};
 
 use rand::{CryptoRng, Rng};
 use std::convert::TryFrom;
+use subtle::ConstantTimeEq;
 
 use prost::Message;
 
@@ -21,17 +27,31 @@ const ALTERNATE_IDENTITY_SIGNATURE_PREFIX_2: &[u8] = b"Signal_PNI_Signature";
 /// A public key that represents the identity of a user.
 ///
 /// Wrapper for [`PublicKey`].
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialOrd, Ord, Eq, Clone, Copy)]
 pub struct IdentityKey {
     public_key: PublicKey,
 }
 
+impl PartialEq for IdentityKey {
+    fn eq(&self, other: &IdentityKey) -> bool {
+        self.ct_eq(other)
+    }
+}
+
 impl IdentityKey {
     /// Initialize a public-facing identity from a public key.
     pub fn new(public_key: PublicKey) -> Self {
         Self { public_key }
     }
 
+    /// A constant-time comparison of the underlying public keys.
+    ///
+    /// This is also what `==` uses; prefer it when deciding whether to trust an identity, so the
+    /// intent is explicit.
+    pub fn ct_eq(&self, other: &IdentityKey) -> bool {
+        self.public_key.ct_eq(&other.public_key).into()
+    }
+
     /// Return the public key representing this identity.
     #[inline]
     pub fn public_key(&self) -> &PublicKey {
@@ -89,7 +109,7 @@ impl From<IdentityKey> for PublicKey {
 /// The private identity of a user.
 ///
 /// Can be converted to and from [`KeyPair`].
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct IdentityKeyPair {
     identity_key: IdentityKey,
     private_key: PrivateKey,
@@ -114,6 +134,20 @@ impl IdentityKeyPair {
         }
     }
 
+    /// Generate a random new identity on the curve given by `key_type`.
+    ///
+    /// Sessions can only be set up between identities of the same type.
+    pub fn generate_with_key_type<R: CryptoRng + Rng>(key_type: KeyType, csprng: &mut R) -> Self {
+        KeyPair::generate_with_key_type(key_type, csprng).into()
+    }
+
+    /// Deterministically derive an identity from `seed`.
+    ///
+    /// Gives the same key as [KeyPair::derive_from_seed] with the same arguments.
+    pub fn derive_from_seed(seed: &[u8], info: &str) -> Result<Self> {
+        Ok(KeyPair::derive_from_seed(seed, info)?.into())
+    }
+
     /// Return the public identity of this user.
     #[inline]
     pub fn identity_key(&self) -> &IdentityKey {
@@ -160,6 +194,24 @@ impl IdentityKeyPair {
     }
 }
 
+impl PrivateKeyOps for IdentityKeyPair {
+    fn public_key(&self) -> Result<PublicKey> {
+        Ok(*self.public_key())
+    }
+
+    fn calculate_signature_for_multipart_message(
+        &self,
+        message: &[&[u8]],
+        csprng: &mut dyn CryptoRngCore,
+    ) -> Result<Box<[u8]>> {
+        PrivateKeyOps::calculate_signature_for_multipart_message(&self.private_key, message, csprng)
+    }
+
+    fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>> {
+        self.private_key.calculate_agreement(their_key)
+    }
+}
+
 impl TryFrom<&[u8]> for IdentityKeyPair {
     type Error = SignalProtocolError;
 
@@ -211,6 +263,27 @@ mod tests {
         assert_eq!(key_pair_public_serialized, identity_key.serialize());
     }
 
+    #[test]
+    fn test_constant_time_eq() {
+        let identity_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let other = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let decoded = IdentityKey::decode(&identity_key.serialize()).expect("valid");
+        assert!(identity_key.ct_eq(&decoded));
+        assert!(!identity_key.ct_eq(&other));
+        assert!(crate::constant_time_eq(
+            &identity_key.serialize(),
+            &identity_key.serialize()
+        ));
+        assert!(!crate::constant_time_eq(
+            &identity_key.serialize(),
+            &other.serialize()
+        ));
+        assert!(!crate::constant_time_eq(
+            &identity_key.serialize(),
+            &identity_key.serialize()[1..]
+        ));
+    }
+
     #[test]
     fn test_serialize_identity_key_pair() -> Result<()> {
         let identity_key_pair = IdentityKeyPair::generate(&mut OsRng);
@@ -23,7 +23,17 @@
 // #![warn(missing_docs)]
 
 mod address;
+#[cfg(feature = "proptest")]
+mod arbitrary;
+pub mod auth;
+#[cfg(feature = "inmem-stores")]
+pub mod channel;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+mod clock;
+pub mod conformance;
 mod consts;
+pub mod contextual;
 mod crypto;
 mod curve;
 pub mod error;
@@ -32,57 +42,134 @@ mod group_cipher;
 mod identity_key;
 pub mod incremental_mac;
 pub mod kem;
+mod observer;
+mod ordering;
+mod padding;
+mod profile_cipher;
 mod proto;
 mod protocol;
 mod ratchet;
+mod reconcile;
+mod record_integrity;
+mod record_version;
+mod redact;
+mod registration_id;
+mod rng;
 mod sealed_sender;
 mod sender_keys;
+mod sent_message_cache;
 mod session;
 mod session_cipher;
+pub mod session_inspect;
 mod state;
 mod storage;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+mod timestamp;
 mod utils;
 
 use error::Result;
 
 pub use address::{
-    Aci, DeviceId, Pni, ProtocolAddress, ServiceId, ServiceIdFixedWidthBinaryBytes, ServiceIdKind,
+    Aci, DeviceId, Pni, ProtocolAddress, SenderKeyName, ServiceId, ServiceIdFixedWidthBinaryBytes,
+    ServiceIdKind,
+};
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use curve::{KeyPair, KeyType, PrecomputedPublicKey, PrivateKey, PrivateKeyOps, PublicKey};
+pub use error::{
+    ContextualError, ErrorContext, ExtensionError, ExtensionResultExt, ProtocolOperation,
+    ResultExt, SignalProtocolError,
+};
+pub use fingerprint::{
+    DisplayableFingerprint, Fingerprint, FingerprintComparison, FingerprintEncoding,
+    FingerprintGenerator, FingerprintIterations, FingerprintParty, GroupFingerprint,
+    ScannableFingerprint,
 };
-pub use curve::{KeyPair, PrivateKey, PublicKey};
-pub use error::SignalProtocolError;
-pub use fingerprint::{DisplayableFingerprint, Fingerprint, ScannableFingerprint};
 pub use group_cipher::{
-    create_sender_key_distribution_message, group_decrypt, group_encrypt,
-    process_sender_key_distribution_message,
+    create_sender_key_distribution_message, group_decrypt, group_decrypt_with_associated_data,
+    group_decrypt_with_replay_cache, group_encrypt, group_encrypt_batch, group_encrypt_sealed,
+    group_encrypt_with_associated_data, process_attributed_sender_key_distribution_message,
+    process_sender_key_distribution_message, reset_sender_key, SealedGroupMessage, SenderKeyReset,
 };
 pub use identity_key::{IdentityKey, IdentityKeyPair};
+pub use observer::{set_global_observer, with_observer, ProtocolObserver, WithObserver};
+pub use ordering::MessageOrderingToken;
+pub use padding::{
+    pad_plaintext, pad_plaintext_with_bucket_size, unpad_plaintext, DEFAULT_PADDING_BUCKET_SIZE,
+};
+pub use profile_cipher::{
+    decrypt_profile_avatar, decrypt_profile_field, encrypt_profile_avatar, encrypt_profile_field,
+    ProfileField, ProfileKeyCommitment, PROFILE_KEY_LEN,
+};
 pub use protocol::{
     extract_decryption_error_message_from_serialized_content, CiphertextMessage,
-    CiphertextMessageType, DecryptionErrorMessage, KyberPayload, PlaintextContent,
-    PreKeySignalMessage, SenderKeyDistributionMessage, SenderKeyMessage, SignalMessage,
+    CiphertextMessageType, DecryptionErrorMessage, IdentityRotation, KyberPayload,
+    PlaintextContent, PreKeySignalMessage, SenderKeyDistributionMessage, SenderKeyMessage,
+    SignalMessage,
 };
 pub use ratchet::{
     initialize_alice_session_record, initialize_bob_session_record, AliceSignalProtocolParameters,
     BobSignalProtocolParameters,
 };
+pub use reconcile::{
+    reconcile_with_server, HandedOutPreKeys, ReconciliationReport, ServerState,
+    DEFAULT_HANDED_OUT_PRE_KEY_GRACE_PERIOD,
+};
+pub use record_integrity::IntegrityMode;
+pub use registration_id::{generate_registration_id, RegistrationIdRange};
+pub use rng::{verify_rng_health, CryptoRngCore, EntropySource, SeededRng};
 pub use sealed_sender::{
-    sealed_sender_decrypt, sealed_sender_decrypt_to_usmc, sealed_sender_encrypt,
-    sealed_sender_encrypt_from_usmc, sealed_sender_multi_recipient_encrypt,
-    sealed_sender_multi_recipient_fan_out, ContentHint, SealedSenderDecryptionResult,
-    SenderCertificate, ServerCertificate, UnidentifiedSenderMessageContent,
+    derive_unidentified_access_key, sealed_sender_decrypt, sealed_sender_decrypt_contents,
+    sealed_sender_decrypt_to_usmc, sealed_sender_decrypt_with_age_policy,
+    sealed_sender_decrypt_with_sender_keys, sealed_sender_encrypt, sealed_sender_encrypt_from_usmc,
+    sealed_sender_encrypt_message, sealed_sender_multi_recipient_encrypt,
+    sealed_sender_multi_recipient_fan_out, ContentHint, EnvelopeAgePolicy, RevocationProvider,
+    SealedSenderDecryptionResult, SenderCertificate, SenderValidation, ServerCertificate,
+    StaticRevocationList, UnidentifiedAccessMode, UnidentifiedSenderMessageContent,
+    UNIDENTIFIED_ACCESS_KEY_LEN, UNRESTRICTED_UNIDENTIFIED_ACCESS_KEY,
+};
+pub use sender_keys::{DistributionId, SenderKeyRecord};
+pub use sent_message_cache::{message_encrypt_cached, SentMessageCache, SentMessageKey};
+pub use session::{
+    process_prekey, process_prekey_bundle, process_prekey_bundle_with_policy,
+    process_prekey_bundle_with_protocol_store, process_session_reset, session_reset,
+    OneTimePreKeyPolicy, PreKeysUsed,
 };
-pub use sender_keys::SenderKeyRecord;
-pub use session::{process_prekey, process_prekey_bundle};
 pub use session_cipher::{
-    message_decrypt, message_decrypt_prekey, message_decrypt_signal, message_encrypt,
+    can_encrypt, message_decrypt, message_decrypt_prekey, message_decrypt_signal,
+    message_decrypt_with_associated_data, message_decrypt_with_config,
+    message_decrypt_with_identity_policy, message_decrypt_with_info,
+    message_decrypt_with_protocol_store, message_decrypt_with_replay_cache,
+    message_decrypt_with_work_limit, message_encrypt,
+    message_encrypt_with_alternate_identity_signature, message_encrypt_with_associated_data,
+    message_encrypt_with_config, message_encrypt_with_identity_policy,
+    message_encrypt_with_protocol_store, session_exists_and_is_current, DecryptResult,
+    EncryptionProblem, EncryptionReadiness, IdentityChangePolicy, SessionAvailability,
+    SessionConfig, WorkLimit,
 };
 pub use state::{
-    GenericSignedPreKey, KyberPreKeyId, KyberPreKeyRecord, PreKeyBundle, PreKeyBundleContent,
-    PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord,
+    generate_prekey_batch, GenericSignedPreKey, KyberPreKeyId, KyberPreKeyRecord, PreKeyBundle,
+    PreKeyBundleBuilder, PreKeyBundleContent, PreKeyBundleProblem, PreKeyBundleValidation,
+    PreKeyId, PreKeyRecord, SessionArchiveKey, SessionArchivePolicy, SessionRecord, SessionRole,
+    SignedPreKeyId, SignedPreKeyRecord, SignedPreKeyRotation, DEFAULT_SIGNED_PRE_KEY_RETENTION,
+    DEFAULT_SIGNED_PRE_KEY_ROTATION_INTERVAL, MAX_PRE_KEY_ID, SESSION_ARCHIVE_PASSWORD_ITERATIONS,
+};
+pub use storage::{
+    BlockingStoreAdapter, Context, Direction, IdentityKeyStore, InMemStoreJournal, JournalEntry,
+    JournalingStore, KyberPreKeyStore, NotifyingPreKeyStore, PreKeyStore, ProtocolStore,
+    ReplayCache, ReplayKey, SenderKeyStore, SessionStore, SignedPreKeyStore, StoreJournal,
+    StoreMutation, SyncIdentityKeyStore, SyncKyberPreKeyStore, SyncPreKeyStore, SyncSenderKeyStore,
+    SyncSessionStore, SyncSignedPreKeyStore, SyncStoreAdapter,
 };
+#[cfg(feature = "inmem-stores")]
 pub use storage::{
-    Context, Direction, IdentityKeyStore, InMemIdentityKeyStore, InMemKyberPreKeyStore,
-    InMemPreKeyStore, InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore,
-    InMemSignedPreKeyStore, KyberPreKeyStore, PreKeyStore, ProtocolStore, SenderKeyStore,
-    SessionStore, SignedPreKeyStore,
+    CachedSessionStore, InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemMultiAccountStore,
+    InMemPreKeyStore, InMemReplayCache, InMemSenderKeyStore, InMemSessionStore,
+    InMemSignalProtocolStore, InMemSignedPreKeyStore, StoreSnapshot,
 };
+#[cfg(feature = "chaos")]
+pub use storage::{FaultySignalProtocolStore, FaultyStore, InjectedFault, Sleep};
+#[cfg(feature = "inmem-stores")]
+pub use storage::{InMemKvBackend, KvBackend, KvProtocolStore};
+pub use timestamp::Timestamp;
+pub use utils::constant_time_eq;
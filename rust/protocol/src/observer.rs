@@ -0,0 +1,201 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Hooks for watching what the protocol does, for logging, telemetry, and keeping UI up to date.
+//!
+//! The library reports interesting events (a new session, a MAC failure, a changed identity, ...)
+//! to a [ProtocolObserver] in addition to its usual `log` output, so clients can count them or
+//! attach them to their own diagnostics without parsing log lines. It also reports each session
+//! and sender key it saves, so clients can react to those changes (refreshing a conversation's
+//! safety number, sending a sync message) without comparing their database before and after every
+//! operation.
+//!
+//! An observer can be installed for the whole process with [set_global_observer], or for a single
+//! operation with [with_observer], which takes precedence over the global one while that
+//! operation runs.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+
+use uuid::Uuid;
+
+use crate::{ProtocolAddress, SenderKeyName};
+
+/// Receives notifications about protocol events.
+///
+/// Every method has an empty default implementation, so observers only need to implement the
+/// events they care about. Methods are called synchronously from within the operation that
+/// produced the event, and so should return quickly and must not call back into the operation's
+/// stores.
+pub trait ProtocolObserver: Send + Sync {
+    /// A new session with `address` was set up, from either side.
+    fn session_created(&self, _address: &ProtocolAddress) {}
+
+    /// A message from `address` started a new receiving chain, stepping the ratchet.
+    fn ratchet_stepped(&self, _address: &ProtocolAddress) {}
+
+    /// A message from `address` failed MAC verification against one of its sessions.
+    ///
+    /// Decryption tries every stored session, so this can be reported even when the message is
+    /// ultimately decrypted successfully.
+    fn mac_failure(&self, _address: &ProtocolAddress) {}
+
+    /// A message from `address` was rejected as a duplicate.
+    ///
+    /// For sender key messages, `chain_index` and `counter` are iterations of the sender key chain.
+    fn duplicate_message(&self, _address: &ProtocolAddress, _chain_index: u32, _counter: u32) {}
+
+    /// The stored identity key for `address` was replaced with a different one.
+    ///
+    /// This is reported after the new key has been saved to the identity store.
+    fn identity_changed(&self, _address: &ProtocolAddress) {}
+
+    /// The session record for `address` was saved to the session store.
+    ///
+    /// This happens whenever a session is created, used to encrypt or decrypt a message, or
+    /// archived, so it is reported far more often than [session_created](Self::session_created).
+    fn session_updated(&self, _address: &ProtocolAddress) {}
+
+    /// The sender key record for `name` was saved to the sender key store, after encrypting or
+    /// decrypting a group message or processing a distribution message.
+    fn sender_key_updated(&self, _name: &SenderKeyName) {}
+
+    /// `sender` started a new sender key chain for `distribution_id`.
+    ///
+    /// This is reported both when a local sender key is created and when a distribution message
+    /// with a new chain is processed.
+    fn sender_key_rotated(&self, _sender: &ProtocolAddress, _distribution_id: Uuid) {}
+}
+
+static GLOBAL_OBSERVER: RwLock<Option<Arc<dyn ProtocolObserver>>> = RwLock::new(None);
+
+thread_local! {
+    static SCOPED_OBSERVER: RefCell<Option<Arc<dyn ProtocolObserver>>> = RefCell::new(None);
+}
+
+/// Installs `observer` for every operation not run under [with_observer], replacing any
+/// previously installed observer. Pass `None` to remove it.
+pub fn set_global_observer(observer: Option<Arc<dyn ProtocolObserver>>) {
+    *GLOBAL_OBSERVER
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = observer;
+}
+
+/// Runs `operation` with `observer` receiving its events instead of the global observer.
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use libsignal_protocol::{with_observer, ProtocolAddress, ProtocolObserver};
+/// struct CountMacFailures;
+/// impl ProtocolObserver for CountMacFailures {
+///     fn mac_failure(&self, address: &ProtocolAddress) {
+///         eprintln!("bad MAC from {}", address);
+///     }
+/// }
+///
+/// # async fn decrypt() {}
+/// # futures_util::FutureExt::now_or_never(async {
+/// with_observer(Arc::new(CountMacFailures), decrypt()).await;
+/// # });
+/// ```
+pub fn with_observer<F: Future>(
+    observer: Arc<dyn ProtocolObserver>,
+    operation: F,
+) -> WithObserver<F> {
+    WithObserver {
+        observer,
+        operation: Box::pin(operation),
+    }
+}
+
+/// The future returned by [with_observer].
+pub struct WithObserver<F> {
+    observer: Arc<dyn ProtocolObserver>,
+    operation: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithObserver<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        let previous =
+            SCOPED_OBSERVER.with(|scoped| scoped.replace(Some(Arc::clone(&self.observer))));
+        let result = self.operation.as_mut().poll(cx);
+        SCOPED_OBSERVER.with(|scoped| *scoped.borrow_mut() = previous);
+        result
+    }
+}
+
+/// Reports an event to the observer for the current operation, if there is one.
+pub(crate) fn notify(event: impl FnOnce(&dyn ProtocolObserver)) {
+    let observer = SCOPED_OBSERVER
+        .with(|scoped| scoped.borrow().clone())
+        .or_else(|| {
+            GLOBAL_OBSERVER
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone()
+        });
+    if let Some(observer) = observer {
+        event(&*observer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl ProtocolObserver for Recorder {
+        fn mac_failure(&self, address: &ProtocolAddress) {
+            self.0
+                .lock()
+                .expect("not poisoned")
+                .push(format!("mac_failure {}", address));
+        }
+    }
+
+    #[test]
+    fn scoped_observer_only_applies_while_polled() {
+        let address = ProtocolAddress::new("alice".to_owned(), 1.into());
+        let recorder = Arc::new(Recorder::default());
+
+        with_observer(recorder.clone(), async {
+            notify(|o| o.mac_failure(&address));
+        })
+        .now_or_never()
+        .expect("sync");
+        SCOPED_OBSERVER.with(|scoped| assert!(scoped.borrow().is_none()));
+
+        let nested = Arc::new(Recorder::default());
+        with_observer(recorder.clone(), async {
+            with_observer(nested.clone(), async {
+                notify(|o| o.mac_failure(&address));
+            })
+            .await;
+            notify(|o| o.mac_failure(&address));
+        })
+        .now_or_never()
+        .expect("sync");
+
+        assert_eq!(
+            *recorder.0.lock().expect("not poisoned"),
+            vec!["mac_failure alice.1", "mac_failure alice.1"]
+        );
+        assert_eq!(
+            *nested.0.lock().expect("not poisoned"),
+            vec!["mac_failure alice.1"]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::cmp::Ordering;
+use std::convert::TryInto;
+
+use crate::state::SessionRecord;
+use crate::{Result, SignalMessage, SignalProtocolError};
+
+const TOKEN_VERSION: u8 = 1;
+
+/// Records where a decrypted message falls in the order its sender sent them.
+///
+/// Each message is sent on a chain (which changes every time the sender hears back from us) at
+/// some counter within that chain. Tokens from the same session compare in the order the sender
+/// produced the messages, across chain transitions, regardless of the order they arrived in or
+/// what timestamps the server attached. Tokens from different sessions (for instance, after a
+/// session reset) can't be compared, so [`partial_cmp`](PartialOrd::partial_cmp) returns `None`.
+///
+/// Tokens can be [serialized](Self::serialize) to store alongside decrypted messages. Within one
+/// session, the serialized forms sort in the same order as the tokens.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MessageOrderingToken {
+    chain: u32,
+    counter: u32,
+    session_id: Vec<u8>,
+}
+
+impl MessageOrderingToken {
+    /// Computes the token for `message`, which has just been decrypted using `record`.
+    ///
+    /// Returns `None` if the chain predates ordering support, and its position is unknown.
+    pub(crate) fn for_decrypted_message(
+        record: &SessionRecord,
+        message: &SignalMessage,
+    ) -> Result<Option<Self>> {
+        let state = record.session_state().ok_or_else(|| {
+            SignalProtocolError::InvalidState(
+                "ordering_token",
+                "no current session after decryption".to_owned(),
+            )
+        })?;
+        let chain = match state.get_receiver_chain_ordinal(message.sender_ratchet_key()?)? {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            chain,
+            counter: message.counter()?,
+            session_id: state.alice_base_key().to_vec(),
+        }))
+    }
+
+    /// Whether `self` and `other` came from the same session, and can therefore be compared.
+    pub fn is_same_session(&self, other: &Self) -> bool {
+        self.session_id == other.session_id
+    }
+
+    /// Whether `self` is on a later chain than `other`, meaning the sender heard from us in
+    /// between sending the two messages.
+    ///
+    /// Returns `None` if the tokens are from different sessions.
+    pub fn is_on_later_chain_than(&self, other: &Self) -> Option<bool> {
+        if self.is_same_session(other) {
+            Some(self.chain > other.chain)
+        } else {
+            None
+        }
+    }
+
+    /// Encodes the token as opaque bytes.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(9 + self.session_id.len());
+        result.push(TOKEN_VERSION);
+        result.extend_from_slice(&self.chain.to_be_bytes());
+        result.extend_from_slice(&self.counter.to_be_bytes());
+        result.extend_from_slice(&self.session_id);
+        result
+    }
+
+    /// Decodes the output of [`serialize`](Self::serialize).
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [TOKEN_VERSION, rest @ ..] if rest.len() >= 8 => Ok(Self {
+                chain: u32::from_be_bytes(rest[..4].try_into().expect("correct length")),
+                counter: u32::from_be_bytes(rest[4..8].try_into().expect("correct length")),
+                session_id: rest[8..].to_vec(),
+            }),
+            _ => Err(SignalProtocolError::InvalidArgument(
+                "invalid message ordering token".to_owned(),
+            )),
+        }
+    }
+}
+
+impl PartialOrd for MessageOrderingToken {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.is_same_session(other) {
+            Some((self.chain, self.counter).cmp(&(other.chain, other.counter)))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! The padding Signal clients apply to message plaintexts, so that ciphertext lengths only reveal
+//! roughly how long a message is.
+//!
+//! A padded plaintext is the original bytes, a single `0x80` byte, and then as many zero bytes as
+//! it takes to reach a multiple of the bucket size.
+
+use crate::{Result, SignalProtocolError};
+
+/// The bucket size used by Signal clients.
+pub const DEFAULT_PADDING_BUCKET_SIZE: usize = 160;
+
+const PADDING_BOUNDARY_BYTE: u8 = 0x80;
+
+/// Pads `plaintext` to a multiple of [`DEFAULT_PADDING_BUCKET_SIZE`] bytes.
+pub fn pad_plaintext(plaintext: &[u8]) -> Vec<u8> {
+    pad_plaintext_with_bucket_size(plaintext, DEFAULT_PADDING_BUCKET_SIZE)
+        .expect("default bucket size is valid")
+}
+
+/// Pads `plaintext` to a multiple of `bucket_size` bytes.
+///
+/// Fails if `bucket_size` is zero. The result is always longer than `plaintext`, so a plaintext
+/// that is already a multiple of `bucket_size` gets a whole bucket of padding.
+pub fn pad_plaintext_with_bucket_size(plaintext: &[u8], bucket_size: usize) -> Result<Vec<u8>> {
+    if bucket_size == 0 {
+        return Err(SignalProtocolError::InvalidArgument(
+            "padding bucket size must not be zero".to_owned(),
+        ));
+    }
+    let buckets = plaintext.len() / bucket_size + 1;
+    let mut padded = Vec::with_capacity(buckets * bucket_size);
+    padded.extend_from_slice(plaintext);
+    padded.push(PADDING_BOUNDARY_BYTE);
+    padded.resize(buckets * bucket_size, 0);
+    Ok(padded)
+}
+
+/// Removes the padding added by [`pad_plaintext`] or [`pad_plaintext_with_bucket_size`].
+///
+/// The bucket size doesn't need to be known; anything after the last `0x80` byte must be zeros.
+pub fn unpad_plaintext(padded: &[u8]) -> Result<&[u8]> {
+    let boundary = padded
+        .iter()
+        .rposition(|&b| b != 0)
+        .ok_or(SignalProtocolError::InvalidPadding("no padding boundary"))?;
+    if padded[boundary] != PADDING_BOUNDARY_BYTE {
+        return Err(SignalProtocolError::InvalidPadding(
+            "padding contains non-zero bytes",
+        ));
+    }
+    Ok(&padded[..boundary])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        for len in [0, 1, 158, 159, 160, 161, 500] {
+            let plaintext = vec![0x80; len];
+            let padded = pad_plaintext(&plaintext);
+            assert_eq!(padded.len() % DEFAULT_PADDING_BUCKET_SIZE, 0);
+            assert!(padded.len() > len);
+            assert_eq!(unpad_plaintext(&padded)?, &plaintext[..]);
+        }
+
+        let padded = pad_plaintext_with_bucket_size(b"hello", 4)?;
+        assert_eq!(padded, b"hello\x80\0\0");
+        assert_eq!(unpad_plaintext(&padded)?, b"hello");
+        assert!(pad_plaintext_with_bucket_size(b"hello", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_padding() {
+        for padded in [&b""[..], b"\0\0\0", b"hello\x81\0", b"hello"] {
+            assert!(matches!(
+                unpad_plaintext(padded),
+                Err(SignalProtocolError::InvalidPadding(_))
+            ));
+        }
+    }
+}
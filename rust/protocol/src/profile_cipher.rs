@@ -0,0 +1,267 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Encryption of profile fields under a profile key.
+//!
+//! Every field is encrypted with AES-256-GCM using the profile key directly, and stored as
+//! `nonce (12 bytes) || ciphertext || tag (16 bytes)`. Text fields are padded with zero bytes to
+//! one of a few fixed lengths first, so the server only learns roughly how long they are; avatars
+//! are not padded.
+
+use rand::{CryptoRng, Rng};
+use sha2::{Digest, Sha256};
+use signal_crypto::{Aes256GcmDecryption, Aes256GcmEncryption};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::{Result, SignalProtocolError};
+
+/// The length of a profile key.
+pub const PROFILE_KEY_LEN: usize = 32;
+
+const NONCE_SIZE: usize = Aes256GcmEncryption::NONCE_SIZE;
+const TAG_SIZE: usize = Aes256GcmEncryption::TAG_SIZE;
+
+/// A padded text field of a profile.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProfileField {
+    /// The profile name: the given name, a NUL byte, and the family name, if any.
+    Name,
+    /// The "about" text.
+    About,
+    /// The emoji shown next to the "about" text.
+    AboutEmoji,
+}
+
+impl ProfileField {
+    /// The lengths this field is padded to before encryption, shortest first.
+    pub fn padded_lengths(self) -> &'static [usize] {
+        match self {
+            Self::Name => &[53, 257],
+            Self::About => &[128, 254, 512],
+            Self::AboutEmoji => &[32],
+        }
+    }
+}
+
+fn encrypt_with_nonce(
+    profile_key: &[u8; PROFILE_KEY_LEN],
+    nonce: &[u8],
+    plaintext: Vec<u8>,
+) -> Vec<u8> {
+    let mut gcm =
+        Aes256GcmEncryption::new(profile_key, nonce, &[]).expect("valid key and nonce sizes");
+    let mut output = Vec::with_capacity(NONCE_SIZE + plaintext.len() + TAG_SIZE);
+    output.extend_from_slice(nonce);
+    output.extend_from_slice(&plaintext);
+    gcm.encrypt(&mut output[NONCE_SIZE..])
+        .expect("GCM accepts any message length");
+    output.extend_from_slice(&gcm.compute_tag().expect("not yet finalized"));
+    output
+}
+
+fn encrypt<R: Rng + CryptoRng>(
+    profile_key: &[u8; PROFILE_KEY_LEN],
+    plaintext: Vec<u8>,
+    csprng: &mut R,
+) -> Vec<u8> {
+    let mut nonce = [0; NONCE_SIZE];
+    csprng.fill_bytes(&mut nonce);
+    encrypt_with_nonce(profile_key, &nonce, plaintext)
+}
+
+fn decrypt(profile_key: &[u8; PROFILE_KEY_LEN], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    if ciphertext.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(SignalProtocolError::InvalidProfileCiphertext(
+            "ciphertext too short",
+        ));
+    }
+    let (nonce, rest) = ciphertext.split_at(NONCE_SIZE);
+    let (body, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+    let mut gcm =
+        Aes256GcmDecryption::new(profile_key, nonce, &[]).expect("valid key and nonce sizes");
+    let mut plaintext = body.to_vec();
+    gcm.decrypt(&mut plaintext)
+        .expect("GCM accepts any message length");
+    gcm.verify_tag(tag)
+        .map_err(|_| SignalProtocolError::InvalidProfileCiphertext("authentication failed"))?;
+    Ok(plaintext)
+}
+
+/// Pads `plaintext` to the shortest length allowed for `field` and encrypts it.
+///
+/// Fails if `plaintext` is longer than the longest padded length for `field`.
+pub fn encrypt_profile_field<R: Rng + CryptoRng>(
+    profile_key: &[u8; PROFILE_KEY_LEN],
+    field: ProfileField,
+    plaintext: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>> {
+    let padded_len = field
+        .padded_lengths()
+        .iter()
+        .copied()
+        .find(|&len| len >= plaintext.len())
+        .ok_or_else(|| {
+            SignalProtocolError::InvalidArgument(format!(
+                "{:?} of {} bytes is too long to encrypt",
+                field,
+                plaintext.len()
+            ))
+        })?;
+    let mut padded = plaintext.to_vec();
+    padded.resize(padded_len, 0);
+    Ok(encrypt(profile_key, padded, csprng))
+}
+
+/// Decrypts a field encrypted by [encrypt_profile_field], removing its padding.
+///
+/// Since the padding is zero bytes, any trailing zero bytes in the original plaintext are removed
+/// as well.
+pub fn decrypt_profile_field(
+    profile_key: &[u8; PROFILE_KEY_LEN],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    let mut plaintext = decrypt(profile_key, ciphertext)?;
+    let len = plaintext
+        .iter()
+        .rposition(|&b| b != 0)
+        .map_or(0, |last| last + 1);
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+/// Encrypts a profile avatar.
+pub fn encrypt_profile_avatar<R: Rng + CryptoRng>(
+    profile_key: &[u8; PROFILE_KEY_LEN],
+    avatar: &[u8],
+    csprng: &mut R,
+) -> Vec<u8> {
+    encrypt(profile_key, avatar.to_vec(), csprng)
+}
+
+/// Decrypts a profile avatar encrypted by [encrypt_profile_avatar].
+pub fn decrypt_profile_avatar(
+    profile_key: &[u8; PROFILE_KEY_LEN],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    decrypt(profile_key, ciphertext)
+}
+
+/// A commitment to a profile key for a particular account.
+///
+/// This lets a profile be published alongside a value that only someone who knows the profile key
+/// can check, without revealing the key itself. It is a plain hash commitment; it does not replace
+/// the zero-knowledge commitments used for group membership.
+#[derive(Clone, Copy, Debug)]
+pub struct ProfileKeyCommitment([u8; 32]);
+
+impl ProfileKeyCommitment {
+    const LABEL: &'static [u8] = b"Signal_ProfileKeyCommitment_20230501";
+
+    /// Commits to `profile_key` as the profile key of the account `aci`.
+    pub fn new(profile_key: &[u8; PROFILE_KEY_LEN], aci: Uuid) -> Self {
+        let mut hash = Sha256::new();
+        hash.update(Self::LABEL);
+        hash.update(aci.as_bytes());
+        hash.update(profile_key);
+        Self(hash.finalize().into())
+    }
+
+    /// Checks whether this commitment was made to `profile_key` for the account `aci`.
+    pub fn verify(&self, profile_key: &[u8; PROFILE_KEY_LEN], aci: Uuid) -> bool {
+        Self::new(profile_key, aci).0.ct_eq(&self.0).into()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    const PROFILE_KEY: [u8; PROFILE_KEY_LEN] = [0x42; PROFILE_KEY_LEN];
+
+    #[test]
+    fn field_round_trip() -> Result<()> {
+        for (field, plaintext, padded_len) in [
+            (ProfileField::Name, &b"Alice\0Smith"[..], 53),
+            (ProfileField::Name, &[b'a'; 54][..], 257),
+            (ProfileField::About, &b""[..], 128),
+            (ProfileField::About, &[b'a'; 500][..], 512),
+            (ProfileField::AboutEmoji, "\u{1F600}".as_bytes(), 32),
+        ] {
+            let ciphertext = encrypt_profile_field(&PROFILE_KEY, field, plaintext, &mut OsRng)?;
+            assert_eq!(ciphertext.len(), NONCE_SIZE + padded_len + TAG_SIZE);
+            assert_eq!(decrypt_profile_field(&PROFILE_KEY, &ciphertext)?, plaintext);
+        }
+
+        assert!(matches!(
+            encrypt_profile_field(&PROFILE_KEY, ProfileField::Name, &[b'a'; 258], &mut OsRng),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_tampering() -> Result<()> {
+        let ciphertext = encrypt_profile_avatar(&PROFILE_KEY, b"avatar", &mut OsRng);
+        assert_eq!(
+            decrypt_profile_avatar(&PROFILE_KEY, &ciphertext)?,
+            b"avatar"
+        );
+
+        for i in [0, NONCE_SIZE, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[i] ^= 1;
+            assert!(matches!(
+                decrypt_profile_avatar(&PROFILE_KEY, &tampered),
+                Err(SignalProtocolError::InvalidProfileCiphertext(_))
+            ));
+        }
+        assert!(matches!(
+            decrypt_profile_avatar(&[0; PROFILE_KEY_LEN], &ciphertext),
+            Err(SignalProtocolError::InvalidProfileCiphertext(_))
+        ));
+        assert!(matches!(
+            decrypt_profile_field(&PROFILE_KEY, &ciphertext[..NONCE_SIZE + TAG_SIZE - 1]),
+            Err(SignalProtocolError::InvalidProfileCiphertext(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn known_answer() {
+        // The same construction as the unidentified access key: GCM with the profile key as the
+        // key, here with an explicit nonce.
+        let nonce = [0; NONCE_SIZE];
+        let ciphertext = encrypt_with_nonce(&PROFILE_KEY, &nonce, vec![0; 16]);
+        assert_eq!(
+            &ciphertext[NONCE_SIZE..NONCE_SIZE + 16],
+            &crate::derive_unidentified_access_key(&PROFILE_KEY)
+        );
+    }
+
+    #[test]
+    fn commitment() {
+        let aci = Uuid::from_bytes([0x11; 16]);
+        let commitment = ProfileKeyCommitment::new(&PROFILE_KEY, aci);
+        assert!(commitment.verify(&PROFILE_KEY, aci));
+        assert!(!commitment.verify(&[0; PROFILE_KEY_LEN], aci));
+        assert!(!commitment.verify(&PROFILE_KEY, Uuid::from_bytes([0x22; 16])));
+
+        let round_tripped = ProfileKeyCommitment::from_bytes(*commitment.as_bytes());
+        assert!(round_tripped.verify(&PROFILE_KEY, aci));
+    }
+}
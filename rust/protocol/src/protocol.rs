@@ -3,11 +3,19 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use crate::ratchet::MessageKeys;
+use crate::redact::Redact;
+use crate::registration_id::is_valid_registration_id;
 use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
-use crate::{kem, proto, IdentityKey, PrivateKey, PublicKey, Result, SignalProtocolError};
+use crate::{
+    kem, proto, IdentityKey, IdentityKeyPair, PrivateKey, PublicKey, Result, SignalProtocolError,
+    Timestamp,
+};
 
 use std::convert::TryFrom;
 
+use aes_gcm_siv::aead::{AeadInPlace, NewAead};
+use aes_gcm_siv::Aes256GcmSiv;
 use hmac::{Hmac, Mac, NewMac};
 use prost::Message;
 use rand::{CryptoRng, Rng};
@@ -18,7 +26,51 @@ use uuid::Uuid;
 pub(crate) const CIPHERTEXT_MESSAGE_CURRENT_VERSION: u8 = 4;
 // Backward compatible, lacking Kyber keys, version
 pub(crate) const CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION: u8 = 3;
+// Like the current version, but with the ratchet header encrypted
+pub(crate) const CIPHERTEXT_MESSAGE_HEADER_ENCRYPTED_VERSION: u8 = 5;
+// Like the current version, but with the body sealed by AES-256-GCM-SIV instead of AES-CBC and a
+// truncated HMAC
+pub(crate) const CIPHERTEXT_MESSAGE_AEAD_VERSION: u8 = 6;
+// AEAD-sealed body and an encrypted ratchet header
+pub(crate) const CIPHERTEXT_MESSAGE_AEAD_HEADER_ENCRYPTED_VERSION: u8 = 7;
+const CIPHERTEXT_MESSAGE_NEWEST_VERSION: u8 = CIPHERTEXT_MESSAGE_AEAD_HEADER_ENCRYPTED_VERSION;
 pub(crate) const SENDERKEY_MESSAGE_CURRENT_VERSION: u8 = 3;
+const IDENTITY_ROTATION_CURRENT_VERSION: u8 = 1;
+
+// Used for domain separation between identity rotation signatures and other key-to-key signatures.
+const IDENTITY_ROTATION_SIGNATURE_PREFIX_1: &[u8] = &[0xFF; 32];
+const IDENTITY_ROTATION_SIGNATURE_PREFIX_2: &[u8] = b"Signal_IdentityRotation";
+
+// Used for domain separation between sender key distribution signatures and other signatures made
+// with an identity key.
+const SENDER_KEY_DISTRIBUTION_SIGNATURE_PREFIX_1: &[u8] = &[0xFF; 32];
+const SENDER_KEY_DISTRIBUTION_SIGNATURE_PREFIX_2: &[u8] = b"Signal_SenderKeyDistribution";
+
+/// The bytes that bind caller-supplied associated data into a MAC, tag, or signature.
+///
+/// This is empty when there is no associated data, so those messages are unchanged. Otherwise the
+/// data is followed by its length, so that it can't be confused with the end of the message.
+fn associated_data_suffix(associated_data: &[u8]) -> Vec<u8> {
+    if associated_data.is_empty() {
+        return Vec::new();
+    }
+    let mut suffix = Vec::with_capacity(associated_data.len() + 8);
+    suffix.extend_from_slice(associated_data);
+    suffix.extend_from_slice(&(associated_data.len() as u64).to_be_bytes());
+    suffix
+}
+
+/// Whether messages of this version carry an encrypted ratchet header.
+pub(crate) fn version_has_encrypted_header(message_version: u8) -> bool {
+    message_version == CIPHERTEXT_MESSAGE_HEADER_ENCRYPTED_VERSION
+        || message_version == CIPHERTEXT_MESSAGE_AEAD_HEADER_ENCRYPTED_VERSION
+}
+
+/// Whether messages of this version are sealed with AES-256-GCM-SIV rather than AES-CBC and
+/// HMAC-SHA256.
+pub(crate) fn version_uses_aead(message_version: u8) -> bool {
+    message_version >= CIPHERTEXT_MESSAGE_AEAD_VERSION
+}
 
 #[derive(Debug)]
 pub enum CiphertextMessage {
@@ -60,14 +112,88 @@ impl CiphertextMessage {
 #[derive(Debug, Clone)]
 pub struct SignalMessage {
     message_version: u8,
+    /// `None` if the header is encrypted and has not been decrypted yet.
+    header: Option<SignalMessageHeader>,
+    encrypted_header: Option<Box<[u8]>>,
+    ciphertext: Box<[u8]>,
+    serialized: Box<[u8]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SignalMessageHeader {
     sender_ratchet_key: PublicKey,
     counter: u32,
-    #[allow(dead_code)]
     previous_counter: u32,
-    ciphertext: Box<[u8]>,
-    serialized: Box<[u8]>,
 }
 
+impl SignalMessageHeader {
+    fn to_proto(&self) -> proto::wire::SignalMessage {
+        proto::wire::SignalMessage {
+            ratchet_key: Some(self.sender_ratchet_key.serialize().into_vec()),
+            counter: Some(self.counter),
+            previous_counter: Some(self.previous_counter),
+            ciphertext: None,
+            encrypted_header: None,
+        }
+    }
+
+    fn from_proto(proto_structure: &proto::wire::SignalMessage) -> Result<Self> {
+        let sender_ratchet_key = proto_structure
+            .ratchet_key
+            .as_ref()
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let sender_ratchet_key = PublicKey::deserialize(sender_ratchet_key)?;
+        let counter = proto_structure
+            .counter
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let previous_counter = proto_structure.previous_counter.unwrap_or(0);
+        Ok(Self {
+            sender_ratchet_key,
+            counter,
+            previous_counter,
+        })
+    }
+
+    // AES-GCM-SIV with a fixed nonce: each header key belongs to a single sending chain, and no
+    // two headers on a chain are the same because the counter differs. The version byte is
+    // authenticated so that a header can't be replayed in a message claiming another version.
+    fn encrypt(&self, header_key: &[u8; 32], message_version: u8) -> Vec<u8> {
+        let mut buffer = self.to_proto().encode_to_vec();
+        let tag = Aes256GcmSiv::new_from_slice(header_key)
+            .and_then(|cipher| {
+                cipher.encrypt_in_place_detached(
+                    &aes_gcm_siv::Nonce::default(),
+                    &[message_version],
+                    &mut buffer,
+                )
+            })
+            .expect("AES-GCM-SIV encryption should not fail with a 32-byte key");
+        buffer.extend_from_slice(&tag);
+        buffer
+    }
+
+    fn decrypt(encrypted: &[u8], header_key: &[u8; 32], message_version: u8) -> Option<Self> {
+        let tag_start = encrypted.len().checked_sub(HEADER_TAG_LENGTH)?;
+        let (ciphertext, tag) = encrypted.split_at(tag_start);
+        let mut buffer = ciphertext.to_vec();
+        Aes256GcmSiv::new_from_slice(header_key)
+            .and_then(|cipher| {
+                cipher.decrypt_in_place_detached(
+                    &aes_gcm_siv::Nonce::default(),
+                    &[message_version],
+                    &mut buffer,
+                    aes_gcm_siv::Tag::from_slice(tag),
+                )
+            })
+            .ok()?;
+        let proto_structure = proto::wire::SignalMessage::decode(buffer.as_slice()).ok()?;
+        Self::from_proto(&proto_structure).ok()
+    }
+}
+
+const HEADER_TAG_LENGTH: usize = 16;
+const AEAD_TAG_LENGTH: usize = 16;
+
 impl SignalMessage {
     const MAC_LENGTH: usize = 8;
 
@@ -81,49 +207,245 @@ impl SignalMessage {
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
     ) -> Result<Self> {
-        let message = proto::wire::SignalMessage {
-            ratchet_key: Some(sender_ratchet_key.serialize().into_vec()),
-            counter: Some(counter),
-            previous_counter: Some(previous_counter),
-            ciphertext: Some(Vec::<u8>::from(ciphertext)),
+        Self::new_impl(
+            message_version,
+            mac_key,
+            SignalMessageHeader {
+                sender_ratchet_key,
+                counter,
+                previous_counter,
+            },
+            None,
+            ciphertext,
+            sender_identity_key,
+            receiver_identity_key,
+            &[],
+        )
+    }
+
+    /// Like [`new`](Self::new), but binds `associated_data` into the MAC, and if `header_key` is
+    /// given, encrypts the ratchet key and counters with it instead of sending them in the clear.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_with_associated_data(
+        message_version: u8,
+        mac_key: &[u8],
+        header_key: Option<&[u8; 32]>,
+        sender_ratchet_key: PublicKey,
+        counter: u32,
+        previous_counter: u32,
+        ciphertext: &[u8],
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        associated_data: &[u8],
+    ) -> Result<Self> {
+        Self::new_impl(
+            message_version,
+            mac_key,
+            SignalMessageHeader {
+                sender_ratchet_key,
+                counter,
+                previous_counter,
+            },
+            header_key,
+            ciphertext,
+            sender_identity_key,
+            receiver_identity_key,
+            associated_data,
+        )
+    }
+
+    /// Like [`new_with_associated_data`](Self::new_with_associated_data), but for the AEAD message
+    /// versions: `plaintext` is sealed with AES-256-GCM-SIV under `message_keys`, and the full tag
+    /// takes the place of the truncated MAC.
+    ///
+    /// The tag covers both identity keys, the version byte, the header as sent, and
+    /// `associated_data`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_aead(
+        message_version: u8,
+        message_keys: &MessageKeys,
+        header_key: Option<&[u8; 32]>,
+        sender_ratchet_key: PublicKey,
+        counter: u32,
+        previous_counter: u32,
+        plaintext: &[u8],
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        associated_data: &[u8],
+    ) -> Result<Self> {
+        debug_assert!(version_uses_aead(message_version));
+        let header = SignalMessageHeader {
+            sender_ratchet_key,
+            counter,
+            previous_counter,
         };
-        let mut serialized = Vec::new();
-        serialized.reserve(1 + message.encoded_len() + Self::MAC_LENGTH);
-        serialized.push(((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
-        message
-            .encode(&mut serialized)
-            .expect("can always append to a buffer");
+        let encrypted_header = header_key.map(|key| header.encrypt(key, message_version));
+        let header_as_sent = encrypted_header
+            .clone()
+            .unwrap_or_else(|| header.to_proto().encode_to_vec());
+        let associated_data = Self::aead_associated_data(
+            message_version,
+            sender_identity_key,
+            receiver_identity_key,
+            &header_as_sent,
+            associated_data,
+        );
+
+        let mut ciphertext = plaintext.to_vec();
+        let tag = Aes256GcmSiv::new_from_slice(message_keys.cipher_key())
+            .and_then(|cipher| {
+                cipher.encrypt_in_place_detached(
+                    Self::aead_nonce(message_keys),
+                    &associated_data,
+                    &mut ciphertext,
+                )
+            })
+            .expect("AES-GCM-SIV encryption should not fail with a 32-byte key");
+
+        let mut serialized = Self::encode(
+            message_version,
+            &header,
+            encrypted_header.as_deref(),
+            &ciphertext,
+            AEAD_TAG_LENGTH,
+        );
+        serialized.extend_from_slice(&tag);
+        Ok(Self {
+            message_version,
+            header: Some(header),
+            encrypted_header: encrypted_header.map(Vec::into_boxed_slice),
+            ciphertext: ciphertext.into_boxed_slice(),
+            serialized: serialized.into_boxed_slice(),
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new_impl(
+        message_version: u8,
+        mac_key: &[u8],
+        header: SignalMessageHeader,
+        header_key: Option<&[u8; 32]>,
+        ciphertext: &[u8],
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        associated_data: &[u8],
+    ) -> Result<Self> {
+        let encrypted_header = header_key.map(|key| header.encrypt(key, message_version));
+        let mut serialized = Self::encode(
+            message_version,
+            &header,
+            encrypted_header.as_deref(),
+            ciphertext,
+            Self::MAC_LENGTH,
+        );
         let mac = Self::compute_mac(
             sender_identity_key,
             receiver_identity_key,
             mac_key,
             &serialized,
+            associated_data,
         )?;
         serialized.extend_from_slice(&mac);
         let serialized = serialized.into_boxed_slice();
         Ok(Self {
             message_version,
-            sender_ratchet_key,
-            counter,
-            previous_counter,
+            header: Some(header),
+            encrypted_header: encrypted_header.map(Vec::into_boxed_slice),
             ciphertext: ciphertext.into(),
             serialized,
         })
     }
 
+    /// The version byte followed by the message protobuf, with room left for a MAC or tag of
+    /// `trailer_length` bytes.
+    fn encode(
+        message_version: u8,
+        header: &SignalMessageHeader,
+        encrypted_header: Option<&[u8]>,
+        ciphertext: &[u8],
+        trailer_length: usize,
+    ) -> Vec<u8> {
+        let message = match encrypted_header {
+            None => proto::wire::SignalMessage {
+                ciphertext: Some(Vec::<u8>::from(ciphertext)),
+                ..header.to_proto()
+            },
+            Some(encrypted_header) => proto::wire::SignalMessage {
+                ciphertext: Some(Vec::<u8>::from(ciphertext)),
+                encrypted_header: Some(encrypted_header.to_vec()),
+                ..Default::default()
+            },
+        };
+        let mut serialized = Vec::with_capacity(1 + message.encoded_len() + trailer_length);
+        serialized.push(((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
+        message
+            .encode(&mut serialized)
+            .expect("can always append to a buffer");
+        serialized
+    }
+
+    /// The length of the MAC or tag at the end of a message of this version.
+    fn trailer_length(message_version: u8) -> usize {
+        if version_uses_aead(message_version) {
+            AEAD_TAG_LENGTH
+        } else {
+            Self::MAC_LENGTH
+        }
+    }
+
     #[inline]
     pub fn message_version(&self) -> u8 {
         self.message_version
     }
 
+    /// Whether the ratchet key and counters were sent encrypted (message versions 5 and 7).
     #[inline]
-    pub fn sender_ratchet_key(&self) -> &PublicKey {
-        &self.sender_ratchet_key
+    pub fn has_encrypted_header(&self) -> bool {
+        self.encrypted_header.is_some()
     }
 
+    /// The sender's current ratchet public key.
+    ///
+    /// Fails with [`SignalProtocolError::InvalidMessage`] if this message
+    /// [has an encrypted header](Self::has_encrypted_header) and was received rather than created
+    /// locally. Only the session the message belongs to can read it.
     #[inline]
-    pub fn counter(&self) -> u32 {
-        self.counter
+    pub fn sender_ratchet_key(&self) -> Result<&PublicKey> {
+        Ok(&self.header()?.sender_ratchet_key)
+    }
+
+    /// The message's index in its sending chain.
+    ///
+    /// Fails under the same conditions as [`sender_ratchet_key`](Self::sender_ratchet_key).
+    #[inline]
+    pub fn counter(&self) -> Result<u32> {
+        Ok(self.header()?.counter)
+    }
+
+    fn header(&self) -> Result<&SignalMessageHeader> {
+        self.header
+            .as_ref()
+            .ok_or(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Whisper,
+                "header is encrypted",
+            ))
+    }
+
+    /// Returns a copy of this message with its header decrypted using `header_key`, or `None` if
+    /// the header was not encrypted with that key.
+    ///
+    /// Messages without an encrypted header are returned as-is.
+    pub(crate) fn with_decrypted_header(&self, header_key: &[u8; 32]) -> Option<Self> {
+        let encrypted_header = match &self.encrypted_header {
+            Some(encrypted_header) => encrypted_header,
+            None => return Some(self.clone()),
+        };
+        let header =
+            SignalMessageHeader::decrypt(encrypted_header, header_key, self.message_version)?;
+        Some(Self {
+            header: Some(header),
+            ..self.clone()
+        })
     }
 
     #[inline]
@@ -136,17 +458,45 @@ impl SignalMessage {
         &self.ciphertext
     }
 
+    /// Checks the MAC on a message.
+    ///
+    /// Messages using one of the AEAD versions have no separate MAC; their tag is checked when
+    /// the body is decrypted, so they are rejected with
+    /// [`SignalProtocolError::InvalidArgument`].
     pub fn verify_mac(
         &self,
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
         mac_key: &[u8],
     ) -> Result<bool> {
+        self.verify_mac_with_associated_data(
+            sender_identity_key,
+            receiver_identity_key,
+            mac_key,
+            &[],
+        )
+    }
+
+    /// Like [`verify_mac`](Self::verify_mac), for a message whose sender bound `associated_data`
+    /// into the MAC.
+    pub fn verify_mac_with_associated_data(
+        &self,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        mac_key: &[u8],
+        associated_data: &[u8],
+    ) -> Result<bool> {
+        if version_uses_aead(self.message_version) {
+            return Err(SignalProtocolError::InvalidArgument(
+                "AEAD messages are authenticated during decryption".to_string(),
+            ));
+        }
         let our_mac = &Self::compute_mac(
             sender_identity_key,
             receiver_identity_key,
             mac_key,
             &self.serialized[..self.serialized.len() - Self::MAC_LENGTH],
+            associated_data,
         )?;
         let their_mac = &self.serialized[self.serialized.len() - Self::MAC_LENGTH..];
         let result: bool = our_mac.ct_eq(their_mac).into();
@@ -154,8 +504,8 @@ impl SignalMessage {
             // A warning instead of an error because we try multiple sessions.
             log::warn!(
                 "Bad Mac! Their Mac: {} Our Mac: {}",
-                hex::encode(their_mac),
-                hex::encode(our_mac)
+                Redact(their_mac),
+                Redact(our_mac)
             );
         }
         Ok(result)
@@ -166,6 +516,7 @@ impl SignalMessage {
         receiver_identity_key: &IdentityKey,
         mac_key: &[u8],
         message: &[u8],
+        associated_data: &[u8],
     ) -> Result<[u8; Self::MAC_LENGTH]> {
         if mac_key.len() != 32 {
             return Err(SignalProtocolError::InvalidMacKeyLength(mac_key.len()));
@@ -176,10 +527,67 @@ impl SignalMessage {
         mac.update(sender_identity_key.public_key().serialize().as_ref());
         mac.update(receiver_identity_key.public_key().serialize().as_ref());
         mac.update(message);
+        mac.update(&associated_data_suffix(associated_data));
         let mut result = [0u8; Self::MAC_LENGTH];
         result.copy_from_slice(&mac.finalize().into_bytes()[..Self::MAC_LENGTH]);
         Ok(result)
     }
+
+    /// Opens the body of a message using one of the AEAD versions, returning `None` if it was not
+    /// sealed with these keys between these identities, or with different associated data.
+    pub(crate) fn decrypt_aead(
+        &self,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        message_keys: &MessageKeys,
+        associated_data: &[u8],
+    ) -> Option<Vec<u8>> {
+        debug_assert!(version_uses_aead(self.message_version));
+        let header_as_sent = match &self.encrypted_header {
+            Some(encrypted_header) => encrypted_header.to_vec(),
+            None => self.header().ok()?.to_proto().encode_to_vec(),
+        };
+        let associated_data = Self::aead_associated_data(
+            self.message_version,
+            sender_identity_key,
+            receiver_identity_key,
+            &header_as_sent,
+            associated_data,
+        );
+        let tag = &self.serialized[self.serialized.len() - AEAD_TAG_LENGTH..];
+        let mut buffer = self.ciphertext.to_vec();
+        Aes256GcmSiv::new_from_slice(message_keys.cipher_key())
+            .and_then(|cipher| {
+                cipher.decrypt_in_place_detached(
+                    Self::aead_nonce(message_keys),
+                    &associated_data,
+                    &mut buffer,
+                    aes_gcm_siv::Tag::from_slice(tag),
+                )
+            })
+            .ok()?;
+        Some(buffer)
+    }
+
+    fn aead_nonce(message_keys: &MessageKeys) -> &aes_gcm_siv::Nonce {
+        aes_gcm_siv::Nonce::from_slice(&message_keys.iv()[..12])
+    }
+
+    fn aead_associated_data(
+        message_version: u8,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        header_as_sent: &[u8],
+        caller_associated_data: &[u8],
+    ) -> Vec<u8> {
+        let mut associated_data = Vec::new();
+        associated_data.extend_from_slice(&sender_identity_key.public_key().serialize());
+        associated_data.extend_from_slice(&receiver_identity_key.public_key().serialize());
+        associated_data.push(((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
+        associated_data.extend_from_slice(header_as_sent);
+        associated_data.extend_from_slice(&associated_data_suffix(caller_associated_data));
+        associated_data
+    }
 }
 
 impl AsRef<[u8]> for SignalMessage {
@@ -201,24 +609,34 @@ impl TryFrom<&[u8]> for SignalMessage {
                 message_version,
             ));
         }
-        if message_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        if message_version > CIPHERTEXT_MESSAGE_NEWEST_VERSION {
             return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
                 message_version,
             ));
         }
+        let trailer_length = SignalMessage::trailer_length(message_version);
+        if value.len() < trailer_length + 1 {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
 
         let proto_structure =
-            proto::wire::SignalMessage::decode(&value[1..value.len() - SignalMessage::MAC_LENGTH])
+            proto::wire::SignalMessage::decode(&value[1..value.len() - trailer_length])
                 .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
 
-        let sender_ratchet_key = proto_structure
-            .ratchet_key
-            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
-        let sender_ratchet_key = PublicKey::deserialize(&sender_ratchet_key)?;
-        let counter = proto_structure
-            .counter
-            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
-        let previous_counter = proto_structure.previous_counter.unwrap_or(0);
+        let (header, encrypted_header) = if version_has_encrypted_header(message_version) {
+            if proto_structure.ratchet_key.is_some() || proto_structure.counter.is_some() {
+                return Err(SignalProtocolError::InvalidProtobufEncoding);
+            }
+            let encrypted_header = proto_structure
+                .encrypted_header
+                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+            (None, Some(encrypted_header.into_boxed_slice()))
+        } else {
+            (
+                Some(SignalMessageHeader::from_proto(&proto_structure)?),
+                None,
+            )
+        };
         let ciphertext = proto_structure
             .ciphertext
             .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
@@ -226,9 +644,8 @@ impl TryFrom<&[u8]> for SignalMessage {
 
         Ok(SignalMessage {
             message_version,
-            sender_ratchet_key,
-            counter,
-            previous_counter,
+            header,
+            encrypted_header,
             ciphertext,
             serialized: Box::from(value),
         })
@@ -260,6 +677,7 @@ pub struct PreKeySignalMessage {
     base_key: PublicKey,
     identity_key: IdentityKey,
     message: SignalMessage,
+    alternate_identity_signature: Option<Box<[u8]>>,
     serialized: Box<[u8]>,
 }
 
@@ -275,35 +693,67 @@ impl PreKeySignalMessage {
         identity_key: IdentityKey,
         message: SignalMessage,
     ) -> Result<Self> {
+        if !is_valid_registration_id(registration_id) {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "registration id {} is out of range",
+                registration_id
+            )));
+        }
+
+        let mut result = Self {
+            message_version,
+            registration_id,
+            pre_key_id,
+            signed_pre_key_id,
+            kyber_payload,
+            base_key,
+            identity_key,
+            message,
+            alternate_identity_signature: None,
+            serialized: Box::default(),
+        };
+        result.serialized = result.encode();
+        Ok(result)
+    }
+
+    /// Attaches a signature by the sender's identity key over an alternate identity, as produced
+    /// by [`IdentityKeyPair::sign_alternate_identity`][crate::IdentityKeyPair::sign_alternate_identity].
+    ///
+    /// Replaces any signature the message already had.
+    pub fn with_alternate_identity_signature(mut self, signature: Box<[u8]>) -> Self {
+        self.alternate_identity_signature = Some(signature);
+        self.serialized = self.encode();
+        self
+    }
+
+    fn encode(&self) -> Box<[u8]> {
         let proto_message = proto::wire::PreKeySignalMessage {
-            registration_id: Some(registration_id),
-            pre_key_id: pre_key_id.map(|id| id.into()),
-            signed_pre_key_id: Some(signed_pre_key_id.into()),
-            kyber_pre_key_id: kyber_payload.as_ref().map(|kyber| kyber.pre_key_id.into()),
-            kyber_ciphertext: kyber_payload
+            registration_id: Some(self.registration_id),
+            pre_key_id: self.pre_key_id.map(|id| id.into()),
+            signed_pre_key_id: Some(self.signed_pre_key_id.into()),
+            kyber_pre_key_id: self
+                .kyber_payload
+                .as_ref()
+                .map(|kyber| kyber.pre_key_id.into()),
+            kyber_ciphertext: self
+                .kyber_payload
                 .as_ref()
                 .map(|kyber| kyber.ciphertext.to_vec()),
-            base_key: Some(base_key.serialize().into_vec()),
-            identity_key: Some(identity_key.serialize().into_vec()),
-            message: Some(Vec::from(message.as_ref())),
+            base_key: Some(self.base_key.serialize().into_vec()),
+            identity_key: Some(self.identity_key.serialize().into_vec()),
+            message: Some(Vec::from(self.message.as_ref())),
+            alternate_identity_signature: self
+                .alternate_identity_signature
+                .as_ref()
+                .map(|signature| signature.to_vec()),
         };
         let mut serialized = Vec::new();
         serialized.reserve(1 + proto_message.encoded_len());
-        serialized.push(((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
+        serialized.push(((self.message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION);
         proto_message
             .encode(&mut serialized)
             .expect("can always append to a Vec");
-        Ok(Self {
-            message_version,
-            registration_id,
-            pre_key_id,
-            signed_pre_key_id,
-            kyber_payload,
-            base_key,
-            identity_key,
-            message,
-            serialized: serialized.into_boxed_slice(),
-        })
+        serialized.into_boxed_slice()
     }
 
     #[inline]
@@ -351,6 +801,26 @@ impl PreKeySignalMessage {
         &self.message
     }
 
+    /// A signature by [`Self::identity_key`] attesting to an alternate identity of the sender,
+    /// as produced by [`IdentityKeyPair::sign_alternate_identity`][crate::IdentityKeyPair::sign_alternate_identity].
+    #[inline]
+    pub fn alternate_identity_signature(&self) -> Option<&[u8]> {
+        self.alternate_identity_signature.as_deref()
+    }
+
+    /// Check that the sender's identity key has signed off on `alternate` as another identity for
+    /// the same user (e.g. an ACI identity vouching for a PNI identity).
+    ///
+    /// Returns `Ok(false)` if the message does not carry an alternate identity signature.
+    pub fn verify_alternate_identity(&self, alternate: &IdentityKey) -> Result<bool> {
+        match &self.alternate_identity_signature {
+            Some(signature) => self
+                .identity_key
+                .verify_alternate_identity(alternate, signature),
+            None => Ok(false),
+        }
+    }
+
     #[inline]
     pub fn serialized(&self) -> &[u8] {
         &self.serialized
@@ -377,7 +847,7 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
                 message_version,
             ));
         }
-        if message_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        if message_version > CIPHERTEXT_MESSAGE_NEWEST_VERSION {
             return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
                 message_version,
             ));
@@ -399,6 +869,14 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
             .signed_pre_key_id
             .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
 
+        let registration_id = proto_structure.registration_id.unwrap_or(0);
+        if !is_valid_registration_id(registration_id) {
+            return Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::PreKey,
+                "registration id out of range",
+            ));
+        }
+
         let base_key = PublicKey::deserialize(base_key.as_ref())?;
 
         let kyber_payload = match (
@@ -423,13 +901,16 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
 
         Ok(PreKeySignalMessage {
             message_version,
-            registration_id: proto_structure.registration_id.unwrap_or(0),
+            registration_id,
             pre_key_id: proto_structure.pre_key_id.map(|id| id.into()),
             signed_pre_key_id: signed_pre_key_id.into(),
             kyber_payload,
             base_key,
             identity_key: IdentityKey::try_from(identity_key.as_ref())?,
             message: SignalMessage::try_from(message.as_ref())?,
+            alternate_identity_signature: proto_structure
+                .alternate_identity_signature
+                .map(Vec::into_boxed_slice),
             serialized: Box::from(value),
         })
     }
@@ -456,6 +937,30 @@ impl SenderKeyMessage {
         ciphertext: Box<[u8]>,
         csprng: &mut R,
         signature_key: &PrivateKey,
+    ) -> Result<Self> {
+        Self::new_with_associated_data(
+            message_version,
+            distribution_id,
+            chain_id,
+            iteration,
+            ciphertext,
+            &[],
+            csprng,
+            signature_key,
+        )
+    }
+
+    /// Like [`new`](Self::new), but binds `associated_data` into the signature.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_with_associated_data<R: CryptoRng + Rng>(
+        message_version: u8,
+        distribution_id: Uuid,
+        chain_id: u32,
+        iteration: u32,
+        ciphertext: Box<[u8]>,
+        associated_data: &[u8],
+        csprng: &mut R,
+        signature_key: &PrivateKey,
     ) -> Result<Self> {
         let proto_message = proto::wire::SenderKeyMessage {
             distribution_uuid: Some(distribution_id.as_bytes().to_vec()),
@@ -470,7 +975,10 @@ impl SenderKeyMessage {
         proto_message
             .encode(&mut serialized)
             .expect("can always append to a buffer");
-        let signature = signature_key.calculate_signature(&serialized, csprng)?;
+        let signature = signature_key.calculate_signature_for_multipart_message(
+            &[&serialized, &associated_data_suffix(associated_data)],
+            csprng,
+        )?;
         serialized.extend_from_slice(&signature[..]);
         Ok(Self {
             message_version: SENDERKEY_MESSAGE_CURRENT_VERSION,
@@ -483,8 +991,21 @@ impl SenderKeyMessage {
     }
 
     pub fn verify_signature(&self, signature_key: &PublicKey) -> Result<bool> {
-        let valid = signature_key.verify_signature(
-            &self.serialized[..self.serialized.len() - Self::SIGNATURE_LEN],
+        self.verify_signature_with_associated_data(signature_key, &[])
+    }
+
+    /// Like [`verify_signature`](Self::verify_signature), for a message whose sender bound
+    /// `associated_data` into the signature.
+    pub fn verify_signature_with_associated_data(
+        &self,
+        signature_key: &PublicKey,
+        associated_data: &[u8],
+    ) -> Result<bool> {
+        let valid = signature_key.verify_signature_for_multipart_message(
+            &[
+                &self.serialized[..self.serialized.len() - Self::SIGNATURE_LEN],
+                &associated_data_suffix(associated_data),
+            ],
             &self.serialized[self.serialized.len() - Self::SIGNATURE_LEN..],
         )?;
 
@@ -584,6 +1105,7 @@ pub struct SenderKeyDistributionMessage {
     iteration: u32,
     chain_key: Vec<u8>,
     signing_key: PublicKey,
+    identity_signature: Option<Box<[u8]>>,
     serialized: Box<[u8]>,
 }
 
@@ -596,29 +1118,98 @@ impl SenderKeyDistributionMessage {
         chain_key: Vec<u8>,
         signing_key: PublicKey,
     ) -> Result<Self> {
+        let mut result = Self {
+            message_version,
+            distribution_id,
+            chain_id,
+            iteration,
+            chain_key,
+            signing_key,
+            identity_signature: None,
+            serialized: Box::default(),
+        };
+        result.serialized = result.serialize();
+        Ok(result)
+    }
+
+    /// Sign this message with the sender's identity key.
+    ///
+    /// The chain signing key only shows that later messages come from whoever sent this one. An
+    /// identity signature lets the receiver also check which account sent it; see
+    /// [Self::verify_identity_signature].
+    pub fn with_identity_signature<R: CryptoRng + Rng>(
+        mut self,
+        identity: &IdentityKeyPair,
+        csprng: &mut R,
+    ) -> Result<Self> {
+        let signature = identity
+            .private_key()
+            .calculate_signature_for_multipart_message(
+                &[
+                    SENDER_KEY_DISTRIBUTION_SIGNATURE_PREFIX_1,
+                    SENDER_KEY_DISTRIBUTION_SIGNATURE_PREFIX_2,
+                    &self.signed_content(),
+                ],
+                csprng,
+            )?;
+        self.identity_signature = Some(signature);
+        self.serialized = self.serialize();
+        Ok(self)
+    }
+
+    fn serialize(&self) -> Box<[u8]> {
         let proto_message = proto::wire::SenderKeyDistributionMessage {
-            distribution_uuid: Some(distribution_id.as_bytes().to_vec()),
-            chain_id: Some(chain_id),
-            iteration: Some(iteration),
-            chain_key: Some(chain_key.clone()),
-            signing_key: Some(signing_key.serialize().to_vec()),
+            distribution_uuid: Some(self.distribution_id.as_bytes().to_vec()),
+            chain_id: Some(self.chain_id),
+            iteration: Some(self.iteration),
+            chain_key: Some(self.chain_key.clone()),
+            signing_key: Some(self.signing_key.serialize().to_vec()),
+            identity_signature: self.identity_signature.as_ref().map(|s| s.to_vec()),
         };
         let mut serialized = Vec::new();
         serialized.reserve(1 + proto_message.encoded_len());
-        serialized.push(((message_version & 0xF) << 4) | SENDERKEY_MESSAGE_CURRENT_VERSION);
+        serialized.push(((self.message_version & 0xF) << 4) | SENDERKEY_MESSAGE_CURRENT_VERSION);
         proto_message
             .encode(&mut serialized)
             .expect("can always append to a buffer");
+        serialized.into_boxed_slice()
+    }
 
-        Ok(Self {
-            message_version,
-            distribution_id,
-            chain_id,
-            iteration,
-            chain_key,
-            signing_key,
-            serialized: serialized.into_boxed_slice(),
-        })
+    fn signed_content(&self) -> Vec<u8> {
+        let signing_key = self.signing_key.serialize();
+        let mut content =
+            Vec::with_capacity(1 + 16 + 4 + 4 + self.chain_key.len() + signing_key.len());
+        content.push(self.message_version);
+        content.extend_from_slice(self.distribution_id.as_bytes());
+        content.extend_from_slice(&self.chain_id.to_be_bytes());
+        content.extend_from_slice(&self.iteration.to_be_bytes());
+        content.extend_from_slice(&self.chain_key);
+        content.extend_from_slice(&signing_key);
+        content
+    }
+
+    /// Check the signature added by [Self::with_identity_signature] against `identity_key`.
+    ///
+    /// Returns `false` if the message has no identity signature. When the message arrived through
+    /// sealed sender, `identity_key` should come from the sender certificate (see
+    /// [process_attributed_sender_key_distribution_message]).
+    ///
+    /// [process_attributed_sender_key_distribution_message]:
+    ///     crate::process_attributed_sender_key_distribution_message
+    pub fn verify_identity_signature(&self, identity_key: &IdentityKey) -> Result<bool> {
+        match &self.identity_signature {
+            None => Ok(false),
+            Some(signature) => identity_key
+                .public_key()
+                .verify_signature_for_multipart_message(
+                    &[
+                        SENDER_KEY_DISTRIBUTION_SIGNATURE_PREFIX_1,
+                        SENDER_KEY_DISTRIBUTION_SIGNATURE_PREFIX_2,
+                        &self.signed_content(),
+                    ],
+                    signature,
+                ),
+        }
     }
 
     #[inline]
@@ -651,6 +1242,12 @@ impl SenderKeyDistributionMessage {
         Ok(&self.signing_key)
     }
 
+    /// The signature added by [Self::with_identity_signature], if any.
+    #[inline]
+    pub fn identity_signature(&self) -> Option<&[u8]> {
+        self.identity_signature.as_deref()
+    }
+
     #[inline]
     pub fn serialized(&self) -> &[u8] {
         &self.serialized
@@ -718,11 +1315,149 @@ impl TryFrom<&[u8]> for SenderKeyDistributionMessage {
             iteration,
             chain_key,
             signing_key,
+            identity_signature: proto_structure
+                .identity_signature
+                .map(Vec::into_boxed_slice),
             serialized: Box::from(value),
         })
     }
 }
 
+/// A statement, signed by a user's old identity key, that they have moved to a new one.
+///
+/// Send this to peers before switching identities; a peer that still trusts the old key can
+/// accept the new one with [IdentityKeyStore::apply_identity_rotation] instead of asking the user
+/// to verify safety numbers again. A rotation can only be deserialized if its signature is valid.
+///
+/// [IdentityKeyStore::apply_identity_rotation]: crate::IdentityKeyStore::apply_identity_rotation
+#[derive(Debug, Clone)]
+pub struct IdentityRotation {
+    old_identity_key: IdentityKey,
+    new_identity_key: IdentityKey,
+    signature: Box<[u8]>,
+    serialized: Box<[u8]>,
+}
+
+impl IdentityRotation {
+    /// Vouch for `new_identity_key` as the replacement for `old_identity`.
+    pub fn new<R: CryptoRng + Rng>(
+        old_identity: &IdentityKeyPair,
+        new_identity_key: &IdentityKey,
+        csprng: &mut R,
+    ) -> Result<Self> {
+        let old_identity_key = *old_identity.identity_key();
+        let signature = old_identity
+            .private_key()
+            .calculate_signature_for_multipart_message(
+                &[
+                    IDENTITY_ROTATION_SIGNATURE_PREFIX_1,
+                    IDENTITY_ROTATION_SIGNATURE_PREFIX_2,
+                    &old_identity_key.serialize(),
+                    &new_identity_key.serialize(),
+                ],
+                csprng,
+            )?;
+
+        let proto_message = proto::wire::IdentityRotation {
+            old_identity_key: Some(old_identity_key.serialize().into_vec()),
+            new_identity_key: Some(new_identity_key.serialize().into_vec()),
+            signature: Some(signature.to_vec()),
+        };
+        let mut serialized = Vec::with_capacity(1 + proto_message.encoded_len());
+        serialized.push(IDENTITY_ROTATION_CURRENT_VERSION);
+        proto_message
+            .encode(&mut serialized)
+            .expect("can always append to a buffer");
+
+        Ok(Self {
+            old_identity_key,
+            new_identity_key: *new_identity_key,
+            signature,
+            serialized: serialized.into_boxed_slice(),
+        })
+    }
+
+    fn verify_signature(&self) -> Result<bool> {
+        self.old_identity_key
+            .public_key()
+            .verify_signature_for_multipart_message(
+                &[
+                    IDENTITY_ROTATION_SIGNATURE_PREFIX_1,
+                    IDENTITY_ROTATION_SIGNATURE_PREFIX_2,
+                    &self.old_identity_key.serialize(),
+                    &self.new_identity_key.serialize(),
+                ],
+                &self.signature,
+            )
+    }
+
+    /// The identity being retired, which signed this statement.
+    #[inline]
+    pub fn old_identity_key(&self) -> &IdentityKey {
+        &self.old_identity_key
+    }
+
+    /// The identity that replaces [old_identity_key](Self::old_identity_key).
+    #[inline]
+    pub fn new_identity_key(&self) -> &IdentityKey {
+        &self.new_identity_key
+    }
+
+    #[inline]
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+
+    #[inline]
+    pub fn serialized(&self) -> &[u8] {
+        &self.serialized
+    }
+}
+
+impl AsRef<[u8]> for IdentityRotation {
+    fn as_ref(&self) -> &[u8] {
+        &self.serialized
+    }
+}
+
+impl TryFrom<&[u8]> for IdentityRotation {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let (&version, proto_bytes) = value
+            .split_first()
+            .ok_or(SignalProtocolError::CiphertextMessageTooShort(0))?;
+        if version != IDENTITY_ROTATION_CURRENT_VERSION {
+            return Err(SignalProtocolError::UnrecognizedMessageVersion(
+                version.into(),
+            ));
+        }
+
+        let proto_structure = proto::wire::IdentityRotation::decode(proto_bytes)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+        let old_identity_key = proto_structure
+            .old_identity_key
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let new_identity_key = proto_structure
+            .new_identity_key
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let signature = proto_structure
+            .signature
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+
+        let rotation = Self {
+            old_identity_key: IdentityKey::decode(&old_identity_key)?,
+            new_identity_key: IdentityKey::decode(&new_identity_key)?,
+            signature: signature.into_boxed_slice(),
+            serialized: Box::from(value),
+        };
+        if !rotation.verify_signature()? {
+            return Err(SignalProtocolError::SignatureValidationFailed);
+        }
+        Ok(rotation)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PlaintextContent {
     serialized: Box<[u8]>,
@@ -790,7 +1525,7 @@ impl TryFrom<&[u8]> for PlaintextContent {
 #[derive(Debug, Clone)]
 pub struct DecryptionErrorMessage {
     ratchet_key: Option<PublicKey>,
-    timestamp: u64,
+    timestamp: Timestamp,
     device_id: u32,
     serialized: Box<[u8]>,
 }
@@ -799,18 +1534,20 @@ impl DecryptionErrorMessage {
     pub fn for_original(
         original_bytes: &[u8],
         original_type: CiphertextMessageType,
-        original_timestamp: u64,
+        original_timestamp: Timestamp,
         original_sender_device_id: u32,
     ) -> Result<Self> {
+        // Messages with encrypted headers don't reveal their ratchet key.
+        fn ratchet_key_if_visible(message: &SignalMessage) -> Option<PublicKey> {
+            message.sender_ratchet_key().ok().copied()
+        }
         let ratchet_key = match original_type {
             CiphertextMessageType::Whisper => {
-                Some(*SignalMessage::try_from(original_bytes)?.sender_ratchet_key())
+                ratchet_key_if_visible(&SignalMessage::try_from(original_bytes)?)
+            }
+            CiphertextMessageType::PreKey => {
+                ratchet_key_if_visible(PreKeySignalMessage::try_from(original_bytes)?.message())
             }
-            CiphertextMessageType::PreKey => Some(
-                *PreKeySignalMessage::try_from(original_bytes)?
-                    .message()
-                    .sender_ratchet_key(),
-            ),
             CiphertextMessageType::SenderKey => None,
             CiphertextMessageType::Plaintext => {
                 return Err(SignalProtocolError::InvalidArgument(
@@ -819,23 +1556,35 @@ impl DecryptionErrorMessage {
             }
         };
 
+        Ok(Self::new(
+            ratchet_key,
+            original_timestamp,
+            original_sender_device_id,
+        ))
+    }
+
+    pub(crate) fn new(
+        ratchet_key: Option<PublicKey>,
+        timestamp: Timestamp,
+        device_id: u32,
+    ) -> Self {
         let proto_message = proto::service::DecryptionErrorMessage {
-            timestamp: Some(original_timestamp),
+            timestamp: Some(timestamp.epoch_millis()),
             ratchet_key: ratchet_key.map(|k| k.serialize().into()),
-            device_id: Some(original_sender_device_id),
+            device_id: Some(device_id),
         };
         let serialized = proto_message.encode_to_vec();
 
-        Ok(Self {
+        Self {
             ratchet_key,
-            timestamp: original_timestamp,
-            device_id: original_sender_device_id,
+            timestamp,
+            device_id,
             serialized: serialized.into_boxed_slice(),
-        })
+        }
     }
 
     #[inline]
-    pub fn timestamp(&self) -> u64 {
+    pub fn timestamp(&self) -> Timestamp {
         self.timestamp
     }
 
@@ -863,6 +1612,7 @@ impl TryFrom<&[u8]> for DecryptionErrorMessage {
             .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
         let timestamp = proto_structure
             .timestamp
+            .map(Timestamp::from_epoch_millis)
             .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
         let ratchet_key = proto_structure
             .ratchet_key
@@ -901,7 +1651,8 @@ pub fn extract_decryption_error_message_from_serialized_content(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::KeyPair;
+    use crate::consts::MAX_REGISTRATION_ID;
+    use crate::{IdentityKeyPair, KeyPair};
 
     use rand::rngs::OsRng;
     use rand::{CryptoRng, Rng};
@@ -936,9 +1687,8 @@ mod tests {
 
     fn assert_signal_message_equals(m1: &SignalMessage, m2: &SignalMessage) {
         assert_eq!(m1.message_version, m2.message_version);
-        assert_eq!(m1.sender_ratchet_key, m2.sender_ratchet_key);
-        assert_eq!(m1.counter, m2.counter);
-        assert_eq!(m1.previous_counter, m2.previous_counter);
+        assert_eq!(m1.header, m2.header);
+        assert_eq!(m1.encrypted_header, m2.encrypted_header);
         assert_eq!(m1.ciphertext, m2.ciphertext);
         assert_eq!(m1.serialized, m2.serialized);
     }
@@ -953,6 +1703,60 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_signal_message_encrypted_header() -> Result<()> {
+        let mut csprng = OsRng;
+        let mac_key: [u8; 32] = csprng.gen();
+        let header_key: [u8; 32] = csprng.gen();
+
+        let sender_ratchet_key_pair = KeyPair::generate(&mut csprng);
+        let sender_identity_key_pair = KeyPair::generate(&mut csprng);
+        let receiver_identity_key_pair = KeyPair::generate(&mut csprng);
+
+        let message = SignalMessage::new_with_associated_data(
+            CIPHERTEXT_MESSAGE_HEADER_ENCRYPTED_VERSION,
+            &mac_key,
+            Some(&header_key),
+            sender_ratchet_key_pair.public_key,
+            42,
+            41,
+            b"ciphertext",
+            &sender_identity_key_pair.public_key.into(),
+            &receiver_identity_key_pair.public_key.into(),
+            &[],
+        )?;
+        assert!(message.has_encrypted_header());
+        let ratchet_key_bytes = sender_ratchet_key_pair.public_key.serialize();
+        assert!(!message
+            .serialized()
+            .windows(ratchet_key_bytes.len())
+            .any(|w| w == &ratchet_key_bytes[..]));
+
+        let deser_message = SignalMessage::try_from(message.as_ref())?;
+        assert!(deser_message.has_encrypted_header());
+        assert_eq!(deser_message.serialized(), message.serialized());
+        assert!(matches!(
+            deser_message.sender_ratchet_key(),
+            Err(SignalProtocolError::InvalidMessage(..))
+        ));
+        assert!(deser_message.counter().is_err());
+
+        let opened = deser_message
+            .with_decrypted_header(&header_key)
+            .expect("correct header key");
+        assert_eq!(
+            opened.sender_ratchet_key()?,
+            &sender_ratchet_key_pair.public_key
+        );
+        assert_eq!(opened.counter()?, 42);
+        assert_eq!(opened.serialized(), message.serialized());
+
+        let mut wrong_key = header_key;
+        wrong_key[0] ^= 1;
+        assert!(deser_message.with_decrypted_header(&wrong_key).is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_pre_key_signal_message_serialize_deserialize() -> Result<()> {
         let mut csprng = OsRng;
@@ -1007,6 +1811,83 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_pre_key_signal_message_registration_id_range() -> Result<()> {
+        let mut csprng = OsRng;
+        let identity_key_pair = KeyPair::generate(&mut csprng);
+        let base_key_pair = KeyPair::generate(&mut csprng);
+        let new_message = |registration_id| -> Result<PreKeySignalMessage> {
+            PreKeySignalMessage::new(
+                3,
+                registration_id,
+                None,
+                97.into(),
+                None,
+                base_key_pair.public_key,
+                identity_key_pair.public_key.into(),
+                create_signal_message(&mut OsRng)?,
+            )
+        };
+
+        assert!(matches!(
+            new_message(MAX_REGISTRATION_ID + 1),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        // Patch the encoded id of a valid message, as a misbehaving client might.
+        let message = new_message(MAX_REGISTRATION_ID)?;
+        let mut proto_structure =
+            proto::wire::PreKeySignalMessage::decode(&message.serialized[1..])
+                .expect("valid protobuf");
+        proto_structure.registration_id = Some(MAX_REGISTRATION_ID + 1);
+        let mut serialized = vec![message.serialized[0]];
+        proto_structure
+            .encode(&mut serialized)
+            .expect("can always append to a Vec");
+        assert!(matches!(
+            PreKeySignalMessage::try_from(serialized.as_slice()),
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::PreKey,
+                _
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pre_key_signal_message_alternate_identity_signature() -> Result<()> {
+        let mut csprng = OsRng;
+        let identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+        let alternate_identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+        let base_key_pair = KeyPair::generate(&mut csprng);
+        let message = create_signal_message(&mut csprng)?;
+        let signature = identity_key_pair
+            .sign_alternate_identity(alternate_identity_key_pair.identity_key(), &mut csprng)?;
+        let pre_key_signal_message = PreKeySignalMessage::new(
+            3,
+            365,
+            None,
+            97.into(),
+            None,
+            base_key_pair.public_key,
+            *identity_key_pair.identity_key(),
+            message,
+        )?
+        .with_alternate_identity_signature(signature.clone());
+        let deser_pre_key_signal_message =
+            PreKeySignalMessage::try_from(pre_key_signal_message.as_ref())
+                .expect("should deserialize without error");
+        assert_eq!(
+            deser_pre_key_signal_message.alternate_identity_signature(),
+            Some(&signature[..])
+        );
+        assert!(deser_pre_key_signal_message
+            .verify_alternate_identity(alternate_identity_key_pair.identity_key())?);
+        assert!(!deser_pre_key_signal_message
+            .verify_alternate_identity(identity_key_pair.identity_key())?);
+        Ok(())
+    }
+
     #[test]
     fn test_sender_key_message_serialize_deserialize() -> Result<()> {
         let mut csprng = OsRng;
@@ -1051,7 +1932,7 @@ mod tests {
         let identity_key_pair = KeyPair::generate(&mut csprng);
         let base_key_pair = KeyPair::generate(&mut csprng);
         let message = create_signal_message(&mut csprng)?;
-        let timestamp = 0x2_0000_0001;
+        let timestamp = Timestamp::from_epoch_millis(0x2_0000_0001);
         let device_id = 0x8086_2021;
 
         {
@@ -1064,7 +1945,7 @@ mod tests {
             let error_message = DecryptionErrorMessage::try_from(error_message.serialized())?;
             assert_eq!(
                 error_message.ratchet_key(),
-                Some(message.sender_ratchet_key())
+                Some(message.sender_ratchet_key()?)
             );
             assert_eq!(error_message.timestamp(), timestamp);
             assert_eq!(error_message.device_id(), device_id);
@@ -1091,7 +1972,7 @@ mod tests {
             let error_message = DecryptionErrorMessage::try_from(error_message.serialized())?;
             assert_eq!(
                 error_message.ratchet_key(),
-                Some(pre_key_signal_message.message().sender_ratchet_key())
+                Some(pre_key_signal_message.message().sender_ratchet_key()?)
             );
             assert_eq!(error_message.timestamp(), timestamp);
             assert_eq!(error_message.device_id(), device_id);
@@ -1126,7 +2007,12 @@ mod tests {
     #[test]
     fn test_decryption_error_message_for_plaintext() {
         assert!(matches!(
-            DecryptionErrorMessage::for_original(&[], CiphertextMessageType::Plaintext, 5, 7),
+            DecryptionErrorMessage::for_original(
+                &[],
+                CiphertextMessageType::Plaintext,
+                Timestamp::from_epoch_millis(5),
+                7
+            ),
             Err(SignalProtocolError::InvalidArgument(_))
         ));
     }
@@ -10,9 +10,13 @@ use std::convert::TryInto;
 
 pub(crate) use self::keys::{ChainKey, MessageKeys, RootKey};
 pub use self::params::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
-use crate::protocol::{CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION};
+use crate::protocol::{
+    CIPHERTEXT_MESSAGE_AEAD_HEADER_ENCRYPTED_VERSION, CIPHERTEXT_MESSAGE_AEAD_VERSION,
+    CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_HEADER_ENCRYPTED_VERSION,
+    CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION,
+};
 use crate::state::SessionState;
-use crate::{KeyPair, Result, SessionRecord};
+use crate::{KeyPair, Result, SessionRecord, SessionRole, SignalProtocolError};
 use rand::{CryptoRng, Rng};
 
 fn derive_keys(has_kyber: bool, secret_input: &[u8]) -> (RootKey, ChainKey) {
@@ -24,8 +28,42 @@ fn derive_keys(has_kyber: bool, secret_input: &[u8]) -> (RootKey, ChainKey) {
     derive_keys_with_label(label, secret_input)
 }
 
-fn message_version(has_kyber: bool) -> u8 {
-    if has_kyber {
+/// Like [derive_keys], but for header-encrypted sessions, which are always Kyber-aware.
+///
+/// Also returns the header key for the initial chain.
+fn derive_header_encrypted_keys(secret_input: &[u8]) -> (RootKey, ChainKey, [u8; 32]) {
+    let mut secrets = [0; 96];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, secret_input)
+        .expand(
+            b"WhisperText_X25519_SHA-256_CRYSTALS-KYBER-1024_HeaderEncryption",
+            &mut secrets,
+        )
+        .expect("valid length");
+
+    let root_key = RootKey::new(secrets[..32].try_into().expect("correct length"));
+    let chain_key = ChainKey::new(secrets[32..64].try_into().expect("correct length"), 0);
+    let header_key = secrets[64..].try_into().expect("correct length");
+
+    (root_key, chain_key, header_key)
+}
+
+/// The key behind [crate::auth], which stays the same for the life of the session.
+fn derive_auxiliary_auth_key(secret_input: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, secret_input)
+        .expand(b"WhisperText_AuxiliaryAuthentication", &mut key)
+        .expect("valid length");
+    key
+}
+
+fn message_version(has_kyber: bool, header_encryption: bool, aead: bool) -> u8 {
+    if aead && header_encryption {
+        CIPHERTEXT_MESSAGE_AEAD_HEADER_ENCRYPTED_VERSION
+    } else if aead {
+        CIPHERTEXT_MESSAGE_AEAD_VERSION
+    } else if header_encryption {
+        CIPHERTEXT_MESSAGE_HEADER_ENCRYPTED_VERSION
+    } else if has_kyber {
         CIPHERTEXT_MESSAGE_CURRENT_VERSION
     } else {
         CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION
@@ -49,9 +87,10 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     parameters: &AliceSignalProtocolParameters,
     mut csprng: &mut R,
 ) -> Result<SessionState> {
-    let local_identity = parameters.our_identity_key_pair().identity_key();
+    let local_identity = parameters.our_identity_key();
 
-    let sending_ratchet_key = KeyPair::generate(&mut csprng);
+    let sending_ratchet_key =
+        KeyPair::generate_with_key_type(parameters.their_ratchet_key().key_type(), &mut csprng);
 
     let mut secrets = Vec::with_capacity(32 * 5);
 
@@ -61,8 +100,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
 
     secrets.extend_from_slice(
         &parameters
-            .our_identity_key_pair()
-            .private_key()
+            .our_identity_private_key()
             .calculate_agreement(parameters.their_signed_pre_key())?,
     );
 
@@ -85,8 +123,27 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
         ct
     });
     let has_kyber = parameters.their_kyber_pre_key().is_some();
+    let header_encryption = parameters.header_encryption();
+    if header_encryption && !has_kyber {
+        return Err(SignalProtocolError::InvalidArgument(
+            "header encryption requires a Kyber pre-key".to_string(),
+        ));
+    }
+    let aead = parameters.aead();
+    if aead && !has_kyber {
+        return Err(SignalProtocolError::InvalidArgument(
+            "AEAD messages require a Kyber pre-key".to_string(),
+        ));
+    }
 
-    let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
+    let (root_key, chain_key, initial_header_key) = if header_encryption {
+        let (root_key, chain_key, header_key) = derive_header_encrypted_keys(&secrets);
+        (root_key, chain_key, Some(header_key))
+    } else {
+        let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
+        (root_key, chain_key, None)
+    };
+    let sending_header_key = root_key.header_key();
 
     let (sending_chain_root_key, sending_chain_chain_key) = root_key.create_chain(
         parameters.their_ratchet_key(),
@@ -94,13 +151,22 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     )?;
 
     let mut session = SessionState::new(
-        message_version(has_kyber),
+        message_version(has_kyber, header_encryption, aead),
         local_identity,
         parameters.their_identity_key(),
         &sending_chain_root_key,
     )
     .with_receiver_chain(parameters.their_ratchet_key(), &chain_key)
     .with_sender_chain(&sending_ratchet_key, &sending_chain_chain_key);
+    session.set_auxiliary_auth_key(&derive_auxiliary_auth_key(&secrets));
+    session.set_local_role(SessionRole::Alice);
+    session.set_used_one_time_pre_key(parameters.their_one_time_pre_key().is_some());
+
+    if let Some(initial_header_key) = initial_header_key {
+        session
+            .set_receiver_chain_header_key(parameters.their_ratchet_key(), &initial_header_key)?;
+        session.set_sender_chain_header_key(&sending_header_key);
+    }
 
     if let Some(kyber_ciphertext) = kyber_ciphertext {
         session.set_kyber_ciphertext(kyber_ciphertext);
@@ -112,7 +178,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
 pub(crate) fn initialize_bob_session(
     parameters: &BobSignalProtocolParameters,
 ) -> Result<SessionState> {
-    let local_identity = parameters.our_identity_key_pair().identity_key();
+    let local_identity = parameters.our_identity_key();
 
     let mut secrets = Vec::with_capacity(32 * 5);
 
@@ -127,8 +193,7 @@ pub(crate) fn initialize_bob_session(
 
     secrets.extend_from_slice(
         &parameters
-            .our_identity_key_pair()
-            .private_key()
+            .our_identity_private_key()
             .calculate_agreement(parameters.their_base_key())?,
     );
 
@@ -161,16 +226,41 @@ pub(crate) fn initialize_bob_session(
         }
     }
     let has_kyber = parameters.our_kyber_pre_key_pair().is_some();
+    let header_encryption = parameters.header_encryption();
+    if header_encryption && !has_kyber {
+        return Err(SignalProtocolError::InvalidArgument(
+            "header encryption requires a Kyber pre-key".to_string(),
+        ));
+    }
+    let aead = parameters.aead();
+    if aead && !has_kyber {
+        return Err(SignalProtocolError::InvalidArgument(
+            "AEAD messages require a Kyber pre-key".to_string(),
+        ));
+    }
 
-    let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
+    let (root_key, chain_key, initial_header_key) = if header_encryption {
+        let (root_key, chain_key, header_key) = derive_header_encrypted_keys(&secrets);
+        (root_key, chain_key, Some(header_key))
+    } else {
+        let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
+        (root_key, chain_key, None)
+    };
 
-    let session = SessionState::new(
-        message_version(has_kyber),
+    let mut session = SessionState::new(
+        message_version(has_kyber, header_encryption, aead),
         local_identity,
         parameters.their_identity_key(),
         &root_key,
     )
     .with_sender_chain(parameters.our_ratchet_key_pair(), &chain_key);
+    session.set_auxiliary_auth_key(&derive_auxiliary_auth_key(&secrets));
+    session.set_local_role(SessionRole::Bob);
+    session.set_used_one_time_pre_key(parameters.our_one_time_pre_key_pair().is_some());
+
+    if let Some(initial_header_key) = initial_header_key {
+        session.set_sender_chain_header_key(&initial_header_key);
+    }
 
     Ok(session)
 }
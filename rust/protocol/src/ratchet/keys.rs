@@ -5,6 +5,7 @@
 
 use arrayref::array_ref;
 
+use crate::redact::Redact;
 use crate::{crypto, PrivateKey, PublicKey, Result};
 use std::fmt;
 
@@ -60,12 +61,21 @@ impl MessageKeys {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub(crate) struct ChainKey {
     key: [u8; 32],
     index: u32,
 }
 
+impl fmt::Debug for ChainKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChainKey")
+            .field("key", &Redact(&self.key))
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
 impl ChainKey {
     const MESSAGE_KEY_SEED: [u8; 1] = [0x01u8];
     const CHAIN_KEY_SEED: [u8; 1] = [0x02u8];
@@ -103,7 +113,7 @@ impl ChainKey {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub(crate) struct RootKey {
     key: [u8; 32],
 }
@@ -117,6 +127,19 @@ impl RootKey {
         &self.key
     }
 
+    /// The header key for the next chain created from this root key.
+    ///
+    /// Used by the header-encrypted ratchet. Both sides know the root key before either has seen
+    /// the ratchet key for the new chain, so the receiver can decrypt the first header on it.
+    pub(crate) fn header_key(&self) -> [u8; 32] {
+        let mut header_key = [0; 32];
+        hkdf::Hkdf::<sha2::Sha256>::from_prk(&self.key)
+            .expect("root key is a valid PRK")
+            .expand(b"WhisperHeaderKey", &mut header_key)
+            .expect("valid output length");
+        header_key
+    }
+
     pub(crate) fn create_chain(
         self,
         their_ratchet_key: &PublicKey,
@@ -140,9 +163,17 @@ impl RootKey {
     }
 }
 
+impl fmt::Debug for RootKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RootKey")
+            .field("key", &Redact(&self.key))
+            .finish()
+    }
+}
+
 impl fmt::Display for RootKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", hex::encode(self.key))
+        write!(f, "{}", Redact(&self.key))
     }
 }
 
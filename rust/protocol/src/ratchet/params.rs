@@ -3,10 +3,11 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::{kem, IdentityKey, IdentityKeyPair, KeyPair, PublicKey};
+use crate::{kem, IdentityKey, IdentityKeyPair, KeyPair, PrivateKeyOps, PublicKey};
 
 pub struct AliceSignalProtocolParameters {
-    our_identity_key_pair: IdentityKeyPair,
+    our_identity_key: IdentityKey,
+    our_identity_private_key: Box<dyn PrivateKeyOps>,
     our_base_key_pair: KeyPair,
 
     their_identity_key: IdentityKey,
@@ -14,6 +15,8 @@ pub struct AliceSignalProtocolParameters {
     their_one_time_pre_key: Option<PublicKey>,
     their_ratchet_key: PublicKey,
     their_kyber_pre_key: Option<kem::PublicKey>,
+    header_encryption: bool,
+    aead: bool,
 }
 
 impl AliceSignalProtocolParameters {
@@ -23,15 +26,37 @@ impl AliceSignalProtocolParameters {
         their_identity_key: IdentityKey,
         their_signed_pre_key: PublicKey,
         their_ratchet_key: PublicKey,
+    ) -> Self {
+        Self::with_identity_private_key(
+            *our_identity_key_pair.identity_key(),
+            Box::new(our_identity_key_pair),
+            our_base_key_pair,
+            their_identity_key,
+            their_signed_pre_key,
+            their_ratchet_key,
+        )
+    }
+
+    /// Like [Self::new], but with an identity private key that may not be held in memory.
+    pub fn with_identity_private_key(
+        our_identity_key: IdentityKey,
+        our_identity_private_key: Box<dyn PrivateKeyOps>,
+        our_base_key_pair: KeyPair,
+        their_identity_key: IdentityKey,
+        their_signed_pre_key: PublicKey,
+        their_ratchet_key: PublicKey,
     ) -> Self {
         Self {
-            our_identity_key_pair,
+            our_identity_key,
+            our_identity_private_key,
             our_base_key_pair,
             their_identity_key,
             their_signed_pre_key,
             their_one_time_pre_key: None,
             their_ratchet_key,
             their_kyber_pre_key: None,
+            header_encryption: false,
+            aead: false,
         }
     }
 
@@ -53,9 +78,35 @@ impl AliceSignalProtocolParameters {
         self
     }
 
+    /// Use the header-encrypted variant of the ratchet, which requires a Kyber pre-key.
+    pub fn set_header_encryption(&mut self, enabled: bool) {
+        self.header_encryption = enabled;
+    }
+
+    pub fn with_header_encryption(mut self, enabled: bool) -> Self {
+        self.set_header_encryption(enabled);
+        self
+    }
+
+    /// Seal messages with AES-256-GCM-SIV instead of AES-CBC and a truncated HMAC, which also
+    /// requires a Kyber pre-key.
+    pub fn set_aead(&mut self, enabled: bool) {
+        self.aead = enabled;
+    }
+
+    pub fn with_aead(mut self, enabled: bool) -> Self {
+        self.set_aead(enabled);
+        self
+    }
+
     #[inline]
-    pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
-        &self.our_identity_key_pair
+    pub fn our_identity_key(&self) -> &IdentityKey {
+        &self.our_identity_key
+    }
+
+    #[inline]
+    pub fn our_identity_private_key(&self) -> &dyn PrivateKeyOps {
+        self.our_identity_private_key.as_ref()
     }
 
     #[inline]
@@ -87,10 +138,21 @@ impl AliceSignalProtocolParameters {
     pub fn their_ratchet_key(&self) -> &PublicKey {
         &self.their_ratchet_key
     }
+
+    #[inline]
+    pub fn header_encryption(&self) -> bool {
+        self.header_encryption
+    }
+
+    #[inline]
+    pub fn aead(&self) -> bool {
+        self.aead
+    }
 }
 
 pub struct BobSignalProtocolParameters<'a> {
-    our_identity_key_pair: IdentityKeyPair,
+    our_identity_key: IdentityKey,
+    our_identity_private_key: Box<dyn PrivateKeyOps>,
     our_signed_pre_key_pair: KeyPair,
     our_one_time_pre_key_pair: Option<KeyPair>,
     our_ratchet_key_pair: KeyPair,
@@ -100,6 +162,8 @@ pub struct BobSignalProtocolParameters<'a> {
     their_identity_key: IdentityKey,
     their_base_key: PublicKey,
     their_kyber_ciphertext: Option<&'a kem::SerializedCiphertext>,
+    header_encryption: bool,
+    aead: bool,
 }
 
 impl<'a> BobSignalProtocolParameters<'a> {
@@ -112,9 +176,36 @@ impl<'a> BobSignalProtocolParameters<'a> {
         their_identity_key: IdentityKey,
         their_base_key: PublicKey,
         their_kyber_ciphertext: Option<&'a kem::SerializedCiphertext>,
+    ) -> Self {
+        Self::with_identity_private_key(
+            *our_identity_key_pair.identity_key(),
+            Box::new(our_identity_key_pair),
+            our_signed_pre_key_pair,
+            our_one_time_pre_key_pair,
+            our_ratchet_key_pair,
+            our_kyber_pre_key_pair,
+            their_identity_key,
+            their_base_key,
+            their_kyber_ciphertext,
+        )
+    }
+
+    /// Like [Self::new], but with an identity private key that may not be held in memory.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_identity_private_key(
+        our_identity_key: IdentityKey,
+        our_identity_private_key: Box<dyn PrivateKeyOps>,
+        our_signed_pre_key_pair: KeyPair,
+        our_one_time_pre_key_pair: Option<KeyPair>,
+        our_ratchet_key_pair: KeyPair,
+        our_kyber_pre_key_pair: Option<kem::KeyPair>,
+        their_identity_key: IdentityKey,
+        their_base_key: PublicKey,
+        their_kyber_ciphertext: Option<&'a kem::SerializedCiphertext>,
     ) -> Self {
         Self {
-            our_identity_key_pair,
+            our_identity_key,
+            our_identity_private_key,
             our_signed_pre_key_pair,
             our_one_time_pre_key_pair,
             our_ratchet_key_pair,
@@ -122,12 +213,39 @@ impl<'a> BobSignalProtocolParameters<'a> {
             their_identity_key,
             their_base_key,
             their_kyber_ciphertext,
+            header_encryption: false,
+            aead: false,
         }
     }
 
+    /// Use the header-encrypted variant of the ratchet, as requested by Alice's message version.
+    pub fn set_header_encryption(&mut self, enabled: bool) {
+        self.header_encryption = enabled;
+    }
+
+    pub fn with_header_encryption(mut self, enabled: bool) -> Self {
+        self.set_header_encryption(enabled);
+        self
+    }
+
+    /// Seal messages with AES-256-GCM-SIV, as requested by Alice's message version.
+    pub fn set_aead(&mut self, enabled: bool) {
+        self.aead = enabled;
+    }
+
+    pub fn with_aead(mut self, enabled: bool) -> Self {
+        self.set_aead(enabled);
+        self
+    }
+
+    #[inline]
+    pub fn our_identity_key(&self) -> &IdentityKey {
+        &self.our_identity_key
+    }
+
     #[inline]
-    pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
-        &self.our_identity_key_pair
+    pub fn our_identity_private_key(&self) -> &dyn PrivateKeyOps {
+        self.our_identity_private_key.as_ref()
     }
 
     #[inline]
@@ -164,4 +282,14 @@ impl<'a> BobSignalProtocolParameters<'a> {
     pub fn their_kyber_ciphertext(&self) -> Option<&kem::SerializedCiphertext> {
         self.their_kyber_ciphertext
     }
+
+    #[inline]
+    pub fn header_encryption(&self) -> bool {
+        self.header_encryption
+    }
+
+    #[inline]
+    pub fn aead(&self) -> bool {
+        self.aead
+    }
 }
@@ -0,0 +1,181 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+use crate::{
+    Clock, Context, DeviceId, PreKeyId, PreKeyStore, ProtocolAddress, Result, SessionStore,
+    SystemClock, Timestamp,
+};
+
+/// How long [HandedOutPreKeys::default] keeps a pre-key after the server has handed it out.
+///
+/// This matches how long the server holds on to undelivered messages.
+pub const DEFAULT_HANDED_OUT_PRE_KEY_GRACE_PERIOD: Duration =
+    Duration::from_secs(30 * 24 * 60 * 60);
+
+/// What the server reports as current, as input to [reconcile_with_server].
+#[derive(Debug, Clone, Default)]
+pub struct ServerState {
+    /// The one-time pre-keys of ours that the server still has available to hand out.
+    pub available_pre_key_ids: HashSet<PreKeyId>,
+    /// For each user whose device list was fetched, the devices the server says are registered.
+    ///
+    /// Users that aren't listed here are left alone.
+    pub registered_devices: HashMap<String, HashSet<DeviceId>>,
+}
+
+/// One-time pre-keys the server has handed out but that haven't been used yet.
+///
+/// The server drops a pre-key as soon as it hands it out, but the PreKey message built from it
+/// may not arrive until much later, so [reconcile_with_server] notes when it first saw each
+/// pre-key missing from the server and only deletes it once `grace_period` has passed. A pre-key
+/// that is used before then is removed by [process_prekey](crate::process_prekey) as usual, and
+/// its mark is dropped on the next reconciliation.
+///
+/// The marks must outlive the process for the grace period to mean anything; save them with
+/// [HandedOutPreKeys::marks] and load them with [HandedOutPreKeys::restore].
+#[derive(Clone, Debug)]
+pub struct HandedOutPreKeys<C = SystemClock> {
+    grace_period: Duration,
+    marked_at: BTreeMap<PreKeyId, Timestamp>,
+    clock: C,
+}
+
+impl HandedOutPreKeys {
+    /// Track handed-out pre-keys using the system clock.
+    pub fn new(grace_period: Duration) -> Self {
+        Self::with_clock(grace_period, SystemClock)
+    }
+}
+
+impl Default for HandedOutPreKeys {
+    fn default() -> Self {
+        Self::new(DEFAULT_HANDED_OUT_PRE_KEY_GRACE_PERIOD)
+    }
+}
+
+impl<C: Clock> HandedOutPreKeys<C> {
+    /// Track handed-out pre-keys, reading the current time from `clock`.
+    pub fn with_clock(grace_period: Duration, clock: C) -> Self {
+        Self {
+            grace_period,
+            marked_at: BTreeMap::new(),
+            clock,
+        }
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Each marked pre-key, with the time it was first seen missing from the server.
+    pub fn marks(&self) -> impl Iterator<Item = (PreKeyId, Timestamp)> + '_ {
+        self.marked_at.iter().map(|(&id, &at)| (id, at))
+    }
+
+    /// Add marks previously saved from [HandedOutPreKeys::marks].
+    pub fn restore(&mut self, marks: impl IntoIterator<Item = (PreKeyId, Timestamp)>) {
+        self.marked_at.extend(marks)
+    }
+}
+
+/// The changes made (or needed) by [reconcile_with_server]. Each list is sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconciliationReport {
+    /// Local one-time pre-keys the server handed out more than the grace period ago, which were
+    /// removed.
+    pub removed_pre_key_ids: Vec<PreKeyId>,
+    /// Local one-time pre-keys the server has handed out, which are being kept until the grace
+    /// period passes.
+    pub handed_out_pre_key_ids: Vec<PreKeyId>,
+    /// Pre-keys the server is handing out that we don't have locally.
+    ///
+    /// Sessions started with these will fail, so they should be replaced on the server.
+    pub missing_pre_key_ids: Vec<PreKeyId>,
+    /// Sessions with devices that are no longer registered, which were deleted.
+    pub removed_sessions: Vec<ProtocolAddress>,
+}
+
+impl ReconciliationReport {
+    /// True if local state already matched the server.
+    pub fn is_clean(&self) -> bool {
+        self.removed_pre_key_ids.is_empty()
+            && self.handed_out_pre_key_ids.is_empty()
+            && self.missing_pre_key_ids.is_empty()
+            && self.removed_sessions.is_empty()
+    }
+}
+
+/// Bring the local stores in line with what the server reports.
+///
+/// - One-time pre-keys in `local_pre_key_ids` that the server no longer has are marked in
+///   `handed_out`, and removed from `pre_key_store` once they have been missing for longer than
+///   its grace period.
+/// - Sessions in `session_store` with a device that is no longer registered to a user listed in
+///   [ServerState::registered_devices] are deleted. This requires the store to implement
+///   [SessionStore::all_session_addresses] and [SessionStore::delete_session].
+///
+/// Signed and Kyber pre-keys are not touched; see [SignedPreKeyRotation](crate::SignedPreKeyRotation)
+/// for the former.
+pub async fn reconcile_with_server(
+    server: &ServerState,
+    local_pre_key_ids: impl IntoIterator<Item = PreKeyId>,
+    handed_out: &mut HandedOutPreKeys<impl Clock>,
+    pre_key_store: &mut dyn PreKeyStore,
+    session_store: &mut dyn SessionStore,
+    ctx: Context,
+) -> Result<ReconciliationReport> {
+    let local_pre_key_ids: BTreeSet<PreKeyId> = local_pre_key_ids.into_iter().collect();
+
+    let now = handed_out.clock.now();
+
+    // Pre-keys that are gone locally were used (or removed some other way) and need no mark.
+    handed_out
+        .marked_at
+        .retain(|id, _| local_pre_key_ids.contains(id));
+
+    let mut report = ReconciliationReport::default();
+    for &id in &local_pre_key_ids {
+        if server.available_pre_key_ids.contains(&id) {
+            handed_out.marked_at.remove(&id);
+            continue;
+        }
+        let marked_at = *handed_out.marked_at.entry(id).or_insert(now);
+        // If the clock has gone backwards, wait for it to catch up rather than deleting early.
+        let elapsed = now.duration_since(marked_at).unwrap_or_default();
+        if elapsed >= handed_out.grace_period {
+            pre_key_store.remove_pre_key(id, ctx).await?;
+            handed_out.marked_at.remove(&id);
+            report.removed_pre_key_ids.push(id);
+        } else {
+            report.handed_out_pre_key_ids.push(id);
+        }
+    }
+    report.missing_pre_key_ids = server
+        .available_pre_key_ids
+        .iter()
+        .filter(|id| !local_pre_key_ids.contains(id))
+        .copied()
+        .collect();
+    report.missing_pre_key_ids.sort_unstable();
+
+    if !server.registered_devices.is_empty() {
+        for address in session_store.all_session_addresses(ctx).await? {
+            let registered = match server.registered_devices.get(address.name()) {
+                Some(devices) => devices.contains(&address.device_id()),
+                None => continue,
+            };
+            if !registered {
+                session_store.delete_session(&address, ctx).await?;
+                report.removed_sessions.push(address);
+            }
+        }
+        report.removed_sessions.sort_unstable();
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,114 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::crypto::hmac_sha256;
+use crate::{ProtocolAddress, Result, SenderKeyName, SignalProtocolError};
+
+/// Starts every tagged record. A serialized protobuf message can never start with a zero byte
+/// (field number 0 is invalid), so this also tells tagged records apart from untagged ones.
+const TAGGED_RECORD_PREFIX: [u8; 2] = [0x00, 0x01];
+const TAG_LENGTH: usize = 32;
+
+/// What to do when loading a record with an integrity key, as in
+/// [SessionRecord::deserialize_with_integrity](crate::SessionRecord::deserialize_with_integrity).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityMode {
+    /// Reject records that don't carry a tag.
+    Strict,
+    /// Accept records without a tag, such as those written before tagging was turned on, so
+    /// existing databases can be migrated as records are rewritten. Records that do carry a tag
+    /// must still match it.
+    ///
+    /// This offers no protection against tampering: anyone who can modify a tagged record can
+    /// just as well replace it with an untagged one. Switch to [Strict](Self::Strict) once every
+    /// record has been rewritten.
+    AllowUntagged,
+}
+
+/// The location of a session record in its store, for [add_tag] and [check_tag].
+pub(crate) fn session_location(address: &ProtocolAddress) -> Vec<u8> {
+    let name = address.name().as_bytes();
+    let mut location = Vec::with_capacity(4 + name.len() + 4);
+    location.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    location.extend_from_slice(name);
+    location.extend_from_slice(&u32::from(address.device_id()).to_be_bytes());
+    location
+}
+
+/// The location of a sender key record in its store, for [add_tag] and [check_tag].
+pub(crate) fn sender_key_location(name: &SenderKeyName) -> Vec<u8> {
+    let mut location = session_location(name.sender());
+    location.extend_from_slice(name.distribution_id().as_bytes());
+    location
+}
+
+/// `record_type` keeps a tagged record of one type from being accepted as another, and `location`
+/// (from [session_location] or [sender_key_location]) keeps a record stored for one address from
+/// being accepted for another.
+pub(crate) fn add_tag(
+    key: &[u8],
+    record_type: &[u8],
+    location: &[u8],
+    serialized: Vec<u8>,
+) -> Vec<u8> {
+    let tag = compute_tag(key, record_type, location, &serialized);
+    let mut result = Vec::with_capacity(TAGGED_RECORD_PREFIX.len() + TAG_LENGTH + serialized.len());
+    result.extend_from_slice(&TAGGED_RECORD_PREFIX);
+    result.extend_from_slice(&tag);
+    result.extend_from_slice(&serialized);
+    result
+}
+
+/// Checks the tag added by [add_tag], returning the serialized record underneath.
+pub(crate) fn check_tag<'a>(
+    key: &[u8],
+    record_type: &[u8],
+    location: &[u8],
+    bytes: &'a [u8],
+    mode: IntegrityMode,
+) -> Result<&'a [u8]> {
+    if !bytes.starts_with(&TAGGED_RECORD_PREFIX) {
+        return match mode {
+            IntegrityMode::Strict => Err(SignalProtocolError::RecordIntegrityCheckFailed(
+                "record is not tagged",
+            )),
+            IntegrityMode::AllowUntagged => Ok(bytes),
+        };
+    }
+
+    let rest = &bytes[TAGGED_RECORD_PREFIX.len()..];
+    if rest.len() < TAG_LENGTH {
+        return Err(SignalProtocolError::RecordIntegrityCheckFailed(
+            "record is truncated",
+        ));
+    }
+    let (tag, serialized) = rest.split_at(TAG_LENGTH);
+    if !bool::from(compute_tag(key, record_type, location, serialized).ct_eq(tag)) {
+        return Err(SignalProtocolError::RecordIntegrityCheckFailed(
+            "tag does not match",
+        ));
+    }
+    Ok(serialized)
+}
+
+fn compute_tag(
+    key: &[u8],
+    record_type: &[u8],
+    location: &[u8],
+    serialized: &[u8],
+) -> [u8; TAG_LENGTH] {
+    let record_key = hmac_sha256(key, record_type);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&record_key)
+        .expect("HMAC-SHA256 should accept any size key");
+    mac.update(&TAGGED_RECORD_PREFIX);
+    mac.update(&(location.len() as u64).to_be_bytes());
+    mac.update(location);
+    mac.update(serialized);
+    mac.finalize().into_bytes().into()
+}
@@ -0,0 +1,42 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Format versions for stored records.
+//!
+//! Each record type keeps a list of migrations, where the migration at index `n` upgrades a
+//! record from format version `n` to `n + 1`; the current version is the length of the list.
+//! Records written before versioning was introduced have no version field, which protobuf reads
+//! as version 0.
+
+use crate::{Result, SignalProtocolError};
+
+/// Upgrades a decoded record by one format version.
+pub(crate) type Migration<T> = fn(&mut T) -> Result<()>;
+
+/// Brings `record`, stored at format version `version`, up to date by running every migration
+/// after that version in turn.
+///
+/// Fails if `version` is newer than any migration knows about, since fields added in that version
+/// would be silently dropped the next time the record was written.
+pub(crate) fn migrate<T>(
+    record_type: &'static str,
+    record: &mut T,
+    version: u32,
+    migrations: &[Migration<T>],
+) -> Result<()> {
+    let pending = migrations.get(version as usize..).ok_or(
+        SignalProtocolError::UnrecognizedRecordVersion(record_type, version),
+    )?;
+    for migration in pending {
+        migration(record)?;
+    }
+    Ok(())
+}
+
+/// The migration to version 1, which introduced the explicit version field without changing
+/// anything else.
+pub(crate) fn add_version_field<T>(_record: &mut T) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Keeping key material out of log output.
+//!
+//! Anything secret that ends up in a `Debug` or `Display` impl, or in a log line, goes through
+//! [Redact], which prints a placeholder instead of the value. Debug builds with the
+//! `unredacted-logs` feature print the value itself, for tracking down protocol bugs; release builds
+//! always redact, whatever features are enabled.
+
+use std::fmt;
+
+/// Whether [Redact] shows the values it wraps.
+const SHOW_SECRETS: bool = cfg!(all(feature = "unredacted-logs", debug_assertions));
+
+/// Formats a secret value as a placeholder, unless unredacted logging is enabled.
+///
+/// `Display` formats byte strings as hex; `Debug` uses the wrapped value's own `Debug` impl.
+pub(crate) struct Redact<T>(pub(crate) T);
+
+impl<T: AsRef<[u8]>> fmt::Display for Redact<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0.as_ref();
+        if SHOW_SECRETS {
+            write!(f, "{}", hex::encode(bytes))
+        } else {
+            write!(f, "<redacted {} bytes>", bytes.len())
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redact<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if SHOW_SECRETS {
+            self.0.fmt(f)
+        } else {
+            write!(f, "<redacted>")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_unless_enabled() {
+        let key = [0xAB; 4];
+        if SHOW_SECRETS {
+            assert_eq!(Redact(key).to_string(), "abababab");
+            assert_eq!(format!("{:?}", Redact(key)), "[171, 171, 171, 171]");
+        } else {
+            assert_eq!(Redact(key).to_string(), "<redacted 4 bytes>");
+            assert_eq!(format!("{:?}", Redact(key)), "<redacted>");
+        }
+    }
+}
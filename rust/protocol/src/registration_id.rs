@@ -0,0 +1,80 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Generating and checking registration IDs.
+//!
+//! A registration ID is a small random number chosen when an account (or one of its identities)
+//! is registered, and sent in pre-key bundles and pre-key messages so that the other side can
+//! notice when a device has been re-registered. It has to fit in 14 bits, because that is all the
+//! room the multi-recipient sealed sender format has for it.
+
+use std::ops::RangeInclusive;
+
+use rand::{CryptoRng, Rng};
+
+use crate::consts::MAX_REGISTRATION_ID;
+
+/// The ranges registration IDs are drawn from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegistrationIdRange {
+    /// `1..=16380`, the range clients have always generated ACI registration IDs from.
+    Legacy,
+    /// `1..=0x3FFF`, every ID that can be sent on the wire. PNI registration IDs are drawn from
+    /// this range.
+    Extended,
+}
+
+impl RegistrationIdRange {
+    pub fn range(self) -> RangeInclusive<u32> {
+        match self {
+            Self::Legacy => 1..=16380,
+            Self::Extended => 1..=MAX_REGISTRATION_ID,
+        }
+    }
+
+    pub fn contains(self, registration_id: u32) -> bool {
+        self.range().contains(&registration_id)
+    }
+
+    /// Picks a registration ID uniformly from this range.
+    pub fn generate<R: Rng + CryptoRng>(self, csprng: &mut R) -> u32 {
+        let range = self.range();
+        csprng.gen_range(range.start(), range.end() + 1)
+    }
+}
+
+/// Generates a registration ID in the [legacy range](RegistrationIdRange::Legacy).
+pub fn generate_registration_id<R: Rng + CryptoRng>(csprng: &mut R) -> u32 {
+    RegistrationIdRange::Legacy.generate(csprng)
+}
+
+/// Whether `registration_id` can be sent in a pre-key bundle or message.
+///
+/// Zero is accepted, since older clients send it when they have no registration ID.
+pub(crate) fn is_valid_registration_id(registration_id: u32) -> bool {
+    registration_id <= MAX_REGISTRATION_ID
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn generated_ids_are_in_range() {
+        for range in [RegistrationIdRange::Legacy, RegistrationIdRange::Extended] {
+            for _ in 0..1000 {
+                let id = range.generate(&mut OsRng);
+                assert!(range.contains(id), "{} not in {:?}", id, range);
+                assert!(is_valid_registration_id(id));
+            }
+        }
+        assert!(!RegistrationIdRange::Legacy.contains(0));
+        assert!(!RegistrationIdRange::Legacy.contains(16381));
+        assert!(RegistrationIdRange::Extended.contains(MAX_REGISTRATION_ID));
+        assert!(!is_valid_registration_id(MAX_REGISTRATION_ID + 1));
+    }
+}
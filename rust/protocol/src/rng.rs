@@ -0,0 +1,233 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Checks on the random number generators used for key generation, and a generator that can be
+//! seeded without blocking.
+
+use async_trait::async_trait;
+use rand::{CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+use crate::{Result, SignalProtocolError};
+
+const SAMPLE_BLOCKS: usize = 32;
+const BLOCK_LEN: usize = 32;
+const SAMPLE_BITS: u32 = (SAMPLE_BLOCKS * BLOCK_LEN * 8) as u32;
+/// Six standard deviations of a binomial(8192, 1/2) distribution, rounded up.
+const MAX_BIT_BIAS: u32 = 272;
+/// Each byte value is expected 4 times in the sample; seeing one 32 times is vanishingly unlikely.
+const MAX_BYTE_COUNT: usize = 32;
+
+/// Draws a sample from `rng` and checks that it looks random.
+///
+/// This is a startup sanity check in the spirit of the health tests in NIST SP 800-90B, meant to
+/// catch a generator that is broken outright: one that fails, repeats itself, gets stuck on a
+/// value, or is badly biased. It cannot show that the output is unpredictable, and should not be
+/// taken as evidence that it is.
+///
+/// Returns [`SignalProtocolError::RngHealthCheckFailed`] if any check fails.
+pub fn verify_rng_health<R: RngCore + ?Sized>(rng: &mut R) -> Result<()> {
+    let mut sample = [0u8; SAMPLE_BLOCKS * BLOCK_LEN];
+    rng.try_fill_bytes(&mut sample)
+        .map_err(|_| SignalProtocolError::RngHealthCheckFailed("generator reported an error"))?;
+
+    let blocks: Vec<&[u8]> = sample.chunks(BLOCK_LEN).collect();
+    if blocks.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(SignalProtocolError::RngHealthCheckFailed(
+            "generator repeated its output",
+        ));
+    }
+    if blocks
+        .iter()
+        .any(|block| block.iter().all(|b| *b == block[0]))
+    {
+        return Err(SignalProtocolError::RngHealthCheckFailed(
+            "generator is stuck on a single value",
+        ));
+    }
+
+    let ones: u32 = sample.iter().map(|b| b.count_ones()).sum();
+    if ones.max(SAMPLE_BITS - ones) - SAMPLE_BITS / 2 > MAX_BIT_BIAS {
+        return Err(SignalProtocolError::RngHealthCheckFailed(
+            "generator output is biased",
+        ));
+    }
+
+    let mut counts = [0usize; 256];
+    for b in sample.iter() {
+        counts[usize::from(*b)] += 1;
+    }
+    if counts.iter().any(|count| *count > MAX_BYTE_COUNT) {
+        return Err(SignalProtocolError::RngHealthCheckFailed(
+            "generator output is biased",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Somewhere to get seed material for a [`SeededRng`].
+///
+/// This is for platforms where the operating system's generator may block for a long time after
+/// boot (some embedded devices and freshly started VMs), so that the wait can happen
+/// asynchronously, or entropy can come from elsewhere: a hardware generator, a server, or a
+/// previously saved seed file.
+#[async_trait(?Send)]
+pub trait EntropySource {
+    /// Fill `seed` with fresh, unpredictable bytes.
+    async fn fill_seed(&mut self, seed: &mut [u8; 32]) -> Result<()>;
+}
+
+/// A cryptographically secure generator that can be used as a trait object.
+///
+/// `CryptoRng + RngCore` can't be named in `dyn` position, so traits whose implementations may
+/// live behind a `dyn` (like [PrivateKeyOps](crate::PrivateKeyOps)) take one of these instead.
+/// Every `CryptoRng + RngCore` type implements it.
+pub trait CryptoRngCore: CryptoRng + RngCore {}
+
+impl<R: CryptoRng + RngCore + ?Sized> CryptoRngCore for R {}
+
+/// A deterministic random bit generator (ChaCha20) that is seeded once and then never blocks.
+///
+/// Can be passed anywhere this crate takes a caller-provided RNG in place of `OsRng`.
+pub struct SeededRng {
+    inner: ChaCha20Rng,
+}
+
+impl SeededRng {
+    /// Creates a generator from a seed the caller already has.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self {
+            inner: ChaCha20Rng::from_seed(seed),
+        }
+    }
+
+    /// Creates a generator seeded from `source`.
+    ///
+    /// Rejects a seed that is a single repeated byte, which is what a source that hasn't actually
+    /// been initialized tends to produce.
+    pub async fn from_source(source: &mut dyn EntropySource) -> Result<Self> {
+        Ok(Self::from_seed(Self::seed_from(source).await?))
+    }
+
+    /// Mixes fresh seed material from `source` into the generator's state.
+    pub async fn reseed_from(&mut self, source: &mut dyn EntropySource) -> Result<()> {
+        let seed = Self::seed_from(source).await?;
+        self.reseed(&seed);
+        Ok(())
+    }
+
+    /// Mixes `additional` into the generator's state.
+    ///
+    /// The current state is replaced rather than extended, so earlier output can't be recovered
+    /// from a later compromise of the generator.
+    pub fn reseed(&mut self, additional: &[u8]) {
+        let mut current = [0u8; 32];
+        self.inner.fill_bytes(&mut current);
+        let seed = Sha256::new()
+            .chain(b"Signal_SeededRng_Reseed")
+            .chain(current)
+            .chain(additional)
+            .finalize();
+        self.inner = ChaCha20Rng::from_seed(seed.into());
+    }
+
+    async fn seed_from(source: &mut dyn EntropySource) -> Result<[u8; 32]> {
+        let mut seed = [0u8; 32];
+        source.fill_seed(&mut seed).await?;
+        if seed.iter().all(|b| *b == seed[0]) {
+            return Err(SignalProtocolError::RngHealthCheckFailed(
+                "entropy source returned a constant seed",
+            ));
+        }
+        Ok(seed)
+    }
+}
+
+impl std::fmt::Debug for SeededRng {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeededRng").finish_non_exhaustive()
+    }
+}
+
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        self.inner.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.inner.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.inner.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for SeededRng {}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use rand::rngs::mock::StepRng;
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    struct FixedSource([u8; 32]);
+
+    #[async_trait(?Send)]
+    impl EntropySource for FixedSource {
+        async fn fill_seed(&mut self, seed: &mut [u8; 32]) -> Result<()> {
+            *seed = self.0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_health_check() {
+        verify_rng_health(&mut OsRng).expect("healthy");
+        verify_rng_health(&mut SeededRng::from_seed([7; 32])).expect("healthy");
+
+        for mut broken in [StepRng::new(0, 0), StepRng::new(0, 1), StepRng::new(!0, 0)] {
+            assert!(matches!(
+                verify_rng_health(&mut broken),
+                Err(SignalProtocolError::RngHealthCheckFailed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_seeded_rng() -> Result<()> {
+        async {
+            let mut seed = [0u8; 32];
+            OsRng.fill_bytes(&mut seed);
+
+            let mut a = SeededRng::from_source(&mut FixedSource(seed)).await?;
+            let mut b = SeededRng::from_seed(seed);
+            assert_eq!(a.next_u64(), b.next_u64());
+
+            a.reseed(b"more");
+            assert_ne!(a.next_u64(), b.next_u64());
+
+            assert!(matches!(
+                SeededRng::from_source(&mut FixedSource([0; 32])).await,
+                Err(SignalProtocolError::RngHealthCheckFailed(_))
+            ));
+            assert!(matches!(
+                a.reseed_from(&mut FixedSource([0xff; 32])).await,
+                Err(SignalProtocolError::RngHealthCheckFailed(_))
+            ));
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
@@ -4,12 +4,14 @@
 //
 
 use crate::{
-    message_encrypt, CiphertextMessageType, Context, DeviceId, Direction, IdentityKey,
-    IdentityKeyPair, IdentityKeyStore, KeyPair, KyberPreKeyStore, PreKeySignalMessage, PreKeyStore,
-    PrivateKey, ProtocolAddress, PublicKey, Result, ServiceId, SessionRecord, SessionStore,
-    SignalMessage, SignalProtocolError, SignedPreKeyStore,
+    group_decrypt, message_encrypt, CiphertextMessage, CiphertextMessageType, Clock, Context,
+    DeviceId, Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, KeyPair, KyberPreKeyStore,
+    PreKeySignalMessage, PreKeyStore, PrivateKey, ProtocolAddress, PublicKey, Result,
+    SenderKeyStore, ServiceId, SessionRecord, SessionStore, SignalMessage, SignalProtocolError,
+    SignedPreKeyStore, Timestamp,
 };
 
+use crate::registration_id::is_valid_registration_id;
 use crate::{crypto, curve, proto, session_cipher};
 
 use aes_gcm_siv::aead::{AeadInPlace, NewAead};
@@ -23,6 +25,7 @@ use subtle::ConstantTimeEq;
 use proto::sealed_sender::unidentified_sender_message::message::Type as ProtoMessageType;
 
 use std::convert::{TryFrom, TryInto};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct ServerCertificate {
@@ -43,6 +46,57 @@ with this test certificate ID, Bad Things will happen.
 */
 const REVOKED_SERVER_CERTIFICATE_KEY_IDS: &[u32] = &[0xDEADC357];
 
+/// Decides whether a server signing key, identified by its [`ServerCertificate::key_id`], has been
+/// revoked.
+///
+/// This is consulted by [`ServerCertificate::validate_with_revocation`] and
+/// [`SenderCertificate::validate_with_revocation`]. Implementations may fetch and cache a
+/// revocation list from elsewhere, so that a compromised server key can be revoked without
+/// shipping a new client. Such implementations should generally also consult
+/// [`StaticRevocationList::default`], which holds the list bundled with this library.
+pub trait RevocationProvider {
+    /// Return `true` if certificates signed with the key `key_id` must be rejected.
+    fn is_revoked(&self, key_id: u32) -> bool;
+}
+
+/// A fixed list of revoked server certificate key IDs.
+///
+/// The [`Default`] instance contains the revocations bundled with this library, and is what
+/// [`ServerCertificate::validate`] and [`SenderCertificate::validate`] use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticRevocationList {
+    revoked_key_ids: Vec<u32>,
+}
+
+impl StaticRevocationList {
+    /// Revoke exactly `revoked_key_ids`, *without* including the bundled revocations.
+    pub fn new(revoked_key_ids: Vec<u32>) -> Self {
+        Self { revoked_key_ids }
+    }
+
+    /// Add further revoked key IDs on top of this list.
+    pub fn with_revoked_key_ids(mut self, revoked_key_ids: impl IntoIterator<Item = u32>) -> Self {
+        self.revoked_key_ids.extend(revoked_key_ids);
+        self
+    }
+
+    pub fn revoked_key_ids(&self) -> &[u32] {
+        &self.revoked_key_ids
+    }
+}
+
+impl Default for StaticRevocationList {
+    fn default() -> Self {
+        Self::new(REVOKED_SERVER_CERTIFICATE_KEY_IDS.to_vec())
+    }
+}
+
+impl RevocationProvider for StaticRevocationList {
+    fn is_revoked(&self, key_id: u32) -> bool {
+        self.revoked_key_ids.contains(&key_id)
+    }
+}
+
 impl ServerCertificate {
     pub fn deserialize(data: &[u8]) -> Result<Self> {
         let pb = proto::sealed_sender::ServerCertificate::decode(data)
@@ -117,7 +171,17 @@ impl ServerCertificate {
     }
 
     pub fn validate(&self, trust_root: &PublicKey) -> Result<bool> {
-        if REVOKED_SERVER_CERTIFICATE_KEY_IDS.contains(&self.key_id()?) {
+        self.validate_with_revocation(trust_root, &StaticRevocationList::default())
+    }
+
+    /// Like [`Self::validate`], but checks the certificate's key ID against `revocation` rather
+    /// than only the bundled revocation list.
+    pub fn validate_with_revocation(
+        &self,
+        trust_root: &PublicKey,
+        revocation: &dyn RevocationProvider,
+    ) -> Result<bool> {
+        if revocation.is_revoked(self.key_id()?) {
             log::error!(
                 "received server certificate with revoked ID {:x}",
                 self.key_id()?
@@ -155,7 +219,7 @@ pub struct SenderCertificate {
     sender_device_id: DeviceId,
     sender_uuid: String,
     sender_e164: Option<String>,
-    expiration: u64,
+    expiration: Timestamp,
     serialized: Vec<u8>,
     certificate: Vec<u8>,
     signature: Vec<u8>,
@@ -181,6 +245,7 @@ impl SenderCertificate {
             .into();
         let expiration = certificate_data
             .expires
+            .map(Timestamp::from_epoch_millis)
             .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
         let signer_pb = certificate_data
             .signer
@@ -217,7 +282,7 @@ impl SenderCertificate {
         sender_e164: Option<String>,
         key: PublicKey,
         sender_device_id: DeviceId,
-        expiration: u64,
+        expiration: Timestamp,
         signer: ServerCertificate,
         signer_key: &PrivateKey,
         rng: &mut R,
@@ -226,7 +291,7 @@ impl SenderCertificate {
             sender_uuid: Some(sender_uuid.clone()),
             sender_e164: sender_e164.clone(),
             sender_device: Some(sender_device_id.into()),
-            expires: Some(expiration),
+            expires: Some(expiration.epoch_millis()),
             identity_key: Some(key.serialize().to_vec()),
             signer: Some(signer.to_protobuf()?),
         };
@@ -254,8 +319,26 @@ impl SenderCertificate {
         })
     }
 
-    pub fn validate(&self, trust_root: &PublicKey, validation_time: u64) -> Result<bool> {
-        if !self.signer.validate(trust_root)? {
+    pub fn validate(&self, trust_root: &PublicKey, validation_time: Timestamp) -> Result<bool> {
+        self.validate_with_revocation(
+            trust_root,
+            validation_time,
+            &StaticRevocationList::default(),
+        )
+    }
+
+    /// Like [`Self::validate`], but checks the signing server certificate against `revocation`
+    /// rather than only the bundled revocation list.
+    pub fn validate_with_revocation(
+        &self,
+        trust_root: &PublicKey,
+        validation_time: Timestamp,
+        revocation: &dyn RevocationProvider,
+    ) -> Result<bool> {
+        if !self
+            .signer
+            .validate_with_revocation(trust_root, revocation)?
+        {
             log::error!("received server certificate not signed by trust root");
             return Ok(false);
         }
@@ -271,7 +354,7 @@ impl SenderCertificate {
 
         if validation_time > self.expiration {
             log::error!(
-                "received expired sender certificate (expiration: {}, validation_time: {})",
+                "received expired sender certificate (expiration: {:?}, validation_time: {:?})",
                 self.expiration,
                 validation_time
             );
@@ -301,7 +384,7 @@ impl SenderCertificate {
         Ok(self.sender_e164.as_deref())
     }
 
-    pub fn expiration(&self) -> Result<u64> {
+    pub fn expiration(&self) -> Result<Timestamp> {
         Ok(self.expiration)
     }
 
@@ -393,6 +476,61 @@ impl From<ContentHint> for u32 {
     }
 }
 
+/// The length of an unidentified access key, presented to the server when sending sealed sender
+/// messages.
+pub const UNIDENTIFIED_ACCESS_KEY_LEN: usize = 16;
+
+/// The access key to present for a recipient who accepts sealed sender messages from anyone.
+///
+/// The server doesn't check the key for such recipients, but one still has to be sent; this is
+/// the value the Signal apps use.
+pub const UNRESTRICTED_UNIDENTIFIED_ACCESS_KEY: [u8; UNIDENTIFIED_ACCESS_KEY_LEN] =
+    [0; UNIDENTIFIED_ACCESS_KEY_LEN];
+
+/// Derives the unidentified access key for a recipient from their profile key.
+///
+/// This is the first 16 bytes of the AES-256-GCM encryption of 16 zero bytes, using the profile
+/// key as the key and an all-zero nonce.
+pub fn derive_unidentified_access_key(profile_key: &[u8; 32]) -> [u8; UNIDENTIFIED_ACCESS_KEY_LEN] {
+    let mut access_key = [0; UNIDENTIFIED_ACCESS_KEY_LEN];
+    signal_crypto::Aes256GcmEncryption::new(
+        profile_key,
+        &[0; signal_crypto::Aes256GcmEncryption::NONCE_SIZE],
+        &[],
+    )
+    .and_then(|mut gcm| gcm.encrypt(&mut access_key))
+    .expect("valid key and nonce sizes");
+    access_key
+}
+
+/// Whether a recipient accepts sealed sender messages, as published in their profile.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnidentifiedAccessMode {
+    /// Sealed sender messages are not accepted.
+    Disabled,
+    /// Sealed sender messages are accepted from those who know the recipient's profile key.
+    Enabled,
+    /// Sealed sender messages are accepted from anyone.
+    Unrestricted,
+}
+
+impl UnidentifiedAccessMode {
+    /// The access key to present when sending a sealed sender message to a recipient with this
+    /// mode, or `None` if the message can't be sent with sealed sender.
+    ///
+    /// `profile_key` is the recipient's profile key, if known.
+    pub fn access_key(
+        self,
+        profile_key: Option<&[u8; 32]>,
+    ) -> Option<[u8; UNIDENTIFIED_ACCESS_KEY_LEN]> {
+        match self {
+            Self::Disabled => None,
+            Self::Enabled => profile_key.map(derive_unidentified_access_key),
+            Self::Unrestricted => Some(UNRESTRICTED_UNIDENTIFIED_ACCESS_KEY),
+        }
+    }
+}
+
 pub struct UnidentifiedSenderMessageContent {
     serialized: Vec<u8>,
     contents: Vec<u8>,
@@ -783,12 +921,42 @@ pub async fn sealed_sender_encrypt<R: Rng + CryptoRng>(
     rng: &mut R,
 ) -> Result<Vec<u8>> {
     let message = message_encrypt(ptext, destination, session_store, identity_store, ctx).await?;
+    sealed_sender_encrypt_message(
+        destination,
+        sender_cert,
+        &message,
+        None,
+        identity_store,
+        ctx,
+        rng,
+    )
+    .await
+}
+
+/// Seal an already-encrypted `message` for `destination` with [Sealed Sender v1].
+///
+/// Unlike [`sealed_sender_encrypt`], this accepts any kind of message, including a
+/// [`SenderKeyMessage`] produced by [`group_encrypt`](crate::group_encrypt). Group messages
+/// should pass the group's ID as `group_id`, which tells the recipient which group the message
+/// belongs to. [`sealed_sender_decrypt_with_sender_keys`] undoes both layers on the receiving
+/// side.
+///
+/// [Sealed Sender v1]: sealed_sender_encrypt_from_usmc
+pub async fn sealed_sender_encrypt_message<R: Rng + CryptoRng>(
+    destination: &ProtocolAddress,
+    sender_cert: &SenderCertificate,
+    message: &CiphertextMessage,
+    group_id: Option<Vec<u8>>,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+    rng: &mut R,
+) -> Result<Vec<u8>> {
     let usmc = UnidentifiedSenderMessageContent::new(
         message.message_type(),
         sender_cert.clone(),
         message.serialize().to_vec(),
         ContentHint::Default,
-        None,
+        group_id,
     )?;
     sealed_sender_encrypt_from_usmc(destination, &usmc, identity_store, ctx, rng).await
 }
@@ -1262,6 +1430,13 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
                     destination.name()
                 ))
             })?;
+        if !destination.device_id().is_valid() {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "multi-recipient sealed sender cannot send to device {} of {}",
+                destination.device_id(),
+                destination.name()
+            )));
+        }
 
         let their_identity = identity_store
             .get_identity(destination, ctx)
@@ -1287,8 +1462,7 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
             )
         })?;
         // Valid registration IDs fit in 14 bits.
-        // TODO: move this into a RegistrationId strong type.
-        if their_registration_id & 0x3FFF != their_registration_id {
+        if !is_valid_registration_id(their_registration_id) {
             return Err(SignalProtocolError::InvalidRegistrationId(
                 destination.clone(),
                 their_registration_id,
@@ -1355,6 +1529,11 @@ pub async fn sealed_sender_multi_recipient_encrypt<R: Rng + CryptoRng>(
 ///
 /// [Routing messages to recipients]: sealed_sender_multi_recipient_encrypt#routing-messages-to-recipients
 pub fn sealed_sender_multi_recipient_fan_out(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    if data.is_empty() {
+        return Err(SignalProtocolError::InvalidSealedSenderMessage(
+            "Message was empty".to_owned(),
+        ));
+    }
     let version = data[0] >> 4;
     if version != SEALED_SENDER_V2_VERSION {
         return Err(SignalProtocolError::UnknownSealedSenderVersion(version));
@@ -1558,6 +1737,8 @@ pub struct SealedSenderDecryptionResult {
     pub sender_uuid: String,
     pub sender_e164: Option<String>,
     pub device_id: DeviceId,
+    /// The group a group message was sent to, if the sender included it.
+    pub group_id: Option<Vec<u8>>,
     pub message: Vec<u8>,
 }
 
@@ -1574,23 +1755,71 @@ impl SealedSenderDecryptionResult {
         Ok(self.device_id)
     }
 
+    pub fn group_id(&self) -> Result<Option<&[u8]>> {
+        Ok(self.group_id.as_deref())
+    }
+
     pub fn message(&self) -> Result<&[u8]> {
         Ok(self.message.as_ref())
     }
 }
 
-/// Decrypt a Sealed Sender message `ciphertext` in either the v1 or v2 format, validate its sender
-/// certificate, and then decrypt the inner message payload.
+/// How far the server timestamp on a sealed sender envelope may be from the local clock.
 ///
-/// This method calls [`sealed_sender_decrypt_to_usmc`] to extract the sender information, including
-/// the embedded [`SenderCertificate`]. The sender certificate (signed by the [`ServerCertificate`])
-/// is then validated against the `trust_root` baked into the client to ensure that the sender's
-/// identity was not forged.
+/// [`sealed_sender_decrypt`] only checks the server timestamp against the sender certificate's
+/// expiration, so an envelope with an old timestamp (and a certificate that was valid at the time)
+/// can be replayed indefinitely. Checking the timestamp against the local clock as well bounds how
+/// long such an envelope is accepted. Use it with [`SenderValidation::with_age_policy`] or
+/// [`sealed_sender_decrypt_with_age_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeAgePolicy {
+    /// The oldest server timestamp accepted, relative to the local clock.
+    pub max_age: Duration,
+    /// How far in the future a server timestamp may be, to allow for clock skew.
+    pub max_clock_skew: Duration,
+}
+
+impl EnvelopeAgePolicy {
+    /// Envelopes up to 30 days old (the server's message retention period), with up to an hour of
+    /// clock skew.
+    pub const DEFAULT: Self = Self {
+        max_age: Duration::from_secs(30 * 24 * 60 * 60),
+        max_clock_skew: Duration::from_secs(60 * 60),
+    };
+
+    /// Checks `timestamp` (as reported by the server) against the local time `now`.
+    ///
+    /// Returns [`SignalProtocolError::EnvelopeTooOld`] or
+    /// [`SignalProtocolError::EnvelopeFromFuture`] if it falls outside the acceptance window.
+    pub fn check(&self, timestamp: Timestamp, now: Timestamp) -> Result<()> {
+        if timestamp < now.saturating_sub(self.max_age) {
+            return Err(SignalProtocolError::EnvelopeTooOld { timestamp, now });
+        }
+        if timestamp > now.saturating_add(self.max_clock_skew) {
+            return Err(SignalProtocolError::EnvelopeFromFuture { timestamp, now });
+        }
+        Ok(())
+    }
+}
+
+impl Default for EnvelopeAgePolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Like [`sealed_sender_decrypt`], but first rejects envelopes whose server `timestamp` is outside
+/// the window allowed by `age_policy` around the current time according to `clock`.
+///
+/// The check is made by [`SenderValidation::with_age_policy`], before the inner message is
+/// decrypted, so a rejected envelope does not change any of the stores.
 #[allow(clippy::too_many_arguments)]
-pub async fn sealed_sender_decrypt(
+pub async fn sealed_sender_decrypt_with_age_policy<R: Rng + CryptoRng>(
     ciphertext: &[u8],
     trust_root: &PublicKey,
-    timestamp: u64,
+    timestamp: Timestamp,
+    age_policy: &EnvelopeAgePolicy,
+    clock: &dyn Clock,
     local_e164: Option<String>,
     local_uuid: String,
     local_device_id: DeviceId,
@@ -1599,74 +1828,346 @@ pub async fn sealed_sender_decrypt(
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
     kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
     ctx: Context,
 ) -> Result<SealedSenderDecryptionResult> {
-    let usmc = sealed_sender_decrypt_to_usmc(ciphertext, identity_store, ctx).await?;
+    decrypt_validated(
+        ciphertext,
+        SenderValidation::new(trust_root).with_age_policy(*age_policy, clock),
+        timestamp,
+        local_e164,
+        local_uuid,
+        local_device_id,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        None,
+        csprng,
+        ctx,
+    )
+    .await
+}
 
-    if !usmc.sender()?.validate(trust_root, timestamp)? {
-        return Err(SignalProtocolError::InvalidSealedSenderMessage(
-            "trust root validation failed".to_string(),
-        ));
+/// The checks on a sealed sender message's sender that [`sealed_sender_decrypt`] makes before
+/// decrypting the message itself, as a separate step.
+///
+/// This is for callers that use [`sealed_sender_decrypt_to_usmc`] directly, so that they can make
+/// the same checks without reimplementing them.
+#[derive(Clone, Copy)]
+pub struct SenderValidation<'a> {
+    trust_root: &'a PublicKey,
+    revocation: Option<&'a dyn RevocationProvider>,
+    age_policy: Option<(EnvelopeAgePolicy, &'a dyn Clock)>,
+    allow_self_send: bool,
+}
+
+impl<'a> SenderValidation<'a> {
+    /// Validate sender certificates against `trust_root` and the bundled revocation list, and
+    /// reject messages sent by the local device.
+    pub fn new(trust_root: &'a PublicKey) -> Self {
+        Self {
+            trust_root,
+            revocation: None,
+            age_policy: None,
+            allow_self_send: false,
+        }
     }
 
-    let is_local_uuid = local_uuid == usmc.sender()?.sender_uuid()?;
+    /// Check server certificates against `revocation` instead of the bundled revocation list.
+    pub fn with_revocation(mut self, revocation: &'a dyn RevocationProvider) -> Self {
+        self.revocation = Some(revocation);
+        self
+    }
 
-    let is_local_e164 = match (local_e164, usmc.sender()?.sender_e164()?) {
-        (Some(l), Some(s)) => l == s,
-        (_, _) => false,
-    };
+    /// Also reject envelopes whose server timestamp is outside the window allowed by `age_policy`
+    /// around the current time according to `clock`.
+    pub fn with_age_policy(mut self, age_policy: EnvelopeAgePolicy, clock: &'a dyn Clock) -> Self {
+        self.age_policy = Some((age_policy, clock));
+        self
+    }
 
-    if (is_local_e164 || is_local_uuid) && usmc.sender()?.sender_device_id()? == local_device_id {
-        return Err(SignalProtocolError::SealedSenderSelfSend);
+    /// Accept messages that claim to come from the local device.
+    ///
+    /// Messages from the account's *other* devices (such as sync messages) are always accepted;
+    /// this is for clients that also route their own sync transcripts through sealed sender
+    /// decryption. Don't use it otherwise: a message from "yourself" is normally a sign of a bug
+    /// or a replay.
+    pub fn allow_self_send(mut self) -> Self {
+        self.allow_self_send = true;
+        self
     }
 
-    let mut rng = rand::rngs::OsRng;
+    /// Validate the sender of `usmc` as of `validation_time`, returning the sender's address.
+    ///
+    /// `validation_time` is normally the server timestamp on the envelope, not the local clock. If
+    /// an [age policy](Self::with_age_policy) was given, it is checked against the policy's clock
+    /// first. The local account is identified by `local_uuid` (and `local_e164`, if it has one) and
+    /// `local_device_id`.
+    pub fn validate(
+        &self,
+        usmc: &UnidentifiedSenderMessageContent,
+        validation_time: Timestamp,
+        local_uuid: &str,
+        local_e164: Option<&str>,
+        local_device_id: DeviceId,
+    ) -> Result<ProtocolAddress> {
+        if let Some((age_policy, clock)) = &self.age_policy {
+            age_policy.check(validation_time, clock.now())?;
+        }
+
+        let sender = usmc.sender()?;
+        let valid = match self.revocation {
+            Some(revocation) => {
+                sender.validate_with_revocation(self.trust_root, validation_time, revocation)?
+            }
+            None => sender.validate(self.trust_root, validation_time)?,
+        };
+        if !valid {
+            return Err(SignalProtocolError::InvalidSealedSenderMessage(
+                "trust root validation failed".to_string(),
+            ));
+        }
+
+        if !self.allow_self_send {
+            let is_local_uuid = local_uuid == sender.sender_uuid()?;
+            let is_local_e164 = match (local_e164, sender.sender_e164()?) {
+                (Some(l), Some(s)) => l == s,
+                (_, _) => false,
+            };
+            if (is_local_e164 || is_local_uuid) && sender.sender_device_id()? == local_device_id {
+                return Err(SignalProtocolError::SealedSenderSelfSend);
+            }
+        }
+
+        Ok(ProtocolAddress::new(
+            sender.sender_uuid()?.to_string(),
+            sender.sender_device_id()?,
+        ))
+    }
+}
 
-    let remote_address = ProtocolAddress::new(
-        usmc.sender()?.sender_uuid()?.to_string(),
-        usmc.sender()?.sender_device_id()?,
-    );
+/// Decrypt the 1:1 message carried by `usmc`, which was sent by `remote_address`.
+///
+/// This is the last step of [`sealed_sender_decrypt`]; the sender should already have been
+/// checked, for instance with [`SenderValidation`].
+#[allow(clippy::too_many_arguments)]
+pub async fn sealed_sender_decrypt_contents<R: Rng + CryptoRng>(
+    usmc: &UnidentifiedSenderMessageContent,
+    remote_address: &ProtocolAddress,
+    identity_store: &mut dyn IdentityKeyStore,
+    session_store: &mut dyn SessionStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    decrypt_contents(
+        usmc,
+        remote_address,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        None,
+        csprng,
+        ctx,
+    )
+    .await
+}
 
-    let message = match usmc.msg_type()? {
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_contents<R: Rng + CryptoRng>(
+    usmc: &UnidentifiedSenderMessageContent,
+    remote_address: &ProtocolAddress,
+    identity_store: &mut dyn IdentityKeyStore,
+    session_store: &mut dyn SessionStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    sender_key_store: Option<&mut dyn SenderKeyStore>,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    match usmc.msg_type()? {
         CiphertextMessageType::Whisper => {
             let ctext = SignalMessage::try_from(usmc.contents()?)?;
             session_cipher::message_decrypt_signal(
                 &ctext,
-                &remote_address,
+                remote_address,
                 session_store,
                 identity_store,
-                &mut rng,
+                csprng,
                 ctx,
             )
-            .await?
+            .await
         }
         CiphertextMessageType::PreKey => {
             let ctext = PreKeySignalMessage::try_from(usmc.contents()?)?;
             session_cipher::message_decrypt_prekey(
                 &ctext,
-                &remote_address,
+                remote_address,
                 session_store,
                 identity_store,
                 pre_key_store,
                 signed_pre_key_store,
                 kyber_pre_key_store,
-                &mut rng,
+                csprng,
                 ctx,
             )
-            .await?
-        }
-        msg_type => {
-            return Err(SignalProtocolError::InvalidMessage(
-                msg_type,
-                "unexpected message type for sealed_sender_decrypt",
-            ));
+            .await
         }
-    };
+        CiphertextMessageType::SenderKey => match sender_key_store {
+            Some(sender_key_store) => {
+                group_decrypt(usmc.contents()?, sender_key_store, remote_address, ctx).await
+            }
+            None => Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::SenderKey,
+                "sender key messages need sealed_sender_decrypt_with_sender_keys",
+            )),
+        },
+        msg_type => Err(SignalProtocolError::InvalidMessage(
+            msg_type,
+            "unexpected message type for sealed_sender_decrypt",
+        )),
+    }
+}
+
+/// Decrypt a Sealed Sender message `ciphertext` in either the v1 or v2 format, validate its sender
+/// certificate, and then decrypt the inner message payload.
+///
+/// This method calls [`sealed_sender_decrypt_to_usmc`] to extract the sender information, including
+/// the embedded [`SenderCertificate`]. The sender certificate (signed by the [`ServerCertificate`])
+/// is then validated against the `trust_root` baked into the client to ensure that the sender's
+/// identity was not forged, as of the server's `timestamp`.
+///
+/// The three steps are also available separately, as [`sealed_sender_decrypt_to_usmc`],
+/// [`SenderValidation::validate`], and [`sealed_sender_decrypt_contents`].
+#[allow(clippy::too_many_arguments)]
+pub async fn sealed_sender_decrypt<R: Rng + CryptoRng>(
+    ciphertext: &[u8],
+    trust_root: &PublicKey,
+    timestamp: Timestamp,
+    local_e164: Option<String>,
+    local_uuid: String,
+    local_device_id: DeviceId,
+    identity_store: &mut dyn IdentityKeyStore,
+    session_store: &mut dyn SessionStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SealedSenderDecryptionResult> {
+    decrypt_validated(
+        ciphertext,
+        SenderValidation::new(trust_root),
+        timestamp,
+        local_e164,
+        local_uuid,
+        local_device_id,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        None,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+/// Like [`sealed_sender_decrypt`], but also accepts group messages, which are decrypted with
+/// [`group_decrypt`] using `sender_key_store`.
+///
+/// The result's [`group_id`](SealedSenderDecryptionResult::group_id) tells the caller which group
+/// a group message was sent to.
+#[allow(clippy::too_many_arguments)]
+pub async fn sealed_sender_decrypt_with_sender_keys<R: Rng + CryptoRng>(
+    ciphertext: &[u8],
+    trust_root: &PublicKey,
+    timestamp: Timestamp,
+    local_e164: Option<String>,
+    local_uuid: String,
+    local_device_id: DeviceId,
+    identity_store: &mut dyn IdentityKeyStore,
+    session_store: &mut dyn SessionStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    sender_key_store: &mut dyn SenderKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SealedSenderDecryptionResult> {
+    decrypt_validated(
+        ciphertext,
+        SenderValidation::new(trust_root),
+        timestamp,
+        local_e164,
+        local_uuid,
+        local_device_id,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        Some(sender_key_store),
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn decrypt_validated<R: Rng + CryptoRng>(
+    ciphertext: &[u8],
+    validation: SenderValidation<'_>,
+    timestamp: Timestamp,
+    local_e164: Option<String>,
+    local_uuid: String,
+    local_device_id: DeviceId,
+    identity_store: &mut dyn IdentityKeyStore,
+    session_store: &mut dyn SessionStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    sender_key_store: Option<&mut dyn SenderKeyStore>,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<SealedSenderDecryptionResult> {
+    let usmc = sealed_sender_decrypt_to_usmc(ciphertext, identity_store, ctx).await?;
+
+    let remote_address = validation.validate(
+        &usmc,
+        timestamp,
+        &local_uuid,
+        local_e164.as_deref(),
+        local_device_id,
+    )?;
+
+    let message = decrypt_contents(
+        &usmc,
+        &remote_address,
+        identity_store,
+        session_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        sender_key_store,
+        csprng,
+        ctx,
+    )
+    .await?;
 
     Ok(SealedSenderDecryptionResult {
         sender_uuid: usmc.sender()?.sender_uuid()?.to_string(),
         sender_e164: usmc.sender()?.sender_e164()?.map(|s| s.to_string()),
         device_id: usmc.sender()?.sender_device_id()?,
+        group_id: usmc.group_id()?.map(<[u8]>::to_vec),
         message,
     })
 }
@@ -1733,6 +2234,9 @@ fn test_lossless_round_trip() -> Result<()> {
 
     let sender_certificate =
         SenderCertificate::deserialize(&sender_certificate_data.encode_to_vec())?;
-    assert!(sender_certificate.validate(&trust_root.public_key()?, 31336)?);
+    assert!(sender_certificate.validate(
+        &trust_root.public_key()?,
+        Timestamp::from_epoch_millis(31336)
+    )?);
     Ok(())
 }
@@ -5,13 +5,21 @@
 
 use std::collections::VecDeque;
 use std::convert::TryFrom;
+use std::fmt;
 
+use arrayref::array_ref;
 use itertools::Itertools;
 use prost::Message;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 
 use crate::crypto::hmac_sha256;
 use crate::proto::storage as storage_proto;
-use crate::{consts, PrivateKey, PublicKey, SignalProtocolError};
+use crate::protocol::SENDERKEY_MESSAGE_CURRENT_VERSION;
+use crate::record_integrity::{self, IntegrityMode};
+use crate::record_version::{self, Migration};
+use crate::redact::Redact;
+use crate::{consts, PrivateKey, PublicKey, SenderKeyName, SignalProtocolError};
 
 /// A distinct error type to keep from accidentally propagating deserialization errors.
 #[derive(Debug)]
@@ -23,7 +31,7 @@ impl std::fmt::Display for InvalidSessionError {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct SenderMessageKey {
     iteration: u32,
     iv: Vec<u8>,
@@ -31,6 +39,15 @@ pub(crate) struct SenderMessageKey {
     seed: Vec<u8>,
 }
 
+impl fmt::Debug for SenderMessageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SenderMessageKey")
+            .field("iteration", &self.iteration)
+            .field("seed", &Redact(&self.seed))
+            .finish_non_exhaustive()
+    }
+}
+
 impl SenderMessageKey {
     pub(crate) fn new(iteration: u32, seed: Vec<u8>) -> Self {
         let mut derived = [0; 48];
@@ -73,12 +90,21 @@ impl SenderMessageKey {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct SenderChainKey {
     iteration: u32,
     chain_key: Vec<u8>,
 }
 
+impl fmt::Debug for SenderChainKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SenderChainKey")
+            .field("iteration", &self.iteration)
+            .field("chain_key", &Redact(&self.chain_key))
+            .finish()
+    }
+}
+
 impl SenderChainKey {
     const MESSAGE_KEY_SEED: u8 = 0x01;
     const CHAIN_KEY_SEED: u8 = 0x02;
@@ -122,11 +148,19 @@ impl SenderChainKey {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct SenderKeyState {
     state: storage_proto::SenderKeyStateStructure,
 }
 
+impl fmt::Debug for SenderKeyState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SenderKeyState")
+            .field("state", &Redact(&self.state))
+            .finish()
+    }
+}
+
 impl SenderKeyState {
     pub(crate) fn new(
         message_version: u8,
@@ -230,6 +264,90 @@ impl SenderKeyState {
     }
 }
 
+/// Identifies one sender's chain of group messages, as used by [`group_encrypt`] and
+/// [`create_sender_key_distribution_message`].
+///
+/// Distribution ids are normally random, which means the sender has to tell the other members
+/// which one it is using. [`DistributionId::derive`] and [`DistributionId::derive_for_sender`]
+/// instead let every member compute the same id from information they already share.
+///
+/// [`group_encrypt`]: crate::group_encrypt
+/// [`create_sender_key_distribution_message`]: crate::create_sender_key_distribution_message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DistributionId(Uuid);
+
+impl DistributionId {
+    /// Derives the distribution id for `group_id` at membership epoch `epoch`.
+    ///
+    /// The result is a version 8 (custom) UUID holding the first 122 bits of a SHA-256 hash of the
+    /// inputs, so it can't be mistaken for a SHA-1-based UUIDv5. Bumping the epoch whenever the membership changes gives a
+    /// fresh id, and therefore fresh sender key state, without any coordination between members.
+    pub fn derive(group_id: &[u8], epoch: u64) -> Self {
+        let hash = Sha256::new()
+            .chain(b"Signal_DistributionId_GroupEpoch")
+            .chain(group_id)
+            .chain(epoch.to_be_bytes())
+            .finalize();
+        Self(custom_uuid(*array_ref![hash, 0, 16]))
+    }
+
+    /// Derives the distribution id `sender` uses in the group identified by `group_id`.
+    ///
+    /// `group_id` can be anything every member knows and outsiders don't, such as the group's
+    /// master key or a group identifier derived from it; `sender` is the name part of the sender's
+    /// [ProtocolAddress](crate::ProtocolAddress), so all of a sender's devices share one id. As
+    /// with [`derive`](Self::derive), the result is a version 8 UUID from a SHA-256 hash, so
+    /// members can work out each other's distribution ids instead of generating random ones and
+    /// telling each other about them.
+    pub fn derive_for_sender(group_id: &[u8], sender: &str) -> Self {
+        let hash = Sha256::new()
+            .chain(b"Signal_DistributionId_GroupSender")
+            .chain((group_id.len() as u64).to_be_bytes())
+            .chain(group_id)
+            .chain(sender.as_bytes())
+            .finalize();
+        Self(custom_uuid(*array_ref![hash, 0, 16]))
+    }
+
+    /// The id as a plain UUID, which is what the group messaging functions take.
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+/// Equivalent to `uuid::Builder::from_custom_bytes`, which uuid still keeps behind its unstable
+/// flag: marks `bytes` as a version 8 RFC 4122 UUID and otherwise leaves them alone.
+fn custom_uuid(mut bytes: [u8; 16]) -> Uuid {
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    uuid::Builder::from_bytes(bytes)
+        .with_variant(uuid::Variant::RFC4122)
+        .into_uuid()
+}
+
+impl From<Uuid> for DistributionId {
+    fn from(value: Uuid) -> Self {
+        Self(value)
+    }
+}
+
+impl From<DistributionId> for Uuid {
+    fn from(value: DistributionId) -> Self {
+        value.0
+    }
+}
+
+impl std::fmt::Display for DistributionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+const SENDER_KEY_RECORD_TYPE: &[u8] = b"SenderKeyRecord";
+
+/// Migrations for [SenderKeyRecord]; see [record_version].
+const SENDER_KEY_RECORD_MIGRATIONS: &[Migration<storage_proto::SenderKeyRecordStructure>] =
+    &[record_version::add_version_field];
+
 #[derive(Debug, Clone)]
 pub struct SenderKeyRecord {
     states: VecDeque<SenderKeyState>,
@@ -242,9 +360,32 @@ impl SenderKeyRecord {
         }
     }
 
-    pub fn deserialize(buf: &[u8]) -> Result<SenderKeyRecord, SignalProtocolError> {
-        let skr = storage_proto::SenderKeyRecordStructure::decode(buf)
+    /// The format version written by [serialize](Self::serialize).
+    ///
+    /// Records in older formats are upgraded when they are loaded; records in newer formats are
+    /// rejected with [SignalProtocolError::UnrecognizedRecordVersion].
+    pub const CURRENT_FORMAT_VERSION: u32 = SENDER_KEY_RECORD_MIGRATIONS.len() as u32;
+
+    /// Decodes a serialized record and brings it up to the current format, also returning the
+    /// format it was stored in.
+    fn decode_and_migrate(
+        buf: &[u8],
+    ) -> Result<(storage_proto::SenderKeyRecordStructure, u32), SignalProtocolError> {
+        let mut skr = storage_proto::SenderKeyRecordStructure::decode(buf)
             .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+        let stored_version = skr.format_version;
+        record_version::migrate(
+            "SenderKeyRecord",
+            &mut skr,
+            stored_version,
+            SENDER_KEY_RECORD_MIGRATIONS,
+        )?;
+        skr.format_version = Self::CURRENT_FORMAT_VERSION;
+        Ok((skr, stored_version))
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<SenderKeyRecord, SignalProtocolError> {
+        let (skr, _) = Self::decode_and_migrate(buf)?;
 
         let mut states = VecDeque::with_capacity(skr.sender_key_states.len());
         for state in skr.sender_key_states {
@@ -253,6 +394,70 @@ impl SenderKeyRecord {
         Ok(Self { states })
     }
 
+    /// Like [deserialize](Self::deserialize), for a record written by
+    /// [serialize_with_integrity](Self::serialize_with_integrity) under the same `key` and
+    /// `name`.
+    pub fn deserialize_with_integrity(
+        buf: &[u8],
+        key: &[u8],
+        name: &SenderKeyName,
+        mode: IntegrityMode,
+    ) -> Result<SenderKeyRecord, SignalProtocolError> {
+        Self::deserialize(record_integrity::check_tag(
+            key,
+            SENDER_KEY_RECORD_TYPE,
+            &record_integrity::sender_key_location(name),
+            buf,
+            mode,
+        )?)
+    }
+
+    /// Upgrades a record written by [serialize](Self::serialize) in an older format to
+    /// [CURRENT_FORMAT_VERSION](Self::CURRENT_FORMAT_VERSION), returning `None` if it is already
+    /// current.
+    ///
+    /// As with [SessionRecord::migrate](crate::SessionRecord::migrate), this is only needed to
+    /// rewrite stored records ahead of time.
+    pub fn migrate(buf: &[u8]) -> Result<Option<Vec<u8>>, SignalProtocolError> {
+        let (skr, stored_version) = Self::decode_and_migrate(buf)?;
+        if stored_version == Self::CURRENT_FORMAT_VERSION {
+            return Ok(None);
+        }
+        Ok(Some(skr.encode_to_vec()))
+    }
+
+    /// Imports a sender key record serialized by libsignal-protocol-java's `SenderKeyRecord`.
+    ///
+    /// Java's states carry no message version (they are all version 3), and may include states
+    /// whose signing key doesn't parse, which are dropped. Java identified sender keys by group ID
+    /// rather than by [DistributionId]; the caller picks the distribution ID to store the result
+    /// under, and has to use the same one for the group from then on.
+    pub fn from_legacy_java_format(buf: &[u8]) -> Result<SenderKeyRecord, SignalProtocolError> {
+        let skr = storage_proto::SenderKeyRecordStructure::decode(buf)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+
+        let mut states = VecDeque::with_capacity(consts::MAX_SENDER_KEY_STATES);
+        for mut state in skr.sender_key_states {
+            let signing_key_valid = match &state.sender_signing_key {
+                Some(key) => PublicKey::deserialize(&key.public).is_ok(),
+                None => false,
+            };
+            if !signing_key_valid || state.sender_chain_key.is_none() {
+                log::info!(
+                    "dropping legacy sender key state with chain ID {}",
+                    state.chain_id
+                );
+                continue;
+            }
+            if state.message_version == 0 {
+                state.message_version = SENDERKEY_MESSAGE_CURRENT_VERSION.into();
+            }
+            states.push_back(SenderKeyState::from_protobuf(state));
+        }
+        states.truncate(consts::MAX_SENDER_KEY_STATES);
+        Ok(Self { states })
+    }
+
     pub(crate) fn sender_key_state(&self) -> Result<&SenderKeyState, InvalidSessionError> {
         if !self.states.is_empty() {
             return Ok(&self.states[0]);
@@ -350,12 +555,31 @@ impl SenderKeyRecord {
 
         storage_proto::SenderKeyRecordStructure {
             sender_key_states: states,
+            format_version: Self::CURRENT_FORMAT_VERSION,
         }
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, SignalProtocolError> {
         Ok(self.as_protobuf().encode_to_vec())
     }
+
+    /// Like [serialize](Self::serialize), but with an HMAC under `key` attached, so that
+    /// corruption or tampering in storage is caught by
+    /// [deserialize_with_integrity](Self::deserialize_with_integrity).
+    ///
+    /// `name` is the name the record is being stored under.
+    pub fn serialize_with_integrity(
+        &self,
+        key: &[u8],
+        name: &SenderKeyName,
+    ) -> Result<Vec<u8>, SignalProtocolError> {
+        Ok(record_integrity::add_tag(
+            key,
+            SENDER_KEY_RECORD_TYPE,
+            &record_integrity::sender_key_location(name),
+            self.serialize()?,
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -584,3 +808,132 @@ mod sender_key_record_add_sender_key_state_tests {
         context.assert_record_order(vec![record_key_1, record_key_2]);
     }
 }
+
+#[cfg(test)]
+mod distribution_id_tests {
+    use super::*;
+
+    #[test]
+    fn derive_is_deterministic() {
+        let id = DistributionId::derive(b"group", 3);
+        assert_eq!(id, DistributionId::derive(b"group", 3));
+        assert_eq!(id.as_uuid().get_version_num(), 8);
+        assert_eq!(id.as_uuid().get_variant(), uuid::Variant::RFC4122);
+
+        assert_ne!(id, DistributionId::derive(b"group", 4));
+        assert_ne!(id, DistributionId::derive(b"other group", 3));
+        assert_eq!(
+            id.to_string(),
+            Uuid::from(DistributionId::derive(b"group", 3)).to_string()
+        );
+    }
+
+    #[test]
+    fn derive_for_sender_is_deterministic() {
+        let id = DistributionId::derive_for_sender(b"group", "alice");
+        assert_eq!(id, DistributionId::derive_for_sender(b"group", "alice"));
+        assert_eq!(id.as_uuid().get_version_num(), 8);
+
+        assert_ne!(id, DistributionId::derive_for_sender(b"group", "bob"));
+        assert_ne!(
+            id,
+            DistributionId::derive_for_sender(b"other group", "alice")
+        );
+        // The group id is length-prefixed, so moving bytes between the inputs changes the result.
+        assert_ne!(id, DistributionId::derive_for_sender(b"groupa", "lice"));
+    }
+}
+
+#[cfg(test)]
+mod format_version_tests {
+    use rand::rngs::OsRng;
+
+    use crate::KeyPair;
+
+    use super::*;
+
+    #[test]
+    fn legacy_records_are_migrated() -> Result<(), SignalProtocolError> {
+        let mut record = SenderKeyRecord::new_empty();
+        record.add_sender_key_state(
+            3,
+            7,
+            0,
+            &[0x42; 32],
+            KeyPair::generate(&mut OsRng).public_key,
+            None,
+        );
+        let legacy = storage_proto::SenderKeyRecordStructure {
+            format_version: 0,
+            ..record.as_protobuf()
+        }
+        .encode_to_vec();
+
+        let loaded = SenderKeyRecord::deserialize(&legacy)?;
+        assert_eq!(loaded.chain_ids_for_logging().collect::<Vec<_>>(), [7]);
+
+        let migrated = SenderKeyRecord::migrate(&legacy)?.expect("needs migration");
+        assert_eq!(migrated, record.serialize()?);
+        assert!(SenderKeyRecord::migrate(&migrated)?.is_none());
+
+        let future = storage_proto::SenderKeyRecordStructure {
+            format_version: SenderKeyRecord::CURRENT_FORMAT_VERSION + 1,
+            ..record.as_protobuf()
+        }
+        .encode_to_vec();
+        assert!(matches!(
+            SenderKeyRecord::deserialize(&future),
+            Err(SignalProtocolError::UnrecognizedRecordVersion(
+                "SenderKeyRecord",
+                _
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_java_import() -> Result<(), SignalProtocolError> {
+        let mut record = SenderKeyRecord::new_empty();
+        record.add_sender_key_state(
+            3,
+            7,
+            0,
+            &[0x42; 32],
+            KeyPair::generate(&mut OsRng).public_key,
+            None,
+        );
+        record.add_sender_key_state(
+            3,
+            8,
+            0,
+            &[0x43; 32],
+            KeyPair::generate(&mut OsRng).public_key,
+            None,
+        );
+        let mut legacy = record.as_protobuf();
+        legacy.format_version = 0;
+        for state in &mut legacy.sender_key_states {
+            state.message_version = 0;
+        }
+        legacy.sender_key_states[0]
+            .sender_signing_key
+            .as_mut()
+            .expect("present")
+            .public = vec![0x05; 3];
+
+        let imported = SenderKeyRecord::from_legacy_java_format(&legacy.encode_to_vec())?;
+        assert_eq!(imported.chain_ids_for_logging().collect::<Vec<_>>(), [7]);
+        assert_eq!(
+            imported
+                .sender_key_state()
+                .expect("present")
+                .message_version(),
+            3
+        );
+        assert_eq!(
+            imported.as_protobuf().sender_key_states[0].message_version,
+            3
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::VecDeque;
+
+use crate::{
+    message_encrypt, CiphertextMessage, Context, IdentityKeyStore, ProtocolAddress, PublicKey,
+    Result, SessionStore,
+};
+
+/// Identifies a message in a [`SentMessageCache`].
+///
+/// Counters restart on every new sending chain, so a counter alone is ambiguous; paired with the
+/// ratchet key of the chain the message was sent on, it names exactly one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SentMessageKey {
+    ratchet_key: PublicKey,
+    counter: u32,
+}
+
+impl SentMessageKey {
+    pub fn new(ratchet_key: PublicKey, counter: u32) -> Self {
+        Self {
+            ratchet_key,
+            counter,
+        }
+    }
+
+    /// The sender ratchet key of the chain the message was sent on.
+    pub fn ratchet_key(&self) -> &PublicKey {
+        &self.ratchet_key
+    }
+
+    /// The message's counter within that chain.
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+}
+
+/// Remembers recently encrypted messages so a failed send can be retried with the same
+/// ciphertext.
+///
+/// Calling [`message_encrypt`] again after a transport failure advances the ratchet, leaving a
+/// gap in the counters the recipient sees (and using up one of the skipped message keys they are
+/// willing to hold on to). Encrypting with [`message_encrypt_cached`] instead keeps the result
+/// here, so it can be looked up by its [`SentMessageKey`] and resent as-is.
+///
+/// The cache holds at most `capacity` messages across all recipients, evicting the oldest first.
+/// It lives only in memory; messages encrypted before a restart must be encrypted afresh.
+#[derive(Debug)]
+pub struct SentMessageCache {
+    capacity: usize,
+    entries: VecDeque<(ProtocolAddress, SentMessageKey, CiphertextMessage)>,
+}
+
+impl SentMessageCache {
+    /// Creates an empty cache that holds up to `capacity` messages (at least one).
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The message encrypted for `address` with `key`, if still cached.
+    pub fn get(
+        &self,
+        address: &ProtocolAddress,
+        key: &SentMessageKey,
+    ) -> Option<&CiphertextMessage> {
+        self.entries
+            .iter()
+            .find(|(a, k, _)| a == address && k == key)
+            .map(|(_, _, message)| message)
+    }
+
+    /// The message most recently encrypted for `address`, if still cached.
+    pub fn last(&self, address: &ProtocolAddress) -> Option<&CiphertextMessage> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(a, _, _)| a == address)
+            .map(|(_, _, message)| message)
+    }
+
+    /// Forgets the message sent to `address` with `key`, once it has been delivered.
+    pub fn remove(
+        &mut self,
+        address: &ProtocolAddress,
+        key: &SentMessageKey,
+    ) -> Option<CiphertextMessage> {
+        let index = self
+            .entries
+            .iter()
+            .position(|(a, k, _)| a == address && k == key)?;
+        self.entries.remove(index).map(|(_, _, message)| message)
+    }
+
+    /// Forgets every message sent to `address`, for instance after its session is reset.
+    pub fn clear(&mut self, address: &ProtocolAddress) {
+        self.entries.retain(|(a, _, _)| a != address);
+    }
+
+    /// The number of cached messages.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(
+        &mut self,
+        address: &ProtocolAddress,
+        key: SentMessageKey,
+        message: CiphertextMessage,
+    ) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((address.clone(), key, message));
+    }
+}
+
+fn key_of(message: &CiphertextMessage) -> Option<SentMessageKey> {
+    let message = match message {
+        CiphertextMessage::SignalMessage(m) => m,
+        CiphertextMessage::PreKeySignalMessage(m) => m.message(),
+        CiphertextMessage::SenderKeyMessage(_) | CiphertextMessage::PlaintextContent(_) => {
+            return None
+        }
+    };
+    Some(SentMessageKey::new(
+        *message.sender_ratchet_key().ok()?,
+        message.counter().ok()?,
+    ))
+}
+
+/// Like [`message_encrypt`], but also keeps the result in `cache`.
+///
+/// Returns the key the message was cached under, which can later be passed to
+/// [`SentMessageCache::get`] to retry the send without encrypting again.
+pub async fn message_encrypt_cached<'c>(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    cache: &'c mut SentMessageCache,
+    ctx: Context,
+) -> Result<(SentMessageKey, &'c CiphertextMessage)> {
+    let message =
+        message_encrypt(ptext, remote_address, session_store, identity_store, ctx).await?;
+    let key = key_of(&message).expect("message_encrypt produces 1:1 messages");
+    cache.insert(remote_address, key, message);
+    Ok((key, cache.last(remote_address).expect("just inserted")))
+}
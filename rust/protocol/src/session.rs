@@ -4,19 +4,30 @@
 //
 
 use crate::{
-    kem, Context, Direction, IdentityKeyStore, KeyPair, KyberPreKeyId, KyberPreKeyStore,
-    PreKeyBundle, PreKeyId, PreKeySignalMessage, PreKeyStore, ProtocolAddress, Result,
-    SessionRecord, SessionStore, SignalProtocolError, SignedPreKeyStore,
+    kem, observer, CiphertextMessage, Context, DecryptionErrorMessage, Direction, IdentityKeyStore,
+    KeyPair, KyberPreKeyId, KyberPreKeyStore, PreKeyBundle, PreKeyId, PreKeySignalMessage,
+    PreKeyStore, ProtocolAddress, ProtocolStore, Result, SessionRecord, SessionStore,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyStore, Timestamp,
 };
 
+use crate::protocol::{version_has_encrypted_header, version_uses_aead};
 use crate::ratchet;
 use crate::ratchet::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
 use crate::state::GenericSignedPreKey;
+use crate::storage::SharedStore;
 use rand::{CryptoRng, Rng};
+use std::time::SystemTime;
 
-#[derive(Default)]
+/// The pre-keys used to set up a session by [process_prekey].
+///
+/// All fields are `None` if the message was for a session that had already been set up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct PreKeysUsed {
+    /// The one-time pre-key consumed, if the sender used one.
     pub pre_key_id: Option<PreKeyId>,
+    /// The signed pre-key used.
+    pub signed_pre_key_id: Option<SignedPreKeyId>,
+    /// The Kyber pre-key used, if the sender used one.
     pub kyber_pre_key_id: Option<KyberPreKeyId>,
 }
 
@@ -67,9 +78,12 @@ pub async fn process_prekey(
     )
     .await?;
 
-    identity_store
+    if identity_store
         .save_identity(remote_address, their_identity_key, ctx)
-        .await?;
+        .await?
+    {
+        observer::notify(|o| o.identity_changed(remote_address));
+    }
 
     Ok(pre_keys_used)
 }
@@ -126,8 +140,10 @@ async fn process_prekey_impl(
         None
     };
 
-    let parameters = BobSignalProtocolParameters::new(
-        identity_store.get_identity_key_pair(ctx).await?,
+    let our_identity_private_key = identity_store.get_identity_private_key(ctx).await?;
+    let parameters = BobSignalProtocolParameters::with_identity_private_key(
+        our_identity_private_key.public_key()?.into(),
+        our_identity_private_key,
         our_signed_pre_key_pair, // signed pre key
         our_one_time_pre_key_pair,
         our_signed_pre_key_pair, // ratchet key
@@ -135,7 +151,9 @@ async fn process_prekey_impl(
         *message.identity_key(),
         *message.base_key(),
         message.kyber_ciphertext(),
-    );
+    )
+    .with_header_encryption(version_has_encrypted_header(message.message_version()))
+    .with_aead(version_uses_aead(message.message_version()));
 
     session_record.archive_current_state()?;
 
@@ -146,22 +164,69 @@ async fn process_prekey_impl(
     new_session.set_alice_base_key(&message.base_key().serialize());
 
     session_record.promote_state(new_session);
+    observer::notify(|o| o.session_created(remote_address));
 
     let pre_keys_used = PreKeysUsed {
         pre_key_id: message.pre_key_id(),
+        signed_pre_key_id: Some(message.signed_pre_key_id()),
         kyber_pre_key_id: message.kyber_pre_key_id(),
     };
     Ok(pre_keys_used)
 }
 
+/// Whether [`process_prekey_bundle_with_policy`] accepts a bundle without a one-time pre-key.
+///
+/// Servers hand out bundles with only a signed pre-key once a user's one-time pre-keys run out.
+/// Sessions set up that way are still secure, but an attacker who later compromises the signed
+/// pre-key can decrypt the first messages sent before the other side replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OneTimePreKeyPolicy {
+    /// Set up the session from the signed pre-key alone, as [`process_prekey_bundle`] does.
+    ///
+    /// [`SessionRecord::used_one_time_pre_key`] records which kind of session resulted.
+    Optional,
+    /// Fail with [`SignalProtocolError::InvalidArgument`], leaving the stores unchanged.
+    Required,
+}
+
 pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     bundle: &PreKeyBundle,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<()> {
+    process_prekey_bundle_with_policy(
+        remote_address,
+        session_store,
+        identity_store,
+        bundle,
+        OneTimePreKeyPolicy::Optional,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+/// Like [`process_prekey_bundle`], but handles a bundle without a one-time pre-key according to
+/// `policy`.
+pub async fn process_prekey_bundle_with_policy<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    bundle: &PreKeyBundle,
+    policy: OneTimePreKeyPolicy,
     mut csprng: &mut R,
     ctx: Context,
 ) -> Result<()> {
+    if policy == OneTimePreKeyPolicy::Required && bundle.pre_key_id()?.is_none() {
+        return Err(SignalProtocolError::InvalidArgument(format!(
+            "pre-key bundle for {} has no one-time pre-key",
+            remote_address
+        )));
+    }
+
     let their_identity_key = bundle.identity_key()?;
 
     if !identity_store
@@ -196,15 +261,31 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         .await?
         .unwrap_or_else(SessionRecord::new_fresh);
 
-    let our_base_key_pair = KeyPair::generate(&mut csprng);
     let their_signed_prekey = bundle.signed_pre_key_public()?;
-
     let their_one_time_prekey_id = bundle.pre_key_id()?;
 
-    let our_identity_key_pair = identity_store.get_identity_key_pair(ctx).await?;
+    let our_identity_private_key = identity_store.get_identity_private_key(ctx).await?;
+
+    // Every key in a session has to be on the same curve.
+    let key_type = their_identity_key.public_key().key_type();
+    let other_key_types = [
+        Some(their_signed_prekey.key_type()),
+        bundle.pre_key_public()?.map(|key| key.key_type()),
+        Some(our_identity_private_key.public_key()?.key_type()),
+    ];
+    if let Some(other) = other_key_types
+        .iter()
+        .flatten()
+        .find(|&&other| other != key_type)
+    {
+        return Err(SignalProtocolError::MismatchedKeyTypes(key_type, *other));
+    }
+
+    let our_base_key_pair = KeyPair::generate_with_key_type(key_type, &mut csprng);
 
-    let mut parameters = AliceSignalProtocolParameters::new(
-        our_identity_key_pair,
+    let mut parameters = AliceSignalProtocolParameters::with_identity_private_key(
+        our_identity_private_key.public_key()?.into(),
+        our_identity_private_key,
         our_base_key_pair,
         *their_identity_key,
         their_signed_prekey,
@@ -216,6 +297,8 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
 
     if let Some(key) = bundle.kyber_pre_key_public()? {
         parameters.set_their_kyber_pre_key(key);
+        parameters.set_header_encryption(bundle.supports_header_encryption());
+        parameters.set_aead(bundle.supports_aead());
     }
 
     let mut session = ratchet::initialize_alice_session(&parameters, csprng)?;
@@ -230,6 +313,7 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
         their_one_time_prekey_id,
         bundle.signed_pre_key_id()?,
         &our_base_key_pair.public_key,
+        SystemTime::now(),
     );
 
     if let Some(kyber_pre_key_id) = bundle.kyber_pre_key_id()? {
@@ -240,15 +324,147 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
     session.set_remote_registration_id(bundle.registration_id()?);
     session.set_alice_base_key(&our_base_key_pair.public_key.serialize());
 
-    identity_store
+    if identity_store
         .save_identity(remote_address, their_identity_key, ctx)
-        .await?;
+        .await?
+    {
+        observer::notify(|o| o.identity_changed(remote_address));
+    }
 
     session_record.promote_state(session);
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
+    store_session_with_archive_policy(session_store, remote_address, &mut session_record, ctx)
         .await?;
+    observer::notify(|o| o.session_created(remote_address));
 
     Ok(())
 }
+
+/// Like [`process_prekey_bundle`], but takes one store that fills every role.
+pub async fn process_prekey_bundle_with_protocol_store<
+    S: ProtocolStore + ?Sized,
+    R: Rng + CryptoRng,
+>(
+    remote_address: &ProtocolAddress,
+    store: &mut S,
+    bundle: &PreKeyBundle,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<()> {
+    let store = SharedStore::new(store);
+    process_prekey_bundle(
+        remote_address,
+        &mut store.handle(),
+        &mut store.handle(),
+        bundle,
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+/// Archives the current session with `remote_address`, returning a message that asks them to do
+/// the same.
+///
+/// The message is a [DecryptionErrorMessage] naming the other party's current ratchet key, sent
+/// as unencrypted [PlaintextContent](crate::PlaintextContent); `timestamp` is used as its
+/// timestamp. The other party should pass it to [process_session_reset]. Afterwards, the next
+/// message either side sends needs a new session, set up with [process_prekey_bundle] as usual.
+///
+/// Sessions that use encrypted headers can't be reset this way: naming their ratchet key in the
+/// clear would reveal it, and without it the other party can't tell which session to archive. For
+/// those this returns [SignalProtocolError::InvalidState] and leaves the session as it was.
+///
+/// The message is not authenticated in any way; see [process_session_reset] for what that means
+/// for the receiving side. Send it sealed sender, or over another channel that authenticates the
+/// sender, so the other party can tell it came from us.
+pub async fn session_reset(
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    timestamp: Timestamp,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    let mut session_record = session_store
+        .load_session(remote_address, ctx)
+        .await?
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
+    let session = session_record
+        .session_state()
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
+
+    if session.uses_header_encryption()? {
+        return Err(SignalProtocolError::InvalidState(
+            "session_reset",
+            format!(
+                "session with {} uses encrypted headers and can't be reset",
+                remote_address
+            ),
+        ));
+    }
+    let ratchet_key = session.latest_receiver_ratchet_key()?;
+    let message =
+        DecryptionErrorMessage::new(ratchet_key, timestamp, remote_address.device_id().into());
+
+    session_record.archive_current_state()?;
+    store_session_with_archive_policy(session_store, remote_address, &mut session_record, ctx)
+        .await?;
+
+    Ok(CiphertextMessage::PlaintextContent(message.into()))
+}
+
+/// Handles a reset requested by [session_reset] on the other side, archiving our current session
+/// with `remote_address` if it is the one `message` names.
+///
+/// Returns whether a session was archived.
+///
+/// # Security
+///
+/// Nothing in `message` proves who sent it. The only check is that it names the ratchet key of
+/// our current session, and that key appears in the clear in every message we send on a session
+/// without encrypted headers, so anyone who can see our traffic can forge a reset. A forged reset
+/// reveals nothing and can't substitute a session of the attacker's choosing (the next message
+/// still needs a fresh pre-key bundle for `remote_address`), but it can make us throw away
+/// sessions over and over. Callers must therefore:
+///
+/// - only pass in messages whose sender has already been authenticated, such as those that
+///   arrived sealed sender with a valid sender certificate for `remote_address`, or over a
+///   connection on which the server vouches for the sender; and
+/// - rate-limit resets per address, ignoring further requests for a while after one has been
+///   processed.
+pub async fn process_session_reset(
+    message: &DecryptionErrorMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    ctx: Context,
+) -> Result<bool> {
+    let ratchet_key = match message.ratchet_key() {
+        Some(ratchet_key) => ratchet_key,
+        None => return Ok(false),
+    };
+    let mut session_record = match session_store.load_session(remote_address, ctx).await? {
+        Some(session_record) => session_record,
+        None => return Ok(false),
+    };
+    if !session_record.current_ratchet_key_matches(ratchet_key)? {
+        return Ok(false);
+    }
+
+    session_record.archive_current_state()?;
+    store_session_with_archive_policy(session_store, remote_address, &mut session_record, ctx)
+        .await?;
+    Ok(true)
+}
+
+/// Trims `record` to the store's [archive_policy](SessionStore::archive_policy), then saves it and
+/// tells the observer.
+pub(crate) async fn store_session_with_archive_policy(
+    session_store: &mut dyn SessionStore,
+    address: &ProtocolAddress,
+    record: &mut SessionRecord,
+    ctx: Context,
+) -> Result<()> {
+    record.apply_archive_policy(&session_store.archive_policy(), SystemTime::now())?;
+    session_store.store_session(address, record, ctx).await?;
+    observer::notify(|o| o.session_updated(address));
+    Ok(())
+}
@@ -3,16 +3,23 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::borrow::Cow;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
 use rand::{CryptoRng, Rng};
 
-use crate::consts::MAX_FORWARD_JUMPS;
+use crate::consts::{MAX_FORWARD_JUMPS, MAX_UNACKNOWLEDGED_SESSION_AGE};
+use crate::ordering::MessageOrderingToken;
 use crate::ratchet::{ChainKey, MessageKeys};
-use crate::state::{InvalidSessionError, SessionState};
+use crate::state::{FlowEvent, InvalidSessionError, SessionState};
+use crate::storage::SharedStore;
 use crate::{
-    session, CiphertextMessage, CiphertextMessageType, Context, Direction, IdentityKeyStore,
-    KeyPair, KyberPayload, KyberPreKeyStore, PreKeySignalMessage, PreKeyStore, ProtocolAddress,
-    PublicKey, Result, SessionRecord, SessionStore, SignalMessage, SignalProtocolError,
-    SignedPreKeyStore,
+    observer, padding, session, CiphertextMessage, CiphertextMessageType, Context, Direction,
+    IdentityKey, IdentityKeyStore, KeyPair, KyberPayload, KyberPreKeyId, KyberPreKeyStore,
+    PreKeyId, PreKeySignalMessage, PreKeyStore, ProtocolAddress, ProtocolStore, PublicKey,
+    ReplayCache, ReplayKey, Result, SessionRecord, SessionStore, SignalMessage,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyStore,
 };
 
 pub async fn message_encrypt(
@@ -21,6 +28,148 @@ pub async fn message_encrypt(
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt_with_identity_policy(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        IdentityChangePolicy::AcceptNew,
+        ctx,
+    )
+    .await
+}
+
+/// Like [`message_encrypt`], but takes one store that fills every role.
+pub async fn message_encrypt_with_protocol_store<S: ProtocolStore + ?Sized>(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    store: &mut S,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    let store = SharedStore::new(store);
+    message_encrypt(
+        ptext,
+        remote_address,
+        &mut store.handle(),
+        &mut store.handle(),
+        ctx,
+    )
+    .await
+}
+
+/// Options for [`message_encrypt_with_config`] and [`message_decrypt_with_config`].
+///
+/// Both sides of a session must use the same options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// If set, plaintexts are [padded](crate::pad_plaintext_with_bucket_size) to a multiple of
+    /// this many bytes before encryption, and the padding is removed after decryption.
+    ///
+    /// Signal clients use [`DEFAULT_PADDING_BUCKET_SIZE`](crate::DEFAULT_PADDING_BUCKET_SIZE).
+    pub padding_bucket_size: Option<usize>,
+}
+
+/// Like [`message_encrypt`], but applies the options in `config`.
+pub async fn message_encrypt_with_config(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    config: SessionConfig,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    match config.padding_bucket_size {
+        Some(bucket_size) => {
+            let padded = padding::pad_plaintext_with_bucket_size(ptext, bucket_size)?;
+            message_encrypt(&padded, remote_address, session_store, identity_store, ctx).await
+        }
+        None => message_encrypt(ptext, remote_address, session_store, identity_store, ctx).await,
+    }
+}
+
+/// Like [`message_encrypt`], but handles a change in the recipient's identity key according to
+/// `policy`.
+pub async fn message_encrypt_with_identity_policy(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    policy: IdentityChangePolicy,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt_impl(
+        ptext,
+        &[],
+        remote_address,
+        session_store,
+        identity_store,
+        policy,
+        ctx,
+    )
+    .await
+}
+
+/// Like [`message_encrypt`], but attaches `alternate_identity_signature` to the message if it is
+/// a [`PreKeySignalMessage`], so the recipient can check it with
+/// [`PreKeySignalMessage::verify_alternate_identity`].
+///
+/// The signature should come from
+/// [`IdentityKeyPair::sign_alternate_identity`](crate::IdentityKeyPair::sign_alternate_identity).
+/// Messages on a session the recipient has already accepted are [`SignalMessage`]s and carry
+/// nothing extra.
+pub async fn message_encrypt_with_alternate_identity_signature(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    alternate_identity_signature: &[u8],
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    let message =
+        message_encrypt(ptext, remote_address, session_store, identity_store, ctx).await?;
+    Ok(match message {
+        CiphertextMessage::PreKeySignalMessage(message) => CiphertextMessage::PreKeySignalMessage(
+            message.with_alternate_identity_signature(alternate_identity_signature.into()),
+        ),
+        message => message,
+    })
+}
+
+/// Like [`message_encrypt`], but binds `associated_data` into the message's MAC (or AEAD tag).
+///
+/// The data is not sent. The recipient must pass the same bytes to
+/// [`message_decrypt_with_associated_data`], or decryption fails as if the message had been
+/// tampered with. Binding envelope details such as the timestamp and destination this way keeps a
+/// server from replaying a ciphertext under a different envelope.
+pub async fn message_encrypt_with_associated_data(
+    ptext: &[u8],
+    associated_data: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    message_encrypt_impl(
+        ptext,
+        associated_data,
+        remote_address,
+        session_store,
+        identity_store,
+        IdentityChangePolicy::AcceptNew,
+        ctx,
+    )
+    .await
+}
+
+async fn message_encrypt_impl(
+    ptext: &[u8],
+    associated_data: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    policy: IdentityChangePolicy,
+    ctx: Context,
 ) -> Result<CiphertextMessage> {
     let mut session_record = session_store
         .load_session(remote_address, ctx)
@@ -46,12 +195,48 @@ pub async fn message_encrypt(
         )
     })?;
 
-    let ctext =
-        signal_crypto::aes_256_cbc_encrypt(ptext, message_keys.cipher_key(), message_keys.iv())
-            .map_err(|_| {
-                log::error!("session state corrupt for {}", remote_address);
-                SignalProtocolError::InvalidSessionStructure("invalid sender chain message keys")
-            })?;
+    let header_key = if session_state.uses_header_encryption()? {
+        Some(session_state.sender_chain_header_key()?)
+    } else {
+        None
+    };
+
+    let message = if session_state.uses_aead()? {
+        SignalMessage::new_aead(
+            session_version,
+            &message_keys,
+            header_key.as_ref(),
+            sender_ephemeral,
+            chain_key.index(),
+            previous_counter,
+            ptext,
+            &local_identity_key,
+            &their_identity_key,
+            associated_data,
+        )?
+    } else {
+        let ctext =
+            signal_crypto::aes_256_cbc_encrypt(ptext, message_keys.cipher_key(), message_keys.iv())
+                .map_err(|_| {
+                    log::error!("session state corrupt for {}", remote_address);
+                    SignalProtocolError::InvalidSessionStructure(
+                        "invalid sender chain message keys",
+                    )
+                })?;
+
+        SignalMessage::new_with_associated_data(
+            session_version,
+            message_keys.mac_key(),
+            header_key.as_ref(),
+            sender_ephemeral,
+            chain_key.index(),
+            previous_counter,
+            &ctext,
+            &local_identity_key,
+            &their_identity_key,
+            associated_data,
+        )?
+    };
 
     let message = if let Some(items) = session_state.unacknowledged_pre_key_message_items()? {
         let local_registration_id = session_state.local_registration_id();
@@ -64,17 +249,6 @@ pub async fn message_encrypt(
                 .map_or_else(|| "<none>".to_string(), |id| id.to_string())
         );
 
-        let message = SignalMessage::new(
-            session_version,
-            message_keys.mac_key(),
-            sender_ephemeral,
-            chain_key.index(),
-            previous_counter,
-            &ctext,
-            &local_identity_key,
-            &their_identity_key,
-        )?;
-
         let kyber_payload = items
             .kyber_pre_key_id()
             .zip(items.kyber_ciphertext())
@@ -91,82 +265,681 @@ pub async fn message_encrypt(
             message,
         )?)
     } else {
-        CiphertextMessage::SignalMessage(SignalMessage::new(
-            session_version,
-            message_keys.mac_key(),
-            sender_ephemeral,
-            chain_key.index(),
-            previous_counter,
-            &ctext,
-            &local_identity_key,
-            &their_identity_key,
-        )?)
+        CiphertextMessage::SignalMessage(message)
     };
 
     session_state.set_sender_chain_key(&chain_key.next_chain_key());
+    session_state.record_flow_event(FlowEvent::Sent, SystemTime::now());
+
+    // XXX why is this check after everything else?!!
+    if !identity_store
+        .is_trusted_identity(remote_address, &their_identity_key, Direction::Sending, ctx)
+        .await?
+    {
+        log::warn!(
+            "Identity key {} is not trusted for remote address {}",
+            their_identity_key
+                .public_key()
+                .public_key_bytes()
+                .map_or_else(|e| format!("<error: {}>", e), hex::encode),
+            remote_address,
+        );
+        return Err(SignalProtocolError::UntrustedIdentity(
+            remote_address.clone(),
+        ));
+    }
+
+    let identity_changed = check_identity_change(
+        policy,
+        remote_address,
+        &their_identity_key,
+        identity_store,
+        ctx,
+    )
+    .await?;
+
+    // XXX this could be combined with the above call to the identity store (in a new API)
+    if identity_store
+        .save_identity(remote_address, &their_identity_key, ctx)
+        .await?
+    {
+        observer::notify(|o| o.identity_changed(remote_address));
+    }
+
+    if identity_changed && policy == IdentityChangePolicy::ArchiveSession {
+        session_record.archive_current_state()?;
+    }
+
+    session::store_session_with_archive_policy(
+        session_store,
+        remote_address,
+        &mut session_record,
+        ctx,
+    )
+    .await?;
+    Ok(message)
+}
+
+/// What to do when a session turns out to be using a different identity key for the remote party
+/// than the one in the [`IdentityKeyStore`].
+///
+/// This only comes up if the store trusts the new key (otherwise the operation fails with
+/// [`SignalProtocolError::UntrustedIdentity`] before the policy is consulted). Different products
+/// want different things at that point, so it is up to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityChangePolicy {
+    /// Save the new key and carry on, as [`message_encrypt`] and [`message_decrypt`] do.
+    AcceptNew,
+    /// Save the new key, then archive the session the message was sent or received on, so that
+    /// the next message needs a new session built from a fresh pre-key bundle.
+    ///
+    /// A session that was just set up by a PreKey message already uses the new key, so it is
+    /// kept.
+    ArchiveSession,
+    /// Fail with [`SignalProtocolError::IdentityKeyChanged`], leaving the stores unchanged.
+    Reject,
+}
+
+/// Returns whether `their_identity_key` replaces a different identity saved for
+/// `remote_address`, failing if `policy` doesn't allow that.
+///
+/// Must be called before the new key is saved.
+async fn check_identity_change(
+    policy: IdentityChangePolicy,
+    remote_address: &ProtocolAddress,
+    their_identity_key: &IdentityKey,
+    identity_store: &dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<bool> {
+    if policy == IdentityChangePolicy::AcceptNew {
+        // Nothing to do either way, so don't spend a store lookup finding out.
+        return Ok(false);
+    }
+    let changed = match identity_store.get_identity(remote_address, ctx).await? {
+        Some(saved) => !saved.ct_eq(their_identity_key),
+        None => false,
+    };
+    if changed {
+        log::warn!("Identity key changed for remote address {}", remote_address);
+        if policy == IdentityChangePolicy::Reject {
+            return Err(SignalProtocolError::IdentityKeyChanged(
+                remote_address.clone(),
+            ));
+        }
+    }
+    Ok(changed)
+}
+
+/// A reason [`message_encrypt`] would fail (or produce a message unlikely to be decrypted), as
+/// reported by [`can_encrypt`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptionProblem {
+    /// There is no session with the recipient; fetch a pre-key bundle and call
+    /// [`process_prekey_bundle`](crate::process_prekey_bundle).
+    NoSession,
+    /// The recipient only has archived sessions, which can decrypt but not encrypt; fetch a new
+    /// pre-key bundle.
+    ArchivedSessionOnly,
+    /// The recipient's identity key is not trusted for sending; the user needs to accept the new
+    /// key first.
+    UntrustedIdentity(IdentityKey),
+    /// The session has not been acknowledged by the recipient since it was created from their
+    /// signed pre-key `signed_pre_key_id`, `age` ago. The recipient has probably deleted that
+    /// signed pre-key by now, so a new pre-key bundle should be fetched.
+    StaleSignedPreKey {
+        signed_pre_key_id: SignedPreKeyId,
+        age: Duration,
+    },
+}
+
+impl fmt::Display for EncryptionProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSession => write!(f, "no session"),
+            Self::ArchivedSessionOnly => write!(f, "only archived sessions"),
+            Self::UntrustedIdentity(_) => write!(f, "untrusted identity"),
+            Self::StaleSignedPreKey {
+                signed_pre_key_id,
+                age,
+            } => write!(
+                f,
+                "session from signed pre-key {} unacknowledged for {}s",
+                signed_pre_key_id,
+                age.as_secs()
+            ),
+        }
+    }
+}
+
+/// The outcome of [`can_encrypt`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionReadiness {
+    remote_address: ProtocolAddress,
+    problems: Vec<EncryptionProblem>,
+}
+
+impl EncryptionReadiness {
+    pub fn is_ready(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn problems(&self) -> &[EncryptionProblem] {
+        &self.problems
+    }
+
+    /// Convert the first problem found (if any) into the error [`message_encrypt`] would report.
+    ///
+    /// A stale signed pre-key never counts as an error, because [`message_encrypt`] still succeeds;
+    /// check [`problems`](Self::problems) to find out about one.
+    pub fn into_result(self) -> Result<()> {
+        let remote_address = self.remote_address;
+        for problem in self.problems {
+            match problem {
+                EncryptionProblem::NoSession | EncryptionProblem::ArchivedSessionOnly => {
+                    return Err(SignalProtocolError::SessionNotFound(remote_address));
+                }
+                EncryptionProblem::UntrustedIdentity(_) => {
+                    return Err(SignalProtocolError::UntrustedIdentity(remote_address));
+                }
+                EncryptionProblem::StaleSignedPreKey { .. } => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check whether a message could be encrypted for `remote_address` right now, without advancing
+/// any session state.
+///
+/// Unlike [`message_encrypt`], which stops at the first failure (and checks the identity key
+/// last), this reports every problem it finds, so a client can resolve them before sending.
+pub async fn can_encrypt(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    identity_store: &dyn IdentityKeyStore,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<EncryptionReadiness> {
+    let mut problems = Vec::new();
+
+    let session_record = session_store.load_session(remote_address, ctx).await?;
+    let session_state = session_record
+        .as_ref()
+        .and_then(|record| record.session_state());
+    match (&session_record, session_state) {
+        (None, _) => problems.push(EncryptionProblem::NoSession),
+        (Some(record), None) => {
+            if record.previous_session_states().next().is_some() {
+                problems.push(EncryptionProblem::ArchivedSessionOnly);
+            } else {
+                problems.push(EncryptionProblem::NoSession);
+            }
+        }
+        (Some(_), Some(state)) => {
+            let their_identity_key = state.remote_identity_key()?.ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "can_encrypt",
+                    format!("no remote identity key for {}", remote_address),
+                )
+            })?;
+            if !identity_store
+                .is_trusted_identity(remote_address, &their_identity_key, Direction::Sending, ctx)
+                .await?
+            {
+                problems.push(EncryptionProblem::UntrustedIdentity(their_identity_key));
+            }
+
+            if let Some(items) = state.unacknowledged_pre_key_message_items()? {
+                let age = items
+                    .timestamp()
+                    .and_then(|created| now.duration_since(created).ok());
+                if let Some(age) = age {
+                    if age > MAX_UNACKNOWLEDGED_SESSION_AGE {
+                        problems.push(EncryptionProblem::StaleSignedPreKey {
+                            signed_pre_key_id: items.signed_pre_key_id(),
+                            age,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(EncryptionReadiness {
+        remote_address: remote_address.clone(),
+        problems,
+    })
+}
+
+/// What kind of session there is with a recipient, as reported by
+/// [`session_exists_and_is_current`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAvailability {
+    /// There is no session at all; fetch a pre-key bundle before sending.
+    None,
+    /// There are only archived sessions, which can decrypt late messages but not encrypt; fetch a
+    /// pre-key bundle before sending.
+    ArchivedOnly,
+    /// [`message_encrypt`] will use the current session, which has the given version.
+    Current { version: u32 },
+}
+
+impl SessionAvailability {
+    /// Whether a pre-key bundle has to be processed before [`message_encrypt`] can succeed.
+    pub fn needs_pre_key_bundle(self) -> bool {
+        !matches!(self, Self::Current { .. })
+    }
+}
+
+/// Check whether there is a current session with `remote_address`, so a sender can decide whether
+/// to fetch a pre-key bundle before calling [`message_encrypt`].
+///
+/// This only looks at the session record; use [`can_encrypt`] to also check the recipient's
+/// identity and whether the session has gone unacknowledged for too long.
+pub async fn session_exists_and_is_current(
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    ctx: Context,
+) -> Result<SessionAvailability> {
+    let session_record = match session_store.load_session(remote_address, ctx).await? {
+        Some(record) => record,
+        None => return Ok(SessionAvailability::None),
+    };
+    match session_record.session_state() {
+        Some(state) => Ok(SessionAvailability::Current {
+            version: state.session_version()?,
+        }),
+        None if session_record.previous_session_states().next().is_some() => {
+            Ok(SessionAvailability::ArchivedOnly)
+        }
+        None => Ok(SessionAvailability::None),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    match ciphertext {
+        CiphertextMessage::SignalMessage(m) => {
+            message_decrypt_signal(
+                m,
+                remote_address,
+                session_store,
+                identity_store,
+                csprng,
+                ctx,
+            )
+            .await
+        }
+        CiphertextMessage::PreKeySignalMessage(m) => {
+            message_decrypt_prekey(
+                m,
+                remote_address,
+                session_store,
+                identity_store,
+                pre_key_store,
+                signed_pre_key_store,
+                kyber_pre_key_store,
+                csprng,
+                ctx,
+            )
+            .await
+        }
+        _ => Err(SignalProtocolError::InvalidArgument(format!(
+            "message_decrypt cannot be used to decrypt {:?} messages",
+            ciphertext.message_type()
+        ))),
+    }
+}
+
+/// Like [`message_decrypt`], but takes one store that fills every role.
+pub async fn message_decrypt_with_protocol_store<S: ProtocolStore + ?Sized, R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    store: &mut S,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let store = SharedStore::new(store);
+    message_decrypt(
+        ciphertext,
+        remote_address,
+        &mut store.handle(),
+        &mut store.handle(),
+        &mut store.handle(),
+        &mut store.handle(),
+        &mut store.handle(),
+        csprng,
+        ctx,
+    )
+    .await
+}
+
+/// Like [`message_decrypt`], but applies the options in `config`.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_config<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    config: SessionConfig,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let ptext = message_decrypt(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        csprng,
+        ctx,
+    )
+    .await?;
+    match config.padding_bucket_size {
+        Some(_) => Ok(padding::unpad_plaintext(&ptext)?.to_vec()),
+        None => Ok(ptext),
+    }
+}
+
+/// Like [`message_decrypt`], for a message encrypted with
+/// [`message_encrypt_with_associated_data`].
+///
+/// `associated_data` must match what the sender passed, or decryption fails as it would for a
+/// corrupted message.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_associated_data<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    associated_data: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    message_decrypt_with_options(
+        ciphertext,
+        associated_data,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        None,
+        WorkLimit::UNLIMITED,
+        IdentityChangePolicy::AcceptNew,
+        csprng,
+        ctx,
+    )
+    .await
+    .map(|result| result.plaintext)
+}
+
+/// The plaintext of a decrypted message, along with details about how it was decrypted.
+#[derive(Debug, Clone)]
+pub struct DecryptResult {
+    pub plaintext: Vec<u8>,
+    /// The protocol version of the message.
+    pub message_version: u8,
+    /// The message's index in its sending chain.
+    pub counter: u32,
+    /// Whether decrypting this message set up a new session (only possible for PreKey messages).
+    pub session_was_created: bool,
+    /// The one-time pre-key consumed to set up the new session, if any.
+    ///
+    /// Clients can use this to decide when to upload more pre-keys.
+    pub pre_key_used: Option<PreKeyId>,
+    /// The signed pre-key used to set up the new session, if any.
+    pub signed_pre_key_used: Option<SignedPreKeyId>,
+    /// The Kyber pre-key used to set up the new session, if any.
+    pub kyber_pre_key_used: Option<KyberPreKeyId>,
+    /// Where this message falls in the order the sender sent them, if known.
+    ///
+    /// This is `None` for messages on chains received before ordering tokens were introduced.
+    pub ordering_token: Option<MessageOrderingToken>,
+}
+
+/// A cap on the work a single call to [`message_decrypt_with_work_limit`] may do.
+///
+/// Without one, a message with a large counter, or one that has to be tried against every archived
+/// session, can cost thousands of key derivations to reject. Services that decrypt traffic from
+/// untrusted senders can set a limit to bound that cost; decryption stops with
+/// [`SignalProtocolError::WorkLimitExceeded`] before doing work beyond it, leaving the session
+/// unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkLimit {
+    /// Chain key derivations, mostly from skipping ahead to a message's counter.
+    pub max_chain_key_derivations: u32,
+    /// Elliptic curve operations, from setting up a new session or advancing the ratchet.
+    pub max_curve_operations: u32,
+}
+
+impl WorkLimit {
+    /// No limit beyond the protocol's own limit on skipped messages.
+    pub const UNLIMITED: Self = Self {
+        max_chain_key_derivations: u32::MAX,
+        max_curve_operations: u32::MAX,
+    };
+}
+
+/// The work left before hitting a [`WorkLimit`].
+struct WorkBudget(WorkLimit);
+
+impl WorkBudget {
+    fn spend_chain_key_derivations(&mut self, count: u32) -> Result<()> {
+        Self::spend(
+            &mut self.0.max_chain_key_derivations,
+            count,
+            "chain key derivations",
+        )
+    }
+
+    fn spend_curve_operations(&mut self, count: u32) -> Result<()> {
+        Self::spend(&mut self.0.max_curve_operations, count, "curve operations")
+    }
+
+    fn spend(remaining: &mut u32, count: u32, what: &'static str) -> Result<()> {
+        *remaining = remaining
+            .checked_sub(count)
+            .ok_or(SignalProtocolError::WorkLimitExceeded(what))?;
+        Ok(())
+    }
+}
+
+/// Like [`message_decrypt`], but also returns metadata about the message and how it was
+/// processed.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_info<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<DecryptResult> {
+    message_decrypt_with_work_limit(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        WorkLimit::UNLIMITED,
+        csprng,
+        ctx,
+    )
+    .await
+}
 
-    // XXX why is this check after everything else?!!
-    if !identity_store
-        .is_trusted_identity(remote_address, &their_identity_key, Direction::Sending, ctx)
-        .await?
-    {
-        log::warn!(
-            "Identity key {} is not trusted for remote address {}",
-            their_identity_key
-                .public_key()
-                .public_key_bytes()
-                .map_or_else(|e| format!("<error: {}>", e), hex::encode),
-            remote_address,
-        );
-        return Err(SignalProtocolError::UntrustedIdentity(
-            remote_address.clone(),
-        ));
-    }
+/// Like [`message_decrypt_with_info`], but gives up once decryption has done `limit`'s worth of
+/// work.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_work_limit<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    limit: WorkLimit,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<DecryptResult> {
+    message_decrypt_with_identity_policy(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        limit,
+        IdentityChangePolicy::AcceptNew,
+        csprng,
+        ctx,
+    )
+    .await
+}
 
-    // XXX this could be combined with the above call to the identity store (in a new API)
-    identity_store
-        .save_identity(remote_address, &their_identity_key, ctx)
-        .await?;
+/// Like [`message_decrypt_with_work_limit`], but handles a change in the sender's identity key
+/// according to `policy`.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_identity_policy<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    limit: WorkLimit,
+    policy: IdentityChangePolicy,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<DecryptResult> {
+    message_decrypt_with_options(
+        ciphertext,
+        &[],
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        None,
+        limit,
+        policy,
+        csprng,
+        ctx,
+    )
+    .await
+}
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
-    Ok(message)
+/// Like [`message_decrypt_with_identity_policy`], but rejects messages found in `replay_cache`
+/// with [`SignalProtocolError::DuplicatedMessage`], and adds the message to it once decrypted.
+///
+/// The session is left unchanged when a replay is rejected.
+#[allow(clippy::too_many_arguments)]
+pub async fn message_decrypt_with_replay_cache<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    replay_cache: &mut dyn ReplayCache,
+    limit: WorkLimit,
+    policy: IdentityChangePolicy,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<DecryptResult> {
+    message_decrypt_with_options(
+        ciphertext,
+        &[],
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        Some(replay_cache),
+        limit,
+        policy,
+        csprng,
+        ctx,
+    )
+    .await
 }
 
 #[allow(clippy::too_many_arguments)]
-pub async fn message_decrypt<R: Rng + CryptoRng>(
+async fn message_decrypt_with_options<R: Rng + CryptoRng>(
     ciphertext: &CiphertextMessage,
+    associated_data: &[u8],
     remote_address: &ProtocolAddress,
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
     kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    replay_cache: Option<&mut dyn ReplayCache>,
+    limit: WorkLimit,
+    policy: IdentityChangePolicy,
     csprng: &mut R,
     ctx: Context,
-) -> Result<Vec<u8>> {
+) -> Result<DecryptResult> {
     match ciphertext {
         CiphertextMessage::SignalMessage(m) => {
-            message_decrypt_signal(
+            message_decrypt_signal_with_info(
                 m,
+                associated_data,
                 remote_address,
                 session_store,
                 identity_store,
+                replay_cache,
+                limit,
+                policy,
                 csprng,
                 ctx,
             )
             .await
         }
         CiphertextMessage::PreKeySignalMessage(m) => {
-            message_decrypt_prekey(
+            message_decrypt_prekey_with_info(
                 m,
+                associated_data,
                 remote_address,
                 session_store,
                 identity_store,
                 pre_key_store,
                 signed_pre_key_store,
                 kyber_pre_key_store,
+                replay_cache,
+                limit,
+                policy,
                 csprng,
                 ctx,
             )
@@ -179,6 +952,52 @@ pub async fn message_decrypt<R: Rng + CryptoRng>(
     }
 }
 
+/// Fails with [`SignalProtocolError::DuplicatedMessage`] if `replay_cache` has seen the message
+/// with `replay_key`, which has just been decrypted using `record`.
+async fn check_replay(
+    replay_cache: Option<&dyn ReplayCache>,
+    replay_key: &ReplayKey,
+    record: &SessionRecord,
+    message: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    ctx: Context,
+) -> Result<()> {
+    let replay_cache = match replay_cache {
+        Some(replay_cache) => replay_cache,
+        None => return Ok(()),
+    };
+    if !replay_cache.contains(replay_key, ctx).await? {
+        return Ok(());
+    }
+    let counter = message.counter()?;
+    log::warn!(
+        "rejecting replayed message from {} with counter {}",
+        remote_address,
+        counter
+    );
+    let chain_index = record
+        .session_state()
+        .and_then(|state| {
+            state
+                .get_receiver_chain_key(message.sender_ratchet_key().ok()?)
+                .ok()
+                .flatten()
+        })
+        .map_or(counter, |chain_key| chain_key.index());
+    observer::notify(|o| o.duplicate_message(remote_address, chain_index, counter));
+    Err(SignalProtocolError::DuplicatedMessage(chain_index, counter))
+}
+
+/// Counts `event` in the flow statistics of the current session in `record`, if there is one.
+///
+/// Messages decrypted (or rejected as duplicates) by an archived session are counted against the
+/// current one.
+fn record_flow_event(record: &mut SessionRecord, event: FlowEvent) {
+    if let Some(state) = record.session_state_mut() {
+        state.record_flow_event(event, SystemTime::now());
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
     ciphertext: &PreKeySignalMessage,
@@ -191,11 +1010,71 @@ pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
     csprng: &mut R,
     ctx: Context,
 ) -> Result<Vec<u8>> {
+    message_decrypt_prekey_with_info(
+        ciphertext,
+        &[],
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        kyber_pre_key_store,
+        None,
+        WorkLimit::UNLIMITED,
+        IdentityChangePolicy::AcceptNew,
+        csprng,
+        ctx,
+    )
+    .await
+    .map(|result| result.plaintext)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn message_decrypt_prekey_with_info<R: Rng + CryptoRng>(
+    ciphertext: &PreKeySignalMessage,
+    associated_data: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    kyber_pre_key_store: &mut dyn KyberPreKeyStore,
+    replay_cache: Option<&mut dyn ReplayCache>,
+    limit: WorkLimit,
+    policy: IdentityChangePolicy,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<DecryptResult> {
+    let mut budget = WorkBudget(limit);
     let mut session_record = session_store
         .load_session(remote_address, ctx)
         .await?
         .unwrap_or_else(SessionRecord::new_fresh);
 
+    let session_already_existed = session_record.has_session_state(
+        ciphertext.message_version() as u32,
+        &ciphertext.base_key().serialize(),
+    )?;
+    if !session_already_existed {
+        // One agreement each for the identity, signed, and one-time pre-keys.
+        let agreements = if ciphertext.pre_key_id().is_some() {
+            4
+        } else {
+            3
+        };
+        budget.spend_curve_operations(agreements)?;
+    }
+
+    // process_prekey saves the identity, so this has to be checked first.
+    let identity_changed = check_identity_change(
+        policy,
+        remote_address,
+        ciphertext.identity_key(),
+        identity_store,
+        ctx,
+    )
+    .await?;
+
     // Make sure we log the session state if we fail to process the pre-key.
     let pre_key_used_or_err = session::process_prekey(
         ciphertext,
@@ -227,17 +1106,62 @@ pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
         }
     };
 
-    let ptext = decrypt_message_with_record(
+    let (ptext, message) = match decrypt_message_with_record(
         remote_address,
         &mut session_record,
         ciphertext.message(),
+        associated_data,
         CiphertextMessageType::PreKey,
+        &mut budget,
         csprng,
-    )?;
+    ) {
+        Ok(result) => result,
+        Err(e @ SignalProtocolError::DuplicatedMessage(_, counter)) if session_already_existed => {
+            record_flow_event(&mut session_record, FlowEvent::Duplicate { counter });
+            session::store_session_with_archive_policy(
+                session_store,
+                remote_address,
+                &mut session_record,
+                ctx,
+            )
+            .await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    let replay_key = ReplayKey::for_session_message(
+        remote_address,
+        message.sender_ratchet_key()?,
+        message.counter()?,
+    );
+    check_replay(
+        replay_cache.as_deref(),
+        &replay_key,
+        &session_record,
+        &message,
+        remote_address,
+        ctx,
+    )
+    .await?;
+    record_flow_event(&mut session_record, FlowEvent::Received);
+
+    // Archiving leaves no current session to take the ordering token from.
+    let ordering_token = MessageOrderingToken::for_decrypted_message(&session_record, &message)?;
+    if identity_changed && policy == IdentityChangePolicy::ArchiveSession && session_already_existed
+    {
+        session_record.archive_current_state()?;
+    }
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+    session::store_session_with_archive_policy(
+        session_store,
+        remote_address,
+        &mut session_record,
+        ctx,
+    )
+    .await?;
+    if let Some(replay_cache) = replay_cache {
+        replay_cache.insert(&replay_key, ctx).await?;
+    }
 
     if let Some(pre_key_id) = pre_key_used.pre_key_id {
         pre_key_store.remove_pre_key(pre_key_id, ctx).await?;
@@ -249,7 +1173,16 @@ pub async fn message_decrypt_prekey<R: Rng + CryptoRng>(
             .await?;
     }
 
-    Ok(ptext)
+    Ok(DecryptResult {
+        plaintext: ptext,
+        message_version: ciphertext.message_version(),
+        counter: message.counter()?,
+        session_was_created: !session_already_existed,
+        pre_key_used: pre_key_used.pre_key_id,
+        signed_pre_key_used: pre_key_used.signed_pre_key_id,
+        kyber_pre_key_used: pre_key_used.kyber_pre_key_id,
+        ordering_token,
+    })
 }
 
 pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
@@ -260,18 +1193,79 @@ pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
     csprng: &mut R,
     ctx: Context,
 ) -> Result<Vec<u8>> {
+    message_decrypt_signal_with_info(
+        ciphertext,
+        &[],
+        remote_address,
+        session_store,
+        identity_store,
+        None,
+        WorkLimit::UNLIMITED,
+        IdentityChangePolicy::AcceptNew,
+        csprng,
+        ctx,
+    )
+    .await
+    .map(|result| result.plaintext)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn message_decrypt_signal_with_info<R: Rng + CryptoRng>(
+    ciphertext: &SignalMessage,
+    associated_data: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    replay_cache: Option<&mut dyn ReplayCache>,
+    limit: WorkLimit,
+    policy: IdentityChangePolicy,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<DecryptResult> {
+    let mut budget = WorkBudget(limit);
     let mut session_record = session_store
         .load_session(remote_address, ctx)
         .await?
         .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
 
-    let ptext = decrypt_message_with_record(
+    let (ptext, message) = match decrypt_message_with_record(
         remote_address,
         &mut session_record,
         ciphertext,
+        associated_data,
         CiphertextMessageType::Whisper,
+        &mut budget,
         csprng,
-    )?;
+    ) {
+        Ok(result) => result,
+        Err(e @ SignalProtocolError::DuplicatedMessage(_, counter)) => {
+            record_flow_event(&mut session_record, FlowEvent::Duplicate { counter });
+            session::store_session_with_archive_policy(
+                session_store,
+                remote_address,
+                &mut session_record,
+                ctx,
+            )
+            .await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    let replay_key = ReplayKey::for_session_message(
+        remote_address,
+        message.sender_ratchet_key()?,
+        message.counter()?,
+    );
+    check_replay(
+        replay_cache.as_deref(),
+        &replay_key,
+        &session_record,
+        &message,
+        remote_address,
+        ctx,
+    )
+    .await?;
+    record_flow_event(&mut session_record, FlowEvent::Received);
 
     // Why are we performing this check after decryption instead of before?
     let their_identity_key = session_record
@@ -303,15 +1297,48 @@ pub async fn message_decrypt_signal<R: Rng + CryptoRng>(
         ));
     }
 
-    identity_store
+    let identity_changed = check_identity_change(
+        policy,
+        remote_address,
+        &their_identity_key,
+        identity_store,
+        ctx,
+    )
+    .await?;
+
+    if identity_store
         .save_identity(remote_address, &their_identity_key, ctx)
-        .await?;
+        .await?
+    {
+        observer::notify(|o| o.identity_changed(remote_address));
+    }
+
+    let ordering_token = MessageOrderingToken::for_decrypted_message(&session_record, &message)?;
+    if identity_changed && policy == IdentityChangePolicy::ArchiveSession {
+        session_record.archive_current_state()?;
+    }
 
-    session_store
-        .store_session(remote_address, &session_record, ctx)
-        .await?;
+    session::store_session_with_archive_policy(
+        session_store,
+        remote_address,
+        &mut session_record,
+        ctx,
+    )
+    .await?;
+    if let Some(replay_cache) = replay_cache {
+        replay_cache.insert(&replay_key, ctx).await?;
+    }
 
-    Ok(ptext)
+    Ok(DecryptResult {
+        plaintext: ptext,
+        message_version: ciphertext.message_version(),
+        counter: message.counter()?,
+        session_was_created: false,
+        pre_key_used: None,
+        signed_pre_key_used: None,
+        kyber_pre_key_used: None,
+        ordering_token,
+    })
 }
 
 fn create_decryption_failure_log(
@@ -376,10 +1403,9 @@ fn create_decryption_failure_log(
     let mut lines = vec![];
 
     lines.push(format!(
-        "Message from {} failed to decrypt; sender ratchet public key {} message counter {}",
+        "Message from {} failed to decrypt; {}",
         remote_address,
-        hex::encode(ciphertext.sender_ratchet_key().public_key_bytes()?),
-        ciphertext.counter()
+        describe_header(ciphertext),
     ));
 
     if let Some(current_session) = record.session_state() {
@@ -407,13 +1433,32 @@ fn create_decryption_failure_log(
     Ok(lines.join("\n"))
 }
 
-fn decrypt_message_with_record<R: Rng + CryptoRng>(
+fn describe_header(ciphertext: &SignalMessage) -> String {
+    if ciphertext.has_encrypted_header() {
+        return "header encrypted".to_string();
+    }
+    format!(
+        "sender ratchet public key {} message counter {}",
+        ciphertext
+            .sender_ratchet_key()
+            .and_then(PublicKey::public_key_bytes)
+            .map_or_else(|e| format!("<error: {}>", e), hex::encode),
+        ciphertext
+            .counter()
+            .map_or_else(|e| format!("<error: {}>", e), |c| c.to_string())
+    )
+}
+
+/// Decrypts `ciphertext`, also returning it with its header decrypted if it was encrypted.
+fn decrypt_message_with_record<'a, R: Rng + CryptoRng>(
     remote_address: &ProtocolAddress,
     record: &mut SessionRecord,
-    ciphertext: &SignalMessage,
+    ciphertext: &'a SignalMessage,
+    associated_data: &[u8],
     original_message_type: CiphertextMessageType,
+    budget: &mut WorkBudget,
     csprng: &mut R,
-) -> Result<Vec<u8>> {
+) -> Result<(Vec<u8>, Cow<'a, SignalMessage>)> {
     debug_assert!(matches!(
         original_message_type,
         CiphertextMessageType::Whisper | CiphertextMessageType::PreKey
@@ -421,14 +1466,10 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
     let log_decryption_failure = |state: &SessionState, error: &SignalProtocolError| {
         // A warning rather than an error because we try multiple sessions.
         log::warn!(
-            "Failed to decrypt {:?} message with ratchet key: {} and counter: {}. \
+            "Failed to decrypt {:?} message with {}. \
              Session loaded for {}. Local session has base key: {} and counter: {}. {}",
             original_message_type,
-            ciphertext
-                .sender_ratchet_key()
-                .public_key_bytes()
-                .map_or_else(|e| format!("<error: {}>", e), hex::encode),
-            ciphertext.counter(),
+            describe_header(ciphertext),
             remote_address,
             state
                 .sender_ratchet_key_for_logging()
@@ -446,13 +1487,15 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
             CurrentOrPrevious::Current,
             &mut current_state,
             ciphertext,
+            associated_data,
             original_message_type,
             remote_address,
+            budget,
             csprng,
         );
 
         match result {
-            Ok(ptext) => {
+            Ok(decrypted) => {
                 log::info!(
                     "decrypted {:?} message from {} with current session state (base key {})",
                     original_message_type,
@@ -462,10 +1505,18 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
                         .expect("successful decrypt always has a valid base key"),
                 );
                 record.set_session_state(current_state); // update the state
-                return Ok(ptext);
+                if original_message_type == CiphertextMessageType::Whisper {
+                    // The other party has the current session too, so any older-version session
+                    // kept around during an upgrade is only needed for messages still in flight.
+                    record.retire_older_versions(SystemTime::now())?;
+                }
+                return Ok(decrypted);
             }
-            Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
-                return result;
+            Err(
+                e @ (SignalProtocolError::DuplicatedMessage(_, _)
+                | SignalProtocolError::WorkLimitExceeded(_)),
+            ) => {
+                return Err(e);
             }
             Err(e) => {
                 log_decryption_failure(&current_state, &e);
@@ -475,6 +1526,10 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
     }
 
     // Try some old sessions:
+    let current_version = record
+        .session_state()
+        .map(|state| state.session_version())
+        .transpose()?;
     let mut updated_session = None;
 
     for (idx, previous) in record.previous_session_states().enumerate() {
@@ -484,13 +1539,15 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
             CurrentOrPrevious::Previous,
             &mut previous,
             ciphertext,
+            associated_data,
             original_message_type,
             remote_address,
+            budget,
             csprng,
         );
 
         match result {
-            Ok(ptext) => {
+            Ok(decrypted) => {
                 log::info!(
                     "decrypted {:?} message from {} with PREVIOUS session state (base key {})",
                     original_message_type,
@@ -499,11 +1556,14 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
                         .sender_ratchet_key_for_logging()
                         .expect("successful decrypt always has a valid base key"),
                 );
-                updated_session = Some((ptext, idx, previous));
+                updated_session = Some((decrypted, idx, previous));
                 break;
             }
-            Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
-                return result;
+            Err(
+                e @ (SignalProtocolError::DuplicatedMessage(_, _)
+                | SignalProtocolError::WorkLimitExceeded(_)),
+            ) => {
+                return Err(e);
             }
             Err(e) => {
                 log_decryption_failure(&previous, &e);
@@ -512,9 +1572,19 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
         }
     }
 
-    if let Some((ptext, idx, updated_session)) = updated_session {
-        record.promote_old_session(idx, updated_session);
-        Ok(ptext)
+    if let Some((decrypted, idx, updated_session)) = updated_session {
+        if current_version > Some(updated_session.session_version()?) {
+            // Don't undo an upgrade to a newer version just because the other party hasn't seen
+            // it yet.
+            log::info!(
+                "keeping older-version session for {} archived during upgrade",
+                remote_address
+            );
+            record.update_previous_session(idx, updated_session);
+        } else {
+            record.promote_old_session(idx, updated_session);
+        }
+        Ok(decrypted)
     } else {
         let previous_state_count = || record.previous_session_states().len();
 
@@ -559,14 +1629,16 @@ impl std::fmt::Display for CurrentOrPrevious {
     }
 }
 
-fn decrypt_message_with_state<R: Rng + CryptoRng>(
+fn decrypt_message_with_state<'a, R: Rng + CryptoRng>(
     current_or_previous: CurrentOrPrevious,
     state: &mut SessionState,
-    ciphertext: &SignalMessage,
+    ciphertext: &'a SignalMessage,
+    associated_data: &[u8],
     original_message_type: CiphertextMessageType,
     remote_address: &ProtocolAddress,
+    budget: &mut WorkBudget,
     csprng: &mut R,
-) -> Result<Vec<u8>> {
+) -> Result<(Vec<u8>, Cow<'a, SignalMessage>)> {
     if !state.has_sender_chain()? {
         return Err(SignalProtocolError::InvalidMessage(
             original_message_type,
@@ -581,9 +1653,13 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
         ));
     }
 
-    let their_ephemeral = ciphertext.sender_ratchet_key();
-    let counter = ciphertext.counter();
-    let chain_key = get_or_create_chain_key(state, their_ephemeral, remote_address, csprng)?;
+    let ciphertext = decrypt_header(state, ciphertext, original_message_type)?;
+
+    let their_ephemeral = ciphertext.sender_ratchet_key()?;
+    let counter = ciphertext.counter()?;
+    let stepped_ratchet = state.get_receiver_chain_key(their_ephemeral)?.is_none();
+    let chain_key =
+        get_or_create_chain_key(state, their_ephemeral, remote_address, budget, csprng)?;
     let message_keys = get_or_create_message_key(
         state,
         their_ephemeral,
@@ -591,6 +1667,7 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
         original_message_type,
         &chain_key,
         counter,
+        budget,
     )?;
 
     let their_identity_key =
@@ -600,53 +1677,133 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
                 "cannot decrypt without remote identity key",
             ))?;
 
-    let mac_valid = ciphertext.verify_mac(
-        &their_identity_key,
+    let ptext = if state.uses_aead()? {
+        match ciphertext.decrypt_aead(
+            &their_identity_key,
+            &state.local_identity_key()?,
+            &message_keys,
+            associated_data,
+        ) {
+            Some(ptext) => ptext,
+            None => {
+                observer::notify(|o| o.mac_failure(remote_address));
+                return Err(SignalProtocolError::InvalidMessage(
+                    original_message_type,
+                    "AEAD tag verification failed",
+                ));
+            }
+        }
+    } else {
+        decrypt_cbc_body(
+            current_or_previous,
+            state,
+            &ciphertext,
+            associated_data,
+            &their_identity_key,
+            &message_keys,
+            original_message_type,
+            remote_address,
+        )?
+    };
+
+    state.clear_unacknowledged_pre_key_message();
+
+    if stepped_ratchet {
+        observer::notify(|o| o.ratchet_stepped(remote_address));
+    }
+
+    Ok((ptext, ciphertext))
+}
+
+/// Checks the MAC on a message using one of the AES-CBC versions and decrypts its body.
+fn decrypt_cbc_body(
+    current_or_previous: CurrentOrPrevious,
+    state: &SessionState,
+    ciphertext: &SignalMessage,
+    associated_data: &[u8],
+    their_identity_key: &IdentityKey,
+    message_keys: &MessageKeys,
+    original_message_type: CiphertextMessageType,
+    remote_address: &ProtocolAddress,
+) -> Result<Vec<u8>> {
+    let mac_valid = ciphertext.verify_mac_with_associated_data(
+        their_identity_key,
         &state.local_identity_key()?,
         message_keys.mac_key(),
+        associated_data,
     )?;
 
     if !mac_valid {
+        observer::notify(|o| o.mac_failure(remote_address));
         return Err(SignalProtocolError::InvalidMessage(
             original_message_type,
             "MAC verification failed",
         ));
     }
 
-    let ptext = match signal_crypto::aes_256_cbc_decrypt(
+    match signal_crypto::aes_256_cbc_decrypt(
         ciphertext.body(),
         message_keys.cipher_key(),
         message_keys.iv(),
     ) {
-        Ok(ptext) => ptext,
+        Ok(ptext) => Ok(ptext),
         Err(signal_crypto::DecryptionError::BadKeyOrIv) => {
             log::warn!(
                 "{} session state corrupt for {}",
                 current_or_previous,
                 remote_address,
             );
-            return Err(SignalProtocolError::InvalidSessionStructure(
+            Err(SignalProtocolError::InvalidSessionStructure(
                 "invalid receiver chain message keys",
-            ));
+            ))
         }
         Err(signal_crypto::DecryptionError::BadCiphertext(msg)) => {
             log::warn!("failed to decrypt 1:1 message: {}", msg);
-            return Err(SignalProtocolError::InvalidMessage(
+            Err(SignalProtocolError::InvalidMessage(
                 original_message_type,
                 "failed to decrypt",
-            ));
+            ))
         }
-    };
+    }
+}
 
-    state.clear_unacknowledged_pre_key_message();
+/// For header-encrypted sessions, finds the header key `ciphertext` was sent with and decrypts
+/// its header.
+///
+/// Tries the receiver chains' header keys first, then the key for the sender's next chain.
+fn decrypt_header<'a>(
+    state: &SessionState,
+    ciphertext: &'a SignalMessage,
+    original_message_type: CiphertextMessageType,
+) -> Result<Cow<'a, SignalMessage>> {
+    if !state.uses_header_encryption()? {
+        return Ok(Cow::Borrowed(ciphertext));
+    }
+    if !ciphertext.has_encrypted_header() {
+        return Err(SignalProtocolError::InvalidMessage(
+            original_message_type,
+            "header encryption is required for this session",
+        ));
+    }
 
-    Ok(ptext)
+    let next_header_key = state.root_key()?.header_key();
+    state
+        .receiver_chain_header_keys()?
+        .iter()
+        .chain(std::iter::once(&next_header_key))
+        .find_map(|header_key| ciphertext.with_decrypted_header(header_key))
+        .map(Cow::Owned)
+        .ok_or(SignalProtocolError::InvalidMessage(
+            original_message_type,
+            "header decryption failed",
+        ))
 }
 
 fn get_or_create_chain_key<R: Rng + CryptoRng>(
     state: &mut SessionState,
     their_ephemeral: &PublicKey,
     remote_address: &ProtocolAddress,
+    budget: &mut WorkBudget,
     csprng: &mut R,
 ) -> Result<ChainKey> {
     if let Some(chain) = state.get_receiver_chain_key(their_ephemeral)? {
@@ -654,12 +1811,18 @@ fn get_or_create_chain_key<R: Rng + CryptoRng>(
         return Ok(chain);
     }
 
+    // Two agreements and a new key pair, each deriving a root key and a chain key.
+    budget.spend_curve_operations(3)?;
+    budget.spend_chain_key_derivations(2)?;
+
     log::info!("{} creating new chains.", remote_address);
 
     let root_key = state.root_key()?;
+    let receiver_header_key = root_key.header_key();
     let our_ephemeral = state.sender_ratchet_private_key()?;
     let receiver_chain = root_key.create_chain(their_ephemeral, &our_ephemeral)?;
-    let our_new_ephemeral = KeyPair::generate(csprng);
+    let sender_header_key = receiver_chain.0.header_key();
+    let our_new_ephemeral = KeyPair::generate_with_key_type(their_ephemeral.key_type(), csprng);
     let sender_chain = receiver_chain
         .0
         .create_chain(their_ephemeral, &our_new_ephemeral.private_key)?;
@@ -676,6 +1839,11 @@ fn get_or_create_chain_key<R: Rng + CryptoRng>(
     state.set_previous_counter(previous_index);
     state.set_sender_chain(&our_new_ephemeral, &sender_chain.1);
 
+    if state.uses_header_encryption()? {
+        state.set_receiver_chain_header_key(their_ephemeral, &receiver_header_key)?;
+        state.set_sender_chain_header_key(&sender_header_key);
+    }
+
     Ok(receiver_chain.1)
 }
 
@@ -686,6 +1854,7 @@ fn get_or_create_message_key(
     original_message_type: CiphertextMessageType,
     chain_key: &ChainKey,
     counter: u32,
+    budget: &mut WorkBudget,
 ) -> Result<MessageKeys> {
     let chain_index = chain_key.index();
 
@@ -698,6 +1867,7 @@ fn get_or_create_message_key(
                     remote_address,
                     counter
                 );
+                observer::notify(|o| o.duplicate_message(remote_address, chain_index, counter));
                 Err(SignalProtocolError::DuplicatedMessage(chain_index, counter))
             }
         };
@@ -731,6 +1901,8 @@ fn get_or_create_message_key(
         }
     }
 
+    budget.spend_chain_key_derivations((counter - chain_index).saturating_add(1))?;
+
     let mut chain_key = chain_key.clone();
 
     while chain_key.index() < counter {
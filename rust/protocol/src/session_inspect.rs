@@ -0,0 +1,265 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Read-only inspection of serialized session records, for debugging tools.
+//!
+//! The report types in this module only have fields for public keys, identifiers, counters, and
+//! lengths. Root keys, chain keys, message keys, and private keys are dropped while converting from
+//! the stored protobuf, so a report can be printed or logged without leaking session secrets.
+
+#![warn(missing_docs)]
+
+use std::time::{Duration, SystemTime};
+
+use prost::Message;
+
+use crate::consts;
+use crate::proto::storage::{session_structure, RecordStructure, SessionStructure};
+use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
+use crate::{IdentityKey, PublicKey, Result, SessionRecord, SessionRole, SignalProtocolError};
+
+/// A non-secret summary of a serialized [`SessionRecord`].
+#[derive(Debug, Clone)]
+pub struct SessionRecordReport {
+    /// The session used for sending, if any.
+    pub current_session: Option<SessionStateReport>,
+    /// Archived sessions that may still be used to decrypt incoming messages, newest first.
+    pub previous_sessions: Vec<SessionStateReport>,
+}
+
+impl SessionRecordReport {
+    /// Inspect the output of [`SessionRecord::serialize`].
+    pub fn from_serialized(bytes: &[u8]) -> Result<Self> {
+        let record = RecordStructure::decode(bytes).map_err(|_| {
+            SignalProtocolError::InvalidSessionStructure("failed to decode session record protobuf")
+        })?;
+        Ok(Self {
+            current_session: record
+                .current_session
+                .as_ref()
+                .map(SessionStateReport::from_structure)
+                .transpose()?,
+            previous_sessions: record
+                .previous_sessions
+                .iter()
+                .map(|bytes| SessionStateReport::from_serialized(bytes))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Inspect an in-memory [`SessionRecord`].
+    pub fn from_record(record: &SessionRecord) -> Result<Self> {
+        Self::from_serialized(&record.serialize()?)
+    }
+}
+
+/// A non-secret summary of a single session state within a [`SessionRecord`].
+#[derive(Debug, Clone)]
+pub struct SessionStateReport {
+    /// The version of the Signal protocol in use by this session.
+    pub session_version: u32,
+    /// Our identity key, as recorded when the session was created.
+    pub local_identity_key: Option<IdentityKey>,
+    /// The other party's identity key.
+    pub remote_identity_key: Option<IdentityKey>,
+    /// Our registration ID, as recorded when the session was created.
+    pub local_registration_id: u32,
+    /// The other party's registration ID.
+    pub remote_registration_id: u32,
+    /// The base key Alice used to set up this session, which identifies it.
+    pub alice_base_key: Option<PublicKey>,
+    /// Whether we started this session or accepted it, if recorded.
+    pub local_role: Option<SessionRole>,
+    /// The length of the previous sending chain.
+    pub previous_counter: u32,
+    /// The length in bytes of the root key (but not the key itself).
+    pub root_key_length: usize,
+    /// The current sending chain, if we have one.
+    pub sender_chain: Option<ChainReport>,
+    /// Receiving chains, newest first.
+    pub receiver_chains: Vec<ChainReport>,
+    /// Pre-key information that will be sent until the other party responds.
+    pub pending_pre_key: Option<PendingPreKeyReport>,
+    /// Kyber pre-key information that will be sent until the other party responds.
+    pub pending_kyber_pre_key: Option<PendingKyberPreKeyReport>,
+    /// Recent message counts, if any messages have been sent or received since they were tracked.
+    pub flow: Option<FlowReport>,
+    /// When a message was last sent or received with this session, if recorded.
+    pub last_used: Option<SystemTime>,
+}
+
+impl SessionStateReport {
+    /// Inspect a single serialized session state, as stored in a record's previous sessions.
+    pub fn from_serialized(bytes: &[u8]) -> Result<Self> {
+        let session = SessionStructure::decode(bytes).map_err(|_| {
+            SignalProtocolError::InvalidSessionStructure("failed to decode session state protobuf")
+        })?;
+        Self::from_structure(&session)
+    }
+
+    fn from_structure(session: &SessionStructure) -> Result<Self> {
+        Ok(Self {
+            session_version: session.session_version,
+            local_identity_key: optional_public_key(&session.local_identity_public)?
+                .map(IdentityKey::new),
+            remote_identity_key: optional_public_key(&session.remote_identity_public)?
+                .map(IdentityKey::new),
+            local_registration_id: session.local_registration_id,
+            remote_registration_id: session.remote_registration_id,
+            alice_base_key: optional_public_key(&session.alice_base_key)?,
+            local_role: SessionRole::from_proto(session.local_role()),
+            previous_counter: session.previous_counter,
+            root_key_length: session.root_key.len(),
+            sender_chain: session
+                .sender_chain
+                .as_ref()
+                .map(ChainReport::from_structure)
+                .transpose()?,
+            receiver_chains: session
+                .receiver_chains
+                .iter()
+                .map(ChainReport::from_structure)
+                .collect::<Result<_>>()?,
+            pending_pre_key: session
+                .pending_pre_key
+                .as_ref()
+                .map(PendingPreKeyReport::from_structure)
+                .transpose()?,
+            pending_kyber_pre_key: session
+                .pending_kyber_pre_key
+                .as_ref()
+                .map(PendingKyberPreKeyReport::from_structure),
+            flow: session
+                .flow_statistics
+                .as_ref()
+                .map(FlowReport::from_structure),
+            last_used: (session.last_used != 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(session.last_used)),
+        })
+    }
+}
+
+/// A non-secret summary of a sending or receiving chain.
+#[derive(Debug, Clone)]
+pub struct ChainReport {
+    /// The ratchet key that identifies this chain.
+    pub sender_ratchet_key: Option<PublicKey>,
+    /// Whether we hold the private half of the ratchet key (true only for sending chains).
+    pub has_sender_ratchet_private_key: bool,
+    /// The index of the next message key to be derived from the chain key.
+    pub chain_key_index: Option<u32>,
+    /// Indexes of message keys saved for out-of-order messages.
+    pub saved_message_key_indexes: Vec<u32>,
+}
+
+impl ChainReport {
+    fn from_structure(chain: &session_structure::Chain) -> Result<Self> {
+        Ok(Self {
+            sender_ratchet_key: optional_public_key(&chain.sender_ratchet_key)?,
+            has_sender_ratchet_private_key: !chain.sender_ratchet_key_private.is_empty(),
+            chain_key_index: chain.chain_key.as_ref().map(|chain_key| chain_key.index),
+            saved_message_key_indexes: chain.message_keys.iter().map(|key| key.index).collect(),
+        })
+    }
+}
+
+/// A non-secret summary of the pre-keys used to start a session that hasn't been acknowledged.
+#[derive(Debug, Clone)]
+pub struct PendingPreKeyReport {
+    /// The one-time pre-key used, if any.
+    pub pre_key_id: Option<PreKeyId>,
+    /// The signed pre-key used.
+    pub signed_pre_key_id: SignedPreKeyId,
+    /// Our base key for the session.
+    pub base_key: Option<PublicKey>,
+}
+
+impl PendingPreKeyReport {
+    fn from_structure(pending: &session_structure::PendingPreKey) -> Result<Self> {
+        Ok(Self {
+            pre_key_id: pending.pre_key_id.map(Into::into),
+            signed_pre_key_id: (pending.signed_pre_key_id as u32).into(),
+            base_key: optional_public_key(&pending.base_key)?,
+        })
+    }
+}
+
+/// A non-secret summary of the Kyber pre-key used to start a session that hasn't been
+/// acknowledged.
+#[derive(Debug, Clone)]
+pub struct PendingKyberPreKeyReport {
+    /// The Kyber pre-key used.
+    pub pre_key_id: KyberPreKeyId,
+    /// The length in bytes of the encapsulated secret sent to the other party.
+    pub ciphertext_length: usize,
+}
+
+impl PendingKyberPreKeyReport {
+    fn from_structure(pending: &session_structure::PendingKyberPreKey) -> Self {
+        Self {
+            pre_key_id: pending.pre_key_id.into(),
+            ciphertext_length: pending.ciphertext.len(),
+        }
+    }
+}
+
+/// Message counts for a session over two consecutive windows of [FlowReport::window] each.
+///
+/// Counts are only updated when a message is sent or received, so the "current" window may have
+/// ended long ago; check [FlowReport::window_start] against the current time before treating them
+/// as rates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowReport {
+    /// The length of each window.
+    pub window: Duration,
+    /// When the current window started.
+    pub window_start: SystemTime,
+    /// Messages encrypted in the current window.
+    pub sent: u32,
+    /// Messages decrypted in the current window.
+    pub received: u32,
+    /// Messages rejected as duplicates in the current window.
+    pub duplicates: u32,
+    /// Messages encrypted in the window just before the current one.
+    pub previous_sent: u32,
+    /// Messages decrypted in the window just before the current one.
+    pub previous_received: u32,
+    /// Messages rejected as duplicates in the window just before the current one.
+    pub previous_duplicates: u32,
+    /// The counter of the latest duplicate, if any have arrived since the last message was
+    /// decrypted.
+    pub repeated_duplicate_counter: Option<u32>,
+    /// How many duplicates in a row have had [FlowReport::repeated_duplicate_counter].
+    ///
+    /// A large number means the other side is probably stuck re-sending the same message.
+    pub repeated_duplicates: u32,
+}
+
+impl FlowReport {
+    fn from_structure(stats: &session_structure::FlowStatistics) -> Self {
+        Self {
+            window: consts::FLOW_STATISTICS_WINDOW,
+            window_start: SystemTime::UNIX_EPOCH + Duration::from_secs(stats.window_start),
+            sent: stats.sent,
+            received: stats.received,
+            duplicates: stats.duplicates,
+            previous_sent: stats.previous_sent,
+            previous_received: stats.previous_received,
+            previous_duplicates: stats.previous_duplicates,
+            repeated_duplicate_counter: (stats.repeated_duplicates > 0)
+                .then_some(stats.last_duplicate_counter),
+            repeated_duplicates: stats.repeated_duplicates,
+        }
+    }
+}
+
+/// proto3 doesn't distinguish between missing and empty bytes fields, so treat both as missing.
+fn optional_public_key(bytes: &[u8]) -> Result<Option<PublicKey>> {
+    if bytes.is_empty() {
+        Ok(None)
+    } else {
+        PublicKey::deserialize(bytes).map(Some)
+    }
+}
@@ -7,11 +7,21 @@ mod bundle;
 mod kyber_prekey;
 mod prekey;
 mod session;
+mod session_archive;
 mod signed_prekey;
+mod signed_prekey_rotation;
 
-pub use bundle::{PreKeyBundle, PreKeyBundleContent};
+pub use bundle::{
+    PreKeyBundle, PreKeyBundleBuilder, PreKeyBundleContent, PreKeyBundleProblem,
+    PreKeyBundleValidation,
+};
 pub use kyber_prekey::{KyberPreKeyId, KyberPreKeyRecord};
-pub use prekey::{PreKeyId, PreKeyRecord};
-pub use session::SessionRecord;
-pub(crate) use session::{InvalidSessionError, SessionState};
+pub use prekey::{generate_prekey_batch, PreKeyId, PreKeyRecord, MAX_PRE_KEY_ID};
+pub(crate) use session::{FlowEvent, InvalidSessionError, SessionState};
+pub use session::{SessionArchivePolicy, SessionRecord, SessionRole};
+pub use session_archive::{SessionArchiveKey, SESSION_ARCHIVE_PASSWORD_ITERATIONS};
 pub use signed_prekey::{GenericSignedPreKey, SignedPreKeyId, SignedPreKeyRecord};
+pub use signed_prekey_rotation::{
+    SignedPreKeyRotation, DEFAULT_SIGNED_PRE_KEY_RETENTION,
+    DEFAULT_SIGNED_PRE_KEY_ROTATION_INTERVAL,
+};
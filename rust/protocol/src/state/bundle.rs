@@ -3,10 +3,12 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use crate::registration_id::is_valid_registration_id;
 use crate::state::{PreKeyId, SignedPreKeyId};
 use crate::{kem, DeviceId, IdentityKey, KyberPreKeyId, PublicKey, Result, SignalProtocolError};
 use std::clone::Clone;
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 
 #[derive(Clone)]
 struct SignedPreKey {
@@ -46,6 +48,7 @@ impl KyberPreKey {
 // fields.
 // Can be used as a "builder" for PreKeyBundle, in which case all the validation will happen in
 // PreKeyBundle::new.
+#[derive(Default)]
 pub struct PreKeyBundleContent {
     pub registration_id: Option<u32>,
     pub device_id: Option<DeviceId>,
@@ -58,6 +61,8 @@ pub struct PreKeyBundleContent {
     pub kyber_pre_key_id: Option<KyberPreKeyId>,
     pub kyber_pre_key_public: Option<kem::PublicKey>,
     pub kyber_pre_key_signature: Option<Vec<u8>>,
+    pub supports_header_encryption: bool,
+    pub supports_aead: bool,
 }
 
 impl From<PreKeyBundle> for PreKeyBundleContent {
@@ -80,6 +85,8 @@ impl From<PreKeyBundle> for PreKeyBundleContent {
                 .kyber_pre_key
                 .as_ref()
                 .map(|kyber| kyber.signature.clone()),
+            supports_header_encryption: bundle.supports_header_encryption,
+            supports_aead: bundle.supports_aead,
         }
     }
 }
@@ -127,6 +134,12 @@ impl TryFrom<PreKeyBundleContent> for PreKeyBundle {
         ) {
             bundle = bundle.with_kyber_pre_key(kyber_id, kyber_public, kyber_sig);
         }
+        if content.supports_header_encryption {
+            bundle = bundle.with_header_encryption_support();
+        }
+        if content.supports_aead {
+            bundle = bundle.with_aead_support();
+        }
         Ok(bundle)
     }
 }
@@ -142,6 +155,29 @@ pub struct PreKeyBundle {
     // Optional to support older clients
     // TODO: remove optionality once the transition is over
     kyber_pre_key: Option<KyberPreKey>,
+    supports_header_encryption: bool,
+    supports_aead: bool,
+}
+
+impl fmt::Debug for PreKeyBundle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreKeyBundle")
+            .field("registration_id", &self.registration_id)
+            .field("device_id", &self.device_id)
+            .field("pre_key_id", &self.pre_key_id)
+            .field("signed_pre_key_id", &self.ec_signed_pre_key.id)
+            .field(
+                "kyber_pre_key_id",
+                &self.kyber_pre_key.as_ref().map(|kyber| kyber.id),
+            )
+            .field("identity_key", &self.identity_key)
+            .field(
+                "supports_header_encryption",
+                &self.supports_header_encryption,
+            )
+            .field("supports_aead", &self.supports_aead)
+            .finish_non_exhaustive()
+    }
 }
 
 impl PreKeyBundle {
@@ -173,6 +209,8 @@ impl PreKeyBundle {
             ec_signed_pre_key,
             identity_key,
             kyber_pre_key: None,
+            supports_header_encryption: false,
+            supports_aead: false,
         })
     }
 
@@ -186,6 +224,26 @@ impl PreKeyBundle {
         self
     }
 
+    /// Advertise that the bundle's owner accepts header-encrypted sessions.
+    ///
+    /// Sessions are only header-encrypted if the bundle also has a Kyber pre-key. This flag is not
+    /// covered by any signature, so whoever distributes bundles can strip it.
+    pub fn with_header_encryption_support(mut self) -> Self {
+        self.supports_header_encryption = true;
+        self
+    }
+
+    /// Advertise that the bundle's owner accepts sessions whose messages are sealed with
+    /// AES-256-GCM-SIV, with a full 16-byte tag rather than an 8-byte truncated HMAC.
+    ///
+    /// As with [header encryption](Self::with_header_encryption_support), this only takes effect
+    /// if the bundle also has a Kyber pre-key, and the flag is not signed. Clients that never see
+    /// the flag keep using the older message versions, so sessions with them still work.
+    pub fn with_aead_support(mut self) -> Self {
+        self.supports_aead = true;
+        self
+    }
+
     pub fn registration_id(&self) -> Result<u32> {
         Ok(self.registration_id)
     }
@@ -222,6 +280,14 @@ impl PreKeyBundle {
         self.kyber_pre_key.is_some()
     }
 
+    pub fn supports_header_encryption(&self) -> bool {
+        self.supports_header_encryption
+    }
+
+    pub fn supports_aead(&self) -> bool {
+        self.supports_aead
+    }
+
     pub fn kyber_pre_key_id(&self) -> Result<Option<KyberPreKeyId>> {
         Ok(self.kyber_pre_key.as_ref().map(|pre_key| pre_key.id))
     }
@@ -248,4 +314,289 @@ impl PreKeyBundle {
         modify(&mut content);
         content.try_into()
     }
+
+    /// Check everything about this bundle that can be checked without a session, reporting all
+    /// problems found rather than stopping at the first.
+    ///
+    /// `expected_identity_key` is the identity the bundle is supposed to belong to; the pre-key
+    /// signatures are checked against it rather than the identity key embedded in the bundle.
+    pub fn validate(&self, expected_identity_key: &IdentityKey) -> Result<PreKeyBundleValidation> {
+        let mut problems = Vec::new();
+
+        if !self.identity_key.ct_eq(expected_identity_key) {
+            problems.push(PreKeyBundleProblem::IdentityKeyMismatch);
+        }
+
+        if !is_valid_registration_id(self.registration_id) {
+            problems.push(PreKeyBundleProblem::InvalidRegistrationId(
+                self.registration_id,
+            ));
+        }
+
+        let identity_key_type = self.identity_key.public_key().key_type();
+        if self
+            .pre_key_public
+            .iter()
+            .chain(std::iter::once(&self.ec_signed_pre_key.public_key))
+            .any(|key| key.key_type() != identity_key_type)
+        {
+            problems.push(PreKeyBundleProblem::MixedKeyTypes);
+        }
+
+        if !expected_identity_key.public_key().verify_signature(
+            &self.ec_signed_pre_key.public_key.serialize(),
+            &self.ec_signed_pre_key.signature,
+        )? {
+            problems.push(PreKeyBundleProblem::InvalidSignedPreKeySignature);
+        }
+
+        if let Some(kyber) = &self.kyber_pre_key {
+            if !expected_identity_key
+                .public_key()
+                .verify_signature(&kyber.public_key.serialize(), &kyber.signature)?
+            {
+                problems.push(PreKeyBundleProblem::InvalidKyberPreKeySignature);
+            }
+        }
+
+        Ok(PreKeyBundleValidation { problems })
+    }
+}
+
+/// A problem found by [`PreKeyBundle::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreKeyBundleProblem {
+    /// The bundle's identity key is not the one expected.
+    IdentityKeyMismatch,
+    /// The registration ID doesn't fit in 14 bits.
+    InvalidRegistrationId(u32),
+    /// The signed pre-key's signature was not made by the expected identity key.
+    InvalidSignedPreKeySignature,
+    /// The Kyber pre-key's signature was not made by the expected identity key.
+    InvalidKyberPreKeySignature,
+    /// The bundle's elliptic-curve keys don't all have the same [KeyType](crate::KeyType).
+    MixedKeyTypes,
+}
+
+impl fmt::Display for PreKeyBundleProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IdentityKeyMismatch => write!(f, "identity key does not match"),
+            Self::InvalidRegistrationId(id) => write!(f, "invalid registration id {}", id),
+            Self::InvalidSignedPreKeySignature => write!(f, "invalid signed pre-key signature"),
+            Self::InvalidKyberPreKeySignature => write!(f, "invalid kyber pre-key signature"),
+            Self::MixedKeyTypes => write!(f, "pre-keys do not match the identity key type"),
+        }
+    }
+}
+
+impl From<PreKeyBundleProblem> for SignalProtocolError {
+    fn from(problem: PreKeyBundleProblem) -> Self {
+        match problem {
+            PreKeyBundleProblem::InvalidSignedPreKeySignature
+            | PreKeyBundleProblem::InvalidKyberPreKeySignature => {
+                SignalProtocolError::SignatureValidationFailed
+            }
+            PreKeyBundleProblem::IdentityKeyMismatch
+            | PreKeyBundleProblem::InvalidRegistrationId(_)
+            | PreKeyBundleProblem::MixedKeyTypes => {
+                SignalProtocolError::InvalidArgument(format!("invalid pre-key bundle: {}", problem))
+            }
+        }
+    }
+}
+
+/// The outcome of [`PreKeyBundle::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreKeyBundleValidation {
+    problems: Vec<PreKeyBundleProblem>,
+}
+
+impl PreKeyBundleValidation {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn problems(&self) -> &[PreKeyBundleProblem] {
+        &self.problems
+    }
+
+    /// Convert the first problem found (if any) into an error.
+    pub fn into_result(self) -> Result<()> {
+        match self.problems.into_iter().next() {
+            None => Ok(()),
+            Some(problem) => Err(problem.into()),
+        }
+    }
+}
+
+/// Assembles a [`PreKeyBundle`], checking it with [`PreKeyBundle::validate`] before returning it.
+///
+/// Unlike [`PreKeyBundle::new`], which accepts anything, a bundle produced by
+/// [`PreKeyBundleBuilder::build`] will not fail signature checks in
+/// [`process_prekey_bundle`][crate::process_prekey_bundle].
+#[derive(Default)]
+pub struct PreKeyBundleBuilder {
+    content: PreKeyBundleContent,
+}
+
+impl PreKeyBundleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn registration_id(mut self, registration_id: u32) -> Self {
+        self.content.registration_id = Some(registration_id);
+        self
+    }
+
+    pub fn device_id(mut self, device_id: DeviceId) -> Self {
+        self.content.device_id = Some(device_id);
+        self
+    }
+
+    pub fn pre_key(mut self, id: PreKeyId, public_key: PublicKey) -> Self {
+        self.content.pre_key_id = Some(id);
+        self.content.pre_key_public = Some(public_key);
+        self
+    }
+
+    pub fn signed_pre_key(
+        mut self,
+        id: SignedPreKeyId,
+        public_key: PublicKey,
+        signature: Vec<u8>,
+    ) -> Self {
+        self.content.ec_pre_key_id = Some(id);
+        self.content.ec_pre_key_public = Some(public_key);
+        self.content.ec_pre_key_signature = Some(signature);
+        self
+    }
+
+    pub fn kyber_pre_key(
+        mut self,
+        id: KyberPreKeyId,
+        public_key: kem::PublicKey,
+        signature: Vec<u8>,
+    ) -> Self {
+        self.content.kyber_pre_key_id = Some(id);
+        self.content.kyber_pre_key_public = Some(public_key);
+        self.content.kyber_pre_key_signature = Some(signature);
+        self
+    }
+
+    pub fn identity_key(mut self, identity_key: IdentityKey) -> Self {
+        self.content.identity_key = Some(identity_key);
+        self
+    }
+
+    /// Build the bundle, failing if a required field is missing or if
+    /// [`PreKeyBundle::validate`] finds any problem against the bundle's own identity key.
+    pub fn build(self) -> Result<PreKeyBundle> {
+        let bundle = PreKeyBundle::try_from(self.content)?;
+        bundle.validate(&bundle.identity_key)?.into_result()?;
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::MAX_REGISTRATION_ID;
+    use crate::{GenericSignedPreKey, IdentityKeyPair, KeyPair, SignedPreKeyRecord};
+
+    use rand::rngs::OsRng;
+
+    fn signed_builder(identity: &IdentityKeyPair) -> Result<PreKeyBundleBuilder> {
+        let mut csprng = OsRng;
+        let signed_pre_key =
+            SignedPreKeyRecord::generate(20.into(), identity.private_key(), &mut csprng)?;
+        Ok(PreKeyBundleBuilder::new()
+            .registration_id(1234)
+            .device_id(1.into())
+            .pre_key(10.into(), KeyPair::generate(&mut csprng).public_key)
+            .signed_pre_key(
+                20.into(),
+                signed_pre_key.public_key()?,
+                signed_pre_key.signature()?,
+            )
+            .identity_key(*identity.identity_key()))
+    }
+
+    #[test]
+    fn test_builder_validates() -> Result<()> {
+        let mut csprng = OsRng;
+        let identity = IdentityKeyPair::generate(&mut csprng);
+        let other_identity = IdentityKeyPair::generate(&mut csprng);
+
+        let bundle = signed_builder(&identity)?.build()?;
+        assert_eq!(bundle.pre_key_id()?, Some(10.into()));
+        assert!(bundle.validate(identity.identity_key())?.is_valid());
+
+        let kyber = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+        let kyber_signature = identity
+            .private_key()
+            .calculate_signature(&kyber.public_key.serialize(), &mut csprng)?;
+        let bundle = signed_builder(&identity)?
+            .kyber_pre_key(
+                30.into(),
+                kyber.public_key.clone(),
+                kyber_signature.into_vec(),
+            )
+            .build()?;
+        assert_eq!(bundle.kyber_pre_key_id()?, Some(30.into()));
+
+        assert!(matches!(
+            signed_builder(&identity)?
+                .kyber_pre_key(30.into(), kyber.public_key, vec![0; 64])
+                .build(),
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+        assert!(matches!(
+            signed_builder(&identity)?
+                .identity_key(*other_identity.identity_key())
+                .build(),
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+        assert!(matches!(
+            signed_builder(&identity)?
+                .registration_id(MAX_REGISTRATION_ID + 1)
+                .build(),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            PreKeyBundleBuilder::new().registration_id(1).build(),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_reports_all_problems() -> Result<()> {
+        let mut csprng = OsRng;
+        let identity = IdentityKeyPair::generate(&mut csprng);
+        let other_identity = IdentityKeyPair::generate(&mut csprng);
+
+        let bundle = signed_builder(&identity)?.build()?.modify(|content| {
+            content.registration_id = Some(0x4000);
+        })?;
+
+        let validation = bundle.validate(other_identity.identity_key())?;
+        assert!(!validation.is_valid());
+        assert_eq!(
+            validation.problems(),
+            &[
+                PreKeyBundleProblem::IdentityKeyMismatch,
+                PreKeyBundleProblem::InvalidRegistrationId(0x4000),
+                PreKeyBundleProblem::InvalidSignedPreKeySignature,
+            ]
+        );
+        assert!(matches!(
+            validation.into_result(),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+
+        Ok(())
+    }
 }
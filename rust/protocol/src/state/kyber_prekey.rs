@@ -4,11 +4,12 @@
 //
 
 use crate::proto::storage::SignedPreKeyRecordStructure;
+use crate::redact::Redact;
 
 use crate::state::GenericSignedPreKey;
-use crate::{kem, PrivateKey, Result};
+use crate::{kem, PrivateKey, Result, Timestamp};
 
-use std::convert::TryInto;
+use rand::{CryptoRng, Rng};
 use std::fmt;
 
 /// A unique identifier selecting among this client's known signed pre-keys.
@@ -33,11 +34,19 @@ impl fmt::Display for KyberPreKeyId {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct KyberPreKeyRecord {
     signed_pre_key: SignedPreKeyRecordStructure,
 }
 
+impl fmt::Debug for KyberPreKeyRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KyberPreKeyRecord")
+            .field("signed_pre_key", &Redact(&self.signed_pre_key))
+            .finish()
+    }
+}
+
 impl GenericSignedPreKey for KyberPreKeyRecord {
     type KeyPair = kem::KeyPair;
     type Id = KyberPreKeyId;
@@ -60,23 +69,24 @@ impl KyberPreKeyRecord {
 }
 
 impl KyberPreKeyRecord {
-    pub fn generate(
+    /// Generates a new Kyber pre-key, signed with `signing_key`.
+    ///
+    /// `csprng` is used for the signature. The Kyber key pair itself always comes from the
+    /// system's randomness (see [`kem::KeyPair::generate`]), so the result is not reproducible
+    /// even with a seeded `csprng`.
+    pub fn generate<R: Rng + CryptoRng>(
         kyber_key_type: kem::KeyType,
         id: KyberPreKeyId,
         signing_key: &PrivateKey,
+        csprng: &mut R,
     ) -> Result<KyberPreKeyRecord> {
         let key_pair = kem::KeyPair::generate(kyber_key_type);
-        let mut rng = rand::rngs::OsRng;
         let signature = signing_key
-            .calculate_signature(&key_pair.public_key.serialize(), &mut rng)?
+            .calculate_signature(&key_pair.public_key.serialize(), csprng)?
             .into_vec();
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .expect("Time should move forward")
-            .as_millis();
         Ok(KyberPreKeyRecord::new(
             id,
-            timestamp.try_into().expect("Timestamp too large"),
+            Timestamp::now(),
             &key_pair,
             &signature,
         ))
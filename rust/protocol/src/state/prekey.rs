@@ -4,12 +4,19 @@
 //
 
 use crate::proto::storage::PreKeyRecordStructure;
+use crate::redact::Redact;
 use crate::{KeyPair, PrivateKey, PublicKey, Result, SignalProtocolError};
 
 use prost::Message;
+use rand::{CryptoRng, Rng};
 
 use std::fmt;
 
+/// The largest pre-key ID generated by [`generate_prekey_batch`].
+///
+/// Pre-key IDs are kept to 24 bits for compatibility with other Signal clients.
+pub const MAX_PRE_KEY_ID: u32 = 0xFF_FFFF;
+
 /// A unique identifier selecting among this client's known pre-keys.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct PreKeyId(u32);
@@ -32,11 +39,19 @@ impl fmt::Display for PreKeyId {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct PreKeyRecord {
     pre_key: PreKeyRecordStructure,
 }
 
+impl fmt::Debug for PreKeyRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreKeyRecord")
+            .field("pre_key", &Redact(&self.pre_key))
+            .finish()
+    }
+}
+
 impl PreKeyRecord {
     pub fn new(id: PreKeyId, key: &KeyPair) -> Self {
         let public_key = key.public_key.serialize().to_vec();
@@ -77,3 +92,68 @@ impl PreKeyRecord {
         Ok(self.pre_key.encode_to_vec())
     }
 }
+
+/// Generate `count` fresh one-time pre-keys, ready to be saved locally and uploaded.
+///
+/// IDs count up from `start_id`, wrapping from [`MAX_PRE_KEY_ID`] back around to 1 (0 is never
+/// used). To continue where a previous batch left off, pass the last ID of that batch plus one;
+/// any value is accepted and will be wrapped into range.
+///
+/// Fails if `count` is larger than the number of distinct IDs available, since that would produce
+/// duplicates.
+pub fn generate_prekey_batch<R: Rng + CryptoRng>(
+    start_id: PreKeyId,
+    count: u32,
+    csprng: &mut R,
+) -> Result<Vec<PreKeyRecord>> {
+    if count > MAX_PRE_KEY_ID {
+        return Err(SignalProtocolError::InvalidArgument(format!(
+            "cannot generate {} pre-keys with distinct IDs",
+            count
+        )));
+    }
+    let max = u64::from(MAX_PRE_KEY_ID);
+    let start = u64::from(u32::from(start_id));
+    Ok((0..u64::from(count))
+        .map(|i| {
+            let id = ((start + i + max - 1) % max + 1) as u32;
+            PreKeyRecord::new(id.into(), &KeyPair::generate(csprng))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+
+    fn ids(batch: &[PreKeyRecord]) -> Result<Vec<u32>> {
+        batch.iter().map(|record| Ok(record.id()?.into())).collect()
+    }
+
+    #[test]
+    fn test_generate_prekey_batch() -> Result<()> {
+        let mut csprng = OsRng;
+
+        let batch = generate_prekey_batch(100.into(), 3, &mut csprng)?;
+        assert_eq!(ids(&batch)?, vec![100, 101, 102]);
+        assert_ne!(batch[0].public_key()?, batch[1].public_key()?);
+
+        let batch = generate_prekey_batch((MAX_PRE_KEY_ID - 1).into(), 4, &mut csprng)?;
+        assert_eq!(ids(&batch)?, vec![MAX_PRE_KEY_ID - 1, MAX_PRE_KEY_ID, 1, 2]);
+
+        let batch = generate_prekey_batch(0.into(), 2, &mut csprng)?;
+        assert_eq!(ids(&batch)?, vec![MAX_PRE_KEY_ID, 1]);
+
+        let batch = generate_prekey_batch(u32::MAX.into(), 1, &mut csprng)?;
+        assert!((1..=MAX_PRE_KEY_ID).contains(&ids(&batch)?[0]));
+
+        assert!(generate_prekey_batch(1.into(), 0, &mut csprng)?.is_empty());
+        assert!(matches!(
+            generate_prekey_batch(1.into(), MAX_PRE_KEY_ID + 1, &mut csprng),
+            Err(SignalProtocolError::InvalidArgument(_))
+        ));
+        Ok(())
+    }
+}
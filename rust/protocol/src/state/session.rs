@@ -5,15 +5,28 @@
 
 use std::convert::TryInto;
 use std::result::Result;
+use std::time::{Duration, SystemTime};
 
 use prost::Message;
+use sha2::{Digest, Sha256};
 use subtle::ConstantTimeEq;
 
 use crate::ratchet::{ChainKey, MessageKeys, RootKey};
-use crate::{kem, IdentityKey, KeyPair, PrivateKey, PublicKey, SignalProtocolError};
+use crate::{
+    kem, IdentityKey, KeyPair, PrivateKey, ProtocolAddress, PublicKey, SignalProtocolError,
+};
 
 use crate::consts;
-use crate::proto::storage::{session_structure, RecordStructure, SessionStructure};
+use crate::proto::storage::{
+    session_structure, LegacyRecordStructure, LegacySessionStructure, RecordStructure,
+    SessionStructure,
+};
+use crate::protocol::{
+    version_has_encrypted_header, version_uses_aead, CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION,
+};
+use crate::record_integrity::{self, IntegrityMode};
+use crate::record_version::{self, Migration};
+use crate::redact::Redact;
 use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
 
 /// A distinct error type to keep from accidentally propagating deserialization errors.
@@ -32,6 +45,14 @@ impl From<InvalidSessionError> for SignalProtocolError {
     }
 }
 
+/// Something counted by a session's flow statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FlowEvent {
+    Sent,
+    Received,
+    Duplicate { counter: u32 },
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct UnacknowledgedPreKeyMessageItems<'a> {
     pre_key_id: Option<PreKeyId>,
@@ -39,6 +60,7 @@ pub(crate) struct UnacknowledgedPreKeyMessageItems<'a> {
     base_key: PublicKey,
     kyber_pre_key_id: Option<KyberPreKeyId>,
     kyber_ciphertext: Option<&'a [u8]>,
+    timestamp: Option<SystemTime>,
 }
 
 impl<'a> UnacknowledgedPreKeyMessageItems<'a> {
@@ -47,6 +69,7 @@ impl<'a> UnacknowledgedPreKeyMessageItems<'a> {
         signed_pre_key_id: SignedPreKeyId,
         base_key: PublicKey,
         pending_kyber_pre_key: Option<&'a session_structure::PendingKyberPreKey>,
+        timestamp: u64,
     ) -> Self {
         let (kyber_pre_key_id, kyber_ciphertext) = pending_kyber_pre_key
             .map(|pending| (pending.pre_key_id.into(), pending.ciphertext.as_slice()))
@@ -57,6 +80,8 @@ impl<'a> UnacknowledgedPreKeyMessageItems<'a> {
             base_key,
             kyber_pre_key_id,
             kyber_ciphertext,
+            timestamp: (timestamp != 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp)),
         }
     }
 
@@ -79,13 +104,26 @@ impl<'a> UnacknowledgedPreKeyMessageItems<'a> {
     pub(crate) fn kyber_ciphertext(&self) -> Option<&'a [u8]> {
         self.kyber_ciphertext
     }
+
+    /// When the session was created, if it was recorded.
+    pub(crate) fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub(crate) struct SessionState {
     session: SessionStructure,
 }
 
+impl std::fmt::Debug for SessionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionState")
+            .field("session", &Redact(&self.session))
+            .finish()
+    }
+}
+
 impl SessionState {
     pub(crate) fn from_session_structure(session: SessionStructure) -> Self {
         Self { session }
@@ -111,10 +149,104 @@ impl SessionState {
                 remote_registration_id: 0,
                 local_registration_id: 0,
                 alice_base_key: vec![],
+                receiver_chain_count: 0,
+                auxiliary_auth_key: vec![],
+                local_role: session_structure::Role::Unknown.into(),
+                flow_statistics: None,
+                archived_at: 0,
+                last_used: 0,
+                used_one_time_pre_key: None,
             },
         }
     }
 
+    pub(crate) fn auxiliary_auth_key(&self) -> Option<&[u8]> {
+        if self.session.auxiliary_auth_key.is_empty() {
+            None
+        } else {
+            Some(&self.session.auxiliary_auth_key)
+        }
+    }
+
+    pub(crate) fn set_auxiliary_auth_key(&mut self, key: &[u8; 32]) {
+        self.session.auxiliary_auth_key = key.to_vec();
+    }
+
+    pub(crate) fn local_role(&self) -> Option<SessionRole> {
+        SessionRole::from_proto(self.session.local_role())
+    }
+
+    pub(crate) fn set_local_role(&mut self, role: SessionRole) {
+        self.session.set_local_role(match role {
+            SessionRole::Alice => session_structure::Role::Alice,
+            SessionRole::Bob => session_structure::Role::Bob,
+        });
+    }
+
+    pub(crate) fn used_one_time_pre_key(&self) -> Option<bool> {
+        self.session.used_one_time_pre_key
+    }
+
+    pub(crate) fn set_used_one_time_pre_key(&mut self, used: bool) {
+        self.session.used_one_time_pre_key = Some(used);
+    }
+
+    pub(crate) fn record_flow_event(&mut self, event: FlowEvent, now: SystemTime) {
+        let window = consts::FLOW_STATISTICS_WINDOW.as_secs();
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let stats = self
+            .session
+            .flow_statistics
+            .get_or_insert_with(Default::default);
+
+        if stats.window_start == 0 || now < stats.window_start {
+            // First event, or the clock went backwards; start over.
+            *stats = session_structure::FlowStatistics {
+                window_start: now,
+                ..Default::default()
+            };
+        } else if now - stats.window_start >= window {
+            let windows_elapsed = (now - stats.window_start) / window;
+            if windows_elapsed == 1 {
+                stats.previous_sent = stats.sent;
+                stats.previous_received = stats.received;
+                stats.previous_duplicates = stats.duplicates;
+            } else {
+                stats.previous_sent = 0;
+                stats.previous_received = 0;
+                stats.previous_duplicates = 0;
+            }
+            stats.sent = 0;
+            stats.received = 0;
+            stats.duplicates = 0;
+            stats.window_start += windows_elapsed * window;
+        }
+
+        if matches!(event, FlowEvent::Sent | FlowEvent::Received) {
+            self.session.last_used = now;
+        }
+
+        match event {
+            FlowEvent::Sent => stats.sent = stats.sent.saturating_add(1),
+            FlowEvent::Received => {
+                stats.received = stats.received.saturating_add(1);
+                stats.repeated_duplicates = 0;
+            }
+            FlowEvent::Duplicate { counter } => {
+                stats.duplicates = stats.duplicates.saturating_add(1);
+                if stats.repeated_duplicates > 0 && stats.last_duplicate_counter == counter {
+                    stats.repeated_duplicates = stats.repeated_duplicates.saturating_add(1);
+                } else {
+                    stats.last_duplicate_counter = counter;
+                    stats.repeated_duplicates = 1;
+                }
+            }
+        }
+    }
+
     pub(crate) fn alice_base_key(&self) -> &[u8] {
         // Check the length before returning?
         &self.session.alice_base_key
@@ -212,6 +344,31 @@ impl SessionState {
         Ok(self.session.sender_chain.is_some())
     }
 
+    pub(crate) fn sender_chain_length(&self) -> Option<u32> {
+        let chain_key = self.session.sender_chain.as_ref()?.chain_key.as_ref()?;
+        Some(chain_key.index)
+    }
+
+    pub(crate) fn receiver_chain_lengths(&self) -> Vec<u32> {
+        self.session
+            .receiver_chains
+            .iter()
+            .map(|chain| {
+                chain
+                    .chain_key
+                    .as_ref()
+                    .map_or(0, |chain_key| chain_key.index)
+            })
+            .collect()
+    }
+
+    pub(crate) fn last_used(&self) -> Option<SystemTime> {
+        match self.session.last_used {
+            0 => None,
+            secs => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
     pub(crate) fn all_receiver_chain_logging_info(&self) -> Vec<(Vec<u8>, Option<u32>)> {
         let mut results = vec![];
         for chain in self.session.receiver_chains.iter() {
@@ -242,6 +399,33 @@ impl SessionState {
         Ok(None)
     }
 
+    /// The ratchet key of the most recently added receiver chain, which is the other party's
+    /// current sending ratchet key unless they've ratcheted since we last heard from them.
+    pub(crate) fn latest_receiver_ratchet_key(
+        &self,
+    ) -> Result<Option<PublicKey>, InvalidSessionError> {
+        self.session
+            .receiver_chains
+            .last()
+            .map(|chain| {
+                PublicKey::deserialize(&chain.sender_ratchet_key)
+                    .map_err(|_| InvalidSessionError("invalid receiver chain ratchet key"))
+            })
+            .transpose()
+    }
+
+    /// The position of the receiver chain for `sender` among all the chains received in this
+    /// session, starting at 1, or `None` if unknown.
+    pub(crate) fn get_receiver_chain_ordinal(
+        &self,
+        sender: &PublicKey,
+    ) -> Result<Option<u32>, InvalidSessionError> {
+        Ok(self
+            .get_receiver_chain(sender)?
+            .map(|(chain, _)| chain.ordinal)
+            .filter(|&ordinal| ordinal != 0))
+    }
+
     pub(crate) fn get_receiver_chain_key(
         &self,
         sender: &PublicKey,
@@ -266,11 +450,14 @@ impl SessionState {
             key: chain_key.key().to_vec(),
         };
 
+        self.session.receiver_chain_count += 1;
         let chain = session_structure::Chain {
             sender_ratchet_key: sender.serialize().to_vec(),
             sender_ratchet_key_private: vec![],
             chain_key: Some(chain_key),
             message_keys: vec![],
+            ordinal: self.session.receiver_chain_count,
+            header_key: vec![],
         };
 
         self.session.receiver_chains.push(chain);
@@ -302,6 +489,8 @@ impl SessionState {
             sender_ratchet_key_private: sender.private_key.serialize().to_vec(),
             chain_key: Some(chain_key),
             message_keys: vec![],
+            ordinal: 0,
+            header_key: vec![],
         };
 
         self.session.sender_chain = Some(new_chain);
@@ -312,6 +501,60 @@ impl SessionState {
         self
     }
 
+    /// Whether messages in this session carry encrypted headers.
+    pub(crate) fn uses_header_encryption(&self) -> Result<bool, InvalidSessionError> {
+        Ok(version_has_encrypted_header(self.session_version()? as u8))
+    }
+
+    /// Whether messages in this session are sealed with AES-256-GCM-SIV.
+    pub(crate) fn uses_aead(&self) -> Result<bool, InvalidSessionError> {
+        Ok(version_uses_aead(self.session_version()? as u8))
+    }
+
+    pub(crate) fn sender_chain_header_key(&self) -> Result<[u8; 32], InvalidSessionError> {
+        let sender_chain = self
+            .session
+            .sender_chain
+            .as_ref()
+            .ok_or(InvalidSessionError("missing sender chain"))?;
+        sender_chain.header_key[..]
+            .try_into()
+            .map_err(|_| InvalidSessionError("invalid sender chain header key"))
+    }
+
+    pub(crate) fn set_sender_chain_header_key(&mut self, header_key: &[u8; 32]) {
+        if let Some(sender_chain) = self.session.sender_chain.as_mut() {
+            sender_chain.header_key = header_key.to_vec();
+        }
+    }
+
+    /// The header keys of the receiver chains, most recent first.
+    pub(crate) fn receiver_chain_header_keys(&self) -> Result<Vec<[u8; 32]>, InvalidSessionError> {
+        self.session
+            .receiver_chains
+            .iter()
+            .rev()
+            .map(|chain| {
+                chain.header_key[..]
+                    .try_into()
+                    .map_err(|_| InvalidSessionError("invalid receiver chain header key"))
+            })
+            .collect()
+    }
+
+    pub(crate) fn set_receiver_chain_header_key(
+        &mut self,
+        sender: &PublicKey,
+        header_key: &[u8; 32],
+    ) -> Result<(), InvalidSessionError> {
+        let (mut chain, idx) = self
+            .get_receiver_chain(sender)?
+            .expect("called set_receiver_chain_header_key for a non-existent chain");
+        chain.header_key = header_key.to_vec();
+        self.session.receiver_chains[idx] = chain;
+        Ok(())
+    }
+
     pub(crate) fn get_sender_chain_key(&self) -> Result<ChainKey, InvalidSessionError> {
         let sender_chain = self
             .session
@@ -349,6 +592,8 @@ impl SessionState {
                 sender_ratchet_key_private: vec![],
                 chain_key: Some(chain_key),
                 message_keys: vec![],
+                ordinal: 0,
+                header_key: vec![],
             },
             Some(mut c) => {
                 c.chain_key = Some(chain_key);
@@ -449,12 +694,17 @@ impl SessionState {
         pre_key_id: Option<PreKeyId>,
         signed_ec_pre_key_id: SignedPreKeyId,
         base_key: &PublicKey,
+        now: SystemTime,
     ) {
         let signed_ec_pre_key_id: u32 = signed_ec_pre_key_id.into();
         let pending = session_structure::PendingPreKey {
             pre_key_id: pre_key_id.map(PreKeyId::into),
             signed_pre_key_id: signed_ec_pre_key_id as i32,
             base_key: base_key.serialize().to_vec(),
+            timestamp: now
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
         };
         self.session.pending_pre_key = Some(pending);
     }
@@ -490,6 +740,7 @@ impl SessionState {
                 PublicKey::deserialize(&pending_pre_key.base_key)
                     .map_err(|_| InvalidSessionError("invalid pending PreKey message base key"))?,
                 self.session.pending_kyber_pre_key.as_ref(),
+                pending_pre_key.timestamp,
             )))
         } else {
             Ok(None)
@@ -542,6 +793,57 @@ impl From<&SessionState> for SessionStructure {
     }
 }
 
+const SESSION_RECORD_TYPE: &[u8] = b"SessionRecord";
+
+/// Which side of the initial key agreement we were on for a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SessionRole {
+    /// We started the session by processing the other party's pre-key bundle.
+    Alice,
+    /// We accepted the session by processing a pre-key message from the other party.
+    Bob,
+}
+
+impl SessionRole {
+    pub(crate) fn from_proto(role: session_structure::Role) -> Option<Self> {
+        match role {
+            session_structure::Role::Unknown => None,
+            session_structure::Role::Alice => Some(Self::Alice),
+            session_structure::Role::Bob => Some(Self::Bob),
+        }
+    }
+}
+
+/// Limits on the archived sessions kept in a [SessionRecord].
+///
+/// The protocol functions apply the policy of the [SessionStore](crate::SessionStore) a record is
+/// saved to, as given by its [archive_policy](crate::SessionStore::archive_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionArchivePolicy {
+    /// The most archived sessions to keep; the oldest are dropped first.
+    ///
+    /// Archiving never keeps more than the default, so this can only lower the limit.
+    pub max_archived_states: usize,
+    /// How long to keep a session after it is archived, if not indefinitely.
+    ///
+    /// Sessions archived by versions of this library that didn't record when are only subject to
+    /// `max_archived_states`.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for SessionArchivePolicy {
+    fn default() -> Self {
+        Self {
+            max_archived_states: consts::ARCHIVED_STATES_MAX_LENGTH,
+            max_age: None,
+        }
+    }
+}
+
+/// Migrations for [SessionRecord]; see [record_version].
+const SESSION_RECORD_MIGRATIONS: &[Migration<RecordStructure>] =
+    &[record_version::add_version_field];
+
 #[derive(Clone)]
 pub struct SessionRecord {
     current_session: Option<SessionState>,
@@ -563,9 +865,30 @@ impl SessionRecord {
         }
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, SignalProtocolError> {
-        let record = RecordStructure::decode(bytes)
+    /// The format version written by [serialize](Self::serialize).
+    ///
+    /// Records in older formats are upgraded when they are loaded; records in newer formats are
+    /// rejected with [SignalProtocolError::UnrecognizedRecordVersion].
+    pub const CURRENT_FORMAT_VERSION: u32 = SESSION_RECORD_MIGRATIONS.len() as u32;
+
+    /// Decodes a serialized record and brings it up to the current format, also returning the
+    /// format it was stored in.
+    fn decode_and_migrate(bytes: &[u8]) -> Result<(RecordStructure, u32), SignalProtocolError> {
+        let mut record = RecordStructure::decode(bytes)
             .map_err(|_| InvalidSessionError("failed to decode session record protobuf"))?;
+        let stored_version = record.format_version;
+        record_version::migrate(
+            "SessionRecord",
+            &mut record,
+            stored_version,
+            SESSION_RECORD_MIGRATIONS,
+        )?;
+        record.format_version = Self::CURRENT_FORMAT_VERSION;
+        Ok((record, stored_version))
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, SignalProtocolError> {
+        let (record, _) = Self::decode_and_migrate(bytes)?;
 
         Ok(Self {
             current_session: record.current_session.map(|s| s.into()),
@@ -573,6 +896,100 @@ impl SessionRecord {
         })
     }
 
+    /// Like [deserialize](Self::deserialize), for a record written by
+    /// [serialize_with_integrity](Self::serialize_with_integrity) under the same `key` and
+    /// `address`.
+    ///
+    /// This catches a record that was corrupted or tampered with in storage up front, rather than
+    /// as a confusing decryption failure later on, including one that was copied over from
+    /// another address's slot.
+    pub fn deserialize_with_integrity(
+        bytes: &[u8],
+        key: &[u8],
+        address: &ProtocolAddress,
+        mode: IntegrityMode,
+    ) -> Result<Self, SignalProtocolError> {
+        Self::deserialize(record_integrity::check_tag(
+            key,
+            SESSION_RECORD_TYPE,
+            &record_integrity::session_location(address),
+            bytes,
+            mode,
+        )?)
+    }
+
+    /// Upgrades a record written by [serialize](Self::serialize) in an older format to
+    /// [CURRENT_FORMAT_VERSION](Self::CURRENT_FORMAT_VERSION), returning `None` if it is already
+    /// current.
+    ///
+    /// [deserialize](Self::deserialize) upgrades records in memory anyway, so this is never needed
+    /// for correctness; it lets a store rewrite its records up front (say, after an app update)
+    /// rather than as each session is next saved. Records written with
+    /// [serialize_with_integrity](Self::serialize_with_integrity) need to be checked and re-tagged
+    /// around this call.
+    pub fn migrate(bytes: &[u8]) -> Result<Option<Vec<u8>>, SignalProtocolError> {
+        let (record, stored_version) = Self::decode_and_migrate(bytes)?;
+        if stored_version == Self::CURRENT_FORMAT_VERSION {
+            return Ok(None);
+        }
+        Ok(Some(record.encode_to_vec()))
+    }
+
+    /// Imports a session record serialized by libsignal-protocol-java's `SessionRecord`.
+    ///
+    /// The two formats are mostly wire-compatible; the differences are handled here:
+    ///
+    /// - Version 2 sessions (including those with no version, which Java also treated as version
+    ///   2) can't be used by this library, so they are dropped.
+    /// - A current session Java marked as needing a refresh is archived, so the next message sent
+    ///   sets up a new session.
+    /// - Fields this library never used, such as Java's pending key exchange, are discarded.
+    ///
+    /// Archived sessions past [SessionArchivePolicy::default] are dropped as well.
+    pub fn from_legacy_java_format(bytes: &[u8]) -> Result<Self, SignalProtocolError> {
+        fn decode_usable_session(
+            bytes: &[u8],
+        ) -> Result<Option<(SessionStructure, bool)>, InvalidSessionError> {
+            let session = SessionStructure::decode(bytes)
+                .map_err(|_| InvalidSessionError("failed to decode legacy session protobuf"))?;
+            if session.session_version < CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION.into() {
+                log::info!(
+                    "dropping legacy session with version {}",
+                    session.session_version
+                );
+                return Ok(None);
+            }
+            let legacy = LegacySessionStructure::decode(bytes)
+                .map_err(|_| InvalidSessionError("failed to decode legacy session protobuf"))?;
+            Ok(Some((session, legacy.needs_refresh)))
+        }
+
+        let record = LegacyRecordStructure::decode(bytes)
+            .map_err(|_| InvalidSessionError("failed to decode legacy session record protobuf"))?;
+
+        let mut previous_sessions = Vec::with_capacity(record.previous_sessions.len());
+        for previous in &record.previous_sessions {
+            if let Some((session, _)) = decode_usable_session(previous)? {
+                previous_sessions.push(session.encode_to_vec());
+            }
+        }
+        let mut result = Self {
+            current_session: None,
+            previous_sessions,
+        };
+
+        if let Some((session, needs_refresh)) = decode_usable_session(&record.current_session)? {
+            result.current_session = Some(session.into());
+            if needs_refresh {
+                result.archive_current_state_inner();
+            }
+        }
+        result
+            .previous_sessions
+            .truncate(SessionArchivePolicy::default().max_archived_states);
+        Ok(result)
+    }
+
     pub fn from_single_session_state(bytes: &[u8]) -> Result<Self, SignalProtocolError> {
         let session = SessionState::from_session_structure(
             SessionStructure::decode(bytes)
@@ -615,6 +1032,25 @@ impl SessionRecord {
         self.current_session.is_some()
     }
 
+    /// True while an upgrade to a newer protocol version is in progress.
+    ///
+    /// That is, the current session uses a newer version than some archived session that is
+    /// still being kept around, because the other party has not yet confirmed the current one or
+    /// because messages they sent before the upgrade may still be in flight. Messages still
+    /// arriving on the older session are decrypted without making it current again.
+    pub fn has_pending_upgrade(&self) -> Result<bool, SignalProtocolError> {
+        let current_version = match &self.current_session {
+            Some(current_session) => current_session.session_version()?,
+            None => return Ok(false),
+        };
+        for previous in self.previous_session_states() {
+            if previous?.session_version()? < current_version {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub(crate) fn session_state(&self) -> Option<&SessionState> {
         self.current_session.as_ref()
     }
@@ -646,14 +1082,62 @@ impl SessionRecord {
         self.promote_state(updated_session)
     }
 
-    pub(crate) fn promote_state(&mut self, new_state: SessionState) {
+    /// Saves changes to an archived session without making it current again.
+    pub(crate) fn update_previous_session(&mut self, index: usize, updated_session: SessionState) {
+        self.previous_sessions[index] = updated_session.session.encode_to_vec();
+    }
+
+    /// Drops archived sessions that use an older protocol version than the current session and
+    /// were archived at least [OLDER_VERSION_GRACE_PERIOD](consts::OLDER_VERSION_GRACE_PERIOD)
+    /// before `now`.
+    ///
+    /// Called once the other party has confirmed the current session. Messages they sent on an
+    /// older session before the upgrade may still be in flight, so those sessions are kept until
+    /// the grace period is over. Sessions archived by versions of this library that didn't record
+    /// when are left to the archive policy.
+    pub(crate) fn retire_older_versions(
+        &mut self,
+        now: SystemTime,
+    ) -> Result<(), InvalidSessionError> {
+        let current_version = match &self.current_session {
+            Some(current_session) => current_session.session_version()?,
+            None => return Ok(()),
+        };
+        let cutoff = match now.checked_sub(consts::OLDER_VERSION_GRACE_PERIOD) {
+            Some(cutoff) => cutoff
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            None => return Ok(()),
+        };
+        let retired = self
+            .previous_session_states()
+            .map(|previous| {
+                let previous = previous?;
+                Ok(previous.session_version()? < current_version
+                    && previous.session.archived_at != 0
+                    && previous.session.archived_at <= cutoff)
+            })
+            .collect::<Result<Vec<_>, InvalidSessionError>>()?;
+        let mut retired = retired.into_iter();
+        self.previous_sessions
+            .retain(|_| !retired.next().expect("one per session"));
+        Ok(())
+    }
+
+    pub(crate) fn promote_state(&mut self, mut new_state: SessionState) {
         self.archive_current_state_inner();
+        new_state.session.archived_at = 0;
         self.current_session = Some(new_state);
     }
 
     // A non-fallible version of archive_current_state.
     fn archive_current_state_inner(&mut self) {
-        if let Some(current_session) = self.current_session.take() {
+        if let Some(mut current_session) = self.current_session.take() {
+            current_session.session.archived_at = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
             if self.previous_sessions.len() >= consts::ARCHIVED_STATES_MAX_LENGTH {
                 self.previous_sessions.pop();
             }
@@ -664,19 +1148,78 @@ impl SessionRecord {
         }
     }
 
+    /// Archives the current session, if any.
+    ///
+    /// At most [ARCHIVED_STATES_MAX_LENGTH](consts::ARCHIVED_STATES_MAX_LENGTH) archived sessions
+    /// are kept, dropping the oldest. A stricter [SessionArchivePolicy] is applied by
+    /// [apply_archive_policy](Self::apply_archive_policy), which the protocol functions do before
+    /// saving the record.
     pub fn archive_current_state(&mut self) -> Result<(), SignalProtocolError> {
         self.archive_current_state_inner();
         Ok(())
     }
 
+    /// Drops archived sessions that were archived before `older_than`.
+    ///
+    /// Sessions archived by versions of this library that didn't record when are kept.
+    pub fn prune(&mut self, older_than: SystemTime) -> Result<(), SignalProtocolError> {
+        let cutoff = older_than
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let archived_at = self
+            .previous_session_states()
+            .map(|previous| Ok(previous?.session.archived_at))
+            .collect::<Result<Vec<_>, InvalidSessionError>>()?;
+        let mut archived_at = archived_at.into_iter();
+        self.previous_sessions.retain(|_| {
+            let archived_at = archived_at.next().expect("one per session");
+            archived_at == 0 || archived_at >= cutoff
+        });
+        Ok(())
+    }
+
+    /// Trims the archived sessions to fit `policy`, treating `now` as the current time.
+    pub fn apply_archive_policy(
+        &mut self,
+        policy: &SessionArchivePolicy,
+        now: SystemTime,
+    ) -> Result<(), SignalProtocolError> {
+        // Newer sessions are first.
+        self.previous_sessions.truncate(policy.max_archived_states);
+        if let Some(cutoff) = policy.max_age.and_then(|max_age| now.checked_sub(max_age)) {
+            self.prune(cutoff)?;
+        }
+        Ok(())
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, SignalProtocolError> {
         let record = RecordStructure {
             current_session: self.current_session.as_ref().map(|s| s.into()),
             previous_sessions: self.previous_sessions.clone(),
+            format_version: Self::CURRENT_FORMAT_VERSION,
         };
         Ok(record.encode_to_vec())
     }
 
+    /// Like [serialize](Self::serialize), but with an HMAC under `key` attached; see
+    /// [deserialize_with_integrity](Self::deserialize_with_integrity).
+    ///
+    /// `key` should be a secret held by the store, kept apart from the records themselves.
+    /// `address` is the address the record is being stored for.
+    pub fn serialize_with_integrity(
+        &self,
+        key: &[u8],
+        address: &ProtocolAddress,
+    ) -> Result<Vec<u8>, SignalProtocolError> {
+        Ok(record_integrity::add_tag(
+            key,
+            SESSION_RECORD_TYPE,
+            &record_integrity::session_location(address),
+            self.serialize()?,
+        ))
+    }
+
     pub fn remote_registration_id(&self) -> Result<u32, SignalProtocolError> {
         Ok(self
             .session_state()
@@ -734,6 +1277,156 @@ impl SessionRecord {
             .remote_identity_key_bytes()?)
     }
 
+    /// The other party's identity key for the current session.
+    ///
+    /// This is `None` only for sessions created by very old versions of the protocol.
+    pub fn remote_identity_key(&self) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "remote_identity_key",
+                    "No current session".into(),
+                )
+            })?
+            .remote_identity_key()?)
+    }
+
+    /// Whether we started the current session or accepted it.
+    ///
+    /// This is `None` for sessions created before the role was recorded.
+    pub fn local_role(&self) -> Result<Option<SessionRole>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState("local_role", "No current session".into())
+            })?
+            .local_role())
+    }
+
+    /// Whether the key agreement that set up the current session included a one-time pre-key.
+    ///
+    /// Sessions set up from only a signed pre-key have weaker forward secrecy for their first
+    /// messages. This is `None` for sessions created before this was recorded.
+    pub fn used_one_time_pre_key(&self) -> Result<Option<bool>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "used_one_time_pre_key",
+                    "No current session".into(),
+                )
+            })?
+            .used_one_time_pre_key())
+    }
+
+    /// A short hash of the base key that set up the current session.
+    ///
+    /// Both parties compute the same value for the same session, and a new session always gets a
+    /// new one, so it can be logged or compared to tell sessions apart without exposing the key.
+    pub fn base_key_fingerprint(&self) -> Result<[u8; 8], SignalProtocolError> {
+        let alice_base_key = self.alice_base_key()?;
+        if alice_base_key.is_empty() {
+            return Err(SignalProtocolError::InvalidState(
+                "base_key_fingerprint",
+                "session has no base key".into(),
+            ));
+        }
+        let digest = Sha256::digest(alice_base_key);
+        Ok(digest[..8].try_into().expect("correct length"))
+    }
+
+    /// The number of messages sent on the current session's sending chain, if it has one.
+    pub fn sender_chain_length(&self) -> Result<Option<u32>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "sender_chain_length",
+                    "No current session".into(),
+                )
+            })?
+            .sender_chain_length())
+    }
+
+    /// The number of message keys derived on each of the current session's receiving chains,
+    /// oldest chain first.
+    pub fn receiver_chain_lengths(&self) -> Result<Vec<u32>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "receiver_chain_lengths",
+                    "No current session".into(),
+                )
+            })?
+            .receiver_chain_lengths())
+    }
+
+    /// When a message was last sent or received using the current session.
+    ///
+    /// This is `None` if there hasn't been one since this library started recording it.
+    pub fn last_used(&self) -> Result<Option<SystemTime>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState("last_used", "No current session".into())
+            })?
+            .last_used())
+    }
+
+    /// The number of archived sessions kept for decrypting late messages.
+    pub fn archived_session_count(&self) -> usize {
+        self.previous_sessions.len()
+    }
+
+    /// A short, non-secret summary of the current session, for comparing over a support channel.
+    ///
+    /// The result looks like `v4:1a2b3c4d5e6f7a8b:9c0d1e2f`, with three parts:
+    ///
+    /// - the session version;
+    /// - the [base_key_fingerprint](Self::base_key_fingerprint), in hex;
+    /// - the first four bytes, in hex, of a SHA-256 hash of this side's ratchet structure: the
+    ///   local role, the sending and receiving chain lengths, and the number of archived sessions.
+    ///
+    /// Two parties who share a session have the same first two parts; if those differ, they are
+    /// using different sessions. The last part differs between the two sides and changes with
+    /// every message, so it tells support whether a party's session moved on between two reports.
+    /// No key material goes into any part.
+    pub fn diagnostic_digest(&self) -> Result<String, SignalProtocolError> {
+        let state = self.session_state().ok_or_else(|| {
+            SignalProtocolError::InvalidState("diagnostic_digest", "No current session".into())
+        })?;
+
+        let mut structure = Sha256::new();
+        structure.update(b"Signal_SessionDiagnosticDigest");
+        structure.update([match state.local_role() {
+            None => 0,
+            Some(SessionRole::Alice) => 1,
+            Some(SessionRole::Bob) => 2,
+        }]);
+        match state.sender_chain_length() {
+            None => structure.update([0]),
+            Some(length) => {
+                structure.update([1]);
+                structure.update(length.to_be_bytes());
+            }
+        }
+        let receiver_chain_lengths = state.receiver_chain_lengths();
+        structure.update((receiver_chain_lengths.len() as u32).to_be_bytes());
+        for length in receiver_chain_lengths {
+            structure.update(length.to_be_bytes());
+        }
+        structure.update((self.previous_sessions.len() as u32).to_be_bytes());
+
+        Ok(format!(
+            "v{}:{}:{}",
+            state.session_version()?,
+            hex::encode(self.base_key_fingerprint()?),
+            hex::encode(&structure.finalize()[..4])
+        ))
+    }
+
     pub fn has_sender_chain(&self) -> Result<bool, SignalProtocolError> {
         match &self.current_session {
             Some(session) => Ok(session.has_sender_chain()?),
@@ -800,3 +1493,282 @@ impl SessionRecord {
             .get_kyber_ciphertext())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_versions() -> Result<(), SignalProtocolError> {
+        let legacy = RecordStructure {
+            current_session: Some(SessionStructure {
+                session_version: 3,
+                ..Default::default()
+            }),
+            previous_sessions: vec![vec![1, 2, 3]],
+            format_version: 0,
+        }
+        .encode_to_vec();
+
+        let record = SessionRecord::deserialize(&legacy)?;
+        assert_eq!(record.session_version()?, 3);
+        assert_eq!(record.previous_sessions, vec![vec![1, 2, 3]]);
+
+        let migrated = SessionRecord::migrate(&legacy)?.expect("needs migration");
+        assert_eq!(
+            RecordStructure::decode(&migrated[..])
+                .expect("valid")
+                .format_version,
+            SessionRecord::CURRENT_FORMAT_VERSION
+        );
+        assert_eq!(migrated, record.serialize()?);
+        assert!(SessionRecord::migrate(&migrated)?.is_none());
+
+        let future = RecordStructure {
+            format_version: SessionRecord::CURRENT_FORMAT_VERSION + 1,
+            ..Default::default()
+        }
+        .encode_to_vec();
+        assert!(matches!(
+            SessionRecord::deserialize(&future),
+            Err(SignalProtocolError::UnrecognizedRecordVersion("SessionRecord", v))
+                if v == SessionRecord::CURRENT_FORMAT_VERSION + 1
+        ));
+        assert!(SessionRecord::migrate(&future).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_java_import() -> Result<(), SignalProtocolError> {
+        let session = |version: u32, counter: u32, needs_refresh: bool| {
+            let mut bytes = SessionStructure {
+                session_version: version,
+                previous_counter: counter,
+                ..Default::default()
+            }
+            .encode_to_vec();
+            LegacySessionStructure { needs_refresh }.encode_raw(&mut bytes);
+            // Java's pending key exchange, which is skipped.
+            prost::encoding::bytes::encode(8, &vec![1, 2, 3], &mut bytes);
+            bytes
+        };
+        let counters = |record: &SessionRecord| -> Vec<u32> {
+            record
+                .previous_session_states()
+                .map(|state| state.expect("valid").session.previous_counter)
+                .collect()
+        };
+
+        let legacy = LegacyRecordStructure {
+            current_session: session(3, 1, false),
+            previous_sessions: vec![session(2, 2, false), session(3, 3, false)],
+        }
+        .encode_to_vec();
+        let record = SessionRecord::from_legacy_java_format(&legacy)?;
+        assert_eq!(record.session_version()?, 3);
+        assert_eq!(
+            record
+                .session_state()
+                .expect("present")
+                .session
+                .previous_counter,
+            1
+        );
+        assert_eq!(counters(&record), [3]);
+        assert_eq!(
+            SessionRecord::deserialize(&record.serialize()?)?.previous_sessions,
+            record.previous_sessions
+        );
+
+        let legacy = LegacyRecordStructure {
+            current_session: session(3, 1, true),
+            previous_sessions: vec![session(3, 2, false)],
+        }
+        .encode_to_vec();
+        let record = SessionRecord::from_legacy_java_format(&legacy)?;
+        assert!(record.session_state().is_none());
+        assert_eq!(counters(&record), [1, 2]);
+
+        let legacy = LegacyRecordStructure {
+            current_session: session(0, 1, false),
+            previous_sessions: vec![],
+        }
+        .encode_to_vec();
+        let record = SessionRecord::from_legacy_java_format(&legacy)?;
+        assert!(record.session_state().is_none());
+        assert!(record.previous_sessions.is_empty());
+
+        assert!(SessionRecord::from_legacy_java_format(&[0xFF]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn flow_statistics_windows() {
+        let mut state = SessionState::from_session_structure(SessionStructure::default());
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let window = consts::FLOW_STATISTICS_WINDOW;
+        let stats = |state: &SessionState| state.session.flow_statistics.clone().expect("present");
+
+        state.record_flow_event(FlowEvent::Sent, start);
+        state.record_flow_event(FlowEvent::Sent, start + window / 2);
+        state.record_flow_event(FlowEvent::Received, start + window / 2);
+        assert_eq!(stats(&state).sent, 2);
+        assert_eq!(stats(&state).received, 1);
+
+        // The next window keeps the previous one's counts.
+        state.record_flow_event(FlowEvent::Duplicate { counter: 7 }, start + window);
+        let next = stats(&state);
+        assert_eq!(next.window_start, 1_000_000 + window.as_secs());
+        assert_eq!((next.sent, next.received, next.duplicates), (0, 0, 1));
+        assert_eq!((next.previous_sent, next.previous_received), (2, 1));
+
+        state.record_flow_event(FlowEvent::Duplicate { counter: 7 }, start + window);
+        assert_eq!(stats(&state).repeated_duplicates, 2);
+        state.record_flow_event(FlowEvent::Duplicate { counter: 8 }, start + window);
+        assert_eq!(stats(&state).last_duplicate_counter, 8);
+        assert_eq!(stats(&state).repeated_duplicates, 1);
+        state.record_flow_event(FlowEvent::Received, start + window);
+        assert_eq!(stats(&state).repeated_duplicates, 0);
+
+        // After a quiet window, there's nothing to carry over.
+        state.record_flow_event(FlowEvent::Sent, start + window * 3);
+        let later = stats(&state);
+        assert_eq!(later.window_start, 1_000_000 + 3 * window.as_secs());
+        assert_eq!(
+            (later.sent, later.previous_sent, later.previous_duplicates),
+            (1, 0, 0)
+        );
+
+        // A clock that goes backwards starts over.
+        state.record_flow_event(FlowEvent::Received, start);
+        let reset = stats(&state);
+        assert_eq!(reset.window_start, 1_000_000);
+        assert_eq!((reset.sent, reset.received), (0, 1));
+    }
+
+    #[test]
+    fn archive_policy() -> Result<(), SignalProtocolError> {
+        let structure = |archived_at: u64, counter: u32| SessionStructure {
+            archived_at,
+            previous_counter: counter,
+            ..Default::default()
+        };
+        let archived =
+            |archived_at: u64, counter: u32| structure(archived_at, counter).encode_to_vec();
+        let counters = |record: &SessionRecord| -> Vec<u32> {
+            record
+                .previous_session_states()
+                .map(|state| state.expect("valid").session.previous_counter)
+                .collect()
+        };
+        let mut record = SessionRecord::new_fresh();
+        // Newest first; the third was archived before archive times were recorded.
+        record.previous_sessions = vec![
+            archived(3_000, 1),
+            archived(2_000, 2),
+            archived(0, 3),
+            archived(1_000, 4),
+        ];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(3_500);
+
+        let mut pruned = record.clone();
+        pruned.prune(SystemTime::UNIX_EPOCH + Duration::from_secs(2_000))?;
+        assert_eq!(counters(&pruned), [1, 2, 3]);
+
+        let mut trimmed = record.clone();
+        trimmed.apply_archive_policy(&SessionArchivePolicy::default(), now)?;
+        assert_eq!(counters(&trimmed), [1, 2, 3, 4]);
+        trimmed.apply_archive_policy(
+            &SessionArchivePolicy {
+                max_archived_states: 3,
+                max_age: Some(Duration::from_secs(1_000)),
+            },
+            now,
+        )?;
+        assert_eq!(counters(&trimmed), [1, 3]);
+
+        // Archiving stamps the session and promoting a new one clears the stamp.
+        let mut record = SessionRecord::new(SessionState::from_session_structure(structure(0, 5)));
+        record.promote_state(SessionState::from_session_structure(structure(1_000, 6)));
+        assert_eq!(
+            record.session_state().expect("present").session.archived_at,
+            0
+        );
+        record.archive_current_state()?;
+        assert!(record.previous_session_states().all(|state| state
+            .expect("valid")
+            .session
+            .archived_at
+            > 0));
+        record.prune(SystemTime::now() + Duration::from_secs(60))?;
+        assert_eq!(counters(&record), Vec::<u32>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn archiving_is_bounded() -> Result<(), SignalProtocolError> {
+        let mut record = SessionRecord::new_fresh();
+        for counter in 0..(consts::ARCHIVED_STATES_MAX_LENGTH as u32 + 10) {
+            record.promote_state(SessionState::from_session_structure(SessionStructure {
+                previous_counter: counter,
+                ..Default::default()
+            }));
+            record.archive_current_state()?;
+        }
+        assert_eq!(
+            record.previous_session_states().len(),
+            consts::ARCHIVED_STATES_MAX_LENGTH
+        );
+        // The newest are kept.
+        assert_eq!(
+            record
+                .previous_session_states()
+                .next()
+                .expect("present")?
+                .session
+                .previous_counter,
+            consts::ARCHIVED_STATES_MAX_LENGTH as u32 + 9
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retire_older_versions_after_grace_period() -> Result<(), SignalProtocolError> {
+        let structure = |version: u32, archived_at: u64, counter: u32| SessionStructure {
+            session_version: version,
+            archived_at,
+            previous_counter: counter,
+            ..Default::default()
+        };
+        let archived = |version: u32, archived_at: u64, counter: u32| {
+            structure(version, archived_at, counter).encode_to_vec()
+        };
+        let counters = |record: &SessionRecord| -> Vec<u32> {
+            record
+                .previous_session_states()
+                .map(|state| state.expect("valid").session.previous_counter)
+                .collect()
+        };
+        let grace_period = consts::OLDER_VERSION_GRACE_PERIOD.as_secs();
+        let mut record =
+            SessionRecord::new(SessionState::from_session_structure(structure(4, 0, 0)));
+        record.previous_sessions = vec![
+            archived(3, 2_000, 1),
+            archived(4, 1_000, 2),
+            archived(3, 1_000, 3),
+            archived(3, 0, 4),
+        ];
+
+        record.retire_older_versions(SystemTime::UNIX_EPOCH)?;
+        assert_eq!(counters(&record), [1, 2, 3, 4]);
+        record.retire_older_versions(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000 + grace_period),
+        )?;
+        assert_eq!(counters(&record), [1, 2, 4]);
+        record.retire_older_versions(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2_000 + grace_period),
+        )?;
+        assert_eq!(counters(&record), [2, 4]);
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! An encrypted, authenticated container for moving a [SessionRecord] between devices or into a
+//! backup.
+//!
+//! The layout is:
+//!
+//! ```text
+//! magic "SSAR" || version (1 byte) || kdf (1 byte) || iterations (u32 BE) || salt (32 bytes)
+//!     || AES-256-CTR(record) || HMAC-SHA256(everything before it)
+//! ```
+//!
+//! The cipher and MAC keys come from HKDF-SHA256 over the caller's secret, using the random salt.
+//! When the secret is a password it is first stretched with PBKDF2-HMAC-SHA256, and the iteration
+//! count is recorded so that it can be raised later without breaking existing archives.
+
+use std::convert::TryInto;
+
+use hkdf::Hkdf;
+use rand::{CryptoRng, Rng};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::crypto;
+use crate::state::SessionRecord;
+use crate::{Result, SignalProtocolError};
+
+const MAGIC: &[u8; 4] = b"SSAR";
+const CURRENT_VERSION: u8 = 1;
+const KDF_RAW_KEY: u8 = 0;
+const KDF_PBKDF2_HMAC_SHA256: u8 = 1;
+const SALT_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4 + SALT_LEN;
+const MAC_LEN: usize = 32;
+
+/// The recommended PBKDF2 iteration count for [SessionArchiveKey::Password].
+pub const SESSION_ARCHIVE_PASSWORD_ITERATIONS: u32 = 600_000;
+/// Archives asking for more PBKDF2 iterations than this are rejected rather than letting a
+/// malicious archive tie up the importing device.
+const MAX_PASSWORD_ITERATIONS: u32 = 10_000_000;
+
+/// The secret protecting an exported [SessionRecord].
+#[derive(Clone, Copy)]
+pub enum SessionArchiveKey<'a> {
+    /// A uniformly random 256-bit key, such as one shared during device linking.
+    Key(&'a [u8; 32]),
+    /// A user-chosen password, which will be stretched with PBKDF2.
+    ///
+    /// `iterations` must be at least 1. It is only used when exporting, and is recorded in the
+    /// archive; importing uses whatever count the archive was created with. Unless there's a reason
+    /// to prefer speed, use [SESSION_ARCHIVE_PASSWORD_ITERATIONS].
+    Password { password: &'a [u8], iterations: u32 },
+}
+
+impl SessionRecord {
+    /// Serialize this record, including all archived session states, into an encrypted and
+    /// authenticated blob that can only be read back by [import_secure](Self::import_secure) with
+    /// the same `key`.
+    pub fn export_secure<R: Rng + CryptoRng>(
+        &self,
+        key: SessionArchiveKey<'_>,
+        csprng: &mut R,
+    ) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        csprng.fill_bytes(&mut salt);
+        let (kdf, iterations) = match key {
+            SessionArchiveKey::Key(_) => (KDF_RAW_KEY, 0),
+            SessionArchiveKey::Password { iterations, .. } => (KDF_PBKDF2_HMAC_SHA256, iterations),
+        };
+        if kdf == KDF_PBKDF2_HMAC_SHA256 && iterations == 0 {
+            return Err(SignalProtocolError::InvalidArgument(
+                "PBKDF2 needs at least one iteration".to_owned(),
+            ));
+        }
+        let (cipher_key, mac_key) = derive_keys(key, iterations, &salt);
+
+        let mut result = Vec::with_capacity(HEADER_LEN + MAC_LEN);
+        result.extend_from_slice(MAGIC);
+        result.push(CURRENT_VERSION);
+        result.push(kdf);
+        result.extend_from_slice(&iterations.to_be_bytes());
+        result.extend_from_slice(&salt);
+        result.extend(
+            crypto::aes_256_ctr_encrypt(&self.serialize()?, &cipher_key)
+                .expect("key size is valid"),
+        );
+        let mac = crypto::hmac_sha256(&mac_key, &result);
+        result.extend_from_slice(&mac);
+        Ok(result)
+    }
+
+    /// Decrypt and verify a blob produced by [export_secure](Self::export_secure).
+    ///
+    /// Fails with [InvalidSessionStructure](crate::SignalProtocolError::InvalidSessionStructure)
+    /// if the blob is malformed, from an unsupported version, or was not produced with `key`.
+    pub fn import_secure(bytes: &[u8], key: SessionArchiveKey<'_>) -> Result<Self> {
+        if bytes.len() < HEADER_LEN + MAC_LEN || !bytes.starts_with(MAGIC) {
+            return Err(SignalProtocolError::InvalidSessionStructure(
+                "not a session archive",
+            ));
+        }
+        let (header, rest) = bytes.split_at(HEADER_LEN);
+        let version = header[4];
+        let kdf = header[5];
+        let iterations = u32::from_be_bytes(header[6..10].try_into().expect("correct length"));
+        let salt = &header[10..];
+
+        if version != CURRENT_VERSION {
+            return Err(SignalProtocolError::InvalidSessionStructure(
+                "unsupported session archive version",
+            ));
+        }
+        let expected_kdf = match key {
+            SessionArchiveKey::Key(_) => KDF_RAW_KEY,
+            SessionArchiveKey::Password { .. } => KDF_PBKDF2_HMAC_SHA256,
+        };
+        if kdf != expected_kdf {
+            return Err(SignalProtocolError::InvalidSessionStructure(
+                "session archive uses a different kind of key",
+            ));
+        }
+        if kdf == KDF_PBKDF2_HMAC_SHA256 && iterations == 0 {
+            return Err(SignalProtocolError::InvalidSessionStructure(
+                "session archive asks for no iterations",
+            ));
+        }
+        if iterations > MAX_PASSWORD_ITERATIONS {
+            return Err(SignalProtocolError::InvalidSessionStructure(
+                "session archive asks for too many iterations",
+            ));
+        }
+
+        let (cipher_key, mac_key) = derive_keys(key, iterations, salt);
+        let (ciphertext, their_mac) = rest.split_at(rest.len() - MAC_LEN);
+        let our_mac = crypto::hmac_sha256(&mac_key, &bytes[..bytes.len() - MAC_LEN]);
+        if !bool::from(our_mac.ct_eq(their_mac)) {
+            return Err(SignalProtocolError::InvalidSessionStructure(
+                "session archive MAC verification failed",
+            ));
+        }
+
+        let serialized =
+            crypto::aes_256_ctr_decrypt(ciphertext, &cipher_key).expect("key size is valid");
+        Self::deserialize(&serialized)
+    }
+}
+
+fn derive_keys(key: SessionArchiveKey<'_>, iterations: u32, salt: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut stretched = [0u8; 32];
+    let input_key_material: &[u8] = match key {
+        SessionArchiveKey::Key(key) => key,
+        SessionArchiveKey::Password { password, .. } => {
+            crypto::pbkdf2_hmac_sha256(password, salt, iterations, &mut stretched);
+            &stretched
+        }
+    };
+
+    let mut derived = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(salt), input_key_material)
+        .expand(b"Signal_SessionArchive_v1", &mut derived)
+        .expect("valid output length");
+    (
+        derived[..32].try_into().expect("correct length"),
+        derived[32..].try_into().expect("correct length"),
+    )
+}
@@ -4,9 +4,11 @@
 //
 
 use crate::proto::storage::SignedPreKeyRecordStructure;
-use crate::{kem, KeyPair, PrivateKey, PublicKey, Result, SignalProtocolError};
+use crate::redact::Redact;
+use crate::{kem, KeyPair, PrivateKey, PublicKey, Result, SignalProtocolError, Timestamp};
 
 use prost::Message;
+use rand::{CryptoRng, Rng};
 
 use std::convert::AsRef;
 use std::fmt;
@@ -33,15 +35,40 @@ impl fmt::Display for SignedPreKeyId {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct SignedPreKeyRecord {
     signed_pre_key: SignedPreKeyRecordStructure,
 }
 
+impl fmt::Debug for SignedPreKeyRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignedPreKeyRecord")
+            .field("signed_pre_key", &Redact(&self.signed_pre_key))
+            .finish()
+    }
+}
+
 impl SignedPreKeyRecord {
     pub fn private_key(&self) -> Result<PrivateKey> {
         PrivateKey::deserialize(&self.get_storage().private_key)
     }
+
+    /// Generates a fresh key pair for `id`, signed with `signing_key` and timestamped now.
+    pub fn generate<R: Rng + CryptoRng>(
+        id: SignedPreKeyId,
+        signing_key: &PrivateKey,
+        csprng: &mut R,
+    ) -> Result<SignedPreKeyRecord> {
+        let key_pair = KeyPair::generate(csprng);
+        let signature =
+            signing_key.calculate_signature(&key_pair.public_key.serialize(), csprng)?;
+        Ok(SignedPreKeyRecord::new(
+            id,
+            Timestamp::now(),
+            &key_pair,
+            &signature,
+        ))
+    }
 }
 
 impl GenericSignedPreKey for SignedPreKeyRecord {
@@ -66,7 +93,7 @@ pub trait GenericSignedPreKey {
     fn get_storage(&self) -> &SignedPreKeyRecordStructure;
     fn from_storage(storage: SignedPreKeyRecordStructure) -> Self;
 
-    fn new(id: Self::Id, timestamp: u64, key_pair: &Self::KeyPair, signature: &[u8]) -> Self
+    fn new(id: Self::Id, timestamp: Timestamp, key_pair: &Self::KeyPair, signature: &[u8]) -> Self
     where
         Self: Sized,
     {
@@ -75,7 +102,7 @@ pub trait GenericSignedPreKey {
         let signature = signature.to_vec();
         Self::from_storage(SignedPreKeyRecordStructure {
             id: id.into(),
-            timestamp,
+            timestamp: timestamp.epoch_millis(),
             public_key,
             private_key,
             signature,
@@ -100,8 +127,8 @@ pub trait GenericSignedPreKey {
         Ok(self.get_storage().id.into())
     }
 
-    fn timestamp(&self) -> Result<u64> {
-        Ok(self.get_storage().timestamp)
+    fn timestamp(&self) -> Result<Timestamp> {
+        Ok(Timestamp::from_epoch_millis(self.get_storage().timestamp))
     }
 
     fn signature(&self) -> Result<Vec<u8>> {
@@ -0,0 +1,273 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::time::Duration;
+
+use rand::{CryptoRng, Rng};
+
+use crate::state::{GenericSignedPreKey, SignedPreKeyId, SignedPreKeyRecord};
+use crate::{
+    Clock, Context, IdentityKeyPair, KeyPair, Result, SignalProtocolError, SignedPreKeyStore,
+    SystemClock,
+};
+
+/// How often a new signed pre-key is generated by [SignedPreKeyRotation::default].
+pub const DEFAULT_SIGNED_PRE_KEY_ROTATION_INTERVAL: Duration =
+    Duration::from_secs(2 * 24 * 60 * 60);
+/// How long a replaced signed pre-key is kept by [SignedPreKeyRotation::default], to decrypt
+/// messages that were sent using it before the replacement was uploaded.
+pub const DEFAULT_SIGNED_PRE_KEY_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Decides when to rotate the local signed pre-key, and which replaced keys can be deleted.
+///
+/// Ages are measured from the [Timestamp](crate::Timestamp) stored in each [SignedPreKeyRecord],
+/// and "now" comes from the clock passed to [SignedPreKeyRotation::with_clock] (by default,
+/// [SystemClock]).
+#[derive(Clone, Debug)]
+pub struct SignedPreKeyRotation<C = SystemClock> {
+    rotation_interval: Duration,
+    retention: Duration,
+    clock: C,
+}
+
+impl SignedPreKeyRotation {
+    /// Create a rotation policy that uses the system clock.
+    pub fn new(rotation_interval: Duration, retention: Duration) -> Self {
+        Self::with_clock(rotation_interval, retention, SystemClock)
+    }
+}
+
+impl Default for SignedPreKeyRotation {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_SIGNED_PRE_KEY_ROTATION_INTERVAL,
+            DEFAULT_SIGNED_PRE_KEY_RETENTION,
+        )
+    }
+}
+
+impl<C: Clock> SignedPreKeyRotation<C> {
+    /// Create a rotation policy that reads the current time from `clock`.
+    pub fn with_clock(rotation_interval: Duration, retention: Duration, clock: C) -> Self {
+        Self {
+            rotation_interval,
+            retention,
+            clock,
+        }
+    }
+
+    pub fn rotation_interval(&self) -> Duration {
+        self.rotation_interval
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Returns true if there is no `active` key, or if it is at least
+    /// [rotation_interval](Self::rotation_interval) old.
+    pub fn is_rotation_due(&self, active: Option<&SignedPreKeyRecord>) -> Result<bool> {
+        let active = match active {
+            Some(active) => active,
+            None => return Ok(true),
+        };
+        let age = self
+            .clock
+            .now()
+            .duration_since(active.timestamp()?)
+            .unwrap_or_default();
+        Ok(age >= self.rotation_interval)
+    }
+
+    /// Generate a new signed pre-key with the given `id`, sign it with `identity_key_pair`, and
+    /// save it to `store`.
+    ///
+    /// The previously active key is left in the store; use [prune](Self::prune) once the new key
+    /// has been uploaded.
+    pub async fn rotate<R: Rng + CryptoRng>(
+        &self,
+        id: SignedPreKeyId,
+        identity_key_pair: &IdentityKeyPair,
+        store: &mut dyn SignedPreKeyStore,
+        csprng: &mut R,
+        ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        let key_pair = KeyPair::generate(csprng);
+        let signature = identity_key_pair
+            .private_key()
+            .calculate_signature(&key_pair.public_key.serialize(), csprng)?;
+        let record = SignedPreKeyRecord::new(id, self.clock.now(), &key_pair, &signature);
+        store.save_signed_pre_key(id, &record, ctx).await?;
+        Ok(record)
+    }
+
+    /// Remove signed pre-keys that were replaced more than [retention](Self::retention) ago.
+    ///
+    /// `known_ids` lists the keys the caller has stored; ids that are no longer present in `store`
+    /// are skipped. The key identified by `active_id` is never removed, and neither are keys
+    /// created after it (for instance, one that has been generated but not uploaded yet). A key
+    /// counts as replaced once the next newer key was created, not from its own creation time.
+    ///
+    /// Returns the ids that were removed.
+    pub async fn prune(
+        &self,
+        active_id: SignedPreKeyId,
+        known_ids: impl IntoIterator<Item = SignedPreKeyId>,
+        store: &mut dyn SignedPreKeyStore,
+        ctx: Context,
+    ) -> Result<Vec<SignedPreKeyId>> {
+        let active_timestamp = store
+            .get_signed_pre_key(active_id, ctx)
+            .await?
+            .timestamp()?;
+
+        let mut older = Vec::new();
+        for id in known_ids {
+            if id == active_id {
+                continue;
+            }
+            let record = match store.get_signed_pre_key(id, ctx).await {
+                Ok(record) => record,
+                Err(SignalProtocolError::InvalidSignedPreKeyId) => continue,
+                Err(e) => return Err(e),
+            };
+            let timestamp = record.timestamp()?;
+            if timestamp <= active_timestamp {
+                older.push((timestamp, id));
+            }
+        }
+        older.sort_unstable();
+        older.dedup();
+
+        let now = self.clock.now();
+        let mut removed = Vec::new();
+        for (i, &(_, id)) in older.iter().enumerate() {
+            let replaced_at = older
+                .get(i + 1)
+                .map_or(active_timestamp, |&(timestamp, _)| timestamp);
+            if now.duration_since(replaced_at).unwrap_or_default() >= self.retention {
+                store.remove_signed_pre_key(id, ctx).await?;
+                removed.push(id);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(all(test, feature = "inmem-stores"))]
+mod tests {
+    use super::*;
+    use crate::InMemSignedPreKeyStore;
+
+    use crate::Timestamp;
+
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+    use std::cell::Cell;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn test_rotation_and_pruning() -> Result<()> {
+        let now = Cell::new(Timestamp::UNIX_EPOCH + 1000 * DAY);
+        let rotation = SignedPreKeyRotation::with_clock(2 * DAY, 3 * DAY, || now.get());
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let mut store = InMemSignedPreKeyStore::new();
+
+        async {
+            assert!(rotation.is_rotation_due(None)?);
+
+            let first = rotation
+                .rotate(1.into(), &identity, &mut store, &mut OsRng, None)
+                .await?;
+            assert!(identity
+                .public_key()
+                .verify_signature(&first.public_key()?.serialize(), &first.signature()?)?);
+            assert!(!rotation.is_rotation_due(Some(&first))?);
+
+            now.set(now.get() + 2 * DAY);
+            assert!(rotation.is_rotation_due(Some(&first))?);
+            rotation
+                .rotate(2.into(), &identity, &mut store, &mut OsRng, None)
+                .await?;
+
+            now.set(now.get() + 2 * DAY);
+            rotation
+                .rotate(3.into(), &identity, &mut store, &mut OsRng, None)
+                .await?;
+
+            // Key 1 was replaced 2 days ago, so it's still within the retention window, even
+            // though it was created 4 days ago.
+            let removed = rotation
+                .prune(
+                    3.into(),
+                    vec![1.into(), 2.into(), 3.into()],
+                    &mut store,
+                    None,
+                )
+                .await?;
+            assert!(removed.is_empty());
+
+            now.set(now.get() + DAY);
+            let removed = rotation
+                .prune(
+                    3.into(),
+                    vec![1.into(), 2.into(), 3.into()],
+                    &mut store,
+                    None,
+                )
+                .await?;
+            assert_eq!(removed, vec![SignedPreKeyId::from(1)]);
+            assert!(store.get_signed_pre_key(1.into(), None).await.is_err());
+
+            // The active key is never removed, no matter how old it is.
+            now.set(now.get() + 100 * DAY);
+            let removed = rotation
+                .prune(
+                    3.into(),
+                    vec![1.into(), 2.into(), 3.into()],
+                    &mut store,
+                    None,
+                )
+                .await?;
+            assert_eq!(removed, vec![SignedPreKeyId::from(2)]);
+            store.get_signed_pre_key(3.into(), None).await?;
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_pruning_keeps_newer_keys() -> Result<()> {
+        let now = Cell::new(Timestamp::UNIX_EPOCH + 1000 * DAY);
+        let rotation = SignedPreKeyRotation::with_clock(2 * DAY, DAY, || now.get());
+        let identity = IdentityKeyPair::generate(&mut OsRng);
+        let mut store = InMemSignedPreKeyStore::new();
+
+        async {
+            rotation
+                .rotate(1.into(), &identity, &mut store, &mut OsRng, None)
+                .await?;
+            now.set(now.get() + 10 * DAY);
+            rotation
+                .rotate(2.into(), &identity, &mut store, &mut OsRng, None)
+                .await?;
+            now.set(now.get() + 10 * DAY);
+
+            // Key 2 hasn't been uploaded yet, so key 1 is still active.
+            let removed = rotation
+                .prune(1.into(), vec![1.into(), 2.into()], &mut store, None)
+                .await?;
+            assert!(removed.is_empty());
+            store.get_signed_pre_key(2.into(), None).await?;
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
@@ -7,14 +7,42 @@
 
 #![warn(missing_docs)]
 
+mod blocking;
+#[cfg(feature = "inmem-stores")]
+mod cached;
+#[cfg(feature = "chaos")]
+mod faulty;
+#[cfg(feature = "inmem-stores")]
 mod inmem;
+mod journal;
+#[cfg(feature = "inmem-stores")]
+mod kv;
+mod notifying;
+mod shared;
 mod traits;
 
+#[cfg(feature = "inmem-stores")]
+pub(crate) use blocking::block_on;
+pub use blocking::{
+    BlockingStoreAdapter, SyncIdentityKeyStore, SyncKyberPreKeyStore, SyncPreKeyStore,
+    SyncSenderKeyStore, SyncSessionStore, SyncSignedPreKeyStore, SyncStoreAdapter,
+};
+#[cfg(feature = "inmem-stores")]
+pub use cached::CachedSessionStore;
+#[cfg(feature = "chaos")]
+pub use faulty::{FaultySignalProtocolStore, FaultyStore, InjectedFault, Sleep};
+#[cfg(feature = "inmem-stores")]
 pub use inmem::{
-    InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemPreKeyStore, InMemSenderKeyStore,
-    InMemSessionStore, InMemSignalProtocolStore, InMemSignedPreKeyStore,
+    InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemMultiAccountStore, InMemPreKeyStore,
+    InMemReplayCache, InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore,
+    InMemSignedPreKeyStore, StoreSnapshot,
 };
+pub use journal::{InMemStoreJournal, JournalEntry, JournalingStore, StoreJournal, StoreMutation};
+#[cfg(feature = "inmem-stores")]
+pub use kv::{InMemKvBackend, KvBackend, KvProtocolStore};
+pub use notifying::NotifyingPreKeyStore;
+pub(crate) use shared::SharedStore;
 pub use traits::{
     Context, Direction, IdentityKeyStore, KyberPreKeyStore, PreKeyStore, ProtocolStore,
-    SenderKeyStore, SessionStore, SignedPreKeyStore,
+    ReplayCache, ReplayKey, SenderKeyStore, SessionStore, SignedPreKeyStore,
 };
@@ -0,0 +1,632 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Blocking versions of the store traits, for applications that don't otherwise use async code.
+//!
+//! A store implementing the `Sync*Store` traits can be passed to the protocol functions by wrapping
+//! it in [SyncStoreAdapter]. Going the other way, [BlockingStoreAdapter] turns an async store into
+//! a blocking one, waiting on each operation in the calling thread, so that the same store can be
+//! shared with synchronous code.
+//!
+//! The blocking traits leave out the FFI [Context], which only the bridges need.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use crate::storage::traits::{self, unsupported, Direction};
+use crate::storage::Context;
+use crate::{
+    IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord,
+    ProtocolAddress, Result, SenderKeyName, SenderKeyRecord, SessionArchivePolicy, SessionRecord,
+    SignedPreKeyId, SignedPreKeyRecord,
+};
+
+/// Blocking counterpart of [traits::IdentityKeyStore].
+pub trait SyncIdentityKeyStore {
+    /// See [traits::IdentityKeyStore::get_identity_key_pair].
+    fn get_identity_key_pair(&self) -> Result<IdentityKeyPair>;
+
+    /// See [traits::IdentityKeyStore::get_local_registration_id].
+    fn get_local_registration_id(&self) -> Result<u32>;
+
+    /// See [traits::IdentityKeyStore::save_identity].
+    fn save_identity(&mut self, address: &ProtocolAddress, identity: &IdentityKey) -> Result<bool>;
+
+    /// See [traits::IdentityKeyStore::is_trusted_identity].
+    fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+    ) -> Result<bool>;
+
+    /// See [traits::IdentityKeyStore::get_identity].
+    fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>>;
+}
+
+/// Blocking counterpart of [traits::PreKeyStore].
+pub trait SyncPreKeyStore {
+    /// See [traits::PreKeyStore::get_pre_key].
+    fn get_pre_key(&self, prekey_id: PreKeyId) -> Result<PreKeyRecord>;
+
+    /// See [traits::PreKeyStore::save_pre_key].
+    fn save_pre_key(&mut self, prekey_id: PreKeyId, record: &PreKeyRecord) -> Result<()>;
+
+    /// See [traits::PreKeyStore::remove_pre_key].
+    fn remove_pre_key(&mut self, prekey_id: PreKeyId) -> Result<()>;
+}
+
+/// Blocking counterpart of [traits::SignedPreKeyStore].
+pub trait SyncSignedPreKeyStore {
+    /// See [traits::SignedPreKeyStore::get_signed_pre_key].
+    fn get_signed_pre_key(&self, signed_prekey_id: SignedPreKeyId) -> Result<SignedPreKeyRecord>;
+
+    /// See [traits::SignedPreKeyStore::save_signed_pre_key].
+    fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()>;
+
+    /// See [traits::SignedPreKeyStore::remove_signed_pre_key]. The default implementation fails.
+    fn remove_signed_pre_key(&mut self, signed_prekey_id: SignedPreKeyId) -> Result<()> {
+        let _ = signed_prekey_id;
+        Err(unsupported("remove_signed_pre_key"))
+    }
+}
+
+/// Blocking counterpart of [traits::KyberPreKeyStore].
+pub trait SyncKyberPreKeyStore {
+    /// See [traits::KyberPreKeyStore::get_kyber_pre_key].
+    fn get_kyber_pre_key(&self, kyber_prekey_id: KyberPreKeyId) -> Result<KyberPreKeyRecord>;
+
+    /// See [traits::KyberPreKeyStore::save_kyber_pre_key].
+    fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()>;
+
+    /// See [traits::KyberPreKeyStore::mark_kyber_pre_key_used].
+    fn mark_kyber_pre_key_used(&mut self, kyber_prekey_id: KyberPreKeyId) -> Result<()>;
+}
+
+/// Blocking counterpart of [traits::SessionStore].
+pub trait SyncSessionStore {
+    /// See [traits::SessionStore::load_session].
+    fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>>;
+
+    /// See [traits::SessionStore::store_session].
+    fn store_session(&mut self, address: &ProtocolAddress, record: &SessionRecord) -> Result<()>;
+
+    /// See [traits::SessionStore::all_session_addresses]. The default implementation fails.
+    fn all_session_addresses(&self) -> Result<Vec<ProtocolAddress>> {
+        Err(unsupported("all_session_addresses"))
+    }
+
+    /// See [traits::SessionStore::delete_session]. The default implementation fails.
+    fn delete_session(&mut self, address: &ProtocolAddress) -> Result<()> {
+        let _ = address;
+        Err(unsupported("delete_session"))
+    }
+
+    /// See [traits::SessionStore::archive_policy].
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        SessionArchivePolicy::default()
+    }
+}
+
+/// Blocking counterpart of [traits::SenderKeyStore].
+pub trait SyncSenderKeyStore {
+    /// See [traits::SenderKeyStore::store_sender_key].
+    fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+    ) -> Result<()>;
+
+    /// See [traits::SenderKeyStore::load_sender_key].
+    fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+    ) -> Result<Option<SenderKeyRecord>>;
+
+    /// See [traits::SenderKeyStore::delete_sender_key]. The default implementation fails.
+    fn delete_sender_key(&mut self, sender_key_name: &SenderKeyName) -> Result<()> {
+        let _ = sender_key_name;
+        Err(unsupported("delete_sender_key"))
+    }
+
+    /// See [traits::SenderKeyStore::prune_expired]. The default implementation fails.
+    fn prune_expired(&mut self, now: SystemTime) -> Result<Vec<SenderKeyName>> {
+        let _ = now;
+        Err(unsupported("prune_expired"))
+    }
+}
+
+/// Makes a blocking store usable wherever the protocol expects an async one.
+///
+/// Every operation completes as soon as it is polled, so the protocol functions never wait when
+/// given these stores, and can be driven by polling them once.
+#[derive(Clone, Debug, Default)]
+pub struct SyncStoreAdapter<S>(pub S);
+
+#[async_trait(?Send)]
+impl<S: SyncIdentityKeyStore> traits::IdentityKeyStore for SyncStoreAdapter<S> {
+    async fn get_identity_key_pair(&self, _ctx: Context) -> Result<IdentityKeyPair> {
+        self.0.get_identity_key_pair()
+    }
+
+    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32> {
+        self.0.get_local_registration_id()
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _ctx: Context,
+    ) -> Result<bool> {
+        self.0.save_identity(address, identity)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+        _ctx: Context,
+    ) -> Result<bool> {
+        self.0.is_trusted_identity(address, identity, direction)
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.0.get_identity(address)
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: SyncPreKeyStore> traits::PreKeyStore for SyncStoreAdapter<S> {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, _ctx: Context) -> Result<PreKeyRecord> {
+        self.0.get_pre_key(prekey_id)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.save_pre_key(prekey_id, record)
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, _ctx: Context) -> Result<()> {
+        self.0.remove_pre_key(prekey_id)
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: SyncSignedPreKeyStore> traits::SignedPreKeyStore for SyncStoreAdapter<S> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        self.0.get_signed_pre_key(signed_prekey_id)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.save_signed_pre_key(signed_prekey_id, record)
+    }
+
+    async fn remove_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.remove_signed_pre_key(signed_prekey_id)
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: SyncKyberPreKeyStore> traits::KyberPreKeyStore for SyncStoreAdapter<S> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<KyberPreKeyRecord> {
+        self.0.get_kyber_pre_key(kyber_prekey_id)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.save_kyber_pre_key(kyber_prekey_id, record)
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.mark_kyber_pre_key_used(kyber_prekey_id)
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: SyncSessionStore> traits::SessionStore for SyncStoreAdapter<S> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        self.0.load_session(address)
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.store_session(address, record)
+    }
+
+    async fn all_session_addresses(&self, _ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.0.all_session_addresses()
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, _ctx: Context) -> Result<()> {
+        self.0.delete_session(address)
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.0.archive_policy()
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: SyncSenderKeyStore> traits::SenderKeyStore for SyncStoreAdapter<S> {
+    async fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.store_sender_key(sender_key_name, record)
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        _ctx: Context,
+    ) -> Result<Option<SenderKeyRecord>> {
+        self.0.load_sender_key(sender_key_name)
+    }
+
+    async fn delete_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.0.delete_sender_key(sender_key_name)
+    }
+
+    async fn prune_expired(
+        &mut self,
+        now: SystemTime,
+        _ctx: Context,
+    ) -> Result<Vec<SenderKeyName>> {
+        self.0.prune_expired(now)
+    }
+}
+
+/// Makes an async store usable from blocking code, by waiting for each operation to finish on
+/// the calling thread.
+///
+/// The wrapped store's futures must be woken from some other thread (or be ready immediately, as
+/// with the in-memory stores); waiting for a store that needs the calling thread to run an event
+/// loop will never finish.
+#[derive(Clone, Debug, Default)]
+pub struct BlockingStoreAdapter<S>(pub S);
+
+impl<S: traits::IdentityKeyStore> SyncIdentityKeyStore for BlockingStoreAdapter<S> {
+    fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
+        block_on(self.0.get_identity_key_pair(None))
+    }
+
+    fn get_local_registration_id(&self) -> Result<u32> {
+        block_on(self.0.get_local_registration_id(None))
+    }
+
+    fn save_identity(&mut self, address: &ProtocolAddress, identity: &IdentityKey) -> Result<bool> {
+        block_on(self.0.save_identity(address, identity, None))
+    }
+
+    fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+    ) -> Result<bool> {
+        block_on(
+            self.0
+                .is_trusted_identity(address, identity, direction, None),
+        )
+    }
+
+    fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
+        block_on(self.0.get_identity(address, None))
+    }
+}
+
+impl<S: traits::PreKeyStore> SyncPreKeyStore for BlockingStoreAdapter<S> {
+    fn get_pre_key(&self, prekey_id: PreKeyId) -> Result<PreKeyRecord> {
+        block_on(self.0.get_pre_key(prekey_id, None))
+    }
+
+    fn save_pre_key(&mut self, prekey_id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        block_on(self.0.save_pre_key(prekey_id, record, None))
+    }
+
+    fn remove_pre_key(&mut self, prekey_id: PreKeyId) -> Result<()> {
+        block_on(self.0.remove_pre_key(prekey_id, None))
+    }
+}
+
+impl<S: traits::SignedPreKeyStore> SyncSignedPreKeyStore for BlockingStoreAdapter<S> {
+    fn get_signed_pre_key(&self, signed_prekey_id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
+        block_on(self.0.get_signed_pre_key(signed_prekey_id, None))
+    }
+
+    fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()> {
+        block_on(self.0.save_signed_pre_key(signed_prekey_id, record, None))
+    }
+
+    fn remove_signed_pre_key(&mut self, signed_prekey_id: SignedPreKeyId) -> Result<()> {
+        block_on(self.0.remove_signed_pre_key(signed_prekey_id, None))
+    }
+}
+
+impl<S: traits::KyberPreKeyStore> SyncKyberPreKeyStore for BlockingStoreAdapter<S> {
+    fn get_kyber_pre_key(&self, kyber_prekey_id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        block_on(self.0.get_kyber_pre_key(kyber_prekey_id, None))
+    }
+
+    fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        block_on(self.0.save_kyber_pre_key(kyber_prekey_id, record, None))
+    }
+
+    fn mark_kyber_pre_key_used(&mut self, kyber_prekey_id: KyberPreKeyId) -> Result<()> {
+        block_on(self.0.mark_kyber_pre_key_used(kyber_prekey_id, None))
+    }
+}
+
+impl<S: traits::SessionStore> SyncSessionStore for BlockingStoreAdapter<S> {
+    fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
+        block_on(self.0.load_session(address, None))
+    }
+
+    fn store_session(&mut self, address: &ProtocolAddress, record: &SessionRecord) -> Result<()> {
+        block_on(self.0.store_session(address, record, None))
+    }
+
+    fn all_session_addresses(&self) -> Result<Vec<ProtocolAddress>> {
+        block_on(self.0.all_session_addresses(None))
+    }
+
+    fn delete_session(&mut self, address: &ProtocolAddress) -> Result<()> {
+        block_on(self.0.delete_session(address, None))
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.0.archive_policy()
+    }
+}
+
+impl<S: traits::SenderKeyStore> SyncSenderKeyStore for BlockingStoreAdapter<S> {
+    fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+    ) -> Result<()> {
+        block_on(self.0.store_sender_key(sender_key_name, record, None))
+    }
+
+    fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+    ) -> Result<Option<SenderKeyRecord>> {
+        block_on(self.0.load_sender_key(sender_key_name, None))
+    }
+
+    fn delete_sender_key(&mut self, sender_key_name: &SenderKeyName) -> Result<()> {
+        block_on(self.0.delete_sender_key(sender_key_name, None))
+    }
+
+    fn prune_expired(&mut self, now: SystemTime) -> Result<Vec<SenderKeyName>> {
+        block_on(self.0.prune_expired(now, None))
+    }
+}
+
+/// Polls `future` to completion, parking the current thread whenever it isn't ready.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            // Spurious wakeups just mean polling again.
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "inmem-stores"))]
+mod tests {
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::{
+        message_decrypt, message_encrypt, process_prekey_bundle, GenericSignedPreKey,
+        InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemPreKeyStore, InMemSessionStore,
+        InMemSignedPreKeyStore, KeyPair, PreKeyBundle,
+    };
+
+    /// Wraps an async store in both adapters, so that every call goes through each of them.
+    fn round_trip<S>(store: S) -> SyncStoreAdapter<BlockingStoreAdapter<S>> {
+        SyncStoreAdapter(BlockingStoreAdapter(store))
+    }
+
+    #[test]
+    fn session_through_adapters() -> Result<()> {
+        let mut csprng = OsRng;
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_identity = IdentityKeyPair::generate(&mut csprng);
+        let mut bob_identity_store = round_trip(InMemIdentityKeyStore::new(bob_identity, 2));
+        let mut bob_session_store = round_trip(InMemSessionStore::new());
+        let mut bob_pre_key_store = round_trip(InMemPreKeyStore::new());
+        let mut bob_signed_pre_key_store = round_trip(InMemSignedPreKeyStore::new());
+        let mut bob_kyber_pre_key_store = round_trip(InMemKyberPreKeyStore::new());
+
+        let pre_key_pair = KeyPair::generate(&mut csprng);
+        let signed_pre_key =
+            SignedPreKeyRecord::generate(2.into(), bob_identity.private_key(), &mut csprng)?;
+        bob_pre_key_store
+            .0
+            .save_pre_key(1.into(), &PreKeyRecord::new(1.into(), &pre_key_pair))?;
+        bob_signed_pre_key_store
+            .0
+            .save_signed_pre_key(2.into(), &signed_pre_key)?;
+        let bundle = PreKeyBundle::new(
+            2,
+            1.into(),
+            Some((1.into(), pre_key_pair.public_key)),
+            2.into(),
+            signed_pre_key.public_key()?,
+            signed_pre_key.signature()?,
+            *bob_identity.identity_key(),
+        )?;
+
+        let mut alice_identity_store = round_trip(InMemIdentityKeyStore::new(
+            IdentityKeyPair::generate(&mut csprng),
+            1,
+        ));
+        let mut alice_session_store = round_trip(InMemSessionStore::new());
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_session_store,
+            &mut alice_identity_store,
+            &bundle,
+            &mut csprng,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")?;
+        let message = message_encrypt(
+            b"no executor",
+            &bob_address,
+            &mut alice_session_store,
+            &mut alice_identity_store,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")?;
+        let plaintext = message_decrypt(
+            &message,
+            &alice_address,
+            &mut bob_session_store,
+            &mut bob_identity_store,
+            &mut bob_pre_key_store,
+            &mut bob_signed_pre_key_store,
+            &mut bob_kyber_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")?;
+        assert_eq!(plaintext, b"no executor");
+
+        // The one-time pre-key was removed through both adapters.
+        assert!(bob_pre_key_store.0.get_pre_key(1.into()).is_err());
+        assert!(bob_session_store.0.load_session(&alice_address)?.is_some());
+        assert_eq!(
+            bob_identity_store.0.get_identity(&alice_address)?,
+            Some(
+                *alice_identity_store
+                    .0
+                    .get_identity_key_pair()?
+                    .identity_key()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn block_on_waits_for_other_threads() {
+        /// Ready once another thread has woken it.
+        struct WokenElsewhere(Option<thread::JoinHandle<()>>);
+
+        impl Future for WokenElsewhere {
+            type Output = ();
+
+            fn poll(
+                mut self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+            ) -> Poll<()> {
+                match self.0.take() {
+                    Some(handle) => {
+                        handle.join().expect("no panic");
+                        Poll::Ready(())
+                    }
+                    None => {
+                        let waker = cx.waker().clone();
+                        self.0 = Some(thread::spawn(move || waker.wake()));
+                        Poll::Pending
+                    }
+                }
+            }
+        }
+
+        block_on(WokenElsewhere(None));
+    }
+}
@@ -0,0 +1,337 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A [traits::SessionStore] wrapper that keeps recently used sessions deserialized.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+
+use crate::storage::{traits, Context};
+use crate::{ProtocolAddress, Result, SessionArchivePolicy, SessionRecord};
+
+/// Wraps another [traits::SessionStore], keeping the `capacity` most recently used sessions in
+/// memory so they don't have to be loaded and decoded again for every message.
+///
+/// Stores are written through: every change goes to the wrapped store before the call returns, so
+/// the cache never holds anything the wrapped store doesn't. If the wrapped store is changed some
+/// other way (through [inner_mut](Self::inner_mut), or by another process sharing the database),
+/// call [invalidate](Self::invalidate) or [invalidate_all](Self::invalidate_all) to drop the stale
+/// copies.
+pub struct CachedSessionStore<S> {
+    inner: S,
+    capacity: usize,
+    entries: RefCell<HashMap<ProtocolAddress, CacheEntry>>,
+    clock: Cell<u64>,
+}
+
+struct CacheEntry {
+    record: SessionRecord,
+    last_used: u64,
+}
+
+impl<S: traits::SessionStore> CachedSessionStore<S> {
+    /// Wrap `inner`, caching up to `capacity` sessions.
+    ///
+    /// A capacity of zero disables caching.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            entries: Default::default(),
+            clock: Cell::new(0),
+        }
+    }
+
+    /// Forget the cached session for `address`, if any, so the next load goes to the wrapped
+    /// store.
+    pub fn invalidate(&self, address: &ProtocolAddress) {
+        self.entries.borrow_mut().remove(address);
+    }
+
+    /// Forget every cached session.
+    pub fn invalidate_all(&self) {
+        self.entries.borrow_mut().clear();
+    }
+
+    /// The number of sessions currently cached.
+    pub fn cached_len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    /// Access the wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the wrapped store.
+    ///
+    /// Changes made through this reference are not reflected in the cache; invalidate any
+    /// sessions that are modified.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner store, discarding the cache.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn tick(&self) -> u64 {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        now
+    }
+
+    fn get(&self, address: &ProtocolAddress) -> Option<SessionRecord> {
+        let last_used = self.tick();
+        let mut entries = self.entries.borrow_mut();
+        let entry = entries.get_mut(address)?;
+        entry.last_used = last_used;
+        Some(entry.record.clone())
+    }
+
+    fn insert(&self, address: &ProtocolAddress, record: &SessionRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let last_used = self.tick();
+        let mut entries = self.entries.borrow_mut();
+        if entries.len() >= self.capacity && !entries.contains_key(address) {
+            // Eviction is linear, but only happens on a miss once the cache is full, which is
+            // still much cheaper than the decode the miss already paid for.
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(address, _)| address.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            address.clone(),
+            CacheEntry {
+                record: record.clone(),
+                last_used,
+            },
+        );
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::SessionStore> traits::SessionStore for CachedSessionStore<S> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        if let Some(record) = self.get(address) {
+            return Ok(Some(record));
+        }
+        let record = self.inner.load_session(address, ctx).await?;
+        if let Some(record) = &record {
+            self.insert(address, record);
+        }
+        Ok(record)
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        // If the write fails, the cached copy may no longer match what's stored.
+        self.invalidate(address);
+        self.inner.store_session(address, record, ctx).await?;
+        self.insert(address, record);
+        Ok(())
+    }
+
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        let cached: Vec<Option<SessionRecord>> =
+            addresses.iter().map(|address| self.get(address)).collect();
+        let misses: Vec<&ProtocolAddress> = addresses
+            .iter()
+            .zip(&cached)
+            .filter(|(_, record)| record.is_none())
+            .map(|(&address, _)| address)
+            .collect();
+
+        // Let the wrapped store batch whatever isn't cached.
+        let mut loaded = if misses.is_empty() {
+            Vec::new()
+        } else {
+            self.inner.load_existing_sessions(&misses, ctx).await?
+        }
+        .into_iter();
+        for (address, record) in misses.iter().zip(loaded.as_slice()) {
+            self.insert(address, record);
+        }
+
+        Ok(cached
+            .into_iter()
+            .map(|record| record.unwrap_or_else(|| loaded.next().expect("one per miss")))
+            .collect())
+    }
+
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.inner.all_session_addresses(ctx).await
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        self.invalidate(address);
+        self.inner.delete_session(address, ctx).await
+    }
+
+    async fn delete_all_sessions(&mut self, name: &str, ctx: Context) -> Result<()> {
+        self.entries
+            .borrow_mut()
+            .retain(|address, _| address.name() != name);
+        self.inner.delete_all_sessions(name, ctx).await
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.inner.archive_policy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::storage::traits::SessionStore;
+    use crate::InMemSessionStore;
+
+    /// Counts the loads that reach the wrapped store.
+    #[derive(Default)]
+    struct CountingStore {
+        inner: InMemSessionStore,
+        loads: Cell<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl traits::SessionStore for CountingStore {
+        async fn load_session(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<SessionRecord>> {
+            self.loads.set(self.loads.get() + 1);
+            self.inner.load_session(address, ctx).await
+        }
+
+        async fn store_session(
+            &mut self,
+            address: &ProtocolAddress,
+            record: &SessionRecord,
+            ctx: Context,
+        ) -> Result<()> {
+            self.inner.store_session(address, record, ctx).await
+        }
+
+        async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+            self.inner.delete_session(address, ctx).await
+        }
+    }
+
+    fn address(device_id: u32) -> ProtocolAddress {
+        ProtocolAddress::new("+14151111111".to_owned(), device_id.into())
+    }
+
+    #[test]
+    fn caches_most_recently_used() -> Result<()> {
+        async {
+            let mut store = CachedSessionStore::new(CountingStore::default(), 2);
+            for device_id in 1..=3 {
+                store
+                    .inner_mut()
+                    .store_session(&address(device_id), &SessionRecord::new_fresh(), None)
+                    .await?;
+            }
+
+            // Misses go to the wrapped store once, then stay cached.
+            assert!(store.load_session(&address(1), None).await?.is_some());
+            assert!(store.load_session(&address(1), None).await?.is_some());
+            assert!(store.load_session(&address(2), None).await?.is_some());
+            assert_eq!(store.inner().loads.get(), 2);
+
+            // Loading a third evicts the least recently used, which is now device 1.
+            store.load_session(&address(2), None).await?;
+            store.load_session(&address(3), None).await?;
+            assert_eq!(store.cached_len(), 2);
+            store.load_session(&address(2), None).await?;
+            assert_eq!(store.inner().loads.get(), 3);
+            store.load_session(&address(1), None).await?;
+            assert_eq!(store.inner().loads.get(), 4);
+
+            // Missing sessions aren't cached.
+            assert!(store.load_session(&address(4), None).await?.is_none());
+            assert!(store.load_session(&address(4), None).await?.is_none());
+            assert_eq!(store.inner().loads.get(), 6);
+
+            // Stores write through and leave the new record cached.
+            let loads = store.inner().loads.get();
+            store
+                .store_session(&address(5), &SessionRecord::new_fresh(), None)
+                .await?;
+            assert!(store
+                .inner()
+                .inner
+                .load_session(&address(5), None)
+                .await?
+                .is_some());
+            assert!(store.load_session(&address(5), None).await?.is_some());
+            assert_eq!(store.inner().loads.get(), loads);
+
+            store.invalidate(&address(5));
+            store.load_session(&address(5), None).await?;
+            assert_eq!(store.inner().loads.get(), loads + 1);
+
+            store.delete_session(&address(5), None).await?;
+            assert!(store.load_session(&address(5), None).await?.is_none());
+
+            store.invalidate_all();
+            assert_eq!(store.cached_len(), 0);
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn load_existing_sessions_mixes_cached_and_loaded() -> Result<()> {
+        async {
+            let mut store = CachedSessionStore::new(CountingStore::default(), 10);
+            for device_id in 1..=3 {
+                store
+                    .store_session(&address(device_id), &SessionRecord::new_fresh(), None)
+                    .await?;
+            }
+            store.invalidate(&address(2));
+
+            let sessions = store
+                .load_existing_sessions(&[&address(1), &address(2), &address(3)], None)
+                .await?;
+            assert_eq!(sessions.len(), 3);
+            assert_eq!(store.inner().loads.get(), 1);
+            assert_eq!(store.cached_len(), 3);
+
+            assert!(store
+                .load_existing_sessions(&[&address(1), &address(4)], None)
+                .await
+                .is_err());
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
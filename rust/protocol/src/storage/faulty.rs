@@ -0,0 +1,604 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Store wrappers that misbehave on request, for testing how clients handle store failures.
+//!
+//! Only available with the `chaos` feature.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+
+use crate::storage::{
+    traits, Context, InMemIdentityKeyStore, InMemKyberPreKeyStore, InMemPreKeyStore,
+    InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore, InMemSignedPreKeyStore,
+};
+use crate::{
+    IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord,
+    PrivateKeyOps, ProtocolAddress, Result, SenderKeyName, SenderKeyRecord, SessionArchivePolicy,
+    SessionRecord, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
+};
+
+/// The error wrapped in [SignalProtocolError::ApplicationCallbackError] when a
+/// [FaultySignalProtocolStore] has been told to fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InjectedFault {
+    /// The store method that failed.
+    pub operation: &'static str,
+    /// Which call to that method failed, starting at 1.
+    pub call: usize,
+}
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "injected failure on {} call #{}",
+            self.operation, self.call
+        )
+    }
+}
+
+impl std::error::Error for InjectedFault {}
+
+/// An async sleep provided by the caller, so the faulty stores don't depend on a particular
+/// runtime.
+pub type Sleep = Box<dyn Fn(Duration) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+#[derive(Default)]
+struct Faults {
+    store_session_calls: usize,
+    fail_store_session_on: Option<usize>,
+    delay: Option<(Duration, Sleep)>,
+    flipped_identities: HashMap<ProtocolAddress, IdentityKey>,
+}
+
+/// One of the stores in a [FaultySignalProtocolStore].
+///
+/// Every operation first awaits the configured delay (if any), then either fails or forwards to
+/// the wrapped store.
+pub struct FaultyStore<S> {
+    inner: S,
+    faults: Rc<RefCell<Faults>>,
+}
+
+impl<S> FaultyStore<S> {
+    /// Access the wrapped store, bypassing any injected faults.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the wrapped store, bypassing any injected faults.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    async fn delay(&self) {
+        // Don't hold the borrow across the await.
+        let sleep = {
+            let faults = self.faults.borrow();
+            faults
+                .delay
+                .as_ref()
+                .map(|(duration, sleep)| sleep(*duration))
+        };
+        if let Some(sleep) = sleep {
+            sleep.await;
+        }
+    }
+}
+
+/// An [InMemSignalProtocolStore] whose component stores can be told to fail, stall, or lie.
+///
+/// The fields mirror those of [InMemSignalProtocolStore], so the stores can be passed separately
+/// to functions like [message_encrypt](crate::message_encrypt). All of them share the fault
+/// configuration set on this object. With no faults configured, this behaves exactly like the
+/// wrapped store.
+#[allow(missing_docs)]
+pub struct FaultySignalProtocolStore {
+    pub session_store: FaultyStore<InMemSessionStore>,
+    pub pre_key_store: FaultyStore<InMemPreKeyStore>,
+    pub signed_pre_key_store: FaultyStore<InMemSignedPreKeyStore>,
+    pub kyber_pre_key_store: FaultyStore<InMemKyberPreKeyStore>,
+    pub identity_store: FaultyStore<InMemIdentityKeyStore>,
+    pub sender_key_store: FaultyStore<InMemSenderKeyStore>,
+    faults: Rc<RefCell<Faults>>,
+}
+
+impl FaultySignalProtocolStore {
+    /// Wrap the stores in `inner`, without any faults configured.
+    pub fn new(inner: InMemSignalProtocolStore) -> Self {
+        let faults = Rc::new(RefCell::new(Faults::default()));
+        fn wrap<S>(inner: S, faults: &Rc<RefCell<Faults>>) -> FaultyStore<S> {
+            FaultyStore {
+                inner,
+                faults: Rc::clone(faults),
+            }
+        }
+        Self {
+            session_store: wrap(inner.session_store, &faults),
+            pre_key_store: wrap(inner.pre_key_store, &faults),
+            signed_pre_key_store: wrap(inner.signed_pre_key_store, &faults),
+            kyber_pre_key_store: wrap(inner.kyber_pre_key_store, &faults),
+            identity_store: wrap(inner.identity_store, &faults),
+            sender_key_store: wrap(inner.sender_key_store, &faults),
+            faults,
+        }
+    }
+
+    /// Unwrap the inner stores.
+    pub fn into_inner(self) -> InMemSignalProtocolStore {
+        InMemSignalProtocolStore {
+            session_store: self.session_store.inner,
+            pre_key_store: self.pre_key_store.inner,
+            signed_pre_key_store: self.signed_pre_key_store.inner,
+            kyber_pre_key_store: self.kyber_pre_key_store.inner,
+            identity_store: self.identity_store.inner,
+            sender_key_store: self.sender_key_store.inner,
+        }
+    }
+
+    /// Make the `n`th call to `store_session` (counting from 1, including calls already made)
+    /// fail with an [InjectedFault] instead of saving the session.
+    ///
+    /// Passing `None` stops injecting failures.
+    pub fn fail_store_session_on(&mut self, n: Option<usize>) {
+        self.faults.borrow_mut().fail_store_session_on = n;
+    }
+
+    /// The number of times `store_session` has been called, including failed calls.
+    pub fn store_session_calls(&self) -> usize {
+        self.faults.borrow().store_session_calls
+    }
+
+    /// Await `sleep(delay)` at the start of every store operation.
+    ///
+    /// Passing `None` removes the delay.
+    pub fn set_delay(&mut self, delay: Option<(Duration, Sleep)>) {
+        self.faults.borrow_mut().delay = delay;
+    }
+
+    /// Report `identity` as the stored identity for `address`, as if the remote user had
+    /// reinstalled, without touching the wrapped identity store.
+    ///
+    /// `is_trusted_identity` is answered as if `identity` had been saved, so any other key for
+    /// `address` is untrusted.
+    pub fn flip_identity(&mut self, address: ProtocolAddress, identity: IdentityKey) {
+        self.faults
+            .borrow_mut()
+            .flipped_identities
+            .insert(address, identity);
+    }
+
+    /// Stop reporting a flipped identity for `address`.
+    pub fn unflip_identity(&mut self, address: &ProtocolAddress) {
+        self.faults.borrow_mut().flipped_identities.remove(address);
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::IdentityKeyStore> traits::IdentityKeyStore for FaultyStore<S> {
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        self.delay().await;
+        self.inner.get_identity_key_pair(ctx).await
+    }
+
+    async fn get_identity_private_key(&self, ctx: Context) -> Result<Box<dyn PrivateKeyOps>> {
+        self.delay().await;
+        self.inner.get_identity_private_key(ctx).await
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        self.delay().await;
+        self.inner.get_local_registration_id(ctx).await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.delay().await;
+        self.inner.save_identity(address, identity, ctx).await
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: traits::Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.delay().await;
+        let flipped = self
+            .faults
+            .borrow()
+            .flipped_identities
+            .get(address)
+            .copied();
+        match flipped {
+            Some(flipped) => Ok(flipped.ct_eq(identity)),
+            None => {
+                self.inner
+                    .is_trusted_identity(address, identity, direction, ctx)
+                    .await
+            }
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.delay().await;
+        let flipped = self
+            .faults
+            .borrow()
+            .flipped_identities
+            .get(address)
+            .copied();
+        match flipped {
+            Some(flipped) => Ok(Some(flipped)),
+            None => self.inner.get_identity(address, ctx).await,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::PreKeyStore> traits::PreKeyStore for FaultyStore<S> {
+    async fn get_pre_key(&self, id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        self.delay().await;
+        self.inner.get_pre_key(id, ctx).await
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        id: PreKeyId,
+        record: &PreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        self.inner.save_pre_key(id, record, ctx).await
+    }
+
+    async fn remove_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<()> {
+        self.delay().await;
+        self.inner.remove_pre_key(id, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::SignedPreKeyStore> traits::SignedPreKeyStore for FaultyStore<S> {
+    async fn get_signed_pre_key(
+        &self,
+        id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        self.delay().await;
+        self.inner.get_signed_pre_key(id, ctx).await
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        self.inner.save_signed_pre_key(id, record, ctx).await
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId, ctx: Context) -> Result<()> {
+        self.delay().await;
+        self.inner.remove_signed_pre_key(id, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::KyberPreKeyStore> traits::KyberPreKeyStore for FaultyStore<S> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<KyberPreKeyRecord> {
+        self.delay().await;
+        self.inner.get_kyber_pre_key(kyber_prekey_id, ctx).await
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        self.inner
+            .save_kyber_pre_key(kyber_prekey_id, record, ctx)
+            .await
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        self.inner
+            .mark_kyber_pre_key_used(kyber_prekey_id, ctx)
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::SessionStore> traits::SessionStore for FaultyStore<S> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        self.delay().await;
+        self.inner.load_session(address, ctx).await
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        let failed_call = {
+            let mut faults = self.faults.borrow_mut();
+            faults.store_session_calls += 1;
+            Some(faults.store_session_calls)
+                .filter(|&call| faults.fail_store_session_on == Some(call))
+        };
+        if let Some(call) = failed_call {
+            return Err(SignalProtocolError::ApplicationCallbackError(
+                "store_session",
+                Box::new(InjectedFault {
+                    operation: "store_session",
+                    call,
+                }),
+            ));
+        }
+        self.inner.store_session(address, record, ctx).await
+    }
+
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        self.delay().await;
+        self.inner.load_existing_sessions(addresses, ctx).await
+    }
+
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.delay().await;
+        self.inner.all_session_addresses(ctx).await
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        self.delay().await;
+        self.inner.delete_session(address, ctx).await
+    }
+
+    async fn delete_all_sessions(&mut self, name: &str, ctx: Context) -> Result<()> {
+        self.delay().await;
+        self.inner.delete_all_sessions(name, ctx).await
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.inner.archive_policy()
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: traits::SenderKeyStore> traits::SenderKeyStore for FaultyStore<S> {
+    async fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        self.inner
+            .store_sender_key(sender_key_name, record, ctx)
+            .await
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<Option<SenderKeyRecord>> {
+        self.delay().await;
+        self.inner.load_sender_key(sender_key_name, ctx).await
+    }
+
+    async fn delete_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<()> {
+        self.delay().await;
+        self.inner.delete_sender_key(sender_key_name, ctx).await
+    }
+
+    async fn prune_expired(&mut self, now: SystemTime, ctx: Context) -> Result<Vec<SenderKeyName>> {
+        self.delay().await;
+        self.inner.prune_expired(now, ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::traits::{IdentityKeyStore, SessionStore};
+    use crate::{message_encrypt, process_prekey_bundle, GenericSignedPreKey, PreKeyBundle};
+
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+
+    fn faulty_store_with_session(
+        remote_address: &ProtocolAddress,
+    ) -> Result<FaultySignalProtocolStore> {
+        let mut store = FaultySignalProtocolStore::new(InMemSignalProtocolStore::new(
+            IdentityKeyPair::generate(&mut OsRng),
+            1,
+        )?);
+
+        let remote_identity = IdentityKeyPair::generate(&mut OsRng);
+        let signed_pre_key =
+            SignedPreKeyRecord::generate(1.into(), remote_identity.private_key(), &mut OsRng)?;
+        let bundle = PreKeyBundle::new(
+            2,
+            remote_address.device_id(),
+            None,
+            1.into(),
+            signed_pre_key.public_key()?,
+            signed_pre_key.signature()?,
+            *remote_identity.identity_key(),
+        )?;
+        process_prekey_bundle(
+            remote_address,
+            &mut store.session_store,
+            &mut store.identity_store,
+            &bundle,
+            &mut OsRng,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")?;
+        Ok(store)
+    }
+
+    fn encrypt(
+        store: &mut FaultySignalProtocolStore,
+        remote_address: &ProtocolAddress,
+    ) -> Result<()> {
+        message_encrypt(
+            b"hello",
+            remote_address,
+            &mut store.session_store,
+            &mut store.identity_store,
+            None,
+        )
+        .now_or_never()
+        .expect("sync")
+        .map(|_| ())
+    }
+
+    #[test]
+    fn test_fail_nth_store_session() -> Result<()> {
+        let bob_address = ProtocolAddress::new("bob".to_owned(), 1.into());
+        let mut store = faulty_store_with_session(&bob_address)?;
+        assert_eq!(store.store_session_calls(), 1);
+
+        store.fail_store_session_on(Some(3));
+        encrypt(&mut store, &bob_address)?;
+        match encrypt(&mut store, &bob_address) {
+            Err(SignalProtocolError::ApplicationCallbackError("store_session", e)) => {
+                assert_eq!(
+                    e.to_string(),
+                    InjectedFault {
+                        operation: "store_session",
+                        call: 3,
+                    }
+                    .to_string()
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        encrypt(&mut store, &bob_address)?;
+        assert_eq!(store.store_session_calls(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flipped_identity() -> Result<()> {
+        let bob_address = ProtocolAddress::new("bob".to_owned(), 1.into());
+        let mut store = faulty_store_with_session(&bob_address)?;
+        let real_identity = store
+            .identity_store
+            .get_identity(&bob_address, None)
+            .now_or_never()
+            .expect("sync")?
+            .expect("saved during session setup");
+
+        let flipped = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        store.flip_identity(bob_address.clone(), flipped);
+        assert_eq!(
+            store
+                .identity_store
+                .get_identity(&bob_address, None)
+                .now_or_never()
+                .expect("sync")?,
+            Some(flipped)
+        );
+        assert!(matches!(
+            encrypt(&mut store, &bob_address),
+            Err(SignalProtocolError::UntrustedIdentity(address)) if address == bob_address
+        ));
+
+        store.unflip_identity(&bob_address);
+        encrypt(&mut store, &bob_address)?;
+        assert_eq!(
+            store
+                .into_inner()
+                .identity_store
+                .get_identity(&bob_address, None)
+                .now_or_never()
+                .expect("sync")?,
+            Some(real_identity)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delay() -> Result<()> {
+        let bob_address = ProtocolAddress::new("bob".to_owned(), 1.into());
+        let mut store = faulty_store_with_session(&bob_address)?;
+
+        let slept = Rc::new(RefCell::new(Vec::new()));
+        let slept_for_callback = Rc::clone(&slept);
+        store.set_delay(Some((
+            Duration::from_millis(5),
+            Box::new(move |duration| {
+                slept_for_callback.borrow_mut().push(duration);
+                futures_util::future::ready(()).boxed_local()
+            }),
+        )));
+
+        let session = store
+            .session_store
+            .load_session(&bob_address, None)
+            .now_or_never()
+            .expect("sync")?;
+        assert!(session.is_some());
+        assert_eq!(*slept.borrow(), vec![Duration::from_millis(5)]);
+
+        // A pending sleep blocks the operation.
+        store.set_delay(Some((
+            Duration::from_secs(1),
+            Box::new(|_| futures_util::future::pending().boxed_local()),
+        )));
+        assert!(store
+            .session_store
+            .load_session(&bob_address, None)
+            .now_or_never()
+            .is_none());
+        Ok(())
+    }
+}
@@ -9,22 +9,53 @@
 
 use crate::storage::{traits, Context};
 use crate::{
-    IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord,
-    ProtocolAddress, Result, SenderKeyRecord, SessionRecord, SignalProtocolError, SignedPreKeyId,
-    SignedPreKeyRecord,
+    consts, IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord,
+    ProtocolAddress, Result, SenderKeyName, SenderKeyRecord, SessionArchivePolicy, SessionRecord,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
 };
 
 use async_trait::async_trait;
-use std::borrow::Cow;
-use std::collections::HashMap;
-use uuid::Uuid;
+use rand::rngs::OsRng;
+use rand::Rng;
+use siphasher::sip::SipHasher13;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::time::{Duration, SystemTime};
+
+/// Hashes the keys of the in-memory stores with SipHash-1-3 under a secret 128-bit key.
+///
+/// Store keys are often derived from names chosen by remote users, so an attacker who could predict
+/// the hash function could fill a store with colliding entries and make every lookup slow. Each
+/// store created with `new()` picks its own random key; the `with_hash_key` constructors accept a
+/// caller-provided one instead (for instance, to share one key across the stores of an account).
+#[derive(Clone)]
+struct KeyedHashBuilder {
+    key: [u8; 16],
+}
+
+impl KeyedHashBuilder {
+    fn new(key: [u8; 16]) -> Self {
+        Self { key }
+    }
+}
+
+impl BuildHasher for KeyedHashBuilder {
+    type Hasher = SipHasher13;
+
+    fn build_hasher(&self) -> SipHasher13 {
+        SipHasher13::new_with_key(&self.key)
+    }
+}
+
+type KeyedHashMap<K, V> = HashMap<K, V, KeyedHashBuilder>;
 
 /// Reference implementation of [traits::IdentityKeyStore].
 #[derive(Clone)]
 pub struct InMemIdentityKeyStore {
     key_pair: IdentityKeyPair,
     registration_id: u32,
-    known_keys: HashMap<ProtocolAddress, IdentityKey>,
+    known_keys: KeyedHashMap<ProtocolAddress, IdentityKey>,
 }
 
 impl InMemIdentityKeyStore {
@@ -33,10 +64,19 @@ impl InMemIdentityKeyStore {
     /// `key_pair` corresponds to [traits::IdentityKeyStore::get_identity_key_pair], and
     /// `registration_id` corresponds to [traits::IdentityKeyStore::get_local_registration_id].
     pub fn new(key_pair: IdentityKeyPair, registration_id: u32) -> Self {
+        Self::with_hash_key(key_pair, registration_id, OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but hashing addresses with `hash_key`.
+    pub fn with_hash_key(
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+        hash_key: [u8; 16],
+    ) -> Self {
         Self {
             key_pair,
             registration_id,
-            known_keys: HashMap::new(),
+            known_keys: HashMap::with_hasher(KeyedHashBuilder::new(hash_key)),
         }
     }
 
@@ -67,7 +107,7 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
                 self.known_keys.insert(address.clone(), *identity);
                 Ok(false) // new key
             }
-            Some(k) if k == identity => {
+            Some(k) if k.ct_eq(identity) => {
                 Ok(false) // same key
             }
             Some(_k) => {
@@ -88,7 +128,7 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
             None => {
                 Ok(true) // first use
             }
-            Some(k) => Ok(k == identity),
+            Some(k) => Ok(k.ct_eq(identity)),
         }
     }
 
@@ -107,14 +147,19 @@ impl traits::IdentityKeyStore for InMemIdentityKeyStore {
 /// Reference implementation of [traits::PreKeyStore].
 #[derive(Clone)]
 pub struct InMemPreKeyStore {
-    pre_keys: HashMap<PreKeyId, PreKeyRecord>,
+    pre_keys: KeyedHashMap<PreKeyId, PreKeyRecord>,
 }
 
 impl InMemPreKeyStore {
     /// Create an empty pre-key store.
     pub fn new() -> Self {
+        Self::with_hash_key(OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but hashing pre-key IDs with `hash_key`.
+    pub fn with_hash_key(hash_key: [u8; 16]) -> Self {
         Self {
-            pre_keys: HashMap::new(),
+            pre_keys: HashMap::with_hasher(KeyedHashBuilder::new(hash_key)),
         }
     }
 
@@ -161,14 +206,19 @@ impl traits::PreKeyStore for InMemPreKeyStore {
 /// Reference implementation of [traits::SignedPreKeyStore].
 #[derive(Clone)]
 pub struct InMemSignedPreKeyStore {
-    signed_pre_keys: HashMap<SignedPreKeyId, SignedPreKeyRecord>,
+    signed_pre_keys: KeyedHashMap<SignedPreKeyId, SignedPreKeyRecord>,
 }
 
 impl InMemSignedPreKeyStore {
     /// Create an empty signed pre-key store.
     pub fn new() -> Self {
+        Self::with_hash_key(OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but hashing signed pre-key IDs with `hash_key`.
+    pub fn with_hash_key(hash_key: [u8; 16]) -> Self {
         Self {
-            signed_pre_keys: HashMap::new(),
+            signed_pre_keys: HashMap::with_hasher(KeyedHashBuilder::new(hash_key)),
         }
     }
 
@@ -208,19 +258,29 @@ impl traits::SignedPreKeyStore for InMemSignedPreKeyStore {
         self.signed_pre_keys.insert(id, record.to_owned());
         Ok(())
     }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId, _ctx: Context) -> Result<()> {
+        self.signed_pre_keys.remove(&id);
+        Ok(())
+    }
 }
 
 /// Reference implementation of [traits::KyberPreKeyStore].
 #[derive(Clone)]
 pub struct InMemKyberPreKeyStore {
-    kyber_pre_keys: HashMap<KyberPreKeyId, KyberPreKeyRecord>,
+    kyber_pre_keys: KeyedHashMap<KyberPreKeyId, KyberPreKeyRecord>,
 }
 
 impl InMemKyberPreKeyStore {
     /// Create an empty kyber pre-key store.
     pub fn new() -> Self {
+        Self::with_hash_key(OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but hashing Kyber pre-key IDs with `hash_key`.
+    pub fn with_hash_key(hash_key: [u8; 16]) -> Self {
         Self {
-            kyber_pre_keys: HashMap::new(),
+            kyber_pre_keys: HashMap::with_hasher(KeyedHashBuilder::new(hash_key)),
         }
     }
 
@@ -273,17 +333,29 @@ impl traits::KyberPreKeyStore for InMemKyberPreKeyStore {
 /// Reference implementation of [traits::SessionStore].
 #[derive(Clone)]
 pub struct InMemSessionStore {
-    sessions: HashMap<ProtocolAddress, SessionRecord>,
+    sessions: KeyedHashMap<ProtocolAddress, SessionRecord>,
+    archive_policy: SessionArchivePolicy,
 }
 
 impl InMemSessionStore {
     /// Create an empty session store.
     pub fn new() -> Self {
+        Self::with_hash_key(OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but hashing addresses with `hash_key`.
+    pub fn with_hash_key(hash_key: [u8; 16]) -> Self {
         Self {
-            sessions: HashMap::new(),
+            sessions: HashMap::with_hasher(KeyedHashBuilder::new(hash_key)),
+            archive_policy: SessionArchivePolicy::default(),
         }
     }
 
+    /// Change the [archive_policy](traits::SessionStore::archive_policy) reported by this store.
+    pub fn set_archive_policy(&mut self, policy: SessionArchivePolicy) {
+        self.archive_policy = policy;
+    }
+
     /// Bulk version of [`SessionStore::load_session`].
     ///
     /// Useful for [crate::sealed_sender_multi_recipient_encrypt].
@@ -332,23 +404,64 @@ impl traits::SessionStore for InMemSessionStore {
         self.sessions.insert(address.clone(), record.clone());
         Ok(())
     }
+
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        _ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        Ok(InMemSessionStore::load_existing_sessions(self, addresses)?
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    async fn all_session_addresses(&self, _ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        Ok(self.sessions.keys().cloned().collect())
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, _ctx: Context) -> Result<()> {
+        self.sessions.remove(address);
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&mut self, name: &str, _ctx: Context) -> Result<()> {
+        self.sessions.retain(|address, _| address.name() != name);
+        Ok(())
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.archive_policy
+    }
 }
 
 /// Reference implementation of [traits::SenderKeyStore].
 #[derive(Clone)]
 pub struct InMemSenderKeyStore {
-    // We use Cow keys in order to store owned values but compare to referenced ones.
-    // See https://users.rust-lang.org/t/hashmap-with-tuple-keys/12711/6.
-    keys: HashMap<(Cow<'static, ProtocolAddress>, Uuid), SenderKeyRecord>,
+    // Each record is kept alongside the time it was last stored, for prune_expired.
+    keys: KeyedHashMap<SenderKeyName, (SenderKeyRecord, SystemTime)>,
+    max_age: Duration,
 }
 
 impl InMemSenderKeyStore {
     /// Create an empty sender key store.
     pub fn new() -> Self {
+        Self::with_hash_key(OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but hashing sender key names with `hash_key`.
+    pub fn with_hash_key(hash_key: [u8; 16]) -> Self {
         Self {
-            keys: HashMap::new(),
+            keys: HashMap::with_hasher(KeyedHashBuilder::new(hash_key)),
+            max_age: consts::MAX_SENDER_KEY_AGE,
         }
     }
+
+    /// Change how long a record may go without being stored before
+    /// [prune_expired](traits::SenderKeyStore::prune_expired) removes it.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
 }
 
 impl Default for InMemSenderKeyStore {
@@ -361,28 +474,99 @@ impl Default for InMemSenderKeyStore {
 impl traits::SenderKeyStore for InMemSenderKeyStore {
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         record: &SenderKeyRecord,
         _ctx: Context,
     ) -> Result<()> {
-        self.keys.insert(
-            (Cow::Owned(sender.clone()), distribution_id),
-            record.clone(),
-        );
+        self.keys
+            .insert(sender_key_name.clone(), (record.clone(), SystemTime::now()));
         Ok(())
     }
 
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         _ctx: Context,
     ) -> Result<Option<SenderKeyRecord>> {
         Ok(self
             .keys
-            .get(&(Cow::Borrowed(sender), distribution_id))
-            .cloned())
+            .get(sender_key_name)
+            .map(|(record, _)| record.clone()))
+    }
+
+    async fn delete_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        _ctx: Context,
+    ) -> Result<()> {
+        self.keys.remove(sender_key_name);
+        Ok(())
+    }
+
+    async fn prune_expired(
+        &mut self,
+        now: SystemTime,
+        _ctx: Context,
+    ) -> Result<Vec<SenderKeyName>> {
+        let max_age = self.max_age;
+        let expired: Vec<SenderKeyName> = self
+            .keys
+            .iter()
+            .filter(|(_, (_, stored_at))| {
+                now.duration_since(*stored_at).unwrap_or_default() >= max_age
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.keys.remove(name);
+        }
+        Ok(expired)
+    }
+}
+
+/// Reference implementation of [traits::ReplayCache], remembering a fixed number of the most
+/// recently inserted messages.
+#[derive(Clone)]
+pub struct InMemReplayCache {
+    keys: HashSet<traits::ReplayKey>,
+    // Insertion order, so the oldest entry can be forgotten first.
+    order: VecDeque<traits::ReplayKey>,
+    capacity: usize,
+}
+
+impl InMemReplayCache {
+    /// Create an empty cache that remembers up to `capacity` messages.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+}
+
+impl Default for InMemReplayCache {
+    fn default() -> Self {
+        Self::new(consts::REPLAY_CACHE_CAPACITY)
+    }
+}
+
+#[async_trait(?Send)]
+impl traits::ReplayCache for InMemReplayCache {
+    async fn contains(&self, key: &traits::ReplayKey, _ctx: Context) -> Result<bool> {
+        Ok(self.keys.contains(key))
+    }
+
+    async fn insert(&mut self, key: &traits::ReplayKey, _ctx: Context) -> Result<()> {
+        if self.capacity == 0 || !self.keys.insert(*key) {
+            return Ok(());
+        }
+        self.order.push_back(*key);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().expect("over capacity");
+            self.keys.remove(&oldest);
+        }
+        Ok(())
     }
 }
 
@@ -402,13 +586,26 @@ impl InMemSignalProtocolStore {
     /// Create an object with the minimal implementation of [traits::ProtocolStore], representing
     /// the given identity `key_pair` along with the separate randomly chosen `registration_id`.
     pub fn new(key_pair: IdentityKeyPair, registration_id: u32) -> Result<Self> {
+        Self::with_hash_key(key_pair, registration_id, OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but with every store hashing its keys with `hash_key`.
+    pub fn with_hash_key(
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+        hash_key: [u8; 16],
+    ) -> Result<Self> {
         Ok(Self {
-            session_store: InMemSessionStore::new(),
-            pre_key_store: InMemPreKeyStore::new(),
-            signed_pre_key_store: InMemSignedPreKeyStore::new(),
-            kyber_pre_key_store: InMemKyberPreKeyStore::new(),
-            identity_store: InMemIdentityKeyStore::new(key_pair, registration_id),
-            sender_key_store: InMemSenderKeyStore::new(),
+            session_store: InMemSessionStore::with_hash_key(hash_key),
+            pre_key_store: InMemPreKeyStore::with_hash_key(hash_key),
+            signed_pre_key_store: InMemSignedPreKeyStore::with_hash_key(hash_key),
+            kyber_pre_key_store: InMemKyberPreKeyStore::with_hash_key(hash_key),
+            identity_store: InMemIdentityKeyStore::with_hash_key(
+                key_pair,
+                registration_id,
+                hash_key,
+            ),
+            sender_key_store: InMemSenderKeyStore::with_hash_key(hash_key),
         })
     }
 
@@ -426,8 +623,29 @@ impl InMemSignalProtocolStore {
     pub fn all_kyber_pre_key_ids(&self) -> impl Iterator<Item = &KyberPreKeyId> {
         self.kyber_pre_key_store.all_kyber_pre_key_ids()
     }
+
+    /// Capture the current contents of every store, to go back to later with
+    /// [restore](Self::restore).
+    ///
+    /// Later changes to this store do not affect the snapshot, so a test can return to the same
+    /// point any number of times, for instance to deliver the same messages in different orders.
+    pub fn snapshot(&self) -> StoreSnapshot {
+        StoreSnapshot(self.clone())
+    }
+
+    /// Replace the contents of every store with those captured by `snapshot`.
+    ///
+    /// Settings such as the session archive policy and sender key maximum age are restored too.
+    pub fn restore(&mut self, snapshot: &StoreSnapshot) {
+        *self = snapshot.0.clone();
+    }
 }
 
+/// The contents of an [InMemSignalProtocolStore] at some point in time, as captured by
+/// [InMemSignalProtocolStore::snapshot].
+#[derive(Clone)]
+pub struct StoreSnapshot(InMemSignalProtocolStore);
+
 #[async_trait(?Send)]
 impl traits::IdentityKeyStore for InMemSignalProtocolStore {
     async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
@@ -510,6 +728,12 @@ impl traits::SignedPreKeyStore for InMemSignalProtocolStore {
             .save_signed_pre_key(id, record, ctx)
             .await
     }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId, ctx: Context) -> Result<()> {
+        self.signed_pre_key_store
+            .remove_signed_pre_key(id, ctx)
+            .await
+    }
 }
 
 #[async_trait(?Send)]
@@ -564,32 +788,273 @@ impl traits::SessionStore for InMemSignalProtocolStore {
     ) -> Result<()> {
         self.session_store.store_session(address, record, ctx).await
     }
+
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        traits::SessionStore::load_existing_sessions(&self.session_store, addresses, ctx).await
+    }
+
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.session_store.all_session_addresses(ctx).await
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        self.session_store.delete_session(address, ctx).await
+    }
+
+    async fn delete_all_sessions(&mut self, name: &str, ctx: Context) -> Result<()> {
+        self.session_store.delete_all_sessions(name, ctx).await
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.session_store.archive_policy()
+    }
 }
 
 #[async_trait(?Send)]
 impl traits::SenderKeyStore for InMemSignalProtocolStore {
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         record: &SenderKeyRecord,
         ctx: Context,
     ) -> Result<()> {
         self.sender_key_store
-            .store_sender_key(sender, distribution_id, record, ctx)
+            .store_sender_key(sender_key_name, record, ctx)
             .await
     }
 
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         ctx: Context,
     ) -> Result<Option<SenderKeyRecord>> {
         self.sender_key_store
-            .load_sender_key(sender, distribution_id, ctx)
+            .load_sender_key(sender_key_name, ctx)
             .await
     }
+
+    async fn delete_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<()> {
+        self.sender_key_store
+            .delete_sender_key(sender_key_name, ctx)
+            .await
+    }
+
+    async fn prune_expired(&mut self, now: SystemTime, ctx: Context) -> Result<Vec<SenderKeyName>> {
+        self.sender_key_store.prune_expired(now, ctx).await
+    }
+}
+
+/// Several local accounts' [InMemSignalProtocolStore]s, for processes that act as more than one
+/// identity at once, such as an ACI and a PNI, or the users of a multi-tenant bot.
+///
+/// Each account's store is entirely separate, so sessions, identities, pre-keys, and sender keys
+/// saved for one account are never visible to another.
+#[derive(Clone)]
+pub struct InMemMultiAccountStore {
+    accounts: BTreeMap<String, InMemSignalProtocolStore>,
+    hash_key: [u8; 16],
+}
+
+impl InMemMultiAccountStore {
+    /// Create an object with no accounts.
+    pub fn new() -> Self {
+        Self::with_hash_key(OsRng.gen())
+    }
+
+    /// Like [new](Self::new), but with every account's stores hashing their keys with `hash_key`.
+    pub fn with_hash_key(hash_key: [u8; 16]) -> Self {
+        Self {
+            accounts: BTreeMap::new(),
+            hash_key,
+        }
+    }
+
+    /// Add an account named `account`, with the given identity `key_pair` and `registration_id`,
+    /// and return its store.
+    ///
+    /// Fails if there is already an account with that name.
+    pub fn add_account(
+        &mut self,
+        account: impl Into<String>,
+        key_pair: IdentityKeyPair,
+        registration_id: u32,
+    ) -> Result<&mut InMemSignalProtocolStore> {
+        match self.accounts.entry(account.into()) {
+            Entry::Occupied(entry) => Err(SignalProtocolError::InvalidArgument(format!(
+                "account {} already exists",
+                entry.key()
+            ))),
+            Entry::Vacant(entry) => Ok(entry.insert(InMemSignalProtocolStore::with_hash_key(
+                key_pair,
+                registration_id,
+                self.hash_key,
+            )?)),
+        }
+    }
+
+    /// The store for `account`, if it has been added.
+    pub fn account(&self, account: &str) -> Option<&InMemSignalProtocolStore> {
+        self.accounts.get(account)
+    }
+
+    /// The store for `account`, if it has been added, for passing to protocol functions.
+    pub fn account_mut(&mut self, account: &str) -> Option<&mut InMemSignalProtocolStore> {
+        self.accounts.get_mut(account)
+    }
+
+    /// Remove `account`, returning its store.
+    pub fn remove_account(&mut self, account: &str) -> Option<InMemSignalProtocolStore> {
+        self.accounts.remove(account)
+    }
+
+    /// The names of all accounts, in sorted order.
+    pub fn account_names(&self) -> impl Iterator<Item = &str> {
+        self.accounts.keys().map(String::as_str)
+    }
 }
 
-impl traits::ProtocolStore for InMemSignalProtocolStore {}
+impl Default for InMemMultiAccountStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use std::hash::{Hash, Hasher};
+
+    fn hash(builder: &KeyedHashBuilder, address: &ProtocolAddress) -> u64 {
+        let mut hasher = builder.build_hasher();
+        address.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_keyed_hashing() {
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let key = [7; 16];
+        assert_eq!(
+            hash(&KeyedHashBuilder::new(key), &address),
+            hash(&KeyedHashBuilder::new(key), &address)
+        );
+        assert_ne!(
+            hash(&KeyedHashBuilder::new(key), &address),
+            hash(&KeyedHashBuilder::new([8; 16]), &address)
+        );
+        assert_ne!(
+            hash(&KeyedHashBuilder::new(OsRng.gen()), &address),
+            hash(&KeyedHashBuilder::new(OsRng.gen()), &address)
+        );
+    }
+
+    #[test]
+    fn test_replay_cache_eviction() -> Result<()> {
+        use traits::{ReplayCache, ReplayKey};
+
+        let mut cache = InMemReplayCache::new(2);
+        let keys: Vec<ReplayKey> = (0..3).map(|i| ReplayKey::from_bytes([i; 32])).collect();
+
+        async {
+            cache.insert(&keys[0], None).await?;
+            cache.insert(&keys[1], None).await?;
+            // Inserting again doesn't refresh an entry.
+            cache.insert(&keys[0], None).await?;
+            assert!(cache.contains(&keys[0], None).await?);
+
+            cache.insert(&keys[2], None).await?;
+            assert!(!cache.contains(&keys[0], None).await?);
+            assert!(cache.contains(&keys[1], None).await?);
+            assert!(cache.contains(&keys[2], None).await?);
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_sender_key_pruning() -> Result<()> {
+        use traits::SenderKeyStore;
+
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+        let mut store = InMemSenderKeyStore::new();
+        store.set_max_age(10 * DAY);
+        let alice = SenderKeyName::new(
+            ProtocolAddress::new("alice".to_owned(), 1.into()),
+            uuid::Uuid::from_u128(1),
+        );
+        let bob = SenderKeyName::new(
+            ProtocolAddress::new("bob".to_owned(), 1.into()),
+            uuid::Uuid::from_u128(1),
+        );
+
+        async {
+            let record = SenderKeyRecord::new_empty();
+            store.store_sender_key(&alice, &record, None).await?;
+            store.store_sender_key(&bob, &record, None).await?;
+
+            store.delete_sender_key(&bob, None).await?;
+            assert!(store.load_sender_key(&bob, None).await?.is_none());
+            assert!(store.load_sender_key(&alice, None).await?.is_some());
+
+            let now = SystemTime::now();
+            assert!(store.prune_expired(now, None).await?.is_empty());
+            assert_eq!(
+                store.prune_expired(now + 11 * DAY, None).await?,
+                vec![alice.clone()]
+            );
+            assert!(store.load_sender_key(&alice, None).await?.is_none());
+
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_multi_account_isolation() -> Result<()> {
+        use rand::rngs::OsRng;
+        use traits::{IdentityKeyStore, SessionStore};
+
+        let mut stores = InMemMultiAccountStore::new();
+        let aci_key_pair = IdentityKeyPair::generate(&mut OsRng);
+        stores.add_account("aci", aci_key_pair, 1)?;
+        stores.add_account("pni", IdentityKeyPair::generate(&mut OsRng), 2)?;
+        assert!(stores
+            .add_account("aci", IdentityKeyPair::generate(&mut OsRng), 3)
+            .is_err());
+        assert_eq!(stores.account_names().collect::<Vec<_>>(), ["aci", "pni"]);
+
+        let bob = ProtocolAddress::new("bob".to_owned(), 1.into());
+        async {
+            let aci = stores.account_mut("aci").expect("added");
+            aci.store_session(&bob, &SessionRecord::new_fresh(), None)
+                .await?;
+            assert_eq!(
+                aci.get_identity_key_pair(None).await?.public_key(),
+                aci_key_pair.public_key()
+            );
+
+            let pni = stores.account("pni").expect("added");
+            assert_eq!(pni.get_local_registration_id(None).await?, 2);
+            assert!(pni.load_session(&bob, None).await?.is_none());
+            Ok::<_, SignalProtocolError>(())
+        }
+        .now_or_never()
+        .expect("sync")?;
+
+        assert!(stores.remove_account("aci").is_some());
+        assert!(stores.account("aci").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! An append-only record of changes to protocol state, for replicating it to another process.
+//!
+//! Wrapping stores in [JournalingStore] reports each change to a [StoreJournal] as a
+//! [StoreMutation]. A replica can apply the same mutations to its own stores with
+//! [StoreMutation::apply], instead of copying the whole database after every message.
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use prost::Message;
+
+use crate::proto::storage::{journal_entry_structure, JournalEntryStructure};
+use crate::storage::{traits, Context};
+use crate::{
+    IdentityKey, IdentityKeyPair, PreKeyId, PreKeyRecord, PrivateKeyOps, ProtocolAddress, Result,
+    SessionArchivePolicy, SessionRecord, SignalProtocolError,
+};
+
+/// A change to protocol state made through a [JournalingStore].
+#[derive(Clone, Debug)]
+pub enum StoreMutation {
+    /// A session was created or updated; `record` is the output of [SessionRecord::serialize].
+    SessionStored {
+        /// The remote device the session is with.
+        address: ProtocolAddress,
+        /// The new contents of the session.
+        record: Vec<u8>,
+    },
+    /// A session was deleted.
+    SessionDeleted {
+        /// The remote device the session was with.
+        address: ProtocolAddress,
+    },
+    /// A remote identity was saved for the first time or replaced with a different key.
+    IdentitySaved {
+        /// The remote device the identity belongs to.
+        address: ProtocolAddress,
+        /// The newly trusted identity.
+        identity: IdentityKey,
+    },
+    /// A one-time pre-key was used up and removed.
+    PreKeyConsumed {
+        /// The removed pre-key.
+        id: PreKeyId,
+    },
+}
+
+impl StoreMutation {
+    /// Make the same change to a replica's stores.
+    pub async fn apply(
+        &self,
+        session_store: &mut dyn traits::SessionStore,
+        identity_store: &mut dyn traits::IdentityKeyStore,
+        pre_key_store: &mut dyn traits::PreKeyStore,
+        ctx: Context,
+    ) -> Result<()> {
+        match self {
+            Self::SessionStored { address, record } => {
+                session_store
+                    .store_session(address, &SessionRecord::deserialize(record)?, ctx)
+                    .await
+            }
+            Self::SessionDeleted { address } => session_store.delete_session(address, ctx).await,
+            Self::IdentitySaved { address, identity } => identity_store
+                .save_identity(address, identity, ctx)
+                .await
+                .map(|_| ()),
+            Self::PreKeyConsumed { id } => pre_key_store.remove_pre_key(*id, ctx).await,
+        }
+    }
+}
+
+/// A [StoreMutation] along with its position in the journal.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// Increases by one for each entry, starting from 0, so that a replica can detect gaps.
+    pub sequence: u64,
+    /// The change that was made.
+    pub mutation: StoreMutation,
+}
+
+impl JournalEntry {
+    /// Encode this entry for transmission or storage.
+    pub fn serialize(&self) -> Vec<u8> {
+        use journal_entry_structure::*;
+        let mutation = match &self.mutation {
+            StoreMutation::SessionStored { address, record } => {
+                Mutation::SessionStored(SessionStored {
+                    name: address.name().to_owned(),
+                    device_id: address.device_id().into(),
+                    record: record.clone(),
+                })
+            }
+            StoreMutation::SessionDeleted { address } => Mutation::SessionDeleted(SessionDeleted {
+                name: address.name().to_owned(),
+                device_id: address.device_id().into(),
+            }),
+            StoreMutation::IdentitySaved { address, identity } => {
+                Mutation::IdentitySaved(IdentitySaved {
+                    name: address.name().to_owned(),
+                    device_id: address.device_id().into(),
+                    identity_key: identity.serialize().into_vec(),
+                })
+            }
+            StoreMutation::PreKeyConsumed { id } => Mutation::PreKeyConsumed(PreKeyConsumed {
+                pre_key_id: (*id).into(),
+            }),
+        };
+        JournalEntryStructure {
+            sequence: self.sequence,
+            mutation: Some(mutation),
+        }
+        .encode_to_vec()
+    }
+
+    /// Decode the output of [serialize](Self::serialize).
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        use journal_entry_structure::*;
+        let entry = JournalEntryStructure::decode(bytes)
+            .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
+        let mutation = match entry
+            .mutation
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+        {
+            Mutation::SessionStored(stored) => StoreMutation::SessionStored {
+                address: ProtocolAddress::new(stored.name, stored.device_id.into()),
+                record: stored.record,
+            },
+            Mutation::SessionDeleted(deleted) => StoreMutation::SessionDeleted {
+                address: ProtocolAddress::new(deleted.name, deleted.device_id.into()),
+            },
+            Mutation::IdentitySaved(saved) => StoreMutation::IdentitySaved {
+                address: ProtocolAddress::new(saved.name, saved.device_id.into()),
+                identity: IdentityKey::try_from(saved.identity_key.as_slice())?,
+            },
+            Mutation::PreKeyConsumed(consumed) => StoreMutation::PreKeyConsumed {
+                id: consumed.pre_key_id.into(),
+            },
+        };
+        Ok(Self {
+            sequence: entry.sequence,
+            mutation,
+        })
+    }
+}
+
+/// Receives the changes made through [JournalingStore]s, in the order they happened.
+///
+/// Several stores usually share one journal, so implementations are typically cheap handles
+/// (for instance, around a channel or a database connection) that can be cloned for each store.
+#[async_trait(?Send)]
+pub trait StoreJournal {
+    /// Record `mutation`, which has already been applied to the local store.
+    ///
+    /// If this fails, the error is returned from the store operation that made the change.
+    async fn append(&mut self, mutation: StoreMutation, ctx: Context) -> Result<()>;
+}
+
+/// Reference implementation of [StoreJournal], keeping entries in memory.
+///
+/// Clones share the same entries.
+#[derive(Clone, Default)]
+pub struct InMemStoreJournal {
+    state: Rc<RefCell<InMemJournalState>>,
+}
+
+#[derive(Default)]
+struct InMemJournalState {
+    entries: Vec<JournalEntry>,
+    next_sequence: u64,
+}
+
+impl InMemStoreJournal {
+    /// Create an empty journal.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove and return the entries recorded so far.
+    ///
+    /// Sequence numbers keep counting up from where they left off.
+    pub fn take_entries(&self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.state.borrow_mut().entries)
+    }
+}
+
+#[async_trait(?Send)]
+impl StoreJournal for InMemStoreJournal {
+    async fn append(&mut self, mutation: StoreMutation, _ctx: Context) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.entries.push(JournalEntry { sequence, mutation });
+        Ok(())
+    }
+}
+
+/// Wraps one of the stores, reporting each change made through it to a [StoreJournal].
+///
+/// Session stores report [SessionStored](StoreMutation::SessionStored) and
+/// [SessionDeleted](StoreMutation::SessionDeleted), identity stores report
+/// [IdentitySaved](StoreMutation::IdentitySaved) when the saved key differs from the previous one,
+/// and pre-key stores report [PreKeyConsumed](StoreMutation::PreKeyConsumed). Changes are
+/// journaled after the wrapped store has accepted them. Other changes, such as saving newly
+/// generated pre-keys, are not journaled.
+pub struct JournalingStore<S, J> {
+    inner: S,
+    journal: J,
+}
+
+impl<S, J: StoreJournal> JournalingStore<S, J> {
+    /// Wrap `inner`, reporting changes to `journal`.
+    pub fn new(inner: S, journal: J) -> Self {
+        Self { inner, journal }
+    }
+
+    /// Access the wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the wrapped store.
+    ///
+    /// Changes made through this reference are not journaled.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner store, discarding the journal.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait(?Send)]
+impl<S, J> traits::SessionStore for JournalingStore<S, J>
+where
+    S: traits::SessionStore,
+    J: StoreJournal,
+{
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        self.inner.load_session(address, ctx).await
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.inner.store_session(address, record, ctx).await?;
+        self.journal
+            .append(
+                StoreMutation::SessionStored {
+                    address: address.clone(),
+                    record: record.serialize()?,
+                },
+                ctx,
+            )
+            .await
+    }
+
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        self.inner.load_existing_sessions(addresses, ctx).await
+    }
+
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.inner.all_session_addresses(ctx).await
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        self.inner.delete_session(address, ctx).await?;
+        self.journal
+            .append(
+                StoreMutation::SessionDeleted {
+                    address: address.clone(),
+                },
+                ctx,
+            )
+            .await
+    }
+
+    // delete_all_sessions uses the default implementation, so that each deletion is journaled.
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.inner.archive_policy()
+    }
+}
+
+#[async_trait(?Send)]
+impl<S, J> traits::IdentityKeyStore for JournalingStore<S, J>
+where
+    S: traits::IdentityKeyStore,
+    J: StoreJournal,
+{
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        self.inner.get_identity_key_pair(ctx).await
+    }
+
+    async fn get_identity_private_key(&self, ctx: Context) -> Result<Box<dyn PrivateKeyOps>> {
+        self.inner.get_identity_private_key(ctx).await
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        self.inner.get_local_registration_id(ctx).await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        let previous = self.inner.get_identity(address, ctx).await?;
+        let replaced = self.inner.save_identity(address, identity, ctx).await?;
+        if !matches!(previous, Some(previous) if previous.ct_eq(identity)) {
+            self.journal
+                .append(
+                    StoreMutation::IdentitySaved {
+                        address: address.clone(),
+                        identity: *identity,
+                    },
+                    ctx,
+                )
+                .await?;
+        }
+        Ok(replaced)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: traits::Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.inner
+            .is_trusted_identity(address, identity, direction, ctx)
+            .await
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.inner.get_identity(address, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S, J> traits::PreKeyStore for JournalingStore<S, J>
+where
+    S: traits::PreKeyStore,
+    J: StoreJournal,
+{
+    async fn get_pre_key(&self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        self.inner.get_pre_key(prekey_id, ctx).await
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.inner.save_pre_key(prekey_id, record, ctx).await
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()> {
+        self.inner.remove_pre_key(prekey_id, ctx).await?;
+        self.journal
+            .append(StoreMutation::PreKeyConsumed { id: prekey_id }, ctx)
+            .await
+    }
+}
@@ -0,0 +1,641 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Every store trait implemented on top of a plain key-value database.
+//!
+//! A backend such as RocksDB, Redis, or DynamoDB only needs to implement the four methods of
+//! [KvBackend]; [KvProtocolStore] takes care of laying out keys and serializing records, and
+//! implements all of the store traits over it.
+//!
+//! Keys are binary: a namespace such as `session/`, then the entry's identifier. Addresses are
+//! written as a big-endian `u32` name length, the name, and a big-endian `u32` device ID, so that
+//! the entries for one name are exactly the keys starting with its length and bytes. Values are
+//! the records' usual serialized forms, except that sender keys are preceded by the time they were
+//! stored, as big-endian milliseconds since the Unix epoch, for
+//! [prune_expired](traits::SenderKeyStore::prune_expired).
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::storage::{traits, Context};
+use crate::{
+    consts, GenericSignedPreKey, IdentityKey, IdentityKeyPair, KyberPreKeyId, KyberPreKeyRecord,
+    PreKeyId, PreKeyRecord, ProtocolAddress, Result, SenderKeyName, SenderKeyRecord, SessionRecord,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord, Timestamp,
+};
+
+/// The operations [KvProtocolStore] needs from a key-value database.
+#[async_trait(?Send)]
+pub trait KvBackend {
+    /// The value stored under `key`, if any.
+    async fn get(&self, key: &[u8], ctx: Context) -> Result<Option<Vec<u8>>>;
+
+    /// Store `value` under `key`, replacing any previous value.
+    async fn put(&mut self, key: &[u8], value: &[u8], ctx: Context) -> Result<()>;
+
+    /// Remove the value stored under `key`, doing nothing if there isn't one.
+    async fn delete(&mut self, key: &[u8], ctx: Context) -> Result<()>;
+
+    /// Every entry whose key starts with `prefix`, in any order.
+    async fn scan(&self, prefix: &[u8], ctx: Context) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Reference implementation of [KvBackend], keeping entries in memory.
+///
+/// Clones share the same entries, like several connections to one database.
+#[derive(Clone, Debug, Default)]
+pub struct InMemKvBackend(Rc<RefCell<BTreeMap<Vec<u8>, Vec<u8>>>>);
+
+impl InMemKvBackend {
+    /// Create an empty backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many entries are stored.
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    /// True if nothing is stored.
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+#[async_trait(?Send)]
+impl KvBackend for InMemKvBackend {
+    async fn get(&self, key: &[u8], _ctx: Context) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.borrow().get(key).cloned())
+    }
+
+    async fn put(&mut self, key: &[u8], value: &[u8], _ctx: Context) -> Result<()> {
+        self.0.borrow_mut().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: &[u8], _ctx: Context) -> Result<()> {
+        self.0.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &[u8], _ctx: Context) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .0
+            .borrow()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+const LOCAL_IDENTITY_KEY_PAIR: &[u8] = b"local/identity_key_pair";
+const LOCAL_REGISTRATION_ID: &[u8] = b"local/registration_id";
+const IDENTITY_PREFIX: &[u8] = b"identity/";
+const PRE_KEY_PREFIX: &[u8] = b"pre_key/";
+const SIGNED_PRE_KEY_PREFIX: &[u8] = b"signed_pre_key/";
+const KYBER_PRE_KEY_PREFIX: &[u8] = b"kyber_pre_key/";
+const SESSION_PREFIX: &[u8] = b"session/";
+const SENDER_KEY_PREFIX: &[u8] = b"sender_key/";
+
+const ACCOUNT_PREFIX: &[u8] = b"account/";
+
+fn name_key(prefix: &[u8], name: &str) -> Vec<u8> {
+    let name_len = u32::try_from(name.len()).expect("names are shorter than 4GB");
+    [prefix, &name_len.to_be_bytes(), name.as_bytes()].concat()
+}
+
+fn split_u32(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let (value, rest) = bytes.split_at(4);
+    Some((u32::from_be_bytes(value.try_into().ok()?), rest))
+}
+
+/// Parses an address written by [KvProtocolStore::address_key], returning whatever follows it.
+fn split_address(bytes: &[u8]) -> Option<(ProtocolAddress, &[u8])> {
+    let (name_len, rest) = split_u32(bytes)?;
+    let name_len = usize::try_from(name_len).ok()?;
+    if rest.len() < name_len {
+        return None;
+    }
+    let (name, rest) = rest.split_at(name_len);
+    let name = std::str::from_utf8(name).ok()?.to_owned();
+    let (device_id, rest) = split_u32(rest)?;
+    Some((ProtocolAddress::new(name, device_id.into()), rest))
+}
+
+fn malformed(operation: &'static str, key: &[u8]) -> SignalProtocolError {
+    SignalProtocolError::InvalidState(operation, format!("malformed key {}", hex::encode(key)))
+}
+
+/// Implements every store trait on top of a [KvBackend].
+///
+/// The store keeps no state of its own apart from its settings, so any number of instances can
+/// be opened over the same database. Each instance belongs to one account: either the default
+/// one, or a named one opened with [for_account](Self::for_account), so that several local
+/// identities can share a database without seeing each other's entries.
+#[derive(Clone, Debug)]
+pub struct KvProtocolStore<B> {
+    backend: B,
+    namespace: Vec<u8>,
+    sender_key_max_age: Duration,
+}
+
+impl<B: KvBackend> KvProtocolStore<B> {
+    /// Use `backend` for storage. It may already hold entries from an earlier run.
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            namespace: Vec::new(),
+            sender_key_max_age: consts::MAX_SENDER_KEY_AGE,
+        }
+    }
+
+    /// Like [new](Self::new), but keeping the entries of the account named `account` apart from
+    /// those of every other account in `backend`.
+    ///
+    /// The default account's entries are laid out as described in the [module
+    /// documentation](self); a named account's keys are additionally preceded by `account/`, the
+    /// name's length as a big-endian `u32`, and the name.
+    pub fn for_account(backend: B, account: &str) -> Self {
+        Self {
+            namespace: name_key(ACCOUNT_PREFIX, account),
+            ..Self::new(backend)
+        }
+    }
+
+    /// Record this account's identity key pair and registration ID.
+    ///
+    /// This has to be done once, when the account is registered, before the store is used as an
+    /// [IdentityKeyStore](traits::IdentityKeyStore).
+    pub async fn set_local_identity(
+        &mut self,
+        key_pair: &IdentityKeyPair,
+        registration_id: u32,
+        ctx: Context,
+    ) -> Result<()> {
+        self.backend
+            .put(
+                &self.key(&[LOCAL_IDENTITY_KEY_PAIR]),
+                &key_pair.serialize(),
+                ctx,
+            )
+            .await?;
+        self.backend
+            .put(
+                &self.key(&[LOCAL_REGISTRATION_ID]),
+                &registration_id.to_be_bytes(),
+                ctx,
+            )
+            .await
+    }
+
+    /// Change how long a sender key may go without being stored before
+    /// [prune_expired](traits::SenderKeyStore::prune_expired) removes it.
+    pub fn set_sender_key_max_age(&mut self, max_age: Duration) {
+        self.sender_key_max_age = max_age;
+    }
+
+    /// The backend the store was created with.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Give back the backend the store was created with.
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+
+    fn key(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let mut key = self.namespace.clone();
+        for part in parts {
+            key.extend_from_slice(part);
+        }
+        key
+    }
+
+    fn id_key(&self, prefix: &[u8], id: u32) -> Vec<u8> {
+        self.key(&[prefix, &id.to_be_bytes()])
+    }
+
+    fn name_key(&self, prefix: &[u8], name: &str) -> Vec<u8> {
+        self.key(&[&name_key(prefix, name)])
+    }
+
+    fn address_key(&self, prefix: &[u8], address: &ProtocolAddress) -> Vec<u8> {
+        let mut key = self.name_key(prefix, address.name());
+        key.extend_from_slice(&u32::from(address.device_id()).to_be_bytes());
+        key
+    }
+
+    fn sender_key_key(&self, name: &SenderKeyName) -> Vec<u8> {
+        let mut key = self.address_key(SENDER_KEY_PREFIX, name.sender());
+        key.extend_from_slice(name.distribution_id().as_bytes());
+        key
+    }
+
+    async fn get_local(&self, key: &[u8], ctx: Context) -> Result<Vec<u8>> {
+        self.backend
+            .get(&self.key(&[key]), ctx)
+            .await?
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "KvProtocolStore",
+                    "local identity has not been set".to_string(),
+                )
+            })
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: KvBackend> traits::IdentityKeyStore for KvProtocolStore<B> {
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        IdentityKeyPair::try_from(&self.get_local(LOCAL_IDENTITY_KEY_PAIR, ctx).await?[..])
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        let value = self.get_local(LOCAL_REGISTRATION_ID, ctx).await?;
+        match split_u32(&value) {
+            Some((registration_id, [])) => Ok(registration_id),
+            _ => Err(malformed(
+                "get_local_registration_id",
+                &self.key(&[LOCAL_REGISTRATION_ID]),
+            )),
+        }
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        let existing = self.get_identity(address, ctx).await?;
+        if let Some(existing) = existing {
+            if existing.ct_eq(identity) {
+                return Ok(false);
+            }
+        }
+        self.backend
+            .put(
+                &self.address_key(IDENTITY_PREFIX, address),
+                &identity.serialize(),
+                ctx,
+            )
+            .await?;
+        Ok(existing.is_some())
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _direction: traits::Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        match self.get_identity(address, ctx).await? {
+            None => Ok(true), // first use
+            Some(existing) => Ok(existing.ct_eq(identity)),
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.backend
+            .get(&self.address_key(IDENTITY_PREFIX, address), ctx)
+            .await?
+            .map(|value| IdentityKey::decode(&value))
+            .transpose()
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: KvBackend> traits::PreKeyStore for KvProtocolStore<B> {
+    async fn get_pre_key(&self, id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        let value = self
+            .backend
+            .get(&self.id_key(PRE_KEY_PREFIX, id.into()), ctx)
+            .await?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        PreKeyRecord::deserialize(&value)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        id: PreKeyId,
+        record: &PreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.backend
+            .put(
+                &self.id_key(PRE_KEY_PREFIX, id.into()),
+                &record.serialize()?,
+                ctx,
+            )
+            .await
+    }
+
+    async fn remove_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<()> {
+        self.backend
+            .delete(&self.id_key(PRE_KEY_PREFIX, id.into()), ctx)
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: KvBackend> traits::SignedPreKeyStore for KvProtocolStore<B> {
+    async fn get_signed_pre_key(
+        &self,
+        id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        let value = self
+            .backend
+            .get(&self.id_key(SIGNED_PRE_KEY_PREFIX, id.into()), ctx)
+            .await?
+            .ok_or(SignalProtocolError::InvalidSignedPreKeyId)?;
+        SignedPreKeyRecord::deserialize(&value)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.backend
+            .put(
+                &self.id_key(SIGNED_PRE_KEY_PREFIX, id.into()),
+                &record.serialize()?,
+                ctx,
+            )
+            .await
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: SignedPreKeyId, ctx: Context) -> Result<()> {
+        self.backend
+            .delete(&self.id_key(SIGNED_PRE_KEY_PREFIX, id.into()), ctx)
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: KvBackend> traits::KyberPreKeyStore for KvProtocolStore<B> {
+    async fn get_kyber_pre_key(
+        &self,
+        id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<KyberPreKeyRecord> {
+        let value = self
+            .backend
+            .get(&self.id_key(KYBER_PRE_KEY_PREFIX, id.into()), ctx)
+            .await?
+            .ok_or(SignalProtocolError::InvalidKyberPreKeyId)?;
+        KyberPreKeyRecord::deserialize(&value)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.backend
+            .put(
+                &self.id_key(KYBER_PRE_KEY_PREFIX, id.into()),
+                &record.serialize()?,
+                ctx,
+            )
+            .await
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, _id: KyberPreKeyId, _ctx: Context) -> Result<()> {
+        // Like the in-memory store, keep every Kyber pre-key, since the last-resort key is used
+        // more than once.
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: KvBackend> traits::SessionStore for KvProtocolStore<B> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        self.backend
+            .get(&self.address_key(SESSION_PREFIX, address), ctx)
+            .await?
+            .map(|value| SessionRecord::deserialize(&value))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.backend
+            .put(
+                &self.address_key(SESSION_PREFIX, address),
+                &record.serialize()?,
+                ctx,
+            )
+            .await
+    }
+
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        let prefix = self.key(&[SESSION_PREFIX]);
+        self.backend
+            .scan(&prefix, ctx)
+            .await?
+            .into_iter()
+            .map(|(key, _)| match split_address(&key[prefix.len()..]) {
+                Some((address, [])) => Ok(address),
+                _ => Err(malformed("all_session_addresses", &key)),
+            })
+            .collect()
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        self.backend
+            .delete(&self.address_key(SESSION_PREFIX, address), ctx)
+            .await
+    }
+
+    async fn delete_all_sessions(&mut self, name: &str, ctx: Context) -> Result<()> {
+        for (key, _) in self
+            .backend
+            .scan(&self.name_key(SESSION_PREFIX, name), ctx)
+            .await?
+        {
+            self.backend.delete(&key, ctx).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: KvBackend> traits::SenderKeyStore for KvProtocolStore<B> {
+    async fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        let stored_at = Timestamp::from(SystemTime::now()).epoch_millis();
+        let value = [&stored_at.to_be_bytes()[..], &record.serialize()?].concat();
+        self.backend
+            .put(&self.sender_key_key(sender_key_name), &value, ctx)
+            .await
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<Option<SenderKeyRecord>> {
+        let key = self.sender_key_key(sender_key_name);
+        match self.backend.get(&key, ctx).await? {
+            None => Ok(None),
+            Some(value) if value.len() >= 8 => SenderKeyRecord::deserialize(&value[8..]).map(Some),
+            Some(_) => Err(malformed("load_sender_key", &key)),
+        }
+    }
+
+    async fn delete_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<()> {
+        self.backend
+            .delete(&self.sender_key_key(sender_key_name), ctx)
+            .await
+    }
+
+    async fn prune_expired(&mut self, now: SystemTime, ctx: Context) -> Result<Vec<SenderKeyName>> {
+        let now = Timestamp::from(now);
+        let mut expired = Vec::new();
+        let prefix = self.key(&[SENDER_KEY_PREFIX]);
+        for (key, value) in self.backend.scan(&prefix, ctx).await? {
+            let name = split_address(&key[prefix.len()..])
+                .and_then(|(sender, rest)| {
+                    Some(SenderKeyName::new(sender, Uuid::from_slice(rest).ok()?))
+                })
+                .ok_or_else(|| malformed("prune_expired", &key))?;
+            let stored_at = value
+                .get(..8)
+                .and_then(|stored_at| stored_at.try_into().ok())
+                .map(|stored_at| Timestamp::from_epoch_millis(u64::from_be_bytes(stored_at)))
+                .ok_or_else(|| malformed("prune_expired", &key))?;
+            if now.duration_since(stored_at).unwrap_or_default() >= self.sender_key_max_age {
+                self.backend.delete(&key, ctx).await?;
+                expired.push(name);
+            }
+        }
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::check_store_conformance;
+
+    use futures_util::FutureExt;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn conforms_to_reference_stores() -> Result<()> {
+        async {
+            let backend = InMemKvBackend::new();
+            let mut store = KvProtocolStore::new(backend.clone());
+            store
+                .set_local_identity(&IdentityKeyPair::generate(&mut OsRng), 1234, None)
+                .await?;
+            let open = || KvProtocolStore::new(backend.clone());
+            let (mut sessions, mut pre_keys, mut signed_pre_keys) = (open(), open(), open());
+            let (mut kyber_pre_keys, mut sender_keys) = (open(), open());
+            check_store_conformance(
+                &mut sessions,
+                &mut store,
+                &mut pre_keys,
+                &mut signed_pre_keys,
+                &mut kyber_pre_keys,
+                &mut sender_keys,
+                &mut OsRng,
+                None,
+            )
+            .await
+            .into_result()
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn keys_for_one_name_do_not_match_longer_names() -> Result<()> {
+        async {
+            use traits::SessionStore;
+
+            let mut store = KvProtocolStore::new(InMemKvBackend::new());
+            let alice = ProtocolAddress::new("alice".to_owned(), 1.into());
+            let alice2 = ProtocolAddress::new("alice2".to_owned(), 1.into());
+            let record = SessionRecord::new_fresh();
+            store.store_session(&alice, &record, None).await?;
+            store.store_session(&alice2, &record, None).await?;
+
+            store.delete_all_sessions("alice", None).await?;
+            assert_eq!(store.all_session_addresses(None).await?, vec![alice2]);
+            assert_eq!(store.backend().len(), 1);
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn accounts_do_not_share_entries() -> Result<()> {
+        async {
+            use traits::{IdentityKeyStore, SessionStore};
+
+            let backend = InMemKvBackend::new();
+            let mut default = KvProtocolStore::new(backend.clone());
+            let mut alice = KvProtocolStore::for_account(backend.clone(), "alice");
+            let alice2 = KvProtocolStore::for_account(backend.clone(), "alice2");
+            alice
+                .set_local_identity(&IdentityKeyPair::generate(&mut OsRng), 1, None)
+                .await?;
+
+            let bob = ProtocolAddress::new("bob".to_owned(), 1.into());
+            alice
+                .store_session(&bob, &SessionRecord::new_fresh(), None)
+                .await?;
+            assert_eq!(alice.all_session_addresses(None).await?, vec![bob.clone()]);
+            assert!(alice2.load_session(&bob, None).await?.is_none());
+            assert!(default.all_session_addresses(None).await?.is_empty());
+            assert!(alice2.get_local_registration_id(None).await.is_err());
+
+            default.delete_all_sessions("bob", None).await?;
+            assert!(alice.load_session(&bob, None).await?.is_some());
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
@@ -0,0 +1,79 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A [traits::PreKeyStore] wrapper that reports when one-time pre-keys are used up.
+
+use crate::storage::{traits, Context};
+use crate::{PreKeyId, PreKeyRecord, Result};
+
+use async_trait::async_trait;
+
+/// Wraps another [traits::PreKeyStore], invoking a callback whenever a one-time pre-key is removed.
+///
+/// The library removes a one-time pre-key when it has been used to set up a session from an
+/// incoming PreKey message, and [reconcile_with_server][crate::reconcile_with_server] removes one
+/// the server handed out that went unused for the whole grace period. Either way the server no
+/// longer has the key, so the callback is a convenient place to decide whether to generate and
+/// upload more pre-keys (see [generate_prekey_batch][crate::generate_prekey_batch]).
+///
+/// The callback runs after the inner store has successfully removed the key.
+pub struct NotifyingPreKeyStore<S, F> {
+    inner: S,
+    on_consumed: F,
+}
+
+impl<S, F> NotifyingPreKeyStore<S, F>
+where
+    S: traits::PreKeyStore,
+    F: FnMut(PreKeyId),
+{
+    /// Wrap `inner`, calling `on_consumed` with the ID of each pre-key removed from it.
+    pub fn new(inner: S, on_consumed: F) -> Self {
+        Self { inner, on_consumed }
+    }
+
+    /// Access the wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the wrapped store, e.g. to save freshly generated pre-keys.
+    ///
+    /// Removing keys through this reference will not invoke the callback.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner store, discarding the callback.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait(?Send)]
+impl<S, F> traits::PreKeyStore for NotifyingPreKeyStore<S, F>
+where
+    S: traits::PreKeyStore,
+    F: FnMut(PreKeyId),
+{
+    async fn get_pre_key(&self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        self.inner.get_pre_key(prekey_id, ctx).await
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.inner.save_pre_key(prekey_id, record, ctx).await
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()> {
+        self.inner.remove_pre_key(prekey_id, ctx).await?;
+        (self.on_consumed)(prekey_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::cell::{Ref, RefCell, RefMut};
+
+use async_trait::async_trait;
+
+use crate::error::{Result, SignalProtocolError};
+use crate::state::{
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, SessionArchivePolicy, SessionRecord,
+    SignedPreKeyId, SignedPreKeyRecord,
+};
+use crate::storage::traits::{self, Context, Direction};
+use crate::{IdentityKey, IdentityKeyPair, IdentityRotation, PrivateKeyOps, ProtocolAddress};
+
+/// Lets a single [ProtocolStore](traits::ProtocolStore) be passed as each of the separate stores a
+/// protocol function takes.
+///
+/// Every handle borrows the store only for the duration of one store call. The protocol functions
+/// never run two store calls at once, so the borrows never overlap; if they somehow did, the call
+/// fails with [SignalProtocolError::InvalidState] rather than panicking.
+pub(crate) struct SharedStore<'a, S: ?Sized>(RefCell<&'a mut S>);
+
+impl<'a, S: traits::ProtocolStore + ?Sized> SharedStore<'a, S> {
+    pub(crate) fn new(store: &'a mut S) -> Self {
+        Self(RefCell::new(store))
+    }
+
+    pub(crate) fn handle(&self) -> SharedStoreHandle<'_, 'a, S> {
+        SharedStoreHandle(&self.0)
+    }
+}
+
+pub(crate) struct SharedStoreHandle<'h, 'a, S: ?Sized>(&'h RefCell<&'a mut S>);
+
+impl<'h, 'a, S: ?Sized> SharedStoreHandle<'h, 'a, S> {
+    fn get(&self) -> Result<Ref<'h, &'a mut S>> {
+        self.0.try_borrow().map_err(|_| overlapping_access())
+    }
+
+    fn get_mut(&mut self) -> Result<RefMut<'h, &'a mut S>> {
+        self.0.try_borrow_mut().map_err(|_| overlapping_access())
+    }
+}
+
+fn overlapping_access() -> SignalProtocolError {
+    SignalProtocolError::InvalidState(
+        "shared protocol store",
+        "store used by two operations at once".to_string(),
+    )
+}
+
+// Holding the borrow across the inner store's await is the point: it is what keeps the handles
+// from overlapping.
+#[allow(clippy::await_holding_refcell_ref)]
+#[async_trait(?Send)]
+impl<S: traits::ProtocolStore + ?Sized> traits::IdentityKeyStore for SharedStoreHandle<'_, '_, S> {
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        self.get()?.get_identity_key_pair(ctx).await
+    }
+
+    async fn get_identity_private_key(&self, ctx: Context) -> Result<Box<dyn PrivateKeyOps>> {
+        self.get()?.get_identity_private_key(ctx).await
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        self.get()?.get_local_registration_id(ctx).await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.get_mut()?.save_identity(address, identity, ctx).await
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.get()?
+            .is_trusted_identity(address, identity, direction, ctx)
+            .await
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>> {
+        self.get()?.get_identity(address, ctx).await
+    }
+
+    async fn apply_identity_rotation(
+        &mut self,
+        address: &ProtocolAddress,
+        rotation: &IdentityRotation,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.get_mut()?
+            .apply_identity_rotation(address, rotation, ctx)
+            .await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+#[async_trait(?Send)]
+impl<S: traits::ProtocolStore + ?Sized> traits::PreKeyStore for SharedStoreHandle<'_, '_, S> {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        self.get()?.get_pre_key(prekey_id, ctx).await
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.get_mut()?.save_pre_key(prekey_id, record, ctx).await
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<()> {
+        self.get_mut()?.remove_pre_key(prekey_id, ctx).await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+#[async_trait(?Send)]
+impl<S: traits::ProtocolStore + ?Sized> traits::SignedPreKeyStore for SharedStoreHandle<'_, '_, S> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<SignedPreKeyRecord> {
+        self.get()?.get_signed_pre_key(signed_prekey_id, ctx).await
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.get_mut()?
+            .save_signed_pre_key(signed_prekey_id, record, ctx)
+            .await
+    }
+
+    async fn remove_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<()> {
+        self.get_mut()?
+            .remove_signed_pre_key(signed_prekey_id, ctx)
+            .await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+#[async_trait(?Send)]
+impl<S: traits::ProtocolStore + ?Sized> traits::KyberPreKeyStore for SharedStoreHandle<'_, '_, S> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<KyberPreKeyRecord> {
+        self.get()?.get_kyber_pre_key(kyber_prekey_id, ctx).await
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.get_mut()?
+            .save_kyber_pre_key(kyber_prekey_id, record, ctx)
+            .await
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        ctx: Context,
+    ) -> Result<()> {
+        self.get_mut()?
+            .mark_kyber_pre_key_used(kyber_prekey_id, ctx)
+            .await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+#[async_trait(?Send)]
+impl<S: traits::ProtocolStore + ?Sized> traits::SessionStore for SharedStoreHandle<'_, '_, S> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        self.get()?.load_session(address, ctx).await
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.get_mut()?.store_session(address, record, ctx).await
+    }
+
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        self.get()?.load_existing_sessions(addresses, ctx).await
+    }
+
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        self.get()?.all_session_addresses(ctx).await
+    }
+
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        self.get_mut()?.delete_session(address, ctx).await
+    }
+
+    async fn delete_all_sessions(&mut self, name: &str, ctx: Context) -> Result<()> {
+        self.get_mut()?.delete_all_sessions(name, ctx).await
+    }
+
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        self.0
+            .try_borrow()
+            .map(|store| store.archive_policy())
+            .unwrap_or_default()
+    }
+}
@@ -6,16 +6,18 @@
 //! Traits defining several stores used throughout the Signal Protocol.
 
 use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
 use uuid::Uuid;
 
-use crate::address::ProtocolAddress;
-use crate::error::Result;
+use crate::address::{ProtocolAddress, SenderKeyName};
+use crate::error::{Result, SignalProtocolError};
 use crate::sender_keys::SenderKeyRecord;
 use crate::state::{
-    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId,
-    SignedPreKeyRecord,
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, SessionArchivePolicy, SessionRecord,
+    SignedPreKeyId, SignedPreKeyRecord,
 };
-use crate::{IdentityKey, IdentityKeyPair};
+use crate::{IdentityKey, IdentityKeyPair, IdentityRotation, PrivateKeyOps, PublicKey};
 
 /// Handle to FFI-provided context object.
 ///
@@ -46,6 +48,16 @@ pub trait IdentityKeyStore {
     /// Return the single specific identity the store is assumed to represent, with private key.
     async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair>;
 
+    /// Return the private half of this store's identity, for use in session setup.
+    ///
+    /// The default implementation wraps [Self::get_identity_key_pair]. A store that keeps its
+    /// identity key in hardware can override this to return a handle to that key instead, so that
+    /// [process_prekey](crate::process_prekey) and
+    /// [process_prekey_bundle](crate::process_prekey_bundle) never load the key into memory.
+    async fn get_identity_private_key(&self, ctx: Context) -> Result<Box<dyn PrivateKeyOps>> {
+        Ok(Box::new(self.get_identity_key_pair(ctx).await?))
+    }
+
     /// Return a [u32] specific to this store instance.
     ///
     /// This local registration id is separate from the per-device identifier used in
@@ -69,6 +81,9 @@ pub trait IdentityKeyStore {
     ) -> Result<bool>;
 
     /// Return whether an identity is trusted for the role specified by `direction`.
+    ///
+    /// An identity accepted through [Self::apply_identity_rotation] has been saved with
+    /// [Self::save_identity], so implementations don't need to treat it specially.
     async fn is_trusted_identity(
         &self,
         address: &ProtocolAddress,
@@ -83,6 +98,30 @@ pub trait IdentityKeyStore {
         address: &ProtocolAddress,
         ctx: Context,
     ) -> Result<Option<IdentityKey>>;
+
+    /// Accept `rotation` as a change of identity for `address`.
+    ///
+    /// The rotation's signature was checked when it was deserialized, so this only needs to make
+    /// sure it was signed by the identity currently stored for `address`, and then saves the new
+    /// identity in its place. Returns `Ok(false)` if the new identity was already stored, and
+    /// [SignalProtocolError::UntrustedIdentity] if the stored identity is anything else (including
+    /// if there is none); rotations can't establish trust from nothing.
+    async fn apply_identity_rotation(
+        &mut self,
+        address: &ProtocolAddress,
+        rotation: &IdentityRotation,
+        ctx: Context,
+    ) -> Result<bool> {
+        match self.get_identity(address, ctx).await? {
+            Some(current) if current.ct_eq(rotation.new_identity_key()) => Ok(false),
+            Some(current) if current.ct_eq(rotation.old_identity_key()) => {
+                self.save_identity(address, rotation.new_identity_key(), ctx)
+                    .await?;
+                Ok(true)
+            }
+            _ => Err(SignalProtocolError::UntrustedIdentity(address.clone())),
+        }
+    }
 }
 
 /// Interface for storing pre-keys downloaded from a server.
@@ -120,6 +159,20 @@ pub trait SignedPreKeyStore {
         record: &SignedPreKeyRecord,
         ctx: Context,
     ) -> Result<()>;
+
+    /// Remove the entry for `signed_prekey_id`.
+    ///
+    /// This is only used to prune old keys in [SignedPreKeyRotation](crate::SignedPreKeyRotation).
+    /// The default implementation fails, so stores that don't support removal are never silently
+    /// left holding keys the caller believes were deleted.
+    async fn remove_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        ctx: Context,
+    ) -> Result<()> {
+        let _ = (signed_prekey_id, ctx);
+        Err(unsupported("remove_signed_pre_key"))
+    }
 }
 
 /// Interface for storing signed Kyber pre-keys downloaded from a server.
@@ -174,32 +227,214 @@ pub trait SessionStore {
         record: &SessionRecord,
         ctx: Context,
     ) -> Result<()>;
+
+    /// Look up the sessions for several addresses at once, failing with
+    /// [SignalProtocolError::SessionNotFound] if any of them is missing.
+    ///
+    /// The default implementation calls [load_session](Self::load_session) for each address in
+    /// turn; stores backed by a database should override it to issue a single query.
+    async fn load_existing_sessions(
+        &self,
+        addresses: &[&ProtocolAddress],
+        ctx: Context,
+    ) -> Result<Vec<SessionRecord>> {
+        let mut sessions = Vec::with_capacity(addresses.len());
+        for &address in addresses {
+            let session = self
+                .load_session(address, ctx)
+                .await?
+                .ok_or_else(|| SignalProtocolError::SessionNotFound(address.clone()))?;
+            sessions.push(session);
+        }
+        Ok(sessions)
+    }
+
+    /// List every address that has an entry in this store, in no particular order.
+    ///
+    /// The default implementation fails, since the required methods give no way to enumerate
+    /// entries.
+    async fn all_session_addresses(&self, ctx: Context) -> Result<Vec<ProtocolAddress>> {
+        let _ = ctx;
+        Err(unsupported("all_session_addresses"))
+    }
+
+    /// Remove the entry for `address`, if any.
+    ///
+    /// The default implementation fails.
+    async fn delete_session(&mut self, address: &ProtocolAddress, ctx: Context) -> Result<()> {
+        let _ = (address, ctx);
+        Err(unsupported("delete_session"))
+    }
+
+    /// Remove the entries for every device belonging to the user `name`.
+    ///
+    /// The default implementation combines [all_session_addresses](Self::all_session_addresses)
+    /// and [delete_session](Self::delete_session).
+    async fn delete_all_sessions(&mut self, name: &str, ctx: Context) -> Result<()> {
+        for address in self.all_session_addresses(ctx).await? {
+            if address.name() == name {
+                self.delete_session(&address, ctx).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many archived sessions to keep in each record, and for how long.
+    ///
+    /// The protocol functions trim records to this policy before passing them to
+    /// [store_session](Self::store_session). The default keeps up to 40 archived sessions, however
+    /// old they are.
+    fn archive_policy(&self) -> SessionArchivePolicy {
+        SessionArchivePolicy::default()
+    }
 }
 
 /// Interface for storing sender key records, allowing multiple keys per user.
 #[async_trait(?Send)]
 pub trait SenderKeyStore {
-    /// Assign `record` to the entry for `(sender, distribution_id)`.
+    /// Assign `record` to the entry for `sender_key_name`.
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         // TODO: pass this by value!
         record: &SenderKeyRecord,
         ctx: Context,
     ) -> Result<()>;
 
-    /// Look up the entry corresponding to `(sender, distribution_id)`.
+    /// Look up the entry corresponding to `sender_key_name`.
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         ctx: Context,
     ) -> Result<Option<SenderKeyRecord>>;
+
+    /// Remove the entry for `sender_key_name`, if any.
+    ///
+    /// The default implementation fails.
+    async fn delete_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<()> {
+        let _ = (sender_key_name, ctx);
+        Err(unsupported("delete_sender_key"))
+    }
+
+    /// Remove every entry that has not been stored since before the store's expiration period,
+    /// measured back from `now`, returning the names that were removed.
+    ///
+    /// The expiration period is up to the store; entries are refreshed every time they are stored,
+    /// so only keys for senders who have gone quiet (for instance, members who left the group, or
+    /// distributions that were rotated away) are removed. The default implementation fails.
+    async fn prune_expired(&mut self, now: SystemTime, ctx: Context) -> Result<Vec<SenderKeyName>> {
+        let _ = (now, ctx);
+        Err(unsupported("prune_expired"))
+    }
+}
+
+/// Identifies a decrypted message to a [ReplayCache].
+///
+/// This is a hash of the sender and the message's position in its chain, so it reveals nothing
+/// about the message and can be stored as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReplayKey([u8; 32]);
+
+impl ReplayKey {
+    /// The key for a 1:1 message sent on the chain for `ratchet_key`.
+    pub(crate) fn for_session_message(
+        sender: &ProtocolAddress,
+        ratchet_key: &PublicKey,
+        counter: u32,
+    ) -> Self {
+        Self::hash(
+            b"LibSignal_ReplayKey_Session",
+            sender,
+            &[&ratchet_key.serialize(), &counter.to_be_bytes()],
+        )
+    }
+
+    /// The key for a group message sent on sender key chain `chain_id`.
+    pub(crate) fn for_sender_key_message(
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        chain_id: u32,
+        iteration: u32,
+    ) -> Self {
+        Self::hash(
+            b"LibSignal_ReplayKey_SenderKey",
+            sender,
+            &[
+                distribution_id.as_bytes(),
+                &chain_id.to_be_bytes(),
+                &iteration.to_be_bytes(),
+            ],
+        )
+    }
+
+    fn hash(label: &[u8], sender: &ProtocolAddress, fields: &[&[u8]]) -> Self {
+        let mut hash = Sha256::new();
+        hash.update(label);
+        // Lengths keep the name from running into the fields that follow it.
+        hash.update((sender.name().len() as u64).to_be_bytes());
+        hash.update(sender.name());
+        hash.update(u32::from(sender.device_id()).to_be_bytes());
+        for field in fields {
+            hash.update(field);
+        }
+        Self(hash.finalize().into())
+    }
+
+    /// The bytes of the key, for stores that persist it.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Reconstructs a key previously returned by [as_bytes](Self::as_bytes).
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Remembers recently decrypted messages, so a message delivered twice is rejected the second
+/// time even if the session or sender key state it was decrypted with has been lost or rolled back
+/// in between (for instance, by restoring a backup).
+///
+/// The decryption functions that take a cache check it once a message has been authenticated,
+/// failing with [SignalProtocolError::DuplicatedMessage] if the message was seen before, and
+/// insert the message after saving the updated session or sender key. An implementation may
+/// forget old entries; it only needs to hold on to them long enough to cover the duplicates the
+/// application expects to see.
+#[async_trait(?Send)]
+pub trait ReplayCache {
+    /// Whether `key` has been [inserted](Self::insert) and not yet forgotten.
+    async fn contains(&self, key: &ReplayKey, ctx: Context) -> Result<bool>;
+
+    /// Record that the message identified by `key` has been decrypted.
+    async fn insert(&mut self, key: &ReplayKey, ctx: Context) -> Result<()>;
 }
 
-/// Mixes in all the store interfaces defined in this module.
+/// Mixes in all the store interfaces needed for one-to-one sessions.
+///
+/// This is implemented automatically for every type that implements the individual traits, so a
+/// single store object can be passed to the `*_with_protocol_store` functions (such as
+/// [message_encrypt_with_protocol_store](crate::message_encrypt_with_protocol_store)) instead of
+/// being passed once for each role.
 pub trait ProtocolStore:
     SessionStore + PreKeyStore + SignedPreKeyStore + KyberPreKeyStore + IdentityKeyStore
 {
 }
+
+impl<T> ProtocolStore for T where
+    T: SessionStore
+        + PreKeyStore
+        + SignedPreKeyStore
+        + KyberPreKeyStore
+        + IdentityKeyStore
+        + ?Sized
+{
+}
+
+/// The error returned by optional store methods that an implementation hasn't provided.
+pub(super) fn unsupported(operation: &'static str) -> SignalProtocolError {
+    SignalProtocolError::InvalidState(operation, "not supported by this store".to_string())
+}
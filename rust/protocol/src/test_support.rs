@@ -0,0 +1,145 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Deterministic identities and a conversation simulator for tests.
+//!
+//! [`TestIdentity::named`] turns a name like `"alice"` into the same keys, registration ID, and
+//! address every time, so tests (including those in other languages and repositories) can share
+//! fixtures by name instead of checking in serialized keys.
+//!
+//! Each value is derived with HKDF-SHA256, using the UTF-8 name as the input key material and
+//! `"Signal_TestIdentity"` as the salt, with the following `info` strings:
+//!
+//! - `"identity"`: 32 bytes, used as the identity private key.
+//! - `"registration_id"`: 2 bytes; the big-endian value modulo 16380, plus 1.
+//! - `"aci"`: 16 bytes, made into a version 4 UUID by setting the version and variant bits.
+//!
+//! [`Endpoint`] builds on these identities to simulate a conversation: each endpoint owns a store
+//! and a mailbox, and the test decides when, in what order, and whether messages are delivered.
+//!
+//! Only available with the `test-support` feature. These keys are public knowledge; never use them
+//! outside of tests.
+
+#![warn(missing_docs)]
+
+mod endpoint;
+pub use endpoint::{Endpoint, Envelope};
+
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    Aci, DeviceId, IdentityKey, IdentityKeyPair, InMemSignalProtocolStore, PrivateKey,
+    ProtocolAddress, Result,
+};
+
+const SALT: &[u8] = b"Signal_TestIdentity";
+
+/// A user with keys derived from their name; see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct TestIdentity {
+    name: String,
+    identity_key_pair: IdentityKeyPair,
+    registration_id: u32,
+    aci: Aci,
+}
+
+impl TestIdentity {
+    /// Derives the identity for `name`.
+    pub fn named(name: &str) -> Self {
+        let hkdf = hkdf::Hkdf::<Sha256>::new(Some(SALT), name.as_bytes());
+        let expand = |info: &[u8], output: &mut [u8]| {
+            hkdf.expand(info, output).expect("valid length");
+        };
+
+        let mut private_key = [0; 32];
+        expand(b"identity", &mut private_key);
+        let private_key = PrivateKey::deserialize(&private_key).expect("valid length");
+        let identity_key = IdentityKey::new(private_key.public_key().expect("valid private key"));
+
+        let mut registration_id = [0; 2];
+        expand(b"registration_id", &mut registration_id);
+        let registration_id = u32::from(u16::from_be_bytes(registration_id)) % 16380 + 1;
+
+        let mut aci = [0; 16];
+        expand(b"aci", &mut aci);
+        let aci = uuid::Builder::from_random_bytes(aci).into_uuid();
+
+        Self {
+            name: name.to_owned(),
+            identity_key_pair: IdentityKeyPair::new(identity_key, private_key),
+            registration_id,
+            aci: aci.into(),
+        }
+    }
+
+    /// The name this identity was derived from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The identity key pair.
+    pub fn identity_key_pair(&self) -> &IdentityKeyPair {
+        &self.identity_key_pair
+    }
+
+    /// The public identity key.
+    pub fn identity_key(&self) -> &IdentityKey {
+        self.identity_key_pair.identity_key()
+    }
+
+    /// The registration ID, in the range `1..=16380`.
+    pub fn registration_id(&self) -> u32 {
+        self.registration_id
+    }
+
+    /// The account identifier.
+    pub fn aci(&self) -> Aci {
+        self.aci
+    }
+
+    /// The address of this user's primary device (device 1).
+    pub fn address(&self) -> ProtocolAddress {
+        self.address_for_device(1.into())
+    }
+
+    /// The address of one of this user's devices.
+    pub fn address_for_device(&self, device_id: DeviceId) -> ProtocolAddress {
+        ProtocolAddress::new(Uuid::from(self.aci).to_string(), device_id)
+    }
+
+    /// A fresh in-memory store holding this identity, with no pre-keys or sessions.
+    pub fn store(&self) -> Result<InMemSignalProtocolStore> {
+        InMemSignalProtocolStore::new(self.identity_key_pair, self.registration_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_identities_are_stable() {
+        let alice = TestIdentity::named("alice");
+        assert_eq!(
+            hex::encode(alice.identity_key().serialize()),
+            "05360cc05bbf6724109e683d47e51a257e5ba16425e2097968557c5e808fc14174"
+        );
+        assert_eq!(alice.registration_id(), 7152);
+        assert_eq!(
+            alice.address().name(),
+            "43090620-8deb-423b-b0b5-f506bbc7ed59"
+        );
+        assert_eq!(alice.address().device_id(), 1.into());
+
+        let again = TestIdentity::named("alice");
+        assert_eq!(alice.identity_key(), again.identity_key());
+        assert_eq!(alice.aci(), again.aci());
+
+        let bob = TestIdentity::named("bob");
+        assert_ne!(alice.identity_key(), bob.identity_key());
+        assert_ne!(alice.aci(), bob.aci());
+    }
+}
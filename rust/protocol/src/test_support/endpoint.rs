@@ -0,0 +1,276 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::VecDeque;
+
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+
+use super::TestIdentity;
+use crate::storage::{KyberPreKeyStore, PreKeyStore, SessionStore, SignedPreKeyStore};
+use crate::{
+    kem, message_decrypt, message_encrypt, process_prekey_bundle, CiphertextMessage,
+    GenericSignedPreKey, InMemSignalProtocolStore, KeyPair, KyberPreKeyRecord, PreKeyBundle,
+    PreKeyRecord, ProtocolAddress, Result, SeededRng, SignedPreKeyRecord,
+};
+
+/// A message waiting in an [Endpoint]'s mailbox.
+#[derive(Debug)]
+pub struct Envelope {
+    sender: ProtocolAddress,
+    message: CiphertextMessage,
+}
+
+impl Envelope {
+    /// The address of the endpoint that sent the message.
+    pub fn sender(&self) -> &ProtocolAddress {
+        &self.sender
+    }
+
+    /// The encrypted message.
+    pub fn message(&self) -> &CiphertextMessage {
+        &self.message
+    }
+}
+
+/// One side of a simulated conversation: a [TestIdentity] with its own store, mailbox, and RNG.
+///
+/// Messages sent with [send_to](Self::send_to) wait in the recipient's mailbox until the test
+/// delivers them, in order, shuffled, or not at all, which makes it easy to reproduce reordering
+/// and loss. Sessions are set up automatically the first time one endpoint sends to another, using
+/// a fresh pre-key bundle from the recipient.
+///
+/// The RNG is seeded from the endpoint's name, so everything but Kyber key generation (which
+/// always uses system randomness) is reproducible, including the order of
+/// [deliver_shuffled](Self::deliver_shuffled).
+pub struct Endpoint {
+    identity: TestIdentity,
+    store: InMemSignalProtocolStore,
+    mailbox: VecDeque<Envelope>,
+    rng: SeededRng,
+    next_pre_key_id: u32,
+}
+
+impl Endpoint {
+    /// Creates the endpoint for [`TestIdentity::named(name)`](TestIdentity::named), with an
+    /// empty store and mailbox.
+    pub fn named(name: &str) -> Result<Self> {
+        let identity = TestIdentity::named(name);
+        let store = identity.store()?;
+        let seed = Sha256::new()
+            .chain(b"Signal_TestEndpoint")
+            .chain(name.as_bytes())
+            .finalize();
+        Ok(Self {
+            identity,
+            store,
+            mailbox: VecDeque::new(),
+            rng: SeededRng::from_seed(seed.into()),
+            next_pre_key_id: 1,
+        })
+    }
+
+    /// The identity this endpoint was created from.
+    pub fn identity(&self) -> &TestIdentity {
+        &self.identity
+    }
+
+    /// The address other endpoints use for this one.
+    pub fn address(&self) -> ProtocolAddress {
+        self.identity.address()
+    }
+
+    /// This endpoint's store.
+    pub fn store(&self) -> &InMemSignalProtocolStore {
+        &self.store
+    }
+
+    /// This endpoint's store, for checking or tampering with its state directly.
+    pub fn store_mut(&mut self) -> &mut InMemSignalProtocolStore {
+        &mut self.store
+    }
+
+    /// This endpoint's RNG, for calling protocol functions directly.
+    pub fn rng(&mut self) -> &mut SeededRng {
+        &mut self.rng
+    }
+
+    /// Generates and saves a one-time pre-key, a signed pre-key, and a Kyber pre-key, and returns
+    /// a bundle advertising them, as a server would hand out.
+    pub async fn pre_key_bundle(&mut self) -> Result<PreKeyBundle> {
+        let id = self.next_pre_key_id;
+        self.next_pre_key_id += 1;
+        let identity_key_pair = self.identity.identity_key_pair();
+
+        let pre_key = PreKeyRecord::new(id.into(), &KeyPair::generate(&mut self.rng));
+        self.store.save_pre_key(id.into(), &pre_key, None).await?;
+
+        let signed_pre_key = SignedPreKeyRecord::generate(
+            id.into(),
+            identity_key_pair.private_key(),
+            &mut self.rng,
+        )?;
+        self.store
+            .save_signed_pre_key(id.into(), &signed_pre_key, None)
+            .await?;
+
+        let kyber_pre_key = KyberPreKeyRecord::generate(
+            kem::KeyType::Kyber1024,
+            id.into(),
+            identity_key_pair.private_key(),
+            &mut self.rng,
+        )?;
+        self.store
+            .save_kyber_pre_key(id.into(), &kyber_pre_key, None)
+            .await?;
+
+        Ok(PreKeyBundle::new(
+            self.identity.registration_id(),
+            self.address().device_id(),
+            Some((id.into(), pre_key.public_key()?)),
+            id.into(),
+            signed_pre_key.public_key()?,
+            signed_pre_key.signature()?,
+            *self.identity.identity_key(),
+        )?
+        .with_kyber_pre_key(
+            id.into(),
+            kyber_pre_key.public_key()?,
+            kyber_pre_key.signature()?,
+        ))
+    }
+
+    /// Encrypts `plaintext` for `recipient` and puts it at the back of their mailbox.
+    ///
+    /// If there is no current session with `recipient`, one is started first from their
+    /// [pre_key_bundle](Self::pre_key_bundle).
+    pub async fn send_to(&mut self, recipient: &mut Endpoint, plaintext: &[u8]) -> Result<()> {
+        let recipient_address = recipient.address();
+        let has_session = match self.store.load_session(&recipient_address, None).await? {
+            Some(record) => record.has_current_session_state(),
+            None => false,
+        };
+        if !has_session {
+            let bundle = recipient.pre_key_bundle().await?;
+            process_prekey_bundle(
+                &recipient_address,
+                &mut self.store.session_store,
+                &mut self.store.identity_store,
+                &bundle,
+                &mut self.rng,
+                None,
+            )
+            .await?;
+        }
+
+        let message = message_encrypt(
+            plaintext,
+            &recipient_address,
+            &mut self.store.session_store,
+            &mut self.store.identity_store,
+            None,
+        )
+        .await?;
+        recipient.mailbox.push_back(Envelope {
+            sender: self.address(),
+            message,
+        });
+        Ok(())
+    }
+
+    /// The messages waiting to be delivered, oldest first.
+    pub fn pending(&self) -> impl Iterator<Item = &Envelope> {
+        self.mailbox.iter()
+    }
+
+    /// Removes the oldest waiting message without decrypting it, as if it were lost in transit.
+    pub fn drop_next(&mut self) -> Option<Envelope> {
+        self.mailbox.pop_front()
+    }
+
+    /// Decrypts the oldest waiting message, or returns `None` if the mailbox is empty.
+    ///
+    /// The message is removed from the mailbox even if it fails to decrypt.
+    pub async fn deliver_next(&mut self) -> Result<Option<Vec<u8>>> {
+        let envelope = match self.mailbox.pop_front() {
+            Some(envelope) => envelope,
+            None => return Ok(None),
+        };
+        let plaintext = message_decrypt(
+            &envelope.message,
+            &envelope.sender,
+            &mut self.store.session_store,
+            &mut self.store.identity_store,
+            &mut self.store.pre_key_store,
+            &mut self.store.signed_pre_key_store,
+            &mut self.store.kyber_pre_key_store,
+            &mut self.rng,
+            None,
+        )
+        .await?;
+        Ok(Some(plaintext))
+    }
+
+    /// Decrypts every waiting message, oldest first, stopping at the first one that fails.
+    pub async fn deliver_in_order(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut plaintexts = Vec::new();
+        while let Some(plaintext) = self.deliver_next().await? {
+            plaintexts.push(plaintext);
+        }
+        Ok(plaintexts)
+    }
+
+    /// Shuffles the waiting messages with this endpoint's RNG, then delivers them as
+    /// [deliver_in_order](Self::deliver_in_order) does.
+    pub async fn deliver_shuffled(&mut self) -> Result<Vec<Vec<u8>>> {
+        self.mailbox.make_contiguous().shuffle(&mut self.rng);
+        self.deliver_in_order().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CiphertextMessageType;
+
+    use futures_util::FutureExt;
+
+    #[test]
+    fn test_lossy_shuffled_conversation() -> Result<()> {
+        async {
+            let mut alice = Endpoint::named("alice")?;
+            let mut bob = Endpoint::named("bob")?;
+
+            for plaintext in [&b"one"[..], b"two", b"three", b"four"] {
+                alice.send_to(&mut bob, plaintext).await?;
+            }
+            assert_eq!(bob.pending().count(), 4);
+            assert!(bob
+                .pending()
+                .all(|envelope| envelope.message().message_type()
+                    == CiphertextMessageType::PreKey
+                    && envelope.sender() == &alice.address()));
+
+            assert!(bob.drop_next().is_some());
+            let mut received = bob.deliver_shuffled().await?;
+            received.sort();
+            assert_eq!(received, [&b"four"[..], b"three", b"two"]);
+            assert_eq!(bob.pending().count(), 0);
+
+            bob.send_to(&mut alice, b"reply").await?;
+            assert_eq!(alice.deliver_in_order().await?, [b"reply"]);
+            alice.send_to(&mut bob, b"again").await?;
+            assert_eq!(
+                bob.pending().next().map(|e| e.message().message_type()),
+                Some(CiphertextMessageType::Whisper)
+            );
+            assert_eq!(bob.deliver_next().await?, Some(b"again".to_vec()));
+            assert_eq!(bob.deliver_next().await?, None);
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
@@ -0,0 +1,188 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Points in time, as carried in certificates, pre-key records, and messages.
+
+use std::convert::TryFrom;
+use std::ops::{Add, Sub};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "chrono")]
+use crate::SignalProtocolError;
+
+/// A point in time, stored as milliseconds since the Unix epoch.
+///
+/// This is the representation used on the wire and in stored records. Keeping it in its own type
+/// stops it from being mixed up with counters, IDs, or times measured in seconds.
+///
+/// Converting from a [`SystemTime`] before the epoch gives [`Timestamp::UNIX_EPOCH`], and times too
+/// far in the future to represent saturate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub const UNIX_EPOCH: Self = Self(0);
+
+    #[inline]
+    pub const fn from_epoch_millis(millis: u64) -> Self {
+        Self(millis)
+    }
+
+    #[inline]
+    pub const fn epoch_millis(self) -> u64 {
+        self.0
+    }
+
+    /// The current time according to the system clock.
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Self> {
+        self.0.checked_add(duration_millis(duration)?).map(Self)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Self> {
+        self.0.checked_sub(duration_millis(duration)?).map(Self)
+    }
+
+    pub fn saturating_add(self, duration: Duration) -> Self {
+        self.checked_add(duration).unwrap_or(Self(u64::MAX))
+    }
+
+    pub fn saturating_sub(self, duration: Duration) -> Self {
+        self.checked_sub(duration).unwrap_or(Self::UNIX_EPOCH)
+    }
+
+    /// The time elapsed from `earlier` to `self`, or `None` if `earlier` is later.
+    pub fn duration_since(self, earlier: Self) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_millis)
+    }
+}
+
+fn duration_millis(duration: Duration) -> Option<u64> {
+    u64::try_from(duration.as_millis()).ok()
+}
+
+/// Panics on overflow, like the same operation on [`SystemTime`]; use
+/// [`checked_add`](Timestamp::checked_add) to handle it instead.
+impl Add<Duration> for Timestamp {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to timestamp")
+    }
+}
+
+/// Panics on underflow, like the same operation on [`SystemTime`]; use
+/// [`checked_sub`](Timestamp::checked_sub) to handle it instead.
+impl Sub<Duration> for Timestamp {
+    type Output = Self;
+
+    fn sub(self, duration: Duration) -> Self {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from timestamp")
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self(duration_millis(since_epoch).unwrap_or(u64::MAX))
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp.0)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TryFrom<Timestamp> for chrono::DateTime<chrono::Utc> {
+    type Error = SignalProtocolError;
+
+    fn try_from(timestamp: Timestamp) -> Result<Self, Self::Error> {
+        use chrono::TimeZone;
+        i64::try_from(timestamp.0)
+            .ok()
+            .and_then(|millis| chrono::Utc.timestamp_millis_opt(millis).single())
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidArgument(format!(
+                    "{:?} is out of range for a DateTime",
+                    timestamp
+                ))
+            })
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TryFrom<chrono::DateTime<chrono::Utc>> for Timestamp {
+    type Error = SignalProtocolError;
+
+    fn try_from(time: chrono::DateTime<chrono::Utc>) -> Result<Self, Self::Error> {
+        u64::try_from(time.timestamp_millis())
+            .map(Self)
+            .map_err(|_| {
+                SignalProtocolError::InvalidArgument(format!("{} is before the Unix epoch", time))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_time_round_trip() {
+        let timestamp = Timestamp::from_epoch_millis(1_700_000_000_123);
+        assert_eq!(Timestamp::from(SystemTime::from(timestamp)), timestamp);
+
+        let before_epoch = SystemTime::UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(Timestamp::from(before_epoch), Timestamp::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let timestamp = Timestamp::from_epoch_millis(5_000);
+        assert_eq!(
+            timestamp.checked_add(Duration::from_secs(1)),
+            Some(Timestamp::from_epoch_millis(6_000))
+        );
+        assert_eq!(timestamp.checked_sub(Duration::from_secs(6)), None);
+        assert_eq!(
+            timestamp.saturating_sub(Duration::from_secs(6)),
+            Timestamp::UNIX_EPOCH
+        );
+        assert_eq!(
+            timestamp.duration_since(Timestamp::from_epoch_millis(4_500)),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            Timestamp::from_epoch_millis(4_500).duration_since(timestamp),
+            None
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_round_trip() {
+        let timestamp = Timestamp::from_epoch_millis(1_700_000_000_123);
+        let date_time = chrono::DateTime::<chrono::Utc>::try_from(timestamp).expect("in range");
+        assert_eq!(date_time.timestamp_millis(), 1_700_000_000_123);
+        assert_eq!(
+            Timestamp::try_from(date_time).expect("after epoch"),
+            timestamp
+        );
+
+        assert!(
+            chrono::DateTime::<chrono::Utc>::try_from(Timestamp::from_epoch_millis(u64::MAX))
+                .is_err()
+        );
+    }
+}
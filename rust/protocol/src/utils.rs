@@ -5,6 +5,15 @@
 
 use std::cmp::Ordering;
 
+use subtle::ConstantTimeEq;
+
+/// Compares two byte strings, such as MACs or serialized keys, in constant time.
+///
+/// Only the contents are protected; strings of different lengths compare unequal right away.
+pub fn constant_time_eq(x: &[u8], y: &[u8]) -> bool {
+    x.ct_eq(y).into()
+}
+
 fn expand_top_bit(a: u8) -> u8 {
     //if (a >> 7) == 1 { 0xFF } else { 0 }
     0u8.wrapping_sub(a >> 7)
@@ -15,6 +15,73 @@ use std::convert::TryFrom;
 use support::*;
 use uuid::Uuid;
 
+#[derive(Default)]
+struct SenderKeyUpdates(std::sync::Mutex<Vec<SenderKeyName>>);
+
+impl ProtocolObserver for SenderKeyUpdates {
+    fn sender_key_updated(&self, name: &SenderKeyName) {
+        self.0.lock().expect("not poisoned").push(name.clone());
+    }
+}
+
+#[test]
+fn group_sender_key_updates_are_observed() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+        let name = SenderKeyName::new(sender_address.clone(), distribution_id);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        let updates = std::sync::Arc::new(SenderKeyUpdates::default());
+
+        with_observer(updates.clone(), async {
+            let distribution_message = create_sender_key_distribution_message(
+                &sender_address,
+                distribution_id,
+                &mut alice_store,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            let ciphertext = group_encrypt(
+                &mut alice_store,
+                &sender_address,
+                distribution_id,
+                "space camp?".as_bytes(),
+                &mut csprng,
+                None,
+            )
+            .await?;
+            process_sender_key_distribution_message(
+                &sender_address,
+                &distribution_message,
+                &mut bob_store,
+                None,
+            )
+            .await?;
+            group_decrypt(
+                ciphertext.serialized(),
+                &mut bob_store,
+                &sender_address,
+                None,
+            )
+            .await
+        })
+        .await?;
+
+        // Creating the key, encrypting, processing the distribution message, and decrypting each
+        // save the record once.
+        assert_eq!(*updates.0.lock().expect("not poisoned"), vec![name; 4]);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn group_no_send_session() -> Result<(), SignalProtocolError> {
     let mut csprng = OsRng;
@@ -57,27 +124,23 @@ impl ContextUsingSenderKeyStore {
 impl SenderKeyStore for ContextUsingSenderKeyStore {
     async fn store_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         record: &SenderKeyRecord,
         ctx: Context,
     ) -> Result<(), SignalProtocolError> {
         assert_eq!(ctx, self.expected_context);
         self.store
-            .store_sender_key(sender, distribution_id, record, ctx)
+            .store_sender_key(sender_key_name, record, ctx)
             .await
     }
 
     async fn load_sender_key(
         &mut self,
-        sender: &ProtocolAddress,
-        distribution_id: Uuid,
+        sender_key_name: &SenderKeyName,
         ctx: Context,
     ) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
         assert_eq!(ctx, self.expected_context);
-        self.store
-            .load_sender_key(sender, distribution_id, ctx)
-            .await
+        self.store.load_sender_key(sender_key_name, ctx).await
     }
 }
 
@@ -306,7 +369,7 @@ fn group_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut csprng,
         )?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -400,6 +463,109 @@ fn group_sealed_sender() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn group_attributed_distribution() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_uuid = "aaaaaaaa-7000-11eb-b32a-33b8a8a487a6".to_owned();
+        let alice_address = ProtocolAddress::new(alice_uuid.clone(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        let alice_identity = alice_store.get_identity_key_pair(None).await?;
+
+        let trust_root = KeyPair::generate(&mut csprng);
+        let server_key = KeyPair::generate(&mut csprng);
+        let server_cert = ServerCertificate::new(
+            1,
+            server_key.public_key,
+            &trust_root.private_key,
+            &mut csprng,
+        )?;
+        let sender_cert = SenderCertificate::new(
+            alice_uuid,
+            None,
+            *alice_identity.public_key(),
+            alice_address.device_id(),
+            Timestamp::from_epoch_millis(1605722925),
+            server_cert,
+            &server_key.private_key,
+            &mut csprng,
+        )?;
+
+        let unsigned = create_sender_key_distribution_message(
+            &alice_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert!(unsigned.identity_signature().is_none());
+        assert!(matches!(
+            process_attributed_sender_key_distribution_message(
+                &sender_cert,
+                &unsigned,
+                &mut bob_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+
+        let someone_else = IdentityKeyPair::generate(&mut csprng);
+        let misattributed = unsigned
+            .clone()
+            .with_identity_signature(&someone_else, &mut csprng)?;
+        assert!(matches!(
+            process_attributed_sender_key_distribution_message(
+                &sender_cert,
+                &misattributed,
+                &mut bob_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SignatureValidationFailed)
+        ));
+
+        let signed = unsigned.with_identity_signature(&alice_identity, &mut csprng)?;
+        let received = SenderKeyDistributionMessage::try_from(signed.serialized())?;
+        assert_eq!(received.identity_signature(), signed.identity_signature());
+        assert!(received.verify_identity_signature(alice_identity.identity_key())?);
+        process_attributed_sender_key_distribution_message(
+            &sender_cert,
+            &received,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let alice_ciphertext = group_encrypt(
+            &mut alice_store,
+            &alice_address,
+            distribution_id,
+            "space camp?".as_bytes(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let bob_plaintext = group_decrypt(
+            alice_ciphertext.serialized(),
+            &mut bob_store,
+            &alice_address,
+            None,
+        )
+        .await?;
+        assert_eq!(bob_plaintext, b"space camp?");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn group_large_messages() -> Result<(), SignalProtocolError> {
     async {
@@ -574,6 +740,77 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn group_encrypt_batch_matches_individual_sends() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        process_sender_key_distribution_message(
+            &sender_address,
+            &sent_distribution_message,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let plaintexts: [&[u8]; 3] = [b"swim camp", b"robot camp", b"ninja camp"];
+        let batch = group_encrypt_batch(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            &plaintexts,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            batch.iter().map(|m| m.iteration()).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+
+        // The chain picks up where the batch left off.
+        let after = group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            b"space camp",
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(after.iteration(), 3);
+
+        for (message, plaintext) in batch.iter().zip(&plaintexts).rev() {
+            assert_eq!(
+                &group_decrypt(message.serialized(), &mut bob_store, &sender_address, None).await?,
+                plaintext
+            );
+        }
+        assert_eq!(
+            group_decrypt(after.serialized(), &mut bob_store, &sender_address, None).await?,
+            b"space camp"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn group_late_join() -> Result<(), SignalProtocolError> {
     async {
@@ -873,3 +1110,371 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
     .now_or_never()
     .expect("sync")
 }
+
+#[test]
+fn sender_key_record_integrity() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+        let name = SenderKeyName::new(sender_address.clone(), distribution_id);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let record = alice_store
+            .load_sender_key(&name, None)
+            .await?
+            .expect("created");
+
+        let key = b"sender key store integrity key";
+        let tagged = record.serialize_with_integrity(key, &name)?;
+        let restored = SenderKeyRecord::deserialize_with_integrity(
+            &tagged,
+            key,
+            &name,
+            IntegrityMode::Strict,
+        )?;
+        assert_eq!(restored.serialize()?, record.serialize()?);
+
+        let mut corrupted = tagged.clone();
+        corrupted[10] ^= 1;
+        assert!(matches!(
+            SenderKeyRecord::deserialize_with_integrity(
+                &corrupted,
+                key,
+                &name,
+                IntegrityMode::Strict
+            ),
+            Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+        ));
+
+        // A tagged record of one type can't be passed off as another.
+        assert!(matches!(
+            SessionRecord::deserialize_with_integrity(
+                &tagged,
+                key,
+                &sender_address,
+                IntegrityMode::Strict
+            ),
+            Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+        ));
+
+        // Nor can it be moved to another distribution's slot.
+        let other_name = SenderKeyName::new(sender_address.clone(), Uuid::from_u128(1));
+        assert!(matches!(
+            SenderKeyRecord::deserialize_with_integrity(
+                &tagged,
+                key,
+                &other_name,
+                IntegrityMode::Strict
+            ),
+            Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+        ));
+
+        let untagged = record.serialize()?;
+        assert!(matches!(
+            SenderKeyRecord::deserialize_with_integrity(
+                &untagged,
+                key,
+                &name,
+                IntegrityMode::Strict
+            ),
+            Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+        ));
+        SenderKeyRecord::deserialize_with_integrity(
+            &untagged,
+            key,
+            &name,
+            IntegrityMode::AllowUntagged,
+        )?;
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_replay_cache() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        let mut replay_cache = InMemReplayCache::default();
+
+        let distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        process_sender_key_distribution_message(
+            &sender_address,
+            &SenderKeyDistributionMessage::try_from(distribution_message.serialized())?,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let mut ciphertexts = Vec::new();
+        for text in ["one", "two"] {
+            ciphertexts.push(
+                group_encrypt(
+                    &mut alice_store,
+                    &sender_address,
+                    distribution_id,
+                    text.as_bytes(),
+                    &mut csprng,
+                    None,
+                )
+                .await?,
+            );
+        }
+
+        // Bob's sender key gets rolled back after decrypting the first message.
+        let backup = bob_store.clone();
+        group_decrypt_with_replay_cache(
+            ciphertexts[0].serialized(),
+            &mut bob_store,
+            &mut replay_cache,
+            &sender_address,
+            None,
+        )
+        .await?;
+        bob_store = backup;
+
+        // Without the cache, the message would be accepted again.
+        assert_eq!(
+            group_decrypt(
+                ciphertexts[0].serialized(),
+                &mut bob_store.clone(),
+                &sender_address,
+                None,
+            )
+            .await?,
+            b"one"
+        );
+        assert!(matches!(
+            group_decrypt_with_replay_cache(
+                ciphertexts[0].serialized(),
+                &mut bob_store,
+                &mut replay_cache,
+                &sender_address,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::DuplicatedMessage(0, 0))
+        ));
+
+        assert_eq!(
+            group_decrypt_with_replay_cache(
+                ciphertexts[1].serialized(),
+                &mut bob_store,
+                &mut replay_cache,
+                &sender_address,
+                None,
+            )
+            .await?,
+            b"two"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_reset_sender_key() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14159999222".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        let mut carol_store = test_in_memory_protocol_store()?;
+
+        let original = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        for store in [&mut bob_store, &mut carol_store] {
+            process_sender_key_distribution_message(&sender_address, &original, store, None)
+                .await?;
+        }
+
+        // Carol leaves the group.
+        let reset = reset_sender_key(
+            &sender_address,
+            distribution_id,
+            &[&sender_address, &bob_address, &bob_address],
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(reset.recipients, vec![bob_address.clone()]);
+        assert_ne!(reset.distribution_message.chain_id()?, original.chain_id()?);
+
+        let ciphertext = group_encrypt(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            "space camp?".as_bytes(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        assert!(group_decrypt(
+            ciphertext.serialized(),
+            &mut bob_store,
+            &sender_address,
+            None
+        )
+        .await
+        .is_err());
+        process_sender_key_distribution_message(
+            &sender_address,
+            &SenderKeyDistributionMessage::try_from(reset.distribution_message.serialized())?,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            group_decrypt(
+                ciphertext.serialized(),
+                &mut bob_store,
+                &sender_address,
+                None
+            )
+            .await?,
+            b"space camp?"
+        );
+        assert!(group_decrypt(
+            ciphertext.serialized(),
+            &mut carol_store,
+            &sender_address,
+            None
+        )
+        .await
+        .is_err());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn group_associated_data() -> Result<(), SignalProtocolError> {
+    async {
+        let mut csprng = OsRng;
+
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1.into());
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &sender_address,
+            distribution_id,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        process_sender_key_distribution_message(
+            &sender_address,
+            &sent_distribution_message,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let envelope = b"timestamp=1700000000000;destination=group";
+        let ciphertext = group_encrypt_with_associated_data(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            "space camp?".as_bytes(),
+            envelope,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        for wrong in [&b""[..], b"timestamp=1700000000001;destination=group"] {
+            assert!(matches!(
+                group_decrypt_with_associated_data(
+                    ciphertext.serialized(),
+                    wrong,
+                    &mut bob_store,
+                    &sender_address,
+                    None,
+                )
+                .await,
+                Err(SignalProtocolError::SignatureValidationFailed)
+            ));
+        }
+        assert_eq!(
+            group_decrypt_with_associated_data(
+                ciphertext.serialized(),
+                envelope,
+                &mut bob_store,
+                &sender_address,
+                None,
+            )
+            .await?,
+            b"space camp?"
+        );
+
+        // Without associated data, messages are the same as from group_encrypt.
+        let ciphertext = group_encrypt_with_associated_data(
+            &mut alice_store,
+            &sender_address,
+            distribution_id,
+            "no envelope".as_bytes(),
+            &[],
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            group_decrypt(
+                ciphertext.serialized(),
+                &mut bob_store,
+                &sender_address,
+                None
+            )
+            .await?,
+            b"no envelope"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
@@ -0,0 +1,540 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Known-answer tests against `data/kat_vectors.json`.
+//!
+//! The vectors are meant to be shared with other implementations, so every input is spelled out in
+//! the file, including the random bytes each party consumes. The file's `description` field
+//! explains the format.
+//!
+//! After an intentional change to the wire format, regenerate the file with
+//!
+//! ```text
+//! cargo test -p libsignal-protocol --test kat -- --ignored --nocapture > tests/data/kat_vectors.json
+//! ```
+//!
+//! and strip the test harness's own output from the top and bottom.
+
+use futures_util::FutureExt;
+use libsignal_protocol::*;
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KatVectors {
+    description: Vec<String>,
+    sessions: Vec<SessionVector>,
+    sealed_sender: Vec<SealedSenderVector>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SessionVector {
+    name: String,
+    alice_identity_private: String,
+    alice_base_private: String,
+    bob_identity_private: String,
+    bob_signed_pre_key_private: String,
+    bob_one_time_pre_key_private: Option<String>,
+    alice_random: Vec<String>,
+    bob_random: Vec<String>,
+    messages: Vec<MessageVector>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MessageVector {
+    sender: Party,
+    plaintext: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Party {
+    Alice,
+    Bob,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedSenderVector {
+    name: String,
+    trust_root_public: String,
+    recipient_identity_private: String,
+    sender_identity_public: String,
+    sender_uuid: String,
+    sender_e164: Option<String>,
+    sender_device_id: u32,
+    validation_time: u64,
+    message_type: u8,
+    content_hint: u32,
+    group_id: Option<String>,
+    contents: String,
+    envelope: String,
+}
+
+const DESCRIPTION: &[&str] = &[
+    "Known-answer vectors for the Signal Protocol. All byte strings are hex.",
+    "",
+    "sessions: Alice starts a session with Bob from the given private keys, as in X3DH without a \
+     Kyber pre-key (message version 3); Bob's initial ratchet key is his signed pre-key. \
+     alice_random and bob_random give, in order, the bytes returned by each request that party makes \
+     to its random number generator (32 bytes per new ratchet private key). The messages are sent \
+     in order, each one encrypted by its sender and immediately decrypted by the other party; \
+     ciphertext is the serialized SignalMessage, including its MAC.",
+    "",
+    "sealed_sender: envelope is a version 1 sealed sender message for the recipient. Decrypting \
+     it must produce the given sender certificate fields, message type (a CiphertextMessageType), \
+     content hint, group ID, and contents, and the sender certificate must be valid for \
+     trust_root_public at validation_time (milliseconds since the Unix epoch).",
+];
+
+fn unhex(hex: &str) -> Vec<u8> {
+    hex::decode(hex).expect("valid hex")
+}
+
+fn private_key(hex: &str) -> Result<PrivateKey, SignalProtocolError> {
+    PrivateKey::deserialize(&unhex(hex))
+}
+
+/// Plays back the random bytes listed in a vector, failing the test if more are requested.
+struct ScriptedRng(VecDeque<u8>);
+
+impl ScriptedRng {
+    fn new(random: &[String]) -> Self {
+        Self(random.iter().flat_map(|bytes| unhex(bytes)).collect())
+    }
+}
+
+impl RngCore for ScriptedRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        assert!(dest.len() <= self.0.len(), "vector ran out of random bytes");
+        let len = dest.len();
+        for (byte, random) in dest.iter_mut().zip(self.0.drain(..len)) {
+            *byte = random;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for ScriptedRng {}
+
+/// Records every request made to an underlying generator, for writing new vectors.
+struct RecordingRng<R>(R, Vec<String>);
+
+impl<R: RngCore> RngCore for RecordingRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest);
+        self.1.push(hex::encode(dest));
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl<R: CryptoRng + RngCore> CryptoRng for RecordingRng<R> {}
+
+struct SessionKeys {
+    alice_identity: IdentityKeyPair,
+    alice_base: KeyPair,
+    bob_identity: IdentityKeyPair,
+    bob_signed_pre_key: KeyPair,
+    bob_one_time_pre_key: Option<KeyPair>,
+}
+
+impl SessionKeys {
+    fn from_vector(vector: &SessionVector) -> Result<Self, SignalProtocolError> {
+        Ok(Self {
+            alice_identity: IdentityKeyPair::try_from(private_key(
+                &vector.alice_identity_private,
+            )?)?,
+            alice_base: KeyPair::try_from(private_key(&vector.alice_base_private)?)?,
+            bob_identity: IdentityKeyPair::try_from(private_key(&vector.bob_identity_private)?)?,
+            bob_signed_pre_key: KeyPair::try_from(private_key(
+                &vector.bob_signed_pre_key_private,
+            )?)?,
+            bob_one_time_pre_key: vector
+                .bob_one_time_pre_key_private
+                .as_deref()
+                .map(|key| KeyPair::try_from(private_key(key)?))
+                .transpose()?,
+        })
+    }
+}
+
+/// Sets up the session described by `keys`, then sends each of `messages` and decrypts it on the
+/// other side, returning the ciphertexts.
+async fn run_session<'a>(
+    keys: &SessionKeys,
+    mut alice_rng: &'a mut dyn CryptoRngCore,
+    mut bob_rng: &'a mut dyn CryptoRngCore,
+    messages: &[(Party, Vec<u8>)],
+) -> Result<Vec<Vec<u8>>, SignalProtocolError> {
+    let alice_address = ProtocolAddress::new("alice".to_owned(), 1.into());
+    let bob_address = ProtocolAddress::new("bob".to_owned(), 1.into());
+
+    let mut alice_parameters = AliceSignalProtocolParameters::new(
+        keys.alice_identity,
+        keys.alice_base,
+        *keys.bob_identity.identity_key(),
+        keys.bob_signed_pre_key.public_key,
+        keys.bob_signed_pre_key.public_key,
+    );
+    if let Some(one_time_pre_key) = &keys.bob_one_time_pre_key {
+        alice_parameters.set_their_one_time_pre_key(one_time_pre_key.public_key);
+    }
+    let alice_record = initialize_alice_session_record(&alice_parameters, &mut alice_rng)?;
+
+    let bob_parameters = BobSignalProtocolParameters::new(
+        keys.bob_identity,
+        keys.bob_signed_pre_key,
+        keys.bob_one_time_pre_key,
+        keys.bob_signed_pre_key,
+        None,
+        *keys.alice_identity.identity_key(),
+        keys.alice_base.public_key,
+        None,
+    );
+    let bob_record = initialize_bob_session_record(&bob_parameters)?;
+
+    let mut alice_store = InMemSignalProtocolStore::new(keys.alice_identity, 1)?;
+    alice_store
+        .store_session(&bob_address, &alice_record, None)
+        .await?;
+    let mut bob_store = InMemSignalProtocolStore::new(keys.bob_identity, 2)?;
+    bob_store
+        .store_session(&alice_address, &bob_record, None)
+        .await?;
+
+    let mut ciphertexts = Vec::new();
+    for (sender, plaintext) in messages {
+        let (sender_store, recipient_store, recipient_rng, sender_address, recipient_address) =
+            match sender {
+                Party::Alice => (
+                    &mut alice_store,
+                    &mut bob_store,
+                    &mut bob_rng,
+                    &alice_address,
+                    &bob_address,
+                ),
+                Party::Bob => (
+                    &mut bob_store,
+                    &mut alice_store,
+                    &mut alice_rng,
+                    &bob_address,
+                    &alice_address,
+                ),
+            };
+        let ciphertext = message_encrypt(
+            plaintext,
+            recipient_address,
+            &mut sender_store.session_store,
+            &mut sender_store.identity_store,
+            None,
+        )
+        .await?;
+        assert_eq!(ciphertext.message_type(), CiphertextMessageType::Whisper);
+        let decrypted = message_decrypt(
+            &ciphertext,
+            sender_address,
+            &mut recipient_store.session_store,
+            &mut recipient_store.identity_store,
+            &mut recipient_store.pre_key_store,
+            &mut recipient_store.signed_pre_key_store,
+            &mut recipient_store.kyber_pre_key_store,
+            recipient_rng,
+            None,
+        )
+        .await?;
+        assert_eq!(&decrypted, plaintext);
+        ciphertexts.push(ciphertext.serialize().to_vec());
+    }
+    Ok(ciphertexts)
+}
+
+async fn check_sealed_sender(vector: &SealedSenderVector) -> Result<(), SignalProtocolError> {
+    let recipient_identity =
+        IdentityKeyPair::try_from(private_key(&vector.recipient_identity_private)?)?;
+    let mut recipient_store = InMemSignalProtocolStore::new(recipient_identity, 1)?;
+    let envelope = unhex(&vector.envelope);
+
+    let usmc =
+        sealed_sender_decrypt_to_usmc(&envelope, &mut recipient_store.identity_store, None).await?;
+    assert_eq!(
+        usmc.msg_type()?,
+        CiphertextMessageType::try_from(vector.message_type).expect("valid message type")
+    );
+    assert_eq!(u32::from(usmc.content_hint()?), vector.content_hint);
+    assert_eq!(
+        usmc.group_id()?.map(hex::encode),
+        vector.group_id.as_deref().map(str::to_owned)
+    );
+    assert_eq!(hex::encode(usmc.contents()?), vector.contents);
+
+    let sender = usmc.sender()?;
+    assert_eq!(sender.sender_uuid()?, vector.sender_uuid);
+    assert_eq!(sender.sender_e164()?, vector.sender_e164.as_deref());
+    assert_eq!(
+        u32::from(sender.sender_device_id()?),
+        vector.sender_device_id
+    );
+    assert_eq!(
+        hex::encode(sender.key()?.serialize()),
+        vector.sender_identity_public
+    );
+    let trust_root = PublicKey::deserialize(&unhex(&vector.trust_root_public))?;
+    assert!(sender.validate(
+        &trust_root,
+        Timestamp::from_epoch_millis(vector.validation_time)
+    )?);
+
+    let mut tampered = envelope;
+    *tampered.last_mut().expect("not empty") ^= 1;
+    assert!(
+        sealed_sender_decrypt_to_usmc(&tampered, &mut recipient_store.identity_store, None)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+fn load_vectors() -> KatVectors {
+    serde_json::from_slice(include_bytes!("data/kat_vectors.json")).expect("valid json")
+}
+
+#[test]
+fn test_session_vectors() -> Result<(), SignalProtocolError> {
+    let vectors = load_vectors();
+    assert!(!vectors.sessions.is_empty());
+    for vector in &vectors.sessions {
+        let keys = SessionKeys::from_vector(vector)?;
+        let mut alice_rng = ScriptedRng::new(&vector.alice_random);
+        let mut bob_rng = ScriptedRng::new(&vector.bob_random);
+        let messages: Vec<_> = vector
+            .messages
+            .iter()
+            .map(|message| (message.sender, unhex(&message.plaintext)))
+            .collect();
+
+        let ciphertexts = run_session(&keys, &mut alice_rng, &mut bob_rng, &messages)
+            .now_or_never()
+            .expect("sync")?;
+        for (message, ciphertext) in vector.messages.iter().zip(ciphertexts) {
+            assert_eq!(
+                hex::encode(ciphertext),
+                message.ciphertext,
+                "{}: {} sending {}",
+                vector.name,
+                if message.sender == Party::Alice {
+                    "Alice"
+                } else {
+                    "Bob"
+                },
+                message.plaintext
+            );
+        }
+        assert!(
+            alice_rng.0.is_empty(),
+            "{}: unused random bytes",
+            vector.name
+        );
+        assert!(bob_rng.0.is_empty(), "{}: unused random bytes", vector.name);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sealed_sender_vectors() -> Result<(), SignalProtocolError> {
+    let vectors = load_vectors();
+    assert!(!vectors.sealed_sender.is_empty());
+    for vector in &vectors.sealed_sender {
+        check_sealed_sender(vector).now_or_never().expect("sync")?;
+    }
+    Ok(())
+}
+
+fn generate_session_vector(
+    name: &str,
+    seed: u8,
+    with_one_time_pre_key: bool,
+) -> Result<SessionVector, SignalProtocolError> {
+    let mut rng = SeededRng::from_seed([seed; 32]);
+    let keys = SessionKeys {
+        alice_identity: IdentityKeyPair::generate(&mut rng),
+        alice_base: KeyPair::generate(&mut rng),
+        bob_identity: IdentityKeyPair::generate(&mut rng),
+        bob_signed_pre_key: KeyPair::generate(&mut rng),
+        bob_one_time_pre_key: if with_one_time_pre_key {
+            Some(KeyPair::generate(&mut rng))
+        } else {
+            None
+        },
+    };
+    let messages = vec![
+        (Party::Alice, b"first message from Alice".to_vec()),
+        (Party::Alice, b"second message from Alice".to_vec()),
+        (Party::Bob, b"reply from Bob".to_vec()),
+        (Party::Alice, b"Alice, after a ratchet step".to_vec()),
+        (Party::Bob, b"Bob, after another".to_vec()),
+    ];
+
+    let mut alice_rng = RecordingRng(SeededRng::from_seed([seed ^ 0xA0; 32]), Vec::new());
+    let mut bob_rng = RecordingRng(SeededRng::from_seed([seed ^ 0xB0; 32]), Vec::new());
+    let ciphertexts = run_session(&keys, &mut alice_rng, &mut bob_rng, &messages)
+        .now_or_never()
+        .expect("sync")?;
+
+    let private_hex = |key: &PrivateKey| hex::encode(key.serialize());
+    Ok(SessionVector {
+        name: name.to_owned(),
+        alice_identity_private: private_hex(keys.alice_identity.private_key()),
+        alice_base_private: private_hex(&keys.alice_base.private_key),
+        bob_identity_private: private_hex(keys.bob_identity.private_key()),
+        bob_signed_pre_key_private: private_hex(&keys.bob_signed_pre_key.private_key),
+        bob_one_time_pre_key_private: keys
+            .bob_one_time_pre_key
+            .map(|key| private_hex(&key.private_key)),
+        alice_random: alice_rng.1,
+        bob_random: bob_rng.1,
+        messages: messages
+            .into_iter()
+            .zip(ciphertexts)
+            .map(|((sender, plaintext), ciphertext)| MessageVector {
+                sender,
+                plaintext: hex::encode(plaintext),
+                ciphertext: hex::encode(ciphertext),
+            })
+            .collect(),
+    })
+}
+
+async fn generate_sealed_sender_vector(
+    name: &str,
+    seed: u8,
+    sender_e164: Option<&str>,
+    content_hint: ContentHint,
+    group_id: Option<&[u8]>,
+) -> Result<SealedSenderVector, SignalProtocolError> {
+    let mut rng = SeededRng::from_seed([seed; 32]);
+    let trust_root = KeyPair::generate(&mut rng);
+    let server_key = KeyPair::generate(&mut rng);
+    let sender_identity = IdentityKeyPair::generate(&mut rng);
+    let recipient_identity = IdentityKeyPair::generate(&mut rng);
+    let sender_uuid = uuid::Uuid::from_bytes(rng.gen()).to_string();
+    let sender_device_id = DeviceId::from(2);
+    let validation_time = Timestamp::from_epoch_millis(1_700_000_000_000);
+
+    let server_certificate =
+        ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+    let sender_certificate = SenderCertificate::new(
+        sender_uuid.clone(),
+        sender_e164.map(str::to_owned),
+        *sender_identity.public_key(),
+        sender_device_id,
+        validation_time + std::time::Duration::from_secs(60 * 60 * 24),
+        server_certificate,
+        &server_key.private_key,
+        &mut rng,
+    )?;
+    let contents = b"sealed sender contents".to_vec();
+    let usmc = UnidentifiedSenderMessageContent::new(
+        CiphertextMessageType::Whisper,
+        sender_certificate,
+        contents.clone(),
+        content_hint,
+        group_id.map(<[u8]>::to_vec),
+    )?;
+
+    let recipient_address = ProtocolAddress::new("recipient".to_owned(), 1.into());
+    let mut sender_store = InMemSignalProtocolStore::new(sender_identity, 1)?;
+    sender_store
+        .save_identity(&recipient_address, recipient_identity.identity_key(), None)
+        .await?;
+    let envelope = sealed_sender_encrypt_from_usmc(
+        &recipient_address,
+        &usmc,
+        &mut sender_store.identity_store,
+        None,
+        &mut rng,
+    )
+    .await?;
+
+    Ok(SealedSenderVector {
+        name: name.to_owned(),
+        trust_root_public: hex::encode(trust_root.public_key.serialize()),
+        recipient_identity_private: hex::encode(recipient_identity.private_key().serialize()),
+        sender_identity_public: hex::encode(sender_identity.public_key().serialize()),
+        sender_uuid,
+        sender_e164: sender_e164.map(str::to_owned),
+        sender_device_id: sender_device_id.into(),
+        validation_time: validation_time.epoch_millis(),
+        message_type: CiphertextMessageType::Whisper as u8,
+        content_hint: content_hint.into(),
+        group_id: group_id.map(hex::encode),
+        contents: hex::encode(contents),
+        envelope: hex::encode(envelope),
+    })
+}
+
+#[test]
+#[ignore = "prints new vectors; see the module documentation"]
+fn print_kat_vectors() -> Result<(), SignalProtocolError> {
+    let vectors = KatVectors {
+        description: DESCRIPTION.iter().map(|line| line.to_string()).collect(),
+        sessions: vec![
+            generate_session_vector("with one-time pre-key", 1, true)?,
+            generate_session_vector("without one-time pre-key", 2, false)?,
+        ],
+        sealed_sender: vec![
+            generate_sealed_sender_vector("minimal", 3, None, ContentHint::Default, None)
+                .now_or_never()
+                .expect("sync")?,
+            generate_sealed_sender_vector(
+                "with e164, content hint, and group ID",
+                4,
+                Some("+14151234567"),
+                ContentHint::Resendable,
+                Some(&[0x42; 32]),
+            )
+            .now_or_never()
+            .expect("sync")?,
+        ],
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&vectors).expect("can serialize")
+    );
+    Ok(())
+}
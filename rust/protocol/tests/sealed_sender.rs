@@ -10,6 +10,7 @@ use futures_util::FutureExt;
 use libsignal_protocol::*;
 use rand::rngs::OsRng;
 use std::convert::TryFrom;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[test]
@@ -78,6 +79,178 @@ fn test_revoked_server_cert() -> Result<(), SignalProtocolError> {
     Ok(())
 }
 
+#[test]
+fn test_revocation_provider() -> Result<(), SignalProtocolError> {
+    let mut rng = OsRng;
+    let trust_root = KeyPair::generate(&mut rng);
+    let server_key = KeyPair::generate(&mut rng);
+    let key = KeyPair::generate(&mut rng);
+
+    let server_cert =
+        ServerCertificate::new(7, server_key.public_key, &trust_root.private_key, &mut rng)?;
+    let expires = Timestamp::from_epoch_millis(1605722925);
+    let sender_cert = SenderCertificate::new(
+        "9d0652a3-dcc3-4d11-975f-74d61598733f".to_string(),
+        None,
+        key.public_key,
+        42.into(),
+        expires,
+        server_cert.clone(),
+        &server_key.private_key,
+        &mut rng,
+    )?;
+
+    let bundled = StaticRevocationList::default();
+    assert!(bundled.is_revoked(0xDEADC357));
+    assert!(server_cert.validate_with_revocation(&trust_root.public_key, &bundled)?);
+    assert!(sender_cert.validate_with_revocation(&trust_root.public_key, expires, &bundled)?);
+
+    let revoked = StaticRevocationList::default().with_revoked_key_ids([7]);
+    assert!(!server_cert.validate_with_revocation(&trust_root.public_key, &revoked)?);
+    assert!(!sender_cert.validate_with_revocation(&trust_root.public_key, expires, &revoked)?);
+
+    struct RevokeEverything;
+    impl RevocationProvider for RevokeEverything {
+        fn is_revoked(&self, _key_id: u32) -> bool {
+            true
+        }
+    }
+    assert!(!server_cert.validate_with_revocation(&trust_root.public_key, &RevokeEverything)?);
+
+    // An explicit empty list doesn't include the bundled revocations.
+    let revoked_id_cert = ServerCertificate::new(
+        0xDEADC357,
+        server_key.public_key,
+        &trust_root.private_key,
+        &mut rng,
+    )?;
+    assert!(revoked_id_cert
+        .validate_with_revocation(&trust_root.public_key, &StaticRevocationList::new(vec![]))?);
+
+    Ok(())
+}
+
+#[test]
+fn test_sender_validation() -> Result<(), SignalProtocolError> {
+    let mut rng = OsRng;
+    let trust_root = KeyPair::generate(&mut rng);
+    let server_key = KeyPair::generate(&mut rng);
+    let key = KeyPair::generate(&mut rng);
+
+    let server_cert =
+        ServerCertificate::new(7, server_key.public_key, &trust_root.private_key, &mut rng)?;
+    let local_uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+    let expires = Timestamp::from_epoch_millis(1605722925);
+    let usmc_from_device = |device_id: u32| -> Result<_, SignalProtocolError> {
+        let sender_cert = SenderCertificate::new(
+            local_uuid.to_string(),
+            None,
+            key.public_key,
+            device_id.into(),
+            expires,
+            server_cert.clone(),
+            &server_key.private_key,
+            &mut OsRng,
+        )?;
+        UnidentifiedSenderMessageContent::new(
+            CiphertextMessageType::Whisper,
+            sender_cert,
+            vec![],
+            ContentHint::Default,
+            None,
+        )
+    };
+    let before_expiry = expires;
+    let after_expiry = before_expiry + Duration::from_millis(1);
+
+    // A sync message from another of our devices is fine.
+    let validation = SenderValidation::new(&trust_root.public_key);
+    let from_other_device = usmc_from_device(2)?;
+    assert_eq!(
+        validation.validate(
+            &from_other_device,
+            before_expiry,
+            local_uuid,
+            None,
+            1.into()
+        )?,
+        ProtocolAddress::new(local_uuid.to_string(), 2.into())
+    );
+    assert!(matches!(
+        validation.validate(&from_other_device, after_expiry, local_uuid, None, 1.into()),
+        Err(SignalProtocolError::InvalidSealedSenderMessage(_))
+    ));
+
+    // One from this very device is only accepted on request.
+    let from_this_device = usmc_from_device(1)?;
+    assert!(matches!(
+        validation.validate(&from_this_device, before_expiry, local_uuid, None, 1.into()),
+        Err(SignalProtocolError::SealedSenderSelfSend)
+    ));
+    assert_eq!(
+        validation.allow_self_send().validate(
+            &from_this_device,
+            before_expiry,
+            local_uuid,
+            None,
+            1.into()
+        )?,
+        ProtocolAddress::new(local_uuid.to_string(), 1.into())
+    );
+
+    let revoked = StaticRevocationList::default().with_revoked_key_ids([7]);
+    assert!(matches!(
+        validation.with_revocation(&revoked).validate(
+            &from_other_device,
+            before_expiry,
+            local_uuid,
+            None,
+            1.into()
+        ),
+        Err(SignalProtocolError::InvalidSealedSenderMessage(_))
+    ));
+
+    // With an age policy, the envelope timestamp is also checked against the clock.
+    let policy = EnvelopeAgePolicy {
+        max_age: Duration::from_secs(60),
+        max_clock_skew: Duration::from_secs(60),
+    };
+    let clock = FixedClock(before_expiry);
+    let aged = validation.with_age_policy(policy, &clock);
+    assert_eq!(
+        aged.validate(
+            &from_other_device,
+            before_expiry - Duration::from_secs(30),
+            local_uuid,
+            None,
+            1.into()
+        )?,
+        ProtocolAddress::new(local_uuid.to_string(), 2.into())
+    );
+    assert!(matches!(
+        aged.validate(
+            &from_other_device,
+            before_expiry - Duration::from_secs(120),
+            local_uuid,
+            None,
+            1.into()
+        ),
+        Err(SignalProtocolError::EnvelopeTooOld { .. })
+    ));
+    assert!(matches!(
+        aged.validate(
+            &from_other_device,
+            before_expiry + Duration::from_secs(120),
+            local_uuid,
+            None,
+            1.into()
+        ),
+        Err(SignalProtocolError::EnvelopeFromFuture { .. })
+    ));
+
+    Ok(())
+}
+
 #[test]
 fn test_sender_cert() -> Result<(), SignalProtocolError> {
     let mut rng = OsRng;
@@ -89,7 +262,7 @@ fn test_sender_cert() -> Result<(), SignalProtocolError> {
         ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
     let device_id: DeviceId = 42.into();
-    let expires = 1605722925;
+    let expires = Timestamp::from_epoch_millis(1605722925);
 
     let sender_cert = SenderCertificate::new(
         "9d0652a3-dcc3-4d11-975f-74d61598733f".to_string(),
@@ -103,7 +276,7 @@ fn test_sender_cert() -> Result<(), SignalProtocolError> {
     )?;
 
     assert!(sender_cert.validate(&trust_root.public_key, expires)?);
-    assert!(!sender_cert.validate(&trust_root.public_key, expires + 1)?); // expired
+    assert!(!sender_cert.validate(&trust_root.public_key, expires + Duration::from_millis(1))?); // expired
 
     let mut sender_cert_data = sender_cert.serialized()?.to_vec();
     let sender_cert_bits = sender_cert_data.len() * 8;
@@ -171,7 +344,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
         let server_cert =
             ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -199,7 +372,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
         let bob_ptext = sealed_sender_decrypt(
             &alice_ctext,
             &trust_root.public_key,
-            expires - 1,
+            expires - Duration::from_millis(1),
             Some(bob_e164.clone()),
             bob_uuid.clone(),
             bob_device_id,
@@ -208,6 +381,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
             &mut bob_store.kyber_pre_key_store,
+            &mut rng,
             None,
         )
         .await?;
@@ -233,7 +407,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
         let bob_ptext = sealed_sender_decrypt(
             &alice_ctext,
             &trust_root.public_key,
-            expires + 11,
+            expires + Duration::from_millis(11),
             Some(bob_e164.clone()),
             bob_uuid.clone(),
             bob_device_id,
@@ -242,6 +416,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
             &mut bob_store.kyber_pre_key_store,
+            &mut rng,
             None,
         )
         .await;
@@ -274,7 +449,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
         let bob_ptext = sealed_sender_decrypt(
             &alice_ctext,
             &wrong_trust_root.public_key,
-            expires - 1,
+            expires - Duration::from_millis(1),
             Some(bob_e164.clone()),
             bob_uuid.clone(),
             bob_device_id,
@@ -283,6 +458,7 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
             &mut bob_store.kyber_pre_key_store,
+            &mut rng,
             None,
         )
         .await;
@@ -303,6 +479,144 @@ fn test_sealed_sender() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn test_sealed_sender_envelope_age_policy() -> Result<(), SignalProtocolError> {
+    async {
+        let mut rng = OsRng;
+
+        let alice_device_id: DeviceId = 23.into();
+        let bob_device_id: DeviceId = 42.into();
+
+        let alice_uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f".to_string();
+        let bob_uuid = "796abedb-ca4e-4f18-8803-1fde5b921f9f".to_string();
+
+        let bob_uuid_address = ProtocolAddress::new(bob_uuid.clone(), bob_device_id);
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+
+        let alice_pubkey = *alice_store.get_identity_key_pair(None).await?.public_key();
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut rng).await?;
+
+        process_prekey_bundle(
+            &bob_uuid_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut rng,
+            None,
+        )
+        .await?;
+
+        let trust_root = KeyPair::generate(&mut rng);
+        let server_key = KeyPair::generate(&mut rng);
+
+        let server_cert =
+            ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+
+        let now = Timestamp::from_epoch_millis(1605722925000);
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        // The certificate itself is valid far into the past and future, so only the age policy
+        // can reject these envelopes.
+        let sender_cert = SenderCertificate::new(
+            alice_uuid.clone(),
+            None,
+            alice_pubkey,
+            alice_device_id,
+            now + 365 * day,
+            server_cert,
+            &server_key.private_key,
+            &mut rng,
+        )?;
+
+        let alice_ptext = vec![1, 2, 3, 23, 99];
+        let alice_ctext = sealed_sender_encrypt(
+            &bob_uuid_address,
+            &sender_cert,
+            &alice_ptext,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            None,
+            &mut rng,
+        )
+        .await?;
+
+        let policy = EnvelopeAgePolicy {
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            max_clock_skew: Duration::from_secs(60 * 60),
+        };
+
+        for (timestamp, expect_too_old) in [
+            (now - 8 * day, true),
+            (now + Duration::from_secs(2 * 60 * 60), false),
+        ] {
+            let result = sealed_sender_decrypt_with_age_policy(
+                &alice_ctext,
+                &trust_root.public_key,
+                timestamp,
+                &policy,
+                &FixedClock(now),
+                None,
+                bob_uuid.clone(),
+                bob_device_id,
+                &mut bob_store.identity_store,
+                &mut bob_store.session_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut bob_store.kyber_pre_key_store,
+                &mut rng,
+                None,
+            )
+            .await;
+            match result {
+                Err(SignalProtocolError::EnvelopeTooOld {
+                    timestamp: t,
+                    now: n,
+                }) if expect_too_old => {
+                    assert_eq!((t, n), (timestamp, now));
+                }
+                Err(SignalProtocolError::EnvelopeFromFuture {
+                    timestamp: t,
+                    now: n,
+                }) if !expect_too_old => {
+                    assert_eq!((t, n), (timestamp, now));
+                }
+                Err(err) => panic!("Unexpected error {}", err),
+                Ok(_) => panic!("Shouldn't have decrypted"),
+            }
+        }
+
+        // The rejected attempts didn't consume anything, so the same envelope still decrypts when
+        // its timestamp is within the window.
+        let bob_ptext = sealed_sender_decrypt_with_age_policy(
+            &alice_ctext,
+            &trust_root.public_key,
+            now - 6 * day,
+            &policy,
+            &FixedClock(now),
+            None,
+            bob_uuid.clone(),
+            bob_device_id,
+            &mut bob_store.identity_store,
+            &mut bob_store.session_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut rng,
+            None,
+        )
+        .await?;
+        assert_eq!(bob_ptext.message, alice_ptext);
+        assert_eq!(bob_ptext.sender_uuid, alice_uuid);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn test_sender_key_in_sealed_sender() -> Result<(), SignalProtocolError> {
     async {
@@ -345,7 +659,7 @@ fn test_sender_key_in_sealed_sender() -> Result<(), SignalProtocolError> {
         let server_cert =
             ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -429,6 +743,298 @@ fn test_sender_key_in_sealed_sender() -> Result<(), SignalProtocolError> {
     .expect("sync")
 }
 
+#[test]
+fn test_sealed_sender_group_message_round_trip() -> Result<(), SignalProtocolError> {
+    async {
+        let mut rng = OsRng;
+
+        let alice_device_id: DeviceId = 23.into();
+        let bob_device_id: DeviceId = 42.into();
+        let alice_uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f".to_string();
+        let bob_uuid = "796abedb-ca4e-4f18-8803-1fde5b921f9f".to_string();
+        let alice_address = ProtocolAddress::new(alice_uuid.clone(), alice_device_id);
+        let bob_address = ProtocolAddress::new(bob_uuid.clone(), bob_device_id);
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+        let group_id = b"group ID".to_vec();
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let alice_pubkey = *alice_store.get_identity_key_pair(None).await?.public_key();
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut rng).await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut rng,
+            None,
+        )
+        .await?;
+
+        let trust_root = KeyPair::generate(&mut rng);
+        let server_key = KeyPair::generate(&mut rng);
+        let server_cert =
+            ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+        let expires = Timestamp::from_epoch_millis(1605722925);
+        let sender_cert = SenderCertificate::new(
+            alice_uuid.clone(),
+            None,
+            alice_pubkey,
+            alice_device_id,
+            expires,
+            server_cert,
+            &server_key.private_key,
+            &mut rng,
+        )?;
+
+        let distribution_message = create_sender_key_distribution_message(
+            &alice_address,
+            distribution_id,
+            &mut alice_store,
+            &mut rng,
+            None,
+        )
+        .await?;
+        process_sender_key_distribution_message(
+            &alice_address,
+            &distribution_message,
+            &mut bob_store,
+            None,
+        )
+        .await?;
+
+        let alice_message = group_encrypt(
+            &mut alice_store,
+            &alice_address,
+            distribution_id,
+            "swim camp".as_bytes(),
+            &mut rng,
+            None,
+        )
+        .await?;
+        let alice_ctext = sealed_sender_encrypt_message(
+            &bob_address,
+            &sender_cert,
+            &CiphertextMessage::SenderKeyMessage(alice_message),
+            Some(group_id.clone()),
+            &mut alice_store.identity_store,
+            None,
+            &mut rng,
+        )
+        .await?;
+
+        // Plain sealed_sender_decrypt has nowhere to look up sender keys.
+        assert!(matches!(
+            sealed_sender_decrypt(
+                &alice_ctext,
+                &trust_root.public_key,
+                expires - Duration::from_millis(1),
+                None,
+                bob_uuid.clone(),
+                bob_device_id,
+                &mut bob_store.identity_store,
+                &mut bob_store.session_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut bob_store.kyber_pre_key_store,
+                &mut rng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::SenderKey,
+                _
+            ))
+        ));
+
+        let bob_ptext = sealed_sender_decrypt_with_sender_keys(
+            &alice_ctext,
+            &trust_root.public_key,
+            expires - Duration::from_millis(1),
+            None,
+            bob_uuid.clone(),
+            bob_device_id,
+            &mut bob_store.identity_store,
+            &mut bob_store.session_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut bob_store.sender_key_store,
+            &mut rng,
+            None,
+        )
+        .await?;
+        assert_eq!(bob_ptext.message, b"swim camp");
+        assert_eq!(bob_ptext.sender_uuid, alice_uuid);
+        assert_eq!(bob_ptext.device_id, alice_device_id);
+        assert_eq!(bob_ptext.group_id()?, Some(&group_id[..]));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_group_encrypt_sealed() -> Result<(), SignalProtocolError> {
+    async {
+        let mut rng = OsRng;
+
+        let distribution_id = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+        let alice_uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f".to_string();
+        let alice_address = ProtocolAddress::new(alice_uuid.clone(), 1.into());
+        let bob_address =
+            ProtocolAddress::new("796abedb-ca4e-4f18-8803-1fde5b921f9f".to_owned(), 1.into());
+        let carol_address =
+            ProtocolAddress::new("38381c3b-2606-4ca7-9310-7cb927f2ab4a".to_owned(), 1.into());
+        let dave_address = ProtocolAddress::new("+14151111114".to_owned(), 1.into());
+
+        let mut alice_store = support::test_in_memory_protocol_store()?;
+        let alice_pubkey = *alice_store.get_identity_key_pair(None).await?.public_key();
+
+        let trust_root = KeyPair::generate(&mut rng);
+        let server_key = KeyPair::generate(&mut rng);
+        let server_cert =
+            ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
+        let sender_cert = SenderCertificate::new(
+            alice_uuid,
+            None,
+            alice_pubkey,
+            1.into(),
+            Timestamp::from_epoch_millis(1605722925),
+            server_cert,
+            &server_key.private_key,
+            &mut rng,
+        )?;
+
+        let distribution_message = create_sender_key_distribution_message(
+            &alice_address,
+            distribution_id,
+            &mut alice_store,
+            &mut rng,
+            None,
+        )
+        .await?;
+
+        let mut bob_store = support::test_in_memory_protocol_store()?;
+        let mut carol_store = support::test_in_memory_protocol_store()?;
+        for (address, store) in [
+            (&bob_address, &mut bob_store),
+            (&carol_address, &mut carol_store),
+        ] {
+            let bundle = create_pre_key_bundle(store, &mut rng).await?;
+            process_prekey_bundle(
+                address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut rng,
+                None,
+            )
+            .await?;
+            process_sender_key_distribution_message(
+                &alice_address,
+                &distribution_message,
+                store,
+                None,
+            )
+            .await?;
+        }
+
+        async fn open(
+            envelope: &[u8],
+            store: &mut InMemSignalProtocolStore,
+            sender: &ProtocolAddress,
+        ) -> Result<Vec<u8>, SignalProtocolError> {
+            let usmc =
+                sealed_sender_decrypt_to_usmc(envelope, &mut store.identity_store, None).await?;
+            assert_eq!(usmc.msg_type()?, CiphertextMessageType::SenderKey);
+            assert_eq!(usmc.group_id()?, Some(&b"group"[..]));
+            group_decrypt(usmc.contents()?, store, sender, None).await
+        }
+
+        // Everyone has a session and a ServiceId, so a single v2 message is produced.
+        let sent = group_encrypt_sealed(
+            &[&bob_address, &carol_address],
+            &alice_address,
+            distribution_id,
+            &sender_cert,
+            b"first",
+            ContentHint::Default,
+            Some(b"group".to_vec()),
+            &mut alice_store.sender_key_store,
+            &alice_store.session_store,
+            &mut alice_store.identity_store,
+            &mut rng,
+            None,
+        )
+        .await?;
+        let message = match sent {
+            SealedGroupMessage::MultiRecipient(message) => message,
+            other => panic!("expected a v2 message, got {:?}", other),
+        };
+        let received = sealed_sender_multi_recipient_fan_out(&message)?;
+        assert_eq!(received.len(), 2);
+        assert_eq!(
+            open(&received[0], &mut bob_store, &alice_address).await?,
+            b"first"
+        );
+        assert_eq!(
+            open(&received[1], &mut carol_store, &alice_address).await?,
+            b"first"
+        );
+
+        // Dave has no session and no ServiceId, so everyone gets a v1 envelope instead.
+        let mut dave_store = support::test_in_memory_protocol_store()?;
+        let dave_identity = *dave_store.get_identity_key_pair(None).await?.identity_key();
+        alice_store
+            .save_identity(&dave_address, &dave_identity, None)
+            .await?;
+        process_sender_key_distribution_message(
+            &alice_address,
+            &distribution_message,
+            &mut dave_store,
+            None,
+        )
+        .await?;
+
+        let sent = group_encrypt_sealed(
+            &[&bob_address, &carol_address, &dave_address],
+            &alice_address,
+            distribution_id,
+            &sender_cert,
+            b"second",
+            ContentHint::Default,
+            Some(b"group".to_vec()),
+            &mut alice_store.sender_key_store,
+            &alice_store.session_store,
+            &mut alice_store.identity_store,
+            &mut rng,
+            None,
+        )
+        .await?;
+        let envelopes = match sent {
+            SealedGroupMessage::PerRecipient(envelopes) => envelopes,
+            other => panic!("expected v1 envelopes, got {:?}", other),
+        };
+        assert_eq!(envelopes.len(), 3);
+        for (address, store) in [
+            (&bob_address, &mut bob_store),
+            (&carol_address, &mut carol_store),
+            (&dave_address, &mut dave_store),
+        ] {
+            assert_eq!(
+                open(&envelopes[address], store, &alice_address).await?,
+                b"second"
+            );
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
     async {
@@ -468,7 +1074,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
         let server_cert =
             ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -518,7 +1124,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
         let bob_ptext = sealed_sender_decrypt(
             &bob_ctext,
             &trust_root.public_key,
-            expires - 1,
+            expires - Duration::from_millis(1),
             Some(bob_e164.clone()),
             bob_uuid.clone(),
             bob_device_id,
@@ -527,6 +1133,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
             &mut bob_store.kyber_pre_key_store,
+            &mut rng,
             None,
         )
         .await?;
@@ -573,7 +1180,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
         let bob_ptext = sealed_sender_decrypt(
             &bob_ctext,
             &trust_root.public_key,
-            expires + 11,
+            expires + Duration::from_millis(11),
             Some(bob_e164.clone()),
             bob_uuid.clone(),
             bob_device_id,
@@ -582,6 +1189,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
             &mut bob_store.kyber_pre_key_store,
+            &mut rng,
             None,
         )
         .await;
@@ -636,7 +1244,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
         let bob_ptext = sealed_sender_decrypt(
             &bob_ctext,
             &wrong_trust_root.public_key,
-            expires - 1,
+            expires - Duration::from_millis(1),
             Some(bob_e164.clone()),
             bob_uuid.clone(),
             bob_device_id,
@@ -645,6 +1253,7 @@ fn test_sealed_sender_multi_recipient() -> Result<(), SignalProtocolError> {
             &mut bob_store.pre_key_store,
             &mut bob_store.signed_pre_key_store,
             &mut bob_store.kyber_pre_key_store,
+            &mut rng,
             None,
         )
         .await;
@@ -704,7 +1313,7 @@ fn test_sealed_sender_multi_recipient_encrypt_with_archived_session(
         let server_cert =
             ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -810,7 +1419,7 @@ fn test_sealed_sender_multi_recipient_encrypt_with_bad_registration_id(
         let server_cert =
             ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -936,7 +1545,7 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
         .await?;
 
         let original_ratchet_key = match bob_message {
-            CiphertextMessage::PreKeySignalMessage(ref m) => m.message().sender_ratchet_key(),
+            CiphertextMessage::PreKeySignalMessage(ref m) => m.message().sender_ratchet_key()?,
             _ => panic!("without ACKs, every message should be a PreKeySignalMessage"),
         };
 
@@ -949,7 +1558,7 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
         let server_cert =
             ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut rng)?;
 
-        let expires = 1605722925;
+        let expires = Timestamp::from_epoch_millis(1605722925);
 
         let sender_cert = SenderCertificate::new(
             alice_uuid.clone(),
@@ -965,7 +1574,7 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
         let error_message = DecryptionErrorMessage::for_original(
             bob_message.serialize(),
             bob_message.message_type(),
-            408,
+            Timestamp::from_epoch_millis(408),
             5,
         )?;
         let error_message_content = PlaintextContent::from(error_message);
@@ -1001,7 +1610,10 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
                 .expect("present");
 
         assert_eq!(bob_error_message.ratchet_key(), Some(original_ratchet_key));
-        assert_eq!(bob_error_message.timestamp(), 408);
+        assert_eq!(
+            bob_error_message.timestamp(),
+            Timestamp::from_epoch_millis(408)
+        );
         assert_eq!(bob_error_message.device_id(), 5);
 
         Ok(())
@@ -1009,3 +1621,36 @@ fn test_decryption_error_in_sealed_sender() -> Result<(), SignalProtocolError> {
     .now_or_never()
     .expect("sync")
 }
+
+#[test]
+fn test_sealed_sender_multi_recipient_fan_out_malformed() {
+    assert!(matches!(
+        sealed_sender_multi_recipient_fan_out(&[]),
+        Err(SignalProtocolError::InvalidSealedSenderMessage(_))
+    ));
+    assert!(matches!(
+        sealed_sender_multi_recipient_fan_out(&[0x11]),
+        Err(SignalProtocolError::UnknownSealedSenderVersion(1))
+    ));
+}
+
+#[test]
+fn test_unidentified_access_key() {
+    let profile_key: [u8; 32] = std::array::from_fn(|i| i as u8);
+    let access_key = derive_unidentified_access_key(&profile_key);
+    assert_eq!(hex::encode(access_key), "0ebcb5deb52c83bd08a8a935182c9199");
+
+    assert_eq!(
+        UnidentifiedAccessMode::Enabled.access_key(Some(&profile_key)),
+        Some(access_key)
+    );
+    assert_eq!(UnidentifiedAccessMode::Enabled.access_key(None), None);
+    assert_eq!(
+        UnidentifiedAccessMode::Disabled.access_key(Some(&profile_key)),
+        None
+    );
+    assert_eq!(
+        UnidentifiedAccessMode::Unrestricted.access_key(None),
+        Some(UNRESTRICTED_UNIDENTIFIED_ACCESS_KEY)
+    );
+}
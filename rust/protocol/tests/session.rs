@@ -4,10 +4,12 @@
 //
 mod support;
 
+use async_trait::async_trait;
 use futures_util::FutureExt;
 use libsignal_protocol::*;
 use rand::rngs::OsRng;
 use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
 use support::*;
 
 type TestResult = Result<(), SignalProtocolError>;
@@ -751,6 +753,77 @@ fn test_basic_session() -> TestResult {
     Ok(())
 }
 
+#[test]
+fn test_session_export_import() -> TestResult {
+    let mut csprng = OsRng;
+    let key = [0x42; 32];
+
+    let (alice_session, bob_session) = initialize_sessions_v4()?;
+    let exported = alice_session.export_secure(SessionArchiveKey::Key(&key), &mut csprng)?;
+    let imported = SessionRecord::import_secure(&exported, SessionArchiveKey::Key(&key))?;
+    assert_eq!(imported.serialize()?, alice_session.serialize()?);
+    run_session_interaction(imported, bob_session)?;
+
+    // Archived states come along too.
+    let mut archived = alice_session;
+    archived.archive_current_state()?;
+    let exported = archived.export_secure(SessionArchiveKey::Key(&key), &mut csprng)?;
+    let imported = SessionRecord::import_secure(&exported, SessionArchiveKey::Key(&key))?;
+    assert_eq!(imported.serialize()?, archived.serialize()?);
+    assert!(!imported.has_current_session_state());
+
+    // Keep the test fast; the iteration count is read back from the archive.
+    let password = |password| SessionArchiveKey::Password {
+        password,
+        iterations: 1000,
+    };
+    let password_exported = archived.export_secure(password(b"hunter2"), &mut csprng)?;
+    let imported = SessionRecord::import_secure(&password_exported, password(b"hunter2"))?;
+    assert_eq!(imported.serialize()?, archived.serialize()?);
+
+    assert!(matches!(
+        archived.export_secure(
+            SessionArchiveKey::Password {
+                password: b"hunter2",
+                iterations: 0,
+            },
+            &mut csprng,
+        ),
+        Err(SignalProtocolError::InvalidArgument(_))
+    ));
+    let mut no_iterations = password_exported.clone();
+    no_iterations[6..10].copy_from_slice(&0u32.to_be_bytes());
+    assert!(matches!(
+        SessionRecord::import_secure(&no_iterations, password(b"hunter2")),
+        Err(SignalProtocolError::InvalidSessionStructure(_))
+    ));
+
+    for (blob, key) in [
+        (&exported, SessionArchiveKey::Key(&[0x43; 32])),
+        (&exported, password(b"hunter2")),
+        (&password_exported, password(b"hunter3")),
+    ] {
+        assert!(matches!(
+            SessionRecord::import_secure(blob, key),
+            Err(SignalProtocolError::InvalidSessionStructure(_))
+        ));
+    }
+
+    let mut tampered = exported.clone();
+    let middle = tampered.len() / 2;
+    tampered[middle] ^= 1;
+    assert!(matches!(
+        SessionRecord::import_secure(&tampered, SessionArchiveKey::Key(&key)),
+        Err(SignalProtocolError::InvalidSessionStructure(_))
+    ));
+    assert!(matches!(
+        SessionRecord::import_secure(&archived.serialize()?, SessionArchiveKey::Key(&key)),
+        Err(SignalProtocolError::InvalidSessionStructure(_))
+    ));
+
+    Ok(())
+}
+
 #[test]
 fn test_message_key_limits() -> TestResult {
     run(initialize_sessions_v3()?)?;
@@ -2172,3 +2245,3579 @@ async fn is_session_id_equal(
             .expect("session found")
             .alice_base_key()?)
 }
+
+#[test]
+fn test_session_inspect() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_device_id: DeviceId = 1.into();
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), bob_device_id);
+
+        let mut bob_store_builder = TestStoreBuilder::new();
+        bob_store_builder.add_pre_key(IdChoice::Exactly(24));
+        bob_store_builder.add_signed_pre_key(IdChoice::Exactly(25));
+        bob_store_builder.add_kyber_pre_key(IdChoice::Exactly(26));
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(bob_device_id);
+
+        let mut alice_store_builder = TestStoreBuilder::new();
+        let alice_store = &mut alice_store_builder.store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let _ = encrypt(alice_store, &bob_address, "hello").await?;
+
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        let report = session_inspect::SessionRecordReport::from_record(&record)?;
+        assert!(report.previous_sessions.is_empty());
+
+        let state = report.current_session.expect("has current session");
+        assert_eq!(state.session_version, KYBER_AWARE_MESSAGE_VERSION);
+        assert_eq!(
+            state.remote_identity_key.as_ref(),
+            Some(bob_pre_key_bundle.identity_key()?)
+        );
+        assert_eq!(
+            state.remote_registration_id,
+            bob_pre_key_bundle.registration_id()?
+        );
+        assert_eq!(state.local_role, Some(SessionRole::Alice));
+        assert_eq!(state.root_key_length, 32);
+        assert!(state.receiver_chains.len() <= 1);
+
+        let sender_chain = state.sender_chain.expect("has sender chain");
+        assert!(sender_chain.has_sender_ratchet_private_key);
+        assert_eq!(sender_chain.chain_key_index, Some(1));
+
+        let pending = state.pending_pre_key.expect("not yet acknowledged");
+        assert_eq!(pending.pre_key_id, Some(24.into()));
+        assert_eq!(pending.signed_pre_key_id, 25.into());
+        assert_eq!(
+            pending.base_key.map(|key| key.serialize()),
+            Some(record.alice_base_key()?.into())
+        );
+
+        let pending_kyber = state.pending_kyber_pre_key.expect("not yet acknowledged");
+        assert_eq!(pending_kyber.pre_key_id, 26.into());
+
+        assert!(matches!(
+            session_inspect::SessionRecordReport::from_serialized(&[0xFF; 5]),
+            Err(SignalProtocolError::InvalidSessionStructure(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_health_accessors() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(record.sender_chain_length()?, Some(0));
+        assert_eq!(record.receiver_chain_lengths()?, vec![0]);
+        assert_eq!(record.last_used()?, None);
+        assert_eq!(record.archived_session_count(), 0);
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let first = encrypt(&mut alice_store, &bob_address, "one").await?;
+        let second = encrypt(&mut alice_store, &bob_address, "two").await?;
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(record.sender_chain_length()?, Some(2));
+        assert!(record.last_used()?.expect("just used") >= before);
+
+        let bob_store = &mut bob_store_builder.store;
+        decrypt(bob_store, &alice_address, &second).await?;
+        decrypt(bob_store, &alice_address, &first).await?;
+        let record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(record.receiver_chain_lengths()?, vec![2]);
+        assert_eq!(record.session_version()?, KYBER_AWARE_MESSAGE_VERSION);
+        assert_eq!(
+            record.remote_registration_id()?,
+            alice_store.get_local_registration_id(None).await?
+        );
+        assert!(record.last_used()?.is_some());
+
+        // New sessions archive the old ones, up to the store's limit.
+        alice_store
+            .session_store
+            .set_archive_policy(SessionArchivePolicy {
+                max_archived_states: 1,
+                ..Default::default()
+            });
+        for _ in 0..3 {
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+        }
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        assert_eq!(record.archived_session_count(), 1);
+        assert_eq!(record.last_used()?, None);
+
+        assert!(SessionRecord::new_fresh().sender_chain_length().is_err());
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_replay_cache() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let first = encrypt(&mut alice_store, &bob_address, "one").await?;
+        let second = encrypt(&mut alice_store, &bob_address, "two").await?;
+
+        let mut replay_cache = InMemReplayCache::default();
+        let mut bob_store = bob_store_builder.store;
+        let mut decrypt_with_cache =
+            |store: &mut InMemSignalProtocolStore, message: &CiphertextMessage| {
+                let result = message_decrypt_with_replay_cache(
+                    message,
+                    &alice_address,
+                    &mut store.session_store,
+                    &mut store.identity_store,
+                    &mut store.pre_key_store,
+                    &mut store.signed_pre_key_store,
+                    &mut store.kyber_pre_key_store,
+                    &mut replay_cache,
+                    WorkLimit::UNLIMITED,
+                    IdentityChangePolicy::AcceptNew,
+                    &mut csprng,
+                    None,
+                )
+                .now_or_never()
+                .expect("sync");
+                result.map(|result| result.plaintext)
+            };
+
+        assert_eq!(decrypt_with_cache(&mut bob_store, &first)?, b"one");
+
+        // Bob's session gets rolled back after decrypting the second message.
+        let backup = bob_store.clone();
+        assert_eq!(decrypt_with_cache(&mut bob_store, &second)?, b"two");
+        bob_store = backup;
+
+        // Without the cache, the message would be accepted again.
+        assert_eq!(
+            decrypt(&mut bob_store.clone(), &alice_address, &second).await?,
+            b"two"
+        );
+        assert!(matches!(
+            decrypt_with_cache(&mut bob_store, &second),
+            Err(SignalProtocolError::DuplicatedMessage(2, 1))
+        ));
+        // The rejected replay didn't change the session.
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &second).await?,
+            b"two"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_reset() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let bob_store = &mut bob_store_builder.store;
+
+        let message = encrypt(&mut alice_store, &bob_address, "one").await?;
+        decrypt(bob_store, &alice_address, &message).await?;
+        let message = encrypt(bob_store, &alice_address, "two").await?;
+        decrypt(&mut alice_store, &bob_address, &message).await?;
+        let message = encrypt(&mut alice_store, &bob_address, "three").await?;
+        decrypt(bob_store, &alice_address, &message).await?;
+
+        let reset = session_reset(
+            &alice_address,
+            &mut bob_store.session_store,
+            Timestamp::from_epoch_millis(1234),
+            None,
+        )
+        .await?;
+        assert_eq!(reset.message_type(), CiphertextMessageType::Plaintext);
+        let bob_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+        assert!(!bob_record.has_current_session_state());
+        assert_eq!(bob_record.archived_session_count(), 1);
+
+        let reset_message = extract_decryption_error_message_from_serialized_content(
+            PlaintextContent::try_from(reset.serialize())?.body(),
+        )?;
+        assert_eq!(
+            reset_message.timestamp(),
+            Timestamp::from_epoch_millis(1234)
+        );
+        assert_eq!(reset_message.device_id(), 1);
+        assert!(
+            process_session_reset(
+                &reset_message,
+                &bob_address,
+                &mut alice_store.session_store,
+                None
+            )
+            .await?
+        );
+        assert!(!alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found")
+            .has_current_session_state());
+
+        // There's nothing left to reset.
+        assert!(
+            !process_session_reset(
+                &reset_message,
+                &bob_address,
+                &mut alice_store.session_store,
+                None
+            )
+            .await?
+        );
+        assert!(matches!(
+            session_reset(
+                &alice_address,
+                &mut bob_store.session_store,
+                Timestamp::from_epoch_millis(1234),
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+        assert!(matches!(
+            encrypt(&mut alice_store, &bob_address, "four").await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_padded_messages() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let bob_store = &mut bob_store_builder.store;
+
+        let config = SessionConfig {
+            padding_bucket_size: Some(DEFAULT_PADDING_BUCKET_SIZE),
+        };
+        let mut messages = Vec::new();
+        for _ in 0..2 {
+            messages.push(
+                message_encrypt_with_config(
+                    b"hello",
+                    &bob_address,
+                    &mut alice_store.session_store,
+                    &mut alice_store.identity_store,
+                    config,
+                    None,
+                )
+                .await?,
+            );
+        }
+
+        let plaintext = message_decrypt_with_config(
+            &messages[0],
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            config,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(plaintext, b"hello");
+
+        // A receiver that doesn't expect padding sees it.
+        let plaintext = decrypt(bob_store, &alice_address, &messages[1]).await?;
+        assert_eq!(plaintext.len(), DEFAULT_PADDING_BUCKET_SIZE);
+        assert_eq!(unpad_plaintext(&plaintext)?, b"hello");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[derive(Default)]
+struct RecordingObserver(std::sync::Mutex<Vec<String>>);
+
+impl RecordingObserver {
+    fn record(&self, event: String) {
+        self.0.lock().expect("not poisoned").push(event);
+    }
+
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().expect("not poisoned"))
+    }
+}
+
+impl ProtocolObserver for RecordingObserver {
+    fn session_created(&self, address: &ProtocolAddress) {
+        self.record(format!("session_created {}", address));
+    }
+    fn ratchet_stepped(&self, address: &ProtocolAddress) {
+        self.record(format!("ratchet_stepped {}", address));
+    }
+    fn mac_failure(&self, address: &ProtocolAddress) {
+        self.record(format!("mac_failure {}", address));
+    }
+    fn duplicate_message(&self, address: &ProtocolAddress, chain_index: u32, counter: u32) {
+        self.record(format!(
+            "duplicate_message {} {} {}",
+            address, chain_index, counter
+        ));
+    }
+}
+
+#[test]
+fn test_protocol_observer() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        let alice_observer = std::sync::Arc::new(RecordingObserver::default());
+        let bob_observer = std::sync::Arc::new(RecordingObserver::default());
+
+        with_observer(
+            alice_observer.clone(),
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            ),
+        )
+        .await?;
+        assert_eq!(
+            alice_observer.take(),
+            [format!("session_created {}", bob_address)]
+        );
+
+        let first = encrypt(&mut alice_store, &bob_address, "one").await?;
+        with_observer(
+            bob_observer.clone(),
+            decrypt(&mut bob_store, &alice_address, &first),
+        )
+        .await?;
+        assert_eq!(
+            bob_observer.take(),
+            [
+                format!("session_created {}", alice_address),
+                format!("ratchet_stepped {}", alice_address),
+            ]
+        );
+
+        assert!(matches!(
+            with_observer(
+                bob_observer.clone(),
+                decrypt(&mut bob_store, &alice_address, &first),
+            )
+            .await,
+            Err(SignalProtocolError::DuplicatedMessage(1, 0))
+        ));
+        assert_eq!(
+            bob_observer.take(),
+            [format!("duplicate_message {} 1 0", alice_address)]
+        );
+
+        let reply = encrypt(&mut bob_store, &alice_address, "two").await?;
+        with_observer(
+            alice_observer.clone(),
+            decrypt(&mut alice_store, &bob_address, &reply),
+        )
+        .await?;
+        assert_eq!(
+            alice_observer.take(),
+            [format!("ratchet_stepped {}", bob_address)]
+        );
+
+        let mut tampered = encrypt(&mut alice_store, &bob_address, "three")
+            .await?
+            .serialize()
+            .to_vec();
+        *tampered.last_mut().expect("not empty") ^= 1;
+        let tampered = CiphertextMessage::SignalMessage(SignalMessage::try_from(&tampered[..])?);
+        assert!(with_observer(
+            bob_observer.clone(),
+            decrypt(&mut bob_store, &alice_address, &tampered),
+        )
+        .await
+        .is_err());
+        assert_eq!(
+            bob_observer.take(),
+            [format!("mac_failure {}", alice_address)]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_single_protocol_store_round_trip() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        process_prekey_bundle_with_protocol_store(
+            &bob_address,
+            &mut alice_store,
+            &bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message =
+            message_encrypt_with_protocol_store(b"hello", &bob_address, &mut alice_store, None)
+                .await?;
+        // Any ProtocolStore works, including a trait object.
+        let bob_store: &mut dyn ProtocolStore = &mut bob_store;
+        let plaintext = message_decrypt_with_protocol_store(
+            &message,
+            &alice_address,
+            bob_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(plaintext, b"hello");
+
+        let reply =
+            message_encrypt_with_protocol_store(b"hi", &alice_address, bob_store, None).await?;
+        assert_eq!(reply.message_type(), CiphertextMessageType::Whisper);
+        let plaintext = message_decrypt_with_protocol_store(
+            &reply,
+            &bob_address,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(plaintext, b"hi");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_store_snapshot_and_restore() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        process_prekey_bundle_with_protocol_store(
+            &bob_address,
+            &mut alice_store,
+            &bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        let second = encrypt(&mut alice_store, &bob_address, "second").await?;
+
+        let bob_before = bob_store.snapshot();
+
+        // Deliver in order...
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &first).await?,
+            b"first"
+        );
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &second).await?,
+            b"second"
+        );
+        assert!(decrypt(&mut bob_store, &alice_address, &first)
+            .await
+            .is_err());
+
+        // ...then go back and deliver out of order from the same starting point.
+        bob_store.restore(&bob_before);
+        assert!(bob_store.all_session_addresses(None).await?.is_empty());
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &second).await?,
+            b"second"
+        );
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &first).await?,
+            b"first"
+        );
+
+        // The snapshot is unaffected by what happened after restoring it.
+        bob_store.restore(&bob_before);
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &first).await?,
+            b"first"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[derive(Default)]
+struct SessionUpdates(std::sync::Mutex<Vec<ProtocolAddress>>);
+
+impl ProtocolObserver for SessionUpdates {
+    fn session_updated(&self, address: &ProtocolAddress) {
+        self.0.lock().expect("not poisoned").push(address.clone());
+    }
+}
+
+#[test]
+fn test_session_updates_are_observed() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        let updates = std::sync::Arc::new(SessionUpdates::default());
+        let take = || std::mem::take(&mut *updates.0.lock().expect("not poisoned"));
+
+        with_observer(updates.clone(), async {
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+            decrypt(&mut bob_store, &alice_address, &message).await?;
+            Ok::<_, SignalProtocolError>(())
+        })
+        .await?;
+        assert_eq!(
+            take(),
+            [
+                bob_address.clone(),
+                bob_address.clone(),
+                alice_address.clone()
+            ]
+        );
+
+        // Outside of with_observer, nothing is reported.
+        let message = encrypt(&mut alice_store, &bob_address, "again").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        assert_eq!(take(), []);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_flow_statistics() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut messages = Vec::new();
+        for text in ["one", "two", "three"] {
+            messages.push(encrypt(&mut alice_store, &bob_address, text).await?);
+        }
+        for message in &messages {
+            decrypt(bob_store, &alice_address, message).await?;
+        }
+
+        // Bob's copy of the last message keeps coming back.
+        let last = messages.last().expect("not empty");
+        for _ in 0..2 {
+            assert!(matches!(
+                decrypt(bob_store, &alice_address, last).await,
+                Err(SignalProtocolError::DuplicatedMessage(_, 2))
+            ));
+        }
+
+        let flow_report = |record: SessionRecord| -> Result<_, SignalProtocolError> {
+            Ok(session_inspect::SessionRecordReport::from_record(&record)?
+                .current_session
+                .expect("has current session")
+                .flow
+                .expect("has flow statistics"))
+        };
+
+        let alice_flow = flow_report(
+            alice_store
+                .load_session(&bob_address, None)
+                .await?
+                .expect("session found"),
+        )?;
+        assert_eq!(alice_flow.sent + alice_flow.previous_sent, 3);
+        assert_eq!(alice_flow.repeated_duplicate_counter, None);
+
+        let bob_flow = flow_report(
+            bob_store
+                .load_session(&alice_address, None)
+                .await?
+                .expect("session found"),
+        )?;
+        assert_eq!(bob_flow.received + bob_flow.previous_received, 3);
+        assert_eq!(bob_flow.duplicates + bob_flow.previous_duplicates, 2);
+        assert_eq!(bob_flow.repeated_duplicate_counter, Some(2));
+        assert_eq!(bob_flow.repeated_duplicates, 2);
+
+        // A fresh message ends the run.
+        let next = encrypt(&mut alice_store, &bob_address, "four").await?;
+        decrypt(bob_store, &alice_address, &next).await?;
+        let bob_flow = flow_report(
+            bob_store
+                .load_session(&alice_address, None)
+                .await?
+                .expect("session found"),
+        )?;
+        assert_eq!(bob_flow.repeated_duplicate_counter, None);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_message_decrypt_with_info() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_device_id: DeviceId = 1.into();
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), bob_device_id);
+
+        let mut bob_store_builder = TestStoreBuilder::new();
+        bob_store_builder.add_pre_key(IdChoice::Exactly(24));
+        bob_store_builder.add_signed_pre_key(IdChoice::Exactly(25));
+        bob_store_builder.add_kyber_pre_key(IdChoice::Exactly(26));
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(bob_device_id);
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut alice_store_builder = TestStoreBuilder::new();
+        let alice_store = &mut alice_store_builder.store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        async fn decrypt_with_info(
+            store: &mut InMemSignalProtocolStore,
+            remote_address: &ProtocolAddress,
+            msg: &CiphertextMessage,
+        ) -> Result<DecryptResult, SignalProtocolError> {
+            message_decrypt_with_info(
+                msg,
+                remote_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                &mut store.pre_key_store,
+                &mut store.signed_pre_key_store,
+                &mut store.kyber_pre_key_store,
+                &mut OsRng,
+                None,
+            )
+            .await
+        }
+
+        let first = encrypt(alice_store, &bob_address, "first").await?;
+        let second = encrypt(alice_store, &bob_address, "second").await?;
+        assert_eq!(second.message_type(), CiphertextMessageType::PreKey);
+
+        let result = decrypt_with_info(bob_store, &alice_address, &first).await?;
+        assert_eq!(result.plaintext, b"first");
+        assert_eq!(result.message_version, KYBER_AWARE_MESSAGE_VERSION as u8);
+        assert_eq!(result.counter, 0);
+        assert!(result.session_was_created);
+        assert_eq!(result.pre_key_used, Some(24.into()));
+        assert_eq!(result.signed_pre_key_used, Some(25.into()));
+        assert_eq!(result.kyber_pre_key_used, Some(26.into()));
+
+        let result = decrypt_with_info(bob_store, &alice_address, &second).await?;
+        assert_eq!(result.plaintext, b"second");
+        assert_eq!(result.counter, 1);
+        assert!(!result.session_was_created);
+        assert_eq!(result.pre_key_used, None);
+        assert_eq!(result.signed_pre_key_used, None);
+
+        let reply = encrypt(bob_store, &alice_address, "reply").await?;
+        assert_eq!(reply.message_type(), CiphertextMessageType::Whisper);
+        let result = decrypt_with_info(alice_store, &bob_address, &reply).await?;
+        assert_eq!(result.plaintext, b"reply");
+        assert_eq!(result.counter, 0);
+        assert!(!result.session_was_created);
+        assert_eq!(result.pre_key_used, None);
+        assert_eq!(result.signed_pre_key_used, None);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_decrypt_error_context() -> TestResult {
+    async {
+        let (alice_session, bob_session) = initialize_sessions_v4()?;
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let mut bob_store = test_in_memory_protocol_store()?;
+        alice_store
+            .store_session(&bob_address, &alice_session, None)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session, None)
+            .await?;
+
+        let message = encrypt(&mut alice_store, &bob_address, "hello").await?;
+        let mut corrupted = message.serialize().to_vec();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        let corrupted = CiphertextMessage::SignalMessage(SignalMessage::try_from(&corrupted[..])?);
+
+        let err = contextual::message_decrypt(
+            &corrupted,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut OsRng,
+            None,
+        )
+        .await
+        .expect_err("MAC is wrong");
+
+        assert_eq!(err.context().operation, ProtocolOperation::MessageDecrypt);
+        assert_eq!(err.context().remote_address.as_ref(), Some(&alice_address));
+        assert_eq!(
+            err.context().message_type,
+            Some(CiphertextMessageType::Whisper)
+        );
+        assert!(matches!(
+            err.error(),
+            SignalProtocolError::InvalidMessage(CiphertextMessageType::Whisper, _)
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "message_decrypt of Whisper message for +14151111111.1 failed: {}",
+                err.error()
+            )
+        );
+
+        // The original message still decrypts.
+        assert_eq!(
+            String::from_utf8(decrypt(&mut bob_store, &alice_address, &message).await?)
+                .expect("valid utf8"),
+            "hello"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_message_ordering_tokens() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let bob_store = &mut bob_store_builder.store;
+        let alice_store = &mut TestStoreBuilder::new().store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        async fn decrypt_token(
+            store: &mut InMemSignalProtocolStore,
+            remote_address: &ProtocolAddress,
+            msg: &CiphertextMessage,
+        ) -> Result<MessageOrderingToken, SignalProtocolError> {
+            let result = message_decrypt_with_info(
+                msg,
+                remote_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                &mut store.pre_key_store,
+                &mut store.signed_pre_key_store,
+                &mut store.kyber_pre_key_store,
+                &mut OsRng,
+                None,
+            )
+            .await?;
+            Ok(result.ordering_token.expect("new sessions track ordering"))
+        }
+
+        // Alice sends on three chains, hearing back from Bob in between.
+        let mut sent = vec![];
+        for round in 0..3 {
+            for i in 0..3 {
+                sent.push(encrypt(alice_store, &bob_address, &format!("{} {}", round, i)).await?);
+            }
+            decrypt_token(bob_store, &alice_address, &sent[round * 3]).await?;
+            let reply = encrypt(bob_store, &alice_address, "ack").await?;
+            decrypt(alice_store, &bob_address, &reply).await?;
+        }
+
+        // Deliver the rest in reverse.
+        let mut tokens = vec![];
+        for (i, message) in sent.iter().enumerate().rev() {
+            if i % 3 != 0 {
+                tokens.push((i, decrypt_token(bob_store, &alice_address, message).await?));
+            }
+        }
+        tokens.sort_by(|(_, a), (_, b)| a.partial_cmp(b).expect("same session"));
+        let order: Vec<usize> = tokens.iter().map(|(i, _)| *i).collect();
+        assert_eq!(order, vec![1, 2, 4, 5, 7, 8]);
+
+        assert_eq!(tokens[2].1.is_on_later_chain_than(&tokens[1].1), Some(true));
+        assert_eq!(
+            tokens[1].1.is_on_later_chain_than(&tokens[0].1),
+            Some(false)
+        );
+
+        for (_, token) in &tokens {
+            assert_eq!(
+                &MessageOrderingToken::deserialize(&token.serialize())?,
+                token
+            );
+        }
+        assert!(MessageOrderingToken::deserialize(&[]).is_err());
+
+        // A brand new session's tokens can't be compared with the old ones.
+        let mut bob_store_builder = TestStoreBuilder::from_store(bob_store)
+            .with_pre_key(IdChoice::Exactly(10))
+            .with_signed_pre_key(IdChoice::Exactly(11))
+            .with_kyber_pre_key(IdChoice::Exactly(12));
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        alice_store
+            .session_store
+            .delete_session(&bob_address, None)
+            .await?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(alice_store, &bob_address, "fresh").await?;
+        let fresh = decrypt_token(&mut bob_store_builder.store, &alice_address, &message).await?;
+        assert!(!fresh.is_same_session(&tokens[0].1));
+        assert_eq!(fresh.partial_cmp(&tokens[0].1), None);
+        assert_eq!(fresh.is_on_later_chain_than(&tokens[0].1), None);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_header_encrypted_session() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder
+            .make_bundle_with_latest_keys(1.into())
+            .with_header_encryption_support();
+        assert!(bob_pre_key_bundle.supports_header_encryption());
+        let bob_store = &mut bob_store_builder.store;
+        let alice_store = &mut TestStoreBuilder::new().store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let first = encrypt(alice_store, &bob_address, "first").await?;
+        let first_message = match &first {
+            CiphertextMessage::PreKeySignalMessage(m) => m,
+            other => panic!("unexpected {:?}", other.message_type()),
+        };
+        assert_eq!(first_message.message_version(), 5);
+        assert!(first_message.message().has_encrypted_header());
+        let reparsed = PreKeySignalMessage::try_from(first.serialize())?;
+        assert!(reparsed.message().has_encrypted_header());
+        let error_message = DecryptionErrorMessage::for_original(
+            first.serialize(),
+            CiphertextMessageType::PreKey,
+            Timestamp::UNIX_EPOCH,
+            1,
+        )?;
+        assert_eq!(error_message.ratchet_key(), None);
+
+        let reparsed = CiphertextMessage::PreKeySignalMessage(reparsed);
+        assert_eq!(
+            decrypt(bob_store, &alice_address, &reparsed).await?,
+            b"first"
+        );
+
+        let reply = encrypt(bob_store, &alice_address, "reply").await?;
+        let reparsed = SignalMessage::try_from(reply.serialize())?;
+        assert_eq!(reparsed.message_version(), 5);
+        assert!(reparsed.has_encrypted_header());
+        let reparsed = CiphertextMessage::SignalMessage(reparsed);
+        assert_eq!(
+            decrypt(alice_store, &bob_address, &reparsed).await?,
+            b"reply"
+        );
+
+        run_interaction(alice_store, &alice_address, bob_store, &bob_address).await?;
+
+        // A message from an unrelated session can't be opened.
+        let mut carol_store = TestStoreBuilder::new().store;
+        let mut bob_store_builder = TestStoreBuilder::from_store(bob_store)
+            .with_pre_key(IdChoice::Exactly(10))
+            .with_signed_pre_key(IdChoice::Exactly(11))
+            .with_kyber_pre_key(IdChoice::Exactly(12));
+        process_prekey_bundle(
+            &bob_address,
+            &mut carol_store.session_store,
+            &mut carol_store.identity_store,
+            &bob_store_builder
+                .make_bundle_with_latest_keys(1.into())
+                .with_header_encryption_support(),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let carol_message = match encrypt(&mut carol_store, &bob_address, "hi").await? {
+            CiphertextMessage::PreKeySignalMessage(m) => {
+                CiphertextMessage::SignalMessage(m.message().clone())
+            }
+            other => panic!("unexpected {:?}", other.message_type()),
+        };
+        assert!(matches!(
+            decrypt(&mut bob_store_builder.store, &alice_address, &carol_message).await,
+            Err(SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Whisper,
+                _
+            ))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_reset_with_header_encryption() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder
+            .make_bundle_with_latest_keys(1.into())
+            .with_header_encryption_support();
+        let bob_store = &mut bob_store_builder.store;
+        let alice_store = &mut TestStoreBuilder::new().store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = encrypt(alice_store, &bob_address, "one").await?;
+        decrypt(bob_store, &alice_address, &message).await?;
+        let message = encrypt(bob_store, &alice_address, "two").await?;
+        decrypt(alice_store, &bob_address, &message).await?;
+
+        // The peer couldn't tell which session a reset names, so neither side archives anything.
+        assert!(matches!(
+            session_reset(
+                &alice_address,
+                &mut bob_store.session_store,
+                Timestamp::from_epoch_millis(1234),
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::InvalidState("session_reset", _))
+        ));
+        for (store, address) in [
+            (&mut *alice_store, &bob_address),
+            (&mut *bob_store, &alice_address),
+        ] {
+            let record = store
+                .load_session(address, None)
+                .await?
+                .expect("session found");
+            assert!(record.has_current_session_state());
+            assert_eq!(record.archived_session_count(), 0);
+        }
+
+        let message = encrypt(bob_store, &alice_address, "still here").await?;
+        assert_eq!(
+            decrypt(alice_store, &bob_address, &message).await?,
+            b"still here"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_header_encryption_not_negotiated() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        // Without the bundle's support flag, or without a Kyber pre-key, sessions use plain
+        // headers.
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let with_kyber = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let without_kyber = with_kyber
+            .clone()
+            .modify(|content| {
+                content.kyber_pre_key_id = None;
+                content.kyber_pre_key_public = None;
+                content.kyber_pre_key_signature = None;
+            })?
+            .with_header_encryption_support();
+
+        for (bundle, expected_version) in [(with_kyber, 4), (without_kyber, 3)] {
+            let alice_store = &mut TestStoreBuilder::new().store;
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+            match encrypt(alice_store, &bob_address, "hello").await? {
+                CiphertextMessage::PreKeySignalMessage(m) => {
+                    assert_eq!(m.message_version(), expected_version);
+                    assert!(!m.message().has_encrypted_header());
+                }
+                other => panic!("unexpected {:?}", other.message_type()),
+            }
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_aead_session() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        for (header_encryption, expected_version) in [(false, 6), (true, 7)] {
+            let mut bob_store_builder = TestStoreBuilder::new()
+                .with_pre_key(IdChoice::Next)
+                .with_signed_pre_key(IdChoice::Next)
+                .with_kyber_pre_key(IdChoice::Next);
+            let mut bob_pre_key_bundle = bob_store_builder
+                .make_bundle_with_latest_keys(1.into())
+                .with_aead_support();
+            if header_encryption {
+                bob_pre_key_bundle = bob_pre_key_bundle.with_header_encryption_support();
+            }
+            assert!(bob_pre_key_bundle.supports_aead());
+            let bob_store = &mut bob_store_builder.store;
+            let alice_store = &mut TestStoreBuilder::new().store;
+
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+
+            let first = encrypt(alice_store, &bob_address, "first").await?;
+            let reparsed = PreKeySignalMessage::try_from(first.serialize())?;
+            assert_eq!(reparsed.message_version(), expected_version);
+            assert_eq!(reparsed.message().has_encrypted_header(), header_encryption);
+            assert!(matches!(
+                reparsed.message().verify_mac(
+                    reparsed.identity_key(),
+                    reparsed.identity_key(),
+                    &[0; 32]
+                ),
+                Err(SignalProtocolError::InvalidArgument(_))
+            ));
+            assert_eq!(
+                decrypt(
+                    bob_store,
+                    &alice_address,
+                    &CiphertextMessage::PreKeySignalMessage(reparsed)
+                )
+                .await?,
+                b"first"
+            );
+
+            // Flipping any bit of the body or the tag breaks the message.
+            let reply = encrypt(bob_store, &alice_address, "reply").await?;
+            for index in [reply.serialize().len() - 20, reply.serialize().len() - 1] {
+                let mut tampered = reply.serialize().to_vec();
+                tampered[index] ^= 1;
+                let tampered =
+                    CiphertextMessage::SignalMessage(SignalMessage::try_from(tampered.as_slice())?);
+                assert!(matches!(
+                    decrypt(alice_store, &bob_address, &tampered).await,
+                    Err(SignalProtocolError::InvalidMessage(
+                        CiphertextMessageType::Whisper,
+                        _
+                    ))
+                ));
+            }
+            let reparsed = SignalMessage::try_from(reply.serialize())?;
+            assert_eq!(reparsed.message_version(), expected_version);
+            assert_eq!(
+                decrypt(
+                    alice_store,
+                    &bob_address,
+                    &CiphertextMessage::SignalMessage(reparsed)
+                )
+                .await?,
+                b"reply"
+            );
+
+            run_interaction(alice_store, &alice_address, bob_store, &bob_address).await?;
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_aead_not_negotiated_without_kyber() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next);
+        let bundle = bob_store_builder
+            .make_bundle_with_latest_keys(1.into())
+            .with_aead_support();
+        let alice_store = &mut TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let message = encrypt(alice_store, &bob_address, "hello").await?;
+        match &message {
+            CiphertextMessage::PreKeySignalMessage(m) => assert_eq!(m.message_version(), 3),
+            other => panic!("unexpected {:?}", other.message_type()),
+        }
+        assert_eq!(
+            decrypt(&mut bob_store_builder.store, &alice_address, &message).await?,
+            b"hello"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_associated_data() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        // Both the HMAC and the AEAD message versions bind the associated data.
+        for aead in [false, true] {
+            let mut bob_store_builder = TestStoreBuilder::new()
+                .with_pre_key(IdChoice::Next)
+                .with_signed_pre_key(IdChoice::Next)
+                .with_kyber_pre_key(IdChoice::Next);
+            let mut bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+            if aead {
+                bob_pre_key_bundle = bob_pre_key_bundle.with_aead_support();
+            }
+            let bob_store = &mut bob_store_builder.store;
+            let alice_store = &mut TestStoreBuilder::new().store;
+
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+
+            let envelope = b"timestamp=1700000000000;destination=bob";
+            let ciphertext = message_encrypt_with_associated_data(
+                b"hello",
+                envelope,
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                None,
+            )
+            .await?;
+
+            for wrong in [&b""[..], b"timestamp=1700000000001;destination=bob"] {
+                assert!(matches!(
+                    message_decrypt_with_associated_data(
+                        &ciphertext,
+                        wrong,
+                        &alice_address,
+                        &mut bob_store.session_store,
+                        &mut bob_store.identity_store,
+                        &mut bob_store.pre_key_store,
+                        &mut bob_store.signed_pre_key_store,
+                        &mut bob_store.kyber_pre_key_store,
+                        &mut csprng,
+                        None,
+                    )
+                    .await,
+                    Err(SignalProtocolError::InvalidMessage(
+                        CiphertextMessageType::PreKey,
+                        _
+                    ))
+                ));
+            }
+            assert_eq!(
+                message_decrypt_with_associated_data(
+                    &ciphertext,
+                    envelope,
+                    &alice_address,
+                    &mut bob_store.session_store,
+                    &mut bob_store.identity_store,
+                    &mut bob_store.pre_key_store,
+                    &mut bob_store.signed_pre_key_store,
+                    &mut bob_store.kyber_pre_key_store,
+                    &mut csprng,
+                    None,
+                )
+                .await?,
+                b"hello"
+            );
+
+            // Empty associated data is the same as none at all.
+            let reply = message_encrypt_with_associated_data(
+                b"reply",
+                &[],
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_store.identity_store,
+                None,
+            )
+            .await?;
+            assert_eq!(decrypt(alice_store, &bob_address, &reply).await?, b"reply");
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_sent_message_cache_retry() -> TestResult {
+    async {
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+
+        let mut cache = SentMessageCache::new(2);
+        let mut send = |message: &str| {
+            message_encrypt_cached(
+                message.as_bytes(),
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut cache,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+            .map(|(key, message)| (key, message.serialize().to_vec()))
+        };
+        let (first_key, first) = send("first")?;
+        let (second_key, _) = send("second")?;
+        assert_eq!(second_key.ratchet_key(), first_key.ratchet_key());
+        assert_eq!(second_key.counter(), first_key.counter() + 1);
+
+        // Pretend the first send failed and retry it from the cache.
+        let retry = cache.get(&bob_address, &first_key).expect("still cached");
+        assert_eq!(retry.serialize(), &first[..]);
+        let retry = PreKeySignalMessage::try_from(retry.serialize())?;
+        assert_eq!(retry.message().counter()?, first_key.counter());
+        assert_eq!(
+            decrypt(
+                &mut bob_store,
+                &alice_address,
+                &CiphertextMessage::PreKeySignalMessage(retry)
+            )
+            .await?,
+            b"first"
+        );
+
+        assert_eq!(
+            cache
+                .remove(&bob_address, &first_key)
+                .map(|m| m.serialize().to_vec()),
+            Some(first)
+        );
+        assert!(cache.get(&bob_address, &first_key).is_none());
+
+        // The oldest message is evicted once the cache is full.
+        let mut send = |message: &str| {
+            message_encrypt_cached(
+                message.as_bytes(),
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &mut cache,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+            .map(|(key, _)| key)
+        };
+        let third_key = send("third")?;
+        let fourth_key = send("fourth")?;
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&bob_address, &second_key).is_none());
+        assert!(cache.get(&bob_address, &third_key).is_some());
+        assert_eq!(
+            cache.last(&bob_address).map(|m| m.serialize().to_vec()),
+            cache
+                .get(&bob_address, &fourth_key)
+                .map(|m| m.serialize().to_vec())
+        );
+
+        cache.clear(&bob_address);
+        assert!(cache.is_empty());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_prekey_consumption_notification() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_device_id: DeviceId = 1.into();
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), bob_device_id);
+
+        let mut bob_store_builder = TestStoreBuilder::new();
+        bob_store_builder.add_pre_key(IdChoice::Exactly(24));
+        bob_store_builder.add_signed_pre_key(IdChoice::Exactly(25));
+        bob_store_builder.add_kyber_pre_key(IdChoice::Exactly(26));
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(bob_device_id);
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut alice_store_builder = TestStoreBuilder::new();
+        let alice_store = &mut alice_store_builder.store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let outgoing_message = encrypt(alice_store, &bob_address, "hello").await?;
+
+        let mut consumed = vec![];
+        let mut pre_key_store =
+            NotifyingPreKeyStore::new(bob_store.pre_key_store.clone(), |id| consumed.push(id));
+
+        // Replenish before the old key is used up.
+        for record in generate_prekey_batch(25.into(), 2, &mut csprng)? {
+            pre_key_store
+                .save_pre_key(record.id()?, &record, None)
+                .await?;
+        }
+
+        message_decrypt(
+            &outgoing_message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let remaining: Vec<PreKeyId> = {
+            let mut ids: Vec<PreKeyId> = pre_key_store.inner().all_pre_key_ids().copied().collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(remaining, vec![25.into(), 26.into()]);
+        drop(pre_key_store);
+        assert_eq!(consumed, vec![PreKeyId::from(24)]);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_store_journal_replication() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_device_id: DeviceId = 1.into();
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), bob_device_id);
+
+        let mut bob_store_builder = TestStoreBuilder::new();
+        bob_store_builder.add_pre_key(IdChoice::Exactly(24));
+        bob_store_builder.add_signed_pre_key(IdChoice::Exactly(25));
+        bob_store_builder.add_kyber_pre_key(IdChoice::Exactly(26));
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(bob_device_id);
+        let mut bob_store = bob_store_builder.store;
+        let mut bob_replica = bob_store.clone();
+
+        let mut alice_store_builder = TestStoreBuilder::new();
+        let alice_store = &mut alice_store_builder.store;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let first_message = encrypt(alice_store, &bob_address, "hello").await?;
+        let second_message = encrypt(alice_store, &bob_address, "again").await?;
+
+        let journal = InMemStoreJournal::new();
+        let mut session_store =
+            JournalingStore::new(bob_store.session_store.clone(), journal.clone());
+        let mut identity_store =
+            JournalingStore::new(bob_store.identity_store.clone(), journal.clone());
+        let mut pre_key_store =
+            JournalingStore::new(bob_store.pre_key_store.clone(), journal.clone());
+        message_decrypt(
+            &first_message,
+            &alice_address,
+            &mut session_store,
+            &mut identity_store,
+            &mut pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let entries = journal.take_entries();
+        let sequences: Vec<u64> = entries.iter().map(|entry| entry.sequence).collect();
+        assert_eq!(sequences, (0..entries.len() as u64).collect::<Vec<_>>());
+        assert!(entries.iter().any(|entry| matches!(
+            &entry.mutation,
+            StoreMutation::IdentitySaved { address, .. } if address == &alice_address
+        )));
+        assert!(entries.iter().any(|entry| matches!(
+            entry.mutation,
+            StoreMutation::PreKeyConsumed { id } if id == 24.into()
+        )));
+
+        // Ship the entries to the replica and apply them there.
+        for entry in &entries {
+            let entry = JournalEntry::deserialize(&entry.serialize())?;
+            entry
+                .mutation
+                .apply(
+                    &mut bob_replica.session_store,
+                    &mut bob_replica.identity_store,
+                    &mut bob_replica.pre_key_store,
+                    None,
+                )
+                .await?;
+        }
+
+        assert!(bob_replica.get_pre_key(24.into(), None).await.is_err());
+        assert_eq!(
+            bob_replica.get_identity(&alice_address, None).await?,
+            Some(
+                *alice_store
+                    .get_identity_key_pair(None)
+                    .await?
+                    .identity_key()
+            )
+        );
+        assert_eq!(
+            decrypt(&mut bob_replica, &alice_address, &second_message).await?,
+            b"again"
+        );
+
+        // Saving the same identity again isn't a change.
+        let alice_identity = *alice_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        identity_store
+            .save_identity(&alice_address, &alice_identity, None)
+            .await?;
+        assert!(journal.take_entries().is_empty());
+
+        session_store.delete_session(&alice_address, None).await?;
+        let entries = journal.take_entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sequence, sequences.len() as u64);
+        assert!(matches!(
+            &entries[0].mutation,
+            StoreMutation::SessionDeleted { address } if address == &alice_address
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_can_encrypt() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let bob_device_id: DeviceId = 1.into();
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), bob_device_id);
+
+        let mut bob_store_builder = TestStoreBuilder::new();
+        bob_store_builder.add_pre_key(IdChoice::Next);
+        bob_store_builder.add_signed_pre_key(IdChoice::Exactly(25));
+        bob_store_builder.add_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(bob_device_id);
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut alice_store_builder = TestStoreBuilder::new();
+        let alice_store = &mut alice_store_builder.store;
+
+        async fn check(
+            store: &InMemSignalProtocolStore,
+            remote_address: &ProtocolAddress,
+            now: SystemTime,
+        ) -> Result<Vec<EncryptionProblem>, SignalProtocolError> {
+            let readiness = can_encrypt(
+                remote_address,
+                &store.session_store,
+                &store.identity_store,
+                now,
+                None,
+            )
+            .await?;
+            assert_eq!(readiness.is_ready(), readiness.problems().is_empty());
+            Ok(readiness.problems().to_vec())
+        }
+
+        let now = SystemTime::now();
+        assert_eq!(
+            check(alice_store, &bob_address, now).await?,
+            vec![EncryptionProblem::NoSession]
+        );
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(check(alice_store, &bob_address, now).await?, vec![]);
+
+        let much_later = now + Duration::from_secs(60 * 24 * 60 * 60);
+        match &check(alice_store, &bob_address, much_later).await?[..] {
+            [EncryptionProblem::StaleSignedPreKey {
+                signed_pre_key_id,
+                age,
+            }] => {
+                assert_eq!(*signed_pre_key_id, 25.into());
+                assert!(*age >= Duration::from_secs(59 * 24 * 60 * 60));
+            }
+            problems => panic!("unexpected problems {:?}", problems),
+        }
+        // A stale signed pre-key is worth knowing about, but doesn't stop encryption.
+        can_encrypt(
+            &bob_address,
+            &alice_store.session_store,
+            &alice_store.identity_store,
+            much_later,
+            None,
+        )
+        .await?
+        .into_result()?;
+
+        // Once Bob replies, the session is acknowledged and no longer considered stale.
+        let message = encrypt(alice_store, &bob_address, "hi").await?;
+        decrypt(bob_store, &alice_address, &message).await?;
+        let reply = encrypt(bob_store, &alice_address, "hi back").await?;
+        decrypt(alice_store, &bob_address, &reply).await?;
+        assert_eq!(check(alice_store, &bob_address, much_later).await?, vec![]);
+
+        let new_bob_identity = IdentityKeyPair::generate(&mut csprng);
+        alice_store
+            .identity_store
+            .save_identity(&bob_address, new_bob_identity.identity_key(), None)
+            .await?;
+        let bob_identity = *bob_store.get_identity_key_pair(None).await?.identity_key();
+        assert_eq!(
+            check(alice_store, &bob_address, now).await?,
+            vec![EncryptionProblem::UntrustedIdentity(bob_identity)]
+        );
+        assert!(matches!(
+            can_encrypt(
+                &bob_address,
+                &alice_store.session_store,
+                &alice_store.identity_store,
+                now,
+                None,
+            )
+            .await?
+            .into_result(),
+            Err(SignalProtocolError::UntrustedIdentity(address)) if address == bob_address
+        ));
+        alice_store
+            .identity_store
+            .save_identity(&bob_address, &bob_identity, None)
+            .await?;
+
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session exists");
+        record.archive_current_state()?;
+        alice_store
+            .store_session(&bob_address, &record, None)
+            .await?;
+        assert_eq!(
+            check(alice_store, &bob_address, now).await?,
+            vec![EncryptionProblem::ArchivedSessionOnly]
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_availability() -> TestResult {
+    async {
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        let availability =
+            session_exists_and_is_current(&bob_address, &alice_store.session_store, None).await?;
+        assert_eq!(availability, SessionAvailability::None);
+        assert!(availability.needs_pre_key_bundle());
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let availability =
+            session_exists_and_is_current(&bob_address, &alice_store.session_store, None).await?;
+        assert_eq!(
+            availability,
+            SessionAvailability::Current {
+                version: KYBER_AWARE_MESSAGE_VERSION
+            }
+        );
+        assert!(!availability.needs_pre_key_bundle());
+
+        let mut record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session exists");
+        record.archive_current_state()?;
+        alice_store
+            .store_session(&bob_address, &record, None)
+            .await?;
+        let availability =
+            session_exists_and_is_current(&bob_address, &alice_store.session_store, None).await?;
+        assert_eq!(availability, SessionAvailability::ArchivedOnly);
+        assert!(availability.needs_pre_key_bundle());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_one_time_pre_key_policy() -> TestResult {
+    async {
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        // Without a one-time pre-key, the bundle is only accepted when that's optional.
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        let err = process_prekey_bundle_with_policy(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bundle,
+            OneTimePreKeyPolicy::Required,
+            &mut OsRng,
+            None,
+        )
+        .await
+        .expect_err("no one-time pre-key");
+        assert!(matches!(err, SignalProtocolError::InvalidArgument(_)));
+        assert!(alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .is_none());
+        assert!(alice_store
+            .get_identity(&bob_address, None)
+            .await?
+            .is_none());
+
+        process_prekey_bundle_with_policy(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bundle,
+            OneTimePreKeyPolicy::Optional,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session exists");
+        assert_eq!(record.used_one_time_pre_key()?, Some(false));
+
+        let mut bob_store = bob_store_builder.store;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        let record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session exists");
+        assert_eq!(record.used_one_time_pre_key()?, Some(false));
+
+        // With one, both sides record that it was used.
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle_with_policy(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            OneTimePreKeyPolicy::Required,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session exists");
+        assert_eq!(record.used_one_time_pre_key()?, Some(true));
+
+        let mut bob_store = bob_store_builder.store;
+        let message = encrypt(&mut alice_store, &bob_address, "hi").await?;
+        decrypt(&mut bob_store, &alice_address, &message).await?;
+        let record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session exists");
+        assert_eq!(record.used_one_time_pre_key()?, Some(true));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+#[test]
+fn test_session_store_bulk_operations() -> TestResult {
+    /// Only implements the required methods, to exercise the defaults.
+    struct MinimalSessionStore(InMemSessionStore);
+
+    #[async_trait(?Send)]
+    impl SessionStore for MinimalSessionStore {
+        async fn load_session(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+            self.0.load_session(address, ctx).await
+        }
+
+        async fn store_session(
+            &mut self,
+            address: &ProtocolAddress,
+            record: &SessionRecord,
+            ctx: Context,
+        ) -> Result<(), SignalProtocolError> {
+            self.0.store_session(address, record, ctx).await
+        }
+    }
+
+    async {
+        let mut csprng = OsRng;
+
+        let alice_1 = ProtocolAddress::new("alice".to_owned(), 1.into());
+        let alice_2 = ProtocolAddress::new("alice".to_owned(), 2.into());
+        let bob_1 = ProtocolAddress::new("bob".to_owned(), 1.into());
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        for address in [&alice_1, &alice_2, &bob_1] {
+            let builder = TestStoreBuilder::new()
+                .with_pre_key(IdChoice::Next)
+                .with_signed_pre_key(IdChoice::Next)
+                .with_kyber_pre_key(IdChoice::Next);
+            let bundle = builder.make_bundle_with_latest_keys(address.device_id());
+            process_prekey_bundle(
+                address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bundle,
+                &mut csprng,
+                None,
+            )
+            .await?;
+        }
+
+        let mut addresses = alice_store.all_session_addresses(None).await?;
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec![alice_1.clone(), alice_2.clone(), bob_1.clone()]
+        );
+
+        let sessions =
+            SessionStore::load_existing_sessions(&alice_store, &[&bob_1, &alice_2], None).await?;
+        assert_eq!(sessions.len(), 2);
+        let carol = ProtocolAddress::new("carol".to_owned(), 1.into());
+        assert!(matches!(
+            SessionStore::load_existing_sessions(&alice_store, &[&bob_1, &carol], None).await,
+            Err(SignalProtocolError::SessionNotFound(address)) if address == carol
+        ));
+
+        let minimal = MinimalSessionStore(alice_store.session_store.clone());
+        assert_eq!(
+            minimal
+                .load_existing_sessions(&[&bob_1, &alice_2], None)
+                .await?
+                .len(),
+            2
+        );
+        assert!(minimal.all_session_addresses(None).await.is_err());
+
+        alice_store.delete_all_sessions("alice", None).await?;
+        assert_eq!(
+            alice_store.all_session_addresses(None).await?,
+            vec![bob_1.clone()]
+        );
+
+        alice_store.delete_session(&bob_1, None).await?;
+        assert!(alice_store.load_session(&bob_1, None).await?.is_none());
+        assert!(alice_store.all_session_addresses(None).await?.is_empty());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_reconcile_with_server() -> TestResult {
+    async {
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+        let bob_other_device = ProtocolAddress::new("+14151111112".to_owned(), 2.into());
+        let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 2.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Exactly(1))
+            .with_pre_key(IdChoice::Exactly(2))
+            .with_pre_key(IdChoice::Exactly(3))
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let session = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        alice_store
+            .store_session(&bob_other_device, &session, None)
+            .await?;
+        alice_store
+            .store_session(&carol_address, &session, None)
+            .await?;
+
+        // Bob's device 2 is gone, and pre-key 1 was handed out. Carol's devices weren't fetched.
+        let server = ServerState {
+            available_pre_key_ids: [2, 3, 4].iter().map(|&id| id.into()).collect(),
+            registered_devices: [(
+                bob_address.name().to_owned(),
+                [bob_address.device_id()].iter().copied().collect(),
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+        };
+
+        let now = std::cell::Cell::new(Timestamp::from_epoch_millis(1_000_000));
+        let grace_period = Duration::from_secs(60 * 60);
+        let mut handed_out = HandedOutPreKeys::with_clock(grace_period, || now.get());
+
+        let local_pre_key_ids: Vec<PreKeyId> = bob_store.all_pre_key_ids().copied().collect();
+        let report = reconcile_with_server(
+            &server,
+            local_pre_key_ids,
+            &mut handed_out,
+            &mut bob_store.pre_key_store,
+            &mut alice_store.session_store,
+            None,
+        )
+        .await?;
+        assert_eq!(report.removed_pre_key_ids, []);
+        assert_eq!(report.handed_out_pre_key_ids, [1.into()]);
+        assert_eq!(report.missing_pre_key_ids, [4.into()]);
+        assert_eq!(
+            report.removed_sessions,
+            std::slice::from_ref(&bob_other_device)
+        );
+        assert!(!report.is_clean());
+        assert_eq!(
+            handed_out.marks().collect::<Vec<_>>(),
+            [(1.into(), now.get())]
+        );
+
+        // A PreKey message built from pre-key 1 may still be on its way.
+        assert!(bob_store.get_pre_key(1.into(), None).await.is_ok());
+        assert!(bob_store.get_pre_key(2.into(), None).await.is_ok());
+        assert!(alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .is_some());
+        assert!(alice_store
+            .load_session(&bob_other_device, None)
+            .await?
+            .is_none());
+        assert!(alice_store
+            .load_session(&carol_address, None)
+            .await?
+            .is_some());
+
+        // Once the grace period is over, pre-key 1 is removed.
+        now.set(now.get() + grace_period);
+        let local_pre_key_ids: Vec<PreKeyId> = bob_store.all_pre_key_ids().copied().collect();
+        let report = reconcile_with_server(
+            &server,
+            local_pre_key_ids,
+            &mut handed_out,
+            &mut bob_store.pre_key_store,
+            &mut alice_store.session_store,
+            None,
+        )
+        .await?;
+        assert_eq!(report.removed_pre_key_ids, [1.into()]);
+        assert_eq!(report.handed_out_pre_key_ids, []);
+        assert!(report.removed_sessions.is_empty());
+        assert!(bob_store.get_pre_key(1.into(), None).await.is_err());
+        assert_eq!(handed_out.marks().count(), 0);
+
+        // Nothing left to do the third time around, apart from the missing pre-key.
+        let local_pre_key_ids: Vec<PreKeyId> = bob_store.all_pre_key_ids().copied().collect();
+        let report = reconcile_with_server(
+            &server,
+            local_pre_key_ids,
+            &mut handed_out,
+            &mut bob_store.pre_key_store,
+            &mut alice_store.session_store,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            report,
+            ReconciliationReport {
+                missing_pre_key_ids: vec![4.into()],
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_auxiliary_authentication() -> TestResult {
+    let (alice_session, bob_session) = initialize_sessions_v4()?;
+
+    let alice_key = auth::derive_mac_key(&alice_session, Direction::Sending, b"typing")?;
+    let bob_key = auth::derive_mac_key(&bob_session, Direction::Receiving, b"typing")?;
+    assert_eq!(alice_key, bob_key);
+    assert_ne!(
+        alice_key,
+        auth::derive_mac_key(&alice_session, Direction::Sending, b"receipt")?
+    );
+
+    let mac = auth::authenticate(&alice_key, b"started typing");
+    assert!(auth::verify(&bob_key, b"started typing", &mac));
+    assert!(!auth::verify(&bob_key, b"stopped typing", &mac));
+    assert!(!auth::verify(&bob_key, b"started typing", &mac[..16]));
+
+    // Each direction has its own key, so Alice's MAC can't be reflected back to her as Bob's.
+    assert_ne!(
+        alice_key,
+        auth::derive_mac_key(&alice_session, Direction::Receiving, b"typing")?
+    );
+    assert_eq!(
+        auth::derive_mac_key(&bob_session, Direction::Sending, b"typing")?,
+        auth::derive_mac_key(&alice_session, Direction::Receiving, b"typing")?
+    );
+
+    // Each session has its own keys.
+    let (other_alice_session, _) = initialize_sessions_v4()?;
+    assert_ne!(
+        alice_key,
+        auth::derive_mac_key(&other_alice_session, Direction::Sending, b"typing")?
+    );
+    assert!(matches!(
+        auth::derive_mac_key(&SessionRecord::new_fresh(), Direction::Sending, b"typing"),
+        Err(SignalProtocolError::InvalidState("derive_mac_key", _))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_session_record_integrity() -> TestResult {
+    let (alice_session, _) = initialize_sessions_v4()?;
+    let key = b"session store integrity key";
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+    let tagged = alice_session.serialize_with_integrity(key, &bob_address)?;
+    let restored = SessionRecord::deserialize_with_integrity(
+        &tagged,
+        key,
+        &bob_address,
+        IntegrityMode::Strict,
+    )?;
+    assert_eq!(restored.serialize()?, alice_session.serialize()?);
+
+    let mut corrupted = tagged.clone();
+    *corrupted.last_mut().expect("not empty") ^= 1;
+    assert!(matches!(
+        SessionRecord::deserialize_with_integrity(
+            &corrupted,
+            key,
+            &bob_address,
+            IntegrityMode::AllowUntagged
+        ),
+        Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+    ));
+    assert!(matches!(
+        SessionRecord::deserialize_with_integrity(
+            &tagged,
+            b"wrong key",
+            &bob_address,
+            IntegrityMode::Strict
+        ),
+        Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+    ));
+    assert!(matches!(
+        SessionRecord::deserialize_with_integrity(
+            &tagged[..20],
+            key,
+            &bob_address,
+            IntegrityMode::Strict
+        ),
+        Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+    ));
+
+    // A record can't be moved to another address's slot.
+    let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1.into());
+    assert!(matches!(
+        SessionRecord::deserialize_with_integrity(
+            &tagged,
+            key,
+            &carol_address,
+            IntegrityMode::Strict
+        ),
+        Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+    ));
+
+    // Records written before tagging was turned on.
+    let untagged = alice_session.serialize()?;
+    assert!(matches!(
+        SessionRecord::deserialize_with_integrity(
+            &untagged,
+            key,
+            &bob_address,
+            IntegrityMode::Strict
+        ),
+        Err(SignalProtocolError::RecordIntegrityCheckFailed(_))
+    ));
+    let restored = SessionRecord::deserialize_with_integrity(
+        &untagged,
+        key,
+        &bob_address,
+        IntegrityMode::AllowUntagged,
+    )?;
+    assert_eq!(restored.serialize()?, untagged);
+
+    Ok(())
+}
+
+#[test]
+fn test_upgrade_to_pq_session() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next);
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let first = encrypt(&mut alice_store, &bob_address, "hello").await?;
+        decrypt(&mut bob_store_builder.store, &alice_address, &first).await?;
+        assert_eq!(alice_store.session_version(&bob_address)?, 3);
+        assert_eq!(bob_store_builder.store.session_version(&alice_address)?, 3);
+
+        // Alice sends on the old session one more time before upgrading.
+        let delayed_v3 = encrypt(&mut alice_store, &bob_address, "delayed").await?;
+
+        bob_store_builder.add_pre_key(IdChoice::Next);
+        bob_store_builder.add_kyber_pre_key(IdChoice::Next);
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let bob_store = &mut bob_store_builder.store;
+        let load_alice_record = |store: &InMemSignalProtocolStore| {
+            store
+                .load_session(&bob_address, None)
+                .now_or_never()
+                .expect("sync")
+                .map(|record| record.expect("session found"))
+        };
+        assert_eq!(alice_store.session_version(&bob_address)?, 4);
+        assert!(load_alice_record(&alice_store)?.has_pending_upgrade()?);
+
+        // Bob hasn't seen the upgrade yet; Alice can still read his messages without giving it up.
+        let from_old_session = encrypt(bob_store, &alice_address, "still on v3").await?;
+        let late_from_old_session = encrypt(bob_store, &alice_address, "too late").await?;
+        assert_eq!(
+            decrypt(&mut alice_store, &bob_address, &from_old_session).await?,
+            b"still on v3"
+        );
+        assert_eq!(alice_store.session_version(&bob_address)?, 4);
+        assert!(load_alice_record(&alice_store)?.has_pending_upgrade()?);
+
+        let upgrade = encrypt(&mut alice_store, &bob_address, "upgrading").await?;
+        assert_eq!(upgrade.message_type(), CiphertextMessageType::PreKey);
+        assert_eq!(
+            decrypt(bob_store, &alice_address, &upgrade).await?,
+            b"upgrading"
+        );
+        assert_eq!(bob_store.session_version(&alice_address)?, 4);
+
+        // A message sent before the upgrade still arrives, and doesn't undo it.
+        assert_eq!(
+            decrypt(bob_store, &alice_address, &delayed_v3).await?,
+            b"delayed"
+        );
+        assert_eq!(bob_store.session_version(&alice_address)?, 4);
+
+        // Bob's reply on the new session confirms it, but the old one is kept for the grace
+        // period so that his earlier messages still arrive.
+        let reply = encrypt(bob_store, &alice_address, "upgraded").await?;
+        assert_eq!(reply.message_type(), CiphertextMessageType::Whisper);
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        assert_eq!(
+            decrypt(&mut alice_store, &bob_address, &late_from_old_session).await?,
+            b"too late"
+        );
+        let alice_record = load_alice_record(&alice_store)?;
+        assert!(alice_record.has_pending_upgrade()?);
+        assert_eq!(alice_record.session_version()?, 4);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_process_prekey_reports_pre_keys_used() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Exactly(24))
+            .with_signed_pre_key(IdChoice::Exactly(25))
+            .with_kyber_pre_key(IdChoice::Exactly(26));
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = match encrypt(&mut alice_store, &bob_address, "hi").await? {
+            CiphertextMessage::PreKeySignalMessage(m) => m,
+            other => panic!("unexpected {:?} message", other.message_type()),
+        };
+
+        let mut record = SessionRecord::new_fresh();
+        let mut process = |record: &mut SessionRecord| {
+            process_prekey(
+                &message,
+                &alice_address,
+                record,
+                &mut bob_store.identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut bob_store.kyber_pre_key_store,
+                None,
+            )
+            .now_or_never()
+            .expect("sync")
+        };
+        assert_eq!(
+            process(&mut record)?,
+            PreKeysUsed {
+                pre_key_id: Some(24.into()),
+                signed_pre_key_id: Some(25.into()),
+                kyber_pre_key_id: Some(26.into()),
+            }
+        );
+        // Nothing new is used for a session that already exists.
+        assert_eq!(process(&mut record)?, PreKeysUsed::default());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_decrypt_work_limit() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        async fn decrypt_with_limit(
+            store: &mut InMemSignalProtocolStore,
+            remote_address: &ProtocolAddress,
+            msg: &CiphertextMessage,
+            limit: WorkLimit,
+        ) -> Result<DecryptResult, SignalProtocolError> {
+            message_decrypt_with_work_limit(
+                msg,
+                remote_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                &mut store.pre_key_store,
+                &mut store.signed_pre_key_store,
+                &mut store.kyber_pre_key_store,
+                limit,
+                &mut OsRng,
+                None,
+            )
+            .await
+        }
+
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        let few_curve_operations = WorkLimit {
+            max_curve_operations: 2,
+            ..WorkLimit::UNLIMITED
+        };
+        assert!(matches!(
+            decrypt_with_limit(bob_store, &alice_address, &first, few_curve_operations).await,
+            Err(SignalProtocolError::WorkLimitExceeded("curve operations"))
+        ));
+        assert!(bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+        decrypt_with_limit(bob_store, &alice_address, &first, WorkLimit::UNLIMITED).await?;
+
+        let reply = encrypt(bob_store, &alice_address, "reply").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+
+        // Make Bob skip ahead past 100 messages he never received.
+        for _ in 0..100 {
+            encrypt(&mut alice_store, &bob_address, "lost").await?;
+        }
+        let far_ahead = encrypt(&mut alice_store, &bob_address, "far ahead").await?;
+        let few_derivations = WorkLimit {
+            max_chain_key_derivations: 50,
+            ..WorkLimit::UNLIMITED
+        };
+        assert!(matches!(
+            decrypt_with_limit(bob_store, &alice_address, &far_ahead, few_derivations).await,
+            Err(SignalProtocolError::WorkLimitExceeded(
+                "chain key derivations"
+            ))
+        ));
+        let enough_derivations = WorkLimit {
+            max_chain_key_derivations: 200,
+            max_curve_operations: 3,
+        };
+        let result =
+            decrypt_with_limit(bob_store, &alice_address, &far_ahead, enough_derivations).await?;
+        assert_eq!(result.plaintext, b"far ahead");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_decrypt_work_limit_with_self_at_max_counter() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let a1_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let a2_address = ProtocolAddress::new("+14151111111".to_owned(), 2.into());
+
+        let mut a1_store = TestStoreBuilder::new().store;
+        let mut a2_store_builder = TestStoreBuilder::from_store(&a1_store)
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        process_prekey_bundle(
+            &a2_address,
+            &mut a1_store.session_store,
+            &mut a1_store.identity_store,
+            &a2_store_builder.make_bundle_with_latest_keys(2.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let a2_store = &mut a2_store_builder.store;
+
+        let first = encrypt(&mut a1_store, &a2_address, "first").await?;
+        decrypt(a2_store, &a1_address, &first).await?;
+        let message_version = match &first {
+            CiphertextMessage::PreKeySignalMessage(m) => m.message().message_version(),
+            other => panic!("unexpected {:?}", other.message_type()),
+        };
+
+        // A message on a new chain starts from index 0, so reaching the last possible counter
+        // means deriving every key up to u32::MAX, which sessions with ourselves are allowed to
+        // try. The limit has to reject that before doing any of the work.
+        let identity_key = *a1_store
+            .identity_store
+            .get_identity_key_pair(None)
+            .await?
+            .identity_key();
+        let last_counter = CiphertextMessage::SignalMessage(SignalMessage::new(
+            message_version,
+            &[0; 32],
+            KeyPair::generate(&mut csprng).public_key,
+            u32::MAX,
+            0,
+            b"never decrypted",
+            &identity_key,
+            &identity_key,
+        )?);
+        let limit = WorkLimit {
+            max_chain_key_derivations: 1000,
+            ..WorkLimit::UNLIMITED
+        };
+        assert!(matches!(
+            message_decrypt_with_work_limit(
+                &last_counter,
+                &a1_address,
+                &mut a2_store.session_store,
+                &mut a2_store.identity_store,
+                &mut a2_store.pre_key_store,
+                &mut a2_store.signed_pre_key_store,
+                &mut a2_store.kyber_pre_key_store,
+                limit,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::WorkLimitExceeded(
+                "chain key derivations"
+            ))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_identity_rotation() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        decrypt(&mut bob_store_builder.store, &alice_address, &first).await?;
+
+        let old_identity = alice_store.get_identity_key_pair(None).await?;
+        let new_identity = IdentityKeyPair::generate(&mut csprng);
+        let rotation =
+            IdentityRotation::new(&old_identity, new_identity.identity_key(), &mut csprng)?;
+        let received = IdentityRotation::try_from(rotation.serialized())?;
+        assert_eq!(received.old_identity_key(), old_identity.identity_key());
+        assert_eq!(received.new_identity_key(), new_identity.identity_key());
+
+        let mut tampered = rotation.serialized().to_vec();
+        *tampered.last_mut().expect("not empty") ^= 1;
+        assert!(IdentityRotation::try_from(&tampered[..]).is_err());
+
+        // A rotation signed by some other key doesn't apply.
+        let unrelated = IdentityKeyPair::generate(&mut csprng);
+        let unrelated_rotation =
+            IdentityRotation::new(&unrelated, new_identity.identity_key(), &mut csprng)?;
+        assert!(matches!(
+            bob_store_builder
+                .store
+                .apply_identity_rotation(&alice_address, &unrelated_rotation, None)
+                .await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+
+        // Alice starts over with her new identity.
+        let mut new_alice_store = InMemSignalProtocolStore::new(
+            new_identity,
+            alice_store.get_local_registration_id(None).await?,
+        )?;
+        bob_store_builder.add_pre_key(IdChoice::Next);
+        process_prekey_bundle(
+            &bob_address,
+            &mut new_alice_store.session_store,
+            &mut new_alice_store.identity_store,
+            &bob_store_builder.make_bundle_with_latest_keys(1.into()),
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let second = encrypt(&mut new_alice_store, &bob_address, "second").await?;
+
+        let bob_store = &mut bob_store_builder.store;
+        assert!(matches!(
+            decrypt(bob_store, &alice_address, &second).await,
+            Err(SignalProtocolError::UntrustedIdentity(_))
+        ));
+
+        assert!(
+            bob_store
+                .apply_identity_rotation(&alice_address, &received, None)
+                .await?
+        );
+        assert!(
+            !bob_store
+                .apply_identity_rotation(&alice_address, &received, None)
+                .await?
+        );
+        assert!(
+            bob_store
+                .is_trusted_identity(
+                    &alice_address,
+                    new_identity.identity_key(),
+                    Direction::Receiving,
+                    None
+                )
+                .await?
+        );
+        assert_eq!(
+            decrypt(bob_store, &alice_address, &second).await?,
+            b"second"
+        );
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_record_accessors() -> TestResult {
+    async {
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let bob_store = &mut bob_store_builder.store;
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let first = encrypt(&mut alice_store, &bob_address, "first").await?;
+        decrypt(bob_store, &alice_address, &first).await?;
+
+        let alice_record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        let bob_record = bob_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session found");
+
+        assert_eq!(alice_record.local_role()?, Some(SessionRole::Alice));
+        assert_eq!(bob_record.local_role()?, Some(SessionRole::Bob));
+        assert_eq!(
+            alice_record.remote_identity_key()?,
+            Some(*bob_store.get_identity_key_pair(None).await?.identity_key())
+        );
+        assert_eq!(
+            bob_record.remote_identity_key()?,
+            Some(
+                *alice_store
+                    .get_identity_key_pair(None)
+                    .await?
+                    .identity_key()
+            )
+        );
+        assert_eq!(
+            alice_record.base_key_fingerprint()?,
+            bob_record.base_key_fingerprint()?
+        );
+
+        let alice_digest = alice_record.diagnostic_digest()?;
+        let bob_digest = bob_record.diagnostic_digest()?;
+        let shared_prefix =
+            |digest: &str| digest.rsplit_once(':').expect("three parts").0.to_owned();
+        assert_eq!(
+            shared_prefix(&alice_digest),
+            format!(
+                "v{}:{}",
+                alice_record.session_version()?,
+                hex::encode(alice_record.base_key_fingerprint()?)
+            )
+        );
+        assert_eq!(shared_prefix(&alice_digest), shared_prefix(&bob_digest));
+        assert_ne!(alice_digest, bob_digest);
+        assert_eq!(alice_digest, alice_record.diagnostic_digest()?);
+
+        encrypt(&mut alice_store, &bob_address, "second").await?;
+        let alice_record = alice_store
+            .load_session(&bob_address, None)
+            .await?
+            .expect("session found");
+        let later_digest = alice_record.diagnostic_digest()?;
+        assert_ne!(alice_digest, later_digest);
+        assert_eq!(shared_prefix(&alice_digest), shared_prefix(&later_digest));
+
+        let fresh = SessionRecord::new_fresh();
+        assert!(fresh.local_role().is_err());
+        assert!(fresh.remote_identity_key().is_err());
+        assert!(fresh.base_key_fingerprint().is_err());
+        assert!(fresh.diagnostic_digest().is_err());
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_session_with_external_identity_key() -> TestResult {
+    /// Stands in for a key held in hardware: it can only be used, not read.
+    struct ExternalKey {
+        key: PrivateKey,
+        uses: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl PrivateKeyOps for ExternalKey {
+        fn public_key(&self) -> Result<PublicKey, SignalProtocolError> {
+            self.key.public_key()
+        }
+
+        fn calculate_signature_for_multipart_message(
+            &self,
+            message: &[&[u8]],
+            csprng: &mut dyn CryptoRngCore,
+        ) -> Result<Box<[u8]>, SignalProtocolError> {
+            self.uses.set(self.uses.get() + 1);
+            PrivateKeyOps::calculate_signature_for_multipart_message(&self.key, message, csprng)
+        }
+
+        fn calculate_agreement(
+            &self,
+            their_key: &PublicKey,
+        ) -> Result<Box<[u8]>, SignalProtocolError> {
+            self.uses.set(self.uses.get() + 1);
+            self.key.calculate_agreement(their_key)
+        }
+    }
+
+    /// Refuses to hand out its identity key pair.
+    struct ExternalIdentityStore {
+        inner: InMemIdentityKeyStore,
+        key: PrivateKey,
+        uses: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl ExternalIdentityStore {
+        async fn new(inner: InMemIdentityKeyStore) -> Result<Self, SignalProtocolError> {
+            let key = *inner.get_identity_key_pair(None).await?.private_key();
+            Ok(Self {
+                inner,
+                key,
+                uses: Default::default(),
+            })
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl IdentityKeyStore for ExternalIdentityStore {
+        async fn get_identity_key_pair(
+            &self,
+            _ctx: Context,
+        ) -> Result<IdentityKeyPair, SignalProtocolError> {
+            Err(SignalProtocolError::InvalidState(
+                "get_identity_key_pair",
+                "key is not extractable".to_owned(),
+            ))
+        }
+
+        async fn get_identity_private_key(
+            &self,
+            _ctx: Context,
+        ) -> Result<Box<dyn PrivateKeyOps>, SignalProtocolError> {
+            Ok(Box::new(ExternalKey {
+                key: self.key,
+                uses: self.uses.clone(),
+            }))
+        }
+
+        async fn get_local_registration_id(
+            &self,
+            ctx: Context,
+        ) -> Result<u32, SignalProtocolError> {
+            self.inner.get_local_registration_id(ctx).await
+        }
+
+        async fn save_identity(
+            &mut self,
+            address: &ProtocolAddress,
+            identity: &IdentityKey,
+            ctx: Context,
+        ) -> Result<bool, SignalProtocolError> {
+            self.inner.save_identity(address, identity, ctx).await
+        }
+
+        async fn is_trusted_identity(
+            &self,
+            address: &ProtocolAddress,
+            identity: &IdentityKey,
+            direction: Direction,
+            ctx: Context,
+        ) -> Result<bool, SignalProtocolError> {
+            self.inner
+                .is_trusted_identity(address, identity, direction, ctx)
+                .await
+        }
+
+        async fn get_identity(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+            self.inner.get_identity(address, ctx).await
+        }
+    }
+
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut bob_identity_store =
+            ExternalIdentityStore::new(bob_store.identity_store.clone()).await?;
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        let mut alice_identity_store =
+            ExternalIdentityStore::new(alice_store.identity_store.clone()).await?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            &bob_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(alice_identity_store.uses.get(), 1);
+
+        let message = message_encrypt(
+            b"from the enclave",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            None,
+        )
+        .await?;
+        let plaintext = message_decrypt(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(plaintext, b"from the enclave");
+        assert_eq!(bob_identity_store.uses.get(), 1);
+
+        let reply = message_encrypt(
+            b"and back",
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_identity_store,
+            None,
+        )
+        .await?;
+        let plaintext = message_decrypt(
+            &reply,
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            &mut alice_store.pre_key_store,
+            &mut alice_store.signed_pre_key_store,
+            &mut alice_store.kyber_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(plaintext, b"and back");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_p256_session() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = InMemSignalProtocolStore::new(
+            IdentityKeyPair::generate_with_key_type(KeyType::P256, &mut csprng),
+            1,
+        )?;
+        let mut bob_store = InMemSignalProtocolStore::new(
+            IdentityKeyPair::generate_with_key_type(KeyType::P256, &mut csprng),
+            2,
+        )?;
+
+        let pre_key_pair = KeyPair::generate_with_key_type(KeyType::P256, &mut csprng);
+        let signed_pre_key_pair = KeyPair::generate_with_key_type(KeyType::P256, &mut csprng);
+        let signed_pre_key_signature =
+            bob_store
+                .get_identity_key_pair(None)
+                .await?
+                .private_key()
+                .calculate_signature(&signed_pre_key_pair.public_key.serialize(), &mut csprng)?;
+        bob_store
+            .save_pre_key(1.into(), &PreKeyRecord::new(1.into(), &pre_key_pair), None)
+            .await?;
+        bob_store
+            .save_signed_pre_key(
+                2.into(),
+                &SignedPreKeyRecord::new(
+                    2.into(),
+                    Timestamp::from_epoch_millis(42),
+                    &signed_pre_key_pair,
+                    &signed_pre_key_signature,
+                ),
+                None,
+            )
+            .await?;
+
+        let bob_bundle = PreKeyBundle::new(
+            2,
+            1.into(),
+            Some((1.into(), pre_key_pair.public_key)),
+            2.into(),
+            signed_pre_key_pair.public_key,
+            signed_pre_key_signature.to_vec(),
+            *bob_store.get_identity_key_pair(None).await?.identity_key(),
+        )?;
+
+        // A Curve25519 identity can't start a session with a P-256 one.
+        let mut djb_store = test_in_memory_protocol_store()?;
+        assert!(matches!(
+            process_prekey_bundle(
+                &bob_address,
+                &mut djb_store.session_store,
+                &mut djb_store.identity_store,
+                &bob_bundle,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::MismatchedKeyTypes(
+                KeyType::P256,
+                KeyType::Djb
+            ))
+        ));
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+
+        let message = encrypt(&mut alice_store, &bob_address, "on a NIST curve").await?;
+        assert_eq!(message.message_type(), CiphertextMessageType::PreKey);
+        let plaintext = decrypt(&mut bob_store, &alice_address, &message).await?;
+        assert_eq!(plaintext, b"on a NIST curve");
+
+        for i in 0..3 {
+            let reply = encrypt(&mut bob_store, &alice_address, &format!("reply {}", i)).await?;
+            let plaintext = decrypt(&mut alice_store, &bob_address, &reply).await?;
+            assert_eq!(plaintext, format!("reply {}", i).as_bytes());
+
+            let message = encrypt(&mut alice_store, &bob_address, &format!("again {}", i)).await?;
+            let plaintext = decrypt(&mut bob_store, &alice_address, &message).await?;
+            assert_eq!(plaintext, format!("again {}", i).as_bytes());
+        }
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_bundle_with_mixed_key_types() -> TestResult {
+    async {
+        let mut csprng = OsRng;
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+
+        let mut alice_store = test_in_memory_protocol_store()?;
+        let bob_identity = IdentityKeyPair::generate(&mut csprng);
+        let signed_pre_key_pair = KeyPair::generate_with_key_type(KeyType::P256, &mut csprng);
+        let signed_pre_key_signature = bob_identity
+            .private_key()
+            .calculate_signature(&signed_pre_key_pair.public_key.serialize(), &mut csprng)?;
+
+        let bob_bundle = PreKeyBundle::new(
+            2,
+            1.into(),
+            None,
+            2.into(),
+            signed_pre_key_pair.public_key,
+            signed_pre_key_signature.to_vec(),
+            *bob_identity.identity_key(),
+        )?;
+        assert_eq!(
+            bob_bundle.validate(bob_identity.identity_key())?.problems(),
+            &[PreKeyBundleProblem::MixedKeyTypes]
+        );
+
+        assert!(matches!(
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_bundle,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::MismatchedKeyTypes(
+                KeyType::Djb,
+                KeyType::P256
+            ))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_identity_change_policy() -> TestResult {
+    /// Trusts every key, like a client that accepts identity changes without asking the user.
+    struct TrustingIdentityStore(InMemIdentityKeyStore);
+
+    #[async_trait(?Send)]
+    impl IdentityKeyStore for TrustingIdentityStore {
+        async fn get_identity_key_pair(
+            &self,
+            ctx: Context,
+        ) -> Result<IdentityKeyPair, SignalProtocolError> {
+            self.0.get_identity_key_pair(ctx).await
+        }
+
+        async fn get_local_registration_id(
+            &self,
+            ctx: Context,
+        ) -> Result<u32, SignalProtocolError> {
+            self.0.get_local_registration_id(ctx).await
+        }
+
+        async fn save_identity(
+            &mut self,
+            address: &ProtocolAddress,
+            identity: &IdentityKey,
+            ctx: Context,
+        ) -> Result<bool, SignalProtocolError> {
+            self.0.save_identity(address, identity, ctx).await
+        }
+
+        async fn is_trusted_identity(
+            &self,
+            _address: &ProtocolAddress,
+            _identity: &IdentityKey,
+            _direction: Direction,
+            _ctx: Context,
+        ) -> Result<bool, SignalProtocolError> {
+            Ok(true)
+        }
+
+        async fn get_identity(
+            &self,
+            address: &ProtocolAddress,
+            ctx: Context,
+        ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+            self.0.get_identity(address, ctx).await
+        }
+    }
+
+    async {
+        let mut csprng = OsRng;
+
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+        let other_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next);
+        let bob_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let bob_identity = *bob_store.get_identity_key_pair(None).await?.identity_key();
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        let mut alice_identity_store = TrustingIdentityStore(alice_store.identity_store.clone());
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            &bob_bundle,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        let message = message_encrypt(
+            b"first",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            None,
+        )
+        .await?;
+
+        // Bob has some other identity on file for Alice.
+        let mut bob_identity_store = TrustingIdentityStore(bob_store.identity_store.clone());
+        bob_identity_store
+            .save_identity(&alice_address, &other_identity, None)
+            .await?;
+
+        assert!(matches!(
+            message_decrypt_with_identity_policy(
+                &message,
+                &alice_address,
+                &mut bob_store.session_store,
+                &mut bob_identity_store,
+                &mut bob_store.pre_key_store,
+                &mut bob_store.signed_pre_key_store,
+                &mut bob_store.kyber_pre_key_store,
+                WorkLimit::UNLIMITED,
+                IdentityChangePolicy::Reject,
+                &mut csprng,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::IdentityKeyChanged(address)) if address == alice_address
+        ));
+        assert!(bob_store
+            .session_store
+            .load_session(&alice_address, None)
+            .await?
+            .is_none());
+        assert_eq!(
+            bob_identity_store
+                .get_identity(&alice_address, None)
+                .await?,
+            Some(other_identity)
+        );
+
+        // A session set up by the PreKey message already uses the new key, so it's kept.
+        let result = message_decrypt_with_identity_policy(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            WorkLimit::UNLIMITED,
+            IdentityChangePolicy::ArchiveSession,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(result.plaintext, b"first");
+        assert!(result.session_was_created);
+        assert!(bob_store
+            .session_store
+            .load_session(&alice_address, None)
+            .await?
+            .expect("session stored")
+            .has_current_session_state());
+
+        // Now Alice has some other identity on file for Bob.
+        alice_identity_store
+            .save_identity(&bob_address, &other_identity, None)
+            .await?;
+
+        assert!(matches!(
+            message_encrypt_with_identity_policy(
+                b"second",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_identity_store,
+                IdentityChangePolicy::Reject,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::IdentityKeyChanged(address)) if address == bob_address
+        ));
+        assert_eq!(
+            alice_identity_store
+                .get_identity(&bob_address, None)
+                .await?,
+            Some(other_identity)
+        );
+
+        let message = message_encrypt_with_identity_policy(
+            b"second",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identity_store,
+            IdentityChangePolicy::ArchiveSession,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            alice_identity_store
+                .get_identity(&bob_address, None)
+                .await?,
+            Some(bob_identity)
+        );
+        assert!(matches!(
+            message_encrypt(
+                b"third",
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_identity_store,
+                None,
+            )
+            .await,
+            Err(SignalProtocolError::SessionNotFound(_))
+        ));
+
+        // The message sent before archiving is still fine.
+        let plaintext = message_decrypt(
+            &message,
+            &alice_address,
+            &mut bob_store.session_store,
+            &mut bob_identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.signed_pre_key_store,
+            &mut bob_store.kyber_pre_key_store,
+            &mut csprng,
+            None,
+        )
+        .await?;
+        assert_eq!(plaintext, b"second");
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
+#[test]
+fn test_encrypt_with_alternate_identity_signature() -> TestResult {
+    async {
+        let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1.into());
+        let bob_store_builder = TestStoreBuilder::new()
+            .with_pre_key(IdChoice::Next)
+            .with_signed_pre_key(IdChoice::Next)
+            .with_kyber_pre_key(IdChoice::Next);
+        let bob_pre_key_bundle = bob_store_builder.make_bundle_with_latest_keys(1.into());
+        let mut bob_store = bob_store_builder.store;
+        let mut alice_store = TestStoreBuilder::new().store;
+
+        let alice_identity = alice_store.get_identity_key_pair(None).await?;
+        let alice_pni_identity = IdentityKeyPair::generate(&mut OsRng);
+        let signature = alice_identity
+            .sign_alternate_identity(alice_pni_identity.identity_key(), &mut OsRng)?;
+
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut OsRng,
+            None,
+        )
+        .await?;
+        let outgoing = message_encrypt_with_alternate_identity_signature(
+            b"hi",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &signature,
+            None,
+        )
+        .await?;
+
+        let incoming = PreKeySignalMessage::try_from(outgoing.serialize())?;
+        assert_eq!(
+            incoming.alternate_identity_signature(),
+            Some(&signature[..])
+        );
+        assert!(incoming.verify_alternate_identity(alice_pni_identity.identity_key())?);
+        assert_eq!(
+            decrypt(&mut bob_store, &alice_address, &outgoing).await?,
+            b"hi"
+        );
+
+        // Once Bob has replied, there's no PreKeySignalMessage to carry the signature.
+        let reply = encrypt(&mut bob_store, &alice_address, "reply").await?;
+        decrypt(&mut alice_store, &bob_address, &reply).await?;
+        let outgoing = message_encrypt_with_alternate_identity_signature(
+            b"again",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &signature,
+            None,
+        )
+        .await?;
+        assert_eq!(outgoing.message_type(), CiphertextMessageType::Whisper);
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
@@ -0,0 +1,43 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+import SignalFfi
+import Foundation
+
+/// Lets a long-running operation be stopped from another thread.
+///
+/// Operations that take a token check it periodically, and throw `SignalError.cancelled` once
+/// `cancel()` has been called. Cancelling has no effect on an operation that has already finished.
+public class CancellationToken: ClonableHandleOwner {
+    public convenience init() {
+        var result: OpaquePointer?
+        failOnError(signal_cancellation_token_new(&result))
+        self.init(owned: result!)
+    }
+
+    internal override class func destroyNativeHandle(_ handle: OpaquePointer) -> SignalFfiErrorRef? {
+        return signal_cancellation_token_destroy(handle)
+    }
+
+    internal override class func cloneNativeHandle(_ newHandle: inout OpaquePointer?, currentHandle: OpaquePointer?) -> SignalFfiErrorRef? {
+        return signal_cancellation_token_clone(&newHandle, currentHandle)
+    }
+
+    public func cancel() {
+        withNativeHandle { nativeHandle in
+            failOnError(signal_cancellation_token_cancel(nativeHandle))
+        }
+    }
+
+    public var isCancelled: Bool {
+        return withNativeHandle { nativeHandle in
+            failOnError {
+                var result = false
+                try checkError(signal_cancellation_token_is_cancelled(&result, nativeHandle))
+                return result
+            }
+        }
+    }
+}
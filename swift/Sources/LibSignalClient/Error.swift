@@ -27,13 +27,14 @@ public enum SignalError: Error {
     case fingerprintVersionMismatch(String)
     case fingerprintParsingError(String)
     case sealedSenderSelfSend(String)
-    case untrustedIdentity(String)
+    case untrustedIdentity(address: ProtocolAddress?, message: String)
+    case identityKeyChanged(address: ProtocolAddress, message: String)
     case invalidKeyIdentifier(String)
     case sessionNotFound(String)
     case invalidSession(String)
     case invalidRegistrationId(address: ProtocolAddress, message: String)
     case invalidSenderKeySession(distributionId: UUID, message: String)
-    case duplicatedMessage(String)
+    case duplicatedMessage(chainIndex: UInt32, counter: UInt32, message: String)
     case verificationFailed(String)
     case cannotBeEmpty(String)
     case cannotStartWithDigit(String)
@@ -48,6 +49,15 @@ public enum SignalError: Error {
     case invalidMediaInput(String)
     case unsupportedMediaInput(String)
     case callbackError(String)
+    case cancelled(String)
+    case rngHealthCheckFailed(String)
+    case recordIntegrityCheckFailed(String)
+    case workLimitExceeded(String)
+    case invalidKeyEncoding(String)
+    case mismatchedKeyTypes(String)
+    case invalidPadding(String)
+    case invalidProfileCiphertext(String)
+    case unrecognizedRecordVersion(String)
     case unknown(UInt32, String)
 }
 
@@ -97,7 +107,16 @@ internal func checkError(_ error: SignalFfiErrorRef?) throws {
     case SignalErrorCodeFingerprintVersionMismatch:
         throw SignalError.fingerprintVersionMismatch(errStr)
     case SignalErrorCodeUntrustedIdentity:
-        throw SignalError.untrustedIdentity(errStr)
+        // Not every untrusted identity error comes with an address (e.g. HSM enclave failures).
+        let address: ProtocolAddress? = try? invokeFnReturningNativeHandle {
+            signal_error_get_address(error, $0)
+        }
+        throw SignalError.untrustedIdentity(address: address, message: errStr)
+    case SignalErrorCodeIdentityKeyChanged:
+        let address: ProtocolAddress = try invokeFnReturningNativeHandle {
+            signal_error_get_address(error, $0)
+        }
+        throw SignalError.identityKeyChanged(address: address, message: errStr)
     case SignalErrorCodeInvalidKeyIdentifier:
         throw SignalError.invalidKeyIdentifier(errStr)
     case SignalErrorCodeSessionNotFound:
@@ -115,7 +134,10 @@ internal func checkError(_ error: SignalFfiErrorRef?) throws {
         }
         throw SignalError.invalidSenderKeySession(distributionId: distributionId, message: errStr)
     case SignalErrorCodeDuplicatedMessage:
-        throw SignalError.duplicatedMessage(errStr)
+        var chainIndex: UInt32 = 0
+        var counter: UInt32 = 0
+        try checkError(signal_error_get_duplicated_message_counters(error, &chainIndex, &counter))
+        throw SignalError.duplicatedMessage(chainIndex: chainIndex, counter: counter, message: errStr)
     case SignalErrorCodeVerificationFailure:
         throw SignalError.verificationFailed(errStr)
     case SignalErrorCodeUsernameCannotBeEmpty:
@@ -144,6 +166,24 @@ internal func checkError(_ error: SignalFfiErrorRef?) throws {
         throw SignalError.unsupportedMediaInput(errStr)
     case SignalErrorCodeCallbackError:
         throw SignalError.callbackError(errStr)
+    case SignalErrorCodeCancelled:
+        throw SignalError.cancelled(errStr)
+    case SignalErrorCodeRngHealthCheckFailed:
+        throw SignalError.rngHealthCheckFailed(errStr)
+    case SignalErrorCodeRecordIntegrityCheckFailed:
+        throw SignalError.recordIntegrityCheckFailed(errStr)
+    case SignalErrorCodeWorkLimitExceeded:
+        throw SignalError.workLimitExceeded(errStr)
+    case SignalErrorCodeInvalidKeyEncoding:
+        throw SignalError.invalidKeyEncoding(errStr)
+    case SignalErrorCodeMismatchedKeyTypes:
+        throw SignalError.mismatchedKeyTypes(errStr)
+    case SignalErrorCodeInvalidPadding:
+        throw SignalError.invalidPadding(errStr)
+    case SignalErrorCodeInvalidProfileCiphertext:
+        throw SignalError.invalidProfileCiphertext(errStr)
+    case SignalErrorCodeUnrecognizedRecordVersion:
+        throw SignalError.unrecognizedRecordVersion(errStr)
     default:
         throw SignalError.unknown(errType, errStr)
     }
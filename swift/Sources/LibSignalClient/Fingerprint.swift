@@ -49,17 +49,29 @@ public struct NumericFingerprintGenerator {
                                                 localIdentifier: LocalBytes,
                                                 localKey: PublicKey,
                                                 remoteIdentifier: RemoteBytes,
-                                                remoteKey: PublicKey) throws -> Fingerprint
+                                                remoteKey: PublicKey,
+                                                cancellationToken: CancellationToken? = nil) throws -> Fingerprint
     where LocalBytes: ContiguousBytes, RemoteBytes: ContiguousBytes {
         var obj: OpaquePointer?
         try withNativeHandles(localKey, remoteKey) { localKeyHandle, remoteKeyHandle in
             try localIdentifier.withUnsafeBorrowedBuffer { localBuffer in
                 try remoteIdentifier.withUnsafeBorrowedBuffer { remoteBuffer in
-                    try checkError(signal_fingerprint_new(&obj, UInt32(iterations), UInt32(version),
-                                                          localBuffer,
-                                                          localKeyHandle,
-                                                          remoteBuffer,
-                                                          remoteKeyHandle))
+                    guard let cancellationToken = cancellationToken else {
+                        try checkError(signal_fingerprint_new(&obj, UInt32(iterations), UInt32(version),
+                                                              localBuffer,
+                                                              localKeyHandle,
+                                                              remoteBuffer,
+                                                              remoteKeyHandle))
+                        return
+                    }
+                    try cancellationToken.withNativeHandle { tokenHandle in
+                        try checkError(signal_fingerprint_new_cancellable(&obj, UInt32(iterations), UInt32(version),
+                                                                          localBuffer,
+                                                                          localKeyHandle,
+                                                                          remoteBuffer,
+                                                                          remoteKeyHandle,
+                                                                          tokenHandle))
+                    }
                 }
             }
         }
@@ -54,6 +54,20 @@ public class PrivateKey: ClonableHandleOwner {
         }
     }
 
+    /// Signs an application payload, binding the signature to `label`.
+    ///
+    /// Use a distinct label for each kind of payload. Labels must be 1 to 255 bytes long and must
+    /// not start with `Signal_`.
+    public func generateSignature<Bytes: ContiguousBytes>(label: String, message: Bytes) throws -> [UInt8] {
+        return try withNativeHandle { nativeHandle in
+            try message.withUnsafeBorrowedBuffer { messageBuffer in
+                try invokeFnReturningArray {
+                    signal_privatekey_sign_with_label($0, nativeHandle, label, messageBuffer)
+                }
+            }
+        }
+    }
+
     public func keyAgreement(with other: PublicKey) -> [UInt8] {
         return withNativeHandles(self, other) { nativeHandle, otherHandle in
             failOnError {
@@ -26,6 +26,32 @@ public func signalEncrypt<Bytes: ContiguousBytes>(message: Bytes,
     }
 }
 
+/// Like `signalEncrypt(message:for:sessionStore:identityStore:context:)`, but attaches
+/// `alternateIdentitySignature` (from `IdentityKeyPair.signAlternateIdentity(_:)`) if the message
+/// sets up a new session.
+public func signalEncrypt<Bytes: ContiguousBytes, Signature: ContiguousBytes>(message: Bytes,
+                                                                             for address: ProtocolAddress,
+                                                                             sessionStore: SessionStore,
+                                                                             identityStore: IdentityKeyStore,
+                                                                             alternateIdentitySignature: Signature,
+                                                                             context: StoreContext) throws -> CiphertextMessage {
+    return try address.withNativeHandle { addressHandle in
+        try message.withUnsafeBorrowedBuffer { messageBuffer in
+            try alternateIdentitySignature.withUnsafeBorrowedBuffer { signatureBuffer in
+                try context.withOpaquePointer { context in
+                    try withSessionStore(sessionStore) { ffiSessionStore in
+                        try withIdentityKeyStore(identityStore) { ffiIdentityStore in
+                            try invokeFnReturningNativeHandle {
+                                signal_encrypt_message_with_alternate_identity_signature($0, messageBuffer, addressHandle, ffiSessionStore, ffiIdentityStore, signatureBuffer, context)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 public func signalDecrypt(message: SignalMessage,
                           from address: ProtocolAddress,
                           sessionStore: SessionStore,
@@ -87,6 +113,35 @@ public func processPreKeyBundle(_ bundle: PreKeyBundle,
     }
 }
 
+/// Checks that the given stores behave like ``InMemorySignalProtocolStore``, throwing an error that
+/// lists every check they failed.
+///
+/// The checks write entries under random addresses and IDs and don't remove them afterwards, so
+/// pass scratch instances of the stores rather than ones holding real data.
+public func checkStoreConformance(sessionStore: SessionStore,
+                                  identityStore: IdentityKeyStore,
+                                  preKeyStore: PreKeyStore,
+                                  signedPreKeyStore: SignedPreKeyStore,
+                                  kyberPreKeyStore: KyberPreKeyStore,
+                                  senderKeyStore: SenderKeyStore,
+                                  context: StoreContext) throws {
+    try context.withOpaquePointer { context in
+        try withSessionStore(sessionStore) { ffiSessionStore in
+            try withIdentityKeyStore(identityStore) { ffiIdentityStore in
+                try withPreKeyStore(preKeyStore) { ffiPreKeyStore in
+                    try withSignedPreKeyStore(signedPreKeyStore) { ffiSignedPreKeyStore in
+                        try withKyberPreKeyStore(kyberPreKeyStore) { ffiKyberPreKeyStore in
+                            try withSenderKeyStore(senderKeyStore) { ffiSenderKeyStore in
+                                try checkError(signal_store_conformance_check(ffiSessionStore, ffiIdentityStore, ffiPreKeyStore, ffiSignedPreKeyStore, ffiKyberPreKeyStore, ffiSenderKeyStore, context))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 public func groupEncrypt<Bytes: ContiguousBytes>(_ message: Bytes,
                                                  from sender: ProtocolAddress,
                                                  distributionId: UUID,
@@ -57,6 +57,21 @@ public class PublicKey: ClonableHandleOwner {
         return result
     }
 
+    /// Verifies a signature made by `PrivateKey.generateSignature(label:message:)` with the same
+    /// label.
+    public func verifySignature<MessageBytes, SignatureBytes>(label: String, message: MessageBytes, signature: SignatureBytes) throws -> Bool
+    where MessageBytes: ContiguousBytes, SignatureBytes: ContiguousBytes {
+        var result: Bool = false
+        try withNativeHandle { nativeHandle in
+            try message.withUnsafeBorrowedBuffer { messageBuffer in
+                try signature.withUnsafeBorrowedBuffer { signatureBuffer in
+                    try checkError(signal_publickey_verify_with_label(&result, nativeHandle, label, messageBuffer, signatureBuffer))
+                }
+            }
+        }
+        return result
+    }
+
     public func compare(_ other: PublicKey) -> Int32 {
         var result: Int32 = 0
         withNativeHandles(self, other) { selfHandle, otherHandle in
@@ -68,7 +83,11 @@ public class PublicKey: ClonableHandleOwner {
 
 extension PublicKey: Equatable {
     public static func == (lhs: PublicKey, rhs: PublicKey) -> Bool {
-        return lhs.compare(rhs) == 0
+        var result = false
+        withNativeHandles(lhs, rhs) { lhsHandle, rhsHandle in
+            failOnError(signal_publickey_equals(&result, lhsHandle, rhsHandle))
+        }
+        return result
     }
 }
 
@@ -169,7 +169,8 @@ public func sealedSenderMultiRecipientEncrypt(_ content: UnidentifiedSenderMessa
                                               for recipients: [ProtocolAddress],
                                               identityStore: IdentityKeyStore,
                                               sessionStore: SessionStore,
-                                              context: StoreContext) throws -> [UInt8] {
+                                              context: StoreContext,
+                                              cancellationToken: CancellationToken? = nil) throws -> [UInt8] {
     let sessions = try sessionStore.loadExistingSessions(for: recipients, context: context)
     // Use withExtendedLifetime instead of withNativeHandle for the arrays of wrapper objects,
     // which aren't compatible with withNativeHandle's simple lexical scoping.
@@ -183,12 +184,25 @@ public func sealedSenderMultiRecipientEncrypt(_ content: UnidentifiedSenderMessa
                     let sessionHandlesBuffer = SignalBorrowedSliceOfSessionRecord(base: sessionHandles.baseAddress, length: UInt(sessionHandles.count))
                     return try context.withOpaquePointer { context in
                         try withIdentityKeyStore(identityStore) { ffiIdentityStore in
-                            try invokeFnReturningArray {
-                                signal_sealed_sender_multi_recipient_encrypt($0,
-                                                                             recipientHandlesBuffer,
-                                                                             sessionHandlesBuffer,
-                                                                             contentHandle,
-                                                                             ffiIdentityStore, context)
+                            guard let cancellationToken = cancellationToken else {
+                                return try invokeFnReturningArray {
+                                    signal_sealed_sender_multi_recipient_encrypt($0,
+                                                                                 recipientHandlesBuffer,
+                                                                                 sessionHandlesBuffer,
+                                                                                 contentHandle,
+                                                                                 ffiIdentityStore, context)
+                                }
+                            }
+                            return try cancellationToken.withNativeHandle { tokenHandle in
+                                try invokeFnReturningArray {
+                                    signal_sealed_sender_multi_recipient_encrypt_cancellable($0,
+                                                                                             recipientHandlesBuffer,
+                                                                                             sessionHandlesBuffer,
+                                                                                             contentHandle,
+                                                                                             ffiIdentityStore,
+                                                                                             tokenHandle,
+                                                                                             context)
+                                }
                             }
                         }
                     }
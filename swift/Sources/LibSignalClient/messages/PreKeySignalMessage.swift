@@ -96,4 +96,16 @@ public class PreKeySignalMessage: NativeHandleOwner {
             }
         }
     }
+
+    /// Checks that the sender's identity key has vouched for `alternate` as another identity of
+    /// the same user.
+    ///
+    /// Returns `false` if the message carries no alternate identity signature.
+    public func verifyAlternateIdentity(_ alternate: IdentityKey) throws -> Bool {
+        var result: Bool = false
+        try withNativeHandles(self, alternate.publicKey) { selfHandle, alternateHandle in
+            try checkError(signal_pre_key_signal_message_verify_alternate_identity(&result, selfHandle, alternateHandle))
+        }
+        return result
+    }
 }
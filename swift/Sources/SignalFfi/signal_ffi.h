@@ -145,18 +145,26 @@ typedef enum {
   SignalErrorCodeUnrecognizedMessageVersion = 23,
   SignalErrorCodeInvalidMessage = 30,
   SignalErrorCodeSealedSenderSelfSend = 31,
+  SignalErrorCodeInvalidPadding = 32,
+  SignalErrorCodeInvalidProfileCiphertext = 33,
   SignalErrorCodeInvalidKey = 40,
   SignalErrorCodeInvalidSignature = 41,
   SignalErrorCodeInvalidAttestationData = 42,
+  SignalErrorCodeInvalidKeyEncoding = 43,
+  SignalErrorCodeMismatchedKeyTypes = 44,
   SignalErrorCodeFingerprintVersionMismatch = 51,
   SignalErrorCodeFingerprintParsingError = 52,
   SignalErrorCodeUntrustedIdentity = 60,
+  SignalErrorCodeIdentityKeyChanged = 61,
   SignalErrorCodeInvalidKeyIdentifier = 70,
   SignalErrorCodeSessionNotFound = 80,
   SignalErrorCodeInvalidRegistrationId = 81,
   SignalErrorCodeInvalidSession = 82,
   SignalErrorCodeInvalidSenderKeySession = 83,
+  SignalErrorCodeRecordIntegrityCheckFailed = 84,
+  SignalErrorCodeUnrecognizedRecordVersion = 85,
   SignalErrorCodeDuplicatedMessage = 90,
+  SignalErrorCodeWorkLimitExceeded = 91,
   SignalErrorCodeCallbackError = 100,
   SignalErrorCodeVerificationFailure = 110,
   SignalErrorCodeUsernameCannotBeEmpty = 120,
@@ -171,6 +179,8 @@ typedef enum {
   SignalErrorCodeIoError = 130,
   SignalErrorCodeInvalidMediaInput = 131,
   SignalErrorCodeUnsupportedMediaInput = 132,
+  SignalErrorCodeCancelled = 140,
+  SignalErrorCodeRngHealthCheckFailed = 150,
 } SignalErrorCode;
 
 /**
@@ -184,6 +194,8 @@ typedef struct SignalAes256GcmEncryption SignalAes256GcmEncryption;
 
 typedef struct SignalAes256GcmSiv SignalAes256GcmSiv;
 
+typedef struct SignalCancellationToken SignalCancellationToken;
+
 typedef struct SignalCiphertextMessage SignalCiphertextMessage;
 
 typedef struct SignalDecryptionErrorMessage SignalDecryptionErrorMessage;
@@ -405,6 +417,14 @@ SignalFfiError *signal_error_get_address(const SignalFfiError *err, SignalProtoc
 
 SignalFfiError *signal_error_get_uuid(const SignalFfiError *err, uint8_t (*out)[16]);
 
+SignalFfiError *signal_error_get_registration_id(const SignalFfiError *err, uint32_t *out);
+
+/**
+ * Gets the receiving chain's current index and the rejected message's counter from a
+ * `DuplicatedMessage` error.
+ */
+SignalFfiError *signal_error_get_duplicated_message_counters(const SignalFfiError *err, uint32_t *out_chain_index, uint32_t *out_counter);
+
 uint32_t signal_error_get_type(const SignalFfiError *err);
 
 void signal_error_free(SignalFfiError *err);
@@ -563,6 +583,8 @@ SignalFfiError *signal_publickey_compare(int32_t *out, const SignalPublicKey *ke
 
 SignalFfiError *signal_publickey_verify(bool *out, const SignalPublicKey *key, SignalBorrowedBuffer message, SignalBorrowedBuffer signature);
 
+SignalFfiError *signal_publickey_verify_with_label(bool *out, const SignalPublicKey *key, const char *label, SignalBorrowedBuffer message, SignalBorrowedBuffer signature);
+
 SignalFfiError *signal_privatekey_deserialize(SignalPrivateKey **out, SignalBorrowedBuffer data);
 
 SignalFfiError *signal_privatekey_serialize(SignalOwnedBuffer *out, const SignalPrivateKey *obj);
@@ -573,6 +595,8 @@ SignalFfiError *signal_privatekey_get_public_key(SignalPublicKey **out, const Si
 
 SignalFfiError *signal_privatekey_sign(SignalOwnedBuffer *out, const SignalPrivateKey *key, SignalBorrowedBuffer message);
 
+SignalFfiError *signal_privatekey_sign_with_label(SignalOwnedBuffer *out, const SignalPrivateKey *key, const char *label, SignalBorrowedBuffer message);
+
 SignalFfiError *signal_privatekey_agree(SignalOwnedBuffer *out, const SignalPrivateKey *private_key, const SignalPublicKey *public_key);
 
 SignalFfiError *signal_kyber_public_key_serialize(SignalOwnedBuffer *out, const SignalKyberPublicKey *obj);
@@ -623,6 +647,10 @@ SignalFfiError *signal_message_get_sender_ratchet_key(SignalPublicKey **out, con
 
 SignalFfiError *signal_pre_key_signal_message_new(SignalPreKeySignalMessage **out, uint8_t message_version, uint32_t registration_id, uint32_t pre_key_id, uint32_t signed_pre_key_id, const SignalPublicKey *base_key, const SignalPublicKey *identity_key, const SignalMessage *signal_message);
 
+SignalFfiError *signal_pre_key_signal_message_with_alternate_identity_signature(SignalPreKeySignalMessage **out, const SignalPreKeySignalMessage *m, SignalBorrowedBuffer signature);
+
+SignalFfiError *signal_pre_key_signal_message_verify_alternate_identity(bool *out, const SignalPreKeySignalMessage *m, const SignalPublicKey *alternate_identity);
+
 SignalFfiError *signal_pre_key_signal_message_get_base_key(SignalPublicKey **out, const SignalPreKeySignalMessage *m);
 
 SignalFfiError *signal_pre_key_signal_message_get_identity_key(SignalPublicKey **out, const SignalPreKeySignalMessage *m);
@@ -845,6 +873,8 @@ SignalFfiError *signal_process_prekey_bundle(const SignalPreKeyBundle *bundle, c
 
 SignalFfiError *signal_encrypt_message(SignalCiphertextMessage **out, SignalBorrowedBuffer ptext, const SignalProtocolAddress *protocol_address, const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_key_store, void *ctx);
 
+SignalFfiError *signal_encrypt_message_with_alternate_identity_signature(SignalCiphertextMessage **out, SignalBorrowedBuffer ptext, const SignalProtocolAddress *protocol_address, const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_key_store, SignalBorrowedBuffer alternate_identity_signature, void *ctx);
+
 SignalFfiError *signal_decrypt_message(SignalOwnedBuffer *out, const SignalMessage *message, const SignalProtocolAddress *protocol_address, const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_key_store, void *ctx);
 
 SignalFfiError *signal_decrypt_pre_key_message(SignalOwnedBuffer *out, const SignalPreKeySignalMessage *message, const SignalProtocolAddress *protocol_address, const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_key_store, const SignalPreKeyStore *prekey_store, const SignalSignedPreKeyStore *signed_prekey_store, const SignalKyberPreKeyStore *kyber_prekey_store, void *ctx);
@@ -853,6 +883,20 @@ SignalFfiError *signal_sealed_session_cipher_encrypt(SignalOwnedBuffer *out, con
 
 SignalFfiError *signal_sealed_sender_multi_recipient_encrypt(SignalOwnedBuffer *out, SignalBorrowedSliceOfProtocolAddress recipients, SignalBorrowedSliceOfSessionRecord recipient_sessions, const SignalUnidentifiedSenderMessageContent *content, const SignalIdentityKeyStore *identity_key_store, void *ctx);
 
+SignalFfiError *signal_cancellation_token_destroy(SignalCancellationToken *p);
+
+SignalFfiError *signal_cancellation_token_clone(SignalCancellationToken **new_obj, const SignalCancellationToken *obj);
+
+SignalFfiError *signal_cancellation_token_new(SignalCancellationToken **out);
+
+SignalFfiError *signal_cancellation_token_cancel(const SignalCancellationToken *token);
+
+SignalFfiError *signal_cancellation_token_is_cancelled(bool *out, const SignalCancellationToken *token);
+
+SignalFfiError *signal_fingerprint_new_cancellable(SignalFingerprint **out, uint32_t iterations, uint32_t version, SignalBorrowedBuffer local_identifier, const SignalPublicKey *local_key, SignalBorrowedBuffer remote_identifier, const SignalPublicKey *remote_key, const SignalCancellationToken *token);
+
+SignalFfiError *signal_sealed_sender_multi_recipient_encrypt_cancellable(SignalOwnedBuffer *out, SignalBorrowedSliceOfProtocolAddress recipients, SignalBorrowedSliceOfSessionRecord recipient_sessions, const SignalUnidentifiedSenderMessageContent *content, const SignalIdentityKeyStore *identity_key_store, const SignalCancellationToken *token, void *ctx);
+
 SignalFfiError *signal_sealed_sender_multi_recipient_message_for_single_recipient(SignalOwnedBuffer *out, SignalBorrowedBuffer encoded_multi_recipient_message);
 
 SignalFfiError *signal_sealed_session_cipher_decrypt_to_usmc(SignalUnidentifiedSenderMessageContent **out, SignalBorrowedBuffer ctext, const SignalIdentityKeyStore *identity_store, void *ctx);
@@ -865,6 +909,8 @@ SignalFfiError *signal_group_encrypt_message(SignalCiphertextMessage **out, cons
 
 SignalFfiError *signal_group_decrypt_message(SignalOwnedBuffer *out, const SignalProtocolAddress *sender, SignalBorrowedBuffer message, const SignalSenderKeyStore *store, void *ctx);
 
+SignalFfiError *signal_store_conformance_check(const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_store, const SignalPreKeyStore *pre_key_store, const SignalSignedPreKeyStore *signed_pre_key_store, const SignalKyberPreKeyStore *kyber_pre_key_store, const SignalSenderKeyStore *sender_key_store, void *ctx);
+
 SignalFfiError *signal_device_transfer_generate_private_key(SignalOwnedBuffer *out);
 
 SignalFfiError *signal_device_transfer_generate_private_key_with_format(SignalOwnedBuffer *out, uint8_t key_format);
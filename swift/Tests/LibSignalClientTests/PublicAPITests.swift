@@ -94,6 +94,12 @@ class PublicAPITests: TestCaseBase {
         message[1] ^= 1
         XCTAssertEqual(try! pk.verifySignature(message: message, signature: signature), true)
 
+        let labeled = try! sk.generateSignature(label: "Test_Label", message: message)
+        XCTAssertEqual(try! pk.verifySignature(label: "Test_Label", message: message, signature: labeled), true)
+        XCTAssertEqual(try! pk.verifySignature(label: "Test_Other", message: message, signature: labeled), false)
+        XCTAssertEqual(try! pk.verifySignature(message: message, signature: labeled), false)
+        XCTAssertThrowsError(try sk.generateSignature(label: "Signal_Label", message: message))
+
         let sk2 = PrivateKey.generate()
 
         let shared_secret1 = sk.keyAgreement(with: sk2.publicKey)
@@ -173,6 +179,31 @@ class PublicAPITests: TestCaseBase {
         XCTAssertThrowsError(try bobFingerprint2.scannable.compare(againstEncoding: aliceFingerprint.scannable.encoding))
         XCTAssertThrowsError(try bobFingerprint.scannable.compare(againstEncoding: aliceFingerprint2.scannable.encoding))
 
+        // testCancellation
+
+        let token = CancellationToken()
+        let aliceFingerprintC = try! generator.create(version: VERSION_1,
+                                                      localIdentifier: aliceStableId,
+                                                      localKey: aliceIdentityKey,
+                                                      remoteIdentifier: bobStableId,
+                                                      remoteKey: bobIdentityKey,
+                                                      cancellationToken: token)
+        XCTAssertEqual(aliceFingerprintC.scannable.encoding, ALICE_SCANNABLE_FINGERPRINT_V1)
+
+        token.cancel()
+        XCTAssertTrue(token.isCancelled)
+        XCTAssertThrowsError(try generator.create(version: VERSION_1,
+                                                  localIdentifier: aliceStableId,
+                                                  localKey: aliceIdentityKey,
+                                                  remoteIdentifier: bobStableId,
+                                                  remoteKey: bobIdentityKey,
+                                                  cancellationToken: token)) { error in
+            guard case SignalError.cancelled(_) = error else {
+                XCTFail("unexpected error: \(error)")
+                return
+            }
+        }
+
         // testMismatchingFingerprints
 
         let mitmIdentityKey = PrivateKey.generate().publicKey
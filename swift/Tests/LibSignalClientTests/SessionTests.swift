@@ -70,6 +70,17 @@ class SessionTests: TestCaseBase {
                                               context: NullContext())
 
             XCTAssertEqual(ptext2_a, ptext2_b)
+
+            XCTAssertThrowsError(try signalDecrypt(message: ctext2_a,
+                                                   from: bob_address,
+                                                   sessionStore: alice_store,
+                                                   identityStore: alice_store,
+                                                   context: NullContext())) { error in
+                guard case SignalError.duplicatedMessage(chainIndex: 1, counter: 0, message: _) = error else {
+                    XCTFail("wrong error thrown: \(error)")
+                    return
+                }
+            }
         }
     }
 
@@ -391,6 +402,17 @@ class SessionTests: TestCaseBase {
         let bob_session_with_alice = try XCTUnwrap(bob_store.loadSession(for: alice_address, context: NullContext()))
         XCTAssert(try bob_session_with_alice.currentRatchetKeyMatches(XCTUnwrap(bob_error_message.ratchetKey)))
     }
+
+    func testInMemoryStoreConformance() throws {
+        let store = InMemorySignalProtocolStore()
+        try checkStoreConformance(sessionStore: store,
+                                  identityStore: store,
+                                  preKeyStore: store,
+                                  signedPreKeyStore: store,
+                                  kyberPreKeyStore: store,
+                                  senderKeyStore: store,
+                                  context: NullContext())
+    }
 }
 
 private func initializeSessionsV3(